@@ -0,0 +1,122 @@
+//! Kernel command line.
+//!
+//! The command line is read from the `cmdline` file of the boot partition and
+//! copied into [`BootInfo`](crate::BootInfo), so it stays valid after the
+//! kernel reused the memory the file was loaded to. It is at most
+//! [`MAX_LENGTH`] bytes of UTF-8, longer command lines are truncated.
+//!
+//! The bootloader itself reads the `video=` option, see [`VideoRequest`].
+use core::{fmt, str, str::FromStr};
+
+pub const MAX_LENGTH: usize = 256;
+
+/// Value of the `key=value` option `key`, an empty string for a flag
+pub fn get<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .map(|option| option.split_once('=').unwrap_or((option, "")))
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| value)
+}
+
+/// Display mode requested with `video=<width>x<height>[x<depth>]` or
+/// `video=text`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoRequest {
+    /// Stay in VGA text mode, there is no framebuffer
+    Text,
+    Mode {
+        width: u16,
+        height: u16,
+        depth: u8,
+    },
+}
+
+impl VideoRequest {
+    pub const DEFAULT: Self = Self::Mode {
+        width: 1280,
+        height: 1024,
+        depth: 24,
+    };
+}
+
+impl FromStr for VideoRequest {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        if s == "text" {
+            return Ok(Self::Text);
+        }
+
+        let mut parts = s.split('x');
+        let width = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let height = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let depth = match parts.next() {
+            Some(depth) => depth.parse().map_err(|_| ())?,
+            None => 24,
+        };
+        if parts.next().is_some() {
+            return Err(());
+        }
+        Ok(Self::Mode {
+            width,
+            height,
+            depth,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct CommandLine {
+    data: [u8; MAX_LENGTH],
+    len: usize,
+}
+
+impl CommandLine {
+    pub const fn empty() -> Self {
+        Self {
+            data: [0; MAX_LENGTH],
+            len: 0,
+        }
+    }
+
+    /// Copies `bytes` up to the first line break, invalid UTF-8 results in an
+    /// empty command line
+    pub fn new(bytes: &[u8]) -> Self {
+        let Ok(text) = str::from_utf8(bytes) else {
+            return Self::empty();
+        };
+        let text = text.lines().next().unwrap_or("").trim();
+
+        let mut len = text.len().min(MAX_LENGTH);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut cmdline = Self::empty();
+        cmdline.data[..len].copy_from_slice(&text.as_bytes()[..len]);
+        cmdline.len = len;
+        cmdline
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever filled with valid UTF-8
+        unsafe { str::from_utf8_unchecked(&self.data[..self.len]) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for CommandLine {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl fmt::Debug for CommandLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
@@ -0,0 +1,79 @@
+//! Kernel compression.
+//!
+//! A compressed kernel starts with [`MAGIC`] and the size of the decompressed
+//! kernel as little endian u64, followed by LZSS tokens in groups of up to
+//! eight. Each group starts with a flag byte, its bits tell from the lowest
+//! one on whether a token is a literal byte (0) or a match (1). A match is the
+//! distance back into the output as little endian u16 and the length minus
+//! [`MIN_MATCH`] as u8.
+pub const MAGIC: [u8; 8] = *b"MOSLZSS\0";
+pub const HEADER_SIZE: usize = 16;
+pub const MIN_MATCH: usize = 4;
+pub const MAX_MATCH: usize = MIN_MATCH + u8::MAX as usize;
+/// Largest distance of a match
+pub const WINDOW_SIZE: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressError {
+    InvalidHeader,
+    Truncated,
+    /// A match refers to data before the start of the output
+    InvalidDistance,
+    /// The output doesn't fit or the data decompresses to less than the size
+    /// in the header
+    SizeMismatch,
+}
+
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+/// Size of the decompressed data according to the header
+pub fn decompressed_size(data: &[u8]) -> Result<usize, DecompressError> {
+    if data.len() < HEADER_SIZE || !is_compressed(data) {
+        return Err(DecompressError::InvalidHeader);
+    }
+    Ok(u64::from_le_bytes(data[8..16].try_into().unwrap()) as usize)
+}
+
+/// Decompresses `data` into `out`, which has to be exactly
+/// [`decompressed_size`] bytes long
+pub fn decompress(data: &[u8], out: &mut [u8]) -> Result<(), DecompressError> {
+    if decompressed_size(data)? != out.len() {
+        return Err(DecompressError::SizeMismatch);
+    }
+
+    let mut input = data[HEADER_SIZE..].iter().copied();
+    let mut next = || input.next().ok_or(DecompressError::Truncated);
+    let mut pos = 0;
+
+    while pos < out.len() {
+        let flags = next()?;
+        for bit in 0..8 {
+            if pos == out.len() {
+                break;
+            }
+
+            if flags >> bit & 1 == 0 {
+                out[pos] = next()?;
+                pos += 1;
+                continue;
+            }
+
+            let distance = usize::from(u16::from_le_bytes([next()?, next()?]));
+            let len = usize::from(next()?) + MIN_MATCH;
+            if distance == 0 || distance > pos {
+                return Err(DecompressError::InvalidDistance);
+            }
+            if pos + len > out.len() {
+                return Err(DecompressError::SizeMismatch);
+            }
+            // byte wise since the match may overlap the bytes it produces
+            for i in pos..pos + len {
+                out[i] = out[i - distance];
+            }
+            pos += len;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,420 @@
+//! Ed25519 signature verification.
+//!
+//! Only verification is implemented, the bootloader signs kernels on the host.
+//! Nothing here is constant time, which is fine since no secrets are involved.
+//! https://www.rfc-editor.org/rfc/rfc8032
+use crate::sha512::Sha512;
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureError {
+    InvalidPublicKey,
+    /// The signature is malformed or doesn't match
+    InvalidSignature,
+}
+
+/// Verifies the signature of `message` with the public key
+pub fn verify(
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+    message: &[u8],
+    signature: &[u8; SIGNATURE_SIZE],
+) -> Result<(), SignatureError> {
+    let a = Point::decompress(public_key).ok_or(SignatureError::InvalidPublicKey)?;
+    let r: &[u8; 32] = signature[..32].try_into().unwrap();
+    let s: &[u8; 32] = signature[32..].try_into().unwrap();
+    if !scalar::is_canonical(s) {
+        return Err(SignatureError::InvalidSignature);
+    }
+
+    let mut hasher = Sha512::new();
+    hasher.update(r);
+    hasher.update(public_key);
+    hasher.update(message);
+    let h = scalar::reduce(&hasher.finalize());
+
+    // [S]B = R + [h]A  <=>  [S]B - [h]A = R
+    let check = Point::base().mul(s).add(&a.neg().mul(&h)).compress();
+    if check == *r {
+        Ok(())
+    } else {
+        Err(SignatureError::InvalidSignature)
+    }
+}
+
+/// Element of GF(2^255 - 19) in 5 limbs of 51 bits
+#[derive(Debug, Clone, Copy)]
+struct FieldElement([u64; 5]);
+
+const MASK: u64 = (1 << 51) - 1;
+
+/// Curve constant d = -121665 / 121666
+const D: FieldElement = FieldElement([
+    0x34dca135978a3,
+    0x1a8283b156ebd,
+    0x5e7a26001c029,
+    0x739c663a03cbb,
+    0x52036cee2b6ff,
+]);
+/// 2 * d
+const D2: FieldElement = FieldElement([
+    0x69b9426b2f159,
+    0x35050762add7a,
+    0x3cf44c0038052,
+    0x6738cc7407977,
+    0x2406d9dc56dff,
+]);
+/// 2^((p - 1) / 4), a square root of -1
+const SQRT_M1: FieldElement = FieldElement([
+    0x61b274a0ea0b0,
+    0xd5a5fc8f189d,
+    0x7ef5e9cbd0c60,
+    0x78595a6804c9e,
+    0x2b8324804fc1d,
+]);
+
+impl FieldElement {
+    const ZERO: Self = Self([0; 5]);
+    const ONE: Self = Self([1, 0, 0, 0, 0]);
+
+    fn from_u64(value: u64) -> Self {
+        Self([value, 0, 0, 0, 0]).reduce()
+    }
+
+    /// Ignores the highest bit
+    fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let load = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        Self([
+            load(0) & MASK,
+            (load(6) >> 3) & MASK,
+            (load(12) >> 6) & MASK,
+            (load(19) >> 1) & MASK,
+            (load(24) >> 12) & MASK,
+        ])
+    }
+
+    /// Canonical little endian encoding
+    fn to_bytes(self) -> [u8; 32] {
+        let mut h = self.reduce().0;
+
+        // subtracts p if h >= p, h + 19 overflows 2^255 exactly then
+        let mut q = (h[0] + 19) >> 51;
+        for limb in &h[1..] {
+            q = (limb + q) >> 51;
+        }
+        h[0] += 19 * q;
+        for i in 0..4 {
+            h[i + 1] += h[i] >> 51;
+            h[i] &= MASK;
+        }
+        h[4] &= MASK;
+
+        let mut bytes = [0u8; 32];
+        let mut acc: u128 = 0;
+        let mut acc_bits = 0;
+        let mut i = 0;
+        for limb in h {
+            acc |= u128::from(limb) << acc_bits;
+            acc_bits += 51;
+            while acc_bits >= 8 {
+                bytes[i] = acc as u8;
+                acc >>= 8;
+                acc_bits -= 8;
+                i += 1;
+            }
+        }
+        bytes[i] = acc as u8;
+        bytes
+    }
+
+    /// Carries the limbs down to 51 bits, the result is below 2p
+    fn reduce(self) -> Self {
+        let mut h = self.0;
+        for i in 0..4 {
+            h[i + 1] += h[i] >> 51;
+            h[i] &= MASK;
+        }
+        h[0] += 19 * (h[4] >> 51);
+        h[4] &= MASK;
+        h[1] += h[0] >> 51;
+        h[0] &= MASK;
+        Self(h)
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        let mut h = self.0;
+        for (a, b) in h.iter_mut().zip(other.0) {
+            *a += b;
+        }
+        Self(h).reduce()
+    }
+
+    fn sub(&self, other: &Self) -> Self {
+        // adds 4p to stay positive
+        const FOUR_P: [u64; 5] = [
+            0x1f_ffff_ffff_ffb4,
+            0x1f_ffff_ffff_fffc,
+            0x1f_ffff_ffff_fffc,
+            0x1f_ffff_ffff_fffc,
+            0x1f_ffff_ffff_fffc,
+        ];
+        let mut h = self.0;
+        for i in 0..5 {
+            h[i] = h[i] + FOUR_P[i] - other.0[i];
+        }
+        Self(h).reduce()
+    }
+
+    fn neg(&self) -> Self {
+        Self::ZERO.sub(self)
+    }
+
+    fn mul(&self, other: &Self) -> Self {
+        let a = self.0.map(u128::from);
+        let b = other.0.map(u128::from);
+        // 2^255 = 19 mod p
+        let b19 = b.map(|limb| limb * 19);
+
+        let t = [
+            a[0] * b[0] + a[1] * b19[4] + a[2] * b19[3] + a[3] * b19[2] + a[4] * b19[1],
+            a[0] * b[1] + a[1] * b[0] + a[2] * b19[4] + a[3] * b19[3] + a[4] * b19[2],
+            a[0] * b[2] + a[1] * b[1] + a[2] * b[0] + a[3] * b19[4] + a[4] * b19[3],
+            a[0] * b[3] + a[1] * b[2] + a[2] * b[1] + a[3] * b[0] + a[4] * b19[4],
+            a[0] * b[4] + a[1] * b[3] + a[2] * b[2] + a[3] * b[1] + a[4] * b[0],
+        ];
+
+        let mut h = [0u64; 5];
+        let mut carry: u128 = 0;
+        for i in 0..5 {
+            let value = t[i] + carry;
+            h[i] = value as u64 & MASK;
+            carry = value >> 51;
+        }
+        h[0] += 19 * carry as u64;
+        Self(h).reduce()
+    }
+
+    fn square(&self) -> Self {
+        self.mul(self)
+    }
+
+    /// `self` to the power of the little endian `exponent`
+    fn pow(&self, exponent: &[u8; 32]) -> Self {
+        let mut result = Self::ONE;
+        for bit in (0..256).rev() {
+            result = result.square();
+            if exponent[bit / 8] >> (bit % 8) & 1 == 1 {
+                result = result.mul(self);
+            }
+        }
+        result
+    }
+
+    fn invert(&self) -> Self {
+        // p - 2
+        let mut exponent = [0xff; 32];
+        exponent[0] = 0xeb;
+        exponent[31] = 0x7f;
+        self.pow(&exponent)
+    }
+
+    /// `self` to the power of (p - 5) / 8, used for square roots
+    fn pow_p58(&self) -> Self {
+        let mut exponent = [0xff; 32];
+        exponent[0] = 0xfd;
+        exponent[31] = 0x0f;
+        self.pow(&exponent)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.to_bytes() == [0; 32]
+    }
+
+    fn is_negative(&self) -> bool {
+        self.to_bytes()[0] & 1 == 1
+    }
+
+    fn eq(&self, other: &Self) -> bool {
+        self.to_bytes() == other.to_bytes()
+    }
+}
+
+/// Point of the twisted Edwards curve in extended coordinates
+/// (x, y) = (X / Z, Y / Z), T = X * Y / Z
+#[derive(Debug, Clone, Copy)]
+struct Point {
+    x: FieldElement,
+    y: FieldElement,
+    z: FieldElement,
+    t: FieldElement,
+}
+
+impl Point {
+    const IDENTITY: Self = Self {
+        x: FieldElement::ZERO,
+        y: FieldElement::ONE,
+        z: FieldElement::ONE,
+        t: FieldElement::ZERO,
+    };
+
+    /// The base point B with y = 4 / 5 and a positive x
+    fn base() -> Self {
+        let y = FieldElement::from_u64(4).mul(&FieldElement::from_u64(5).invert());
+        Self::decompress(&y.to_bytes()).unwrap()
+    }
+
+    /// Decodes a point, RFC 8032 5.1.3
+    fn decompress(bytes: &[u8; 32]) -> Option<Self> {
+        let y = FieldElement::from_bytes(bytes);
+        let x_negative = bytes[31] >> 7 == 1;
+        // y must be below p
+        let mut canonical = *bytes;
+        canonical[31] &= 0x7f;
+        if y.to_bytes() != canonical {
+            return None;
+        }
+
+        // x^2 = (y^2 - 1) / (d y^2 + 1) = u / v
+        let y2 = y.square();
+        let u = y2.sub(&FieldElement::ONE);
+        let v = D.mul(&y2).add(&FieldElement::ONE);
+
+        // x = u v^3 (u v^7)^((p - 5) / 8)
+        let v3 = v.square().mul(&v);
+        let v7 = v3.square().mul(&v);
+        let mut x = u.mul(&v3).mul(&u.mul(&v7).pow_p58());
+
+        let vx2 = v.mul(&x.square());
+        if vx2.eq(&u.neg()) {
+            x = x.mul(&SQRT_M1);
+        } else if !vx2.eq(&u) {
+            return None;
+        }
+
+        if x.is_zero() && x_negative {
+            return None;
+        }
+        if x.is_negative() != x_negative {
+            x = x.neg();
+        }
+
+        Some(Self {
+            x,
+            y,
+            z: FieldElement::ONE,
+            t: x.mul(&y),
+        })
+    }
+
+    fn compress(&self) -> [u8; 32] {
+        let z_inv = self.z.invert();
+        let x = self.x.mul(&z_inv);
+        let y = self.y.mul(&z_inv);
+        let mut bytes = y.to_bytes();
+        bytes[31] |= u8::from(x.is_negative()) << 7;
+        bytes
+    }
+
+    /// Unified addition, add-2008-hwcd-3, also works for doubling
+    fn add(&self, other: &Self) -> Self {
+        let a = self.y.sub(&self.x).mul(&other.y.sub(&other.x));
+        let b = self.y.add(&self.x).mul(&other.y.add(&other.x));
+        let c = self.t.mul(&D2).mul(&other.t);
+        let d = self.z.add(&self.z).mul(&other.z);
+        let e = b.sub(&a);
+        let f = d.sub(&c);
+        let g = d.add(&c);
+        let h = b.add(&a);
+
+        Self {
+            x: e.mul(&f),
+            y: g.mul(&h),
+            z: f.mul(&g),
+            t: e.mul(&h),
+        }
+    }
+
+    fn neg(&self) -> Self {
+        Self {
+            x: self.x.neg(),
+            t: self.t.neg(),
+            ..*self
+        }
+    }
+
+    /// Multiplies with the little endian `scalar`
+    fn mul(&self, scalar: &[u8; 32]) -> Self {
+        let mut result = Self::IDENTITY;
+        for bit in (0..256).rev() {
+            result = result.add(&result);
+            if scalar[bit / 8] >> (bit % 8) & 1 == 1 {
+                result = result.add(self);
+            }
+        }
+        result
+    }
+}
+
+/// Scalars modulo the group order L
+mod scalar {
+    /// 2^252 + 27742317777372353535851937790883648493 as little endian limbs
+    const L: [u64; 4] = [
+        0x5812631a5cf5d3ed,
+        0x14def9dea2f79cd6,
+        0x0000000000000000,
+        0x1000000000000000,
+    ];
+
+    fn to_limbs(bytes: &[u8; 32]) -> [u64; 4] {
+        let mut limbs = [0u64; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+            *limb = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        limbs
+    }
+
+    fn less_than_l(limbs: &[u64; 4]) -> bool {
+        for i in (0..4).rev() {
+            if limbs[i] != L[i] {
+                return limbs[i] < L[i];
+            }
+        }
+        false
+    }
+
+    /// Whether the little endian scalar is below L
+    pub fn is_canonical(bytes: &[u8; 32]) -> bool {
+        less_than_l(&to_limbs(bytes))
+    }
+
+    /// Reduces a 512 bit little endian number modulo L
+    pub fn reduce(bytes: &[u8; 64]) -> [u8; 32] {
+        // shifts in one bit at a time, r stays below L < 2^253
+        let mut r = [0u64; 4];
+        for bit in (0..512).rev() {
+            let mut carry = u64::from(bytes[bit / 8] >> (bit % 8) & 1);
+            for limb in r.iter_mut() {
+                let next = *limb >> 63;
+                *limb = *limb << 1 | carry;
+                carry = next;
+            }
+
+            if !less_than_l(&r) {
+                let mut borrow = false;
+                for (limb, l) in r.iter_mut().zip(L) {
+                    let (value, b1) = limb.overflowing_sub(l);
+                    let (value, b2) = value.overflowing_sub(u64::from(borrow));
+                    *limb = value;
+                    borrow = b1 || b2;
+                }
+            }
+        }
+
+        let mut result = [0u8; 32];
+        for (chunk, limb) in result.chunks_exact_mut(8).zip(r) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        result
+    }
+}
@@ -0,0 +1,49 @@
+//! Extended Display Identification Data.
+//!
+//! The bootloader reads the 128 byte base block through VBE/DDC and passes it
+//! on unchanged, only the preferred timing is parsed so far.
+//! https://en.wikipedia.org/wiki/Extended_Display_Identification_Data
+pub const SIZE: usize = 128;
+pub const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+/// Offset of the first detailed timing descriptor, the preferred timing
+const PREFERRED_TIMING: usize = 54;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdidError {
+    InvalidHeader,
+    InvalidChecksum,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Edid {
+    pub data: [u8; SIZE],
+}
+
+impl Edid {
+    /// Checks the header and the checksum of the base block
+    pub fn new(data: [u8; SIZE]) -> Result<Self, EdidError> {
+        if data[..HEADER.len()] != HEADER {
+            return Err(EdidError::InvalidHeader);
+        }
+        // all bytes sum up to 0
+        if data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(EdidError::InvalidChecksum);
+        }
+        Ok(Self { data })
+    }
+
+    /// Width and height of the preferred timing, usually the native
+    /// resolution of the display
+    pub fn native_resolution(&self) -> Option<(u16, u16)> {
+        let timing = &self.data[PREFERRED_TIMING..PREFERRED_TIMING + 18];
+        // a pixel clock of 0 marks a display descriptor instead of a timing
+        if timing[0] == 0 && timing[1] == 0 {
+            return None;
+        }
+        let width = u16::from(timing[2]) | u16::from(timing[4] >> 4) << 8;
+        let height = u16::from(timing[5]) | u16::from(timing[7] >> 4) << 8;
+        Some((width, height))
+    }
+}
@@ -0,0 +1,130 @@
+//! Initial RAM file system archive format.
+//!
+//! The archive is a flat sequence of entries preceded by [`MAGIC`]. Every
+//! entry starts with a 16 byte little endian header:
+//!
+//! | offset | size | field                          |
+//! |--------|------|--------------------------------|
+//! | 0      | 1    | kind (see [`EntryKind`])       |
+//! | 1      | 1    | reserved                       |
+//! | 2      | 2    | path length                    |
+//! | 4      | 4    | reserved                       |
+//! | 8      | 8    | data length                    |
+//!
+//! followed by the path and the file data, each padded to [`ALIGNMENT`]
+//! bytes. Paths are relative, use `/` as separator and directories appear
+//! before their contents. The archive ends with an entry of kind
+//! [`EntryKind::End`].
+use core::str;
+
+pub const MAGIC: [u8; 8] = *b"MOSRAMFS";
+pub const HEADER_SIZE: usize = 16;
+pub const ALIGNMENT: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EntryKind {
+    End = 0,
+    File = 1,
+    Directory = 2,
+}
+
+impl TryFrom<u8> for EntryKind {
+    type Error = ArchiveError;
+
+    fn try_from(value: u8) -> Result<Self, ArchiveError> {
+        match value {
+            0 => Ok(EntryKind::End),
+            1 => Ok(EntryKind::File),
+            2 => Ok(EntryKind::Directory),
+            _ => Err(ArchiveError::InvalidKind),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveError {
+    InvalidMagic,
+    InvalidKind,
+    InvalidPath,
+    Truncated,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    pub kind: EntryKind,
+    pub path: &'a str,
+    pub data: &'a [u8],
+}
+
+pub const fn align_up(value: usize) -> usize {
+    (value + ALIGNMENT - 1) & !(ALIGNMENT - 1)
+}
+
+/// Iterator over the entries of an archive
+pub struct Archive<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Archive<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ArchiveError> {
+        if data.len() < MAGIC.len() || data[..MAGIC.len()] != MAGIC {
+            return Err(ArchiveError::InvalidMagic);
+        }
+        Ok(Self {
+            data,
+            offset: MAGIC.len(),
+            done: false,
+        })
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], ArchiveError> {
+        self.data
+            .get(offset..offset + len)
+            .ok_or(ArchiveError::Truncated)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>, ArchiveError> {
+        let header = self.bytes(self.offset, HEADER_SIZE)?;
+        let kind = EntryKind::try_from(header[0])?;
+        if kind == EntryKind::End {
+            return Ok(None);
+        }
+
+        let path_len = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let data_len = u64::from_le_bytes(header[8..16].try_into().unwrap()) as usize;
+
+        let path_offset = self.offset + HEADER_SIZE;
+        let path = str::from_utf8(self.bytes(path_offset, path_len)?)
+            .map_err(|_| ArchiveError::InvalidPath)?;
+        let data_offset = path_offset + align_up(path_len);
+        let data = self.bytes(data_offset, data_len)?;
+
+        self.offset = data_offset + align_up(data_len);
+        Ok(Some(Entry { kind, path, data }))
+    }
+}
+
+impl<'a> Iterator for Archive<'a> {
+    type Item = Result<Entry<'a>, ArchiveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.next_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(error) => {
+                self.done = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Virtual address space layout of the kernel.
+//!
+//! The kernel lives in the upper half, the lower half is left to processes.
+//! Each zone starts at a PML4 entry boundary and spans whole PML4 entries
+//! (512 GiB each with 4-level paging), so zones never share page tables.
+//!
+//! | Zone              | Start                   | Size    |
+//! |-------------------|-------------------------|---------|
+//! | physical memory   | `0xffff_8000_0000_0000` | 64 TiB  |
+//! | heap              | `0xffff_c000_0000_0000` | 512 GiB |
+//! | vmalloc           | `0xffff_c080_0000_0000` | 16 TiB  |
+//! | MMIO              | `0xffff_d080_0000_0000` | 1 TiB   |
+//! | per-CPU           | `0xffff_d180_0000_0000` | 512 GiB |
+//! | modules           | `0xffff_d200_0000_0000` | 512 GiB |
+//! | recursive mapping | `0xffff_ff00_0000_0000` | 512 GiB |
+//! | boot stack, image | `0xffff_ff80_0000_0000` | 512 GiB |
+use x86_64::memory::{GIB, TIB};
+
+/// A range of virtual addresses reserved for one purpose
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Zone {
+    pub start: u64,
+    pub size: u64,
+}
+
+impl Zone {
+    pub const fn new(start: u64, size: u64) -> Self {
+        Self { start, size }
+    }
+
+    pub const fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    pub const fn contains(&self, address: u64) -> bool {
+        self.start <= address && address < self.end()
+    }
+
+    pub const fn overlaps(&self, other: &Zone) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+/// All of physical memory mapped at a fixed offset
+pub const PHYSICAL_MEMORY: Zone = Zone::new(0xffff_8000_0000_0000, 64 * TIB);
+/// The kernel heap, it never moves
+pub const HEAP: Zone = Zone::new(0xffff_c000_0000_0000, 512 * GIB);
+/// Mappings of the memory manager, e.g. stacks and anonymous memory
+pub const VMALLOC: Zone = Zone::new(0xffff_c080_0000_0000, 16 * TIB);
+/// Device memory remapped into the kernel
+pub const MMIO: Zone = Zone::new(0xffff_d080_0000_0000, TIB);
+/// Data of each CPU
+pub const PER_CPU: Zone = Zone::new(0xffff_d180_0000_0000, 512 * GIB);
+/// Loadable kernel code
+pub const MODULES: Zone = Zone::new(0xffff_d200_0000_0000, 512 * GIB);
+
+/// PML4 entry pointing to the PML4 table itself, covers
+/// `0xffff_ff00_0000_0000` - `0xffff_ff80_0000_0000`
+pub const RECURSIVE_INDEX: u16 = 510;
+/// Top of the stack the bootloader hands over to the kernel
+pub const BOOT_STACK_TOP: u64 = 0xffff_ffff_0000_0000;
+/// Base the kernel image is loaded at
+pub const KERNEL_BASE: u64 = 0xffff_ffff_8000_0000;
@@ -1,7 +1,21 @@
 #![no_std]
+use cmdline::CommandLine;
 use core::ops::{Deref, DerefMut};
+use edid::Edid;
 use x86_64::memory::{MemoryRegion, PhysicalMemoryRegion};
 
+pub mod cmdline;
+pub mod compression;
+pub mod ed25519;
+pub mod edid;
+pub mod initramfs;
+pub mod layout;
+pub mod manifest;
+pub mod pstore;
+pub mod sha512;
+pub mod signature;
+pub mod symbols;
+
 #[derive(Clone, Copy, Debug, Default)]
 #[repr(C)]
 pub enum PixelFormat {
@@ -47,6 +61,45 @@ impl FramebufferInfo {
     }
 }
 
+/// Most graphics modes recorded in [`VideoModes`]
+pub const MAX_VIDEO_MODES: usize = 32;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct VideoMode {
+    /// VBE mode number
+    pub number: u16,
+    pub width: u16,
+    pub height: u16,
+    pub bits_per_pixel: u8,
+}
+
+/// Graphics modes offered by the firmware and the one the bootloader chose
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct VideoModes {
+    modes: [VideoMode; MAX_VIDEO_MODES],
+    len: usize,
+    /// None if the display was left in text mode
+    pub current: Option<VideoMode>,
+}
+
+impl VideoModes {
+    /// Records `mode`, returns false if there is no space left
+    pub fn push(&mut self, mode: VideoMode) -> bool {
+        if self.len == MAX_VIDEO_MODES {
+            return false;
+        }
+        self.modes[self.len] = mode;
+        self.len += 1;
+        true
+    }
+
+    pub fn as_slice(&self) -> &[VideoMode] {
+        &self.modes[..self.len]
+    }
+}
+
 pub struct PhysicalMemoryRegions {
     ptr: *mut PhysicalMemoryRegion,
     len: usize,
@@ -75,22 +128,46 @@ impl DerefMut for PhysicalMemoryRegions {
 pub struct BootInfo {
     pub kernel: PhysicalMemoryRegion,
     pub framebuffer: FramebufferInfo,
+    /// Archive in the format of [`initramfs`], size is 0 if there is none
+    pub initramfs: PhysicalMemoryRegion,
+    /// Kernel symbol table in the format of [`symbols`], size is 0 if there
+    /// is none
+    pub symbols: PhysicalMemoryRegion,
+    /// Empty if the boot partition has no command line
+    pub cmdline: CommandLine,
+    pub video_modes: VideoModes,
+    /// None if the display didn't provide one
+    pub edid: Option<Edid>,
     pub memory_regions: PhysicalMemoryRegions,
     pub physical_memory_offset: u64,
+    /// Index of the PML4 entry that points to the PML4 table itself
+    pub recursive_index: u16,
 }
 
 impl BootInfo {
     pub fn new(
         kernel: PhysicalMemoryRegion,
         framebuffer: FramebufferInfo,
+        initramfs: PhysicalMemoryRegion,
+        symbols: PhysicalMemoryRegion,
+        cmdline: CommandLine,
+        video_modes: VideoModes,
+        edid: Option<Edid>,
         memory_regions: PhysicalMemoryRegions,
         physical_memory_offset: u64,
+        recursive_index: u16,
     ) -> Self {
         Self {
             kernel,
             framebuffer,
+            initramfs,
+            symbols,
+            cmdline,
+            video_modes,
+            edid,
             memory_regions,
             physical_memory_offset,
+            recursive_index,
         }
     }
 }
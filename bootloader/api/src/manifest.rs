@@ -0,0 +1,51 @@
+//! Checksums of the boot files.
+//!
+//! The `manifest` file of the boot partition lists one file per line, its path
+//! within the partition and the CRC32 (IEEE) of its contents in hex:
+//!
+//! ```text
+//! stage3 8f6b2a1c
+//! kernel 0a31d2e4
+//! ```
+//!
+//! Stage2 verifies the files it loaded against it before running any of them.
+pub const FILE_NAME: &str = "manifest";
+
+/// Reversed polynomial of CRC32 (IEEE)
+const POLYNOMIAL: u32 = 0xedb8_8320;
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < table.len() {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Checksum of `path`, None if it isn't listed or the line is invalid
+pub fn lookup(manifest: &str, path: &str) -> Option<u32> {
+    let line = manifest
+        .lines()
+        .find(|line| line.split_whitespace().next() == Some(path))?;
+    let checksum = line.split_whitespace().nth(1)?;
+    u32::from_str_radix(checksum, 16).ok()
+}
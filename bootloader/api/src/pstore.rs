@@ -0,0 +1,42 @@
+//! Crash dump format of the pstore partition.
+//!
+//! The bootloader reserves an MBR partition of type [`PARTITION_TYPE`] and
+//! [`SECTORS`] sectors, zero filled. On panic the kernel writes a record to
+//! the start of it: [`MAGIC`], the length of the text as little endian u64 and
+//! the UTF-8 text of the dump.
+use core::str;
+
+pub const MAGIC: [u8; 8] = *b"MOSCRASH";
+pub const HEADER_SIZE: usize = 16;
+/// Partition type reserved for OS development
+pub const PARTITION_TYPE: u8 = 0x7f;
+pub const SECTOR_SIZE: usize = 512;
+pub const SECTORS: usize = 128;
+pub const SIZE: usize = SECTORS * SECTOR_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PstoreError {
+    /// No crash was recorded
+    Empty,
+    Truncated,
+    InvalidText,
+}
+
+/// Returns the text of the crash dump in `data`
+pub fn parse(data: &[u8]) -> Result<&str, PstoreError> {
+    if data.len() < HEADER_SIZE || data[..MAGIC.len()] != MAGIC {
+        return Err(PstoreError::Empty);
+    }
+
+    let len = u64::from_le_bytes(data[8..16].try_into().unwrap()) as usize;
+    let text = data[HEADER_SIZE..]
+        .get(..len)
+        .ok_or(PstoreError::Truncated)?;
+    str::from_utf8(text).map_err(|_| PstoreError::InvalidText)
+}
+
+/// Writes the header for a text of `len` bytes to the start of `data`
+pub fn write_header(data: &mut [u8], len: usize) {
+    data[..MAGIC.len()].copy_from_slice(&MAGIC);
+    data[8..16].copy_from_slice(&(len as u64).to_le_bytes());
+}
@@ -0,0 +1,39 @@
+//! Kernel signatures.
+//!
+//! The disk image builder signs every kernel with Ed25519 and stores the 64
+//! byte signature next to it as `<kernel>.sig`. Stage4 checks it against the
+//! public key of its [`TrustAnchor`] before loading the kernel. The anchor is a
+//! static of stage4 the builder patches.
+//!
+//! Stage4 and its key are stored on the same partition as the kernels, so
+//! this detects corrupted or mismatched kernels, it doesn't protect against
+//! anyone who can write to the disk.
+pub const SUFFIX: &str = ".sig";
+/// Marks the trust anchor in the stage4 binary
+pub const MAGIC: [u8; 8] = *b"MOSTRUST";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct TrustAnchor {
+    pub magic: [u8; 8],
+    /// All zero if no signing key is configured
+    pub public_key: [u8; 32],
+    /// Non zero to refuse kernels without a valid signature
+    pub require_signature: u8,
+}
+
+impl TrustAnchor {
+    pub const UNSET: Self = Self {
+        magic: MAGIC,
+        public_key: [0; 32],
+        require_signature: 0,
+    };
+
+    pub fn public_key(&self) -> Option<&[u8; 32]> {
+        (self.public_key != [0; 32]).then_some(&self.public_key)
+    }
+
+    pub fn requires_signature(&self) -> bool {
+        self.require_signature != 0
+    }
+}
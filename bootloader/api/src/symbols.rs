@@ -0,0 +1,116 @@
+//! Kernel symbol table format.
+//!
+//! The table starts with [`MAGIC`] followed by the number of symbols as
+//! little endian u64. Then come the symbols sorted by address, each a 24 byte
+//! little endian record:
+//!
+//! | offset | size | field                                  |
+//! |--------|------|----------------------------------------|
+//! | 0      | 8    | address                                |
+//! | 8      | 8    | size, 0 if unknown                     |
+//! | 16     | 4    | name offset relative to the name table |
+//! | 20     | 4    | name length                            |
+//!
+//! The name table with the UTF-8 names follows the records.
+use core::str;
+
+pub const MAGIC: [u8; 8] = *b"MOSKSYMS";
+pub const HEADER_SIZE: usize = 16;
+pub const RECORD_SIZE: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolTableError {
+    InvalidMagic,
+    Truncated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub address: u64,
+    pub size: u64,
+}
+
+impl<'a> Symbol<'a> {
+    /// Whether `address` lies inside of the symbol. Symbols of unknown size
+    /// contain every address up to the next symbol.
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.address && (self.size == 0 || address - self.address < self.size)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable<'a> {
+    records: &'a [u8],
+    names: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, SymbolTableError> {
+        if data.len() < HEADER_SIZE {
+            return Err(SymbolTableError::Truncated);
+        }
+        if data[..MAGIC.len()] != MAGIC {
+            return Err(SymbolTableError::InvalidMagic);
+        }
+
+        let count = u64::from_le_bytes(data[8..16].try_into().unwrap()) as usize;
+        let names_offset = count
+            .checked_mul(RECORD_SIZE)
+            .and_then(|size| size.checked_add(HEADER_SIZE))
+            .filter(|offset| *offset <= data.len())
+            .ok_or(SymbolTableError::Truncated)?;
+
+        Ok(Self {
+            records: &data[HEADER_SIZE..names_offset],
+            names: &data[names_offset..],
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len() / RECORD_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn address(&self, index: usize) -> u64 {
+        let record = &self.records[index * RECORD_SIZE..];
+        u64::from_le_bytes(record[0..8].try_into().unwrap())
+    }
+
+    /// Symbol at `index`, None if its name is out of bounds
+    pub fn get(&self, index: usize) -> Option<Symbol<'a>> {
+        let record = self
+            .records
+            .get(index * RECORD_SIZE..(index + 1) * RECORD_SIZE)?;
+        let size = u64::from_le_bytes(record[8..16].try_into().unwrap());
+        let name_offset = u32::from_le_bytes(record[16..20].try_into().unwrap()) as usize;
+        let name_len = u32::from_le_bytes(record[20..24].try_into().unwrap()) as usize;
+
+        let name = self.names.get(name_offset..name_offset + name_len)?;
+        Some(Symbol {
+            name: str::from_utf8(name).ok()?,
+            address: self.address(index),
+            size,
+        })
+    }
+
+    /// Symbol containing `address`
+    pub fn lookup(&self, address: u64) -> Option<Symbol<'a>> {
+        // index of the first symbol above address
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.address(mid) <= address {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        let symbol = self.get(low.checked_sub(1)?)?;
+        symbol.contains(address).then_some(symbol)
+    }
+}
@@ -12,6 +12,77 @@ impl BiosBoot {
         }
     }
 
+    /// Includes the contents of `dir` as initramfs in the disk image
+    pub fn set_initramfs(&mut self, dir: &Path) -> &mut Self {
+        self.builder.set_initramfs(dir);
+        self
+    }
+
+    /// Sets the command line passed to the kernel
+    pub fn set_cmdline(&mut self, cmdline: &str) -> &mut Self {
+        self.builder.set_cmdline(cmdline);
+        self
+    }
+
+    /// Requests the display mode closest to the given one
+    pub fn set_video_mode(&mut self, width: u16, height: u16, depth: u8) -> &mut Self {
+        self.builder.set_video_mode(width, height, depth);
+        self
+    }
+
+    /// Keeps the display in text mode
+    pub fn set_text_mode(&mut self) -> &mut Self {
+        self.builder.set_text_mode();
+        self
+    }
+
+    /// Copies `path` into the boot partition as `name`
+    pub fn add_file(&mut self, name: &str, path: &Path) -> &mut Self {
+        self.builder.add_file(name, path);
+        self
+    }
+
+    /// Adds a kernel to the boot menu
+    pub fn add_boot_entry(&mut self, label: &str, kernel: &Path, cmdline: &str) -> &mut Self {
+        self.builder.add_boot_entry(label, kernel, cmdline);
+        self
+    }
+
+    /// Seconds the boot menu waits before booting the first entry
+    pub fn set_boot_menu_timeout(&mut self, seconds: u32) -> &mut Self {
+        self.builder.set_boot_menu_timeout(seconds);
+        self
+    }
+
+    /// Signs the kernels with the Ed25519 private key at `key`
+    pub fn set_signing_key(&mut self, key: &Path) -> &mut Self {
+        self.builder.set_signing_key(key);
+        self
+    }
+
+    /// Makes stage4 refuse kernels without a valid signature
+    pub fn require_signed_kernel(&mut self, require: bool) -> &mut Self {
+        self.builder.require_signed_kernel(require);
+        self
+    }
+
+    /// Stores the kernels compressed
+    pub fn compress_kernel(&mut self, compress: bool) -> &mut Self {
+        self.builder.compress_kernel(compress);
+        self
+    }
+
+    pub fn set_volume_label(&mut self, label: &str) -> &mut Self {
+        self.builder.set_volume_label(label);
+        self
+    }
+
+    /// Sets the size of the boot partition in bytes
+    pub fn set_partition_size(&mut self, size: u64) -> &mut Self {
+        self.builder.set_partition_size(size);
+        self
+    }
+
     pub fn create_disk_image(&self, out_path: &Path) {
         self.builder.create_bios_image(out_path)
     }
@@ -0,0 +1,119 @@
+//! Kernel compression.
+//!
+//! The format is described in `api::compression`, stage4 decompresses the
+//! kernel before loading it.
+use anyhow::{Context, Result};
+use std::{fs, path::Path};
+
+const MAGIC: &[u8; 8] = b"MOSLZSS\0";
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = MIN_MATCH + u8::MAX as usize;
+const WINDOW_SIZE: usize = u16::MAX as usize;
+/// Candidates checked per position, trades speed for ratio
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 16;
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+    (value.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Earlier positions with the same hash of the next [`MIN_MATCH`] bytes
+struct HashChains<'a> {
+    data: &'a [u8],
+    /// Most recent position of each hash
+    head: Vec<usize>,
+    /// Previous position with the same hash of each position
+    prev: Vec<usize>,
+}
+
+impl<'a> HashChains<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            prev: vec![usize::MAX; data.len()],
+        }
+    }
+
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH <= self.data.len() {
+            let h = hash(&self.data[pos..]);
+            self.prev[pos] = self.head[h];
+            self.head[h] = pos;
+        }
+    }
+
+    /// Longest match within the window as (length, distance)
+    fn longest_match(&self, pos: usize) -> (usize, usize) {
+        let data = self.data;
+        let (mut best_len, mut best_distance) = (0, 0);
+        if pos + MIN_MATCH > data.len() {
+            return (best_len, best_distance);
+        }
+
+        let max_len = MAX_MATCH.min(data.len() - pos);
+        let mut candidate = self.head[hash(&data[pos..])];
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX || pos - candidate > WINDOW_SIZE {
+                break;
+            }
+            let len = data[candidate..]
+                .iter()
+                .zip(&data[pos..pos + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best_len {
+                (best_len, best_distance) = (len, pos - candidate);
+                if len == max_len {
+                    break;
+                }
+            }
+            candidate = self.prev[candidate];
+        }
+        (best_len, best_distance)
+    }
+}
+
+/// Greedy LZSS
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&(data.len() as u64).to_le_bytes());
+
+    let mut chains = HashChains::new(data);
+
+    let mut pos = 0;
+    let mut flags_index = 0;
+    let mut tokens = 8;
+    while pos < data.len() {
+        if tokens == 8 {
+            flags_index = out.len();
+            out.push(0);
+            tokens = 0;
+        }
+
+        let (len, distance) = chains.longest_match(pos);
+        if len >= MIN_MATCH {
+            out[flags_index] |= 1 << tokens;
+            out.extend_from_slice(&(distance as u16).to_le_bytes());
+            out.push((len - MIN_MATCH) as u8);
+            for i in pos..pos + len {
+                chains.insert(i);
+            }
+            pos += len;
+        } else {
+            out.push(data[pos]);
+            chains.insert(pos);
+            pos += 1;
+        }
+        tokens += 1;
+    }
+    out
+}
+
+/// Writes the compressed file at `path` to `out_path`
+pub(crate) fn compress_file(path: &Path, out_path: &Path) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    fs::write(out_path, compress(&data)).context("Failed to write compressed file")
+}
@@ -0,0 +1,87 @@
+//! Packs a directory into an initramfs archive.
+//!
+//! The format is described in `api::initramfs`, which contains the parser the
+//! kernel uses.
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+const MAGIC: &[u8; 8] = b"MOSRAMFS";
+const ALIGNMENT: usize = 8;
+
+const KIND_END: u8 = 0;
+const KIND_FILE: u8 = 1;
+const KIND_DIRECTORY: u8 = 2;
+
+fn write_padded<W: Write>(out: &mut W, data: &[u8]) -> Result<()> {
+    out.write_all(data)?;
+    let padding = (ALIGNMENT - data.len() % ALIGNMENT) % ALIGNMENT;
+    out.write_all(&[0u8; ALIGNMENT][..padding])?;
+    Ok(())
+}
+
+fn write_entry<W: Write>(out: &mut W, kind: u8, path: &str, data: &[u8]) -> Result<()> {
+    let path_len: u16 = path
+        .len()
+        .try_into()
+        .map_err(|_| anyhow!("Initramfs path too long: {}", path))?;
+
+    let mut header = [0u8; 16];
+    header[0] = kind;
+    header[2..4].copy_from_slice(&path_len.to_le_bytes());
+    header[8..16].copy_from_slice(&(data.len() as u64).to_le_bytes());
+
+    out.write_all(&header)?;
+    write_padded(out, path.as_bytes())?;
+    write_padded(out, data)
+}
+
+/// Collects all entries below `dir` sorted by path, so that directories come
+/// before their contents
+fn collect_entries(root: &Path, dir: &Path, entries: &mut Vec<PathBuf>) -> Result<()> {
+    let mut children = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read initramfs directory {}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    children.sort();
+
+    for child in children {
+        entries.push(child.strip_prefix(root)?.to_path_buf());
+        if child.is_dir() {
+            collect_entries(root, &child, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Packs the contents of `dir` into an archive at `out_path`
+pub fn create_initramfs(dir: &Path, out_path: &Path) -> Result<()> {
+    let mut entries = Vec::new();
+    collect_entries(dir, dir, &mut entries)?;
+
+    let mut out = fs::File::create(out_path).context("Failed to create initramfs archive")?;
+    out.write_all(MAGIC)?;
+
+    for entry in entries {
+        let path = entry
+            .components()
+            .map(|c| c.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow!("Non UTF-8 initramfs path: {}", entry.display()))?
+            .join("/");
+
+        let full_path = dir.join(&entry);
+        if full_path.is_dir() {
+            write_entry(&mut out, KIND_DIRECTORY, &path, &[])?;
+        } else {
+            let data = fs::read(&full_path)
+                .with_context(|| format!("Failed to read {}", full_path.display()))?;
+            write_entry(&mut out, KIND_FILE, &path, &data)?;
+        }
+    }
+
+    write_entry(&mut out, KIND_END, "", &[])
+}
@@ -5,27 +5,173 @@ use std::{
     env,
     fs::{self, File, OpenOptions},
     io::{self, Read, Seek, SeekFrom, Write},
+    iter,
     path::{Path, PathBuf},
     process::Command,
 };
 use tempfile::NamedTempFile;
 
 const SECTOR_SIZE: u32 = 512;
+const DEFAULT_VOLUME_LABEL: [u8; 11] = *b"MiniatureOs";
+/// Files of the boot partition the bootloader loads itself
+const RESERVED_FILES: [&str; 8] = [
+    "stage3", "stage4", "kernel", "initrd", "ksyms", "cmdline", "bootmenu", "manifest",
+];
+/// Label of the kernel passed to [`DiskImageBuilder::new`] in the boot menu
+const DEFAULT_BOOT_ENTRY_LABEL: &str = "MiniatureOs";
+
+/// Additional kernel of the boot menu
+struct BootEntry {
+    label: String,
+    kernel_path: PathBuf,
+    cmdline: String,
+}
 
 struct DiskImageBuilder {
     kernel_path: PathBuf,
+    initramfs_path: Option<PathBuf>,
+    cmdline: Option<String>,
+    /// Value of the `video=` option
+    video_mode: Option<String>,
+    /// Additional files of the boot partition, by path within the partition
+    files: Vec<(String, PathBuf)>,
+    volume_label: [u8; 11],
+    partition_size: Option<u64>,
+    boot_entries: Vec<BootEntry>,
+    boot_menu_timeout: Option<u32>,
+    /// Ed25519 private key in PEM format the kernels are signed with
+    signing_key: Option<PathBuf>,
+    require_signed_kernel: bool,
+    compress_kernel: bool,
 }
 
 #[cfg(feature = "bios")]
 pub mod bios;
+mod compression;
+pub mod initramfs;
+mod manifest;
+pub mod pstore;
+mod signature;
+pub mod symbols;
 
 impl DiskImageBuilder {
     pub fn new(kernel: &Path) -> Self {
         Self {
             kernel_path: PathBuf::from(kernel),
+            initramfs_path: None,
+            cmdline: None,
+            video_mode: None,
+            files: Vec::new(),
+            volume_label: DEFAULT_VOLUME_LABEL,
+            partition_size: None,
+            boot_entries: Vec::new(),
+            boot_menu_timeout: None,
+            signing_key: None,
+            require_signed_kernel: false,
+            compress_kernel: false,
         }
     }
 
+    /// Packs the contents of `dir` into an initramfs which the kernel unpacks
+    /// at boot
+    pub fn set_initramfs(&mut self, dir: &Path) -> &mut Self {
+        self.initramfs_path = Some(PathBuf::from(dir));
+        self
+    }
+
+    /// Sets the kernel command line, at most `api::cmdline::MAX_LENGTH` bytes
+    /// are passed to the kernel
+    pub fn set_cmdline(&mut self, cmdline: &str) -> &mut Self {
+        self.cmdline = Some(String::from(cmdline));
+        self
+    }
+
+    /// Requests the display mode closest to the given one from the bootloader
+    pub fn set_video_mode(&mut self, width: u16, height: u16, depth: u8) -> &mut Self {
+        self.video_mode = Some(format!("{}x{}x{}", width, height, depth));
+        self
+    }
+
+    /// Keeps the display in text mode, the kernel gets no framebuffer
+    pub fn set_text_mode(&mut self) -> &mut Self {
+        self.video_mode = Some(String::from("text"));
+        self
+    }
+
+    /// Copies `path` into the boot partition as `name`, which may contain
+    /// directories separated by `/`
+    pub fn add_file(&mut self, name: &str, path: &Path) -> &mut Self {
+        self.files.push((String::from(name), PathBuf::from(path)));
+        self
+    }
+
+    /// Sets the FAT volume label, at most 11 bytes
+    pub fn set_volume_label(&mut self, label: &str) -> &mut Self {
+        assert!(
+            label.len() <= self.volume_label.len(),
+            "Volume label {:?} is longer than 11 bytes",
+            label
+        );
+        self.volume_label = [b' '; 11];
+        self.volume_label[..label.len()].copy_from_slice(label.as_bytes());
+        self
+    }
+
+    /// Sets the size of the boot partition in bytes instead of sizing it to
+    /// its contents, e.g. to leave room for files added later
+    pub fn set_partition_size(&mut self, size: u64) -> &mut Self {
+        self.partition_size = Some(size);
+        self
+    }
+
+    /// Adds a kernel to the boot menu of stage2, `cmdline` is appended to the
+    /// command line of the image when it is booted. The menu is only shown if
+    /// there is at least one additional kernel, the first entry boots the
+    /// kernel passed to [`new`](Self::new).
+    pub fn add_boot_entry(&mut self, label: &str, kernel: &Path, cmdline: &str) -> &mut Self {
+        assert!(
+            !label.contains(['|', '\n']) && !cmdline.contains('\n'),
+            "Invalid boot entry {:?}",
+            label
+        );
+        self.boot_entries.push(BootEntry {
+            label: String::from(label),
+            kernel_path: PathBuf::from(kernel),
+            cmdline: String::from(cmdline),
+        });
+        self
+    }
+
+    /// Seconds the boot menu waits for a key press before booting the first
+    /// entry, 0 boots it right away
+    pub fn set_boot_menu_timeout(&mut self, seconds: u32) -> &mut Self {
+        self.boot_menu_timeout = Some(seconds);
+        self
+    }
+
+    /// Signs the kernels with the Ed25519 private key at `key`, a PEM file as
+    /// created by `openssl genpkey -algorithm ed25519`. Stage4 verifies the
+    /// signature against the public key before loading the kernel. The key
+    /// is stored on the boot partition with stage4, this catches corrupted
+    /// or mismatched kernels but is no secure boot.
+    pub fn set_signing_key(&mut self, key: &Path) -> &mut Self {
+        self.signing_key = Some(PathBuf::from(key));
+        self
+    }
+
+    /// Makes stage4 refuse kernels without a valid signature, requires a
+    /// signing key
+    pub fn require_signed_kernel(&mut self, require: bool) -> &mut Self {
+        self.require_signed_kernel = require;
+        self
+    }
+
+    /// Stores the kernels compressed, stage4 decompresses them before loading
+    pub fn compress_kernel(&mut self, compress: bool) -> &mut Self {
+        self.compress_kernel = compress;
+        self
+    }
+
     #[cfg(feature = "bios")]
     pub fn create_bios_image(&self, out_path: &Path) {
         let bios_boot_sector_path = Path::new(env!("BIOS_BOOT_SECTOR_PATH"));
@@ -99,13 +245,154 @@ impl DiskImageBuilder {
         io::copy(&mut second_stage, &mut disk)
             .context("failed to copy second stage binary to MBR disk image")?;
 
-        let fat_files = vec![
+        // the public key is patched into stage4. It's stored on the boot
+        // partition as well, the signatures don't protect against someone
+        // with write access to the disk.
+        let stage4 = NamedTempFile::new().context("Unable to create temp file")?;
+        let fourth_stage_path = match &self.signing_key {
+            Some(key) => {
+                let public_key = signature::public_key(key)?;
+                signature::patch_trust_anchor(
+                    fourth_stage_path,
+                    &public_key,
+                    self.require_signed_kernel,
+                    stage4.path(),
+                )?;
+                stage4.path()
+            }
+            None if self.require_signed_kernel => {
+                return Err(anyhow!(
+                    "Signed kernels are required, but no signing key is set"
+                ))
+            }
+            None => fourth_stage_path,
+        };
+
+        // the kernels as stored in the partition, the one of the image first
+        let kernel_paths: Vec<&Path> = iter::once(self.kernel_path.as_path())
+            .chain(
+                self.boot_entries
+                    .iter()
+                    .map(|entry| entry.kernel_path.as_path()),
+            )
+            .collect();
+        let mut compressed = Vec::new();
+        if self.compress_kernel {
+            for path in &kernel_paths {
+                let file = NamedTempFile::new().context("Unable to create temp file")?;
+                compression::compress_file(path, file.path())?;
+                compressed.push(file);
+            }
+        }
+        let stored_kernels: Vec<&Path> = match self.compress_kernel {
+            true => compressed.iter().map(NamedTempFile::path).collect(),
+            false => kernel_paths,
+        };
+
+        let mut fat_files = vec![
             ("stage3", third_stage_path),
             ("stage4", fourth_stage_path),
-            ("kernel", &self.kernel_path),
+            ("kernel", stored_kernels[0]),
         ];
+
+        // stage2 only understands 8.3 names, hence "initrd"
+        let initramfs = NamedTempFile::new().context("Unable to create temp file")?;
+        if let Some(dir) = &self.initramfs_path {
+            initramfs::create_initramfs(dir, initramfs.path())?;
+            fat_files.push(("initrd", initramfs.path()));
+        }
+
+        // lets the kernel symbolize addresses in backtraces
+        let symbols = NamedTempFile::new().context("Unable to create temp file")?;
+        symbols::create_symbol_table(&self.kernel_path, symbols.path())?;
+        fat_files.push(("ksyms", symbols.path()));
+
+        let cmdline = NamedTempFile::new().context("Unable to create temp file")?;
+        let video = self
+            .video_mode
+            .as_ref()
+            .map(|mode| format!("video={}", mode));
+        let options: Vec<&str> = video
+            .iter()
+            .chain(&self.cmdline)
+            .map(String::as_str)
+            .collect();
+        if !options.is_empty() {
+            fs::write(cmdline.path(), options.join(" ")).context("Failed to write command line")?;
+            fat_files.push(("cmdline", cmdline.path()));
+        }
+
+        let bootmenu = NamedTempFile::new().context("Unable to create temp file")?;
+        let kernel_names: Vec<String> = (1..=self.boot_entries.len())
+            .map(|i| format!("kernel{}", i))
+            .collect();
+        if !self.boot_entries.is_empty() {
+            let mut menu = String::new();
+            if let Some(timeout) = self.boot_menu_timeout {
+                menu += &format!("timeout={}\n", timeout);
+            }
+            menu += &format!("entry={}|kernel|\n", DEFAULT_BOOT_ENTRY_LABEL);
+            for ((entry, name), path) in self
+                .boot_entries
+                .iter()
+                .zip(&kernel_names)
+                .zip(&stored_kernels[1..])
+            {
+                menu += &format!("entry={}|{}|{}\n", entry.label, name, entry.cmdline);
+                fat_files.push((name.as_str(), path));
+            }
+            fs::write(bootmenu.path(), menu).context("Failed to write boot menu")?;
+            fat_files.push(("bootmenu", bootmenu.path()));
+        }
+
+        // lets stage2 detect corrupted stages and kernels before running them
+        let manifest = NamedTempFile::new().context("Unable to create temp file")?;
+        let verified: Vec<(&str, &Path)> = fat_files
+            .iter()
+            .filter(|(name, _)| name.starts_with("stage") || name.starts_with("kernel"))
+            .copied()
+            .collect();
+        manifest::create_manifest(&verified, manifest.path())?;
+        fat_files.push((manifest::FILE_NAME, manifest.path()));
+
+        // signs the kernels as stored, stage4 verifies before decompressing
+        let kernels: Vec<(String, &Path)> = iter::once(String::from("kernel"))
+            .chain(kernel_names.iter().cloned())
+            .zip(stored_kernels.iter().copied())
+            .collect();
+        let signature_names: Vec<String> = kernels
+            .iter()
+            .map(|(name, _)| format!("{}{}", name, signature::SUFFIX))
+            .collect();
+        let mut signatures = Vec::new();
+        if let Some(key) = &self.signing_key {
+            for (_, path) in &kernels {
+                let signature = NamedTempFile::new().context("Unable to create temp file")?;
+                signature::sign(key, path, signature.path())?;
+                signatures.push(signature);
+            }
+        }
+        for (name, signature) in signature_names.iter().zip(&signatures) {
+            fat_files.push((name, signature.path()));
+        }
+
+        for (name, path) in &self.files {
+            if kernel_names.contains(name) || signature_names.contains(name) {
+                return Err(anyhow!("{} is reserved for the boot menu", name));
+            }
+            if RESERVED_FILES.contains(&name.as_str()) {
+                return Err(anyhow!("{} is reserved for the bootloader", name));
+            }
+            fat_files.push((name, path));
+        }
+
         let mut boot_partition = NamedTempFile::new().context("Unable to create temp file")?;
-        create_fat_filesystem(fat_files, boot_partition.path())?;
+        create_fat_filesystem(
+            fat_files,
+            self.volume_label,
+            self.partition_size,
+            boot_partition.path(),
+        )?;
 
         let boot_partition_len = boot_partition
             .as_file()
@@ -137,12 +424,36 @@ impl DiskImageBuilder {
         io::copy(&mut boot_partition, &mut disk)
             .context("failed to copy second stage binary to MBR disk image")?;
 
+        // zero filled, the kernel writes crash dumps to it
+        let pstore_start_sector = boot_partition_start_sector + boot_partition_sectors;
+        mbr[3] = mbrman::MBRPartitionEntry {
+            boot: mbrman::BOOT_INACTIVE,
+            starting_lba: pstore_start_sector,
+            sectors: pstore::SECTORS,
+            sys: pstore::PARTITION_TYPE,
+            first_chs: mbrman::CHS::empty(),
+            last_chs: mbrman::CHS::empty(),
+        };
+
+        mbr.write_into(&mut disk)
+            .context("Writing pstore partition info to mbr failed")?;
+
+        disk.set_len(u64::from(
+            (pstore_start_sector + pstore::SECTORS) * SECTOR_SIZE,
+        ))
+        .context("Failed to reserve pstore partition")?;
+
         Ok(())
     }
 }
 
 #[cfg(feature = "bios")]
-fn create_fat_filesystem(files: Vec<(&str, &Path)>, out_path: &Path) -> Result<()> {
+fn create_fat_filesystem(
+    files: Vec<(&str, &Path)>,
+    volume_label: [u8; 11],
+    size: Option<u64>,
+    out_path: &Path,
+) -> Result<()> {
     let mut fat_file = fs::OpenOptions::new()
         .read(true)
         .write(true)
@@ -157,13 +468,24 @@ fn create_fat_filesystem(files: Vec<(&str, &Path)>, out_path: &Path) -> Result<(
     }
     const MB: u64 = 1024 * 1024;
     let fat_size_padded_and_rounded = ((needed_size + 1024 * 64 - 1) / MB + 1) * MB + MB;
+    let fat_size = match size {
+        Some(size) if size < needed_size => {
+            return Err(anyhow!(
+                "Boot partition of {:#x} bytes is too small for {:#x} bytes of files",
+                size,
+                needed_size
+            ))
+        }
+        Some(size) => size,
+        None => fat_size_padded_and_rounded,
+    };
 
     fat_file
-        .set_len(fat_size_padded_and_rounded)
+        .set_len(fat_size)
         .context("Failed to set fat file length")?;
 
     // FAT type is determined based on total number of clusters
-    let format_options = fatfs::FormatVolumeOptions::new().volume_label(*b"MiniatureOs");
+    let format_options = fatfs::FormatVolumeOptions::new().volume_label(volume_label);
     fatfs::format_volume(&fat_file, format_options).context("Failed tor format volume")?;
     let fs = fatfs::FileSystem::new(&mut fat_file, fatfs::FsOptions::new())
         .context("fatfs::Filesystem new")?;
@@ -171,10 +493,16 @@ fn create_fat_filesystem(files: Vec<(&str, &Path)>, out_path: &Path) -> Result<(
     let root_dir = fs.root_dir();
 
     for (name, path) in files.iter() {
-        let mut src_file = fs::File::open(path).context("Failed to open stage file")?;
-        let mut dest_file = root_dir
-            .create_file(name)
-            .context("Failed to create file in FAT root")?;
+        let mut src_file =
+            fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+        let (dir, file_name) = match name.rsplit_once('/') {
+            Some((dir, file_name)) => (create_fat_dirs(&root_dir, dir)?, file_name),
+            None => (root_dir.clone(), *name),
+        };
+        let mut dest_file = dir
+            .create_file(file_name)
+            .with_context(|| format!("Failed to create {} in FAT partition", name))?;
 
         dest_file.truncate()?;
 
@@ -183,3 +511,18 @@ fn create_fat_filesystem(files: Vec<(&str, &Path)>, out_path: &Path) -> Result<(
 
     Ok(())
 }
+
+/// Creates the directories of `path` below `root`, returns the last one
+#[cfg(feature = "bios")]
+fn create_fat_dirs<'a, IO: fatfs::ReadWriteSeek>(
+    root: &fatfs::Dir<'a, IO>,
+    path: &str,
+) -> Result<fatfs::Dir<'a, IO>> {
+    let mut dir = root.clone();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        dir = dir
+            .create_dir(component)
+            .with_context(|| format!("Failed to create directory {} in FAT partition", path))?;
+    }
+    Ok(dir)
+}
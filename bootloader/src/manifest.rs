@@ -0,0 +1,37 @@
+//! Checksums of the boot files.
+//!
+//! The format is described in `api::manifest`, stage2 verifies the files it
+//! loads against it.
+use anyhow::{Context, Result};
+use std::{fmt::Write, fs, path::Path};
+
+pub(crate) const FILE_NAME: &str = "manifest";
+
+/// Reversed polynomial of CRC32 (IEEE)
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Writes the checksums of `files`, given by their path within the boot
+/// partition, to `out_path`
+pub(crate) fn create_manifest(files: &[(&str, &Path)], out_path: &Path) -> Result<()> {
+    let mut manifest = String::new();
+    for (name, path) in files {
+        let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        writeln!(manifest, "{} {:08x}", name, crc32(&data))?;
+    }
+    fs::write(out_path, manifest).context("Failed to write manifest")
+}
@@ -0,0 +1,43 @@
+//! Reserved partition the kernel writes crash dumps to.
+//!
+//! The format is described in `api::pstore`.
+use anyhow::{Context, Result};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+const MAGIC: &[u8; 8] = b"MOSCRASH";
+const HEADER_SIZE: usize = 16;
+pub(crate) const PARTITION_TYPE: u8 = 0x7f;
+pub(crate) const SECTORS: u32 = 128;
+
+/// Reads the crash dump the kernel left in the disk image at `image`, None if
+/// the kernel didn't crash
+pub fn read_crash_dump(image: &Path) -> Result<Option<String>> {
+    let mut disk = File::open(image).context("Failed to open disk image")?;
+    let mbr =
+        mbrman::MBR::read_from(&mut disk, crate::SECTOR_SIZE).context("Failed to read mbr")?;
+    let (_, partition) = mbr
+        .iter()
+        .find(|(_, p)| p.sys == PARTITION_TYPE)
+        .context("Disk image has no pstore partition")?;
+
+    let mut data = vec![0; (partition.sectors * crate::SECTOR_SIZE) as usize];
+    disk.seek(SeekFrom::Start(
+        u64::from(partition.starting_lba) * u64::from(crate::SECTOR_SIZE),
+    ))
+    .context("seek failed")?;
+    disk.read_exact(&mut data)
+        .context("Failed to read pstore partition")?;
+
+    if &data[..MAGIC.len()] != MAGIC {
+        return Ok(None);
+    }
+    let len = u64::from_le_bytes(data[8..16].try_into().unwrap()) as usize;
+    let text = data[HEADER_SIZE..]
+        .get(..len)
+        .context("Crash dump is truncated")?;
+    Ok(Some(String::from_utf8_lossy(text).into_owned()))
+}
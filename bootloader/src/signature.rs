@@ -0,0 +1,93 @@
+//! Kernel signing.
+//!
+//! The format is described in `api::signature`. Kernels are signed with an
+//! Ed25519 private key in PEM format through `openssl`, e.g. one created with
+//! `openssl genpkey -algorithm ed25519 -out key.pem`.
+use anyhow::{anyhow, Context, Result};
+use std::{ffi::OsStr, fs, path::Path, process::Command};
+
+pub(crate) const SUFFIX: &str = ".sig";
+const MAGIC: &[u8; 8] = b"MOSTRUST";
+const PUBLIC_KEY_SIZE: usize = 32;
+/// Magic, public key and policy flag of the unpatched trust anchor
+const ANCHOR_SIZE: usize = MAGIC.len() + PUBLIC_KEY_SIZE + 1;
+
+fn openssl<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(args: I) -> Result<Vec<u8>> {
+    let output = Command::new("openssl")
+        .args(args)
+        .output()
+        .context("Failed to run openssl")?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "openssl failed with exit code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Raw public key of the private key at `key`
+pub(crate) fn public_key(key: &Path) -> Result<[u8; PUBLIC_KEY_SIZE]> {
+    let der = openssl([
+        OsStr::new("pkey"),
+        OsStr::new("-in"),
+        key.as_os_str(),
+        OsStr::new("-pubout"),
+        OsStr::new("-outform"),
+        OsStr::new("DER"),
+    ])?;
+
+    // SubjectPublicKeyInfo of Ed25519, the key is the last 32 bytes
+    if der.len() != 44 {
+        return Err(anyhow!("{} is not an Ed25519 key", key.display()));
+    }
+    Ok(der[der.len() - PUBLIC_KEY_SIZE..].try_into().unwrap())
+}
+
+/// Writes the signature of the file at `path` to `out_path`
+pub(crate) fn sign(key: &Path, path: &Path, out_path: &Path) -> Result<()> {
+    openssl([
+        OsStr::new("pkeyutl"),
+        OsStr::new("-sign"),
+        OsStr::new("-rawin"),
+        OsStr::new("-inkey"),
+        key.as_os_str(),
+        OsStr::new("-in"),
+        path.as_os_str(),
+        OsStr::new("-out"),
+        out_path.as_os_str(),
+    ])
+    .with_context(|| format!("Failed to sign {}", path.display()))?;
+    Ok(())
+}
+
+/// Copies stage4 to `out_path` with the public key and the policy written to
+/// its trust anchor
+pub(crate) fn patch_trust_anchor(
+    stage4: &Path,
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+    require_signature: bool,
+    out_path: &Path,
+) -> Result<()> {
+    let mut binary = fs::read(stage4).context("Failed to read stage4")?;
+
+    let mut unset = [0u8; ANCHOR_SIZE];
+    unset[..MAGIC.len()].copy_from_slice(MAGIC);
+    let mut anchors = binary
+        .windows(ANCHOR_SIZE)
+        .enumerate()
+        .filter(|(_, window)| *window == unset)
+        .map(|(offset, _)| offset);
+    let offset = match (anchors.next(), anchors.next()) {
+        (Some(offset), None) => offset,
+        (None, _) => return Err(anyhow!("No trust anchor in stage4")),
+        (Some(_), Some(_)) => return Err(anyhow!("Multiple trust anchors in stage4")),
+    };
+
+    let key_offset = offset + MAGIC.len();
+    binary[key_offset..key_offset + PUBLIC_KEY_SIZE].copy_from_slice(public_key);
+    binary[key_offset + PUBLIC_KEY_SIZE] = u8::from(require_signature);
+    fs::write(out_path, binary).context("Failed to write stage4")
+}
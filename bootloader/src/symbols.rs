@@ -0,0 +1,85 @@
+//! Extracts the symbol table of the kernel.
+//!
+//! The format is described in `api::symbols`, which contains the lookup the
+//! kernel uses to symbolize backtraces.
+use anyhow::{anyhow, Context, Result};
+use std::{fs, io::Write, path::Path, process::Command};
+
+const MAGIC: &[u8; 8] = b"MOSKSYMS";
+
+struct Symbol {
+    address: u64,
+    size: u64,
+    name: String,
+}
+
+/// Parses a line of `nm --print-size` output. Returns None for symbols that
+/// are not in the text section.
+fn parse_line(line: &str) -> Option<Symbol> {
+    let (address, rest) = line.split_once(' ')?;
+    let address = u64::from_str_radix(address, 16).ok()?;
+
+    // symbols without size only have a type in front of the name
+    let (size, rest) = match rest.split_once(' ')? {
+        (size, rest) if size.len() > 1 => (u64::from_str_radix(size, 16).ok()?, rest),
+        _ => (0, rest),
+    };
+
+    let (kind, name) = rest.split_once(' ')?;
+    matches!(kind, "t" | "T").then(|| Symbol {
+        address,
+        size,
+        name: name.to_string(),
+    })
+}
+
+fn read_symbols(kernel: &Path) -> Result<Vec<Symbol>> {
+    let output = Command::new("nm")
+        .args([
+            "--defined-only",
+            "--print-size",
+            "--numeric-sort",
+            "--demangle",
+        ])
+        .arg(kernel)
+        .output()
+        .context("Failed to run nm")?;
+
+    if !output.status.success() {
+        return Err(anyhow!("nm failed with exit code {}", output.status));
+    }
+
+    let mut symbols: Vec<Symbol> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(parse_line)
+        .collect();
+    symbols.sort_by_key(|s| s.address);
+    symbols.dedup_by_key(|s| s.address);
+    Ok(symbols)
+}
+
+/// Writes the table of all functions of the ELF file `kernel` to `out_path`
+pub fn create_symbol_table(kernel: &Path, out_path: &Path) -> Result<()> {
+    let symbols = read_symbols(kernel)?;
+
+    let mut records = Vec::with_capacity(symbols.len() * 24);
+    let mut names = Vec::new();
+    for symbol in &symbols {
+        let name_offset: u32 = names
+            .len()
+            .try_into()
+            .context("Symbol name table too large")?;
+        records.extend_from_slice(&symbol.address.to_le_bytes());
+        records.extend_from_slice(&symbol.size.to_le_bytes());
+        records.extend_from_slice(&name_offset.to_le_bytes());
+        records.extend_from_slice(&(symbol.name.len() as u32).to_le_bytes());
+        names.extend_from_slice(symbol.name.as_bytes());
+    }
+
+    let mut out = fs::File::create(out_path).context("Failed to create symbol table")?;
+    out.write_all(MAGIC)?;
+    out.write_all(&(symbols.len() as u64).to_le_bytes())?;
+    out.write_all(&records)?;
+    out.write_all(&names)?;
+    Ok(())
+}
@@ -1,6 +1,6 @@
 #![no_std]
 #![no_main]
-use api::FramebufferInfo;
+use api::{edid::Edid, FramebufferInfo, VideoModes};
 use core::{arch::asm, mem::size_of};
 use x86_64::memory::{MemoryRegion, PhysicalMemoryRegion, PhysicalMemoryRegionType};
 
@@ -25,12 +25,23 @@ pub extern "C" fn fail(code: u8) -> ! {
     panic!("Fail called with code: {:x}", code);
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 #[repr(C)]
 pub struct BiosInfo {
+    pub stage3: PhysicalMemoryRegion,
     pub stage4: PhysicalMemoryRegion,
     pub kernel: PhysicalMemoryRegion,
     pub framebuffer: FramebufferInfo,
+    /// Size is 0 if there is no initramfs
+    pub initramfs: PhysicalMemoryRegion,
+    /// Size is 0 if there is no kernel symbol table
+    pub symbols: PhysicalMemoryRegion,
+    /// Size is 0 if there is no kernel command line
+    pub cmdline: PhysicalMemoryRegion,
+    /// Size is 0 if the kernel isn't signed
+    pub kernel_signature: PhysicalMemoryRegion,
+    pub video_modes: VideoModes,
+    pub edid: Option<Edid>,
     pub last_physical_address: u64,
     // cant pass a pointer here since it will be corrupted when switching
     // from protected to long mode because pointer size differs
@@ -40,18 +51,32 @@ pub struct BiosInfo {
 
 impl BiosInfo {
     pub fn new(
+        stage3: PhysicalMemoryRegion,
         stage4: PhysicalMemoryRegion,
         kernel: PhysicalMemoryRegion,
         framebuffer: FramebufferInfo,
+        initramfs: PhysicalMemoryRegion,
+        symbols: PhysicalMemoryRegion,
+        cmdline: PhysicalMemoryRegion,
+        kernel_signature: PhysicalMemoryRegion,
+        video_modes: VideoModes,
+        edid: Option<Edid>,
         last_physical_address: u64,
         // cant use arr because I dont know how many mem regions there are
         memory_map_address: u64,
         memory_map_size: u64,
     ) -> BiosInfo {
         Self {
+            stage3,
             stage4,
             kernel,
             framebuffer,
+            initramfs,
+            symbols,
+            cmdline,
+            kernel_signature,
+            video_modes,
+            edid,
             last_physical_address,
             memory_map_address,
             memory_map_size,
@@ -76,11 +101,25 @@ impl Into<PhysicalMemoryRegionType> for E820MemoryRegionType {
     fn into(self) -> PhysicalMemoryRegionType {
         match self {
             E820MemoryRegionType::Normal => PhysicalMemoryRegionType::Free,
+            E820MemoryRegionType::AcpiReclaimable => PhysicalMemoryRegionType::AcpiReclaimable,
             _ => PhysicalMemoryRegionType::Reserved,
         }
     }
 }
 
+impl memory_map::Kind for E820MemoryRegionType {
+    /// The more restrictive type wins where regions overlap
+    fn precedence(&self) -> u8 {
+        match self {
+            E820MemoryRegionType::Normal => 0,
+            E820MemoryRegionType::AcpiReclaimable => 1,
+            E820MemoryRegionType::AcpiNvs => 2,
+            E820MemoryRegionType::None | E820MemoryRegionType::Reserved => 3,
+            E820MemoryRegionType::Unusable => 4,
+        }
+    }
+}
+
 /// Memory information returned by BIOS 0xe820 command
 #[derive(Default, Clone, Copy, Debug)]
 #[repr(C)]
@@ -103,6 +142,32 @@ impl E820MemoryRegion {
     }
 }
 
+impl memory_map::Region for E820MemoryRegion {
+    type Kind = E820MemoryRegionType;
+
+    /// Normalized regions are always enabled
+    fn with_range(start: u64, end: u64, typ: E820MemoryRegionType) -> Self {
+        Self {
+            start,
+            size: end - start,
+            typ,
+            acpi_extended_attributes: 0x1,
+        }
+    }
+
+    fn region_start(&self) -> u64 {
+        self.start
+    }
+
+    fn region_end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    fn kind(&self) -> E820MemoryRegionType {
+        self.typ
+    }
+}
+
 impl Into<PhysicalMemoryRegion> for E820MemoryRegion {
     fn into(self) -> PhysicalMemoryRegion {
         PhysicalMemoryRegion::new(self.start, self.size, self.typ.into())
@@ -144,3 +209,10 @@ impl MemoryRegion for E820MemoryRegion {
         self.typ == E820MemoryRegionType::Normal
     }
 }
+
+/// Writes the memory map reported by the BIOS to `out`, normalized as
+/// described in [`memory_map`]. Returns the amount of regions in `out`, the
+/// highest regions are dropped if it is too small.
+pub fn normalize_memory_map(regions: &[E820MemoryRegion], out: &mut [E820MemoryRegion]) -> usize {
+    memory_map::normalize(regions, out).unwrap_or(out.len())
+}
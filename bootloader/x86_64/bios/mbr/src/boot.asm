@@ -24,6 +24,8 @@ _start:
 # https://wiki.osdev.org/A20_Line
 # need to enable A20 (address line 20) line to access more than 1MB of memory
 # represents 21st bit of any memory access
+# Only Fast A20 fits in here, stage2 verifies the line and falls back to the
+# BIOS and the keyboard controller
 enable_a20:
     in al, 0x92
     test al, 0x2
@@ -0,0 +1,150 @@
+//! A20 line.
+//!
+//! With the A20 line disabled, address bit 20 is forced to 0, so accesses
+//! above 1 MiB wrap around. Stage1 only tries Fast A20, which not every
+//! chipset supports. Stage2 tries all known methods from the least to the
+//! most invasive one and verifies each of them, since it loads stage3 and the
+//! kernel above 1 MiB.
+//! https://wiki.osdev.org/A20_Line
+use crate::println;
+use core::{arch::asm, ptr};
+use x86_64::port::{io_wait, Port};
+
+/// Free conventional memory and its alias if address bit 20 is masked.
+/// Requires unreal mode to access the alias.
+const LOW: *mut u32 = 0x0000_0500 as *mut u32;
+const HIGH: *mut u32 = 0x0010_0500 as *mut u32;
+
+/// Changing the gate through the keyboard controller can take a while
+const VERIFY_ATTEMPTS: usize = 1000;
+const KBC_TIMEOUT: usize = 100_000;
+
+const KBC_DATA: Port<u8> = Port::new(0x60);
+const KBC_STATUS: Port<u8> = Port::new(0x64);
+const KBC_COMMAND: Port<u8> = Port::new(0x64);
+const KBC_OUTPUT_FULL: u8 = 1 << 0;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_DISABLE_KEYBOARD: u8 = 0xad;
+const KBC_ENABLE_KEYBOARD: u8 = 0xae;
+const KBC_READ_OUTPUT_PORT: u8 = 0xd0;
+const KBC_WRITE_OUTPUT_PORT: u8 = 0xd1;
+const KBC_OUTPUT_PORT_A20: u8 = 1 << 1;
+
+const SYSTEM_CONTROL_PORT_A: Port<u8> = Port::new(0x92);
+const FAST_A20: u8 = 1 << 1;
+/// Writing 1 resets the machine
+const FAST_RESET: u8 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum A20Method {
+    /// Enabled by the firmware
+    AlreadyEnabled,
+    Bios,
+    KeyboardController,
+    FastA20,
+}
+
+/// Enables the A20 line, `None` if no method worked
+pub fn enable() -> Option<A20Method> {
+    if is_enabled() {
+        return Some(A20Method::AlreadyEnabled);
+    }
+
+    let methods: [(A20Method, fn() -> bool); 3] = [
+        (A20Method::Bios, enable_bios),
+        (A20Method::KeyboardController, enable_keyboard_controller),
+        (A20Method::FastA20, enable_fast_a20),
+    ];
+    for (method, enable) in methods {
+        if enable() && wait_enabled() {
+            return Some(method);
+        }
+        println!("A20: {:?} failed", method);
+    }
+    None
+}
+
+/// Checks whether writes to [`LOW`] show up at [`HIGH`]
+pub fn is_enabled() -> bool {
+    unsafe {
+        let low = ptr::read_volatile(LOW);
+        let high = ptr::read_volatile(HIGH);
+
+        ptr::write_volatile(LOW, 0x1234_5678);
+        ptr::write_volatile(HIGH, !0x1234_5678);
+        let enabled = ptr::read_volatile(LOW) == 0x1234_5678;
+
+        // restores LOW last, HIGH is LOW if the line is disabled
+        ptr::write_volatile(HIGH, high);
+        ptr::write_volatile(LOW, low);
+        enabled
+    }
+}
+
+fn wait_enabled() -> bool {
+    (0..VERIFY_ATTEMPTS).any(|_| {
+        io_wait();
+        is_enabled()
+    })
+}
+
+/// INT 15h AX=2401h, supported by most BIOSes since the PS/2
+fn enable_bios() -> bool {
+    let failed: u8;
+    unsafe {
+        asm!(
+            "int 0x15",
+            "setc {failed}",
+            failed = out(reg_byte) failed,
+            inout("ax") 0x2401u16 => _,
+        );
+    }
+    failed == 0
+}
+
+/// Sets the A20 bit of the output port of the 8042 keyboard controller
+fn enable_keyboard_controller() -> bool {
+    let enable = || -> Option<()> {
+        kbc_command(KBC_DISABLE_KEYBOARD)?;
+        kbc_command(KBC_READ_OUTPUT_PORT)?;
+        let output_port = kbc_read()?;
+        kbc_command(KBC_WRITE_OUTPUT_PORT)?;
+        kbc_write(output_port | KBC_OUTPUT_PORT_A20)
+    };
+    let result = enable();
+    // the boot menu still needs the keyboard
+    kbc_command(KBC_ENABLE_KEYBOARD);
+    result.is_some()
+}
+
+/// Sets the A20 bit of system control port A, doesn't exist on all chipsets
+fn enable_fast_a20() -> bool {
+    let value = SYSTEM_CONTROL_PORT_A.read();
+    if value & FAST_A20 == 0 {
+        SYSTEM_CONTROL_PORT_A.write((value | FAST_A20) & !FAST_RESET);
+    }
+    true
+}
+
+fn kbc_wait(ready: impl Fn(u8) -> bool) -> Option<()> {
+    (0..KBC_TIMEOUT)
+        .any(|_| ready(KBC_STATUS.read()))
+        .then_some(())
+}
+
+fn kbc_command(command: u8) -> Option<()> {
+    kbc_wait(|status| status & KBC_INPUT_FULL == 0)?;
+    KBC_COMMAND.write(command);
+    Some(())
+}
+
+fn kbc_write(value: u8) -> Option<()> {
+    kbc_wait(|status| status & KBC_INPUT_FULL == 0)?;
+    KBC_DATA.write(value);
+    Some(())
+}
+
+fn kbc_read() -> Option<u8> {
+    kbc_wait(|status| status & KBC_OUTPUT_FULL != 0)?;
+    Some(KBC_DATA.read())
+}
@@ -0,0 +1,248 @@
+//! Boot menu.
+//!
+//! The optional `bootmenu` file of the boot partition lists kernels to choose
+//! from, one option per line:
+//!
+//! ```text
+//! # comment
+//! timeout=5
+//! default=0
+//! entry=MiniatureOs|kernel|log=info
+//! entry=MiniatureOs (debug)|kernel-debug|log=debug
+//! ```
+//!
+//! An entry consists of a label, the path of the kernel within the partition
+//! and an optional command line that is appended to the one of the `cmdline`
+//! file. The menu is selected with the arrow keys and Enter, the default entry
+//! is booted after `timeout` seconds without a key press. A timeout of 0 boots
+//! the default entry without showing the menu.
+use crate::{print, println};
+use core::{arch::asm, ptr, str};
+
+pub const MAX_ENTRIES: usize = 8;
+pub const MAX_FIELD_LENGTH: usize = 64;
+const DEFAULT_TIMEOUT: u32 = 5;
+
+/// Timer ticks since midnight, incremented by the BIOS 18.2 times a second
+const BDA_TICKS: *const u32 = 0x46c as *const u32;
+const TICKS_PER_SECOND: u32 = 18;
+
+const KEY_UP: u8 = 0x48;
+const KEY_DOWN: u8 = 0x50;
+const KEY_ENTER: u8 = 0x1c;
+
+/// Fixed size string, the menu is parsed before the kernel overwrites the
+/// file it was loaded to
+#[derive(Clone, Copy)]
+pub struct Field {
+    data: [u8; MAX_FIELD_LENGTH],
+    len: usize,
+}
+
+impl Field {
+    const fn empty() -> Self {
+        Self {
+            data: [0; MAX_FIELD_LENGTH],
+            len: 0,
+        }
+    }
+
+    /// Copies `s`, truncated to [`MAX_FIELD_LENGTH`] bytes
+    fn new(s: &str) -> Self {
+        let mut len = s.len().min(MAX_FIELD_LENGTH);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut field = Self::empty();
+        field.data[..len].copy_from_slice(&s.as_bytes()[..len]);
+        field.len = len;
+        field
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever filled with valid UTF-8
+        unsafe { str::from_utf8_unchecked(&self.data[..self.len]) }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct BootEntry {
+    pub label: Field,
+    pub kernel: Field,
+    pub cmdline: Field,
+}
+
+impl BootEntry {
+    const fn empty() -> Self {
+        Self {
+            label: Field::empty(),
+            kernel: Field::empty(),
+            cmdline: Field::empty(),
+        }
+    }
+}
+
+pub struct BootMenu {
+    entries: [BootEntry; MAX_ENTRIES],
+    len: usize,
+    default: usize,
+    /// Seconds until the default entry is booted
+    timeout: u32,
+}
+
+impl BootMenu {
+    /// Parses the menu file, invalid lines and entries beyond
+    /// [`MAX_ENTRIES`] are skipped
+    pub fn parse(text: &str) -> Self {
+        let mut menu = Self {
+            entries: [BootEntry::empty(); MAX_ENTRIES],
+            len: 0,
+            default: 0,
+            timeout: DEFAULT_TIMEOUT,
+        };
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                println!("Boot menu: invalid line: {}", line);
+                continue;
+            };
+            match key.trim() {
+                "timeout" => match value.trim().parse() {
+                    Ok(timeout) => menu.timeout = timeout,
+                    Err(_) => println!("Boot menu: invalid timeout: {}", value),
+                },
+                "default" => match value.trim().parse() {
+                    Ok(default) => menu.default = default,
+                    Err(_) => println!("Boot menu: invalid default: {}", value),
+                },
+                "entry" => menu.push_entry(value),
+                _ => println!("Boot menu: unknown option: {}", key),
+            }
+        }
+
+        if menu.default >= menu.len {
+            menu.default = 0;
+        }
+        menu
+    }
+
+    fn push_entry(&mut self, value: &str) {
+        let mut fields = value.splitn(3, '|').map(str::trim);
+        let label = fields.next().unwrap_or("");
+        let kernel = fields.next().unwrap_or("");
+        let cmdline = fields.next().unwrap_or("");
+        if label.is_empty() || kernel.is_empty() {
+            println!("Boot menu: invalid entry: {}", value);
+            return;
+        }
+        if self.len == MAX_ENTRIES {
+            println!("Boot menu: too many entries, ignoring: {}", label);
+            return;
+        }
+
+        self.entries[self.len] = BootEntry {
+            label: Field::new(label),
+            kernel: Field::new(kernel),
+            cmdline: Field::new(cmdline),
+        };
+        self.len += 1;
+    }
+
+    pub fn entries(&self) -> &[BootEntry] {
+        &self.entries[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Shows the menu until an entry is chosen or the timeout expired
+    pub fn select(&self) -> &BootEntry {
+        let default = &self.entries[self.default];
+        if self.timeout == 0 || self.len == 1 {
+            return default;
+        }
+
+        let mut selected = self.default;
+        let start = ticks();
+        let mut remaining = Some(self.timeout);
+        self.draw(selected, remaining);
+
+        loop {
+            match read_key() {
+                Some(KEY_UP) => selected = selected.checked_sub(1).unwrap_or(self.len - 1),
+                Some(KEY_DOWN) => selected = (selected + 1) % self.len,
+                Some(KEY_ENTER) => break,
+                Some(_) => (),
+                None => {
+                    let Some(seconds) = remaining else {
+                        continue;
+                    };
+                    // the tick counter wraps at midnight
+                    let elapsed = ticks().wrapping_sub(start) / TICKS_PER_SECOND;
+                    if elapsed >= self.timeout {
+                        break;
+                    }
+                    if self.timeout - elapsed != seconds {
+                        remaining = Some(self.timeout - elapsed);
+                        self.draw(selected, remaining);
+                    }
+                    continue;
+                }
+            }
+            // any key stops the countdown
+            remaining = None;
+            self.draw(selected, remaining);
+        }
+
+        clear_screen();
+        &self.entries[selected]
+    }
+
+    fn draw(&self, selected: usize, remaining: Option<u32>) {
+        clear_screen();
+        println!("MiniatureOs boot menu\r\n");
+        for (i, entry) in self.entries().iter().enumerate() {
+            let marker = if i == selected { '>' } else { ' ' };
+            println!("{} {}", marker, entry.label.as_str());
+        }
+        print!("\r\nUp / Down to select, Enter to boot");
+        if let Some(seconds) = remaining {
+            print!(", booting in {}s", seconds);
+        }
+        println!();
+    }
+}
+
+fn ticks() -> u32 {
+    unsafe { ptr::read_volatile(BDA_TICKS) }
+}
+
+/// Scan code of a pending key press, doesn't block
+fn read_key() -> Option<u8> {
+    let empty: u8;
+    unsafe {
+        asm!(
+            "int 0x16",
+            "setz {empty}",
+            empty = out(reg_byte) empty,
+            inout("ax") 0x0100u16 => _,
+        );
+    }
+    if empty != 0 {
+        return None;
+    }
+
+    // removes the key from the keyboard buffer
+    let key: u16;
+    unsafe { asm!("int 0x16", inout("ax") 0u16 => key) };
+    Some((key >> 8) as u8)
+}
+
+/// Resets the 80x25 text mode, which clears the screen
+fn clear_screen() {
+    unsafe { asm!("int 0x10", inout("ax") 0x0003u16 => _) };
+}
@@ -1,4 +1,5 @@
 use crate::{dap, println};
+use core::ptr;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -41,6 +42,9 @@ pub trait Read {
     fn read_sectors(&mut self, sectors_amount: usize, buf: &mut [u8]);
     /// Read data into buffer. Buffer must be aligned to sector size
     fn read(&mut self, buf: &mut [u8]);
+    /// Read complete sectors from disk and copy the first `len` bytes of them
+    /// to `dest`, which doesn't need to be reachable by the BIOS
+    unsafe fn read_to(&mut self, sectors_amount: usize, dest: *mut u8, len: usize);
 }
 
 pub trait Disk {
@@ -64,6 +68,17 @@ pub struct DiskAccess {
 // TODO: dont harcode
 // 512 bytes are enough to read the BPB and the properly set sector size and cluster size
 pub const DEFAULT_SECTOR_SIZE: usize = 512;
+/// The BIOS can only read into memory below this address
+pub const REAL_MODE_LIMIT: usize = 0x10_0000;
+/// Largest amount of sectors read with a single BIOS call
+const MAX_SECTORS_PER_READ: usize = 0x20;
+const BOUNCE_BUFFER_SIZE: usize = DEFAULT_SECTOR_SIZE * MAX_SECTORS_PER_READ;
+
+/// Low memory buffer that reads to destinations beyond the real mode window
+/// go through
+static mut BOUNCE_BUFFER: AlignedArrayBuffer<BOUNCE_BUFFER_SIZE> = AlignedArrayBuffer {
+    buffer: [0; BOUNCE_BUFFER_SIZE],
+};
 
 impl DiskAccess {
     pub fn new(disk_number: u16, base_lba: u64, offset: u64) -> DiskAccess {
@@ -147,6 +162,10 @@ impl Read for DiskAccess {
     fn read_sectors(&mut self, sectors_amount: usize, buf: &mut [u8]) {
         assert_eq!(buf.len() % self.sector_size, 0);
         assert!(buf.len() / self.sector_size >= sectors_amount);
+        assert!(
+            buf.as_ptr() as usize + sectors_amount * self.sector_size <= REAL_MODE_LIMIT,
+            "Disk read buffer beyond the real mode window"
+        );
 
         let mut start_lba = (self.base_offset + self.offset) / self.sector_size as u64;
         let end_addr = self.base_offset + self.offset + (sectors_amount * self.sector_size) as u64;
@@ -155,7 +174,7 @@ impl Read for DiskAccess {
         let mut buffer_address = buf.as_ptr() as u32;
 
         while remaining_sector_count > 0 {
-            let sector_count = u64::min(remaining_sector_count, 0x20) as u16;
+            let sector_count = u64::min(remaining_sector_count, MAX_SECTORS_PER_READ as u64) as u16;
             let packet = dap::DiskAddressPacket::new(buffer_address, sector_count, start_lba);
 
             unsafe {
@@ -169,4 +188,31 @@ impl Read for DiskAccess {
 
         self.offset = end_addr;
     }
+
+    unsafe fn read_to(&mut self, sectors_amount: usize, dest: *mut u8, len: usize) {
+        assert!(len <= sectors_amount * self.sector_size);
+
+        let buf = unsafe { &mut BOUNCE_BUFFER.buffer };
+        let chunk_sectors = buf.len() / self.sector_size;
+        assert!(
+            chunk_sectors > 0,
+            "Sector size larger than the bounce buffer"
+        );
+
+        let mut sectors_left = sectors_amount;
+        let mut copied = 0;
+        while sectors_left > 0 {
+            let sectors = usize::min(sectors_left, chunk_sectors);
+            let chunk = &mut buf[..sectors * self.sector_size];
+            self.read_sectors(sectors, chunk);
+
+            // the last sectors may contain bytes beyond `len`
+            let n = usize::min(chunk.len(), len - copied);
+            unsafe {
+                ptr::copy_nonoverlapping(chunk.as_ptr(), dest.add(copied), n);
+            }
+            copied += n;
+            sectors_left -= sectors;
+        }
+    }
 }
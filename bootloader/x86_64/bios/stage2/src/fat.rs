@@ -8,11 +8,8 @@
 //!
 //! Basically just a big single-linked list of clusters in a big table
 //! https://wiki.osdev.org/FAT
-use crate::{
-    disk::{AlignedArrayBuffer, Disk, Read, Seek, SeekFrom, DEFAULT_SECTOR_SIZE},
-    println,
-};
-use core::{default::Default, ptr, str};
+use crate::disk::{AlignedArrayBuffer, Disk, Read, Seek, SeekFrom, DEFAULT_SECTOR_SIZE};
+use core::{default::Default, iter, ptr, str};
 
 const ROOT_DIR_ENTRY_SIZE: usize = 0x20;
 
@@ -22,6 +19,18 @@ pub enum FatError {
     FileNotFound,
     DirEntryError,
     FileReadError,
+    /// The file doesn't fit at its destination
+    FileTooLarge,
+    /// A directory on the path doesn't fit into the directory buffer
+    DirectoryTooLarge,
+}
+
+/// Directory to read the entries of
+#[derive(Clone, Copy)]
+enum Directory {
+    Root,
+    /// Subdirectory or the FAT32 root directory, by its first cluster
+    Clusters(u32),
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -315,12 +324,7 @@ impl DirectoryEntry {
 
     pub fn eq_name(&self, name: &str) -> bool {
         match self {
-            DirectoryEntry::NormalDirEntry(e) => e
-                .filename
-                .iter()
-                .cloned()
-                .take_while(|&c| c != '\0')
-                .eq(name.chars()),
+            DirectoryEntry::NormalDirEntry(e) => e.eq_short_name(name),
             DirectoryEntry::LongNameDirEntry(e) => e
                 .filename
                 .iter()
@@ -366,6 +370,24 @@ pub struct NormalDirectoryEntry {
     size_in_bytes: u32,
 }
 
+impl NormalDirectoryEntry {
+    /// Compares with a `NAME.EXT` name, case insensitive like FAT itself
+    fn eq_short_name(&self, name: &str) -> bool {
+        fn eq_padded(field: &[char], s: &str) -> bool {
+            s.len() <= field.len()
+                && s.chars()
+                    .chain(iter::repeat(' '))
+                    .zip(field)
+                    .all(|(a, b)| a.eq_ignore_ascii_case(b))
+        }
+
+        let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+        !base.is_empty()
+            && eq_padded(&self.filename[..8], base)
+            && eq_padded(&self.filename[8..], extension)
+    }
+}
+
 /// Long name directory entires are used only when the VFAT extension is supported.
 /// With this extension, filenames can be up to 255 characters.
 /// Long name directory entries are represented by a chain of 32 byte long file name entries,
@@ -426,38 +448,86 @@ impl<D: Read + Seek + Clone + Disk> FATFileSystem<D> {
         Self { bpb, disk }
     }
 
-    pub fn read_root_dir<'a>(
+    /// Reads the entries of `dir` into `buffer`
+    fn read_dir<'a>(
         &mut self,
+        dir: Directory,
         buffer: &'a mut [u8],
-    ) -> impl Iterator<Item = Result<DirectoryEntry, FatError>> + 'a {
-        assert!(buffer.len() == self.bpb.root_dir_size() as usize);
-
-        if self.bpb.fat_type() == FatType::Fat32 {
-            unimplemented!();
-        }
+    ) -> Result<impl Iterator<Item = Result<DirectoryEntry, FatError>> + 'a, FatError> {
+        let len = match dir {
+            // FAT12 and FAT16 have a fixed root directory area in front of the
+            // data area
+            Directory::Root if self.bpb.fat_type() != FatType::Fat32 => {
+                let len = self.bpb.root_dir_size() as usize;
+                assert!(len <= buffer.len());
+
+                self.disk.seek(SeekFrom::StartInSectors(u64::from(
+                    self.bpb.first_root_dir_sector(),
+                )));
+                self.disk.read(&mut buffer[..len]);
+                len
+            }
+            Directory::Root => self.read_cluster_chain(self.bpb.root_cluster, buffer)?,
+            Directory::Clusters(first_cluster) => self.read_cluster_chain(first_cluster, buffer)?,
+        };
 
-        self.disk.seek(SeekFrom::StartInSectors(u64::from(
-            self.bpb.first_root_dir_sector(),
-        )));
+        Ok(DirIter::new(&buffer[..len]))
+    }
 
-        self.disk.read(buffer);
+    /// Reads the clusters of a directory into `buffer`, returns the amount of
+    /// bytes read. Fails with [`FatError::DirectoryTooLarge`] instead of
+    /// truncating the directory.
+    fn read_cluster_chain(
+        &mut self,
+        first_cluster: u32,
+        buffer: &mut [u8],
+    ) -> Result<usize, FatError> {
+        let mut disk: D = self.disk.clone();
+        let cluster_size = disk.cluster_size();
+        let mut len = 0;
 
-        RootDirIter::new(buffer)
+        for cluster in FileIter::new(&mut self.disk, first_cluster, &self.bpb) {
+            let cluster = cluster?;
+            if len + cluster_size > buffer.len() {
+                return Err(FatError::DirectoryTooLarge);
+            }
+            disk.seek(SeekFrom::StartInSectors(u64::from(cluster.start_sector)));
+            disk.read(&mut buffer[len..len + cluster_size]);
+            len += cluster_size;
+        }
+        Ok(len)
     }
 
-    pub fn find_file_in_root_dir(&mut self, name: &str) -> Option<File> {
+    /// Looks up a file by its path from the root directory, with directories
+    /// separated by `/`, e.g. `boot/kernel.elf`
+    pub fn find_file(&mut self, path: &str) -> Result<File, FatError> {
         // TODO: somehow not hardcode this ?
         // FAT16: common to have a root directory with max 512 entries of size 32
         // If I had dynamic memory I could use bpb.root_entry_count
         let mut buffer = [0u8; DEFAULT_SECTOR_SIZE * ROOT_DIR_ENTRY_SIZE];
-        let mut entries = self.read_root_dir(&mut buffer).filter_map(|e| e.ok());
-        let entry = entries.find(|e| e.eq_name(name))?;
-
-        if entry.is_dir() {
-            None
-        } else {
-            Some(File::new(entry.first_cluster(), entry.file_size()))
+        let mut dir = Directory::Root;
+        let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+
+        while let Some(name) = components.next() {
+            let entry = self
+                .read_dir(dir, &mut buffer)?
+                .filter_map(|e| e.ok())
+                .find(|e| e.eq_name(name))
+                .ok_or(FatError::FileNotFound)?;
+
+            match (entry.is_dir(), components.peek().is_some()) {
+                // ".." of a directory in the root points to cluster 0
+                (true, true) => {
+                    dir = match entry.first_cluster() {
+                        0 => Directory::Root,
+                        cluster => Directory::Clusters(cluster),
+                    }
+                }
+                (false, false) => return Ok(File::new(entry.first_cluster(), entry.file_size())),
+                _ => return Err(FatError::FileNotFound),
+            }
         }
+        Err(FatError::FileNotFound)
     }
 
     // The clusters of a file need not be right next to each other on the disk.
@@ -474,45 +544,43 @@ impl<D: Read + Seek + Clone + Disk> FATFileSystem<D> {
     /// be adjacent to each other. We obtain the sector number of the first cluster
     /// from the DirectoryEntry. Afterwards we look up the start sector of any further
     /// clusters by querying the FAT.
-    pub fn try_load_file(&mut self, name: &str, dest: *mut u8) -> Result<usize, FatError> {
-        let file = self
-            .find_file_in_root_dir(name)
-            .ok_or(FatError::FileNotFound)?;
+    ///
+    /// Exactly the size of the file is written to `dest`, which fails with
+    /// [`FatError::FileTooLarge`] if that is more than `max_len`.
+    pub fn try_load_file(
+        &mut self,
+        path: &str,
+        dest: *mut u8,
+        max_len: usize,
+    ) -> Result<usize, FatError> {
+        let file = self.find_file(path)?;
+        let size = file.size as usize;
+        if size > max_len {
+            return Err(FatError::FileTooLarge);
+        }
 
-        let mut buffer = [0u8; DEFAULT_SECTOR_SIZE * 0x8];
         let mut disk: D = self.disk.clone();
-        let mut total_sectors_read = 0x0;
+        let mut loaded = 0x0;
         // clusters are contiguous so always read cluster wise
         for cluster in self.file_clusters(&file) {
+            if loaded == size {
+                break;
+            }
             let cluster = cluster?;
             disk.seek(SeekFrom::StartInSectors(u64::from(cluster.start_sector)));
 
-            let mut sectors_left = disk.sectors_per_cluster();
-            while sectors_left > 0 {
-                let sectors_to_read = usize::min(sectors_left, buffer.len() / disk.sector_size());
-                disk.read_sectors(sectors_to_read, &mut buffer);
-
-                let dest = dest.wrapping_add(total_sectors_read * disk.sector_size());
-                // copy read data to dest
-                unsafe {
-                    ptr::copy_nonoverlapping(
-                        buffer.as_ptr(),
-                        dest,
-                        sectors_to_read * disk.sector_size(),
-                    );
-                }
-
-                total_sectors_read += sectors_to_read;
-                sectors_left -= sectors_to_read;
-            }
+            let len = usize::min(disk.cluster_size(), size - loaded);
+            let sectors = len.div_ceil(disk.sector_size());
+            // the disk is read through a low memory buffer since `dest` is
+            // usually beyond what the BIOS can reach
+            unsafe { disk.read_to(sectors, dest.add(loaded), len) };
+            loaded += len;
         }
 
-        // smaller and not equal because we read cluster wise and therefore
-        // might read more sectors than the size of the file
-        if total_sectors_read < file.size_in_sectors() as usize {
+        if loaded < size {
             Err(FatError::FileReadError)
         } else {
-            Ok(file.size as usize)
+            Ok(size)
         }
     }
 
@@ -521,14 +589,14 @@ impl<D: Read + Seek + Clone + Disk> FATFileSystem<D> {
     }
 }
 
-struct RootDirIter<'a> {
+struct DirIter<'a> {
     buf: &'a [u8],
     offset: usize,
 }
 
-impl<'a> RootDirIter<'a> {
+impl<'a> DirIter<'a> {
     pub fn new(buf: &'a [u8]) -> Self {
-        RootDirIter { buf, offset: 0 }
+        DirIter { buf, offset: 0 }
     }
 
     pub fn next_entry(&mut self) -> Result<DirectoryEntry, FatError> {
@@ -542,10 +610,14 @@ impl<'a> RootDirIter<'a> {
     }
 }
 
-impl<'a> Iterator for RootDirIter<'a> {
+impl<'a> Iterator for DirIter<'a> {
     type Item = Result<DirectoryEntry, FatError>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        // a full directory has no end marker
+        if self.offset >= self.buf.len() {
+            return None;
+        }
         match self.next_entry() {
             Ok(entry) => match entry {
                 DirectoryEntry::EndOfDir => None,
@@ -12,8 +12,13 @@
 //!
 #![no_std]
 #![no_main]
+use api::{
+    cmdline::{self, VideoRequest},
+    edid::Edid,
+    manifest, signature, FramebufferInfo, VideoModes,
+};
 use common::{fail, hlt, mbr, BiosInfo, E820MemoryRegion};
-use core::{panic::PanicInfo, slice};
+use core::{panic::PanicInfo, slice, str};
 use lazy_static::lazy_static;
 use x86_64::{
     gdt::{GlobalDescriptorTable, SegmentDescriptor},
@@ -21,6 +26,8 @@ use x86_64::{
     mutex::Mutex,
 };
 
+mod a20;
+mod bootmenu;
 mod dap;
 mod disk;
 mod fat;
@@ -28,12 +35,16 @@ mod memory_map;
 mod print;
 mod protected_mode;
 mod vesa;
+use bootmenu::BootMenu;
+use fat::FatError;
 use memory_map::MemoryMap;
 use protected_mode::*;
 
 const STAGE3_DST: *mut u8 = 0x0010_0000 as *mut u8;
 const STAGE4_DST: *mut u8 = 0x0012_0000 as *mut u8;
 const KERNEL_DST: *mut u8 = 0x0020_0000 as *mut u8;
+/// Unreal mode segments span the first 4 GiB
+const UNREAL_MODE_LIMIT: u64 = 0x1_0000_0000;
 
 lazy_static! {
     static ref BIOS_INFO: Mutex<BiosInfo> = Mutex::new(BiosInfo::default());
@@ -79,10 +90,115 @@ fn print_memory_map(map: &MemoryMap) {
     }
 }
 
+/// Switches to the display mode requested with `video=` on the command line,
+/// otherwise to the native resolution of the display. Stays in text mode,
+/// without a framebuffer, if asked to or there is no suitable graphics mode.
+fn set_video_mode(cmdline: &str, edid: Option<&Edid>) -> (FramebufferInfo, VideoModes) {
+    let native = edid
+        .and_then(Edid::native_resolution)
+        .map(|(width, height)| VideoRequest::Mode {
+            width,
+            height,
+            depth: 24,
+        });
+    let request = match cmdline::get(cmdline, "video").map(str::parse) {
+        Some(Ok(request)) => request,
+        Some(Err(())) => {
+            println!("Invalid video mode on the command line");
+            native.unwrap_or(VideoRequest::DEFAULT)
+        }
+        None => native.unwrap_or(VideoRequest::DEFAULT),
+    };
+
+    let mut modes = VideoModes::default();
+    let Ok(vesa_info) = vesa::VbeInfo::get() else {
+        println!("No VESA support, staying in text mode");
+        return (FramebufferInfo::default(), modes);
+    };
+    for (number, info) in vesa_info.graphics_modes() {
+        modes.push(info.to_video_mode(number));
+    }
+
+    let VideoRequest::Mode {
+        width,
+        height,
+        depth,
+    } = request
+    else {
+        println!("Staying in text mode");
+        return (FramebufferInfo::default(), modes);
+    };
+    let Some((mode, mode_info)) = vesa_info
+        .get_best_mode(width, height, depth)
+        .and_then(|mode| Some((mode, vesa::VbeModeInfo::get(mode).ok()?)))
+    else {
+        println!("No suitable VESA mode, staying in text mode");
+        return (FramebufferInfo::default(), modes);
+    };
+    println!("VESA mode: {:#x}, {}", mode, mode_info);
+
+    // the screen shows nothing printed after this call, only the debug port
+    if let Err(error) = vesa_info.set_mode(mode) {
+        println!(
+            "Failed to set VESA mode: {:#x}, staying in text mode",
+            error
+        );
+        return (FramebufferInfo::default(), modes);
+    }
+    modes.current = Some(mode_info.to_video_mode(mode));
+    (mode_info.to_framebuffer_info(), modes)
+}
+
+/// Compares the CRC32 of a loaded file with the one in the manifest
+fn verify_file(manifest: &str, path: &str, data: *const u8, len: usize) {
+    let Some(expected) = manifest::lookup(manifest, path) else {
+        println!("{} is not in the manifest, not verified", path);
+        return;
+    };
+    let checksum = manifest::crc32(unsafe { slice::from_raw_parts(data, len) });
+    if checksum != expected {
+        panic!(
+            "{} is corrupted: checksum {:#010x}, expected {:#010x}",
+            path, checksum, expected
+        );
+    }
+    println!("{} verified: {:#010x}", path, checksum);
+}
+
+/// Appends `extra` to the first line of the command line at `dst`, returns
+/// the new length
+fn append_cmdline(dst: *mut u8, len: usize, extra: &str) -> usize {
+    let cmdline = unsafe { slice::from_raw_parts(dst, len) };
+    let base_len = str::from_utf8(cmdline)
+        .unwrap_or("")
+        .lines()
+        .next()
+        .unwrap_or("")
+        .trim_end()
+        .len();
+
+    let mut len = base_len;
+    unsafe {
+        if base_len != 0 {
+            dst.add(len).write(b' ');
+            len += 1;
+        }
+        dst.add(len)
+            .copy_from_nonoverlapping(extra.as_ptr(), extra.len());
+    }
+    len + extra.len()
+}
+
 fn start(disk_number: u16, partition_table_start: *const u8) -> ! {
     enter_unreal_mode();
     println!("Stage2 \r\n");
 
+    // the check needs unreal mode to access the memory above 1 MiB
+    match a20::enable() {
+        Some(method) => println!("A20 line enabled: {:?}", method),
+        None => panic!("Failed to enable the A20 line"),
+    }
+
     let partition_table_raw = unsafe {
         slice::from_raw_parts(
             partition_table_start,
@@ -108,8 +224,21 @@ fn start(disk_number: u16, partition_table_start: *const u8) -> ! {
 
     let mut fs = fat::FATFileSystem::parse(disk);
 
+    // everything from the kernel on has to fit into the usable memory it
+    // starts in
+    let memory_map = MemoryMap::get().expect("Failed to get memory map");
+    let load_limit = memory_map
+        .usable_end(KERNEL_DST as u64)
+        .expect("Kernel destination is not usable memory")
+        .min(UNREAL_MODE_LIMIT);
+    let max_len = |dst: *mut u8| load_limit.saturating_sub(dst as u64) as usize;
+
     let stage3_len = fs
-        .try_load_file("stage3", STAGE3_DST)
+        .try_load_file(
+            "stage3",
+            STAGE3_DST,
+            STAGE4_DST as usize - STAGE3_DST as usize,
+        )
         .expect("Failed to load stage3");
 
     println!(
@@ -118,7 +247,11 @@ fn start(disk_number: u16, partition_table_start: *const u8) -> ! {
     );
 
     let stage4_len = fs
-        .try_load_file("stage4", STAGE4_DST)
+        .try_load_file(
+            "stage4",
+            STAGE4_DST,
+            KERNEL_DST as usize - STAGE4_DST as usize,
+        )
         .expect("Failed to load stage4");
 
     println!(
@@ -126,29 +259,136 @@ fn start(disk_number: u16, partition_table_start: *const u8) -> ! {
         STAGE4_DST, stage4_len
     );
 
+    // the boot menu is optional, it is parsed where the kernel goes next
+    let menu = fs
+        .try_load_file("bootmenu", KERNEL_DST, max_len(KERNEL_DST))
+        .ok()
+        .map(|len| {
+            let text = unsafe { slice::from_raw_parts(KERNEL_DST, len) };
+            BootMenu::parse(str::from_utf8(text).unwrap_or(""))
+        });
+    let entry = menu
+        .as_ref()
+        .filter(|menu| !menu.is_empty())
+        .map(BootMenu::select);
+    let kernel_name = entry.map_or("kernel", |entry| entry.kernel.as_str());
+    if let Some(entry) = entry {
+        println!("Booting: {}", entry.label.as_str());
+    }
+
     let kernel_len = fs
-        .try_load_file("kernel", KERNEL_DST)
+        .try_load_file(kernel_name, KERNEL_DST, max_len(KERNEL_DST))
         .expect("Failed to load kernel");
 
     println!(
-        "Kernel loaded at: {:#p}, size: {:#x}",
-        KERNEL_DST, kernel_len
+        "Kernel {} loaded at: {:#p}, size: {:#x}",
+        kernel_name, KERNEL_DST, kernel_len
     );
 
-    let memory_map = MemoryMap::get().expect("Failed to get memory map");
-    print_memory_map(&memory_map);
+    // the initramfs is optional and placed at the next page after the kernel
+    let initramfs_dst = (KERNEL_DST as usize + kernel_len).next_multiple_of(0x1000) as *mut u8;
+    let initramfs_len = match fs.try_load_file("initrd", initramfs_dst, max_len(initramfs_dst)) {
+        Ok(len) => {
+            println!(
+                "Initramfs loaded at: {:#p}, size: {:#x}",
+                initramfs_dst, len
+            );
+            len
+        }
+        Err(FatError::FileNotFound) => 0,
+        Err(err) => panic!("Failed to load initramfs: {:?}", err),
+    };
+
+    // the kernel symbol table is optional as well and follows on the next page,
+    // it only belongs to the default kernel
+    let symbols_dst = (initramfs_dst as usize + initramfs_len).next_multiple_of(0x1000) as *mut u8;
+    let symbols = match kernel_name {
+        "kernel" => fs.try_load_file("ksyms", symbols_dst, max_len(symbols_dst)),
+        _ => Err(FatError::FileNotFound),
+    };
+    let symbols_len = match symbols {
+        Ok(len) => {
+            println!("Symbols loaded at: {:#p}, size: {:#x}", symbols_dst, len);
+            len
+        }
+        Err(FatError::FileNotFound) => 0,
+        Err(err) => panic!("Failed to load symbols: {:?}", err),
+    };
+
+    // the kernel command line is optional too, stage4 copies it into the
+    // boot info
+    let cmdline_dst = (symbols_dst as usize + symbols_len).next_multiple_of(0x1000) as *mut u8;
+    let cmdline_len = match fs.try_load_file("cmdline", cmdline_dst, max_len(cmdline_dst)) {
+        Ok(len) => {
+            println!(
+                "Command line loaded at: {:#p}, size: {:#x}",
+                cmdline_dst, len
+            );
+            len
+        }
+        Err(FatError::FileNotFound) => 0,
+        Err(err) => panic!("Failed to load command line: {:?}", err),
+    };
+    let cmdline_len = match entry {
+        Some(entry) if !entry.cmdline.as_str().is_empty() => {
+            append_cmdline(cmdline_dst, cmdline_len, entry.cmdline.as_str())
+        }
+        _ => cmdline_len,
+    };
+
+    // the signature of the kernel is optional, stage4 decides whether it is
+    // required
+    let mut signature_name = [0u8; bootmenu::MAX_FIELD_LENGTH + signature::SUFFIX.len()];
+    let signature_name_len = kernel_name.len() + signature::SUFFIX.len();
+    signature_name[..kernel_name.len()].copy_from_slice(kernel_name.as_bytes());
+    signature_name[kernel_name.len()..signature_name_len]
+        .copy_from_slice(signature::SUFFIX.as_bytes());
+    let signature_name = str::from_utf8(&signature_name[..signature_name_len]).unwrap();
+
+    let signature_dst = (cmdline_dst as usize + cmdline_len).next_multiple_of(0x1000) as *mut u8;
+    let signature_len =
+        match fs.try_load_file(signature_name, signature_dst, max_len(signature_dst)) {
+            Ok(len) => {
+                println!(
+                    "Kernel signature loaded at: {:#p}, size: {:#x}",
+                    signature_dst, len
+                );
+                len
+            }
+            Err(FatError::FileNotFound) => 0,
+            Err(err) => panic!("Failed to load kernel signature: {:?}", err),
+        };
+
+    // the manifest is only needed here, so it may be overwritten after stage2
+    let manifest_dst = (signature_dst as usize + signature_len).next_multiple_of(0x1000) as *mut u8;
+    match fs.try_load_file(manifest::FILE_NAME, manifest_dst, max_len(manifest_dst)) {
+        Ok(len) => {
+            let manifest = unsafe { slice::from_raw_parts(manifest_dst, len) };
+            let manifest = str::from_utf8(manifest).expect("Invalid manifest");
+            verify_file(manifest, "stage3", STAGE3_DST, stage3_len);
+            verify_file(manifest, "stage4", STAGE4_DST, stage4_len);
+            verify_file(manifest, kernel_name, KERNEL_DST, kernel_len);
+        }
+        Err(FatError::FileNotFound) => println!("No manifest, boot files are not verified"),
+        Err(err) => panic!("Failed to load manifest: {:?}", err),
+    }
 
-    let vesa_info = vesa::VbeInfo::get().expect("Error getting Vesa info");
-    let mode = vesa_info
-        .get_best_mode(1280, 1024, 24)
-        .expect("Unable to get vesa mode");
-    let mode_info = vesa::VbeModeInfo::get(mode).expect("Failed to get vesa mode info");
+    print_memory_map(&memory_map);
 
-    // println wont work anymore after this call
-    // TODO: forgot why
-    vesa_info.set_mode(mode).expect("Failed to set vesa mode");
+    let cmdline = unsafe { slice::from_raw_parts(cmdline_dst, cmdline_len) };
+    let edid = vesa::read_edid();
+    if let Some((width, height)) = edid.as_ref().and_then(Edid::native_resolution) {
+        println!("Native resolution: {}x{}", width, height);
+    }
+    let (framebuffer, video_modes) =
+        set_video_mode(str::from_utf8(cmdline).unwrap_or(""), edid.as_ref());
 
     let mut bios_info = BIOS_INFO.lock();
+    bios_info.stage3 = PhysicalMemoryRegion::new(
+        STAGE3_DST as u64,
+        stage3_len as u64,
+        PhysicalMemoryRegionType::Reserved,
+    );
     bios_info.stage4 = PhysicalMemoryRegion::new(
         STAGE4_DST as u64,
         stage4_len as u64,
@@ -159,8 +399,40 @@ fn start(disk_number: u16, partition_table_start: *const u8) -> ! {
         kernel_len as u64,
         PhysicalMemoryRegionType::Reserved,
     );
-    bios_info.framebuffer = mode_info.to_framebuffer_info();
-    bios_info.last_physical_address = KERNEL_DST as u64 + kernel_len as u64;
+    bios_info.framebuffer = framebuffer;
+    bios_info.video_modes = video_modes;
+    bios_info.edid = edid;
+    bios_info.initramfs = PhysicalMemoryRegion::new(
+        initramfs_dst as u64,
+        initramfs_len as u64,
+        PhysicalMemoryRegionType::Reserved,
+    );
+    bios_info.symbols = PhysicalMemoryRegion::new(
+        symbols_dst as u64,
+        symbols_len as u64,
+        PhysicalMemoryRegionType::Reserved,
+    );
+    bios_info.cmdline = PhysicalMemoryRegion::new(
+        cmdline_dst as u64,
+        cmdline_len as u64,
+        PhysicalMemoryRegionType::Reserved,
+    );
+    bios_info.kernel_signature = PhysicalMemoryRegion::new(
+        signature_dst as u64,
+        signature_len as u64,
+        PhysicalMemoryRegionType::Reserved,
+    );
+    bios_info.last_physical_address = if signature_len != 0 {
+        signature_dst as u64 + signature_len as u64
+    } else if cmdline_len != 0 {
+        cmdline_dst as u64 + cmdline_len as u64
+    } else if symbols_len != 0 {
+        symbols_dst as u64 + symbols_len as u64
+    } else if initramfs_len != 0 {
+        initramfs_dst as u64 + initramfs_len as u64
+    } else {
+        KERNEL_DST as u64 + kernel_len as u64
+    };
     bios_info.memory_map_address = memory_map.map.as_ptr() as u64;
     bios_info.memory_map_size = memory_map.size as u64;
 
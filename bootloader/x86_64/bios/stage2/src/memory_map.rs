@@ -1,23 +1,31 @@
 //! This module is responsible for detecting available memory using x86 BIOS
 //! functions
-use common::E820MemoryRegion;
+use crate::println;
+use common::{normalize_memory_map, E820MemoryRegion, E820MemoryRegionType};
 use core::{arch::asm, convert::AsRef, mem::size_of};
 use x86_64::mutex::{Mutex, MutexGuard};
 
+/// Regions of the memory map as reported by the BIOS at most
+const MAX_BIOS_REGIONS: usize = 0x80;
+/// Clipping overlapping regions can split them
+const MAX_REGIONS: usize = 2 * MAX_BIOS_REGIONS;
+
 pub static MEMORY_MAP: Mutex<MemoryMap> = Mutex::new(MemoryMap {
-    map: [E820MemoryRegion::empty(); 0x20],
+    map: [E820MemoryRegion::empty(); MAX_REGIONS],
     size: 0,
 });
 
-#[derive(Default)]
 pub struct MemoryMap {
-    pub map: [E820MemoryRegion; 0x20],
+    pub map: [E820MemoryRegion; MAX_REGIONS],
     pub size: usize,
 }
 
 impl MemoryMap {
     /// Detecting memory using BIOS function 0xe820
     /// https://wiki.osdev.org/Detecting_Memory_(x86)#BIOS_Function:_INT_0x15.2C_EAX_.3D_0xE820
+    ///
+    /// The BIOS doesn't guarantee any order and regions may overlap, the map
+    /// is sorted and clipped, see [`normalize_memory_map`]
     pub fn get() -> Result<MutexGuard<'static, MemoryMap>, ()> {
         const MAGIC_NUMBER: u32 = 0x534D4150;
         const QUERY_SYTEM_ADDRESS_MAP_CMD: u32 = 0xE820;
@@ -26,9 +34,18 @@ impl MemoryMap {
         let mut len = 0x0;
         let mut entries_cnt = 0x0;
 
-        let mut memory_map = MEMORY_MAP.lock();
+        // on the stack since the BIOS needs an address it can reach
+        let mut regions = [E820MemoryRegion::empty(); MAX_BIOS_REGIONS];
 
         loop {
+            if entries_cnt == regions.len() {
+                println!(
+                    "Memory map has more than {} regions, ignoring the rest",
+                    regions.len()
+                );
+                break;
+            }
+
             unsafe {
                 asm!(
                     "int 0x15",
@@ -36,7 +53,7 @@ impl MemoryMap {
                     inout("ecx") size_of::<E820MemoryRegion>() => len,
                     inout("ebx") cont_id,
                     in("edx") MAGIC_NUMBER,
-                    in("edi") &memory_map.map[entries_cnt],
+                    in("edi") &regions[entries_cnt],
                     options(nostack)
                 );
             }
@@ -45,20 +62,21 @@ impl MemoryMap {
                 return Err(());
             }
 
-            let entry = &mut memory_map.map[entries_cnt];
+            let entry = &regions[entries_cnt];
 
-            if len > 0x20 && (entry.acpi_extended_attributes & 0x1) == 0 {
-                continue;
+            // ACPI 3.0 entries can ask to be ignored
+            let ignored = len > 20 && (entry.acpi_extended_attributes & 0x1) == 0;
+            if !ignored {
+                entries_cnt += 1;
             }
 
-            if cont_id == 0 || entries_cnt > memory_map.map.len() {
+            if cont_id == 0 {
                 break;
             }
-
-            entries_cnt += 1;
         }
 
-        memory_map.size = entries_cnt;
+        let mut memory_map = MEMORY_MAP.lock();
+        memory_map.size = normalize_memory_map(&regions[..entries_cnt], &mut memory_map.map);
 
         Ok(memory_map)
     }
@@ -66,4 +84,12 @@ impl MemoryMap {
     pub fn iter(&self) -> impl Iterator<Item = &E820MemoryRegion> {
         self.map[..self.size].iter()
     }
+
+    /// End of the usable region that contains `address`
+    pub fn usable_end(&self, address: u64) -> Option<u64> {
+        self.iter()
+            .filter(|region| region.typ == E820MemoryRegionType::Normal)
+            .find(|region| (region.start..region.start + region.size).contains(&address))
+            .map(|region| region.start + region.size)
+    }
 }
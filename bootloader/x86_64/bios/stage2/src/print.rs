@@ -23,8 +23,11 @@ impl Writer {
         Writer {}
     }
 
+    /// Prints to the screen and mirrors to the QEMU / Bochs debug port, which
+    /// keeps working after the switch to a VESA mode
     fn print_char(c: u8) {
         unsafe {
+            asm!("out 0xe9, al", in("al") c);
             asm!("mov ah, 0x0E; xor bh, bh; int 0x10", in("al") c);
         }
     }
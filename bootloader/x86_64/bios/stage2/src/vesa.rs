@@ -6,9 +6,12 @@
 //! which support resolutions, color depths, and frame buffer organizations
 //! beyond the VGA hardware standard
 use crate::println;
-use api::{FramebufferInfo, PixelFormat};
+use api::{
+    edid::{self, Edid},
+    FramebufferInfo, PixelFormat, VideoMode,
+};
 use common::{const_assert, realmode::RealModePointer};
-use core::{arch::asm, borrow::BorrowMut, default::Default, mem::size_of};
+use core::{arch::asm, borrow::BorrowMut, default::Default, fmt, mem::size_of};
 use x86_64::memory::{PhysicalMemoryRegion, PhysicalMemoryRegionType};
 
 /// All VESA functions return 0x4F in AL if they are supported and use AH as a
@@ -89,36 +92,24 @@ impl VbeInfo {
         }
     }
 
+    /// Graphics modes with a linear framebuffer and packed pixel or direct
+    /// color memory model
+    pub fn graphics_modes(&self) -> impl Iterator<Item = (u16, VbeModeInfo)> + '_ {
+        (0..)
+            .map_while(|i| unsafe { self.get_mode(i) })
+            .filter_map(|mode| VbeModeInfo::get(mode).ok().map(|info| (mode, info)))
+            // graphics mode with linear frame buffer support
+            .filter(|(_, info)| info.attributes & 0x90 == 0x90)
+            .filter(|(_, info)| info.memory_model == 4 || info.memory_model == 6)
+    }
+
     /// Gets the display mode id of the mode closest to the specified parameters
     /// Code is basically copied from: https://wiki.osdev.org/VESA_Video_Modes
     pub fn get_best_mode(&self, width: u16, height: u16, depth: u8) -> Option<u16> {
         let mut best: Option<u16> = None;
         let mut best_pix_diff = u32::MAX;
         let mut best_depth_diff = u8::MAX;
-        for i in 0.. {
-            let mode = match unsafe { self.get_mode(i) } {
-                Some(mode) => mode,
-                None => break,
-            };
-
-            let info = match VbeModeInfo::get(mode) {
-                Ok(info) => info,
-                Err(c) => {
-                    //println!("VesaModeInfo query failed with code: {:x}", c);
-                    continue;
-                }
-            };
-
-            // Check if this is a graphics mode with linear frame buffer support
-            if info.attributes & 0x90 != 0x90 {
-                continue;
-            }
-
-            // Check if this is a packed pixel or direct color mode
-            if info.memory_model != 4 && info.memory_model != 6 {
-                continue;
-            }
-
+        for (mode, info) in self.graphics_modes() {
             if info.width == width && info.height == height && info.bits_per_pixel == depth {
                 return Some(mode);
             }
@@ -160,6 +151,24 @@ impl VbeInfo {
     }
 }
 
+/// Reads the EDID of the first display through VBE/DDC, None if the firmware
+/// or the display doesn't support it
+pub fn read_edid() -> Option<Edid> {
+    const DDC_CMD: u16 = 0x4f15;
+    const READ_EDID: u16 = 0x01;
+    let mut data = [0u8; edid::SIZE];
+    let ptr = RealModePointer(data.as_mut_ptr() as u32);
+    let ret: u16;
+    unsafe {
+        asm!("push es", "mov es, {:x}", "int 0x10", "pop es", in(reg) ptr.segment(), in("di") ptr.offset(), inout("ax") DDC_CMD => ret, in("bx") READ_EDID, in("cx") 0u16, in("dx") 0u16);
+    }
+
+    match ret {
+        VESA_SUCCESS => Edid::new(data).ok(),
+        _ => None,
+    }
+}
+
 /// Vbe mode information block
 /// Contains information about a specific display mode
 #[derive(Debug)]
@@ -269,6 +278,15 @@ impl VbeModeInfo {
         }
     }
 
+    pub fn to_video_mode(&self, number: u16) -> VideoMode {
+        VideoMode {
+            number,
+            width: self.width,
+            height: self.height,
+            bits_per_pixel: self.bits_per_pixel,
+        }
+    }
+
     pub fn to_framebuffer_info(&self) -> FramebufferInfo {
         let bytes_per_pixel = self.bits_per_pixel / 8;
         let region = PhysicalMemoryRegion::new(
@@ -288,3 +306,13 @@ impl VbeModeInfo {
         )
     }
 }
+
+impl fmt::Display for VbeModeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{}x{}, framebuffer: {:#x}",
+            self.width, self.height, self.bits_per_pixel, self.framebuffer
+        )
+    }
+}
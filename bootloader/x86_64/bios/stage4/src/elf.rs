@@ -7,8 +7,8 @@ use x86_64::{
         VirtualAddress,
     },
     paging::{
-        mapped_page_table::MappedPageTable, Mapper, MapperAllSizes, PageTable, PageTableEntryFlags,
-        Translator, TranslatorAllSizes,
+        mapped_page_table::MappedPageTable, CacheAttribute, Mapper, MapperAllSizes, PageTable,
+        PageTableEntryFlags, Translator, TranslatorAllSizes,
     },
     println,
 };
@@ -22,6 +22,21 @@ pub struct KernelLoader<'a, M, A> {
     info: &'a BiosInfo,
     page_table: &'a mut M,
     frame_allocator: &'a mut A,
+    /// End of the highest segment
+    image_end: VirtualAddress,
+    /// Contents of the .dynsym section
+    dynsym: &'static [u8],
+    tls: Option<TlsTemplate>,
+    thread_pointer: Option<VirtualAddress>,
+}
+
+/// Initial contents of the thread local storage, from the PT_TLS segment
+#[derive(Clone, Copy)]
+struct TlsTemplate {
+    start: VirtualAddress,
+    file_size: u64,
+    mem_size: u64,
+    align: u64,
 }
 
 /// Kernel binary was loaded into memory by stage2, here we just handle relocations
@@ -42,17 +57,35 @@ where
             info,
             page_table,
             frame_allocator,
+            image_end: VirtualAddress::new(vbase),
+            dynsym: &[],
+            tls: None,
+            thread_pointer: None,
         }
     }
 
+    /// Value for the FS base of the kernel if it has thread local storage
+    pub fn thread_pointer(&self) -> Option<VirtualAddress> {
+        self.thread_pointer
+    }
+
     pub fn load_kernel(&mut self, info: &BiosInfo) -> VirtualAddress {
-        let kernel = unsafe {
+        let kernel: &'static [u8] = unsafe {
             slice::from_raw_parts(info.kernel.start as *const u8, info.kernel.size as usize)
         };
 
         let kernel_elf = ElfBinary::new(kernel).expect("Unable to parse kernel elf");
+        if let Some(dynsym) = kernel_elf.file.find_section_by_name(".dynsym") {
+            self.dynsym = dynsym.raw_data(&kernel_elf.file);
+        }
 
         kernel_elf.load(self).expect("Can't load the binary?");
+        self.relocate_plt(&kernel_elf);
+
+        // the template is copied after relocation, it might contain pointers
+        if let Some(template) = self.tls {
+            self.thread_pointer = Some(self.allocate_tls(template));
+        }
 
         VirtualAddress::new(self.virtual_base + kernel_elf.entry_point())
     }
@@ -61,15 +94,85 @@ where
     // Basically means: Please fill in the value of (virtual_base + addend)
     // at offset from base of executable
     fn handle_relative_relocation(&mut self, entry: RelocationEntry) {
-        let value_bytes = (self.virtual_base
+        let value = self.virtual_base
             + entry
                 .addend
-                .expect("Relative relocation: addend value = None"))
-        .to_ne_bytes();
+                .expect("Relative relocation: addend value = None");
+        self.write_relocation(entry.offset, value);
+    }
+
+    // Relocations against symbols. Without DT_NEEDED entries every symbol is
+    // defined by the binary itself, so no dynamic linker is needed.
+    fn handle_symbol_relocation(
+        &mut self,
+        entry: RelocationEntry,
+        typ: RelocationTypes,
+    ) -> Result<(), ElfLoaderErr> {
+        let symbol = self.symbol_address(entry.index)?;
+        let value = match typ {
+            RelocationTypes::R_AMD64_64 => symbol.wrapping_add(entry.addend.unwrap_or(0)),
+            // GOT and PLT entries, the addend is 0
+            _ => symbol,
+        };
+        self.write_relocation(entry.offset, value);
+        Ok(())
+    }
+
+    /// Address of the dynamic symbol at `index`
+    fn symbol_address(&self, index: u32) -> Result<u64, ElfLoaderErr> {
+        if index as usize >= self.dynsym.len() / SYMBOL_SIZE {
+            return Err(ElfLoaderErr::ElfParser {
+                source: "Relocation references a symbol outside of .dynsym",
+            });
+        }
+        let symbol = &self.dynsym[index as usize * SYMBOL_SIZE..][..SYMBOL_SIZE];
+        let info = symbol[4];
+        let section_index = u16::from_le_bytes([symbol[6], symbol[7]]);
+        let value = u64::from_le_bytes(symbol[8..16].try_into().unwrap());
+
+        if section_index == 0 {
+            // undefined weak symbols resolve to null
+            assert!(
+                info >> 4 == STB_WEAK,
+                "Kernel elf: undefined symbol {} needs a dynamic linker",
+                index
+            );
+            return Ok(0);
+        }
+        if section_index == SHN_ABS {
+            return Ok(value);
+        }
+        Ok(self.virtual_base + value)
+    }
+
+    // The elfloader only processes .rela.dyn, PLT relocations are in their own
+    // section
+    fn relocate_plt(&mut self, kernel_elf: &ElfBinary) {
+        let Some(section) = kernel_elf.file.find_section_by_name(".rela.plt") else {
+            return;
+        };
+
+        for rela in section.raw_data(&kernel_elf.file).chunks_exact(RELA_SIZE) {
+            let word = |i: usize| u64::from_le_bytes(rela[i * 8..(i + 1) * 8].try_into().unwrap());
+            let info = word(1);
+            let entry = RelocationEntry {
+                rtype: RelocationType::x86_64(RelocationTypes::from(info as u32)),
+                offset: word(0),
+                index: (info >> 32) as u32,
+                addend: Some(word(2)),
+            };
+            self.relocate(entry)
+                .expect("Failed to apply PLT relocation");
+        }
+    }
+
+    /// Writes a relocated value at `offset` from the base of the executable
+    fn write_relocation(&mut self, offset: u64, value: u64) {
+        let value_bytes = value.to_ne_bytes();
 
         // the relocation may span two pages
         // (e.g. 4 bytes of value on page A and 4 bytes on page B)
-        let virtual_address = VirtualAddress::new(self.virtual_base + entry.offset);
+        let virtual_address = VirtualAddress::new(self.virtual_base + offset);
         let start_page = Page::containing_address(virtual_address);
         let end_page = Page::containing_address(virtual_address + value_bytes.len());
         let mut bytes_written = 0;
@@ -171,7 +274,13 @@ where
         flusher.ignore();
 
         self.page_table
-            .map_to(new_frame, page, new_flags, self.frame_allocator)
+            .map_to(
+                new_frame,
+                page,
+                new_flags,
+                CacheAttribute::WriteBack,
+                self.frame_allocator,
+            )
             .expect("make mut: failed to map page to new frame")
             .flush();
 
@@ -179,6 +288,90 @@ where
     }
 }
 
+impl<'a, M, A> KernelLoader<'a, M, A>
+where
+    M: MapperAllSizes + TranslatorAllSizes,
+    A: FrameAllocator<Size4KiB>,
+{
+    // x86_64 uses TLS variant II: the TLS block lies right below the thread
+    // pointer, which points to the thread control block (TCB). The first word
+    // of the TCB points to itself, so %fs:0 yields the thread pointer.
+    //
+    //  <  tdata  |  tbss  > < TCB >
+    //                       ^ FS base
+    //
+    // The block is mapped in its own pages a page above the kernel image.
+    fn allocate_tls(&mut self, template: TlsTemplate) -> VirtualAddress {
+        let align = template.align.max(8);
+        let block_size = VirtualAddress::new(template.mem_size)
+            .align_up(align)
+            .as_u64();
+
+        let start = self.image_end.align_up(Size4KiB::SIZE) + Size4KiB::SIZE;
+        let thread_pointer = (start + block_size).align_up(align);
+        let block_start = thread_pointer - block_size;
+
+        let start_page = Page::<Size4KiB>::containing_address(start);
+        let tcb_end = thread_pointer + mem::size_of::<u64>() as u64;
+        let end_page = Page::containing_address(tcb_end - 1u64);
+        for page in Page::range_inclusive(start_page, end_page) {
+            let frame = self
+                .frame_allocator
+                .allocate_frame()
+                .expect("Failed to allocate frame for TLS");
+
+            unsafe { ptr::write_bytes(frame.start() as *mut u8, 0, frame.size()) };
+
+            self.page_table
+                .map_to(
+                    frame,
+                    page,
+                    PageTableEntryFlags::PRESENT
+                        | PageTableEntryFlags::WRITABLE
+                        | PageTableEntryFlags::NO_EXECUTE,
+                    CacheAttribute::WriteBack,
+                    self.frame_allocator,
+                )
+                .expect("Failed to map TLS")
+                .ignore();
+        }
+
+        // .tbss stays zero
+        for i in 0..template.file_size {
+            unsafe {
+                *self.physical_ptr(block_start + i) = *self.physical_ptr(template.start + i);
+            }
+        }
+        unsafe {
+            *self.physical_ptr(thread_pointer).cast::<u64>() = thread_pointer.as_u64();
+        }
+
+        println!(
+            "Kernel elf: TLS block at {:#x}, thread pointer {:#x}",
+            block_start, thread_pointer
+        );
+        thread_pointer
+    }
+
+    /// Translates a kernel address to the identity mapped physical address
+    fn physical_ptr(&self, address: VirtualAddress) -> *mut u8 {
+        let page = Page::<Size4KiB>::containing_address(address);
+        let (frame, _) = self
+            .page_table
+            .translate(page)
+            .expect("Kernel address not mapped");
+        (frame.start() + (address - page.address)) as *mut u8
+    }
+}
+
+/// Size of an Elf64_Sym
+const SYMBOL_SIZE: usize = 24;
+/// Size of an Elf64_Rela
+const RELA_SIZE: usize = 24;
+const STB_WEAK: u8 = 2;
+/// Section index of symbols whose value isn't relative to the load address
+const SHN_ABS: u16 = 0xfff1;
+
 const COPIED: PageTableEntryFlags = PageTableEntryFlags::BIT_9;
 
 impl<'a, M, A> ElfLoader for KernelLoader<'a, M, A>
@@ -207,6 +400,15 @@ where
             let virtual_start_address =
                 VirtualAddress::new(self.virtual_base + header.virtual_addr());
             let start_page = Page::containing_address(virtual_start_address);
+            self.image_end = cmp::max(self.image_end, virtual_start_address + header.mem_size());
+
+            // W^X, code must not be modifiable after loading
+            if header.flags().is_write() && header.flags().is_execute() {
+                panic!(
+                    "Kernel elf: segment at {:#x} is writable and executable",
+                    virtual_start_address
+                );
+            }
 
             let mut flags = PageTableEntryFlags::PRESENT;
             if !header.flags().is_execute() {
@@ -232,7 +434,13 @@ where
                 */
 
                 self.page_table
-                    .map_to(frame, page, flags, self.frame_allocator)
+                    .map_to(
+                        frame,
+                        page,
+                        flags,
+                        CacheAttribute::WriteBack,
+                        self.frame_allocator,
+                    )
                     .expect("Failed to map section")
                     .ignore();
             }
@@ -297,7 +505,13 @@ where
                     }
 
                     self.page_table
-                        .map_to(frame, page, flags, self.frame_allocator)
+                        .map_to(
+                            frame,
+                            page,
+                            flags,
+                            CacheAttribute::WriteBack,
+                            self.frame_allocator,
+                        )
                         .expect("Failed to map .bss section")
                         .ignore();
                 }
@@ -313,6 +527,11 @@ where
                 RelocationTypes::R_AMD64_RELATIVE => {
                     self.handle_relative_relocation(entry);
                 }
+                RelocationTypes::R_AMD64_64
+                | RelocationTypes::R_AMD64_GLOB_DAT
+                | RelocationTypes::R_AMD64_JMP_SLOT => {
+                    self.handle_symbol_relocation(entry, typ)?;
+                }
                 _ => panic!("Unhandled relocation type: {:?}", typ),
             },
             _ => panic!("Expected x86_64 relocation type but got x86 relocation type"),
@@ -320,6 +539,46 @@ where
         Ok(())
     }
 
+    // Called for GNU_RELRO after relocation. Only pages completely inside the
+    // range are remapped, the first and last page may share writable data
+    fn make_readonly(&mut self, base: VAddr, size: usize) -> Result<(), ElfLoaderErr> {
+        let start = VirtualAddress::new(self.virtual_base + base).align_up(Size4KiB::SIZE);
+        let end =
+            VirtualAddress::new(self.virtual_base + base + size as u64).align_down(Size4KiB::SIZE);
+        println!("Kernel elf: relro {:#x} - {:#x}", start, end);
+        if start >= end {
+            return Ok(());
+        }
+
+        let start_page = Page::<Size4KiB>::containing_address(start);
+        let end_page = Page::containing_address(end - 1u64);
+        for page in Page::range_inclusive(start_page, end_page) {
+            let (frame, flags) = self
+                .page_table
+                .translate(page)
+                .expect("Relro: page not mapped");
+
+            let (_, flusher) = self
+                .page_table
+                .unmap(page)
+                .expect("Relro: failed to unmap page");
+            flusher.ignore();
+
+            self.page_table
+                .map_to(
+                    frame,
+                    page,
+                    flags - PageTableEntryFlags::WRITABLE,
+                    CacheAttribute::WriteBack,
+                    self.frame_allocator,
+                )
+                .expect("Relro: failed to remap page")
+                .flush();
+        }
+
+        Ok(())
+    }
+
     fn load(&mut self, flags: Flags, base: VAddr, region: &[u8]) -> Result<(), ElfLoaderErr> {
         //println!("Load called at {:#x}, flags = {}", base, flags);
         Ok(())
@@ -328,15 +587,22 @@ where
     fn tls(
         &mut self,
         tdata_start: VAddr,
-        _tdata_length: u64,
+        tdata_length: u64,
         total_size: u64,
-        _align: u64,
+        align: u64,
     ) -> Result<(), ElfLoaderErr> {
-        let tls_end = tdata_start + total_size;
+        let start = VirtualAddress::new(self.virtual_base + tdata_start);
         println!(
-            "Initial TLS region is at = {:#x} -- {:#x}",
-            tdata_start, tls_end
+            "Kernel elf: TLS template at {:#x} -- {:#x}",
+            start,
+            start + total_size
         );
+        self.tls = Some(TlsTemplate {
+            start,
+            file_size: tdata_length,
+            mem_size: total_size,
+            align,
+        });
         Ok(())
     }
 }
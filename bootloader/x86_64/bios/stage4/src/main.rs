@@ -11,35 +11,34 @@ use core::{
 };
 mod elf;
 mod interrupts;
+mod signature;
 use crate::elf::KernelLoader;
-use api::{BootInfo, PhysicalMemoryRegions};
+use api::{
+    cmdline::CommandLine,
+    compression,
+    layout::{self, BOOT_STACK_TOP, KERNEL_BASE, RECURSIVE_INDEX},
+    BootInfo, PhysicalMemoryRegions,
+};
 use common::{hlt, BiosInfo, E820MemoryRegion};
 use core::alloc::Layout;
 use x86_64::{
+    cpuid::{self, Features},
     gdt::{self, SegmentDescriptor},
     memory::{
         Address, FrameAllocator, MemoryRegion, Page, PageSize, PhysicalAddress, PhysicalFrame,
-        PhysicalMemoryRegion, PhysicalMemoryRegionType, Size2MiB, Size4KiB, VirtualAddress, KIB,
-        TIB,
+        PhysicalMemoryRegion, PhysicalMemoryRegionType, Size1GiB, Size2MiB, Size4KiB,
+        VirtualAddress, KIB,
     },
     paging::{
         bump_frame_allocator::BumpFrameAllocator,
         offset_page_table::{OffsetPageTable, PhysicalOffset},
-        Mapper, MapperAllSizes, PageTable, PageTableEntryFlags,
+        CacheAttribute, Mapper, MapperAllSizes, PageTable, PageTableEntryFlags,
     },
     println,
-    register::{Cr0, Cr0Flags, Efer, EferFlags},
+    register::{Cr0, Cr0Flags, Efer, EferFlags, FsBase},
 };
 
-// hardcoded for now
-const KERNEL_VIRTUAL_BASE: u64 = 0xffffffff80000000;
-const KERNEL_STACK_TOP: u64 = 0xffffffff00000000;
 const KERNEL_STACK_SIZE: u64 = 128 * KIB;
-// map the complete physical address space at this offset in order to enable
-// the kernel to easily access the page table
-// https://os.phil-opp.com/paging-implementation/#map-at-a-fixed-offset
-// map it at an offset of 10 TB
-const PHYSICAL_MEMORY_OFFSET: u64 = 10 * TIB;
 
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
@@ -81,10 +80,10 @@ where
     A: FrameAllocator<Size4KiB>,
     M: Mapper<Size4KiB>,
 {
-    let end_page = Page::containing_address(VirtualAddress::new(KERNEL_STACK_TOP));
+    let end_page = Page::containing_address(VirtualAddress::new(BOOT_STACK_TOP));
     // grows downwards
     let start_page = Page::containing_address(VirtualAddress::new(
-        KERNEL_STACK_TOP - KERNEL_STACK_SIZE as u64,
+        BOOT_STACK_TOP - KERNEL_STACK_SIZE as u64,
     ));
     for page in Page::range_inclusive(start_page, end_page) {
         let frame = frame_allocator
@@ -96,7 +95,13 @@ where
             | PageTableEntryFlags::NO_EXECUTE;
 
         page_table
-            .map_to(frame, page, flags, frame_allocator)
+            .map_to(
+                frame,
+                page,
+                flags,
+                CacheAttribute::WriteBack,
+                frame_allocator,
+            )
             .expect("Failed to map stack page")
             .ignore();
     }
@@ -113,6 +118,7 @@ where
             frame,
             guard_page,
             PageTableEntryFlags::NONE,
+            CacheAttribute::WriteBack,
             frame_allocator,
         )
         .expect("Failed to map guard page")
@@ -132,12 +138,18 @@ where
         PhysicalFrame::containing_address(PhysicalAddress::new(context_switch as *const () as u64));
     let flags = PageTableEntryFlags::PRESENT;
     page_table
-        .identity_map(context_switch_function, flags, frame_allocator)
+        .identity_map(
+            context_switch_function,
+            flags,
+            CacheAttribute::WriteBack,
+            frame_allocator,
+        )
         .expect("Identify mapping failed")
         .ignore();
 }
 
-fn initialize_and_map_gdt<A, M>(frame_allocator: &mut A, page_table: &mut M)
+/// Returns the frame of the GDT, the kernel loads its own one
+fn initialize_and_map_gdt<A, M>(frame_allocator: &mut A, page_table: &mut M) -> PhysicalFrame
 where
     A: FrameAllocator<Size4KiB>,
     M: MapperAllSizes,
@@ -161,71 +173,74 @@ where
 
     // TODO: why is this actually needed ? cpu accesses the gdt based on physical address
     page_table
-        .identity_map(frame, PageTableEntryFlags::PRESENT, frame_allocator)
+        .identity_map(
+            frame,
+            PageTableEntryFlags::PRESENT,
+            CacheAttribute::WriteBack,
+            frame_allocator,
+        )
         .expect("Identity mapping gdt failed")
         .ignore();
+
+    frame
+}
+
+/// Whole frames of `region`, partially used frames at its ends may be shared
+/// with other data
+fn reclaimable_frames(region: PhysicalMemoryRegion) -> PhysicalMemoryRegion {
+    let start = region.start().next_multiple_of(Size4KiB::SIZE);
+    let end = region.end() & !(Size4KiB::SIZE - 1);
+    PhysicalMemoryRegion::new(
+        start,
+        end.saturating_sub(start),
+        PhysicalMemoryRegionType::BootloaderReclaimable,
+    )
 }
 
-/// Returns the current state of the memory (which regions are used and which are not)
-//  Splits a memory region into two of only part of it is used
-fn build_memory_map<A, S>(
-    allocator: &A,
+/// Returns the current state of the memory (which regions are used and which
+/// are not) by writing it to `out`, which needs room for one region more than
+/// `regions` plus two per reclaimable region. Returns the amount of regions
+/// written.
+//  Usable memory is handed out linearly, so everything below `used_end` is used
+//  by the bootloader and the region it ends in is split. The `reclaimable`
+//  regions, sorted by address, are cut out of the used memory.
+fn build_memory_map(
     regions: &[E820MemoryRegion],
-    last_frame: &PhysicalFrame<S>,
-) -> [Option<PhysicalMemoryRegion>; 0x20]
-where
-    A: FrameAllocator<S>,
-    S: PageSize,
-{
-    let mut new_regions = [None; 0x20];
-    let mut idx: usize = 0;
-    for (i, region) in regions.iter().enumerate() {
-        if !region.is_usable() {
-            new_regions[idx] = Some(region.into());
-            idx += 1;
-        } else {
-            // MBR & stage1, stage2 region => mark as used
-            if region.start() == 0x0 {
-                let mut new_region: PhysicalMemoryRegion = region.into();
-                new_region.typ = PhysicalMemoryRegionType::Reserved;
-                new_regions[idx] = Some(new_region);
-                continue;
-            }
-            // split region into usable and unusable pair if the region is not
-            // completely allocated
-            if region.contains(last_frame.address.as_u64()) {
-                let sz = last_frame.end() - region.start();
-                let used_region = PhysicalMemoryRegion::new(
-                    region.start(),
-                    sz,
-                    PhysicalMemoryRegionType::Reserved,
-                );
-
-                new_regions[idx] = Some(used_region);
-                idx += 1;
-
-                if last_frame.end() != region.end() {
-                    let sz = region.end() - last_frame.end();
-                    let free_region = PhysicalMemoryRegion::new(
-                        last_frame.end(),
-                        sz,
-                        PhysicalMemoryRegionType::Free,
-                    );
-
-                    new_regions[idx] = Some(free_region);
-                    idx += 1;
-                }
-            } else {
-                new_regions[idx] = Some(region.into());
-                idx += 1;
-            }
+    used_end: u64,
+    reclaimable: &[PhysicalMemoryRegion],
+    out: &mut [PhysicalMemoryRegion],
+) -> usize {
+    let mut memory_map = memory_map::Builder::new(out);
+    let mut push = |region| {
+        memory_map
+            .push(region)
+            .expect("Memory map doesn't fit into the boot info")
+    };
+
+    for region in regions {
+        let mut new_region: PhysicalMemoryRegion = region.into();
+        // MBR & stage1, stage2 region => mark as used
+        if region.start() == 0x0 {
+            new_region.typ = PhysicalMemoryRegionType::Reserved;
         }
 
-        // Need a better solution if this happens or increase the array size
-        assert!(idx < new_regions.len())
+        let (mut used, free) = memory_map::split(new_region, used_end);
+        if used.is_usable() {
+            used.typ = PhysicalMemoryRegionType::Bootloader;
+            for range in reclaimable {
+                let (below, rest) = memory_map::split(used, range.start());
+                let (mut inner, above) = memory_map::split(rest, range.end());
+                inner.typ = PhysicalMemoryRegionType::BootloaderReclaimable;
+                push(below);
+                push(inner);
+                used = above;
+            }
+        }
+        push(used);
+        push(free);
     }
 
-    new_regions
+    memory_map.len()
 }
 
 fn allocate_and_map_boot_info<A, M>(
@@ -233,55 +248,74 @@ fn allocate_and_map_boot_info<A, M>(
     page_table: &mut M,
     info: &BiosInfo,
     e820_memory_map: &[E820MemoryRegion],
+    reclaimable: &[PhysicalMemoryRegion],
 ) -> VirtualAddress
 where
     A: FrameAllocator<Size4KiB>,
     M: MapperAllSizes,
 {
+    // the memory regions array follows the boot info, stage2 sorted and
+    // clipped the regions so building the map splits at most one of them
+    let max_regions = e820_memory_map.len() + 1 + 2 * reclaimable.len();
+    let memory_regions_layout = Layout::array::<PhysicalMemoryRegion>(max_regions).unwrap();
+    let (combined_layout, memory_regions_offset) = Layout::new::<BootInfo>()
+        .extend(memory_regions_layout)
+        .unwrap();
+
+    let frame_count = combined_layout.size().div_ceil(Size4KiB::SIZE as usize);
     let frame = frame_allocator
-        .allocate_frame()
-        .expect("Failed to allocate frame for boot info");
-
-    let mut boot_info_layout = Layout::new::<BootInfo>();
-    let memory_map = build_memory_map(frame_allocator, e820_memory_map, &frame);
-    let usable_memory_regions_amount = memory_map.iter().filter(|r| r.is_some()).count();
-
-    // write MemoryRegions array onto the same frame behind the bootinfo struct
-    let memory_regions_layout =
-        Layout::array::<PhysicalMemoryRegion>(usable_memory_regions_amount).unwrap();
-    let (combined_layout, memory_regions_offset) =
-        boot_info_layout.extend(memory_regions_layout).unwrap();
+        .allocate_contiguous(frame_count, Size4KiB::SIZE)
+        .expect("Failed to allocate frames for boot info");
+    let last_frame = frame + (frame_count - 1) as u64;
 
-    assert!(
-        combined_layout.size() <= Size4KiB::SIZE.try_into().unwrap(),
-        "Required memory for boot info is bigger than page size"
-    );
-
-    // write memory regions information to allocated frame
+    // physical memory is identity mapped
     let memory_regions_ptr: *mut PhysicalMemoryRegion =
         (frame.address + memory_regions_offset).as_mut_ptr();
-
-    for (idx, mem_region) in memory_map.iter().filter_map(|r| r.as_ref()).enumerate() {
-        let ptr = unsafe { memory_regions_ptr.add(idx) };
-        unsafe { ptr::write(ptr, *mem_region) };
-    }
+    let memory_regions_amount = unsafe {
+        ptr::write_bytes(memory_regions_ptr, 0, max_regions);
+        build_memory_map(
+            e820_memory_map,
+            last_frame.end(),
+            reclaimable,
+            slice::from_raw_parts_mut(memory_regions_ptr, max_regions),
+        )
+    };
 
     // write bootinfo to allocated frame
-    let memory_regions =
-        PhysicalMemoryRegions::new(memory_regions_ptr, usable_memory_regions_amount);
+    let memory_regions = PhysicalMemoryRegions::new(memory_regions_ptr, memory_regions_amount);
+    // physical memory is identity mapped
+    let cmdline = match info.cmdline.size {
+        0 => CommandLine::empty(),
+        size => CommandLine::new(unsafe {
+            slice::from_raw_parts(info.cmdline.start as *const u8, size as usize)
+        }),
+    };
     let boot_info = BootInfo::new(
         info.kernel,
         info.framebuffer,
+        info.initramfs,
+        info.symbols,
+        cmdline,
+        info.video_modes,
+        info.edid,
         memory_regions,
-        PHYSICAL_MEMORY_OFFSET,
+        layout::PHYSICAL_MEMORY.start,
+        RECURSIVE_INDEX,
     );
     unsafe { ptr::write(frame.address.as_mut_ptr(), boot_info) };
 
     let virtual_address = VirtualAddress::new(frame.address.as_u64());
     let page = Page::for_address(virtual_address);
+    let last = frame_count as u64 - 1;
 
     page_table
-        .map_to(frame, page, PageTableEntryFlags::PRESENT, frame_allocator)
+        .map_range(
+            PhysicalFrame::range_inclusive(frame, frame + last),
+            Page::range_inclusive(page, page + last),
+            PageTableEntryFlags::PRESENT,
+            CacheAttribute::WriteBack,
+            frame_allocator,
+        )
         .expect("Failed to map boot info")
         .ignore();
 
@@ -302,28 +336,64 @@ fn map_complete_physical_memory_space_into_kernel<A, M>(
         "Mapping complete physical address space to offset: {:#x}",
         offset.as_u64()
     );
-    let start = PhysicalFrame::containing_address(PhysicalAddress::new(0));
-    let end = PhysicalFrame::containing_address(highest_physical_address);
-    let alignment = Size2MiB::SIZE;
-    // check 2MiB alignment
-    assert!(offset.as_u64() % alignment == 0);
+    // 1GiB pages need a single page table instead of one per GiB
+    if cpuid::has(Features::PAGE_1GIB) {
+        map_physical_memory::<Size1GiB, _, _>(
+            frame_allocator,
+            page_table,
+            highest_physical_address,
+            offset,
+        );
+    } else {
+        map_physical_memory::<Size2MiB, _, _>(
+            frame_allocator,
+            page_table,
+            highest_physical_address,
+            offset,
+        );
+    }
+}
 
-    for frame in PhysicalFrame::<Size2MiB>::range_inclusive(start, end) {
-        let page = Page::containing_address(offset + frame.start());
+fn map_physical_memory<S, A, M>(
+    frame_allocator: &mut A,
+    page_table: &mut M,
+    highest_physical_address: PhysicalAddress,
+    offset: VirtualAddress,
+) where
+    S: PageSize,
+    A: FrameAllocator<Size4KiB>,
+    M: Mapper<S>,
+{
+    let start = PhysicalFrame::<S>::containing_address(PhysicalAddress::new(0));
+    let end = PhysicalFrame::containing_address(highest_physical_address);
+    assert!(offset.as_u64() % S::SIZE == 0);
 
-        let flags = PageTableEntryFlags::PRESENT
-            | PageTableEntryFlags::WRITABLE
-            | PageTableEntryFlags::NO_EXECUTE;
-        page_table
-            .map_to(frame, page, flags, frame_allocator)
-            .expect("Failed to map all of RAM to kernel space")
-            .ignore();
-    }
+    let flags = PageTableEntryFlags::PRESENT
+        | PageTableEntryFlags::WRITABLE
+        | PageTableEntryFlags::NO_EXECUTE;
+    page_table
+        .map_range(
+            PhysicalFrame::range_inclusive(start, end),
+            Page::range_inclusive(
+                Page::containing_address(offset + start.start()),
+                Page::containing_address(offset + end.start()),
+            ),
+            flags,
+            CacheAttribute::WriteBack,
+            frame_allocator,
+        )
+        .expect("Failed to map all of RAM to kernel space")
+        .ignore();
 }
 
 /// Enable the No execute enable bit in the Efer register
 /// Allows to set the Execute Disable flag on page table entries
 fn enable_nxe_bit() {
+    // the bit is reserved otherwise and every mapping would fault
+    assert!(
+        cpuid::has(Features::NX),
+        "CPU doesn't support no-execute pages"
+    );
     unsafe {
         Efer::update(|val| *val |= EferFlags::NO_EXECUTE_ENABLE);
     }
@@ -339,6 +409,41 @@ fn trigger_page_fault() {
     unsafe { *(0xdeabeefdead as *mut u8) = 42 };
 }
 
+/// Decompresses a compressed kernel into newly allocated frames, returns the
+/// region of the ELF file
+fn decompress_kernel<A>(
+    kernel: PhysicalMemoryRegion,
+    frame_allocator: &mut A,
+) -> PhysicalMemoryRegion
+where
+    A: FrameAllocator<Size4KiB>,
+{
+    let data = unsafe { slice::from_raw_parts(kernel.start as *const u8, kernel.size as usize) };
+    if !compression::is_compressed(data) {
+        return kernel;
+    }
+
+    let size = compression::decompressed_size(data).expect("Invalid compressed kernel");
+    let frames = size.div_ceil(Size4KiB::SIZE as usize).max(1);
+    let start = frame_allocator
+        .allocate_contiguous(frames, Size4KiB::SIZE)
+        .expect("Failed to allocate frames for the decompressed kernel");
+
+    // physical memory is identity mapped
+    let out = unsafe { slice::from_raw_parts_mut(start.start() as *mut u8, size) };
+    compression::decompress(data, out).expect("Failed to decompress kernel");
+    println!(
+        "Kernel decompressed: {:#x} -> {:#x} bytes",
+        kernel.size, size
+    );
+
+    PhysicalMemoryRegion::new(
+        start.start(),
+        size as u64,
+        PhysicalMemoryRegionType::Reserved,
+    )
+}
+
 fn start(info: &BiosInfo) -> ! {
     println!("Stage4");
 
@@ -361,6 +466,19 @@ fn start(info: &BiosInfo) -> ! {
     let mut allocator =
         BumpFrameAllocator::new_starting_at(next_free_frame, memory_map.iter().copied().peekable());
 
+    // before the ELF parser touches the kernel
+    signature::verify_kernel(info);
+
+    // the loader and the boot info refer to the decompressed kernel
+    let mut bios_info = info.clone();
+    bios_info.kernel = decompress_kernel(info.kernel, &mut allocator);
+    // the compressed image, if the kernel was decompressed
+    let compressed_kernel = match bios_info.kernel.start == info.kernel.start {
+        true => PhysicalMemoryRegion::default(),
+        false => info.kernel,
+    };
+    let info = &bios_info;
+
     let kernel_page_table_frame = allocator
         .allocate_frame()
         .expect("Failed to allocate frame for kernel page table");
@@ -368,31 +486,63 @@ fn start(info: &BiosInfo) -> ! {
     let kernel_page_table_address = VirtualAddress::new(kernel_page_table_frame.start());
     let kernel_page_table =
         unsafe { PageTable::initialize_empty_at_address(kernel_page_table_address) };
+    kernel_page_table[RECURSIVE_INDEX as usize].set_address(
+        kernel_page_table_frame.address(),
+        PageTableEntryFlags::PRESENT
+            | PageTableEntryFlags::WRITABLE
+            | PageTableEntryFlags::NO_EXECUTE,
+    );
     // 1:1 mapping
     let mapping = PhysicalOffset::new(0);
     let mut page_table = OffsetPageTable::new(kernel_page_table, mapping);
 
-    let mut loader = KernelLoader::new(KERNEL_VIRTUAL_BASE, info, &mut page_table, &mut allocator);
+    let mut loader = KernelLoader::new(KERNEL_BASE, info, &mut page_table, &mut allocator);
     let kernel_entry_point = loader.load_kernel(info);
+    let thread_pointer = loader.thread_pointer();
 
     let stack_top = allocate_and_map_stack(&mut allocator, &mut page_table);
 
     identity_map_context_switch_function(&mut allocator, &mut page_table);
 
-    initialize_and_map_gdt(&mut allocator, &mut page_table);
+    let gdt_frame = initialize_and_map_gdt(&mut allocator, &mut page_table);
+
+    // only needed to get here, the kernel may reuse them once it took over
+    let mut reclaimable = [
+        info.stage3,
+        info.stage4,
+        info.cmdline,
+        info.kernel_signature,
+        compressed_kernel,
+        PhysicalMemoryRegion::new(
+            gdt_frame.start(),
+            Size4KiB::SIZE,
+            PhysicalMemoryRegionType::BootloaderReclaimable,
+        ),
+    ]
+    .map(reclaimable_frames);
+    reclaimable.sort_unstable_by_key(|region| region.start);
 
     // No more allocations should be done after the boot info has been allocated.
     // Otherwise memory regions information is incorrect
-    let boot_info_address =
-        allocate_and_map_boot_info(&mut allocator, &mut page_table, &info, memory_map);
+    let boot_info_address = allocate_and_map_boot_info(
+        &mut allocator,
+        &mut page_table,
+        &info,
+        memory_map,
+        &reclaimable,
+    );
 
     let max_physical_address = allocator.max_physical_address();
+    assert!(
+        max_physical_address.as_u64() <= layout::PHYSICAL_MEMORY.size,
+        "Physical memory doesn't fit into its mapping"
+    );
 
     map_complete_physical_memory_space_into_kernel(
         &mut allocator,
         &mut page_table,
         max_physical_address,
-        VirtualAddress::new(PHYSICAL_MEMORY_OFFSET),
+        VirtualAddress::new(layout::PHYSICAL_MEMORY.start),
     );
 
     // todo: detect RSDP (Root System Description Pointer)
@@ -402,6 +552,11 @@ fn start(info: &BiosInfo) -> ! {
         kernel_page_table_frame.start()
     );
 
+    // the FS base is not touched by the switch, the kernel finds its TLS there
+    if let Some(thread_pointer) = thread_pointer {
+        FsBase::write(thread_pointer);
+    }
+
     context_switch(
         kernel_page_table_frame.start(),
         stack_top.as_u64(),
@@ -0,0 +1,54 @@
+//! Verification of the kernel signature, see `api::signature`.
+use api::{
+    ed25519::{self, SIGNATURE_SIZE},
+    signature::TrustAnchor,
+};
+use common::BiosInfo;
+use core::{ptr, slice};
+use x86_64::println;
+
+/// Patched in the stage4 binary by the disk image builder
+#[used]
+#[no_mangle]
+static TRUST_ANCHOR: TrustAnchor = TrustAnchor::UNSET;
+
+/// Checks the signature of the kernel. Panics if it is invalid, or missing
+/// while the trust anchor requires one.
+pub fn verify_kernel(info: &BiosInfo) {
+    // volatile since the value is changed after compilation
+    let anchor = unsafe { ptr::read_volatile(&TRUST_ANCHOR) };
+
+    let Some(public_key) = anchor.public_key() else {
+        assert!(
+            !anchor.requires_signature(),
+            "Signed kernel required, but no public key configured"
+        );
+        println!("No public key configured, kernel signature not checked");
+        return;
+    };
+
+    if info.kernel_signature.size == 0 {
+        assert!(
+            !anchor.requires_signature(),
+            "Refusing to boot unsigned kernel"
+        );
+        println!("Kernel is not signed");
+        return;
+    }
+
+    let signature: &[u8; SIGNATURE_SIZE] = unsafe {
+        slice::from_raw_parts(
+            info.kernel_signature.start as *const u8,
+            info.kernel_signature.size as usize,
+        )
+    }
+    .try_into()
+    .expect("Invalid kernel signature size");
+    let kernel =
+        unsafe { slice::from_raw_parts(info.kernel.start as *const u8, info.kernel.size as usize) };
+
+    match ed25519::verify(public_key, kernel, signature) {
+        Ok(()) => println!("Kernel signature verified"),
+        Err(error) => panic!("Kernel signature invalid: {:?}", error),
+    }
+}
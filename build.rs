@@ -18,11 +18,41 @@ fn main() {
 
     let bios_img = Path::new("bios.img");
     let kernel_path = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
-    bootloader::bios::BiosBoot::new(&kernel_path).create_disk_image(&bios_img);
+    let mut bios_boot = bootloader::bios::BiosBoot::new(&kernel_path);
+    // contents of the initramfs directory are unpacked into the root fs at boot
+    let initramfs_dir = Path::new("initramfs");
+    if initramfs_dir.is_dir() {
+        println!("cargo:rerun-if-changed={}", initramfs_dir.display());
+        bios_boot.set_initramfs(initramfs_dir);
+    }
+    // kernel command line, e.g. `log=debug`
+    let cmdline_path = Path::new("cmdline.txt");
+    println!("cargo:rerun-if-changed={}", cmdline_path.display());
+    if let Ok(cmdline) = fs::read_to_string(cmdline_path) {
+        bios_boot.set_cmdline(cmdline.trim());
+    }
+    bios_boot.create_disk_image(&bios_img);
 
     // pass the disk image paths as env variables to the `main.rs`
     println!("cargo:rustc-env=BIOS_PATH={}", bios_img.display());
 
+    // large enough to be formatted as FAT32, the kernel is additionally
+    // stored in a subdirectory
+    let unittests_path = PathBuf::from(
+        std::env::var_os("CARGO_BIN_FILE_TEST_KERNEL_UNITTESTS_test_kernel_unittests").unwrap(),
+    );
+    let fat32_img = Path::new("test_fat32.img");
+    bootloader::bios::BiosBoot::new(&unittests_path)
+        .add_file("boot/kernel.elf", &unittests_path)
+        .set_volume_label("MINIATURE")
+        .set_partition_size(512 * 1024 * 1024)
+        .create_disk_image(&fat32_img);
+    println!("cargo:rustc-env=FAT32_BIOS_PATH={}", fat32_img.display());
+    println!(
+        "cargo:rustc-env=FAT32_KERNEL_PATH={}",
+        unittests_path.display()
+    );
+
     for test_kernel in fs::read_dir("tests")
         .unwrap()
         .map(|entry| entry.unwrap().path())
@@ -0,0 +1,251 @@
+//! ACPI table discovery.
+//!
+//! The BIOS places the root system description pointer (RSDP) either in the
+//! first KiB of the extended BIOS data area or in the BIOS ROM area below 1 MiB.
+//! It points to the RSDT or, since ACPI 2.0, to the XSDT, which list the
+//! addresses of all other tables. Tables are read through the physical memory
+//! mapping until [`copy_tables`] copied them to the heap, since the firmware
+//! places them in memory the kernel takes over. They are never modified.
+//!
+//! https://wiki.osdev.org/RSDP
+extern crate alloc;
+use crate::paging;
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    mem::size_of,
+    ptr, slice,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use x86_64::{
+    memory::{Address, PhysicalAddress},
+    mutex::Mutex,
+};
+
+const RSDP_SIGNATURE: [u8; 8] = *b"RSD PTR ";
+/// The EBDA segment is stored at this address in the BIOS data area
+const EBDA_POINTER: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+/// Offset of the RTC century register index in the FADT
+const FADT_CENTURY: usize = 108;
+/// Offset of the interrupt controller entries in the MADT
+const MADT_ENTRIES: usize = 44;
+const MADT_IO_APIC: u8 = 1;
+
+/// Address of the RSDT or XSDT, 0 if none was found
+static ROOT_TABLE: AtomicU64 = AtomicU64::new(0);
+static IS_XSDT: AtomicBool = AtomicBool::new(false);
+/// Copies of the tables once [`copy_tables`] ran
+static TABLES: Mutex<Option<Vec<&'static [u8]>>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    RsdpNotFound,
+    InvalidChecksum,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // only valid for revision >= 2
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+/// Size of the ACPI 1.0 part of the RSDP
+const RSDP_V1_SIZE: usize = 20;
+
+/// Header every system description table starts with
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Location of a register block
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    /// 0 for memory, 1 for I/O ports
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// HPET description table
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct HpetTable {
+    pub header: SdtHeader,
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+fn read<T: Copy>(address: PhysicalAddress) -> T {
+    unsafe { ptr::read_unaligned(paging::physical_to_virtual(address).as_ptr()) }
+}
+
+fn checksum_valid(address: PhysicalAddress, len: usize) -> bool {
+    (0..len as u64)
+        .map(|i| read::<u8>(address + i))
+        .fold(0u8, |sum, byte| sum.wrapping_add(byte))
+        == 0
+}
+
+fn find_rsdp_in(start: u64, end: u64) -> Option<PhysicalAddress> {
+    // the RSDP is 16 byte aligned
+    (start..end)
+        .step_by(16)
+        .map(PhysicalAddress::new)
+        .find(|address| {
+            read::<[u8; 8]>(*address) == RSDP_SIGNATURE && checksum_valid(*address, RSDP_V1_SIZE)
+        })
+}
+
+fn find_rsdp() -> Option<PhysicalAddress> {
+    let ebda = (read::<u16>(PhysicalAddress::new(EBDA_POINTER)) as u64) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = find_rsdp_in(ebda, ebda + 1024) {
+            return Some(rsdp);
+        }
+    }
+    find_rsdp_in(BIOS_AREA_START, BIOS_AREA_END)
+}
+
+/// Locates the root table. Requires the physical memory mapping.
+pub fn init() -> Result<(), AcpiError> {
+    let address = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let rsdp: Rsdp = read(address);
+
+    let (root, is_xsdt) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        if !checksum_valid(address, rsdp.length as usize) {
+            return Err(AcpiError::InvalidChecksum);
+        }
+        (rsdp.xsdt_address, true)
+    } else {
+        (rsdp.rsdt_address as u64, false)
+    };
+
+    let header: SdtHeader = read(PhysicalAddress::new(root));
+    if !checksum_valid(PhysicalAddress::new(root), header.length as usize) {
+        return Err(AcpiError::InvalidChecksum);
+    }
+
+    IS_XSDT.store(is_xsdt, Ordering::SeqCst);
+    ROOT_TABLE.store(root, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Addresses of all tables the root table lists
+fn table_addresses() -> impl Iterator<Item = PhysicalAddress> {
+    let root = PhysicalAddress::new(ROOT_TABLE.load(Ordering::SeqCst));
+    let (entries, entry_size) = match root.as_u64() {
+        0 => (0, 4),
+        _ => {
+            let header: SdtHeader = read(root);
+            let entry_size = if IS_XSDT.load(Ordering::SeqCst) { 8 } else { 4 };
+            let len = (header.length as usize).saturating_sub(size_of::<SdtHeader>());
+            (len / entry_size, entry_size)
+        }
+    };
+    let first = root + size_of::<SdtHeader>() as u64;
+
+    (0..entries as u64)
+        .map(move |i| match entry_size {
+            8 => read::<u64>(first + i * 8),
+            _ => read::<u32>(first + i * 4) as u64,
+        })
+        .map(PhysicalAddress::new)
+        .filter(|table| {
+            let header: SdtHeader = read(*table);
+            checksum_valid(*table, header.length as usize)
+        })
+}
+
+/// Copies all tables to the heap, afterwards the ACPI reclaimable memory can
+/// be freed. Requires the heap.
+pub fn copy_tables() {
+    let tables = table_addresses()
+        .map(|table| {
+            let header: SdtHeader = read(table);
+            let data = unsafe { physical_slice(table, header.length as usize) };
+            &*Box::leak(Box::from(data))
+        })
+        .collect();
+    *TABLES.lock() = Some(tables);
+}
+
+unsafe fn physical_slice(address: PhysicalAddress, len: usize) -> &'static [u8] {
+    unsafe { slice::from_raw_parts(paging::physical_to_virtual(address).as_ptr(), len) }
+}
+
+/// The first table with `signature`, e.g. `b"HPET"`, including its header
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    if let Some(tables) = TABLES.lock().as_ref() {
+        return tables.iter().copied().find(|t| t[..4] == *signature);
+    }
+
+    table_addresses()
+        .map(|table| {
+            let header: SdtHeader = read(table);
+            unsafe { physical_slice(table, header.length as usize) }
+        })
+        .find(|t| t[..4] == *signature)
+}
+
+fn table_field<T: Copy>(table: &[u8], offset: usize) -> Option<T> {
+    let bytes = table.get(offset..offset + size_of::<T>())?;
+    Some(unsafe { ptr::read_unaligned(bytes.as_ptr().cast()) })
+}
+
+/// The HPET table, None if there is no HPET
+pub fn hpet() -> Option<HpetTable> {
+    table_field(find_table(b"HPET")?, 0)
+}
+
+/// CMOS register holding the century of the RTC, None if there is none
+pub fn century_register() -> Option<u8> {
+    match table_field(find_table(b"FACP")?, FADT_CENTURY)? {
+        0 => None,
+        register => Some(register),
+    }
+}
+
+/// Physical addresses of the registers of all I/O APICs
+pub fn io_apics() -> impl Iterator<Item = u64> {
+    let madt = find_table(b"APIC").unwrap_or(&[]);
+    let mut offset = MADT_ENTRIES;
+    core::iter::from_fn(move || loop {
+        let typ: u8 = table_field(madt, offset)?;
+        let len: u8 = table_field(madt, offset + 1)?;
+        if len < 2 {
+            return None;
+        }
+        let entry = offset;
+        offset += len as usize;
+
+        if typ == MADT_IO_APIC {
+            return table_field::<u32>(madt, entry + 4).map(u64::from);
+        }
+    })
+}
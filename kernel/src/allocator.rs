@@ -1,3 +1,4 @@
+use api::layout;
 use buddy_allocator::BuddyAllocator;
 use core::ops::Add;
 /*
@@ -6,18 +7,29 @@ use bump allocator for frame allocations for now. Only handle frame deallocation
 use x86_64::{
     memory::{FrameAllocator, Page, Size4KiB, VirtualAddress},
     mutex::{Mutex, MutexGuard},
-    paging::{Mapper, PageTableEntryFlags},
+    paging::{CacheAttribute, Mapper, PageTableEntryFlags},
     println,
 };
 
 pub mod buddy_allocator;
+#[cfg(feature = "heap-debug")]
+pub mod debug_allocator;
+pub mod slab_allocator;
 
-pub const HEAP_START: VirtualAddress = VirtualAddress::new(0x_4444_4444_0000);
+pub use slab_allocator::{CacheBox, KmemCache, ObjectCache};
+
+pub const HEAP_START: VirtualAddress = VirtualAddress::new(layout::HEAP.start);
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
-#[global_allocator]
+#[cfg_attr(not(feature = "heap-debug"), global_allocator)]
 pub static ALLOCATOR: Locked<BuddyAllocator> = Locked::new(BuddyAllocator::new());
 
+/// Wraps every heap allocation with redzones and quarantines freed memory
+#[cfg(feature = "heap-debug")]
+#[global_allocator]
+static DEBUG_ALLOCATOR: debug_allocator::DebugAllocator =
+    debug_allocator::DebugAllocator::new(&ALLOCATOR);
+
 pub fn init_heap<M, A>(page_table: &mut M, frame_allocator: &mut A)
 where
     M: Mapper<Size4KiB>,
@@ -36,7 +48,13 @@ where
             | PageTableEntryFlags::NO_EXECUTE;
 
         page_table
-            .map_to(frame, page, flags, frame_allocator)
+            .map_to(
+                frame,
+                page,
+                flags,
+                CacheAttribute::WriteBack,
+                frame_allocator,
+            )
             .expect("Failed to map heap page")
             .flush();
     }
@@ -51,6 +69,7 @@ where
             frame,
             guard_page,
             PageTableEntryFlags::NONE,
+            CacheAttribute::WriteBack,
             frame_allocator,
         )
         .expect("Failed to map guard page")
@@ -12,6 +12,7 @@ use core::{
     ptr::NonNull,
 };
 use x86_64::{
+    interrupts::without_interrupts,
     memory::{
         Address, FrameAllocator, MemoryRegion, PageSize, PhysicalAddress, PhysicalFrame,
         PhysicalMemoryRegion, PhysicalMemoryRegionType, Region, Size2MiB, Size4KiB, VirtualAddress,
@@ -375,19 +376,26 @@ impl<'a> BuddyAllocator {
     }
 }
 
+// The heap lock is held with interrupts disabled. Otherwise a thread could be
+// preempted while holding it and the scheduler, which allocates with
+// interrupts disabled, would spin on the lock forever.
 unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match allocator.alloc(layout) {
-            Some(chunk) => chunk.as_ptr() as *mut u8,
-            None => panic!("Allocator ran out of memory"),
-        }
+        without_interrupts(|| {
+            let mut allocator = self.lock();
+            match allocator.alloc(layout) {
+                Some(chunk) => chunk.as_ptr() as *mut u8,
+                None => panic!("Allocator ran out of memory"),
+            }
+        })
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
-        let size = BuddyAllocator::align_layout_size(layout);
-        let chunk = Chunk::new_at_address(VirtualAddress::from_raw_ptr(ptr), size as u64);
-        allocator.dealloc(NonNull::new(chunk as *mut Chunk).unwrap())
+        without_interrupts(|| {
+            let mut allocator = self.lock();
+            let size = BuddyAllocator::align_layout_size(layout);
+            let chunk = Chunk::new_at_address(VirtualAddress::from_raw_ptr(ptr), size as u64);
+            allocator.dealloc(NonNull::new(chunk as *mut Chunk).unwrap())
+        })
     }
 }
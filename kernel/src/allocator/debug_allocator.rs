@@ -0,0 +1,246 @@
+//! Guarded heap allocator used for debugging heap corruptions.
+//!
+//! Enabled with the `heap-debug` cargo feature. Every allocation is wrapped
+//! like this:
+//!
+//! | padding | header | front redzone | data | back redzone |
+//!
+//! The redzones are filled with a known pattern which is verified when the
+//! allocation is freed. Freed memory is poisoned and put into a quarantine
+//! instead of being handed back to the underlying allocator immediately.
+//! When an entry is evicted from the quarantine its poison is verified, which
+//! catches writes to already freed memory.
+//!
+//! Every allocation records the thread that made it and a sequence number.
+//! Both are printed together with the size when a corruption is detected.
+//!
+//! With the `unmap-on-free` feature allocations of at least a page get pages
+//! of their own from the memory manager which are unmapped when freed, see
+//! `memory::freed`.
+use super::{buddy_allocator::BuddyAllocator, Locked};
+#[cfg(feature = "unmap-on-free")]
+use super::{HEAP_SIZE, HEAP_START};
+#[cfg(feature = "unmap-on-free")]
+use crate::memory::freed;
+use crate::scheduler::{self, ThreadId};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::size_of,
+    ptr, slice,
+};
+#[cfg(feature = "unmap-on-free")]
+use x86_64::memory::VirtualAddress;
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+const REDZONE_SIZE: usize = 32;
+const REDZONE_BYTE: u8 = 0xfd;
+const FREED_BYTE: u8 = 0xdd;
+const QUARANTINE_SIZE: usize = 32;
+
+/// Allocations of at least this size are unmapped when freed
+#[cfg(feature = "unmap-on-free")]
+const LARGE_ALLOCATION: usize = 4096;
+
+const MAGIC_ALLOCATED: u64 = 0xa110_ca7e_d0d0_cafe;
+const MAGIC_FREED: u64 = 0xf4ee_d0d0_dead_beef;
+
+/// Metadata stored right in front of the front redzone
+#[repr(C)]
+struct AllocationHeader {
+    magic: u64,
+    /// Size requested by the user
+    size: usize,
+    /// Layout of the complete guarded allocation
+    outer_size: usize,
+    outer_align: usize,
+    /// Offset of the user data from the start of the guarded allocation
+    offset: usize,
+    /// Sequence number of the allocation
+    sequence: u64,
+    /// Thread the allocation was made by
+    owner: ThreadId,
+}
+
+struct Quarantine {
+    entries: [Option<*mut u8>; QUARANTINE_SIZE],
+    next: usize,
+    allocations: u64,
+}
+
+unsafe impl Send for Quarantine {}
+
+pub struct DebugAllocator {
+    inner: &'static Locked<BuddyAllocator>,
+    quarantine: Mutex<Quarantine>,
+}
+
+impl DebugAllocator {
+    pub const fn new(inner: &'static Locked<BuddyAllocator>) -> Self {
+        Self {
+            inner,
+            quarantine: Mutex::new(Quarantine {
+                entries: [None; QUARANTINE_SIZE],
+                next: 0,
+                allocations: 0,
+            }),
+        }
+    }
+
+    fn outer_layout(layout: Layout) -> (Layout, usize) {
+        let align = layout.align().max(size_of::<u64>());
+        let front = size_of::<AllocationHeader>() + REDZONE_SIZE;
+        // keep the user data aligned
+        let offset = (front + align - 1) & !(align - 1);
+        let size = offset + layout.size() + REDZONE_SIZE;
+        (Layout::from_size_align(size, align).unwrap(), offset)
+    }
+
+    unsafe fn header(data: *mut u8) -> *mut AllocationHeader {
+        data.sub(REDZONE_SIZE + size_of::<AllocationHeader>())
+            .cast::<AllocationHeader>()
+    }
+
+    unsafe fn check_pattern(
+        start: *const u8,
+        len: usize,
+        pattern: u8,
+        header: &AllocationHeader,
+        what: &str,
+    ) {
+        let bytes = slice::from_raw_parts(start, len);
+        if let Some(idx) = bytes.iter().position(|b| *b != pattern) {
+            panic!(
+                "Heap debug: {} detected at {:p} in allocation of size {:#x} (#{} by thread {})",
+                what,
+                start.add(idx),
+                header.size,
+                header.sequence,
+                header.owner
+            );
+        }
+    }
+
+    /// Verifies that the freed allocation was not touched while in quarantine
+    /// and hands it back to the underlying allocator
+    unsafe fn release(&self, data: *mut u8) {
+        let header = &*Self::header(data);
+        Self::check_pattern(data, header.size, FREED_BYTE, header, "use after free");
+
+        let outer = data.sub(header.offset);
+        let layout = Layout::from_size_align(header.outer_size, header.outer_align).unwrap();
+        self.inner.dealloc(outer, layout);
+    }
+}
+
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "unmap-on-free")]
+        if layout.size() >= LARGE_ALLOCATION && layout.align() <= LARGE_ALLOCATION {
+            // falls back to the heap while the memory manager is busy
+            if let Some(address) = freed::allocate_pages(layout.size()) {
+                return address.as_mut_ptr();
+            }
+        }
+
+        let (outer_layout, offset) = Self::outer_layout(layout);
+        let outer = self.inner.alloc(outer_layout);
+        if outer.is_null() {
+            return outer;
+        }
+        let data = outer.add(offset);
+
+        let sequence = without_interrupts(|| {
+            let mut quarantine = self.quarantine.lock();
+            quarantine.allocations += 1;
+            quarantine.allocations
+        });
+
+        ptr::write(
+            Self::header(data),
+            AllocationHeader {
+                magic: MAGIC_ALLOCATED,
+                size: layout.size(),
+                outer_size: outer_layout.size(),
+                outer_align: outer_layout.align(),
+                offset,
+                sequence,
+                owner: scheduler::current(),
+            },
+        );
+
+        ptr::write_bytes(data.sub(REDZONE_SIZE), REDZONE_BYTE, REDZONE_SIZE);
+        ptr::write_bytes(data.add(layout.size()), REDZONE_BYTE, REDZONE_SIZE);
+
+        data
+    }
+
+    unsafe fn dealloc(&self, data: *mut u8, layout: Layout) {
+        #[cfg(feature = "unmap-on-free")]
+        {
+            let address = VirtualAddress::from_raw_mut_ptr(data);
+            if address < HEAP_START || address >= HEAP_START + HEAP_SIZE {
+                freed::free_pages(address, layout.size());
+                return;
+            }
+        }
+
+        let header = &mut *Self::header(data);
+
+        match header.magic {
+            MAGIC_ALLOCATED => (),
+            MAGIC_FREED => panic!(
+                "Heap debug: double free of {:p} by thread {}, size {:#x} (#{} by thread {})",
+                data,
+                scheduler::current(),
+                header.size,
+                header.sequence,
+                header.owner
+            ),
+            _ => panic!(
+                "Heap debug: invalid free of {:p}, header corrupted (layout size {:#x})",
+                data,
+                layout.size()
+            ),
+        }
+
+        if header.size != layout.size() {
+            panic!(
+                "Heap debug: free of {:p} with size {:#x} but allocation has size {:#x} (#{} by thread {})",
+                data,
+                layout.size(),
+                header.size,
+                header.sequence,
+                header.owner
+            );
+        }
+
+        Self::check_pattern(
+            data.sub(REDZONE_SIZE),
+            REDZONE_SIZE,
+            REDZONE_BYTE,
+            header,
+            "heap underflow",
+        );
+        Self::check_pattern(
+            data.add(header.size),
+            REDZONE_SIZE,
+            REDZONE_BYTE,
+            header,
+            "heap overflow",
+        );
+
+        header.magic = MAGIC_FREED;
+        ptr::write_bytes(data, FREED_BYTE, header.size);
+
+        let evicted = without_interrupts(|| {
+            let mut quarantine = self.quarantine.lock();
+            let idx = quarantine.next;
+            quarantine.next = (idx + 1) % QUARANTINE_SIZE;
+            quarantine.entries[idx].replace(data)
+        });
+
+        if let Some(evicted) = evicted {
+            self.release(evicted);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! This module implements a slab allocator for fixed size kernel objects
+//!
+//! Objects that are allocated and freed very often (threads, memory regions, ...)
+//! all have the same size. Instead of going through the buddy allocator every
+//! time, a cache grabs a whole slab from the heap and cuts it into equally
+//! sized slots. Freed slots are kept in a free list and handed out again on
+//! the next allocation. This avoids fragmenting the heap with small chunks
+//! and makes allocations O(1).
+//!
+//! Optionally freed slots are poisoned. The poison is verified when the slot
+//! is handed out again, which catches writes to already freed objects.
+//!
+//! [`CacheBox`] is the `Box` equivalent for objects living in a static cache.
+extern crate alloc;
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::{
+    cmp::max,
+    fmt,
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+use x86_64::mutex::InterruptSafeMutex;
+
+/// Size of a single slab. Caches for objects that don't fit at least
+/// `MIN_OBJECTS_PER_SLAB` times into this use bigger slabs.
+const SLAB_SIZE: usize = 0x1000;
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// Byte pattern freed slots are filled with if poisoning is enabled
+pub const POISON_BYTE: u8 = 0x6b;
+
+/// Node of the free list, stored inside of the free slot itself
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+/// Header at the beginning of every slab. Links all slabs of a cache together.
+struct SlabHeader {
+    next: Option<NonNull<SlabHeader>>,
+}
+
+/// Untyped cache for objects of a single size class
+pub struct SlabCache {
+    object_size: usize,
+    object_align: usize,
+    poison: bool,
+    free_list: Option<NonNull<FreeSlot>>,
+    slabs: Option<NonNull<SlabHeader>>,
+    slab_count: usize,
+    allocated: usize,
+    free: usize,
+}
+
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    pub const fn new(object_size: usize, object_align: usize, poison: bool) -> Self {
+        // a free slot needs to be able to hold the free list pointer
+        let align = if object_align > align_of::<FreeSlot>() {
+            object_align
+        } else {
+            align_of::<FreeSlot>()
+        };
+        let size = if object_size > size_of::<FreeSlot>() {
+            object_size
+        } else {
+            size_of::<FreeSlot>()
+        };
+
+        Self {
+            object_size: (size + align - 1) & !(align - 1),
+            object_align: align,
+            poison,
+            free_list: None,
+            slabs: None,
+            slab_count: 0,
+            allocated: 0,
+            free: 0,
+        }
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    /// Number of objects currently handed out
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    /// Number of free slots across all slabs
+    pub fn free(&self) -> usize {
+        self.free
+    }
+
+    pub fn slab_count(&self) -> usize {
+        self.slab_count
+    }
+
+    fn slab_layout(&self) -> Layout {
+        let size = max(
+            SLAB_SIZE,
+            (self.object_size * MIN_OBJECTS_PER_SLAB + size_of::<SlabHeader>()).next_power_of_two(),
+        );
+        Layout::from_size_align(size, SLAB_SIZE).unwrap()
+    }
+
+    /// Offset of the first slot behind the slab header
+    fn first_slot_offset(&self) -> usize {
+        (size_of::<SlabHeader>() + self.object_align - 1) & !(self.object_align - 1)
+    }
+
+    /// Gets a new slab from the heap and puts all of its slots on the free list
+    fn grow(&mut self) -> Option<()> {
+        let layout = self.slab_layout();
+        let slab = NonNull::new(unsafe { alloc(layout) })?;
+
+        let header = slab.cast::<SlabHeader>();
+        unsafe { header.as_ptr().write(SlabHeader { next: self.slabs }) };
+        self.slabs = Some(header);
+        self.slab_count += 1;
+
+        let mut offset = self.first_slot_offset();
+        while offset + self.object_size <= layout.size() {
+            let slot = unsafe { slab.as_ptr().add(offset) };
+            unsafe { self.push_free(NonNull::new_unchecked(slot)) };
+            offset += self.object_size;
+        }
+
+        Some(())
+    }
+
+    unsafe fn push_free(&mut self, slot: NonNull<u8>) {
+        if self.poison {
+            ptr::write_bytes(slot.as_ptr(), POISON_BYTE, self.object_size);
+        }
+
+        let slot = slot.cast::<FreeSlot>();
+        slot.as_ptr().write(FreeSlot {
+            next: self.free_list,
+        });
+        self.free_list = Some(slot);
+        self.free += 1;
+    }
+
+    /// Panics if the poison of a free slot was overwritten
+    unsafe fn check_poison(&self, slot: NonNull<u8>) {
+        let bytes = core::slice::from_raw_parts(slot.as_ptr(), self.object_size);
+        // the free list pointer overwrote the beginning of the slot
+        if let Some(offset) = bytes[size_of::<FreeSlot>()..]
+            .iter()
+            .position(|b| *b != POISON_BYTE)
+        {
+            panic!(
+                "Slab cache: use after free detected in object at {:p} (offset {:#x}, size {:#x})",
+                slot.as_ptr(),
+                offset + size_of::<FreeSlot>(),
+                self.object_size
+            );
+        }
+    }
+
+    /// Allocate a slot. The contents of the slot are undefined.
+    pub fn alloc(&mut self) -> Option<NonNull<u8>> {
+        if self.free_list.is_none() {
+            self.grow()?;
+        }
+
+        let slot = self.free_list?;
+        self.free_list = unsafe { slot.as_ref().next };
+        self.free -= 1;
+        self.allocated += 1;
+
+        let slot = slot.cast::<u8>();
+        if self.poison {
+            unsafe { self.check_poison(slot) };
+        }
+
+        Some(slot)
+    }
+
+    /// Return a slot to the cache.
+    ///
+    /// # Safety
+    ///
+    /// `slot` must have been allocated by this cache and must not be used after
+    /// this call
+    pub unsafe fn dealloc(&mut self, slot: NonNull<u8>) {
+        self.allocated -= 1;
+        self.push_free(slot);
+    }
+}
+
+impl Drop for SlabCache {
+    fn drop(&mut self) {
+        // objects that are still handed out would point into freed slabs
+        assert_eq!(
+            self.allocated, 0,
+            "Slab cache: dropped with {} objects still allocated",
+            self.allocated
+        );
+
+        let layout = self.slab_layout();
+        let mut slab = self.slabs.take();
+        while let Some(header) = slab {
+            slab = unsafe { header.as_ref().next };
+            unsafe { dealloc(header.as_ptr().cast(), layout) };
+        }
+    }
+}
+
+/// Typed cache for kernel objects of type `T`
+pub struct KmemCache<T> {
+    cache: SlabCache,
+    phantom: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for KmemCache<T> {}
+
+impl<T> KmemCache<T> {
+    pub const fn new() -> Self {
+        Self {
+            cache: SlabCache::new(size_of::<T>(), align_of::<T>(), false),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Cache that poisons freed objects and checks the poison on allocation
+    pub const fn new_poisoned() -> Self {
+        Self {
+            cache: SlabCache::new(size_of::<T>(), align_of::<T>(), true),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Moves `value` into a slot of the cache
+    pub fn alloc(&mut self, value: T) -> Option<NonNull<T>> {
+        let slot = self.cache.alloc()?.cast::<T>();
+        unsafe { slot.as_ptr().write(value) };
+        Some(slot)
+    }
+
+    /// Drops the object and returns its slot to the cache.
+    ///
+    /// # Safety
+    ///
+    /// `object` must have been allocated by this cache and must not be used
+    /// after this call
+    pub unsafe fn free(&mut self, object: NonNull<T>) {
+        ptr::drop_in_place(object.as_ptr());
+        self.cache.dealloc(object.cast());
+    }
+
+    /// Returns the slot of an already dropped object to the cache.
+    ///
+    /// # Safety
+    ///
+    /// `object` must have been allocated by this cache and already be dropped
+    pub unsafe fn dealloc(&mut self, object: NonNull<T>) {
+        self.cache.dealloc(object.cast());
+    }
+
+    pub fn allocated(&self) -> usize {
+        self.cache.allocated()
+    }
+
+    pub fn slab_count(&self) -> usize {
+        self.cache.slab_count()
+    }
+}
+
+impl<T> Default for KmemCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Cache backing all [`CacheBox`]es of type `T`
+pub type ObjectCache<T> = InterruptSafeMutex<KmemCache<T>>;
+
+/// Owning pointer to an object allocated from a static [`ObjectCache`]. The
+/// object is dropped and its slot returned to the cache when the box goes out
+/// of scope.
+pub struct CacheBox<T: 'static> {
+    object: NonNull<T>,
+    cache: &'static ObjectCache<T>,
+}
+
+unsafe impl<T: Send> Send for CacheBox<T> {}
+unsafe impl<T: Sync> Sync for CacheBox<T> {}
+
+impl<T> CacheBox<T> {
+    /// Moves `value` into a slot of `cache`. Panics if the cache can't grow,
+    /// like `Box::new` does.
+    pub fn new(cache: &'static ObjectCache<T>, value: T) -> Self {
+        let object = cache
+            .lock()
+            .alloc(value)
+            .expect("Slab cache: out of memory");
+        Self { object, cache }
+    }
+}
+
+impl<T> Deref for CacheBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T> DerefMut for CacheBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CacheBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for CacheBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            // not under the cache lock, the object might own boxes of the
+            // same cache
+            ptr::drop_in_place(self.object.as_ptr());
+            self.cache.lock().dealloc(self.object);
+        }
+    }
+}
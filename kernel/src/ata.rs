@@ -0,0 +1,165 @@
+//! Minimal ATA PIO driver.
+//!
+//! Sectors are transferred one at a time by polling the status register, the
+//! drive's interrupt is disabled. This is slow, but works without interrupts,
+//! which makes it usable from the panic handler.
+use core::hint::spin_loop;
+use x86_64::{mutex::Mutex, port::Port};
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Polls of the status register before giving up
+const TIMEOUT: usize = 1_000_000;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+const CONTROL_NIEN: u8 = 1 << 1;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_CACHE_FLUSH: u8 = 0xe7;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+/// Master drive of the primary bus, the boot disk in QEMU
+pub static PRIMARY_MASTER: Mutex<AtaDrive> = Mutex::new(AtaDrive::new(0x1f0, 0x3f6, false));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    NoDevice,
+    Timeout,
+    /// The drive reported an error, contains the error register
+    Device(u8),
+    /// LBA beyond what 28-bit addressing can reach
+    OutOfRange,
+}
+
+pub struct AtaDrive {
+    data: Port<u16>,
+    error: Port<u8>,
+    sector_count: Port<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive: Port<u8>,
+    /// Status on read, command on write
+    command: Port<u8>,
+    control: Port<u8>,
+    slave: bool,
+}
+
+impl AtaDrive {
+    pub const fn new(io_base: u16, control: u16, slave: bool) -> Self {
+        Self {
+            data: Port::new(io_base),
+            error: Port::new(io_base + 1),
+            sector_count: Port::new(io_base + 2),
+            lba_low: Port::new(io_base + 3),
+            lba_mid: Port::new(io_base + 4),
+            lba_high: Port::new(io_base + 5),
+            drive: Port::new(io_base + 6),
+            command: Port::new(io_base + 7),
+            control: Port::new(control),
+            slave,
+        }
+    }
+
+    /// Returns the number of sectors of the drive
+    pub fn identify(&mut self) -> Result<u32, AtaError> {
+        self.control.write(CONTROL_NIEN);
+        self.drive.write(0xa0 | (self.slave as u8) << 4);
+        self.sector_count.write(0);
+        self.lba_low.write(0);
+        self.lba_mid.write(0);
+        self.lba_high.write(0);
+        self.command.write(COMMAND_IDENTIFY);
+
+        // a floating bus reads as 0xff, no drive as 0
+        let status = self.command.read();
+        if status == 0 || status == 0xff {
+            return Err(AtaError::NoDevice);
+        }
+        self.wait_ready()?;
+        // ATAPI and SATA devices set the signature instead of DRQ
+        if self.lba_mid.read() != 0 || self.lba_high.read() != 0 {
+            return Err(AtaError::NoDevice);
+        }
+        self.wait_data()?;
+
+        let mut identify = [0u16; SECTOR_SIZE / 2];
+        identify
+            .iter_mut()
+            .for_each(|word| *word = self.data.read());
+        Ok(u32::from(identify[60]) | u32::from(identify[61]) << 16)
+    }
+
+    /// Reads whole sectors starting at `lba` into `buffer`
+    pub fn read(&mut self, lba: u32, buffer: &mut [u8]) -> Result<(), AtaError> {
+        assert!(buffer.len().is_multiple_of(SECTOR_SIZE));
+        for (i, sector) in buffer.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            self.command_sector(lba + i as u32, COMMAND_READ_SECTORS)?;
+            self.wait_data()?;
+            for bytes in sector.chunks_exact_mut(2) {
+                bytes.copy_from_slice(&self.data.read().to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes whole sectors starting at `lba` and flushes the write cache
+    pub fn write(&mut self, lba: u32, buffer: &[u8]) -> Result<(), AtaError> {
+        assert!(buffer.len().is_multiple_of(SECTOR_SIZE));
+        for (i, sector) in buffer.chunks_exact(SECTOR_SIZE).enumerate() {
+            self.command_sector(lba + i as u32, COMMAND_WRITE_SECTORS)?;
+            self.wait_data()?;
+            for bytes in sector.chunks_exact(2) {
+                self.data.write(u16::from_le_bytes([bytes[0], bytes[1]]));
+            }
+        }
+
+        self.command.write(COMMAND_CACHE_FLUSH);
+        self.wait_ready()
+    }
+
+    /// Issues `command` for a single sector using 28-bit LBA
+    fn command_sector(&mut self, lba: u32, command: u8) -> Result<(), AtaError> {
+        if lba >= 1 << 28 {
+            return Err(AtaError::OutOfRange);
+        }
+        self.wait_ready()?;
+
+        self.control.write(CONTROL_NIEN);
+        self.drive
+            .write(0xe0 | (self.slave as u8) << 4 | (lba >> 24) as u8);
+        self.sector_count.write(1);
+        self.lba_low.write(lba as u8);
+        self.lba_mid.write((lba >> 8) as u8);
+        self.lba_high.write((lba >> 16) as u8);
+        self.command.write(command);
+        Ok(())
+    }
+
+    fn wait_ready(&mut self) -> Result<(), AtaError> {
+        self.poll(|status| status & STATUS_BSY == 0)
+    }
+
+    fn wait_data(&mut self) -> Result<(), AtaError> {
+        self.poll(|status| status & STATUS_BSY == 0 && status & STATUS_DRQ != 0)
+    }
+
+    fn poll<F: Fn(u8) -> bool>(&mut self, done: F) -> Result<(), AtaError> {
+        for _ in 0..TIMEOUT {
+            let status = self.command.read();
+            if status & STATUS_BSY == 0 && status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(AtaError::Device(self.error.read()));
+            }
+            if done(status) {
+                return Ok(());
+            }
+            spin_loop();
+        }
+        Err(AtaError::Timeout)
+    }
+}
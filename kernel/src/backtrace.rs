@@ -0,0 +1,148 @@
+//! Stack backtraces.
+//!
+//! The kernel is compiled with frame pointers, so every frame starts with the
+//! saved rbp of the caller followed by the return address. The chain ends at
+//! a zero rbp, which the bootloader and the thread trampoline set up.
+//!
+//! Return addresses are symbolized using the symbol table the bootloader
+//! loads next to the kernel, see `api::symbols`.
+use crate::{error, paging};
+use api::{
+    symbols::{Symbol, SymbolTable},
+    BootInfo,
+};
+use core::{
+    arch::asm,
+    slice,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use x86_64::memory::{MemoryRegion, VirtualAddress};
+
+/// Stop walking after this many frames in case the chain is corrupted
+pub const MAX_FRAMES: usize = 64;
+
+static SYMBOLS_START: AtomicU64 = AtomicU64::new(0);
+static SYMBOLS_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init(boot_info: &'static BootInfo) {
+    let symbols = &boot_info.symbols;
+    if symbols.size() == 0 {
+        return;
+    }
+
+    let start = boot_info.physical_memory_offset + symbols.start();
+    SYMBOLS_START.store(start, Ordering::SeqCst);
+    SYMBOLS_SIZE.store(symbols.size() as usize, Ordering::SeqCst);
+}
+
+/// Symbol table of the kernel, None if the bootloader didn't load one
+pub fn symbols() -> Option<SymbolTable<'static>> {
+    let size = SYMBOLS_SIZE.load(Ordering::SeqCst);
+    if size == 0 {
+        return None;
+    }
+
+    let start = SYMBOLS_START.load(Ordering::SeqCst) as *const u8;
+    let data = unsafe { slice::from_raw_parts(start, size) };
+    SymbolTable::parse(data).ok()
+}
+
+/// Symbol containing `address` together with the offset into it
+pub fn symbolize(address: u64) -> Option<(Symbol<'static>, u64)> {
+    let symbol = symbols()?.lookup(address)?;
+    Some((symbol, address - symbol.address))
+}
+
+/// Name of the function at `address` without its path, hash and closures,
+/// e.g. `shell_loop` for `kernel::shell::shell_loop::h0123456789abcdef`
+pub fn function_name(address: u64) -> Option<&'static str> {
+    let (symbol, _) = symbolize(address)?;
+    let is_hash = |s: &str| {
+        s.len() == 17 && s.starts_with('h') && s[1..].bytes().all(|b| b.is_ascii_hexdigit())
+    };
+    symbol
+        .name
+        .split("::")
+        .filter(|s| !s.is_empty() && !s.starts_with('{') && !is_hash(s))
+        .last()
+}
+
+/// Iterator over the return addresses on the stack, innermost first
+pub struct Frames {
+    rbp: u64,
+    depth: usize,
+}
+
+impl Frames {
+    /// Walks the frames starting at `rbp`
+    ///
+    /// # Safety
+    ///
+    /// `rbp` has to be the frame pointer of a frame on a valid stack
+    pub unsafe fn from_rbp(rbp: u64) -> Self {
+        Self { rbp, depth: 0 }
+    }
+
+    /// Reads a u64 from the stack, None if the address isn't mapped
+    fn read(address: u64) -> Option<u64> {
+        if !address.is_multiple_of(8) {
+            return None;
+        }
+        // both words of a frame are in the same page due to the alignment
+        paging::translate(VirtualAddress::new(address))?;
+        Some(unsafe { (address as *const u64).read() })
+    }
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.rbp == 0 || self.depth == MAX_FRAMES {
+            return None;
+        }
+
+        let return_address = Self::read(self.rbp + 8)?;
+        let caller_rbp = Self::read(self.rbp)?;
+        if return_address == 0 {
+            return None;
+        }
+
+        self.rbp = caller_rbp;
+        self.depth += 1;
+        Some(return_address)
+    }
+}
+
+/// Return addresses of the current call stack, starting with the caller of
+/// this function
+#[inline(never)]
+pub fn frames() -> Frames {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        Frames::from_rbp(rbp)
+    }
+}
+
+/// Logs the current call stack
+#[inline(never)]
+pub fn print() {
+    error!("Backtrace:");
+    for (i, address) in frames().enumerate() {
+        // the return address points behind the call, which might already be
+        // the next function
+        match symbolize(address - 1) {
+            Some((symbol, offset)) => {
+                error!(
+                    "{:>4}: {:#018x} {}+{:#x}",
+                    i,
+                    address,
+                    symbol.name,
+                    offset + 1
+                )
+            }
+            None => error!("{:>4}: {:#018x} <unknown>", i, address),
+        }
+    }
+}
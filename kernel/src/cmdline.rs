@@ -0,0 +1,134 @@
+//! Kernel command line.
+//!
+//! The command line is a whitespace separated list of `key=value` options and
+//! flags, e.g. `log=debug nosmp`. Known options:
+//!
+//! - `log=<level>`: log level of targets without a filter
+//! - `nosmp`: only use the boot CPU, which is all the kernel does so far
+//! - `test`: exit QEMU once the kernel is initialized instead of starting the shell
+//! - `doublefault=<halt|reboot>`: what to do after reporting a double fault,
+//!   halting is the default
+//! - `watchdog=<warn|panic>`: whether the watchdog panics after reporting a
+//!   stuck CPU, it only warns by default
+//! - `idle=<halt|mwait>`: how the CPU idles, `mwait` is used if the CPU
+//!   supports it
+//!
+//! `video=` is read by the bootloader, see `api::cmdline::VideoRequest`.
+extern crate alloc;
+use crate::{info, log::Level, warn};
+use alloc::vec::Vec;
+use api::BootInfo;
+use core::{
+    ptr,
+    str::FromStr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+static BOOT_INFO: AtomicPtr<BootInfo> = AtomicPtr::new(ptr::null_mut());
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Options {
+    pub log_level: Option<Level>,
+    pub nosmp: bool,
+    pub test: bool,
+    pub double_fault_reboot: bool,
+    pub watchdog_panic: bool,
+    pub idle_halt: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionError<'a> {
+    Unknown(&'a str),
+    InvalidValue { key: &'a str, value: &'a str },
+}
+
+/// Stores the command line of the boot info and applies the log level
+pub fn init(boot_info: &'static BootInfo) {
+    BOOT_INFO.store(boot_info as *const _ as *mut _, Ordering::SeqCst);
+    if boot_info.cmdline.is_empty() {
+        return;
+    }
+
+    info!("Command line: {}", as_str());
+    let (options, errors) = parse(as_str());
+    for error in errors {
+        warn!("Ignoring command line option: {:?}", error);
+    }
+    if let Some(level) = options.log_level {
+        crate::log::set_level(level);
+    }
+}
+
+/// The command line, empty before [`init`]
+pub fn as_str() -> &'static str {
+    match unsafe { BOOT_INFO.load(Ordering::SeqCst).as_ref() } {
+        Some(boot_info) => boot_info.cmdline.as_str(),
+        None => "",
+    }
+}
+
+pub fn options() -> Options {
+    parse(as_str()).0
+}
+
+/// Value of `key=value`, an empty string for a flag
+pub fn get(key: &str) -> Option<&'static str> {
+    split(as_str()).find(|(k, _)| *k == key).map(|(_, v)| v)
+}
+
+/// Parses the known options, invalid ones are returned as errors
+pub fn parse(cmdline: &str) -> (Options, Vec<OptionError<'_>>) {
+    let mut options = Options::default();
+    let mut errors = Vec::new();
+
+    for (key, value) in split(cmdline) {
+        let result = match key {
+            "log" => Level::from_str(value)
+                .map(|level| options.log_level = Some(level))
+                .map_err(|_| OptionError::InvalidValue { key, value }),
+            "nosmp" => {
+                options.nosmp = true;
+                Ok(())
+            }
+            "test" => {
+                options.test = true;
+                Ok(())
+            }
+            "doublefault" => match value {
+                "halt" | "reboot" => {
+                    options.double_fault_reboot = value == "reboot";
+                    Ok(())
+                }
+                _ => Err(OptionError::InvalidValue { key, value }),
+            },
+            "watchdog" => match value {
+                "warn" | "panic" => {
+                    options.watchdog_panic = value == "panic";
+                    Ok(())
+                }
+                _ => Err(OptionError::InvalidValue { key, value }),
+            },
+            "idle" => match value {
+                "halt" | "mwait" => {
+                    options.idle_halt = value == "halt";
+                    Ok(())
+                }
+                _ => Err(OptionError::InvalidValue { key, value }),
+            },
+            "video" => Ok(()),
+            _ => Err(OptionError::Unknown(key)),
+        };
+
+        if let Err(error) = result {
+            errors.push(error);
+        }
+    }
+
+    (options, errors)
+}
+
+fn split(cmdline: &str) -> impl Iterator<Item = (&str, &str)> {
+    cmdline
+        .split_whitespace()
+        .map(|option| option.split_once('=').unwrap_or((option, "")))
+}
@@ -0,0 +1,125 @@
+//! Unbounded multi-producer single-consumer channel.
+//!
+//! Sending never blocks and works from threads as well as tasks, receiving
+//! is async. [`Receiver::recv`] returns None once all senders are dropped
+//! and the channel is empty.
+extern crate alloc;
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+struct Shared<T> {
+    queue: VecDeque<T>,
+    /// Waker of a pending receive
+    receiver: Option<Waker>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+// locked with interrupts disabled
+type Channel<T> = Arc<Mutex<Shared<T>>>;
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Mutex::new(Shared {
+        queue: VecDeque::new(),
+        receiver: None,
+        senders: 1,
+        receiver_alive: true,
+    }));
+    (Sender(channel.clone()), Receiver(channel))
+}
+
+pub struct Sender<T>(Channel<T>);
+
+impl<T> Sender<T> {
+    /// Queues `value`, returns it if the receiver was dropped
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let waker = without_interrupts(|| {
+            let mut shared = self.0.lock();
+            if !shared.receiver_alive {
+                return Err(value);
+            }
+            shared.queue.push_back(value);
+            Ok(shared.receiver.take())
+        })?;
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        without_interrupts(|| self.0.lock().senders += 1);
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = without_interrupts(|| {
+            let mut shared = self.0.lock();
+            shared.senders -= 1;
+            match shared.senders {
+                0 => shared.receiver.take(),
+                _ => None,
+            }
+        });
+        // lets a pending receive see the channel is closed
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+pub struct Receiver<T>(Channel<T>);
+
+impl<T> Receiver<T> {
+    /// Next value, None once all senders are gone
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv(self)
+    }
+
+    /// Next value if one is queued
+    pub fn try_recv(&mut self) -> Option<T> {
+        without_interrupts(|| self.0.lock().queue.pop_front())
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let queue = without_interrupts(|| {
+            let mut shared = self.0.lock();
+            shared.receiver_alive = false;
+            core::mem::take(&mut shared.queue)
+        });
+        // values are dropped with interrupts enabled
+        drop(queue);
+    }
+}
+
+/// Returned by [`Receiver::recv`]
+pub struct Recv<'a, T>(&'a mut Receiver<T>);
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        without_interrupts(|| {
+            let mut shared = (self.0).0.lock();
+            if let Some(value) = shared.queue.pop_front() {
+                return Poll::Ready(Some(value));
+            }
+            if shared.senders == 0 {
+                return Poll::Ready(None);
+            }
+            shared.receiver = Some(cx.waker().clone());
+            Poll::Pending
+        })
+    }
+}
@@ -0,0 +1,292 @@
+//! Async tasks.
+//!
+//! Drivers and services can be written as futures and [`spawn`]ed as tasks.
+//! All tasks are polled by a single kernel thread, the executor. A task is
+//! polled again once its waker is woken, the executor blocks while no task
+//! is ready.
+//!
+//! Interrupt handlers don't touch wakers, waking may free memory. They
+//! [`signal`] an [`Event`] instead, the executor then wakes the tasks waiting
+//! for it. The keyboard interrupt signals [`Event::Keyboard`], the
+//! [`SoftIrq::BlockComplete`] softirq [`Event::BlockComplete`]. Timers are
+//! kept by the executor, see [`sleep`](sleep::sleep), it sleeps until the
+//! earliest one expires.
+extern crate alloc;
+pub mod channel;
+pub mod sleep;
+
+use crate::{
+    info,
+    interrupts::{
+        self,
+        softirq::{self, SoftIrq},
+    },
+    memory::MemoryError,
+    scheduler::{self, ThreadId, ThreadPriority},
+    sync::WaitQueue,
+    time,
+};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use lockfree::MpmcQueue;
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+/// Tasks that can exist at once
+pub const MAX_TASKS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutorError {
+    /// [`MAX_TASKS`] tasks already exist
+    TooManyTasks,
+}
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    waker: Arc<TaskWaker>,
+}
+
+struct TaskWaker {
+    id: TaskId,
+    /// Set while the task is in the ready queue, so it is queued at most once
+    queued: AtomicBool,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::SeqCst) {
+            // can't be full, every task is queued at most once
+            let _ = READY.push(self.id);
+            notify();
+        }
+    }
+}
+
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(0);
+// locked with interrupts disabled, a task is removed while it is polled
+static TASKS: Mutex<BTreeMap<TaskId, Task>> = Mutex::new(BTreeMap::new());
+static READY: MpmcQueue<TaskId, MAX_TASKS> = MpmcQueue::new();
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+/// Thread id of the executor, u64::MAX until it is started
+static EXECUTOR: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Starts the executor thread and hooks up the interrupt events
+pub fn init() -> Result<(), MemoryError> {
+    let id = scheduler::spawn(executor_loop, ThreadPriority::Normal)?;
+    scheduler::set_name(id, "executor");
+    EXECUTOR.store(id.as_u64(), Ordering::SeqCst);
+
+    if let Err(error) = softirq::register(SoftIrq::BlockComplete, || signal(Event::BlockComplete)) {
+        info!("Block completions don't wake tasks: {:?}", error);
+    }
+    Ok(())
+}
+
+/// Runs `future` as a task of the executor
+pub fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) -> Result<TaskId, ExecutorError> {
+    let id = TaskId(NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed));
+    let waker = Arc::new(TaskWaker {
+        id,
+        queued: AtomicBool::new(false),
+    });
+    let task = Task {
+        future: Box::pin(future),
+        waker: waker.clone(),
+    };
+
+    without_interrupts(|| {
+        let mut tasks = TASKS.lock();
+        if tasks.len() >= MAX_TASKS {
+            return Err(ExecutorError::TooManyTasks);
+        }
+        tasks.insert(id, task);
+        Ok(())
+    })?;
+    waker.wake_by_ref();
+    Ok(id)
+}
+
+/// Runs `future` as a task and blocks the calling thread until it completes.
+/// Lets threads and system calls use async services. Must not be called by
+/// the executor thread.
+pub fn block_on<F>(future: F) -> Result<F::Output, ExecutorError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    // locked with interrupts disabled since it is checked from a wait queue
+    // condition
+    let result = Arc::new(Mutex::new(None));
+    let done = Arc::new(WaitQueue::named("block_on"));
+    let (task_result, task_done) = (result.clone(), done.clone());
+    spawn(async move {
+        let output = future.await;
+        without_interrupts(|| *task_result.lock() = Some(output));
+        task_done.wake_all();
+    })?;
+
+    let mut output = None;
+    done.wait_until(|| {
+        output = result.lock().take();
+        output.is_some()
+    });
+    Ok(output.unwrap())
+}
+
+/// Amount of tasks that didn't complete yet
+pub fn tasks() -> usize {
+    without_interrupts(|| TASKS.lock().len())
+}
+
+/// Amount of tasks completed since boot
+pub fn completed() -> u64 {
+    COMPLETED.load(Ordering::Relaxed)
+}
+
+/// Wakes the executor thread. Safe to call from interrupt handlers.
+fn notify() {
+    let id = EXECUTOR.load(Ordering::SeqCst);
+    if id != u64::MAX {
+        scheduler::wake(ThreadId::from_u64(id));
+    }
+}
+
+/// Interrupt sources tasks can wait for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A scancode was received
+    Keyboard,
+    /// A block device request completed
+    BlockComplete,
+}
+
+impl Event {
+    pub const ALL: [Event; 2] = [Event::Keyboard, Event::BlockComplete];
+
+    /// Future completing once the event is signaled after this call
+    pub fn next(self) -> EventFuture {
+        EventFuture {
+            event: self,
+            generation: GENERATIONS[self as usize].load(Ordering::SeqCst),
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Times each event was signaled
+static GENERATIONS: [AtomicU64; Event::ALL.len()] = [const { AtomicU64::new(0) }; Event::ALL.len()];
+static PENDING_EVENTS: AtomicU32 = AtomicU32::new(0);
+// locked with interrupts disabled, only in thread context
+static EVENT_WAITERS: Mutex<[Vec<Waker>; Event::ALL.len()]> =
+    Mutex::new([const { Vec::new() }; Event::ALL.len()]);
+
+/// Wakes the tasks waiting for `event`. Safe to call from interrupt handlers.
+pub fn signal(event: Event) {
+    GENERATIONS[event as usize].fetch_add(1, Ordering::SeqCst);
+    PENDING_EVENTS.fetch_or(event.bit(), Ordering::SeqCst);
+    notify();
+}
+
+/// Returned by [`Event::next`]
+pub struct EventFuture {
+    event: Event,
+    generation: u64,
+}
+
+impl Future for EventFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if GENERATIONS[self.event as usize].load(Ordering::SeqCst) != self.generation {
+            return Poll::Ready(());
+        }
+        // a signal from now on leaves the event pending, the executor wakes
+        // the waiters after this poll
+        without_interrupts(|| {
+            let waiters = &mut EVENT_WAITERS.lock()[self.event as usize];
+            if !waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+        });
+        Poll::Pending
+    }
+}
+
+/// Next scancode from the keyboard, the async version of
+/// [`read_scancode`](interrupts::read_scancode)
+pub async fn scancode() -> u8 {
+    loop {
+        let next = Event::Keyboard.next();
+        if let Some(scancode) = interrupts::try_read_scancode() {
+            return scancode;
+        }
+        next.await;
+    }
+}
+
+fn wake_event_waiters() {
+    let pending = PENDING_EVENTS.swap(0, Ordering::SeqCst);
+    for event in Event::ALL {
+        if pending & event.bit() == 0 {
+            continue;
+        }
+        let waiters = without_interrupts(|| mem::take(&mut EVENT_WAITERS.lock()[event as usize]));
+        waiters.into_iter().for_each(Waker::wake);
+    }
+}
+
+fn poll(id: TaskId) {
+    let Some(mut task) = without_interrupts(|| TASKS.lock().remove(&id)) else {
+        return;
+    };
+    // cleared first, a wake while polling queues the task again
+    task.waker.queued.store(false, Ordering::SeqCst);
+    let waker = Waker::from(task.waker.clone());
+    match task.future.as_mut().poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(()) => {
+            COMPLETED.fetch_add(1, Ordering::Relaxed);
+        }
+        Poll::Pending => {
+            without_interrupts(|| TASKS.lock().insert(id, task));
+        }
+    }
+}
+
+fn executor_loop() {
+    loop {
+        sleep::expire(time::uptime());
+        wake_event_waiters();
+        while let Some(id) = READY.pop() {
+            poll(id);
+        }
+
+        // wakes from interrupts and other threads can't get lost between
+        // the checks and blocking
+        without_interrupts(|| {
+            if !READY.is_empty() || PENDING_EVENTS.load(Ordering::SeqCst) != 0 {
+                return;
+            }
+            let now = time::uptime();
+            match sleep::next_deadline() {
+                Some(deadline) if deadline <= now => {}
+                Some(deadline) => scheduler::sleep(deadline - now),
+                None => scheduler::block_current("executor"),
+            }
+        });
+    }
+}
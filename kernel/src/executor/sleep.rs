@@ -0,0 +1,120 @@
+//! Async timers.
+//!
+//! A pending [`Sleep`] registers its waker with its deadline. The executor
+//! sleeps until the earliest deadline and wakes the expired timers.
+//! [`timeout`] limits how long another future may take.
+extern crate alloc;
+use crate::time;
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+/// Deadline and a unique id, so timers with the same deadline can coexist
+type TimerKey = (Duration, u64);
+
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+// locked with interrupts disabled, only in thread context
+static TIMERS: Mutex<BTreeMap<TimerKey, Waker>> = Mutex::new(BTreeMap::new());
+
+/// Completes after at least `duration`
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(time::uptime() + duration)
+}
+
+/// Completes once the uptime reached `deadline`
+pub fn sleep_until(deadline: Duration) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+/// Completes with the output of `future` or None if it didn't complete
+/// within `duration`
+pub fn timeout<F: Future + Unpin>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+/// Returned by [`timeout`]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future + Unpin> Future for Timeout<F> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = Pin::new(&mut self.future).poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        Pin::new(&mut self.sleep).poll(cx).map(|()| None)
+    }
+}
+
+/// Returned by [`sleep`] and [`sleep_until`]
+pub struct Sleep {
+    deadline: Duration,
+    timer: Option<TimerKey>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Duration {
+        self.deadline
+    }
+
+    fn cancel(&mut self) {
+        if let Some(key) = self.timer.take() {
+            without_interrupts(|| TIMERS.lock().remove(&key));
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if time::uptime() >= self.deadline {
+            self.cancel();
+            return Poll::Ready(());
+        }
+        let deadline = self.deadline;
+        let key = *self
+            .timer
+            .get_or_insert_with(|| (deadline, NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed)));
+        without_interrupts(|| TIMERS.lock().insert(key, cx.waker().clone()));
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Wakes the timers whose deadline is at most `now`
+pub(super) fn expire(now: Duration) {
+    let expired: Vec<Waker> = without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let later = timers.split_off(&(now, u64::MAX));
+        core::mem::replace(&mut *timers, later)
+            .into_values()
+            .collect()
+    });
+    expired.into_iter().for_each(Waker::wake);
+}
+
+/// Earliest deadline of all pending timers
+pub(super) fn next_deadline() -> Option<Duration> {
+    without_interrupts(|| TIMERS.lock().keys().next().map(|(deadline, _)| *deadline))
+}
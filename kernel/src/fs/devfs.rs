@@ -0,0 +1,311 @@
+//! Device file system.
+//!
+//! Exposes character devices as files below `/dev`. Drivers make their
+//! devices available by calling [`register`] during initialization, each
+//! [`open`](Device::open) of the device file creates a new [`File`].
+extern crate alloc;
+use super::{vfs, File, FileSystem, FsError, Node, NodeKind};
+use crate::{
+    interrupts::{self, read_scancode},
+    memory::{shared, MemoryError},
+    random,
+};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use api::FramebufferInfo;
+use core::ptr;
+use x86_64::{
+    interrupts::without_interrupts, memory::VirtualAddress, mutex::Mutex, paging::CacheAttribute,
+    print::SERIAL,
+};
+
+/// Path devfs is mounted at
+pub const MOUNT_POINT: &str = "/dev";
+
+static DEVICES: Mutex<Vec<(String, Arc<dyn Device>)>> = Mutex::new(Vec::new());
+
+/// A device that can be opened through devfs
+pub trait Device: Send + Sync {
+    fn open(&self) -> Result<Arc<dyn File>, FsError>;
+}
+
+/// Makes `device` available as `/dev/<name>`
+pub fn register(name: &str, device: Arc<dyn Device>) -> Result<(), FsError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|(n, _)| n == name) {
+        return Err(FsError::AlreadyExists);
+    }
+    devices.push((String::from(name), device));
+    Ok(())
+}
+
+/// Removes the device `name`. Files that are already open stay usable.
+pub fn unregister(name: &str) -> Result<(), FsError> {
+    let mut devices = DEVICES.lock();
+    let idx = devices
+        .iter()
+        .position(|(n, _)| n == name)
+        .ok_or(FsError::NotFound)?;
+    devices.remove(idx);
+    Ok(())
+}
+
+/// Registers the built-in devices and mounts devfs
+pub fn init(framebuffer: &FramebufferInfo) -> Result<(), FsError> {
+    register("null", Arc::new(Null))?;
+    register("zero", Arc::new(Zero))?;
+    register("random", Arc::new(Random))?;
+    register("serial", Arc::new(Serial))?;
+    register("keyboard", Arc::new(Keyboard))?;
+    if framebuffer.region.size != 0 {
+        let device = Framebuffer::new(framebuffer).map_err(|_| FsError::NotSupported)?;
+        register("fb", Arc::new(device))?;
+    }
+
+    vfs::mount(MOUNT_POINT, Arc::new(DevFs))
+}
+
+struct DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Node> {
+        Arc::new(DevDirectory)
+    }
+}
+
+struct DevDirectory;
+
+impl Node for DevDirectory {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Node>, FsError> {
+        DEVICES
+            .lock()
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, device)| Arc::new(DeviceNode(device.clone())) as Arc<dyn Node>)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn entries(&self) -> Result<Vec<String>, FsError> {
+        Ok(DEVICES.lock().iter().map(|(n, _)| n.clone()).collect())
+    }
+}
+
+struct DeviceNode(Arc<dyn Device>);
+
+impl Node for DeviceNode {
+    fn kind(&self) -> NodeKind {
+        NodeKind::CharDevice
+    }
+
+    fn open(&self) -> Result<Arc<dyn File>, FsError> {
+        self.0.open()
+    }
+}
+
+/// Discards writes, reads return end of file
+#[derive(Clone, Copy)]
+struct Null;
+
+impl Device for Null {
+    fn open(&self) -> Result<Arc<dyn File>, FsError> {
+        Ok(Arc::new(*self))
+    }
+}
+
+impl File for Null {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+/// Discards writes, reads return zeroes
+#[derive(Clone, Copy)]
+struct Zero;
+
+impl Device for Zero {
+    fn open(&self) -> Result<Arc<dyn File>, FsError> {
+        Ok(Arc::new(*self))
+    }
+}
+
+impl File for Zero {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+/// Random bytes from the kernel random number generator. Writes are
+/// mixed into its entropy pool.
+#[derive(Clone, Copy)]
+struct Random;
+
+impl Device for Random {
+    fn open(&self) -> Result<Arc<dyn File>, FsError> {
+        Ok(Arc::new(*self))
+    }
+}
+
+impl File for Random {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        random::random_bytes(buf);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        for chunk in buf.chunks(8) {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            random::add_entropy(u64::from_le_bytes(bytes));
+        }
+        Ok(buf.len())
+    }
+}
+
+/// COM1
+#[derive(Clone, Copy)]
+struct Serial;
+
+impl Device for Serial {
+    fn open(&self) -> Result<Arc<dyn File>, FsError> {
+        Ok(Arc::new(*self))
+    }
+}
+
+impl File for Serial {
+    /// Blocks until at least one byte was received
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        buf[0] = interrupts::read_serial();
+        let mut read = 1;
+        while read < buf.len() {
+            match interrupts::try_read_serial() {
+                Some(byte) => buf[read] = byte,
+                None => break,
+            }
+            read += 1;
+        }
+        Ok(read)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        without_interrupts(|| {
+            let serial = SERIAL.lock();
+            for byte in buf {
+                serial.send(*byte);
+            }
+        });
+        Ok(buf.len())
+    }
+}
+
+/// Raw scancodes of the PS/2 keyboard
+#[derive(Clone, Copy)]
+struct Keyboard;
+
+impl Device for Keyboard {
+    fn open(&self) -> Result<Arc<dyn File>, FsError> {
+        Ok(Arc::new(*self))
+    }
+}
+
+impl File for Keyboard {
+    /// Blocks until a key is pressed and returns a single scancode
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        match buf.first_mut() {
+            Some(byte) => {
+                *byte = read_scancode();
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+/// Linear framebuffer set up by the bootloader. Reads and writes access the
+/// raw pixel data starting at the current position.
+struct Framebuffer {
+    info: FramebufferInfo,
+    address: VirtualAddress,
+}
+
+impl Framebuffer {
+    fn new(info: &FramebufferInfo) -> Result<Self, MemoryError> {
+        Ok(Self {
+            info: *info,
+            address: shared::map_physical(
+                info.region.address(),
+                info.region.size,
+                CacheAttribute::WriteCombining,
+            )?,
+        })
+    }
+}
+
+impl Device for Framebuffer {
+    fn open(&self) -> Result<Arc<dyn File>, FsError> {
+        Ok(Arc::new(FramebufferFile {
+            address: self.address,
+            size: self.info.region.size,
+            position: Mutex::new(0),
+        }))
+    }
+}
+
+struct FramebufferFile {
+    address: VirtualAddress,
+    size: u64,
+    position: Mutex<u64>,
+}
+
+impl File for FramebufferFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut position = self.position.lock();
+        let len = buf.len().min((self.size - *position) as usize);
+        unsafe {
+            let src: *const u8 = (self.address + *position).as_ptr();
+            ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), len);
+        }
+        *position += len as u64;
+        Ok(len)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        let mut position = self.position.lock();
+        let len = buf.len().min((self.size - *position) as usize);
+        unsafe {
+            let dst: *mut u8 = (self.address + *position).as_mut_ptr();
+            ptr::copy_nonoverlapping(buf.as_ptr(), dst, len);
+        }
+        *position += len as u64;
+        Ok(len)
+    }
+
+    fn seek(&self, offset: u64) -> Result<u64, FsError> {
+        let offset = offset.min(self.size);
+        *self.position.lock() = offset;
+        Ok(offset)
+    }
+}
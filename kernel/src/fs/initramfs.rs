@@ -0,0 +1,82 @@
+//! Unpacks the initramfs passed by the bootloader into the root file system.
+//! The frames of the archive are freed afterwards.
+extern crate alloc;
+use super::{vfs, FsError, NodeKind};
+use crate::memory;
+use alloc::{format, string::String};
+use api::{
+    initramfs::{Archive, ArchiveError, EntryKind},
+    BootInfo,
+};
+use core::slice;
+use x86_64::memory::PhysicalAddress;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitramfsError {
+    Archive(ArchiveError),
+    Fs(FsError),
+}
+
+impl From<ArchiveError> for InitramfsError {
+    fn from(error: ArchiveError) -> Self {
+        InitramfsError::Archive(error)
+    }
+}
+
+impl From<FsError> for InitramfsError {
+    fn from(error: FsError) -> Self {
+        InitramfsError::Fs(error)
+    }
+}
+
+/// Creates the files and directories of the archive below `/`. Returns the
+/// amount of unpacked entries, 0 if the bootloader did not load an initramfs.
+pub fn unpack(boot_info: &BootInfo) -> Result<usize, InitramfsError> {
+    let region = &boot_info.initramfs;
+    if region.size == 0 {
+        return Ok(0);
+    }
+
+    // the bootloader marked the region as reserved, so it is still intact
+    let data = unsafe {
+        slice::from_raw_parts(
+            (boot_info.physical_memory_offset + region.start) as *const u8,
+            region.size as usize,
+        )
+    };
+
+    let result = unpack_archive(data);
+    // the bootloader starts the following files on a new page, so the frames
+    // only hold the archive
+    memory::with_memory_manager(|mm| unsafe {
+        mm.frame_allocator()
+            .reclaim_range(PhysicalAddress::new(region.start), region.size)
+    });
+    result
+}
+
+/// Creates the entries of the archive in `data` below `/`
+pub fn unpack_archive(data: &[u8]) -> Result<usize, InitramfsError> {
+    let mut count = 0;
+    for entry in Archive::parse(data)? {
+        let entry = entry?;
+        let path: String = format!("/{}", entry.path);
+        match entry.kind {
+            EntryKind::Directory => match vfs::create(&path, NodeKind::Directory) {
+                // e.g. mount points that already exist
+                Ok(_) | Err(FsError::AlreadyExists) => (),
+                Err(error) => return Err(error.into()),
+            },
+            EntryKind::File => {
+                let file = vfs::create(&path, NodeKind::File)?.open()?;
+                let mut written = 0;
+                while written < entry.data.len() {
+                    written += file.write(&entry.data[written..])?;
+                }
+            }
+            EntryKind::End => unreachable!(),
+        }
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,257 @@
+//! Virtual file system layer.
+//!
+//! Everything a process can read from or write to is a [`File`]. Processes
+//! refer to their open files using [`FileDescriptor`]s, which index into the
+//! per process [`FileTable`]. Open files are reference counted, an object is
+//! closed once the last descriptor referring to it is gone.
+//!
+//! Files with a name live in file systems, which are mounted into a single
+//! tree of paths (see [`vfs`]). The root file system is a [`tmpfs`], devices
+//! are exposed under `/dev` by [`devfs`] and kernel state under `/proc` by
+//! [`procfs`].
+extern crate alloc;
+use crate::{
+    error, info,
+    net::udp::UdpSocket,
+    process::{self, with_process_table},
+    syscall::{Errno, SyscallResult},
+};
+use alloc::{sync::Arc, vec::Vec};
+use api::BootInfo;
+use core::{fmt, slice, str};
+
+pub mod devfs;
+pub mod initramfs;
+pub mod pipe;
+pub mod procfs;
+pub mod tmpfs;
+pub mod vfs;
+
+pub use vfs::{FileSystem, Node, NodeKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// The descriptor does not refer to an open file
+    BadDescriptor,
+    /// The file does not support the operation
+    NotSupported,
+    /// Writing to a pipe without readers
+    BrokenPipe,
+    /// A buffer passed by the caller is invalid
+    InvalidBuffer,
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    /// Paths have to be absolute and valid UTF-8
+    InvalidPath,
+    /// Removing a directory that still has entries
+    NotEmpty,
+    /// Renaming across file systems
+    CrossDevice,
+}
+
+impl From<FsError> for Errno {
+    fn from(error: FsError) -> Self {
+        match error {
+            FsError::BadDescriptor => Errno::EBADF,
+            FsError::NotSupported => Errno::EINVAL,
+            FsError::BrokenPipe => Errno::EPIPE,
+            FsError::InvalidBuffer => Errno::EFAULT,
+            FsError::NotFound => Errno::ENOENT,
+            FsError::AlreadyExists => Errno::EEXIST,
+            FsError::NotADirectory => Errno::ENOTDIR,
+            FsError::IsADirectory => Errno::EISDIR,
+            FsError::InvalidPath => Errno::EINVAL,
+            FsError::NotEmpty => Errno::ENOTEMPTY,
+            FsError::CrossDevice => Errno::EXDEV,
+        }
+    }
+}
+
+/// An open file
+pub trait File: Send + Sync {
+    /// Reads up to `buf.len()` bytes into `buf`. Returns the amount of bytes
+    /// read, 0 means end of file.
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Writes up to `buf.len()` bytes from `buf`. Returns the amount of bytes
+    /// written.
+    fn write(&self, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Moves the position of the next read or write to `offset`. Returns the
+    /// new position.
+    fn seek(&self, _offset: u64) -> Result<u64, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// The socket behind the file, if it is one
+    fn as_socket(&self) -> Option<&UdpSocket> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileDescriptor(usize);
+
+impl FileDescriptor {
+    pub fn from_u64(fd: u64) -> Self {
+        Self(fd as usize)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0 as u64
+    }
+}
+
+impl fmt::Display for FileDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Open files of a process
+#[derive(Default, Clone)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    pub const fn new() -> Self {
+        Self { files: Vec::new() }
+    }
+
+    /// Adds `file` using the lowest free descriptor
+    pub fn insert(&mut self, file: Arc<dyn File>) -> FileDescriptor {
+        match self.files.iter().position(|f| f.is_none()) {
+            Some(index) => {
+                self.files[index] = Some(file);
+                FileDescriptor(index)
+            }
+            None => {
+                self.files.push(Some(file));
+                FileDescriptor(self.files.len() - 1)
+            }
+        }
+    }
+
+    pub fn get(&self, fd: FileDescriptor) -> Option<Arc<dyn File>> {
+        self.files.get(fd.0).cloned().flatten()
+    }
+
+    pub fn remove(&mut self, fd: FileDescriptor) -> Option<Arc<dyn File>> {
+        self.files.get_mut(fd.0).and_then(|f| f.take())
+    }
+
+    /// Amount of open descriptors
+    pub fn len(&self) -> usize {
+        self.files.iter().filter(|f| f.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Mounts the root file system, devfs and procfs, then unpacks the initramfs into the
+/// root file system
+pub fn init(boot_info: &BootInfo) -> Result<(), FsError> {
+    vfs::mount("/", Arc::new(tmpfs::TmpFs::new()))?;
+    vfs::create(devfs::MOUNT_POINT, NodeKind::Directory)?;
+    devfs::init(&boot_info.framebuffer)?;
+    vfs::create(procfs::MOUNT_POINT, NodeKind::Directory)?;
+    procfs::init()?;
+
+    match initramfs::unpack(boot_info) {
+        Ok(0) => (),
+        Ok(count) => info!("Unpacked {} initramfs entries", count),
+        Err(error) => error!("Failed to unpack initramfs: {:?}", error),
+    }
+    Ok(())
+}
+
+/// Opens the file at `path` and installs it in the file table of the current
+/// process
+pub fn open(path: &str) -> Result<FileDescriptor, FsError> {
+    Ok(install(vfs::open(path)?))
+}
+
+/// Creates an empty file at `path` and opens it
+pub fn create(path: &str) -> Result<FileDescriptor, FsError> {
+    Ok(install(vfs::create(path, NodeKind::File)?.open()?))
+}
+
+/// Installs `file` in the file table of the current process
+pub fn install(file: Arc<dyn File>) -> FileDescriptor {
+    let pid = process::current();
+    with_process_table(|table| table.get_mut(pid).unwrap().files().insert(file))
+}
+
+/// Looks up `fd` in the file table of the current process
+pub fn get(fd: FileDescriptor) -> Result<Arc<dyn File>, FsError> {
+    let pid = process::current();
+    with_process_table(|table| table.get_mut(pid).unwrap().files().get(fd))
+        .ok_or(FsError::BadDescriptor)
+}
+
+pub fn read(fd: FileDescriptor, buf: &mut [u8]) -> Result<usize, FsError> {
+    // the process table must not be locked while blocking on the file
+    get(fd)?.read(buf)
+}
+
+pub fn write(fd: FileDescriptor, buf: &[u8]) -> Result<usize, FsError> {
+    get(fd)?.write(buf)
+}
+
+/// Closes `fd`. The file itself is closed once no descriptor refers to it
+/// anymore.
+pub fn close(fd: FileDescriptor) -> Result<(), FsError> {
+    let pid = process::current();
+    let file = with_process_table(|table| table.get_mut(pid).unwrap().files().remove(fd))
+        .ok_or(FsError::BadDescriptor)?;
+    // dropping a file can block or allocate, so do it after the process table
+    // lock is released
+    drop(file);
+    Ok(())
+}
+
+/// Turns a buffer passed to a system call into a slice
+///
+/// # Safety
+///
+/// `address` must point to `len` bytes of valid memory
+unsafe fn buffer<'a>(address: u64, len: u64) -> Result<&'a mut [u8], FsError> {
+    if address == 0 {
+        return Err(FsError::InvalidBuffer);
+    }
+    Ok(slice::from_raw_parts_mut(address as *mut u8, len as usize))
+}
+
+/// `open(path, len)` system call. Returns the new file descriptor.
+pub fn sys_open(address: u64, len: u64) -> SyscallResult {
+    let path = unsafe { buffer(address, len)? };
+    let path = str::from_utf8(path).map_err(|_| FsError::InvalidPath)?;
+    Ok(open(path)?.as_u64())
+}
+
+/// `read(fd, buf, len)` system call
+pub fn sys_read(fd: u64, address: u64, len: u64) -> SyscallResult {
+    let buf = unsafe { buffer(address, len)? };
+    Ok(read(FileDescriptor::from_u64(fd), buf)? as u64)
+}
+
+/// `write(fd, buf, len)` system call
+pub fn sys_write(fd: u64, address: u64, len: u64) -> SyscallResult {
+    let buf = unsafe { buffer(address, len)? };
+    Ok(write(FileDescriptor::from_u64(fd), buf)? as u64)
+}
+
+/// `close(fd)` system call
+pub fn sys_close(fd: u64) -> SyscallResult {
+    close(FileDescriptor::from_u64(fd))?;
+    Ok(0)
+}
@@ -0,0 +1,175 @@
+//! Anonymous pipes.
+//!
+//! A pipe is a bounded byte buffer with a read and a write end. Readers block
+//! while the pipe is empty, writers while it is full. Once the write end is
+//! closed readers drain the remaining data and then get end of file. Writing
+//! after the read end was closed fails with [`FsError::BrokenPipe`] and raises
+//! `SIGPIPE` in the writing process.
+extern crate alloc;
+use super::{File, FileDescriptor, FsError};
+use crate::{
+    process::{
+        self,
+        signal::{self, Signal},
+    },
+    sync::WaitQueue,
+    syscall::SyscallResult,
+};
+use alloc::{collections::VecDeque, sync::Arc};
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+/// Maximum amount of buffered bytes
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct PipeBuffer {
+    data: VecDeque<u8>,
+    reader_closed: bool,
+    writer_closed: bool,
+}
+
+struct Pipe {
+    // Locked with interrupts disabled since it is checked from wait queue
+    // conditions
+    buffer: Mutex<PipeBuffer>,
+    readable: WaitQueue,
+    writable: WaitQueue,
+}
+
+impl Pipe {
+    fn with_buffer<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut PipeBuffer) -> R,
+    {
+        without_interrupts(|| f(&mut self.buffer.lock()))
+    }
+}
+
+/// Read end of a pipe
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+/// Write end of a pipe
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+/// Creates a new pipe and returns its read and write end
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        buffer: Mutex::new(PipeBuffer {
+            data: VecDeque::with_capacity(PIPE_CAPACITY),
+            reader_closed: false,
+            writer_closed: false,
+        }),
+        readable: WaitQueue::named("pipe read"),
+        writable: WaitQueue::named("pipe write"),
+    });
+
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+impl File for PipeReader {
+    /// Blocks until data is available or the write end is closed
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut read = 0;
+        self.pipe.readable.wait_until(|| {
+            self.pipe.with_buffer(|buffer| {
+                if buffer.data.is_empty() {
+                    return buffer.writer_closed;
+                }
+                let count = buf.len().min(buffer.data.len());
+                for (dst, src) in buf.iter_mut().zip(buffer.data.drain(..count)) {
+                    *dst = src;
+                }
+                read = count;
+                true
+            })
+        });
+
+        if read > 0 {
+            self.pipe.writable.wake_all();
+        }
+        Ok(read)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.pipe.with_buffer(|buffer| buffer.reader_closed = true);
+        self.pipe.writable.wake_all();
+    }
+}
+
+impl File for PipeWriter {
+    /// Blocks until there is space in the pipe. Might write less than
+    /// `buf.len()` bytes.
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut result = Ok(0);
+        self.pipe.writable.wait_until(|| {
+            self.pipe.with_buffer(|buffer| {
+                if buffer.reader_closed {
+                    result = Err(FsError::BrokenPipe);
+                    return true;
+                }
+                let count = buf.len().min(PIPE_CAPACITY - buffer.data.len());
+                if count == 0 {
+                    return false;
+                }
+                buffer.data.extend(&buf[..count]);
+                result = Ok(count);
+                true
+            })
+        });
+
+        match result {
+            Ok(_) => {
+                self.pipe.readable.wake_all();
+            }
+            Err(FsError::BrokenPipe) => {
+                let _ = signal::kill(process::current(), Signal::SIGPIPE);
+            }
+            Err(_) => (),
+        }
+        result
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.with_buffer(|buffer| buffer.writer_closed = true);
+        self.pipe.readable.wake_all();
+    }
+}
+
+/// Creates a pipe and installs both ends in the file table of the current
+/// process. Returns the read and write descriptor.
+pub fn open() -> (FileDescriptor, FileDescriptor) {
+    let (reader, writer) = pipe();
+    (
+        super::install(Arc::new(reader)),
+        super::install(Arc::new(writer)),
+    )
+}
+
+/// `pipe(fds)` system call. Stores the read and write descriptor in the two
+/// element u32 array `fds`.
+pub fn sys_pipe(fds: u64) -> SyscallResult {
+    if fds == 0 || !fds.is_multiple_of(4) {
+        return Err(FsError::InvalidBuffer.into());
+    }
+
+    let (reader, writer) = open();
+    let fds = unsafe { core::slice::from_raw_parts_mut(fds as *mut u32, 2) };
+    fds[0] = reader.as_u64() as u32;
+    fds[1] = writer.as_u64() as u32;
+    Ok(0)
+}
@@ -0,0 +1,329 @@
+//! Process file system.
+//!
+//! Read-only files describing the state of the kernel, mounted at `/proc`:
+//!
+//! - `interrupts`: interrupts handled per vector and the name of the handler
+//! - `softirqs`: times each softirq ran
+//! - `uptime`: seconds since boot and seconds the CPU spent idling
+//! - `schedstat`: context switches, run queue length and the scheduling
+//!   latency histogram of all threads
+//! - `cpuidle`: idle method and entries and residency per idle state
+//! - `threads`: id, process, name and state of every thread
+//! - `<pid>/status`: name, parent, state, threads and CPU time of a process
+//! - `<pid>/sched`: context switches and latency histogram per thread of a
+//!   process
+//!
+//! The contents of a file are generated when it is opened, reads return that
+//! snapshot.
+extern crate alloc;
+use super::{vfs, File, FileSystem, FsError, Node, NodeKind};
+use crate::{
+    interrupts::{
+        self,
+        softirq::{self, SoftIrq},
+    },
+    process::{self, Pid, ProcessState},
+    scheduler::{self, cpuidle, stats::ThreadStats, ThreadId},
+    time,
+};
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use core::{fmt::Write, time::Duration};
+use x86_64::mutex::Mutex;
+
+/// Path procfs is mounted at
+pub const MOUNT_POINT: &str = "/proc";
+
+/// Generates the contents of a file
+type Generator = fn() -> String;
+
+/// Files in the root directory and the functions generating their contents
+const FILES: &[(&str, Generator)] = &[
+    ("interrupts", interrupts),
+    ("softirqs", softirqs),
+    ("uptime", uptime),
+    ("schedstat", schedstat),
+    ("cpuidle", cpuidle),
+    ("threads", threads),
+];
+
+/// Mounts procfs, the mount point has to exist
+pub fn init() -> Result<(), FsError> {
+    vfs::mount(MOUNT_POINT, Arc::new(ProcFs))
+}
+
+struct ProcFs;
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Node> {
+        Arc::new(ProcDirectory)
+    }
+}
+
+fn pids() -> Vec<Pid> {
+    process::with_process_table(|table| table.processes().map(|p| p.pid()).collect())
+}
+
+struct ProcDirectory;
+
+impl Node for ProcDirectory {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Node>, FsError> {
+        if let Some((_, generate)) = FILES.iter().find(|(n, _)| *n == name) {
+            return Ok(Arc::new(GeneratedNode(*generate)));
+        }
+
+        let pid = name
+            .parse()
+            .map(Pid::from_u64)
+            .map_err(|_| FsError::NotFound)?;
+        match pids().contains(&pid) {
+            true => Ok(Arc::new(ProcessDirectory(pid))),
+            false => Err(FsError::NotFound),
+        }
+    }
+
+    fn entries(&self) -> Result<Vec<String>, FsError> {
+        let mut entries: Vec<String> = FILES.iter().map(|(n, _)| String::from(*n)).collect();
+        let mut pids = pids();
+        pids.sort();
+        entries.extend(pids.iter().map(|pid| format!("{}", pid)));
+        Ok(entries)
+    }
+}
+
+struct ProcessDirectory(Pid);
+
+impl Node for ProcessDirectory {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Node>, FsError> {
+        match name {
+            "status" => Ok(Arc::new(ProcessNode(self.0, status))),
+            "sched" => Ok(Arc::new(ProcessNode(self.0, sched))),
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn entries(&self) -> Result<Vec<String>, FsError> {
+        Ok(vec![String::from("status"), String::from("sched")])
+    }
+}
+
+struct GeneratedNode(Generator);
+
+impl Node for GeneratedNode {
+    fn kind(&self) -> NodeKind {
+        NodeKind::File
+    }
+
+    fn open(&self) -> Result<Arc<dyn File>, FsError> {
+        Ok(Arc::new(SnapshotFile::new(self.0())))
+    }
+}
+
+/// File of a process directory and the function generating its contents
+struct ProcessNode(Pid, fn(Pid) -> Result<String, FsError>);
+
+impl Node for ProcessNode {
+    fn kind(&self) -> NodeKind {
+        NodeKind::File
+    }
+
+    fn open(&self) -> Result<Arc<dyn File>, FsError> {
+        Ok(Arc::new(SnapshotFile::new(self.1(self.0)?)))
+    }
+}
+
+/// Contents generated when the file was opened
+struct SnapshotFile {
+    data: Vec<u8>,
+    position: Mutex<usize>,
+}
+
+impl SnapshotFile {
+    fn new(contents: String) -> Self {
+        Self {
+            data: contents.into_bytes(),
+            position: Mutex::new(0),
+        }
+    }
+}
+
+impl File for SnapshotFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut position = self.position.lock();
+        let len = buf.len().min(self.data.len() - *position);
+        buf[..len].copy_from_slice(&self.data[*position..*position + len]);
+        *position += len;
+        Ok(len)
+    }
+
+    fn seek(&self, offset: u64) -> Result<u64, FsError> {
+        let offset = (offset as usize).min(self.data.len());
+        *self.position.lock() = offset;
+        Ok(offset as u64)
+    }
+}
+
+/// Seconds with two decimals, like Linux does
+fn seconds(duration: Duration) -> String {
+    format!(
+        "{}.{:02}",
+        duration.as_secs(),
+        duration.subsec_millis() / 10
+    )
+}
+
+fn interrupts() -> String {
+    let mut out = String::new();
+    let mut handlers: Vec<_> = interrupts::handlers().iter().collect();
+    handlers.sort_by_key(|h| h.vector);
+    for handler in handlers {
+        let _ = writeln!(
+            out,
+            "{:>3}: {:>10} {}",
+            handler.vector,
+            interrupts::interrupt_count(handler.vector),
+            handler.name
+        );
+    }
+    out
+}
+
+fn softirqs() -> String {
+    let mut out = String::new();
+    for softirq in SoftIrq::ALL {
+        let _ = writeln!(
+            out,
+            "{:>14}: {:>10}",
+            softirq.name(),
+            softirq::count(softirq)
+        );
+    }
+    out
+}
+
+fn uptime() -> String {
+    let idle = scheduler::idle()
+        .and_then(|idle| scheduler::with_scheduler(|s| s.cpu_time(idle)))
+        .unwrap_or(Duration::ZERO);
+    format!("{} {}\n", seconds(time::uptime()), seconds(idle))
+}
+
+fn status(pid: Pid) -> Result<String, FsError> {
+    // the scheduler and the process table are locked one after the other
+    let threads: Vec<(ThreadId, Duration)> = scheduler::with_scheduler(|s| {
+        s.threads()
+            .filter_map(|t| Some((t.id(), s.cpu_time(t.id())?)))
+            .collect()
+    });
+    let idle = scheduler::idle();
+
+    process::with_process_table(|table| {
+        let process = table.get(pid).ok_or(FsError::NotFound)?;
+        let threads: Vec<_> = threads
+            .iter()
+            .filter(|(id, _)| table.owner(*id) == pid && Some(*id) != idle)
+            .collect();
+        let cpu_time = threads.iter().map(|(_, time)| *time).sum();
+
+        let state = match process.state() {
+            ProcessState::Running if process.signals().is_stopped() => String::from("stopped"),
+            ProcessState::Running => String::from("running"),
+            ProcessState::Zombie { exit_code } => format!("zombie (exit code {})", exit_code),
+        };
+        let mut out = String::new();
+        let _ = writeln!(out, "Name:\t{}", process.name());
+        let _ = writeln!(out, "Pid:\t{}", pid);
+        let _ = writeln!(out, "PPid:\t{}", process.parent());
+        let _ = writeln!(out, "State:\t{}", state);
+        let _ = writeln!(out, "Threads:\t{}", threads.len());
+        let _ = writeln!(out, "Children:\t{}", process.children().len());
+        let _ = writeln!(out, "CpuTime:\t{}", seconds(cpu_time));
+        Ok(out)
+    })
+}
+
+fn schedstat() -> String {
+    let (stats, ticks) = scheduler::with_scheduler(|s| (*s.stats(), s.ticks()));
+    let mut out = String::new();
+    let _ = writeln!(out, "Switches:\t{}", stats.context_switches);
+    let _ = writeln!(
+        out,
+        "RunQueue:\tmean {}.{:02} max {}",
+        stats.queued / ticks.max(1),
+        stats.queued * 100 / ticks.max(1) % 100,
+        stats.max_queued
+    );
+    let _ = write!(out, "Latency:\t{}", stats.latency);
+    out
+}
+
+fn sched(pid: Pid) -> Result<String, FsError> {
+    let threads: Vec<(ThreadId, ThreadStats)> =
+        scheduler::with_scheduler(|s| s.threads().map(|t| (t.id(), *t.stats())).collect());
+    let idle = scheduler::idle();
+
+    let threads: Vec<_> = process::with_process_table(|table| {
+        table.get(pid).ok_or(FsError::NotFound)?;
+        Ok(threads
+            .into_iter()
+            .filter(|(id, _)| table.owner(*id) == pid && Some(*id) != idle)
+            .collect())
+    })?;
+
+    let mut out = String::new();
+    for (id, stats) in threads {
+        let _ = writeln!(out, "Thread:\t{}", id);
+        let _ = writeln!(
+            out,
+            "Switches:\t{} voluntary {} involuntary",
+            stats.voluntary_switches, stats.involuntary_switches
+        );
+        let _ = write!(out, "Latency:\t{}", stats.latency);
+    }
+    Ok(out)
+}
+
+fn cpuidle() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "Method:\t{:?}", cpuidle::method());
+    for state in cpuidle::states() {
+        let _ = writeln!(
+            out,
+            "{}:\tentries {} time {} target {}us",
+            state.name,
+            state.entries,
+            seconds(state.residency),
+            state.target_residency.as_micros()
+        );
+    }
+    out
+}
+
+fn threads() -> String {
+    let mut threads: Vec<(ThreadId, String, String)> = scheduler::with_scheduler(|s| {
+        s.threads()
+            .map(|t| (t.id(), String::from(t.name()), t.describe_state()))
+            .collect()
+    });
+    threads.sort_by_key(|t| t.0);
+    let owners: Vec<Pid> =
+        process::with_process_table(|table| threads.iter().map(|t| table.owner(t.0)).collect());
+
+    let mut out = String::new();
+    let _ = writeln!(out, "TID\tPID\tNAME\tSTATE");
+    for ((id, name, state), owner) in threads.into_iter().zip(owners) {
+        let _ = writeln!(out, "{}\t{}\t{}\t{}", id, owner, name, state);
+    }
+    out
+}
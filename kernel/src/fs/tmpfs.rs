@@ -0,0 +1,192 @@
+//! In-memory file system.
+//!
+//! Directories and files only exist in memory and are gone once the file
+//! system is dropped. File contents live on the kernel heap and grow on
+//! demand. Files can also be mapped into virtual memory since they implement
+//! [`MappableFile`].
+extern crate alloc;
+use super::{File, FileSystem, FsError, Node, NodeKind};
+use crate::memory::MappableFile;
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use x86_64::mutex::Mutex;
+
+pub struct TmpFs {
+    root: Arc<TmpDirectory>,
+}
+
+impl TmpFs {
+    pub fn new() -> Self {
+        Self {
+            root: Arc::new(TmpDirectory::new()),
+        }
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Node> {
+        self.root.clone()
+    }
+}
+
+pub struct TmpDirectory {
+    entries: Mutex<BTreeMap<String, Arc<dyn Node>>>,
+}
+
+impl TmpDirectory {
+    fn new() -> Self {
+        Self {
+            entries: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Node for TmpDirectory {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Directory
+    }
+
+    fn size(&self) -> u64 {
+        self.entries.lock().len() as u64
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Node>, FsError> {
+        self.entries
+            .lock()
+            .get(name)
+            .cloned()
+            .ok_or(FsError::NotFound)
+    }
+
+    fn entries(&self) -> Result<Vec<String>, FsError> {
+        Ok(self.entries.lock().keys().cloned().collect())
+    }
+
+    fn create(&self, name: &str, kind: NodeKind) -> Result<Arc<dyn Node>, FsError> {
+        let node: Arc<dyn Node> = match kind {
+            NodeKind::File => Arc::new(TmpFile::new()),
+            NodeKind::Directory => Arc::new(TmpDirectory::new()),
+            NodeKind::CharDevice => return Err(FsError::NotSupported),
+        };
+        self.link(name, node.clone())?;
+        Ok(node)
+    }
+
+    fn link(&self, name: &str, node: Arc<dyn Node>) -> Result<(), FsError> {
+        let mut entries = self.entries.lock();
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        entries.insert(String::from(name), node);
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> Result<Arc<dyn Node>, FsError> {
+        self.entries.lock().remove(name).ok_or(FsError::NotFound)
+    }
+}
+
+/// A regular file
+pub struct TmpFile {
+    contents: Arc<TmpFileContents>,
+}
+
+impl TmpFile {
+    fn new() -> Self {
+        Self {
+            contents: Arc::new(TmpFileContents {
+                data: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+}
+
+struct TmpFileContents {
+    data: Mutex<Vec<u8>>,
+}
+
+impl MappableFile for TmpFileContents {
+    fn size(&self) -> u64 {
+        self.data.lock().len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize {
+        let data = self.data.lock();
+        let offset = offset as usize;
+        if offset >= data.len() {
+            return 0;
+        }
+        let len = buf.len().min(data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        len
+    }
+
+    /// Writing past the end grows the file, a gap is filled with zeroes
+    fn write_at(&self, offset: u64, buf: &[u8]) -> usize {
+        let mut data = self.data.lock();
+        let offset = offset as usize;
+        let end = offset + buf.len();
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(buf);
+        buf.len()
+    }
+}
+
+impl Node for TmpFile {
+    fn kind(&self) -> NodeKind {
+        NodeKind::File
+    }
+
+    fn size(&self) -> u64 {
+        self.contents.size()
+    }
+
+    fn open(&self) -> Result<Arc<dyn File>, FsError> {
+        Ok(Arc::new(TmpFileHandle {
+            file: self.contents.clone(),
+            position: Mutex::new(0),
+        }))
+    }
+
+    fn contents(&self) -> Result<Arc<dyn MappableFile>, FsError> {
+        Ok(self.contents.clone())
+    }
+}
+
+/// An open [`TmpFile`]
+struct TmpFileHandle {
+    file: Arc<TmpFileContents>,
+    position: Mutex<u64>,
+}
+
+impl File for TmpFileHandle {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut position = self.position.lock();
+        let read = self.file.read_at(*position, buf);
+        *position += read as u64;
+        Ok(read)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        let mut position = self.position.lock();
+        let written = self.file.write_at(*position, buf);
+        *position += written as u64;
+        Ok(written)
+    }
+
+    fn seek(&self, offset: u64) -> Result<u64, FsError> {
+        *self.position.lock() = offset;
+        Ok(offset)
+    }
+}
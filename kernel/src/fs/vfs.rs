@@ -0,0 +1,265 @@
+//! Mount table and path resolution.
+//!
+//! File systems are mounted at absolute paths. A path is resolved by picking
+//! the mount with the longest matching prefix and walking the remaining
+//! components starting at the root node of its file system.
+//!
+//! Every path resolution reads the mount table while it rarely changes, so it
+//! is read without locking and replaced as a whole by mount and unmount.
+extern crate alloc;
+use super::{File, FsError};
+use crate::{
+    memory::MappableFile,
+    sync::{rcu_read_lock, RcuCell},
+};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref MOUNTS: RcuCell<Vec<Mount>> = RcuCell::new(Vec::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+    CharDevice,
+}
+
+/// An object in a file system, e.g. a file or a directory
+pub trait Node: Send + Sync {
+    fn kind(&self) -> NodeKind;
+
+    /// Size in bytes, 0 for nodes without a meaningful size
+    fn size(&self) -> u64 {
+        0
+    }
+
+    /// Looks up the child `name` of a directory
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Node>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Names of the children of a directory
+    fn entries(&self) -> Result<Vec<String>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Opens the node for reading and writing
+    fn open(&self) -> Result<Arc<dyn File>, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Contents of a regular file, e.g. to map them using
+    /// [`VirtualMemoryObject::FileBacked`](crate::memory::VirtualMemoryObject::FileBacked)
+    fn contents(&self) -> Result<Arc<dyn MappableFile>, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Creates a new empty child `name` of a directory
+    fn create(&self, _name: &str, _kind: NodeKind) -> Result<Arc<dyn Node>, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Adds an existing node of the same file system as child `name`
+    fn link(&self, _name: &str, _node: Arc<dyn Node>) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Removes the child `name` of a directory and returns it
+    fn unlink(&self, _name: &str) -> Result<Arc<dyn Node>, FsError> {
+        Err(FsError::NotSupported)
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn root(&self) -> Arc<dyn Node>;
+}
+
+#[derive(Clone)]
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+/// Splits an absolute path into its components
+fn components(path: &str) -> Result<impl Iterator<Item = &str>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(path.split('/').filter(|c| !c.is_empty() && *c != "."))
+}
+
+/// Normalizes `path` to the form used in the mount table, e.g. "/dev"
+fn normalize(path: &str) -> Result<String, FsError> {
+    let mut normalized = String::new();
+    for component in components(path)? {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Mounts `fs` at `path`
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = normalize(path)?;
+    MOUNTS.update(|mounts| {
+        if mounts.iter().any(|m| m.path == path) {
+            return Err(FsError::AlreadyExists);
+        }
+        mounts.push(Mount { path, fs });
+        Ok(())
+    })
+}
+
+/// Removes the file system mounted at `path`
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let path = normalize(path)?;
+    MOUNTS.update(|mounts| {
+        let idx = mounts
+            .iter()
+            .position(|m| m.path == path)
+            .ok_or(FsError::NotFound)?;
+        mounts.remove(idx);
+        Ok(())
+    })
+}
+
+/// Paths and names of all mounted file systems
+pub fn mounts() -> Vec<(String, String)> {
+    let guard = rcu_read_lock();
+    MOUNTS
+        .read(&guard)
+        .iter()
+        .map(|m| (m.path.clone(), String::from(m.fs.name())))
+        .collect()
+}
+
+/// Finds the mount responsible for `path` and returns its file system
+/// together with the remainder of the path
+fn find_mount(path: &str) -> Result<(Arc<dyn FileSystem>, String), FsError> {
+    let path = normalize(path)?;
+    let guard = rcu_read_lock();
+    let mount = MOUNTS
+        .read(&guard)
+        .iter()
+        .filter(|m| {
+            m.path == "/"
+                || path == m.path
+                || (path.starts_with(m.path.as_str()) && path.as_bytes()[m.path.len()] == b'/')
+        })
+        .max_by_key(|m| m.path.len())
+        .ok_or(FsError::NotFound)?;
+
+    let rest = match mount.path.as_str() {
+        "/" => path.clone(),
+        prefix => String::from(&path[prefix.len()..]),
+    };
+    Ok((mount.fs.clone(), rest))
+}
+
+/// Resolves `path` to a node
+pub fn lookup(path: &str) -> Result<Arc<dyn Node>, FsError> {
+    let (fs, rest) = find_mount(path)?;
+    let mut node = fs.root();
+    for component in components(&rest)? {
+        node = node.lookup(component)?;
+    }
+    Ok(node)
+}
+
+/// Resolves `path` and opens the node
+pub fn open(path: &str) -> Result<Arc<dyn File>, FsError> {
+    lookup(path)?.open()
+}
+
+/// Splits `path` into its parent directory and the last component
+fn split_parent(path: &str) -> Result<(String, String), FsError> {
+    let path = normalize(path)?;
+    match path.rfind('/') {
+        Some(idx) if idx + 1 < path.len() => {
+            let parent = if idx == 0 { "/" } else { &path[..idx] };
+            Ok((String::from(parent), String::from(&path[idx + 1..])))
+        }
+        // the root has no parent
+        _ => Err(FsError::InvalidPath),
+    }
+}
+
+/// Creates an empty node of type `kind` at `path`
+pub fn create(path: &str, kind: NodeKind) -> Result<Arc<dyn Node>, FsError> {
+    let (parent, name) = split_parent(path)?;
+    lookup(&parent)?.create(&name, kind)
+}
+
+fn is_empty_directory(node: &Arc<dyn Node>) -> Result<bool, FsError> {
+    Ok(node.kind() == NodeKind::Directory && node.entries()?.is_empty())
+}
+
+/// Removes the node at `path`. Directories have to be empty.
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let (parent, name) = split_parent(path)?;
+    let parent = lookup(&parent)?;
+    let node = parent.lookup(&name)?;
+    if node.kind() == NodeKind::Directory && !is_empty_directory(&node)? {
+        return Err(FsError::NotEmpty);
+    }
+    parent.unlink(&name).map(|_| ())
+}
+
+/// Moves the node at `old` to `new`, replacing a file or empty directory at
+/// `new`. Both paths have to be on the same file system.
+pub fn rename(old: &str, new: &str) -> Result<(), FsError> {
+    let (old_parent, old_name) = split_parent(old)?;
+    let (new_parent, new_name) = split_parent(new)?;
+
+    let old = normalize(old)?;
+    let new = normalize(new)?;
+    if old == new {
+        return Ok(());
+    }
+    // a directory can't be moved into itself
+    if new.starts_with(old.as_str()) && new.as_bytes()[old.len()] == b'/' {
+        return Err(FsError::InvalidPath);
+    }
+
+    let (old_fs, _) = find_mount(&old)?;
+    let (new_fs, _) = find_mount(&new)?;
+    if !Arc::ptr_eq(&old_fs, &new_fs) {
+        return Err(FsError::CrossDevice);
+    }
+
+    let old_parent = lookup(&old_parent)?;
+    let new_parent = lookup(&new_parent)?;
+    let node = old_parent.lookup(&old_name)?;
+
+    match new_parent.lookup(&new_name) {
+        Ok(existing)
+            if existing.kind() == NodeKind::Directory && node.kind() != NodeKind::Directory =>
+        {
+            return Err(FsError::IsADirectory)
+        }
+        Ok(existing)
+            if existing.kind() != NodeKind::Directory && node.kind() == NodeKind::Directory =>
+        {
+            return Err(FsError::NotADirectory)
+        }
+        Ok(existing) => {
+            if existing.kind() == NodeKind::Directory && !is_empty_directory(&existing)? {
+                return Err(FsError::NotEmpty);
+            }
+            new_parent.unlink(&new_name)?;
+        }
+        Err(FsError::NotFound) => (),
+        Err(error) => return Err(error),
+    }
+
+    new_parent.link(&new_name, node)?;
+    old_parent.unlink(&old_name)?;
+    Ok(())
+}
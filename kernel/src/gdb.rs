@@ -0,0 +1,632 @@
+//! GDB remote protocol stub on COM2.
+//!
+//! Once initialized, breakpoint and debug exceptions stop the kernel and hand
+//! control to a debugger attached to the second serial port, e.g.
+//! `qemu -serial stdio -serial tcp::1235,server` and
+//! `target remote :1235` in gdb.
+//!
+//! Supported are register and memory access, software breakpoints and single
+//! stepping. Breakpoints are set by replacing the first byte of the
+//! instruction with `int3`, single stepping uses the trap flag. Memory is
+//! accessed through the physical memory mapping, so breakpoints can be placed
+//! in read only code as well.
+//!
+//! The stub polls the port with interrupts disabled, the whole kernel is
+//! stopped while the debugger is in control. Asynchronous interrupts (Ctrl-C)
+//! are not supported, call [`breakpoint`] to stop at a specific point.
+use crate::{debug, paging};
+use core::{
+    arch::global_asm,
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    const_assert,
+    idt::HandlerFunc,
+    instructions::int3,
+    interrupts::ExceptionStackFrame,
+    memory::VirtualAddress,
+    mutex::Mutex,
+    register::{DS, ES},
+    uart::SerialPort,
+};
+
+const COM2_BASE: u16 = 0x2F8;
+const PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 32;
+
+const INT3: u8 = 0xcc;
+const TRAP_FLAG: u64 = 1 << 8;
+/// Stop reason reported to gdb, always SIGTRAP
+const SIGTRAP: u8 = 5;
+
+const DEBUG_VECTOR: u64 = 1;
+const BREAKPOINT_VECTOR: u64 = 3;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static BREAKPOINTS: Mutex<[Option<Breakpoint>; MAX_BREAKPOINTS]> =
+    Mutex::new([None; MAX_BREAKPOINTS]);
+
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    address: VirtualAddress,
+    original: u8,
+}
+
+/// Register state of the interrupted code. Written by the exception entry,
+/// changes are restored on return.
+#[repr(C)]
+#[derive(Debug)]
+struct TrapFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    // pushed by the CPU
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+/// Number of registers in the order gdb expects them for x86_64. The first
+/// 17 are 64 bit, the remaining ones (eflags and segments) 32 bit.
+const REGISTER_COUNT: usize = 24;
+const WIDE_REGISTER_COUNT: usize = 17;
+
+impl TrapFrame {
+    fn register(&self, index: usize) -> u64 {
+        match index {
+            0 => self.rax,
+            1 => self.rbx,
+            2 => self.rcx,
+            3 => self.rdx,
+            4 => self.rsi,
+            5 => self.rdi,
+            6 => self.rbp,
+            7 => self.rsp,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            16 => self.rip,
+            17 => self.rflags,
+            18 => self.cs,
+            19 => self.ss,
+            20 => DS::read() as u64,
+            21 => ES::read() as u64,
+            // fs and gs are unused
+            _ => 0,
+        }
+    }
+
+    /// Segment registers are not writable
+    fn set_register(&mut self, index: usize, value: u64) {
+        let register = match index {
+            0 => &mut self.rax,
+            1 => &mut self.rbx,
+            2 => &mut self.rcx,
+            3 => &mut self.rdx,
+            4 => &mut self.rsi,
+            5 => &mut self.rdi,
+            6 => &mut self.rbp,
+            7 => &mut self.rsp,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            16 => &mut self.rip,
+            17 => &mut self.rflags,
+            _ => return,
+        };
+        *register = value;
+    }
+}
+
+fn register_size(index: usize) -> usize {
+    if index < WIDE_REGISTER_COUNT {
+        8
+    } else {
+        4
+    }
+}
+
+// The CPU pushes 5 registers, together with the 15 saved ones the stack stays
+// aligned for the call into Rust.
+macro_rules! trap_entry {
+    ($name:literal, $vector:expr) => {
+        global_asm!(
+            concat!(".global ", $name),
+            concat!($name, ":"),
+            "push rax",
+            "push rbx",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push rbp",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "mov rdi, rsp",
+            "mov rsi, {vector}",
+            "call {handler}",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+            "iretq",
+            vector = const $vector,
+            handler = sym handle_trap,
+        );
+    };
+}
+
+trap_entry!("gdb_debug_entry", DEBUG_VECTOR);
+trap_entry!("gdb_breakpoint_entry", BREAKPOINT_VECTOR);
+
+extern "C" {
+    fn gdb_debug_entry() -> !;
+    fn gdb_breakpoint_entry() -> !;
+}
+
+/// Handler to register in the IDT for the debug exception
+pub fn debug_entry() -> HandlerFunc {
+    unsafe { mem::transmute::<unsafe extern "C" fn() -> !, HandlerFunc>(gdb_debug_entry) }
+}
+
+/// Handler to register in the IDT for the breakpoint exception
+pub fn breakpoint_entry() -> HandlerFunc {
+    unsafe { mem::transmute::<unsafe extern "C" fn() -> !, HandlerFunc>(gdb_breakpoint_entry) }
+}
+
+/// Sets up COM2 and routes breakpoint and debug exceptions to the debugger
+pub fn init() {
+    serial().init();
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Stops and waits for commands of the debugger
+pub fn breakpoint() {
+    int3();
+}
+
+extern "C" fn handle_trap(frame: &mut TrapFrame, vector: u64) {
+    if !is_enabled() {
+        // the part pushed by the CPU follows the saved registers
+        let stack_frame = unsafe { &*((&frame.rip) as *const u64 as *const ExceptionStackFrame) };
+        match vector {
+            BREAKPOINT_VECTOR => debug!("Int3 triggered: {:?}", stack_frame),
+            _ => debug!("Debug handler {:?}", stack_frame),
+        }
+        return;
+    }
+
+    match vector {
+        // rip points behind the int3, step back if it replaced an instruction
+        BREAKPOINT_VECTOR => {
+            let address = VirtualAddress::new(frame.rip - 1);
+            if find_breakpoint(address).is_some() {
+                frame.rip -= 1;
+            }
+        }
+        _ => frame.rflags &= !TRAP_FLAG,
+    }
+
+    Stub::new().run(frame);
+}
+
+fn serial() -> SerialPort {
+    SerialPort::new(COM2_BASE)
+}
+
+fn find_breakpoint(address: VirtualAddress) -> Option<usize> {
+    BREAKPOINTS
+        .lock()
+        .iter()
+        .position(|b| b.is_some_and(|b| b.address == address))
+}
+
+/// Pointer to the byte at `address` in the physical memory mapping, which is
+/// always writable
+fn byte_ptr(address: VirtualAddress) -> Option<*mut u8> {
+    let physical = paging::translate(address)?;
+    Some(paging::physical_to_virtual(physical).as_mut_ptr())
+}
+
+fn read_byte(address: VirtualAddress) -> Option<u8> {
+    byte_ptr(address).map(|ptr| unsafe { ptr.read_volatile() })
+}
+
+fn write_byte(address: VirtualAddress, value: u8) -> Option<()> {
+    byte_ptr(address).map(|ptr| unsafe { ptr.write_volatile(value) })
+}
+
+fn insert_breakpoint(address: VirtualAddress) -> Option<()> {
+    if find_breakpoint(address).is_some() {
+        return Some(());
+    }
+
+    let mut breakpoints = BREAKPOINTS.lock();
+    let slot = breakpoints.iter_mut().find(|b| b.is_none())?;
+    let original = read_byte(address)?;
+    write_byte(address, INT3)?;
+    *slot = Some(Breakpoint { address, original });
+    Some(())
+}
+
+fn remove_breakpoint(address: VirtualAddress) -> Option<()> {
+    let index = find_breakpoint(address)?;
+    let breakpoint = BREAKPOINTS.lock()[index].take()?;
+    write_byte(breakpoint.address, breakpoint.original)
+}
+
+fn remove_all_breakpoints() {
+    let addresses = BREAKPOINTS.lock().map(|b| b.map(|b| b.address));
+    for address in addresses.into_iter().flatten() {
+        remove_breakpoint(address);
+    }
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[(value & 0xf) as usize]
+}
+
+fn parse_hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits
+        .iter()
+        .try_fold(0, |acc, d| Some(acc << 4 | parse_hex_digit(*d)? as u64))
+}
+
+fn parse_hex_byte(digits: &[u8]) -> Option<u8> {
+    Some(parse_hex_digit(digits[0])? << 4 | parse_hex_digit(digits[1])?)
+}
+
+/// Parses a little endian value of `size` bytes from hex digits
+fn parse_hex_le(digits: &[u8], size: usize) -> Option<u64> {
+    (0..size).try_fold(0, |acc, i| {
+        let byte = parse_hex_byte(digits.get(i * 2..i * 2 + 2)?)?;
+        Some(acc | (byte as u64) << (i * 8))
+    })
+}
+
+/// Splits `addr,length` into its parts
+fn parse_range(args: &[u8]) -> Option<(VirtualAddress, usize)> {
+    let mut parts = args.splitn(2, |c| *c == b',');
+    let address = parse_hex(parts.next()?)?;
+    let length = parse_hex(parts.next()?)?;
+    Some((VirtualAddress::new(address), length as usize))
+}
+
+/// Response packet, built in place since the heap might be locked by the
+/// interrupted code
+struct Response {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Response {
+    fn new() -> Self {
+        Self {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.data[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|b| self.push(b));
+    }
+
+    fn push_hex_byte(&mut self, byte: u8) {
+        self.push(hex_digit(byte >> 4));
+        self.push(hex_digit(byte));
+    }
+
+    /// Pushes the lowest `size` bytes of `value` in little endian order
+    fn push_hex_le(&mut self, value: u64, size: usize) {
+        for byte in &value.to_le_bytes()[..size] {
+            self.push_hex_byte(*byte);
+        }
+    }
+
+    fn ok() -> Self {
+        let mut response = Self::new();
+        response.push_str("OK");
+        response
+    }
+
+    fn error() -> Self {
+        let mut response = Self::new();
+        response.push_str("E01");
+        response
+    }
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+struct Stub {
+    port: SerialPort,
+    packet: [u8; PACKET_SIZE],
+}
+
+impl Stub {
+    fn new() -> Self {
+        Self {
+            port: serial(),
+            packet: [0; PACKET_SIZE],
+        }
+    }
+
+    /// Serves requests until the debugger resumes execution
+    fn run(&mut self, frame: &mut TrapFrame) {
+        self.send(&Self::stop_reply());
+
+        loop {
+            let len = self.receive();
+            let (response, resume) = Self::handle(&self.packet[..len], frame);
+            if let Some(response) = response {
+                self.send(&response);
+            }
+
+            match resume {
+                Some(Resume::Continue) => {
+                    frame.rflags &= !TRAP_FLAG;
+                    return;
+                }
+                Some(Resume::Step) => {
+                    frame.rflags |= TRAP_FLAG;
+                    return;
+                }
+                None => (),
+            }
+        }
+    }
+
+    fn stop_reply() -> Response {
+        let mut response = Response::new();
+        response.push(b'S');
+        response.push_hex_byte(SIGTRAP);
+        response
+    }
+
+    /// Handles a single packet. Returns the response to send, if any, and
+    /// whether to resume execution.
+    fn handle(packet: &[u8], frame: &mut TrapFrame) -> (Option<Response>, Option<Resume>) {
+        let Some((&command, args)) = packet.split_first() else {
+            return (Some(Response::new()), None);
+        };
+
+        let response = match command {
+            b'?' => Self::stop_reply(),
+            b'g' => {
+                let mut response = Response::new();
+                for index in 0..REGISTER_COUNT {
+                    response.push_hex_le(frame.register(index), register_size(index));
+                }
+                response
+            }
+            b'G' => {
+                let mut offset = 0;
+                for index in 0..REGISTER_COUNT {
+                    let size = register_size(index);
+                    match parse_hex_le(&args[offset.min(args.len())..], size) {
+                        Some(value) => frame.set_register(index, value),
+                        None => break,
+                    }
+                    offset += size * 2;
+                }
+                Response::ok()
+            }
+            b'P' => {
+                let mut parts = args.splitn(2, |c| *c == b'=');
+                let index = parts.next().and_then(parse_hex).map(|i| i as usize);
+                match (index, parts.next()) {
+                    (Some(index), Some(value)) if index < REGISTER_COUNT => {
+                        match parse_hex_le(value, register_size(index)) {
+                            Some(value) => {
+                                frame.set_register(index, value);
+                                Response::ok()
+                            }
+                            None => Response::error(),
+                        }
+                    }
+                    _ => Response::error(),
+                }
+            }
+            b'm' => match parse_range(args) {
+                Some((address, length)) => {
+                    let mut response = Response::new();
+                    for i in 0..length.min(PACKET_SIZE / 2) {
+                        match read_byte(address + i) {
+                            Some(byte) => response.push_hex_byte(byte),
+                            None if i == 0 => return (Some(Response::error()), None),
+                            None => break,
+                        }
+                    }
+                    response
+                }
+                None => Response::error(),
+            },
+            b'M' => {
+                let mut parts = args.splitn(2, |c| *c == b':');
+                match (parts.next().and_then(parse_range), parts.next()) {
+                    (Some((address, length)), Some(data)) if data.len() >= length * 2 => {
+                        let written = (0..length).try_for_each(|i| {
+                            let byte = parse_hex_byte(&data[i * 2..i * 2 + 2])?;
+                            write_byte(address + i, byte)
+                        });
+                        match written {
+                            Some(()) => Response::ok(),
+                            None => Response::error(),
+                        }
+                    }
+                    _ => Response::error(),
+                }
+            }
+            b'c' | b's' => {
+                if let Some(address) = parse_hex(args) {
+                    frame.rip = address;
+                }
+                let resume = match command {
+                    b'c' => Resume::Continue,
+                    _ => Resume::Step,
+                };
+                return (None, Some(resume));
+            }
+            b'Z' | b'z' => {
+                // only software breakpoints: Z0,addr,kind
+                let mut parts = args.splitn(3, |c| *c == b',');
+                let kind = parts.next();
+                let address = parts.next().and_then(parse_hex).map(VirtualAddress::new);
+                match (kind, address) {
+                    (Some(b"0"), Some(address)) => {
+                        let result = match command {
+                            b'Z' => insert_breakpoint(address),
+                            _ => remove_breakpoint(address),
+                        };
+                        match result {
+                            Some(()) => Response::ok(),
+                            None => Response::error(),
+                        }
+                    }
+                    _ => Response::new(),
+                }
+            }
+            b'D' => {
+                remove_all_breakpoints();
+                return (Some(Response::ok()), Some(Resume::Continue));
+            }
+            // the kernel can't be killed, just let it run
+            b'k' => {
+                remove_all_breakpoints();
+                return (None, Some(Resume::Continue));
+            }
+            b'q' if args.starts_with(b"Supported") => {
+                const_assert!(PACKET_SIZE == 0x400);
+                let mut response = Response::new();
+                response.push_str("PacketSize=400");
+                response
+            }
+            b'q' if args == b"Attached" => {
+                let mut response = Response::new();
+                response.push(b'1');
+                response
+            }
+            // unsupported, answered with an empty packet
+            _ => Response::new(),
+        };
+
+        (Some(response), None)
+    }
+
+    /// Receives a packet into the packet buffer and returns its length
+    fn receive(&mut self) -> usize {
+        loop {
+            while self.port.recv() != b'$' {}
+
+            let mut len = 0;
+            let mut checksum: u8 = 0;
+            let mut overflow = false;
+            loop {
+                let byte = self.port.recv();
+                if byte == b'#' {
+                    break;
+                }
+                checksum = checksum.wrapping_add(byte);
+                if len < PACKET_SIZE {
+                    self.packet[len] = byte;
+                    len += 1;
+                } else {
+                    overflow = true;
+                }
+            }
+
+            let expected = parse_hex_byte(&[self.port.recv(), self.port.recv()]);
+            if !overflow && expected == Some(checksum) {
+                self.port.send(b'+');
+                return len;
+            }
+            self.port.send(b'-');
+        }
+    }
+
+    /// Sends `response` until the debugger acknowledges it
+    fn send(&self, response: &Response) {
+        let data = &response.data[..response.len];
+        let checksum = data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+
+        loop {
+            self.port.send(b'$');
+            data.iter().for_each(|b| self.port.send(*b));
+            self.port.send(b'#');
+            self.port.send(hex_digit(checksum >> 4));
+            self.port.send(hex_digit(checksum));
+
+            if self.port.recv() == b'+' {
+                return;
+            }
+        }
+    }
+}
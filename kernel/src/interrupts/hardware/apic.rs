@@ -0,0 +1,222 @@
+//! This module implements a driver for the local APIC of the current CPU.
+//!
+//! Only the timer and the performance counter interrupt of the local APIC are
+//! used. External interrupts still arrive through the 8259 PIC, which the BIOS
+//! connects to LINT0 of the local APIC (virtual wire mode).
+//!
+//! The timer counts down from an initial count at a rate derived from the bus
+//! clock and fires an interrupt when it reaches zero. Its frequency is not
+//! reported anywhere and has to be measured. CPUs supporting TSC-deadline mode
+//! can instead fire the interrupt once the time stamp counter reaches a
+//! deadline, which doesn't require calibration beyond the TSC frequency.
+//!
+//! https://wiki.osdev.org/APIC_Timer
+use crate::{
+    memory::Mmio,
+    time::{self, ClockEvent},
+};
+use core::{
+    arch::asm,
+    hint::spin_loop,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::{
+    instructions::rdtsc,
+    memory::{Address, VirtualAddress},
+    register::{ApicBase, TscDeadline},
+};
+
+const ID: u64 = 0x20;
+const END_OF_INTERRUPT: u64 = 0xb0;
+const SPURIOUS_INTERRUPT_VECTOR: u64 = 0xf0;
+const LVT_TIMER: u64 = 0x320;
+const LVT_PERFORMANCE_COUNTER: u64 = 0x340;
+const TIMER_INITIAL_COUNT: u64 = 0x380;
+const TIMER_CURRENT_COUNT: u64 = 0x390;
+const TIMER_DIVIDE_CONFIGURATION: u64 = 0x3e0;
+
+/// Size of the register block
+pub const REGISTERS_SIZE: u64 = 0x400;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const MASKED: u32 = 1 << 16;
+const DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+/// Divides the timer clock by 16
+const DIVIDE_BY_16: u32 = 0b0011;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+/// Time the timer is measured against the clock source
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TimerMode {
+    OneShot = 0,
+    TscDeadline = 2 << 17,
+}
+
+/// Virtual address of the registers, 0 until the local APIC is enabled
+static REGISTERS: AtomicU64 = AtomicU64::new(0);
+
+/// Physical address of the registers
+pub fn physical_address() -> u64 {
+    ApicBase::read() & ApicBase::ADDRESS_MASK
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    registers: Mmio,
+}
+
+impl LocalApic {
+    /// Enables the local APIC with its registers mapped at `registers`
+    ///
+    /// # Safety
+    ///
+    /// `registers` has to map the registers of the local APIC of this CPU
+    pub unsafe fn enable(registers: Mmio, spurious_vector: u8) -> Self {
+        ApicBase::write(ApicBase::read() | ApicBase::ENABLE);
+
+        let apic = Self { registers };
+        apic.write(
+            SPURIOUS_INTERRUPT_VECTOR,
+            SOFTWARE_ENABLE | spurious_vector as u32,
+        );
+        apic.write(LVT_TIMER, MASKED);
+        REGISTERS.store(registers.address().as_u64(), Ordering::SeqCst);
+        apic
+    }
+
+    /// The local APIC, None if it isn't enabled yet
+    pub fn get() -> Option<Self> {
+        match REGISTERS.load(Ordering::Relaxed) {
+            0 => None,
+            address => Some(Self {
+                registers: unsafe { Mmio::from_raw(VirtualAddress::new(address), REGISTERS_SIZE) },
+            }),
+        }
+    }
+
+    fn read(&self, register: u64) -> u32 {
+        self.registers.read(register)
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        self.registers.write(register, value)
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(ID) >> 24) as u8
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(END_OF_INTERRUPT, 0);
+    }
+
+    /// Routes the timer to `vector` and stops it
+    pub fn set_timer_mode(&self, vector: u8, mode: TimerMode) {
+        self.write(TIMER_DIVIDE_CONFIGURATION, DIVIDE_BY_16);
+        self.write(LVT_TIMER, mode as u32 | vector as u32);
+        if mode == TimerMode::TscDeadline {
+            // the LVT write has to be visible before the deadline is armed
+            unsafe { asm!("mfence", options(nostack, preserves_flags)) };
+        }
+    }
+
+    /// Starts counting down from `count`, 0 stops the timer
+    pub fn set_timer_count(&self, count: u32) {
+        self.write(TIMER_INITIAL_COUNT, count);
+    }
+
+    pub fn timer_count(&self) -> u32 {
+        self.read(TIMER_CURRENT_COUNT)
+    }
+
+    /// Delivers performance counter overflows as NMI. The entry is masked
+    /// after every delivery, so this has to be called again to get the next.
+    pub fn set_performance_counter_nmi(&self) {
+        self.write(LVT_PERFORMANCE_COUNTER, DELIVERY_MODE_NMI);
+    }
+}
+
+/// The local APIC timer in one-shot mode
+pub struct OneShotTimer {
+    apic: LocalApic,
+    /// Timer decrements per second
+    frequency: u64,
+}
+
+impl OneShotTimer {
+    /// Measures the timer frequency against the clock source
+    pub fn new(apic: LocalApic, vector: u8) -> Self {
+        apic.set_timer_mode(vector, TimerMode::OneShot);
+        // mask while calibrating so the interrupt doesn't fire
+        apic.write(LVT_TIMER, apic.read(LVT_TIMER) | MASKED);
+
+        let start = time::uptime();
+        apic.set_timer_count(u32::MAX);
+        while time::uptime() - start < CALIBRATION_TIME {
+            spin_loop();
+        }
+        let elapsed = time::uptime() - start;
+        let counted = u32::MAX - apic.timer_count();
+        apic.set_timer_count(0);
+        apic.set_timer_mode(vector, TimerMode::OneShot);
+
+        let frequency = counted as u128 * NANOS_PER_SECOND / elapsed.as_nanos();
+        Self {
+            apic,
+            frequency: frequency as u64,
+        }
+    }
+
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+impl ClockEvent for OneShotTimer {
+    fn name(&self) -> &'static str {
+        "apic"
+    }
+
+    fn program(&self, delay: Duration) {
+        // round up, firing early would only cause another event
+        let count = (delay.as_nanos() * self.frequency as u128).div_ceil(NANOS_PER_SECOND);
+        self.apic
+            .set_timer_count(count.clamp(1, u32::MAX as u128) as u32);
+    }
+
+    fn stop(&self) {
+        self.apic.set_timer_count(0);
+    }
+}
+
+/// The local APIC timer in TSC-deadline mode
+pub struct DeadlineTimer {
+    /// TSC increments per second
+    tsc_frequency: u64,
+}
+
+impl DeadlineTimer {
+    pub fn new(apic: LocalApic, vector: u8, tsc_frequency: u64) -> Self {
+        apic.set_timer_mode(vector, TimerMode::TscDeadline);
+        Self { tsc_frequency }
+    }
+}
+
+impl ClockEvent for DeadlineTimer {
+    fn name(&self) -> &'static str {
+        "tsc-deadline"
+    }
+
+    fn program(&self, delay: Duration) {
+        let cycles = (delay.as_nanos() * self.tsc_frequency as u128).div_ceil(NANOS_PER_SECOND);
+        TscDeadline::write(rdtsc() + (cycles as u64).max(1));
+    }
+
+    fn stop(&self) {
+        TscDeadline::write(0);
+    }
+}
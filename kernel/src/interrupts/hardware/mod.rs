@@ -1 +1,3 @@
+pub mod apic;
 pub mod pic8259;
+pub mod pit;
@@ -144,6 +144,22 @@ impl ChainedPics {
         self.slave.write_data(slave_mask);
     }
 
+    /// Masks or unmasks the interrupt line `irq` (0-15)
+    pub fn set_masked(&self, irq: u8, masked: bool) {
+        let (pic, line) = if irq < 8 {
+            (&self.master, irq)
+        } else {
+            (&self.slave, irq - 8)
+        };
+
+        let mask = pic.read_data();
+        if masked {
+            pic.write_data(mask | (1 << line));
+        } else {
+            pic.write_data(mask & !(1 << line));
+        }
+    }
+
     // Signal to PIC that we are done and ready to receive next interrupt.
     // Else PIC won't signal another interrupt
     pub fn notify_end_of_interrupt(&self, irq_number: u8) {
@@ -0,0 +1,59 @@
+//! This module implements a driver for the 8253/8254 programmable interval timer (PIT)
+//!
+//! The PIT consists of an oscillator running at roughly 1.193182 MHz and three
+//! frequency dividers (channels). Channel 0 is connected to IRQ0 of the PIC and
+//! is used as the system tick.
+//!
+//! By default the BIOS programs channel 0 with the maximum divisor which results
+//! in an interrupt frequency of ~18.2 Hz. This is way too coarse for
+//! scheduling, therefore the divisor is reprogrammed during initialization.
+//!
+//! https://wiki.osdev.org/Programmable_Interval_Timer
+use x86_64::port::Port;
+
+/// Frequency of the PIT oscillator in Hz
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL0_DATA_PORT: u16 = 0x40;
+const MODE_COMMAND_PORT: u16 = 0x43;
+
+#[repr(u8)]
+enum Command {
+    /// Channel 0, access mode lobyte/hibyte, mode 3 (square wave generator)
+    Channel0SquareWave = 0x36,
+}
+
+pub struct ProgrammableIntervalTimer {
+    channel0: Port<u8>,
+    command: Port<u8>,
+    frequency: u32,
+}
+
+impl ProgrammableIntervalTimer {
+    pub const fn new() -> Self {
+        Self {
+            channel0: Port::new(CHANNEL0_DATA_PORT),
+            command: Port::new(MODE_COMMAND_PORT),
+            // divisor of 0 is interpreted as 65536
+            frequency: BASE_FREQUENCY / 65536,
+        }
+    }
+
+    /// Programs channel 0 to fire IRQ0 `frequency` times per second.
+    ///
+    /// The frequency is clamped to what the 16 bit divisor can represent.
+    pub fn set_frequency(&mut self, frequency: u32) {
+        let divisor = (BASE_FREQUENCY / frequency.max(1)).clamp(1, u16::MAX as u32) as u16;
+
+        self.command.write(Command::Channel0SquareWave as u8);
+        self.channel0.write(divisor as u8);
+        self.channel0.write((divisor >> 8) as u8);
+
+        self.frequency = BASE_FREQUENCY / divisor as u32;
+    }
+
+    /// Frequency IRQ0 is currently fired at
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
+}
@@ -1,98 +1,168 @@
+extern crate alloc;
+use crate::{
+    cmdline, error,
+    executor::{self, Event},
+    gdb, info, log,
+    memory::{self, MemoryError, PageFaultResolution},
+    paging,
+    process::{self, signal::Signal},
+    profiler, random,
+    scheduler::{self, fpu::DEFAULT_MXCSR},
+    sync::WaitQueue,
+    syscall,
+    test::{self, Exception},
+    time::{self, tsc, ClockEvent, ClockEventError},
+    trace, trace_event,
+};
+use alloc::boxed::Box;
 use bitflags::bitflags;
 use core::{
-    arch::asm,
     fmt::{self, Debug},
+    mem::size_of,
+    ptr, slice,
+    sync::atomic::{AtomicU64, Ordering},
 };
+use kernel_macros::interrupt_handler;
 use lazy_static::lazy_static;
+use lockfree::MpmcQueue;
+use softirq::SoftIrq;
 use x86_64::{
+    cpuid::{self, Features},
     gdt::{GlobalDescriptorTable, SegmentDescriptor, SegmentSelector},
-    handler_with_error_code, handler_without_error_code,
-    idt::InterruptDescriptorTable,
-    instructions::int3,
-    interrupts::{self, ExceptionStackFrame, PageFaultErrorCode},
-    memory::{Address, PageSize, Size4KiB, VirtualAddress},
-    mutex::Mutex,
-    pop_scratch_registers,
+    idt::{self, vectors, HandlerFunc, InterruptDescriptorTable},
+    instructions::{fnclex, fnstsw, hlt, ldmxcsr, rdtsc, stmxcsr},
+    interrupts::{self, ExceptionStackFrame, PageFaultErrorCode, SelectorErrorCode},
+    memory::{PageSize, PhysicalAddress, Size4KiB, VirtualAddress},
+    mutex::{InterruptSafeMutex, Mutex},
+    paging::CacheAttribute,
     port::Port,
-    print, println, push_scratch_registers,
-    register::{CS, DS, ES, SS},
-    tss::{TaskStateSegment, DOUBLE_FAULT_IST_IDX},
+    print::SERIAL,
+    register::{Cr2, Cr3, MachineCheck, CS, DS, ES, SS},
+    tss::{TaskStateSegment, DOUBLE_FAULT_IST_IDX, MACHINE_CHECK_IST_IDX},
+    uart::SerialPort,
+    PrivilegeLevel,
 };
 
 mod hardware;
-use hardware::pic8259::ChainedPics;
+pub mod softirq;
+pub mod watchdog;
+use hardware::{
+    apic::{self, DeadlineTimer, LocalApic, OneShotTimer},
+    pic8259::ChainedPics,
+    pit::ProgrammableIntervalTimer,
+};
 pub const MASTER_PIC_OFFSET: u8 = 0x20;
 pub const SLAVE_PIC_OFFSET: u8 = MASTER_PIC_OFFSET + 8;
-static PICS: Mutex<ChainedPics> = Mutex::new(ChainedPics::new());
+static PICS: InterruptSafeMutex<ChainedPics> = InterruptSafeMutex::new(ChainedPics::new());
+
+/// Frequency of the timer interrupt in Hz. Every timer interrupt is a
+/// scheduler tick until the local APIC timer takes over.
+pub const TIMER_FREQUENCY: u32 = 100;
+static PIT: Mutex<ProgrammableIntervalTimer> = Mutex::new(ProgrammableIntervalTimer::new());
+
+pub const APIC_TIMER_VECTOR: u8 = 0x40;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Scancodes received from the keyboard which have not been read yet. The
+/// queues are lock-free so the interrupt handlers never wait for a reader.
+static SCANCODES: MpmcQueue<u8, 128> = MpmcQueue::new();
+static KEYBOARD_WAITERS: WaitQueue = WaitQueue::named("keyboard");
+
+/// Bytes received on COM1 which have not been read yet
+const COM1_BASE: u16 = 0x3F8;
+static SERIAL_INPUT: MpmcQueue<u8, 256> = MpmcQueue::new();
+static SERIAL_WAITERS: WaitQueue = WaitQueue::named("serial");
+
+/// Lines of the PIC the BIOS routes PCI interrupts to
+pub const PCI_IRQS: [u8; 3] = [9, 10, 11];
+/// Line the slave PIC is cascaded to
+const CASCADE_IRQ: u8 = 2;
+type PciHandlers = [Option<fn()>; PCI_IRQS.len()];
+// locked with interrupts disabled
+static PCI_HANDLERS: Mutex<PciHandlers> = Mutex::new([None; PCI_IRQS.len()]);
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = 0,
     Keyboard,
+    Serial1 = 4,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    const fn as_u8(self) -> u8 {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
-
-    fn as_remapped_idt_number(self) -> u8 {
-        self.as_u8() + MASTER_PIC_OFFSET as u8
+    const fn as_remapped_idt_number(self) -> u8 {
+        self.as_u8() + MASTER_PIC_OFFSET
     }
 }
 
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::default();
-
-        unsafe {
-            idt.divide_error
-                .set_handler_function(handler_without_error_code!(divide_by_zero_handler));
-
-            idt.debug
-                .set_handler_function(handler_without_error_code!(debug_handler));
-
-            idt.non_maskable_interrupt
-                .set_handler_function(handler_without_error_code!(non_maskable_interrupt));
-
-            idt.breakpoint
-                .set_handler_function(handler_without_error_code!(breakpoint_handler));
-
-            idt.invalid_opcode
-                .set_handler_function(handler_without_error_code!(invalid_opcode_handler));
-
-            idt.device_not_available
-                .set_handler_function(handler_without_error_code!(device_not_available_handler));
-
-            idt.invalid_tss
-                .set_handler_function(handler_with_error_code!(invalid_tss_handler));
+/// Entry of the IDT, placed into the `interrupt_handlers` section by
+/// [`interrupt_handler`]
+#[derive(Debug)]
+#[repr(C)]
+pub struct InterruptHandler {
+    /// Name of the handler function
+    pub name: &'static str,
+    pub vector: u8,
+    pub entry: HandlerFunc,
+    /// Interrupt stack of the TSS to switch to
+    pub stack: Option<u16>,
+}
 
-            idt.segment_not_present
-                .set_handler_function(handler_with_error_code!(segment_not_present_handler));
+extern "C" {
+    static __start_interrupt_handlers: *const u8;
+    static __stop_interrupt_handlers: *const u8;
+}
 
-            idt.stack_segment_fault
-                .set_handler_function(handler_with_error_code!(stack_segment_fault_handler));
+/// Interrupts handled per vector
+static INTERRUPT_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
 
-            idt.page_fault
-                .set_handler_function(handler_with_error_code!(page_fault_handler));
+/// Called by every handler registered with [`interrupt_handler`]
+#[doc(hidden)]
+pub fn count_interrupt(vector: u8) {
+    INTERRUPT_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
 
-            idt.alignment_check
-                .set_handler_function(handler_with_error_code!(alignment_check_handler));
+/// Amount of interrupts handled on `vector` since boot. Only counts vectors
+/// with a handler registered by [`interrupt_handler`].
+pub fn interrupt_count(vector: u8) -> u64 {
+    INTERRUPT_COUNTS[vector as usize].load(Ordering::Relaxed)
+}
 
-            idt.double_fault
-                .set_handler_function(handler_with_error_code!(double_fault_handler))
-                .set_interrupt_stack_index(DOUBLE_FAULT_IST_IDX as u16);
+/// All handlers registered with [`interrupt_handler`]
+pub fn handlers() -> &'static [InterruptHandler] {
+    unsafe {
+        let start = &__start_interrupt_handlers as *const _ as *const InterruptHandler;
+        let end = &__stop_interrupt_handlers as *const _ as *const InterruptHandler;
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
 
-            idt.interrupts[InterruptIndex::Timer.as_usize()]
-                .set_handler_function(handler_without_error_code!(timer_interrupt_handler));
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::default();
 
-            idt.interrupts[InterruptIndex::Keyboard.as_usize()]
-                .set_handler_function(handler_without_error_code!(keyboard_interrupt_handler));
+        unsafe {
+            for handler in handlers() {
+                let options = idt
+                    .entry_mut(handler.vector)
+                    .set_handler_function(handler.entry);
+                if let Some(stack) = handler.stack {
+                    options.set_interrupt_stack_index(stack);
+                }
+            }
+
+            // forwarded to the debugger if the gdb stub is enabled
+            idt.debug.set_handler_function(gdb::debug_entry());
+            idt.breakpoint.set_handler_function(gdb::breakpoint_entry());
+
+            // callable from user mode
+            idt.entry_mut(syscall::SYSCALL_VECTOR)
+                .set_handler_function(syscall::entry())
+                .set_privilege_level(PrivilegeLevel::Ring3);
         }
 
         idt
@@ -106,11 +176,17 @@ lazy_static! {
             const STACK_SIZE: usize = Size4KiB::SIZE as usize * 5;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
-            let stack_start = VirtualAddress::from_ptr(unsafe { &STACK });
+            let stack_start = VirtualAddress::from_ptr(unsafe { &*ptr::addr_of!(STACK) });
             let stack_end = stack_start + STACK_SIZE;
 
             stack_end
         };
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_IDX] = {
+            const STACK_SIZE: usize = Size4KiB::SIZE as usize * 2;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            VirtualAddress::from_ptr(unsafe { &*ptr::addr_of!(STACK) }) + STACK_SIZE
+        };
 
         tss
     };
@@ -156,73 +232,481 @@ pub fn init() {
 
     // initialize & remap pic
     PICS.lock().init(MASTER_PIC_OFFSET, SLAVE_PIC_OFFSET);
+    let mut pit = PIT.lock();
+    pit.set_frequency(TIMER_FREQUENCY);
+    time::set_tick_frequency(pit.frequency());
+    drop(pit);
     //PIC.lock().remap_pic();
     unsafe { interrupts::enable() };
 }
 
+/// Starts delivering bytes received on COM1 to [`read_serial`]. Requires the
+/// heap since received bytes are buffered.
+pub fn init_serial_input() {
+    // make sure the port is initialized before the first interrupt arrives
+    drop(SERIAL.lock());
+    PICS.lock()
+        .set_masked(InterruptIndex::Serial1.as_u8(), false);
+}
+
+#[derive(Debug)]
+pub enum LocalApicError {
+    NotSupported,
+    ClockEvent(ClockEventError),
+    Memory(MemoryError),
+}
+
+impl From<ClockEventError> for LocalApicError {
+    fn from(error: ClockEventError) -> Self {
+        LocalApicError::ClockEvent(error)
+    }
+}
+
+impl From<MemoryError> for LocalApicError {
+    fn from(error: MemoryError) -> Self {
+        LocalApicError::Memory(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciIrqError {
+    /// The line isn't one of [`PCI_IRQS`]
+    UnsupportedLine(u8),
+    AlreadyRegistered,
+}
+
+/// Calls `handler` when the PCI interrupt `line` fires and unmasks the line.
+/// The handler runs in interrupt context and has to acknowledge the interrupt
+/// at the device before it returns, the line is level triggered.
+pub fn register_pci_irq(line: u8, handler: fn()) -> Result<(), PciIrqError> {
+    let index = PCI_IRQS
+        .iter()
+        .position(|l| *l == line)
+        .ok_or(PciIrqError::UnsupportedLine(line))?;
+    interrupts::without_interrupts(|| {
+        let mut handlers = PCI_HANDLERS.lock();
+        if handlers[index].is_some() {
+            return Err(PciIrqError::AlreadyRegistered);
+        }
+        handlers[index] = Some(handler);
+        drop(handlers);
+
+        let pics = PICS.lock();
+        pics.set_masked(CASCADE_IRQ, false);
+        pics.set_masked(line, false);
+        Ok(())
+    })
+}
+
+/// Replaces the periodic PIT interrupt with the one-shot local APIC timer,
+/// using TSC-deadline mode if available. Requires the scheduler and a clock
+/// source that doesn't depend on the PIT.
+pub fn init_local_apic() -> Result<(), LocalApicError> {
+    if !cpuid::has(Features::APIC) {
+        return Err(LocalApicError::NotSupported);
+    }
+    if time::clock_source().needs_tick() {
+        return Err(ClockEventError::ClockSourceNeedsTick.into());
+    }
+
+    let registers = memory::ioremap(
+        PhysicalAddress::new(apic::physical_address()),
+        apic::REGISTERS_SIZE,
+        CacheAttribute::Uncached,
+    )?;
+    let apic = unsafe { LocalApic::enable(registers, SPURIOUS_VECTOR) };
+    info!("Enabled local APIC {}", apic.id());
+
+    let event: &'static dyn ClockEvent = match tsc::frequency() {
+        Some(frequency) if tsc::supports_deadline() => Box::leak(Box::new(DeadlineTimer::new(
+            apic,
+            APIC_TIMER_VECTOR,
+            frequency,
+        ))),
+        _ => {
+            let timer = OneShotTimer::new(apic, APIC_TIMER_VECTOR);
+            info!("Local APIC timer runs at {} Hz", timer.frequency());
+            Box::leak(Box::new(timer))
+        }
+    };
+    time::set_clock_event(event)?;
+
+    interrupts::without_interrupts(|| {
+        PICS.lock().set_masked(InterruptIndex::Timer.as_u8(), true);
+        scheduler::start_clock_events();
+    });
+
+    info!("Using {} timer for clock events", event.name());
+    Ok(())
+}
+
+/// Frequency the timer interrupt actually fires at. Might differ slightly from
+/// [`TIMER_FREQUENCY`] due to the PIT divisor granularity.
+pub fn timer_frequency() -> u32 {
+    PIT.lock().frequency()
+}
+
+/// Returns the next scancode received from the keyboard. Blocks the current
+/// thread until a key is pressed.
+pub fn read_scancode() -> u8 {
+    let mut scancode = None;
+    KEYBOARD_WAITERS.wait_until(|| {
+        scancode = SCANCODES.pop();
+        scancode.is_some()
+    });
+    scancode.unwrap()
+}
+
+/// Returns the next scancode received from the keyboard if there is one
+pub fn try_read_scancode() -> Option<u8> {
+    SCANCODES.pop()
+}
+
+/// Returns the next byte received on COM1. Blocks the current thread until a
+/// byte arrives.
+pub fn read_serial() -> u8 {
+    let mut byte = None;
+    SERIAL_WAITERS.wait_until(|| {
+        byte = SERIAL_INPUT.pop();
+        byte.is_some()
+    });
+    byte.unwrap()
+}
+
+/// Returns a byte received on COM1 if there is one, without blocking
+pub fn try_read_serial() -> Option<u8> {
+    SERIAL_INPUT.pop()
+}
+
+/// Appends `value` to `queue`, the oldest value is dropped if it is full
+fn push_overwriting<const N: usize>(queue: &MpmcQueue<u8, N>, mut value: u8) {
+    while let Err(v) = queue.push(value) {
+        queue.pop();
+        value = v;
+    }
+}
+
 // C calling convention
+#[interrupt_handler(vector = vectors::DIVIDE_ERROR)]
 extern "C" fn divide_by_zero_handler(frame: &ExceptionStackFrame) -> ! {
-    println!("Exception: divide by zero");
-    loop {}
+    error!("Exception: divide by zero");
+    test::exception(Exception::DivideByZero);
+    terminate_faulting_thread(frame, Signal::SIGFPE)
 }
 
+#[interrupt_handler(vector = vectors::INVALID_OPCODE)]
 extern "C" fn invalid_opcode_handler(frame: &ExceptionStackFrame) -> ! {
-    println!("Invalid opcode handler");
-    loop {}
+    error!("Invalid opcode handler");
+    test::exception(Exception::InvalidOpcode);
+    terminate_faulting_thread(frame, Signal::SIGILL)
 }
 
+#[interrupt_handler(vector = vectors::GENERAL_PROTECTION_FAULT, error_code)]
 extern "C" fn general_protection_fault_handler(frame: &ExceptionStackFrame, error_code: u64) -> ! {
-    println!("General protection fault");
-    loop {}
+    error!(
+        "General protection fault, error code: {:?}, exception frame: {:?}",
+        SelectorErrorCode::new(error_code),
+        frame
+    );
+    test::exception(Exception::GeneralProtection);
+    terminate_faulting_thread(frame, Signal::SIGSEGV)
 }
 
+#[interrupt_handler(vector = vectors::SEGMENT_NOT_PRESENT, error_code)]
 extern "C" fn segment_not_present_handler(frame: &ExceptionStackFrame, error_code: u64) -> ! {
-    println!(
-        "General protection fault handler \n error_code: {:?} \n exception frame: {:?}",
-        error_code, frame
+    error!(
+        "Segment not present, error code: {:?}, exception frame: {:?}",
+        SelectorErrorCode::new(error_code),
+        frame
     );
-    loop {}
+    test::exception(Exception::SegmentNotPresent);
+    terminate_faulting_thread(frame, Signal::SIGSEGV)
 }
 
+#[interrupt_handler(vector = vectors::PAGE_FAULT, error_code)]
 extern "C" fn page_fault_handler(frame: &ExceptionStackFrame, error_code: u64) {
     let error = PageFaultErrorCode::from_bits(error_code).unwrap();
-    println!(
-        "Page fault handler \n error_code: {:?} \n exception frame: {:?}",
-        error, frame
+    let address = Cr2::read();
+    trace_event!(
+        mm,
+        "page fault at {:#x}, error code {:#x}, rip {:#x}",
+        address,
+        error_code,
+        frame.instruction_pointer()
+    );
+
+    match memory::handle_page_fault(address, &error) {
+        // demand fault on a lazily allocated region or stack growth
+        PageFaultResolution::Resolved => {
+            trace_event!(mm, "page fault at {:#x} resolved", address);
+            return;
+        }
+        PageFaultResolution::StackOverflow => {
+            error!("Stack overflow: guard page hit at {:#x}", address);
+            error!("Exception frame: {:?}", frame);
+            test::exception(Exception::StackOverflow);
+            terminate_faulting_thread(frame, Signal::SIGSEGV)
+        }
+        PageFaultResolution::Unhandled => (),
+    }
+
+    #[cfg(feature = "unmap-on-free")]
+    if let Some(freed) = memory::freed::lookup(address) {
+        error!("Use after free at {:#x}: {}", address, freed);
+    }
+
+    error!(
+        "Page fault at {:#x}, error code: {:?}, exception frame: {:?}",
+        address, error, frame
+    );
+    report_page_fault(address, frame);
+    test::exception(Exception::PageFault);
+    terminate_faulting_thread(frame, Signal::SIGSEGV)
+}
+
+/// Ends an exception the faulting thread can't continue from. Only the
+/// thread is terminated, unless the fault happened with interrupts disabled,
+/// e.g. in an interrupt handler or while holding the scheduler lock, or in
+/// the idle thread.
+fn terminate_faulting_thread(frame: &ExceptionStackFrame, signal: Signal) -> ! {
+    // locks taken with interrupts disabled are free, so the scheduler can be
+    // asked for the idle thread
+    if !frame.interrupts_enabled() {
+        error!("Fault with interrupts disabled, halting");
+        halt()
+    }
+    let thread = scheduler::current();
+    if scheduler::idle().is_none_or(|idle| idle == thread) {
+        error!("Fault in the idle thread or before scheduling, halting");
+        halt()
+    }
+
+    error!("Terminating thread {:?} with {}", thread, signal);
+    process::terminate_current(signal)
+}
+
+/// Stops the machine after an exception nothing can recover from
+fn halt() -> ! {
+    unsafe { interrupts::disable() };
+    loop {
+        hlt();
+    }
+}
+
+/// Bytes dumped at the faulting instruction, the longest x86 instruction
+const INSTRUCTION_BYTES: usize = 15;
+
+/// Prints the mapping state of `address` and the code at the faulting
+/// instruction. Doesn't spin on any lock, the faulting code might hold it.
+fn report_page_fault(address: VirtualAddress, frame: &ExceptionStackFrame) {
+    match paging::translate_in(unsafe { &paging::kernel_page_table() }, address) {
+        Some((physical, flags)) => error!(
+            "{:#x} is mapped to {:#x} with {:?}",
+            address, physical, flags
+        ),
+        None => error!("{:#x} is not mapped", address),
+    }
+
+    let region = memory::try_with_memory_manager(|mm| mm.region(address).cloned());
+    match region {
+        Some(Some(region)) => {
+            let owner = process::try_region_owner(region.start());
+            error!(
+                "In region {:#x} - {:#x} {:?} {:?} of process {:?}, thread {:?}",
+                region.start(),
+                region.end(),
+                region.object(),
+                region.flags(),
+                owner,
+                scheduler::current()
+            );
+        }
+        Some(None) => error!("Not part of any region"),
+        None => error!("Memory manager locked, region unknown"),
+    }
+
+    let rip = frame.instruction_pointer();
+    let last = rip + (INSTRUCTION_BYTES - 1);
+    if paging::translate(rip).is_none() || paging::translate(last).is_none() {
+        error!("Instruction at {:#x} is not mapped", rip);
+        return;
+    }
+    let mut bytes = [0u8; INSTRUCTION_BYTES];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = unsafe { ptr::read_volatile((rip + i).as_ptr::<u8>()) };
+    }
+    error!(
+        "Instruction at {:#x} ({:?}): {:02x?}",
+        rip,
+        frame.privilege_level(),
+        bytes
     );
-    // TODO: handle
-    loop {}
 }
 
+#[interrupt_handler(vector = vectors::ALIGNMENT_CHECK, error_code)]
 extern "C" fn alignment_check_handler(frame: &ExceptionStackFrame, error_code: u64) -> ! {
-    println!("Alignment check handler");
-    loop {}
+    error!("Alignment check, exception frame: {:?}", frame);
+    test::exception(Exception::AlignmentCheck);
+    terminate_faulting_thread(frame, Signal::SIGBUS)
 }
 
+#[interrupt_handler(vector = vectors::INVALID_TSS, error_code)]
 extern "C" fn invalid_tss_handler(frame: &ExceptionStackFrame, error_code: u64) -> ! {
-    println!("Invalid tss handler: {:?}", frame);
-    loop {}
+    error!(
+        "Invalid TSS, error code: {:?}, exception frame: {:?}",
+        SelectorErrorCode::new(error_code),
+        frame
+    );
+    test::exception(Exception::InvalidTss);
+    halt()
 }
 
+#[interrupt_handler(vector = vectors::STACK_SEGMENT_FAULT, error_code)]
 extern "C" fn stack_segment_fault_handler(frame: &ExceptionStackFrame, error_code: u64) -> ! {
-    println!("Stack segment handler: {:?}", frame);
-    loop {}
+    error!(
+        "Stack segment fault, error code: {:?}, exception frame: {:?}",
+        SelectorErrorCode::new(error_code),
+        frame
+    );
+    test::exception(Exception::StackSegmentFault);
+    terminate_faulting_thread(frame, Signal::SIGSEGV)
 }
 
-extern "C" fn breakpoint_handler(frame: &ExceptionStackFrame) {
-    println!("Int3 triggered: {:?}", frame);
+#[interrupt_handler(vector = vectors::OVERFLOW)]
+extern "C" fn overflow_handler(frame: &ExceptionStackFrame) -> ! {
+    error!("Overflow, exception frame: {:?}", frame);
+    test::exception(Exception::Overflow);
+    terminate_faulting_thread(frame, Signal::SIGSEGV)
 }
 
-extern "C" fn non_maskable_interrupt(frame: &ExceptionStackFrame) {
-    println!("Non maskable interrupt handler {:?}", frame);
+#[interrupt_handler(vector = vectors::BOUND_RANGE_EXCEEDED)]
+extern "C" fn bound_range_exceeded_handler(frame: &ExceptionStackFrame) -> ! {
+    error!("Bound range exceeded, exception frame: {:?}", frame);
+    test::exception(Exception::BoundRangeExceeded);
+    terminate_faulting_thread(frame, Signal::SIGSEGV)
+}
+
+#[interrupt_handler(vector = vectors::X87_FLOATING_POINT)]
+extern "C" fn x87_floating_point_handler(frame: &ExceptionStackFrame) -> ! {
+    error!(
+        "x87 floating point exception, status word: {:#06x}, exception frame: {:?}",
+        fnstsw(),
+        frame
+    );
+    // the exception is raised again by the next x87 instruction otherwise
+    fnclex();
+    test::exception(Exception::X87FloatingPoint);
+    terminate_faulting_thread(frame, Signal::SIGFPE)
+}
+
+#[interrupt_handler(vector = vectors::SIMD_FLOATING_POINT)]
+extern "C" fn simd_floating_point_handler(frame: &ExceptionStackFrame) -> ! {
+    // the low 6 bits are the exception flags, bits 7-12 their masks
+    let mxcsr = unsafe { stmxcsr() };
+    error!(
+        "SIMD floating point exception, MXCSR: {:#x}, exception frame: {:?}",
+        mxcsr, frame
+    );
+    unsafe { ldmxcsr(DEFAULT_MXCSR) };
+    test::exception(Exception::SimdFloatingPoint);
+    terminate_faulting_thread(frame, Signal::SIGFPE)
+}
+
+#[interrupt_handler(vector = vectors::MACHINE_CHECK, stack = MACHINE_CHECK_IST_IDX as u16)]
+extern "C" fn machine_check_handler(frame: &ExceptionStackFrame) -> ! {
+    error!(
+        "Machine check, status: {:#x}, exception frame: {:?}",
+        MachineCheck::status(),
+        frame
+    );
+    for bank in 0..MachineCheck::bank_count() {
+        let status = MachineCheck::bank_status(bank);
+        // valid bit
+        if status & (1 << 63) == 0 {
+            continue;
+        }
+        // address valid bit
+        if status & (1 << 58) != 0 {
+            error!(
+                "Bank {}: status {:#x}, address {:#x}",
+                bank,
+                status,
+                MachineCheck::bank_address(bank)
+            );
+        } else {
+            error!("Bank {}: status {:#x}", bank, status);
+        }
+    }
+    test::exception(Exception::MachineCheck);
+    halt()
+}
+
+#[interrupt_handler(vector = vectors::VIRTUALIZATION)]
+extern "C" fn virtualization_handler(frame: &ExceptionStackFrame) -> ! {
+    error!("Virtualization exception, exception frame: {:?}", frame);
+    test::exception(Exception::Virtualization);
+    halt()
+}
+
+#[interrupt_handler(vector = vectors::CONTROL_PROTECTION_EXCEPTION, error_code)]
+extern "C" fn control_protection_handler(frame: &ExceptionStackFrame, error_code: u64) -> ! {
+    let cause = match error_code & 0x7fff {
+        1 => "near return",
+        2 => "far return",
+        3 => "missing endbranch",
+        4 => "rstorssp",
+        5 => "setssbsy",
+        _ => "unknown",
+    };
+    error!(
+        "Control protection exception: {} ({:#x}), exception frame: {:?}",
+        cause, error_code, frame
+    );
+    test::exception(Exception::ControlProtection);
+    terminate_faulting_thread(frame, Signal::SIGSEGV)
+}
+
+#[interrupt_handler(vector = vectors::HYPERVISOR_INJECTION_EXCEPTION)]
+extern "C" fn hypervisor_injection_handler(frame: &ExceptionStackFrame) -> ! {
+    error!(
+        "Hypervisor injection exception, exception frame: {:?}",
+        frame
+    );
+    test::exception(Exception::HypervisorInjection);
+    halt()
+}
+
+#[interrupt_handler(vector = vectors::VMM_COMMUNICATION_EXCEPTION, error_code)]
+extern "C" fn vmm_communication_handler(frame: &ExceptionStackFrame, error_code: u64) -> ! {
+    error!(
+        "VMM communication exception, exit code: {:#x}, exception frame: {:?}",
+        error_code, frame
+    );
+    test::exception(Exception::VmmCommunication);
+    halt()
+}
+
+#[interrupt_handler(vector = vectors::SECURITY_EXCEPTION, error_code)]
+extern "C" fn security_exception_handler(frame: &ExceptionStackFrame, error_code: u64) -> ! {
+    error!(
+        "Security exception, error code: {:#x}, exception frame: {:?}",
+        error_code, frame
+    );
+    test::exception(Exception::Security);
+    halt()
 }
 
-extern "C" fn debug_handler(frame: &ExceptionStackFrame) {
-    println!("Debug handler {:?}", frame);
+#[interrupt_handler(vector = vectors::NON_MASKABLE_INTERRUPT)]
+extern "C" fn non_maskable_interrupt(frame: &ExceptionStackFrame) {
+    if watchdog::nmi(frame) {
+        return;
+    }
+    error!("Non maskable interrupt handler {:?}", frame);
 }
 
+#[interrupt_handler(vector = vectors::DEVICE_NOT_AVAILABLE)]
 extern "C" fn device_not_available_handler(frame: &ExceptionStackFrame) {
-    println!("Device not available handler {:?}", frame);
+    error!("Device not available handler {:?}", frame);
 }
 
 // double fault acts kind of like a catch-all block
@@ -231,23 +715,166 @@ extern "C" fn device_not_available_handler(frame: &ExceptionStackFrame) {
 // Only very specific combinations of exceptions lead to a double fault
 // https://os.phil-opp.com/double-fault-exceptions/
 // (A double fault will always generate an error code with a value of zero. )
+#[interrupt_handler(vector = vectors::DOUBLE_FAULT, error_code, stack = DOUBLE_FAULT_IST_IDX as u16)]
 extern "C" fn double_fault_handler(frame: &ExceptionStackFrame, _error_code: u64) -> ! {
-    println!("Double fault error code: {}", _error_code);
-    println!("Double fault handler: {:?}", frame);
-    loop {}
+    // copy the log before adding to it
+    let mut records = [0u8; DOUBLE_FAULT_LOG_BYTES];
+    let len = log::ring::RING.read(&mut records);
+
+    error!("Double fault error code: {}", _error_code);
+    error!("Double fault handler: {:?}", frame);
+    report_double_fault(frame, &records[..len]);
+    test::exception(Exception::DoubleFault);
+
+    // the heap might be broken, so don't parse the whole command line
+    if cmdline::get("doublefault") == Some("reboot") {
+        error!("Rebooting");
+        idt::triple_fault()
+    }
+    error!("Halting");
+    halt()
 }
 
-extern "C" fn timer_interrupt_handler(_frame: &ExceptionStackFrame) {
-    print!(".");
+/// Log bytes searched for the last [`DOUBLE_FAULT_LOG_LINES`] records
+const DOUBLE_FAULT_LOG_BYTES: usize = 4096;
+const DOUBLE_FAULT_LOG_LINES: usize = 16;
+/// Words dumped from the faulting stack
+const DOUBLE_FAULT_STACK_WORDS: usize = 32;
+
+/// Prints CR2, CR3, the top of the faulting stack and the last records of
+/// `log`. Runs on its own stack, so the faulting one can be inspected even if
+/// it overflowed.
+fn report_double_fault(frame: &ExceptionStackFrame, log: &[u8]) {
+    let (frame_table, flags) = Cr3::read();
+    error!("CR2: {:#x}", Cr2::read());
+    error!("CR3: {:#x} {:?}", frame_table.start(), flags);
+
+    let rsp = frame.stack_pointer();
+    error!("Stack at {:#x}:", rsp);
+    for i in 0..DOUBLE_FAULT_STACK_WORDS {
+        let address = rsp + i * size_of::<u64>();
+        if paging::translate(address).is_none() {
+            error!("  {:#x} is not mapped", address);
+            break;
+        }
+        let word = unsafe { ptr::read_volatile(address.as_ptr::<u64>()) };
+        error!("  {:#x}: {:#018x}", address, word);
+    }
+
+    // the log ends with a newline, so one more is needed than lines printed
+    let mut newlines = 0;
+    let start = log
+        .iter()
+        .rposition(|byte| {
+            newlines += (*byte == b'\n') as usize;
+            newlines > DOUBLE_FAULT_LOG_LINES
+        })
+        .map_or(0, |i| i + 1);
+    error!("Last log records:");
+    for line in log[start..].split(|byte| *byte == b'\n') {
+        if !line.is_empty() {
+            error!(
+                "  {}",
+                core::str::from_utf8(line).unwrap_or("<invalid utf-8>")
+            );
+        }
+    }
+}
+
+#[interrupt_handler(vector = InterruptIndex::Timer.as_remapped_idt_number())]
+extern "C" fn timer_interrupt_handler(frame: &ExceptionStackFrame) {
+    trace_event!(irq, "timer, rip {:#x}", frame.instruction_pointer());
+    watchdog::heartbeat(frame);
+    profiler::sample(frame);
+    // acknowledge before ticking the scheduler since it might switch to a
+    // thread that does not return through this handler
     PICS.lock()
         .notify_end_of_interrupt(InterruptIndex::Timer.as_remapped_idt_number());
+
+    random::add_entropy(rdtsc());
+    time::tick();
+    softirq::raise(SoftIrq::Timer);
+    softirq::irq_exit();
+    scheduler::tick();
+}
+
+#[interrupt_handler(vector = APIC_TIMER_VECTOR)]
+extern "C" fn local_apic_timer_handler(frame: &ExceptionStackFrame) {
+    trace_event!(irq, "apic timer, rip {:#x}", frame.instruction_pointer());
+    watchdog::heartbeat(frame);
+    profiler::sample(frame);
+    if let Some(apic) = LocalApic::get() {
+        apic.end_of_interrupt();
+    }
+    random::add_entropy(rdtsc());
+    softirq::raise(SoftIrq::Timer);
+    softirq::irq_exit();
+
+    scheduler::clock_event();
 }
 
+// spurious interrupts must not be acknowledged
+#[interrupt_handler(vector = SPURIOUS_VECTOR)]
+extern "C" fn spurious_interrupt_handler(_frame: &ExceptionStackFrame) {}
+
+#[interrupt_handler(vector = InterruptIndex::Keyboard.as_remapped_idt_number())]
 extern "C" fn keyboard_interrupt_handler(_frame: &ExceptionStackFrame) {
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
-    print!("{}", scancode);
+    trace_event!(irq, "keyboard, scancode {:#x}", scancode);
+    random::add_entropy(rdtsc() ^ scancode as u64);
+    trace!("Scancode {}", scancode);
+
+    push_overwriting(&SCANCODES, scancode);
+    KEYBOARD_WAITERS.wake_one();
+    executor::signal(Event::Keyboard);
 
     PICS.lock()
         .notify_end_of_interrupt(InterruptIndex::Keyboard.as_remapped_idt_number());
+    softirq::irq_exit();
+}
+
+#[interrupt_handler(vector = InterruptIndex::Serial1.as_remapped_idt_number())]
+extern "C" fn serial_interrupt_handler(_frame: &ExceptionStackFrame) {
+    trace_event!(irq, "serial");
+    // the interrupted thread might hold the lock of the global serial port
+    // while printing, receiving doesn't interfere with sending though
+    let port = SerialPort::new(COM1_BASE);
+
+    while let Some(byte) = port.try_recv() {
+        push_overwriting(&SERIAL_INPUT, byte);
+        random::add_entropy(rdtsc() ^ byte as u64);
+    }
+    SERIAL_WAITERS.wake_one();
+
+    PICS.lock()
+        .notify_end_of_interrupt(InterruptIndex::Serial1.as_remapped_idt_number());
+    softirq::irq_exit();
+}
+
+fn pci_interrupt(index: usize) {
+    let line = PCI_IRQS[index];
+    trace_event!(irq, "pci line {}", line);
+    let handler = PCI_HANDLERS.lock()[index];
+    if let Some(handler) = handler {
+        handler();
+    }
+    PICS.lock()
+        .notify_end_of_interrupt(MASTER_PIC_OFFSET + line);
+    softirq::irq_exit();
+}
+
+#[interrupt_handler(vector = MASTER_PIC_OFFSET + PCI_IRQS[0])]
+extern "C" fn pci_irq9_handler(_frame: &ExceptionStackFrame) {
+    pci_interrupt(0);
+}
+
+#[interrupt_handler(vector = MASTER_PIC_OFFSET + PCI_IRQS[1])]
+extern "C" fn pci_irq10_handler(_frame: &ExceptionStackFrame) {
+    pci_interrupt(1);
+}
+
+#[interrupt_handler(vector = MASTER_PIC_OFFSET + PCI_IRQS[2])]
+extern "C" fn pci_irq11_handler(_frame: &ExceptionStackFrame) {
+    pci_interrupt(2);
 }
@@ -0,0 +1,109 @@
+//! Deferred interrupt processing.
+//!
+//! Interrupt handlers [`raise`] a softirq for the work that has to happen soon
+//! but not while the device waits for its end of interrupt. Pending softirqs
+//! run in [`irq_exit`] at the end of the hardware interrupt handler, before it
+//! returns or switches to another thread, in the order of [`SoftIrq`]. They
+//! run with interrupts disabled, so handlers have to be short, anything that
+//! might block belongs into the [`workqueue`](crate::workqueue).
+//!
+//! Raising a softirq from a handler reruns the loop, up to [`MAX_RESTARTS`]
+//! times, the rest stays pending until the next interrupt. Pending softirqs
+//! are tracked for the boot CPU only, the kernel doesn't use any other.
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+/// Times the pending softirqs are rechecked in one [`irq_exit`]
+pub const MAX_RESTARTS: usize = 10;
+
+/// Softirq classes, the first one has the highest priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SoftIrq {
+    /// Raised by the timer interrupt handlers on every interrupt
+    Timer,
+    /// Received network packets
+    NetRx,
+    /// Completed block device requests
+    BlockComplete,
+}
+
+impl SoftIrq {
+    pub const ALL: [SoftIrq; 3] = [SoftIrq::Timer, SoftIrq::NetRx, SoftIrq::BlockComplete];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SoftIrq::Timer => "timer",
+            SoftIrq::NetRx => "net-rx",
+            SoftIrq::BlockComplete => "block-complete",
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoftIrqError {
+    AlreadyRegistered,
+}
+
+static PENDING: AtomicU32 = AtomicU32::new(0);
+type Handlers = [Option<fn()>; SoftIrq::ALL.len()];
+// locked with interrupts disabled
+static HANDLERS: Mutex<Handlers> = Mutex::new([None; SoftIrq::ALL.len()]);
+static COUNTS: [AtomicU64; SoftIrq::ALL.len()] = [const { AtomicU64::new(0) }; SoftIrq::ALL.len()];
+
+/// Sets the handler of `softirq`, each one has at most one
+pub fn register(softirq: SoftIrq, handler: fn()) -> Result<(), SoftIrqError> {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slot = &mut handlers[softirq as usize];
+        if slot.is_some() {
+            return Err(SoftIrqError::AlreadyRegistered);
+        }
+        *slot = Some(handler);
+        Ok(())
+    })
+}
+
+/// Removes the handler of `softirq`
+pub fn unregister(softirq: SoftIrq) {
+    without_interrupts(|| HANDLERS.lock()[softirq as usize] = None);
+}
+
+/// Marks `softirq` pending, it runs at the next [`irq_exit`]
+pub fn raise(softirq: SoftIrq) {
+    PENDING.fetch_or(softirq.bit(), Ordering::SeqCst);
+}
+
+pub fn is_pending(softirq: SoftIrq) -> bool {
+    PENDING.load(Ordering::SeqCst) & softirq.bit() != 0
+}
+
+/// Times `softirq` ran since boot
+pub fn count(softirq: SoftIrq) -> u64 {
+    COUNTS[softirq as usize].load(Ordering::Relaxed)
+}
+
+/// Runs the pending softirqs. Called by hardware interrupt handlers after
+/// their end of interrupt, with interrupts disabled.
+pub fn irq_exit() {
+    for _ in 0..MAX_RESTARTS {
+        let pending = PENDING.swap(0, Ordering::SeqCst);
+        if pending == 0 {
+            return;
+        }
+
+        let handlers = *HANDLERS.lock();
+        for softirq in SoftIrq::ALL {
+            if pending & softirq.bit() == 0 {
+                continue;
+            }
+            if let Some(handler) = handlers[softirq as usize] {
+                handler();
+                COUNTS[softirq as usize].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Detects a CPU that stopped making progress.
+//!
+//! Every timer interrupt and every time the CPU goes idle is a heartbeat. Code
+//! spinning with interrupts disabled never services the timer interrupt, so
+//! the first performance counter counts unhalted cycles and raises an NMI
+//! through the local APIC after roughly [`CHECK_PERIOD`] of busy time. If there
+//! was no heartbeat for [`TIMEOUT`] the NMI reports where the CPU is stuck. A
+//! halted CPU doesn't count cycles, so idling never triggers it.
+//!
+//! Threads that keep running with interrupts enabled are caught by the
+//! scheduler tick instead: a thread that ran for [`TIMEOUT`] without being
+//! switched out while other threads are ready is reported together with the
+//! RIP and stack of the last timer interrupt.
+//!
+//! With `watchdog=panic` on the command line the kernel panics after a report.
+//! Only the boot CPU is watched since the kernel doesn't use any other.
+use super::hardware::apic::LocalApic;
+use crate::{backtrace, cmdline, error, scheduler::ThreadId, time::tsc};
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+use x86_64::{
+    cpuid::PerformanceMonitoring, instructions::rdtsc, interrupts::ExceptionStackFrame,
+    memory::Address, register::PerformanceCounter,
+};
+
+/// Time without progress after which a CPU is reported
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+/// Busy time between two checks, shorter if the counter can't hold it
+pub const CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+const COUNTER: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogError {
+    UnknownTscFrequency,
+    NoLocalApic,
+    NoPerformanceCounter,
+}
+
+/// Progress of a CPU, timestamps are TSC values
+struct Progress {
+    heartbeats: AtomicU64,
+    /// Interrupted instruction and stack of the last timer interrupt
+    rip: AtomicU64,
+    rsp: AtomicU64,
+    /// Last context switch
+    switched_at: AtomicU64,
+    runaway_reported: AtomicBool,
+    /// Heartbeats seen by the last check and when they last changed
+    checked_heartbeats: AtomicU64,
+    checked_at: AtomicU64,
+    lockup_reported: AtomicBool,
+}
+
+impl Progress {
+    const fn new() -> Self {
+        Self {
+            heartbeats: AtomicU64::new(0),
+            rip: AtomicU64::new(0),
+            rsp: AtomicU64::new(0),
+            switched_at: AtomicU64::new(0),
+            runaway_reported: AtomicBool::new(false),
+            checked_heartbeats: AtomicU64::new(0),
+            checked_at: AtomicU64::new(0),
+            lockup_reported: AtomicBool::new(false),
+        }
+    }
+}
+
+static BOOT_CPU: Progress = Progress::new();
+/// TSC increments per second, 0 until the watchdog is initialized
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// Cycles between two checks, 0 if the NMI isn't set up
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+/// Bits of the performance counter
+static COUNTER_WIDTH: AtomicU8 = AtomicU8::new(0);
+static PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Starts watching the boot CPU. Runaway threads are detected as soon as the
+/// TSC frequency is known, the error tells why hard lockups are not.
+pub fn init() -> Result<(), WatchdogError> {
+    let frequency = tsc::frequency().ok_or(WatchdogError::UnknownTscFrequency)?;
+    PANIC.store(cmdline::get("watchdog") == Some("panic"), Ordering::SeqCst);
+    BOOT_CPU.switched_at.store(rdtsc(), Ordering::SeqCst);
+    BOOT_CPU.checked_at.store(rdtsc(), Ordering::SeqCst);
+    TSC_FREQUENCY.store(frequency, Ordering::SeqCst);
+
+    let apic = LocalApic::get().ok_or(WatchdogError::NoLocalApic)?;
+    let monitoring = PerformanceMonitoring::read()
+        .filter(|m| m.has_unhalted_core_cycles())
+        .ok_or(WatchdogError::NoPerformanceCounter)?;
+
+    // the core clock doesn't necessarily run at the TSC frequency, close
+    // enough for a watchdog though
+    let period = to_cycles(CHECK_PERIOD).min(i32::MAX as u64);
+    COUNTER_WIDTH.store(monitoring.counter_width, Ordering::SeqCst);
+    PERIOD.store(period, Ordering::SeqCst);
+
+    apic.set_performance_counter_nmi();
+    PerformanceCounter::write(COUNTER, -(period as i32));
+    PerformanceCounter::select(
+        COUNTER,
+        PerformanceCounter::UNHALTED_CORE_CYCLES
+            | PerformanceCounter::USER
+            | PerformanceCounter::KERNEL
+            | PerformanceCounter::INTERRUPT
+            | PerformanceCounter::ENABLE,
+    );
+    if monitoring.version >= 2 {
+        PerformanceCounter::enable(COUNTER);
+    }
+    Ok(())
+}
+
+fn to_cycles(duration: Duration) -> u64 {
+    let frequency = TSC_FREQUENCY.load(Ordering::Relaxed) as u128;
+    (duration.as_nanos() * frequency / 1_000_000_000) as u64
+}
+
+/// Called by the timer interrupt handlers
+pub fn heartbeat(frame: &ExceptionStackFrame) {
+    BOOT_CPU.heartbeats.fetch_add(1, Ordering::Relaxed);
+    BOOT_CPU
+        .rip
+        .store(frame.instruction_pointer().as_u64(), Ordering::Relaxed);
+    BOOT_CPU
+        .rsp
+        .store(frame.stack_pointer().as_u64(), Ordering::Relaxed);
+}
+
+/// Called by the idle thread before halting
+pub fn touch() {
+    BOOT_CPU.heartbeats.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Called by the scheduler whenever it switches to another thread
+pub fn switched() {
+    BOOT_CPU.switched_at.store(rdtsc(), Ordering::Relaxed);
+    BOOT_CPU.runaway_reported.store(false, Ordering::Relaxed);
+}
+
+/// Called by the scheduler on every tick. Reports `current` if it ran for
+/// too long while `others_ready`.
+pub fn check_runaway(current: ThreadId, others_ready: bool) {
+    if !others_ready || TSC_FREQUENCY.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let running = rdtsc().saturating_sub(BOOT_CPU.switched_at.load(Ordering::Relaxed));
+    if running < to_cycles(TIMEOUT) || BOOT_CPU.runaway_reported.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    error!(
+        "Watchdog: thread {} ran for more than {:?} while other threads are ready",
+        current, TIMEOUT
+    );
+    report(
+        BOOT_CPU.rip.load(Ordering::Relaxed),
+        BOOT_CPU.rsp.load(Ordering::Relaxed),
+    );
+}
+
+/// Handles an NMI, returns false if it wasn't raised by the watchdog
+pub fn nmi(frame: &ExceptionStackFrame) -> bool {
+    let period = PERIOD.load(Ordering::Relaxed);
+    if period == 0 {
+        return false;
+    }
+    // the counter started at -period, only an overflow clears the top bit
+    let top_bit = 1 << (COUNTER_WIDTH.load(Ordering::Relaxed) - 1);
+    if PerformanceCounter::read(COUNTER) & top_bit != 0 {
+        return false;
+    }
+    PerformanceCounter::write(COUNTER, -(period as i32));
+    if let Some(apic) = LocalApic::get() {
+        apic.set_performance_counter_nmi();
+    }
+
+    let now = rdtsc();
+    let heartbeats = BOOT_CPU.heartbeats.load(Ordering::Relaxed);
+    if BOOT_CPU
+        .checked_heartbeats
+        .swap(heartbeats, Ordering::Relaxed)
+        != heartbeats
+    {
+        BOOT_CPU.checked_at.store(now, Ordering::Relaxed);
+        BOOT_CPU.lockup_reported.store(false, Ordering::Relaxed);
+        return true;
+    }
+    let stuck = now.saturating_sub(BOOT_CPU.checked_at.load(Ordering::Relaxed));
+    if stuck < to_cycles(TIMEOUT) || BOOT_CPU.lockup_reported.swap(true, Ordering::Relaxed) {
+        return true;
+    }
+
+    error!(
+        "Watchdog: no timer interrupt for more than {:?} ({:?})",
+        TIMEOUT,
+        frame.privilege_level()
+    );
+    report(
+        frame.instruction_pointer().as_u64(),
+        frame.stack_pointer().as_u64(),
+    );
+    true
+}
+
+fn report(rip: u64, rsp: u64) {
+    match backtrace::symbolize(rip) {
+        Some((symbol, offset)) => error!(
+            "RIP: {:#x} {}+{:#x}, RSP: {:#x}",
+            rip, symbol.name, offset, rsp
+        ),
+        None => error!("RIP: {:#x}, RSP: {:#x}", rip, rsp),
+    }
+    if PANIC.load(Ordering::Relaxed) {
+        panic!("Watchdog detected a stuck CPU");
+    }
+}
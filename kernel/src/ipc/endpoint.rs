@@ -0,0 +1,118 @@
+extern crate alloc;
+use super::{IpcError, Message};
+use crate::sync::WaitQueue;
+use alloc::collections::VecDeque;
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+struct EndpointState {
+    /// Messages that have not been received yet, with their ticket
+    queue: VecDeque<(u64, Message)>,
+    next_ticket: u64,
+    /// Amount of messages taken by receivers. Since messages are received in
+    /// order, the message with ticket `t` is delivered once `delivered > t`.
+    delivered: u64,
+    closed: bool,
+}
+
+/// Rendezvous point for synchronous message passing.
+///
+/// Senders block until their message has been taken by a receiver, receivers
+/// block until a message is available. Messages are delivered in the order
+/// they were sent.
+pub struct Endpoint {
+    // Locked with interrupts disabled since it is checked from wait queue
+    // conditions
+    state: Mutex<EndpointState>,
+    senders: WaitQueue,
+    receivers: WaitQueue,
+}
+
+impl Endpoint {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(EndpointState {
+                queue: VecDeque::new(),
+                next_ticket: 0,
+                delivered: 0,
+                closed: false,
+            }),
+            senders: WaitQueue::named("ipc send"),
+            receivers: WaitQueue::named("ipc receive"),
+        }
+    }
+
+    fn with_state<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut EndpointState) -> R,
+    {
+        without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    /// Blocks until a receiver took `message`
+    pub fn send(&self, message: Message) -> Result<(), IpcError> {
+        let ticket = self.with_state(|state| {
+            if state.closed {
+                return Err(IpcError::Closed);
+            }
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            state.queue.push_back((ticket, message));
+            Ok(ticket)
+        })?;
+        self.receivers.wake_one();
+
+        let mut result = Ok(());
+        self.senders.wait_until(|| {
+            self.with_state(|state| {
+                if state.delivered > ticket {
+                    return true;
+                }
+                if state.closed {
+                    result = Err(IpcError::Closed);
+                    return true;
+                }
+                false
+            })
+        });
+        result
+    }
+
+    /// Blocks until a message is available and returns it
+    pub fn receive(&self) -> Result<Message, IpcError> {
+        let mut result = Err(IpcError::Closed);
+        self.receivers.wait_until(|| {
+            self.with_state(|state| match state.queue.pop_front() {
+                Some((_, message)) => {
+                    state.delivered += 1;
+                    result = Ok(message);
+                    true
+                }
+                None => state.closed,
+            })
+        });
+
+        if result.is_ok() {
+            self.senders.wake_all();
+        }
+        result
+    }
+
+    /// Closes the endpoint. Pending messages are dropped and all blocked
+    /// senders and receivers fail with [`IpcError::Closed`].
+    pub fn close(&self) {
+        let pending = self.with_state(|state| {
+            state.closed = true;
+            core::mem::take(&mut state.queue)
+        });
+        // dropped outside of the lock since grants might free memory
+        drop(pending);
+        self.senders.wake_all();
+        self.receivers.wake_all();
+    }
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,262 @@
+//! Synchronous message passing between threads and processes.
+//!
+//! Messages are sent to [`Endpoint`]s, which are registered under an
+//! [`EndpointId`]. A message consists of a label and a few words of payload,
+//! larger amounts of data can be transferred by attaching a [`Grant`]: the
+//! frames backing a region of the sender are mapped into the address space of
+//! the receiver. The region becomes shared memory, so the frames stay alive
+//! until both sides unmapped it.
+//!
+//! The system call interface uses [`RawMessage`]. A grant is passed as start
+//! address and size of a region of the sender. On receive the kernel maps the
+//! granted frames and stores the address and size of the new mapping in the
+//! message.
+extern crate alloc;
+use crate::{
+    memory::{self, shared::SharedMemory, MemoryError},
+    process::{self, Pid},
+    syscall::{Errno, SyscallResult},
+};
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use hashmap::HashMap;
+use x86_64::{
+    memory::{Address, PageSize, Size4KiB, VirtualAddress},
+    mutex::Mutex,
+    paging::PageTableEntryFlags,
+};
+
+mod endpoint;
+pub use endpoint::Endpoint;
+
+/// Amount of payload words in a message
+pub const MESSAGE_WORDS: usize = 4;
+
+static NEXT_ENDPOINT_ID: AtomicU64 = AtomicU64::new(1);
+static ENDPOINTS: Mutex<HashMap<EndpointId, Arc<Endpoint>>> = Mutex::new(HashMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EndpointId(u64);
+
+impl EndpointId {
+    pub fn from_u64(id: u64) -> Self {
+        Self(id)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for EndpointId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcError {
+    NoSuchEndpoint,
+    /// The endpoint was destroyed while waiting on it
+    Closed,
+    /// The granted region is not fully mapped or not page aligned
+    InvalidGrant,
+    /// A buffer passed by the caller is invalid
+    InvalidBuffer,
+    OutOfMemory,
+}
+
+impl From<MemoryError> for IpcError {
+    fn from(_: MemoryError) -> Self {
+        IpcError::OutOfMemory
+    }
+}
+
+impl From<IpcError> for Errno {
+    fn from(error: IpcError) -> Self {
+        match error {
+            IpcError::NoSuchEndpoint => Errno::ENOENT,
+            IpcError::Closed => Errno::EIDRM,
+            IpcError::InvalidGrant | IpcError::InvalidBuffer => Errno::EFAULT,
+            IpcError::OutOfMemory => Errno::ENOMEM,
+        }
+    }
+}
+
+/// Frames of a memory region that are handed to the receiver of a message
+#[derive(Debug)]
+pub struct Grant {
+    segment: Arc<SharedMemory>,
+}
+
+impl Grant {
+    /// Grants the frames backing the anonymous or shared region of `size`
+    /// bytes at `start`. The region is turned into shared memory.
+    pub fn from_region(start: VirtualAddress, size: u64) -> Result<Self, IpcError> {
+        if !start.is_aligned(Size4KiB::SIZE) || size == 0 {
+            return Err(IpcError::InvalidGrant);
+        }
+
+        let segment = memory::with_memory_manager(|mm| mm.share_region(start)).map_err(
+            |error| match error {
+                MemoryError::RegionNotFound | MemoryError::NotShareable => IpcError::InvalidGrant,
+                error => error.into(),
+            },
+        )?;
+        // only whole regions can be granted
+        if segment.size() != size.next_multiple_of(Size4KiB::SIZE) {
+            return Err(IpcError::InvalidGrant);
+        }
+        Ok(Self { segment })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.segment.size()
+    }
+
+    /// Maps the granted frames into the address space of the current process
+    pub fn map(self, flags: PageTableEntryFlags) -> Result<VirtualAddress, IpcError> {
+        Ok(memory::shared::map_segment(self.segment, flags)?)
+    }
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub label: u64,
+    pub words: [u64; MESSAGE_WORDS],
+    pub grant: Option<Grant>,
+    /// Set by the kernel on send
+    pub sender: Pid,
+}
+
+impl Message {
+    pub fn new(label: u64, words: [u64; MESSAGE_WORDS]) -> Self {
+        Self {
+            label,
+            words,
+            grant: None,
+            sender: Pid::KERNEL,
+        }
+    }
+
+    pub fn with_grant(mut self, grant: Grant) -> Self {
+        self.grant = Some(grant);
+        self
+    }
+}
+
+/// Message layout used by the system call interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct RawMessage {
+    pub label: u64,
+    pub words: [u64; MESSAGE_WORDS],
+    /// Start and size of the granted region, 0 if there is no grant
+    pub grant_start: u64,
+    pub grant_size: u64,
+    pub sender: u64,
+}
+
+/// Creates a new endpoint
+pub fn create() -> EndpointId {
+    let id = EndpointId(NEXT_ENDPOINT_ID.fetch_add(1, Ordering::Relaxed));
+    ENDPOINTS.lock().insert(id, Arc::new(Endpoint::new()));
+    id
+}
+
+pub fn lookup(id: EndpointId) -> Result<Arc<Endpoint>, IpcError> {
+    ENDPOINTS
+        .lock()
+        .get(&id)
+        .cloned()
+        .ok_or(IpcError::NoSuchEndpoint)
+}
+
+/// Removes an endpoint. Threads blocked on it fail with [`IpcError::Closed`].
+pub fn destroy(id: EndpointId) -> Result<(), IpcError> {
+    let endpoint = ENDPOINTS
+        .lock()
+        .remove(&id)
+        .ok_or(IpcError::NoSuchEndpoint)?;
+    endpoint.close();
+    Ok(())
+}
+
+/// Sends `message` to the endpoint `id` and blocks until it is received
+pub fn send(id: EndpointId, mut message: Message) -> Result<(), IpcError> {
+    let endpoint = lookup(id)?;
+    message.sender = process::current();
+    endpoint.send(message)
+}
+
+/// Blocks until a message arrives at the endpoint `id`
+pub fn receive(id: EndpointId) -> Result<Message, IpcError> {
+    lookup(id)?.receive()
+}
+
+/// `ipc_create()` system call. Returns the id of the new endpoint.
+pub fn sys_create() -> SyscallResult {
+    Ok(create().as_u64())
+}
+
+/// `ipc_destroy(endpoint)` system call
+pub fn sys_destroy(id: u64) -> SyscallResult {
+    destroy(EndpointId(id))?;
+    Ok(0)
+}
+
+fn raw_message<'a>(address: u64) -> Result<&'a mut RawMessage, IpcError> {
+    if address == 0 || !address.is_multiple_of(core::mem::align_of::<RawMessage>() as u64) {
+        return Err(IpcError::InvalidBuffer);
+    }
+    Ok(unsafe { &mut *(address as *mut RawMessage) })
+}
+
+/// `ipc_send(endpoint, message)` system call. `message` points to a
+/// [`RawMessage`].
+pub fn sys_send(id: u64, address: u64) -> SyscallResult {
+    let raw = *raw_message(address)?;
+    let mut message = Message::new(raw.label, raw.words);
+    if raw.grant_size != 0 {
+        message.grant = Some(Grant::from_region(
+            VirtualAddress::new(raw.grant_start),
+            raw.grant_size,
+        )?);
+    }
+
+    send(EndpointId(id), message)?;
+    Ok(0)
+}
+
+/// `ipc_receive(endpoint, message)` system call. The received message is
+/// stored in the [`RawMessage`] `message` points to, a grant is mapped
+/// writable.
+pub fn sys_receive(id: u64, address: u64) -> SyscallResult {
+    let raw = raw_message(address)?;
+    let message = receive(EndpointId(id))?;
+
+    let (grant_start, grant_size) = match message.grant {
+        Some(grant) => {
+            let size = grant.size();
+            let start = grant.map(
+                PageTableEntryFlags::PRESENT
+                    | PageTableEntryFlags::WRITABLE
+                    | PageTableEntryFlags::NO_EXECUTE,
+            )?;
+            (start.as_u64(), size)
+        }
+        None => (0, 0),
+    };
+
+    *raw = RawMessage {
+        label: message.label,
+        words: message.words,
+        grant_start,
+        grant_size,
+        sender: message.sender.as_u64(),
+    };
+    Ok(0)
+}
@@ -2,49 +2,173 @@
 #![no_main]
 #![feature(naked_functions)]
 #![feature(const_mut_refs)]
+#![feature(linkage)]
 use api::BootInfo;
 extern crate alloc;
-use core::iter::Copied;
 use x86_64::{
-    memory::{Address, MemoryRegion, PhysicalMemoryRegion},
-    paging::{
-        bump_frame_allocator::BumpFrameAllocator,
-        offset_page_table::{OffsetPageTable, PhysicalOffset},
-    },
-    println,
+    cpuid::CpuInfo,
+    paging::{bump_frame_allocator::BumpFrameAllocator, offset_page_table::PhysicalOffset},
 };
 
+pub mod acpi;
 pub mod allocator;
+pub mod ata;
+pub mod backtrace;
+pub mod cmdline;
+pub mod executor;
+pub mod fs;
+pub mod gdb;
 pub mod interrupts;
+pub mod ipc;
+pub mod log;
+pub mod memory;
+pub mod net;
 pub mod paging;
+pub mod pci;
+pub mod process;
+pub mod profiler;
+pub mod pstore;
 pub mod qemu;
+pub mod random;
+pub mod scheduler;
+pub mod shell;
+pub mod sync;
+pub mod syscall;
+pub mod test;
+pub mod time;
+pub mod tracepoint;
+pub mod workqueue;
 
 use allocator::init_heap;
+use fs::FsError;
+use log::framebuffer::FramebufferSinkError;
+use memory::{LinkedListFrameAllocator, MemoryError, MemoryManager};
 
-pub fn kernel_init(
-    boot_info: &'static BootInfo,
-) -> Result<
-    (
-        BumpFrameAllocator<
-            Copied<core::slice::Iter<'_, PhysicalMemoryRegion>>,
-            PhysicalMemoryRegion,
-        >,
-        OffsetPageTable<PhysicalOffset>,
-    ),
-    (),
-> {
-    println!("Initializing kernel");
+#[derive(Debug)]
+pub enum InitError {
+    /// Allocating a subsystem's threads or buffers failed
+    Memory(MemoryError),
+    Fs(FsError),
+}
+
+impl From<MemoryError> for InitError {
+    fn from(error: MemoryError) -> Self {
+        InitError::Memory(error)
+    }
+}
+
+impl From<FsError> for InitError {
+    fn from(error: FsError) -> Self {
+        InitError::Fs(error)
+    }
+}
+
+pub fn kernel_init(boot_info: &'static BootInfo) -> Result<(), InitError> {
+    log::init();
+    info!("Initializing kernel");
+    let cpu = CpuInfo::read();
+    info!("CPU: {}", cpu);
+    debug!("CPU features: {:?}", cpu.features);
     interrupts::init();
 
-    let pml4t = unsafe { paging::init(boot_info) };
+    unsafe { paging::init(boot_info) };
+    backtrace::init(boot_info);
+    if let Err(error) = acpi::init() {
+        warn!("Failed to find ACPI tables: {:?}", error);
+    }
+
+    #[cfg(feature = "gdb-stub")]
+    {
+        gdb::init();
+        info!("Waiting for debugger on COM2");
+        gdb::breakpoint();
+    }
 
-    let pt_offset = PhysicalOffset::new(boot_info.physical_memory_offset);
-    let mut page_table = OffsetPageTable::new(pml4t, pt_offset);
+    let mut page_table = unsafe { paging::kernel_page_table() };
+    info!("{:?}-level paging", page_table.levels());
 
-    let mut frame_allocator =
-        BumpFrameAllocator::new(boot_info.memory_regions.iter().copied().peekable());
+    // the frame allocators walk the regions in order and must not hand out a
+    // frame twice
+    assert!(
+        memory_map::is_normalized(&boot_info.memory_regions[..]),
+        "Boot memory map is not normalized"
+    );
+    let memory_regions = unsafe { memory::kernel_memory_map(&boot_info.memory_regions) };
+    let mut frame_allocator = BumpFrameAllocator::new(memory_regions.iter().copied().peekable());
 
     init_heap(&mut page_table, &mut frame_allocator);
 
-    Ok((frame_allocator, page_table))
+    // the bump allocator can't free frames, hand the rest of memory over
+    let frame_allocator = unsafe {
+        LinkedListFrameAllocator::new(
+            memory_regions,
+            frame_allocator.remaining_frames(),
+            PhysicalOffset::new(boot_info.physical_memory_offset),
+        )
+    };
+
+    memory::init(MemoryManager::new(
+        frame_allocator,
+        page_table,
+        boot_info.physical_memory_offset,
+    ));
+    acpi::copy_tables();
+    let reclaimed =
+        memory::with_memory_manager(|mm| unsafe { mm.frame_allocator().reclaim_acpi_memory() });
+    info!("Reclaimed {} frames of ACPI tables", reclaimed);
+    cmdline::init(boot_info);
+    time::init();
+    random::init();
+    if let Err(error) = pstore::init() {
+        info!("Crash dumps are not persisted: {:?}", error);
+    }
+
+    scheduler::fpu::init();
+    scheduler::init()?;
+    workqueue::init()?;
+    executor::init()?;
+    if let Err(error) = interrupts::init_local_apic() {
+        info!("Using the PIT for clock events: {:?}", error);
+    }
+    if let Err(error) = interrupts::watchdog::init() {
+        info!("Hard lockups are not detected: {:?}", error);
+    }
+    process::init();
+    interrupts::init_serial_input();
+    net::init()?;
+    fs::init(boot_info)?;
+
+    match boot_info.video_modes.current {
+        Some(mode) => info!(
+            "Video mode {:#x}: {}x{}x{}",
+            mode.number, mode.width, mode.height, mode.bits_per_pixel
+        ),
+        None => info!("Text mode"),
+    }
+    if let Some((width, height)) = boot_info.edid.as_ref().and_then(|e| e.native_resolution()) {
+        info!("Native display resolution: {}x{}", width, height);
+    }
+    for mode in boot_info.video_modes.as_slice() {
+        debug!(
+            "Available video mode {:#x}: {}x{}x{}",
+            mode.number, mode.width, mode.height, mode.bits_per_pixel
+        );
+    }
+    match log::framebuffer::init(&boot_info.framebuffer) {
+        Ok(()) | Err(FramebufferSinkError::NoFramebuffer) => (),
+        Err(error) => warn!("Failed to log to the framebuffer: {:?}", error),
+    }
+
+    // the kernel has its own GDT and copied what it needs from the bootloader
+    let reclaimed = memory::with_memory_manager(|mm| unsafe {
+        mm.frame_allocator().reclaim_bootloader_memory()
+    });
+    info!("Reclaimed {} frames of bootloader memory", reclaimed);
+
+    #[cfg(feature = "verify-paging")]
+    for issue in memory::with_memory_manager(|mm| paging::verify(mm.page_table())) {
+        warn!("Page table: {}", issue);
+    }
+
+    Ok(())
 }
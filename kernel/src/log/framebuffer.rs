@@ -0,0 +1,185 @@
+//! Text console on the linear framebuffer.
+//!
+//! Draws records with the 8x8 font and scrolls the screen up once the last
+//! line is full.
+extern crate alloc;
+use super::{font, LogError, Record, Sink};
+use crate::memory::{shared, MemoryError};
+use alloc::boxed::Box;
+use api::{FramebufferInfo, PixelFormat};
+use core::{
+    fmt::{self, Write},
+    ptr,
+};
+use x86_64::{
+    interrupts::without_interrupts, memory::VirtualAddress, mutex::Mutex, paging::CacheAttribute,
+};
+
+const FOREGROUND: (u8, u8, u8) = (0xc0, 0xc0, 0xc0);
+const BACKGROUND: (u8, u8, u8) = (0, 0, 0);
+
+#[derive(Debug)]
+pub enum FramebufferSinkError {
+    NoFramebuffer,
+    Memory(MemoryError),
+    Log(LogError),
+}
+
+/// Maps the framebuffer and starts logging to it
+pub fn init(info: &FramebufferInfo) -> Result<(), FramebufferSinkError> {
+    if info.region.size == 0 || info.bytes_per_pixel == 0 {
+        return Err(FramebufferSinkError::NoFramebuffer);
+    }
+
+    let address = shared::map_physical(
+        info.region.address(),
+        info.region.size,
+        CacheAttribute::WriteCombining,
+    )
+    .map_err(FramebufferSinkError::Memory)?;
+    let sink = Box::leak(Box::new(FramebufferSink {
+        console: Mutex::new(Console::new(info, address)),
+    }));
+    super::register_sink(sink).map_err(FramebufferSinkError::Log)
+}
+
+struct Console {
+    address: VirtualAddress,
+    /// Bytes per line of pixels
+    pitch: usize,
+    bytes_per_pixel: usize,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+    foreground: [u8; 4],
+    background: [u8; 4],
+}
+
+impl Console {
+    fn new(info: &FramebufferInfo, address: VirtualAddress) -> Self {
+        let console = Self {
+            address,
+            pitch: info.stride as usize * info.bytes_per_pixel as usize,
+            bytes_per_pixel: (info.bytes_per_pixel as usize).min(4),
+            columns: info.width as usize / font::WIDTH,
+            rows: info.height as usize / font::HEIGHT,
+            column: 0,
+            row: 0,
+            foreground: encode(info.pixel_format, FOREGROUND),
+            background: encode(info.pixel_format, BACKGROUND),
+        };
+        console.clear_rows(0, console.rows);
+        console
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> *mut u8 {
+        (self.address + (y * self.pitch + x * self.bytes_per_pixel) as u64).as_mut_ptr()
+    }
+
+    fn draw(&self, c: u8) {
+        let glyph = font::glyph(c);
+        let (left, top) = (self.column * font::WIDTH, self.row * font::HEIGHT);
+        for (y, bits) in glyph.iter().enumerate() {
+            for x in 0..font::WIDTH {
+                let color = match bits & (1 << x) {
+                    0 => &self.background,
+                    _ => &self.foreground,
+                };
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        color.as_ptr(),
+                        self.pixel(left + x, top + y),
+                        self.bytes_per_pixel,
+                    )
+                };
+            }
+        }
+    }
+
+    /// Fills the text rows `start..end` with the background color
+    fn clear_rows(&self, start: usize, end: usize) {
+        let width = self.columns * font::WIDTH;
+        for y in start * font::HEIGHT..end * font::HEIGHT {
+            for x in 0..width {
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        self.background.as_ptr(),
+                        self.pixel(x, y),
+                        self.bytes_per_pixel,
+                    )
+                };
+            }
+        }
+    }
+
+    fn scroll(&self) {
+        let line = self.pitch * font::HEIGHT;
+        unsafe {
+            ptr::copy(
+                self.pixel(0, font::HEIGHT),
+                self.pixel(0, 0),
+                line * (self.rows - 1),
+            )
+        };
+        self.clear_rows(self.rows - 1, self.rows);
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.rows == 0 || self.columns == 0 {
+            return Ok(());
+        }
+
+        for byte in s.bytes() {
+            match byte {
+                b'\n' => self.new_line(),
+                byte => {
+                    if self.column == self.columns {
+                        self.new_line();
+                    }
+                    self.draw(byte);
+                    self.column += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pixel value of `(r, g, b)` in memory order
+fn encode(format: PixelFormat, (r, g, b): (u8, u8, u8)) -> [u8; 4] {
+    let (r, g, b) = (r as u32, g as u32, b as u32);
+    let value = match format {
+        PixelFormat::Rgb => r | g << 8 | b << 16,
+        PixelFormat::Bgr => b | g << 8 | r << 16,
+        PixelFormat::Unknown {
+            red_position,
+            green_position,
+            blue_position,
+        } => r << red_position | g << green_position | b << blue_position,
+    };
+    value.to_le_bytes()
+}
+
+struct FramebufferSink {
+    console: Mutex<Console>,
+}
+
+impl Sink for FramebufferSink {
+    fn write(&self, record: &Record) {
+        without_interrupts(|| {
+            let _ = writeln!(self.console.lock(), "{}", record);
+        });
+    }
+}
@@ -0,0 +1,307 @@
+//! Kernel logging.
+//!
+//! Messages are logged through the [`error!`](crate::error),
+//! [`warn!`](crate::warn), [`info!`](crate::info), [`debug!`](crate::debug)
+//! and [`trace!`](crate::trace) macros, which tag them with the module they
+//! originate from and the current uptime. Records passing the level filter
+//! are stored in the [`ring`] and handed to every registered [`Sink`].
+//!
+//! Logging doesn't allocate so it can be used before the heap is set up and
+//! from exception handlers.
+pub mod font;
+pub mod framebuffer;
+pub mod ring;
+
+use crate::time;
+use core::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+    time::Duration,
+};
+use x86_64::{interrupts::without_interrupts, mutex::Mutex, print::SERIAL, println};
+
+pub const MAX_SINKS: usize = 8;
+pub const MAX_FILTERS: usize = 16;
+pub const MAX_TARGET_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Error),
+            2 => Some(Self::Warn),
+            3 => Some(Self::Info),
+            4 => Some(Self::Debug),
+            5 => Some(Self::Trace),
+            _ => None,
+        }
+    }
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+            Self::Trace => "TRACE",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        [
+            Self::Error,
+            Self::Warn,
+            Self::Info,
+            Self::Debug,
+            Self::Trace,
+        ]
+        .into_iter()
+        .find(|level| level.as_str().eq_ignore_ascii_case(s))
+        .ok_or(())
+    }
+}
+
+/// A single log message
+pub struct Record<'a> {
+    pub level: Level,
+    /// Module path of the code that logged the message
+    pub target: &'a str,
+    /// Uptime when the message was logged
+    pub timestamp: Duration,
+    pub args: fmt::Arguments<'a>,
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:>5}.{:06}] {:<5} {}: {}",
+            self.timestamp.as_secs(),
+            self.timestamp.subsec_micros(),
+            self.level,
+            self.target,
+            self.args
+        )
+    }
+}
+
+/// Destination of log records
+pub trait Sink: Send + Sync {
+    fn write(&self, record: &Record);
+}
+
+/// Writes records to COM1
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn write(&self, record: &Record) {
+        println!("{}", record);
+    }
+}
+
+static SERIAL_SINK: SerialSink = SerialSink;
+
+static SINKS: Mutex<[Option<&'static dyn Sink>; MAX_SINKS]> = Mutex::new([None; MAX_SINKS]);
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Level override for all targets starting with `target`
+#[derive(Clone, Copy)]
+struct Filter {
+    target: [u8; MAX_TARGET_LENGTH],
+    len: usize,
+    level: Level,
+}
+
+impl Filter {
+    fn target(&self) -> &[u8] {
+        &self.target[..self.len]
+    }
+
+    /// Whether the filter applies to `target`, only matches whole path
+    /// components
+    fn matches(&self, target: &str) -> bool {
+        let target = target.as_bytes();
+        target.starts_with(self.target())
+            && (target.len() == self.len || target[self.len..].starts_with(b"::"))
+    }
+}
+
+static FILTERS: Mutex<[Option<Filter>; MAX_FILTERS]> = Mutex::new([None; MAX_FILTERS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogError {
+    TooManySinks,
+    TooManyFilters,
+    TargetTooLong,
+}
+
+/// Starts logging to the serial port. Records logged before are replayed from
+/// the ring.
+pub fn init() {
+    let mut buf = [0; 256];
+    let (mut position, end) = ring::RING.bounds();
+    while position < end {
+        let (start, len) = ring::RING.read_at(position, &mut buf);
+        let serial = SERIAL.lock();
+        buf[..len].iter().for_each(|byte| serial.send(*byte));
+        position = start + len;
+    }
+
+    register_sink(&SERIAL_SINK).unwrap();
+}
+
+/// Adds a sink that receives all records logged from now on
+pub fn register_sink(sink: &'static dyn Sink) -> Result<(), LogError> {
+    without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        let slot = sinks
+            .iter_mut()
+            .find(|s| s.is_none())
+            .ok_or(LogError::TooManySinks)?;
+        *slot = Some(sink);
+        Ok(())
+    })
+}
+
+/// Sets the level for targets without a filter
+pub fn set_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed)).unwrap()
+}
+
+/// Sets the level of `target` and its submodules, None removes the filter
+pub fn set_target_level(target: &str, level: Option<Level>) -> Result<(), LogError> {
+    if target.len() > MAX_TARGET_LENGTH {
+        return Err(LogError::TargetTooLong);
+    }
+
+    without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        let existing = filters
+            .iter_mut()
+            .find(|f| f.is_some_and(|f| f.target() == target.as_bytes()));
+
+        match (existing, level) {
+            (Some(filter), Some(level)) => filter.as_mut().unwrap().level = level,
+            (Some(filter), None) => *filter = None,
+            (None, Some(level)) => {
+                let slot = filters
+                    .iter_mut()
+                    .find(|f| f.is_none())
+                    .ok_or(LogError::TooManyFilters)?;
+                let mut filter = Filter {
+                    target: [0; MAX_TARGET_LENGTH],
+                    len: target.len(),
+                    level,
+                };
+                filter.target[..target.len()].copy_from_slice(target.as_bytes());
+                *slot = Some(filter);
+            }
+            (None, None) => (),
+        }
+        Ok(())
+    })
+}
+
+/// Calls `f` with every target filter and its level
+pub fn for_each_filter(mut f: impl FnMut(&str, Level)) {
+    let filters = without_interrupts(|| *FILTERS.lock());
+    for filter in filters.iter().flatten() {
+        // filters are only created from a &str
+        f(core::str::from_utf8(filter.target()).unwrap(), filter.level);
+    }
+}
+
+/// Whether a record of `level` logged by `target` passes the filters. The
+/// most specific filter matching the target takes precedence.
+pub fn enabled(level: Level, target: &str) -> bool {
+    let max = without_interrupts(|| {
+        FILTERS
+            .lock()
+            .iter()
+            .flatten()
+            .filter(|f| f.matches(target))
+            .max_by_key(|f| f.len)
+            .map(|f| f.level)
+    })
+    .unwrap_or_else(self::level);
+
+    level <= max
+}
+
+#[doc(hidden)]
+pub fn log(level: Level, target: &str, args: fmt::Arguments) {
+    if !enabled(level, target) {
+        return;
+    }
+
+    let record = Record {
+        level,
+        target,
+        timestamp: time::uptime(),
+        args,
+    };
+    ring::RING.push(&record);
+
+    // sinks might log themselves, so don't hold the lock while writing
+    let sinks = without_interrupts(|| *SINKS.lock());
+    for sink in sinks.iter().flatten() {
+        sink.write(&record);
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log::log($level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Error, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Warn, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Info, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Debug, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => ($crate::log!($crate::log::Level::Trace, $($arg)+));
+}
@@ -0,0 +1,175 @@
+//! In-memory log of the most recent records.
+//!
+//! Every record is written here before it is handed to the sinks, so the log
+//! also contains everything logged before the serial port was set up. The
+//! ring keeps the last [`RING_SIZE`] bytes, older output is overwritten.
+//!
+//! Writers reserve space with an atomic add and never wait for each other.
+//! Interrupts are disabled while a record is copied so a reader running on
+//! this CPU only ever sees complete records. Readers detect output that was
+//! overwritten while they copied it and drop it.
+extern crate alloc;
+use super::Record;
+use crate::fs::{vfs, FsError, NodeKind};
+use alloc::{vec, vec::Vec};
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::interrupts::without_interrupts;
+
+pub const RING_SIZE: usize = 16 * 1024;
+/// Longer records are truncated
+pub const MAX_RECORD_SIZE: usize = 512;
+
+pub static RING: Ring = Ring::new();
+
+pub struct Ring {
+    buffer: UnsafeCell<[u8; RING_SIZE]>,
+    /// Total amount of bytes reserved by writers, the next write starts at
+    /// this modulo the size of the buffer
+    reserved: AtomicUsize,
+    /// Total amount of bytes completely written
+    committed: AtomicUsize,
+    /// Output before this position was cleared
+    start: AtomicUsize,
+}
+
+unsafe impl Sync for Ring {}
+
+/// Formats a record into a fixed buffer, cutting it off if it's too long
+struct RecordBuffer {
+    data: [u8; MAX_RECORD_SIZE],
+    len: usize,
+}
+
+impl Write for RecordBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(MAX_RECORD_SIZE - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            buffer: UnsafeCell::new([0; RING_SIZE]),
+            reserved: AtomicUsize::new(0),
+            committed: AtomicUsize::new(0),
+            start: AtomicUsize::new(0),
+        }
+    }
+
+    pub(super) fn push(&self, record: &Record) {
+        let mut buf = RecordBuffer {
+            data: [0; MAX_RECORD_SIZE],
+            len: 0,
+        };
+        let _ = write!(buf, "{}", record);
+        // keep the newline even if the record was cut off
+        buf.len = buf.len.min(MAX_RECORD_SIZE - 1);
+        buf.data[buf.len] = b'\n';
+        buf.len += 1;
+
+        self.write(&buf.data[..buf.len]);
+    }
+
+    fn write(&self, data: &[u8]) {
+        without_interrupts(|| {
+            let position = self.reserved.fetch_add(data.len(), Ordering::AcqRel);
+            let buffer = self.buffer.get() as *mut u8;
+            for (i, byte) in data.iter().enumerate() {
+                unsafe { buffer.add((position + i) % RING_SIZE).write(*byte) };
+            }
+            self.committed.fetch_add(data.len(), Ordering::Release);
+        });
+    }
+
+    /// Positions of the oldest stored byte and of the end of the output,
+    /// counted in bytes since boot
+    pub fn bounds(&self) -> (usize, usize) {
+        let end = self.committed.load(Ordering::Acquire);
+        let start = self
+            .start
+            .load(Ordering::Relaxed)
+            .max(end.saturating_sub(RING_SIZE));
+        (start.min(end), end)
+    }
+
+    /// Amount of bytes currently stored
+    pub fn len(&self) -> usize {
+        let (start, end) = self.bounds();
+        end - start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies the output starting at `position` into `buf`. Returns the
+    /// position of the first copied byte, which is behind `position` if that
+    /// part was overwritten already, and the amount of bytes copied.
+    pub fn read_at(&self, position: usize, buf: &mut [u8]) -> (usize, usize) {
+        let (start, end) = self.bounds();
+        let mut position = position.clamp(start, end);
+        let len = (end - position).min(buf.len());
+
+        let buffer = self.buffer.get() as *const u8;
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = unsafe { buffer.add((position + i) % RING_SIZE).read() };
+        }
+
+        // writers might have overwritten the oldest part while copying
+        let overwritten = self
+            .reserved
+            .load(Ordering::Acquire)
+            .saturating_sub(RING_SIZE);
+        let skip = overwritten.saturating_sub(position).min(len);
+        buf.copy_within(skip..len, 0);
+        position += skip;
+
+        (position, len - skip)
+    }
+
+    /// Copies the most recent output into `buf`, returns the amount of bytes
+    /// copied. The first line might be cut off if the ring wrapped around.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let (start, end) = self.bounds();
+        let position = start.max(end.saturating_sub(buf.len()));
+        self.read_at(position, buf).1
+    }
+
+    /// Contents of the ring
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = vec![0; RING_SIZE];
+        let len = self.read(&mut buf);
+        buf.truncate(len);
+        buf
+    }
+
+    /// Drops all stored output
+    pub fn clear(&self) {
+        self.start
+            .store(self.committed.load(Ordering::Acquire), Ordering::Relaxed);
+    }
+
+    /// Writes the contents of the ring to the file at `path`, replacing it if
+    /// it exists. Returns the amount of bytes written.
+    pub fn flush(&self, path: &str) -> Result<usize, FsError> {
+        let data = self.to_vec();
+        match vfs::unlink(path) {
+            Ok(()) | Err(FsError::NotFound) => (),
+            Err(error) => return Err(error),
+        }
+
+        let file = vfs::create(path, NodeKind::File)?.open()?;
+        let mut written = 0;
+        while written < data.len() {
+            written += file.write(&data[written..])?;
+        }
+        Ok(written)
+    }
+}
@@ -0,0 +1,81 @@
+//! Buffers for devices that access memory directly.
+//!
+//! DMA buffers are physically contiguous and are accessed through the physical
+//! memory mapping. Devices that can only address part of the physical address
+//! space, e.g. the low 4 GiB, get a buffer below that limit. Data located
+//! elsewhere is copied through such a buffer (bounce buffer).
+use core::slice;
+use x86_64::memory::{Address, PhysicalAddress, PhysicalFrame, VirtualAddress};
+
+/// Highest address reachable by devices with 32-bit DMA
+pub const DMA_32BIT_LIMIT: u64 = 1 << 32;
+
+/// Physically contiguous buffer allocated by
+/// [`MemoryManager::allocate_dma`](super::MemoryManager::allocate_dma)
+#[derive(Debug)]
+pub struct DmaBuffer {
+    pub(super) frame: PhysicalFrame,
+    pub(super) frames: usize,
+    pub(super) address: VirtualAddress,
+    pub(super) len: usize,
+}
+
+impl DmaBuffer {
+    /// Address to program into the device
+    pub fn physical_address(&self) -> PhysicalAddress {
+        self.frame.address
+    }
+
+    pub fn virtual_address(&self) -> VirtualAddress {
+        self.address
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The buffer as a single scatter-gather segment
+    pub fn segment(&self) -> DmaSegment {
+        DmaSegment {
+            address: self.physical_address(),
+            len: self.len as u64,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.address.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.address.as_mut_ptr(), self.len) }
+    }
+
+    /// Bounces `data` into the buffer before a device reads it
+    pub fn copy_from(&mut self, data: &[u8]) {
+        self.as_mut_slice()[..data.len()].copy_from_slice(data);
+    }
+
+    /// Bounces the buffer contents out to `data` after a device wrote them
+    pub fn copy_to(&self, data: &mut [u8]) {
+        data.copy_from_slice(&self.as_slice()[..data.len()]);
+    }
+}
+
+/// Physically contiguous part of a buffer, one entry of a scatter-gather list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaSegment {
+    pub address: PhysicalAddress,
+    pub len: u64,
+}
+
+impl DmaSegment {
+    /// Whether a device limited to addresses below `limit` can access the
+    /// whole segment
+    pub fn is_below(&self, limit: u64) -> bool {
+        self.address.as_u64() + self.len <= limit
+    }
+}
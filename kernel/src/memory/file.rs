@@ -0,0 +1,19 @@
+//! Interface between the memory manager and whatever provides file contents.
+//!
+//! There is no VFS yet, so anything that is able to read and write pages at a
+//! byte offset can be mapped into memory.
+
+/// A file whose contents can be mapped into virtual memory
+pub trait MappableFile: Send + Sync {
+    /// Size of the file in bytes
+    fn size(&self) -> u64;
+
+    /// Reads up to `buf.len()` bytes starting at `offset` into `buf`.
+    /// Returns the amount of bytes read. Bytes past the end of the file are
+    /// not touched.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> usize;
+
+    /// Writes `buf` to the file starting at `offset`. Returns the amount of
+    /// bytes written.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> usize;
+}
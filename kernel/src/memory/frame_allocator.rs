@@ -0,0 +1,605 @@
+//! Physical frame allocator of the kernel.
+//!
+//! Free memory is kept in blocks of 2^order frames that are aligned to their
+//! size, like in a buddy allocator. There is one doubly linked list of free
+//! blocks per order. The header of each block is stored in its first frame and
+//! accessed through the physical memory mapping, so the allocator doesn't
+//! need any memory of its own.
+//!
+//! Allocations split the smallest block that fits, frees merge a block with
+//! its buddy as long as the buddy is free as well. Both take O(log n) steps.
+//! Whether the buddy is free is told by its header, which only counts if the
+//! list it claims to be in links back to it, since an allocated frame may
+//! contain anything.
+//!
+//! A block has to be aligned to its size, so `count` free frames might not
+//! form a block large enough even though they are contiguous. Only then free
+//! blocks are drawn into a bitmap to search for a run of free frames.
+use bitmap::Bitmap;
+use core::{
+    fmt,
+    ptr::{self, addr_of_mut},
+};
+use x86_64::{
+    memory::{
+        FrameAllocator, FrameDeallocator, MemoryRegion, PageSize, PhysicalAddress, PhysicalFrame,
+        PhysicalMemoryRegion, PhysicalMemoryRegionType, Size4KiB,
+    },
+    paging::{mapped_page_table::PageTableFrameMapping, offset_page_table::PhysicalOffset},
+};
+
+/// Blocks have at most 2^(ORDERS - 1) frames, i.e. 2 GiB
+const ORDERS: usize = 20;
+
+/// Frames of physical memory the bitmap covers at once, i.e. 1 GiB. Windows
+/// overlap by half, so runs up to half a window are found anywhere.
+const WINDOW_FRAMES: usize = 1 << 18;
+/// Only used by the allocator of the memory manager, which is locked
+static mut WINDOW: [u64; bitmap::words_for(WINDOW_FRAMES)] = [0; bitmap::words_for(WINDOW_FRAMES)];
+
+/// Header of a free block. Raw addresses, 0 if there is no block, since the
+/// header of an allocated frame is garbage.
+#[repr(C)]
+struct FreeBlock {
+    order: u64,
+    previous: u64,
+    next: u64,
+}
+
+/// Frame counts of the allocator. `total` is the sum of the other counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Usable frames in the memory map, including reclaimed memory
+    pub total: usize,
+    pub free: usize,
+    pub allocated: usize,
+    /// Usable frames that were in use before the allocator took over
+    pub reserved: usize,
+    /// Number of free blocks, a measure of fragmentation
+    pub free_ranges: usize,
+    /// Frames in the largest free block, the upper bound for a contiguous
+    /// allocation
+    pub largest_free: usize,
+}
+
+/// Free frames within a usable region of the memory map
+#[derive(Debug, Clone, Copy)]
+pub struct RegionUsage {
+    pub region: PhysicalMemoryRegion,
+    pub total: usize,
+    pub free: usize,
+}
+
+/// An allocation that couldn't be satisfied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfFrames {
+    pub requested: usize,
+    pub alignment: u64,
+    pub stats: FrameStats,
+}
+
+impl fmt::Display for OutOfFrames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no {} contiguous frames aligned to {:#x}: {} of {} frames free in {} ranges, largest {}",
+            self.requested,
+            self.alignment,
+            self.stats.free,
+            self.stats.total,
+            self.stats.free_ranges,
+            self.stats.largest_free
+        )
+    }
+}
+
+pub struct LinkedListFrameAllocator {
+    /// First free block of every order
+    free_lists: [Option<PhysicalFrame>; ORDERS],
+    mapping: PhysicalOffset,
+    regions: &'static [PhysicalMemoryRegion],
+    total: usize,
+    reserved: usize,
+    allocated: usize,
+    free: usize,
+    /// Whether the reclaimable bootloader and ACPI memory was taken over
+    reclaimed_bootloader: bool,
+    reclaimed_acpi: bool,
+}
+
+impl LinkedListFrameAllocator {
+    /// Creates an allocator owning `frames`, e.g. the frames the bump
+    /// allocator didn't hand out during early boot. All other usable frames of
+    /// `regions` count as reserved.
+    ///
+    /// # Safety
+    ///
+    /// The frames must be unused and accessible through `mapping`
+    pub unsafe fn new<I>(
+        regions: &'static [PhysicalMemoryRegion],
+        frames: I,
+        mapping: PhysicalOffset,
+    ) -> Self
+    where
+        I: Iterator<Item = PhysicalFrame>,
+    {
+        let total = regions
+            .iter()
+            .filter(|r| r.is_usable())
+            .map(region_frames)
+            .sum();
+        let mut allocator = Self {
+            free_lists: [None; ORDERS],
+            mapping,
+            regions,
+            total,
+            reserved: 0,
+            allocated: 0,
+            free: 0,
+            reclaimed_bootloader: false,
+            reclaimed_acpi: false,
+        };
+
+        let mut run: Option<(PhysicalFrame, u64)> = None;
+        for frame in frames {
+            run = match run {
+                Some((start, count)) if start + count == frame => Some((start, count + 1)),
+                Some((start, count)) => {
+                    unsafe { allocator.deallocate_contiguous(start, count as usize) };
+                    Some((frame, 1))
+                }
+                None => Some((frame, 1)),
+            };
+        }
+        if let Some((start, count)) = run {
+            unsafe { allocator.deallocate_contiguous(start, count as usize) };
+        }
+
+        allocator.reserved = total.saturating_sub(allocator.free);
+        allocator
+    }
+
+    /// Takes over the memory the bootloader only needed to boot. Returns the
+    /// number of reclaimed frames.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use the memory of the bootloader stages anymore, e.g. the
+    /// GDT of the bootloader must have been replaced
+    pub unsafe fn reclaim_bootloader_memory(&mut self) -> usize {
+        if self.reclaimed_bootloader {
+            return 0;
+        }
+        self.reclaimed_bootloader = true;
+        unsafe { self.reclaim(PhysicalMemoryRegionType::BootloaderReclaimable) }
+    }
+
+    /// Takes over the memory of the ACPI tables. Returns the number of
+    /// reclaimed frames.
+    ///
+    /// # Safety
+    ///
+    /// The tables must not be read from physical memory anymore, see
+    /// [`acpi::copy_tables`](crate::acpi::copy_tables)
+    pub unsafe fn reclaim_acpi_memory(&mut self) -> usize {
+        if self.reclaimed_acpi {
+            return 0;
+        }
+        self.reclaimed_acpi = true;
+        unsafe { self.reclaim(PhysicalMemoryRegionType::AcpiReclaimable) }
+    }
+
+    /// Takes over the frames of `size` bytes at `start` that the bootloader
+    /// handed to the kernel, e.g. the initramfs. Returns the number of
+    /// reclaimed frames.
+    ///
+    /// # Safety
+    ///
+    /// The frames must not be shared with anything else and not be used
+    /// anymore
+    pub unsafe fn reclaim_range(&mut self, start: PhysicalAddress, size: u64) -> usize {
+        let first = PhysicalFrame::containing_address(start);
+        let frames = (start - first.address + size).div_ceil(Size4KiB::SIZE) as usize;
+        if frames == 0 {
+            return 0;
+        }
+        unsafe { self.insert(first, frames) };
+        self.total += frames;
+        frames
+    }
+
+    unsafe fn reclaim(&mut self, typ: PhysicalMemoryRegionType) -> usize {
+        let mut reclaimed = 0;
+        for region in self.regions {
+            if region.typ != typ {
+                continue;
+            }
+            let frames = region_frames(region);
+            if frames == 0 {
+                continue;
+            }
+
+            let frame = PhysicalFrame::containing_address(region.address());
+            unsafe { self.insert(frame, frames) };
+            reclaimed += frames;
+        }
+
+        self.total += reclaimed;
+        reclaimed
+    }
+
+    /// Number of frames currently handed out
+    pub fn allocated_frames(&self) -> usize {
+        self.allocated
+    }
+
+    /// Number of frames available for allocation
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        let mut stats = FrameStats {
+            total: self.total,
+            free: self.free,
+            allocated: self.allocated,
+            reserved: self.reserved,
+            free_ranges: 0,
+            largest_free: 0,
+        };
+        for (_, frames) in self.free_ranges() {
+            stats.free_ranges += 1;
+            stats.largest_free = stats.largest_free.max(frames as usize);
+        }
+        stats
+    }
+
+    /// Free frames per usable region of the memory map, including reclaimed
+    /// memory
+    pub fn region_usage(&self) -> impl Iterator<Item = RegionUsage> + '_ {
+        self.regions.iter().filter(|r| self.owns(r)).map(|region| {
+            let start = region.start() & !(Size4KiB::SIZE - 1);
+            let total = region_frames(region);
+            let end = start + total as u64 * Size4KiB::SIZE;
+            let free = self
+                .free_ranges()
+                .map(|(first, frames)| {
+                    let first = first.start();
+                    let last = first + frames * Size4KiB::SIZE;
+                    last.min(end).saturating_sub(first.max(start)) / Size4KiB::SIZE
+                })
+                .sum::<u64>();
+            RegionUsage {
+                region: *region,
+                total,
+                free: free as usize,
+            }
+        })
+    }
+
+    /// Allocates like [`FrameAllocator::allocate_contiguous`] but reports why
+    /// an allocation failed
+    pub fn try_allocate(
+        &mut self,
+        count: usize,
+        alignment: u64,
+    ) -> Result<PhysicalFrame, OutOfFrames> {
+        self.try_allocate_below(count, alignment, u64::MAX)
+    }
+
+    /// Like [`try_allocate`](Self::try_allocate), but all frames end at or
+    /// below the physical address `limit`, e.g. for devices that can only
+    /// address the low 4 GiB
+    pub fn try_allocate_below(
+        &mut self,
+        count: usize,
+        alignment: u64,
+        limit: u64,
+    ) -> Result<PhysicalFrame, OutOfFrames> {
+        self.allocate(count, alignment, limit)
+            .ok_or_else(|| OutOfFrames {
+                requested: count,
+                alignment,
+                stats: self.stats(),
+            })
+    }
+
+    /// First frame and length of every free block
+    fn free_ranges(&self) -> impl Iterator<Item = (PhysicalFrame, u64)> + '_ {
+        (0..ORDERS).flat_map(move |order| {
+            let mut current = self.free_lists[order];
+            core::iter::from_fn(move || {
+                let block = current?;
+                current = frame(self.header(block).next);
+                Some((block, 1 << order))
+            })
+        })
+    }
+
+    /// Allocates `count` frames aligned to `alignment` that end at or below
+    /// `limit`
+    fn allocate(&mut self, count: usize, alignment: u64, limit: u64) -> Option<PhysicalFrame> {
+        assert!(count > 0 && alignment.is_power_of_two());
+        let alignment = alignment.max(Size4KiB::SIZE) / Size4KiB::SIZE;
+        let frame = self
+            .allocate_block(count, alignment, limit)
+            .or_else(|| self.allocate_run(count, alignment, limit))?;
+
+        self.allocated += count;
+        self.free -= count;
+        Some(frame)
+    }
+
+    /// Takes the smallest free block that fits the allocation. The frames
+    /// behind the allocation are freed again.
+    fn allocate_block(
+        &mut self,
+        count: usize,
+        alignment: u64,
+        limit: u64,
+    ) -> Option<PhysicalFrame> {
+        let order = (count.next_power_of_two().trailing_zeros() as usize)
+            .max(alignment.trailing_zeros() as usize);
+        let size = (1 << order) * Size4KiB::SIZE;
+
+        // blocks are aligned to their size, so the lower half of a larger one
+        // ends below the limit if the larger one starts low enough
+        let (block, block_order) = (order..ORDERS).find_map(|block_order| {
+            let mut current = self.free_lists[block_order];
+            while let Some(block) = current {
+                if block.start() + size <= limit {
+                    return Some((block, block_order));
+                }
+                current = frame(self.header(block).next);
+            }
+            None
+        })?;
+
+        self.unlink(block, block_order);
+        for half in (order..block_order).rev() {
+            self.push(block + (1 << half), half);
+        }
+        self.release(block + count as u64, (1 << order) - count as u64);
+        Some(block)
+    }
+
+    /// Searches the free blocks for a run of frames spanning several of them
+    fn allocate_run(&mut self, count: usize, alignment: u64, limit: u64) -> Option<PhysicalFrame> {
+        // windows start at multiples of half a window
+        if count > WINDOW_FRAMES / 2 || alignment > WINDOW_FRAMES as u64 / 2 {
+            return None;
+        }
+        let end = self
+            .regions
+            .iter()
+            .filter(|r| self.owns(r))
+            .map(|r| r.start() / Size4KiB::SIZE + region_frames(r) as u64)
+            .max()?
+            .min(limit / Size4KiB::SIZE);
+
+        let words = unsafe { &mut *addr_of_mut!(WINDOW) };
+        let mut base = 0;
+        while base < end {
+            let mut window = Bitmap::new(&mut words[..], WINDOW_FRAMES);
+            window.fill(true);
+            for (block, frames) in self.free_ranges() {
+                let first = block.start() / Size4KiB::SIZE;
+                let (start, stop) = (
+                    first.max(base),
+                    (first + frames).min(base + WINDOW_FRAMES as u64),
+                );
+                if start < stop {
+                    window.clear_range((start - base) as usize..(stop - base) as usize);
+                }
+            }
+
+            let window_limit = (end - base).min(WINDOW_FRAMES as u64) as usize;
+            if let Some(start) =
+                window.find_free_run_aligned(count, alignment as usize, window_limit)
+            {
+                let first = frame_at(base + start as u64);
+                self.take_run(first, count as u64);
+                return Some(first);
+            }
+            base += WINDOW_FRAMES as u64 / 2;
+        }
+        None
+    }
+
+    /// Unlinks the free blocks covering the `count` frames from `first` on and
+    /// frees the parts of them outside of the run again
+    fn take_run(&mut self, first: PhysicalFrame, count: u64) {
+        let end = first + count;
+        let mut current = first;
+        while current < end {
+            let (block, order) = self
+                .containing_block(current)
+                .expect("Frame of a free run is not free");
+            self.unlink(block, order);
+
+            let block_end = block + (1 << order);
+            if block < first {
+                self.release(block, (first.start() - block.start()) / Size4KiB::SIZE);
+            }
+            if block_end > end {
+                self.release(end, (block_end.start() - end.start()) / Size4KiB::SIZE);
+            }
+            current = block_end;
+        }
+    }
+
+    /// The free block containing `frame` and its order
+    fn containing_block(&self, frame: PhysicalFrame) -> Option<(PhysicalFrame, usize)> {
+        let index = frame.start() / Size4KiB::SIZE;
+        (0..ORDERS).find_map(|order| {
+            let block = frame_at(index & !((1 << order) - 1));
+            (self.free_order(block) == Some(order)).then_some((block, order))
+        })
+    }
+
+    /// Frees frames without counting them, as the blocks they decompose into
+    fn release(&mut self, mut frame: PhysicalFrame, mut count: u64) {
+        while count > 0 {
+            let index = frame.start() / Size4KiB::SIZE;
+            let order = (index.trailing_zeros() as usize)
+                .min(count.ilog2() as usize)
+                .min(ORDERS - 1);
+
+            assert!(
+                self.free_order(frame).is_none(),
+                "Frame {} is already free",
+                frame
+            );
+            self.free_block(frame, order);
+            frame += 1 << order;
+            count -= 1 << order;
+        }
+    }
+
+    /// Puts a block onto its free list, merged with its buddy as often as
+    /// possible
+    fn free_block(&mut self, mut block: PhysicalFrame, mut order: usize) {
+        while order + 1 < ORDERS {
+            let index = block.start() / Size4KiB::SIZE;
+            let buddy = PhysicalFrame::containing_address(PhysicalAddress::new(
+                (index ^ (1 << order)) * Size4KiB::SIZE,
+            ));
+            if self.free_order(buddy) != Some(order) {
+                break;
+            }
+
+            self.unlink(buddy, order);
+            block = block.min(buddy);
+            order += 1;
+        }
+        self.push(block, order);
+    }
+
+    /// Order of the free block starting at `block`, None if it isn't the
+    /// start of one
+    fn free_order(&self, block: PhysicalFrame) -> Option<usize> {
+        // never read frames that aren't memory
+        if !self.manages(block.start(), 1) {
+            return None;
+        }
+
+        let header = unsafe { ptr::read(self.header(block)) };
+        let order = usize::try_from(header.order).ok().filter(|&o| o < ORDERS)?;
+        let index = block.start() / Size4KiB::SIZE;
+        if index % (1 << order) != 0 || !self.manages(block.start(), 1 << order) {
+            return None;
+        }
+
+        let linked = match frame(header.previous) {
+            None => self.free_lists[order] == Some(block),
+            Some(previous) if self.manages(previous.start(), 1) => {
+                self.header(previous).next == block.start()
+            }
+            Some(_) => false,
+        };
+        linked.then_some(order)
+    }
+
+    /// Whether the frames lie within memory the allocator hands out. Blocks
+    /// may span neighbouring regions, which are sorted.
+    fn manages(&self, start: u64, frames: u64) -> bool {
+        let end = start + frames * Size4KiB::SIZE;
+        let mut covered = start;
+        for region in self.regions {
+            let region_end = region.start() + region_frames(region) as u64 * Size4KiB::SIZE;
+            if self.owns(region) && region.start() <= covered && covered < region_end {
+                covered = region_end;
+            }
+        }
+        covered >= end
+    }
+
+    /// Whether the frames of `region` belong to the allocator
+    fn owns(&self, region: &PhysicalMemoryRegion) -> bool {
+        match region.typ {
+            PhysicalMemoryRegionType::Free => true,
+            PhysicalMemoryRegionType::BootloaderReclaimable => self.reclaimed_bootloader,
+            PhysicalMemoryRegionType::AcpiReclaimable => self.reclaimed_acpi,
+            _ => false,
+        }
+    }
+
+    fn push(&mut self, block: PhysicalFrame, order: usize) {
+        let next = self.free_lists[order];
+        *self.header(block) = FreeBlock {
+            order: order as u64,
+            previous: 0,
+            next: next.map_or(0, |next| next.start()),
+        };
+        if let Some(next) = next {
+            self.header(next).previous = block.start();
+        }
+        self.free_lists[order] = Some(block);
+    }
+
+    fn unlink(&mut self, block: PhysicalFrame, order: usize) {
+        let (previous, next) = {
+            let header = self.header(block);
+            (header.previous, header.next)
+        };
+        match frame(previous) {
+            Some(previous) => self.header(previous).next = next,
+            None => self.free_lists[order] = frame(next),
+        }
+        if let Some(next) = frame(next) {
+            self.header(next).previous = previous;
+        }
+    }
+
+    fn header(&self, block: PhysicalFrame) -> &'static mut FreeBlock {
+        let address = self.mapping.frame_to_virtual(block);
+        unsafe { &mut *address.as_mut_ptr::<FreeBlock>() }
+    }
+}
+
+/// Frame of a raw address of a header, 0 is none
+fn frame(address: u64) -> Option<PhysicalFrame> {
+    (address != 0).then(|| PhysicalFrame::containing_address(PhysicalAddress::new(address)))
+}
+
+/// Frame number `index`
+fn frame_at(index: u64) -> PhysicalFrame {
+    PhysicalFrame::containing_address(PhysicalAddress::new(index * Size4KiB::SIZE))
+}
+
+unsafe impl FrameAllocator<Size4KiB> for LinkedListFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysicalFrame> {
+        self.allocate_contiguous(1, Size4KiB::SIZE)
+    }
+
+    fn allocate_contiguous(&mut self, count: usize, alignment: u64) -> Option<PhysicalFrame> {
+        self.allocate(count, alignment, u64::MAX)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for LinkedListFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysicalFrame) {
+        unsafe { self.deallocate_contiguous(frame, 1) }
+    }
+
+    unsafe fn deallocate_contiguous(&mut self, frame: PhysicalFrame, count: usize) {
+        unsafe { self.insert(frame, count) };
+
+        // frames passed to `new` were never counted as allocated
+        self.allocated = self.allocated.saturating_sub(count);
+    }
+}
+
+impl LinkedListFrameAllocator {
+    /// Frees frames that weren't handed out by this allocator
+    unsafe fn insert(&mut self, frame: PhysicalFrame, count: usize) {
+        assert!(count > 0);
+        self.release(frame, count as u64);
+        self.free += count;
+    }
+}
+
+/// Number of frames the bump allocator hands out for `region`
+fn region_frames(region: &PhysicalMemoryRegion) -> usize {
+    region.size().div_ceil(Size4KiB::SIZE) as usize
+}
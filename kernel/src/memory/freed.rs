@@ -0,0 +1,130 @@
+//! Unmap-on-free debug mode.
+//!
+//! Enabled with the `unmap-on-free` cargo feature. Large heap allocations get
+//! their own pages and dead thread stacks are unmapped, and since the memory
+//! manager never reuses virtual addresses any later access through a dangling
+//! pointer page faults. The freed ranges are remembered so the page fault
+//! handler can name their previous owner.
+use super::{VirtualMemoryObject, MEMORY_MANAGER};
+use crate::scheduler::thread::ThreadId;
+use core::{fmt, mem};
+use x86_64::{
+    interrupts::without_interrupts, memory::VirtualAddress, mutex::Mutex,
+    paging::PageTableEntryFlags,
+};
+
+/// Freed ranges that are remembered, older ones are forgotten
+const HISTORY_SIZE: usize = 64;
+/// Frees that had to wait because the memory manager was locked
+const PENDING_SIZE: usize = 16;
+
+static FREED: Mutex<FreedRanges> = Mutex::new(FreedRanges {
+    entries: [None; HISTORY_SIZE],
+    next: 0,
+    pending: [None; PENDING_SIZE],
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    Thread(ThreadId),
+    /// Heap allocation of the given size
+    Allocation(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreedRange {
+    pub start: VirtualAddress,
+    pub size: u64,
+    pub owner: Owner,
+}
+
+impl fmt::Display for FreedRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x} - {:#x} ", self.start, self.start + self.size)?;
+        match self.owner {
+            Owner::Thread(id) => write!(f, "stack of thread {}", id),
+            Owner::Allocation(size) => write!(f, "heap allocation of {} bytes", size),
+        }
+    }
+}
+
+struct FreedRanges {
+    entries: [Option<FreedRange>; HISTORY_SIZE],
+    next: usize,
+    pending: [Option<FreedRange>; PENDING_SIZE],
+}
+
+/// Remembers that `range` was unmapped
+pub fn record(range: FreedRange) {
+    without_interrupts(|| {
+        let mut freed = FREED.lock();
+        let idx = freed.next;
+        freed.entries[idx] = Some(range);
+        freed.next = (idx + 1) % HISTORY_SIZE;
+    });
+}
+
+/// Finds the freed range containing `address`
+pub fn lookup(address: VirtualAddress) -> Option<FreedRange> {
+    without_interrupts(|| {
+        let freed = FREED.lock();
+        freed
+            .entries
+            .iter()
+            .flatten()
+            .find(|r| r.start <= address && address < r.start + r.size)
+            .copied()
+    })
+}
+
+/// Maps zeroed pages for a large heap allocation. Returns None instead of
+/// waiting if the memory manager is locked, e.g. when it allocates itself.
+pub fn allocate_pages(size: usize) -> Option<VirtualAddress> {
+    let mut guard = MEMORY_MANAGER.try_lock()?;
+    let mm = guard.as_mut()?;
+    mm.allocate(
+        size as u64,
+        PageTableEntryFlags::PRESENT
+            | PageTableEntryFlags::WRITABLE
+            | PageTableEntryFlags::NO_EXECUTE,
+        VirtualMemoryObject::Anonymous,
+    )
+    .ok()
+}
+
+/// Unmaps the pages of a large heap allocation. If the memory manager is
+/// locked the pages are unmapped by a later call.
+pub fn free_pages(start: VirtualAddress, size: usize) {
+    let range = FreedRange {
+        start,
+        size: size as u64,
+        owner: Owner::Allocation(size),
+    };
+
+    let mut guard = match MEMORY_MANAGER.try_lock() {
+        Some(guard) => guard,
+        None => {
+            defer(range);
+            return;
+        }
+    };
+    let mm = guard.as_mut().expect("Memory manager not initialized");
+
+    let pending =
+        without_interrupts(|| mem::replace(&mut FREED.lock().pending, [None; PENDING_SIZE]));
+    for range in pending.iter().flatten().chain(Some(&range)) {
+        mm.munmap(range.start)
+            .expect("Large allocation is not mapped");
+        record(*range);
+    }
+}
+
+/// Queues an unmap, the pages are leaked if the queue is full
+fn defer(range: FreedRange) {
+    without_interrupts(|| {
+        let mut freed = FREED.lock();
+        if let Some(slot) = freed.pending.iter_mut().find(|e| e.is_none()) {
+            *slot = Some(range);
+        }
+    });
+}
@@ -0,0 +1,81 @@
+//! Device registers mapped into the MMIO zone.
+//!
+//! Drivers map the registers of their device with [`ioremap`] instead of
+//! going through the physical memory mapping, which is write back cached.
+//! All accesses through [`Mmio`] are volatile and bounds checked.
+use super::{shared, with_memory_manager, MemoryError};
+use core::{mem::size_of, ptr};
+use x86_64::{
+    memory::{PageSize, PhysicalAddress, Size4KiB, VirtualAddress},
+    paging::CacheAttribute,
+};
+
+/// Registers of a device mapped by [`ioremap`]
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio {
+    address: VirtualAddress,
+    len: u64,
+}
+
+impl Mmio {
+    /// # Safety
+    ///
+    /// `address..address + len` has to be a mapping created by [`ioremap`]
+    pub unsafe fn from_raw(address: VirtualAddress, len: u64) -> Self {
+        Self { address, len }
+    }
+
+    pub fn address(&self) -> VirtualAddress {
+        self.address
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn read<T: Copy>(&self, offset: u64) -> T {
+        unsafe { ptr::read_volatile(self.pointer::<T>(offset)) }
+    }
+
+    pub fn write<T: Copy>(&self, offset: u64, value: T) {
+        unsafe { ptr::write_volatile(self.pointer::<T>(offset), value) }
+    }
+
+    fn pointer<T>(&self, offset: u64) -> *mut T {
+        let size = size_of::<T>() as u64;
+        assert!(
+            offset.checked_add(size).is_some_and(|end| end <= self.len),
+            "MMIO access at {:#x} out of bounds",
+            offset
+        );
+        let address = self.address + offset;
+        assert!(
+            address.is_aligned(size),
+            "Unaligned MMIO access at {:#x}",
+            offset
+        );
+        address.as_mut_ptr()
+    }
+}
+
+/// Maps the device registers at `physical..physical + len` into the MMIO zone
+/// with the caching policy `cache`, usually [`CacheAttribute::Uncached`]
+pub fn ioremap(
+    physical: PhysicalAddress,
+    len: u64,
+    cache: CacheAttribute,
+) -> Result<Mmio, MemoryError> {
+    let address = shared::map_physical(physical, len, cache)?;
+    Ok(Mmio { address, len })
+}
+
+/// Unmaps registers mapped by [`ioremap`]. Copies of `mmio` must not be used
+/// anymore.
+pub fn iounmap(mmio: Mmio) -> Result<(), MemoryError> {
+    let start = mmio.address.align_down(Size4KiB::SIZE);
+    with_memory_manager(|mm| mm.munmap(start))
+}
@@ -0,0 +1,751 @@
+//! This module implements the kernel memory manager.
+//!
+//! The memory manager owns the frame allocator and the kernel page table and
+//! keeps track of the virtual memory regions it handed out. Regions can either
+//! be backed eagerly at allocation time or lazily on first access, in which
+//! case the page fault handler asks the memory manager to populate the page.
+//!
+//! Addresses are picked from the zones of the kernel half, see
+//! [`virtual_range`].
+//!
+//! The interface loosely follows mmap(2): regions are created with [`mmap`],
+//! removed with [`munmap`] and file backed regions can be written back to
+//! their file with [`msync`].
+//!
+//! Physical memory can be shared between processes using the segments in
+//! [`shared`].
+//!
+//! [`mmap`]: MemoryManager::mmap
+//! [`munmap`]: MemoryManager::munmap
+//! [`msync`]: MemoryManager::msync
+extern crate alloc;
+use crate::{
+    allocator::{CacheBox, KmemCache, ObjectCache},
+    paging,
+};
+use alloc::{sync::Arc, vec::Vec};
+use api::layout;
+use btree::BTreeMap;
+use core::{cmp::min, ops::Bound, ptr, slice};
+use x86_64::{
+    interrupts::PageFaultErrorCode,
+    memory::{
+        Address, FrameDeallocator, Page, PageSize, PhysicalAddress, PhysicalFrame, Size4KiB,
+        VirtualAddress,
+    },
+    mutex::{InterruptSafeMutex, Mutex},
+    paging::{
+        mapped_page_table::PageTableFrameMapping, offset_page_table::PhysicalOffset,
+        CacheAttribute, Mapper, MappingError, PageTableEntryFlags, Translator,
+    },
+};
+
+pub mod dma;
+mod file;
+pub mod frame_allocator;
+#[cfg(feature = "unmap-on-free")]
+pub mod freed;
+mod mmio;
+mod region;
+mod reserved;
+pub mod shared;
+pub mod virtual_range;
+pub use dma::{DmaBuffer, DmaSegment};
+pub use file::MappableFile;
+pub use frame_allocator::{FrameStats, LinkedListFrameAllocator, OutOfFrames, RegionUsage};
+pub use mmio::{ioremap, iounmap, Mmio};
+pub use region::{StackGrowth, VirtualMemoryObject, VirtualMemoryRegion};
+pub use reserved::{kernel_memory_map, kernel_memory_regions};
+pub use shared::{shm_create, shm_map, shm_unmap, SharedMemory, ShmKey};
+pub use virtual_range::{VirtualRangeAllocator, Zone};
+
+pub type KernelFrameAllocator = LinkedListFrameAllocator;
+
+#[cfg(not(feature = "recursive-paging"))]
+pub type KernelPageTable =
+    x86_64::paging::offset_page_table::OffsetPageTable<'static, PhysicalOffset>;
+#[cfg(feature = "recursive-paging")]
+pub type KernelPageTable = x86_64::paging::recursive_page_table::RecursivePageTable<'static>;
+
+/// Start of the virtual address range the memory manager hands out regions from
+/// unless a zone is given
+pub const VIRTUAL_MEMORY_START: VirtualAddress = VirtualAddress::new(layout::VMALLOC.start);
+pub const VIRTUAL_MEMORY_SIZE: u64 = layout::VMALLOC.size;
+
+/// How far below the lowest mapped page of a stack an access may be to still
+/// count as stack growth instead of a wild access
+pub const DEFAULT_STACK_GROWTH_WINDOW: u64 = 16 * Size4KiB::SIZE;
+
+static MEMORY_MANAGER: Mutex<Option<MemoryManager>> = Mutex::new(None);
+static REGION_CACHE: ObjectCache<VirtualMemoryRegion> = InterruptSafeMutex::new(KmemCache::new());
+
+/// Outcome of a page fault as seen by the memory manager
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultResolution {
+    /// The fault was a demand fault and the page has been populated
+    Resolved,
+    /// The fault hit the guard page of a stack region
+    StackOverflow,
+    /// The fault is a real fault that needs to be handled by the caller
+    Unhandled,
+}
+
+#[derive(Debug)]
+pub enum MemoryError {
+    OutOfVirtualMemory,
+    OutOfPhysicalMemory(OutOfFrames),
+    RegionOverlap,
+    RegionNotFound,
+    /// The region is file backed or a stack
+    NotShareable,
+    SegmentExists,
+    SegmentNotFound,
+    /// An address of a buffer is not mapped
+    NotMapped,
+    Mapping(MappingError),
+}
+
+impl From<OutOfFrames> for MemoryError {
+    fn from(error: OutOfFrames) -> Self {
+        MemoryError::OutOfPhysicalMemory(error)
+    }
+}
+
+impl From<MappingError> for MemoryError {
+    fn from(error: MappingError) -> Self {
+        MemoryError::Mapping(error)
+    }
+}
+
+pub struct MemoryManager {
+    frame_allocator: KernelFrameAllocator,
+    page_table: KernelPageTable,
+    physical_memory_offset: PhysicalOffset,
+    // keyed by the end address, which doesn't change when a stack grows down
+    regions: BTreeMap<VirtualAddress, CacheBox<VirtualMemoryRegion>>,
+    virtual_ranges: VirtualRangeAllocator,
+    stack_growth_window: u64,
+}
+
+impl MemoryManager {
+    pub fn new(
+        frame_allocator: KernelFrameAllocator,
+        page_table: KernelPageTable,
+        physical_memory_offset: u64,
+    ) -> Self {
+        Self {
+            frame_allocator,
+            page_table,
+            physical_memory_offset: PhysicalOffset::new(physical_memory_offset),
+            regions: BTreeMap::new(),
+            virtual_ranges: VirtualRangeAllocator::new(),
+            stack_growth_window: DEFAULT_STACK_GROWTH_WINDOW,
+        }
+    }
+
+    pub fn frame_allocator(&mut self) -> &mut KernelFrameAllocator {
+        &mut self.frame_allocator
+    }
+
+    /// Physical address and flags of the mapping of `address`. Works for
+    /// pages of all sizes.
+    pub fn translate(
+        &self,
+        address: VirtualAddress,
+    ) -> Option<(PhysicalAddress, PageTableEntryFlags)> {
+        paging::translate_in(&self.page_table, address)
+    }
+
+    pub fn page_table(&mut self) -> &mut KernelPageTable {
+        &mut self.page_table
+    }
+
+    pub fn virtual_ranges(&self) -> &VirtualRangeAllocator {
+        &self.virtual_ranges
+    }
+
+    /// All regions ordered by their address
+    pub fn regions(&self) -> impl Iterator<Item = &VirtualMemoryRegion> {
+        self.regions.values().map(|r| &**r)
+    }
+
+    pub fn region_count(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// The region containing `address`
+    pub fn region(&self, address: VirtualAddress) -> Option<&VirtualMemoryRegion> {
+        self.region_above(address).filter(|r| r.contains(address))
+    }
+
+    /// The lowest region ending above `address`, which is the one containing
+    /// it if there is any
+    fn region_above(&self, address: VirtualAddress) -> Option<&VirtualMemoryRegion> {
+        self.regions
+            .range((Bound::Excluded(address), Bound::Unbounded))
+            .next()
+            .map(|(_, region)| &**region)
+    }
+
+    /// The region starting exactly at `start`
+    fn region_at(&self, start: VirtualAddress) -> Option<&VirtualMemoryRegion> {
+        self.region_above(start).filter(|r| r.start() == start)
+    }
+
+    pub fn set_stack_growth_window(&mut self, window: u64) {
+        self.stack_growth_window = window;
+    }
+
+    /// Allocates a region of virtual memory of at least `size` bytes.
+    ///
+    /// Depending on `object` the region is either backed immediately or only
+    /// once the pages are accessed.
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        flags: PageTableEntryFlags,
+        object: VirtualMemoryObject,
+    ) -> Result<VirtualAddress, MemoryError> {
+        self.mmap(None, size, flags, object)
+    }
+
+    /// Maps `object` into virtual memory.
+    ///
+    /// If `address` is None the memory manager picks a free address, else the
+    /// region is placed at the given page aligned address.
+    pub fn mmap(
+        &mut self,
+        address: Option<VirtualAddress>,
+        size: u64,
+        flags: PageTableEntryFlags,
+        object: VirtualMemoryObject,
+    ) -> Result<VirtualAddress, MemoryError> {
+        self.mmap_with_cache(address, size, flags, CacheAttribute::WriteBack, object)
+    }
+
+    /// Like [`mmap`](Self::mmap), but maps shared frames with `cache` instead
+    /// of write back, e.g. for device memory
+    pub fn mmap_with_cache(
+        &mut self,
+        address: Option<VirtualAddress>,
+        size: u64,
+        flags: PageTableEntryFlags,
+        cache: CacheAttribute,
+        object: VirtualMemoryObject,
+    ) -> Result<VirtualAddress, MemoryError> {
+        let size = VirtualAddress::new(size).align_up(Size4KiB::SIZE).as_u64();
+        match address {
+            Some(address) => {
+                let region = VirtualMemoryRegion::new(address, size, flags, object);
+                self.map_region(region.with_cache(cache))?;
+                Ok(address)
+            }
+            None => self.mmap_in(Zone::Vmalloc, size, flags, cache, object),
+        }
+    }
+
+    /// Maps `object` at a free address of `zone`, e.g. [`Zone::Mmio`] for
+    /// device memory
+    pub fn mmap_in(
+        &mut self,
+        zone: Zone,
+        size: u64,
+        flags: PageTableEntryFlags,
+        cache: CacheAttribute,
+        object: VirtualMemoryObject,
+    ) -> Result<VirtualAddress, MemoryError> {
+        let size = VirtualAddress::new(size).align_up(Size4KiB::SIZE).as_u64();
+        let start = self
+            .virtual_ranges
+            .allocate(zone, size, Size4KiB::SIZE)
+            .ok_or(MemoryError::OutOfVirtualMemory)?;
+
+        let region = VirtualMemoryRegion::new(start, size, flags, object).with_cache(cache);
+        if let Err(error) = self.map(region) {
+            self.virtual_ranges.free(start, size);
+            return Err(error);
+        }
+        Ok(start)
+    }
+
+    /// Allocates a stack with `size` bytes initially available which can grow
+    /// up to `max_size` bytes. Below the maximum size a guard page is reserved.
+    ///
+    /// Returns the top of the stack.
+    pub fn allocate_stack(
+        &mut self,
+        size: u64,
+        max_size: u64,
+        flags: PageTableEntryFlags,
+    ) -> Result<VirtualAddress, MemoryError> {
+        let size = VirtualAddress::new(size).align_up(Size4KiB::SIZE).as_u64();
+        let max_size = VirtualAddress::new(max_size)
+            .align_up(Size4KiB::SIZE)
+            .as_u64();
+        assert!(size <= max_size);
+
+        let reservation_size = max_size + Size4KiB::SIZE;
+        let reservation_start = self
+            .virtual_ranges
+            .allocate(Zone::Vmalloc, reservation_size, Size4KiB::SIZE)
+            .ok_or(MemoryError::OutOfVirtualMemory)?;
+
+        let guard_page = Page::containing_address(reservation_start);
+        let start = reservation_start + reservation_size - size;
+        let region = VirtualMemoryRegion::new_stack(start, size, flags, guard_page);
+        let top = region.end();
+
+        if let Err(error) = self.map(region) {
+            self.virtual_ranges
+                .free(reservation_start, reservation_size);
+            return Err(error);
+        }
+        Ok(top)
+    }
+
+    /// Registers a region at a fixed virtual address
+    pub fn map_region(&mut self, region: VirtualMemoryRegion) -> Result<(), MemoryError> {
+        let start = region.reservation_start();
+        let size = region.end() - start;
+        if !self.virtual_ranges.reserve(start, size) {
+            return Err(MemoryError::RegionOverlap);
+        }
+
+        let result = self.map(region);
+        if result.is_err() {
+            self.virtual_ranges.free(start, size);
+        }
+        result
+    }
+
+    fn map(&mut self, region: VirtualMemoryRegion) -> Result<(), MemoryError> {
+        assert!(region.start().is_aligned(Size4KiB::SIZE));
+
+        if self
+            .region_above(region.start())
+            .is_some_and(|r| r.overlaps(region.start(), region.size()))
+        {
+            return Err(MemoryError::RegionOverlap);
+        }
+
+        match region.object() {
+            VirtualMemoryObject::Shared(segment) => {
+                assert!(region.size() == segment.size());
+                // physically contiguous streaks are mapped at once
+                let first = Page::containing_address(region.start());
+                let frames = segment.frames();
+                let mut start = 0;
+                while start < frames.len() {
+                    let mut end = start + 1;
+                    while end < frames.len() && frames[end] == frames[end - 1] + 1 {
+                        end += 1;
+                    }
+                    let last = (end - start - 1) as u64;
+                    let page = first + start as u64;
+                    self.page_table
+                        .map_range(
+                            PhysicalFrame::range_inclusive(frames[start], frames[start] + last),
+                            Page::range_inclusive(page, page + last),
+                            region.flags(),
+                            region.cache(),
+                            &mut self.frame_allocator,
+                        )?
+                        .flush();
+                    start = end;
+                }
+            }
+            object if !object.is_lazy() => {
+                for page in region.pages() {
+                    self.back_page(page, region.flags())?;
+                }
+            }
+            _ => (),
+        }
+
+        self.regions
+            .insert(region.end(), CacheBox::new(&REGION_CACHE, region));
+
+        Ok(())
+    }
+
+    /// Unmaps the region starting at `start`. Dirty pages of file backed
+    /// regions are written back to the file before unmapping.
+    ///
+    /// Frames backing the region are freed, except for shared frames which
+    /// belong to their segment.
+    pub fn munmap(&mut self, start: VirtualAddress) -> Result<(), MemoryError> {
+        let end = self
+            .region_at(start)
+            .map(|r| r.end())
+            .ok_or(MemoryError::RegionNotFound)?;
+
+        let region = self.regions.remove(&end).unwrap();
+        self.write_back(&region, false);
+        let start = region.reservation_start();
+        self.virtual_ranges.free(start, region.end() - start);
+
+        let owns_frames = !matches!(region.object(), VirtualMemoryObject::Shared(_));
+        for page in region.pages() {
+            // lazy regions might not have all pages populated
+            if let Ok((frame, flusher)) = self.page_table.unmap(page) {
+                flusher.flush();
+                if owns_frames {
+                    unsafe { self.frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Turns the anonymous region starting at `start` into shared memory, so
+    /// its frames stay alive as long as any mapping of the returned segment.
+    /// Lazy pages are backed first. Shared regions return their segment.
+    pub fn share_region(
+        &mut self,
+        start: VirtualAddress,
+    ) -> Result<Arc<SharedMemory>, MemoryError> {
+        let region = self.region_at(start).ok_or(MemoryError::RegionNotFound)?;
+        match region.object() {
+            VirtualMemoryObject::Shared(segment) => return Ok(segment.clone()),
+            VirtualMemoryObject::FileBacked { .. } => return Err(MemoryError::NotShareable),
+            _ if region.growth().is_some() => return Err(MemoryError::NotShareable),
+            _ => (),
+        }
+
+        let (end, flags) = (region.end(), region.flags());
+        let pages: Vec<_> = region.pages().collect();
+        let mut frames = Vec::with_capacity(pages.len());
+        for page in pages {
+            if self.page_table.translate(page).is_err() {
+                self.back_page(page, flags)?;
+            }
+            frames.push(self.page_table.translate(page).unwrap().0);
+        }
+
+        let segment = Arc::new(SharedMemory::from_owned_frames(frames));
+        self.regions
+            .get_mut(&end)
+            .unwrap()
+            .set_shared(segment.clone());
+        Ok(segment)
+    }
+
+    /// Allocates a zeroed, physically contiguous buffer of `len` bytes that
+    /// ends at or below the physical address `max_physical_address`
+    pub fn allocate_dma(
+        &mut self,
+        len: usize,
+        max_physical_address: u64,
+    ) -> Result<DmaBuffer, MemoryError> {
+        let frames = (len as u64).div_ceil(Size4KiB::SIZE).max(1) as usize;
+        let frame = self.frame_allocator.try_allocate_below(
+            frames,
+            Size4KiB::SIZE,
+            max_physical_address,
+        )?;
+
+        let address = self.physical_memory_offset.frame_to_virtual(frame);
+        unsafe { ptr::write_bytes(address.as_mut_ptr::<u8>(), 0, frames * frame.size()) };
+
+        Ok(DmaBuffer {
+            frame,
+            frames,
+            address,
+            len,
+        })
+    }
+
+    pub fn free_dma(&mut self, buffer: DmaBuffer) {
+        unsafe {
+            self.frame_allocator
+                .deallocate_contiguous(buffer.frame, buffer.frames)
+        };
+    }
+
+    /// Splits the virtually contiguous buffer at `start` into physically
+    /// contiguous segments, e.g. to build a scatter-gather list
+    pub fn dma_segments(
+        &mut self,
+        start: VirtualAddress,
+        len: u64,
+    ) -> Result<Vec<DmaSegment>, MemoryError> {
+        let mut segments: Vec<DmaSegment> = Vec::new();
+        let mut address = start;
+        let end = start + len;
+        while address < end {
+            let (physical, _) = self.translate(address).ok_or(MemoryError::NotMapped)?;
+            let page_end = address.align_down(Size4KiB::SIZE) + Size4KiB::SIZE;
+            let chunk = min(page_end, end) - address;
+
+            match segments.last_mut() {
+                Some(last) if last.address + last.len == physical => last.len += chunk,
+                _ => segments.push(DmaSegment {
+                    address: physical,
+                    len: chunk,
+                }),
+            }
+            address += chunk;
+        }
+        Ok(segments)
+    }
+
+    /// Writes all dirty pages of the file backed region starting at `start`
+    /// back to the file
+    pub fn msync(&mut self, start: VirtualAddress) -> Result<(), MemoryError> {
+        let region = self
+            .region_at(start)
+            .cloned()
+            .ok_or(MemoryError::RegionNotFound)?;
+
+        self.write_back(&region, true);
+
+        Ok(())
+    }
+
+    /// Handles a page fault at `address`.
+    ///
+    /// Legitimate demand faults on lazily allocated regions and faults inside
+    /// the growth window of a stack are resolved by populating the page.
+    /// Everything else is reported back to the caller.
+    pub fn handle_page_fault(
+        &mut self,
+        address: VirtualAddress,
+        error: &PageFaultErrorCode,
+    ) -> PageFaultResolution {
+        // page was present, so this is an access rights violation
+        if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+            || error.contains(PageFaultErrorCode::MALFORMED_TABLE)
+        {
+            return PageFaultResolution::Unhandled;
+        }
+
+        if let Some(resolution) = self.grow_stack(address) {
+            return resolution;
+        }
+
+        let region = match self.region_above(address).filter(|r| r.contains(address)) {
+            Some(region) => region.clone(),
+            None => return PageFaultResolution::Unhandled,
+        };
+
+        if !region.object().is_lazy() {
+            return PageFaultResolution::Unhandled;
+        }
+
+        if error.contains(PageFaultErrorCode::WRITE_VIOLATION)
+            && !region.flags().contains(PageTableEntryFlags::WRITABLE)
+        {
+            return PageFaultResolution::Unhandled;
+        }
+
+        if error.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && region.flags().contains(PageTableEntryFlags::NO_EXECUTE)
+        {
+            return PageFaultResolution::Unhandled;
+        }
+
+        let page = Page::containing_address(address);
+        let result = match region.object() {
+            VirtualMemoryObject::FileBacked { file, offset } => {
+                let file_offset = offset + (page.address() - region.start());
+                self.back_page_from_file(page, region.flags(), file.as_ref(), file_offset)
+            }
+            _ => self.back_page(page, region.flags()),
+        };
+
+        match result {
+            Ok(_) => PageFaultResolution::Resolved,
+            Err(_) => PageFaultResolution::Unhandled,
+        }
+    }
+
+    /// Checks whether `address` lies below a stack region.
+    ///
+    /// Faults within the growth window extend the region downwards and are
+    /// afterwards populated like any other lazy fault. Faults on the guard
+    /// page are stack overflows.
+    fn grow_stack(&mut self, address: VirtualAddress) -> Option<PageFaultResolution> {
+        let region = self.region_above(address).filter(|r| match r.growth() {
+            Some(growth) => growth.guard_page().address() <= address && address < r.start(),
+            None => false,
+        })?;
+        let growth = region.growth().unwrap();
+        if address < growth.limit() {
+            return Some(PageFaultResolution::StackOverflow);
+        }
+
+        if region.start() - address > self.stack_growth_window {
+            return Some(PageFaultResolution::Unhandled);
+        }
+
+        let page = Page::<Size4KiB>::containing_address(address);
+        let end = region.end();
+        // another region might end within the page
+        if self.region_above(page.address()).map(|r| r.end()) != Some(end) {
+            return Some(PageFaultResolution::Unhandled);
+        }
+
+        self.regions.get_mut(&end).unwrap().grow_down_to(address);
+
+        None
+    }
+
+    /// Maps a zeroed frame to `page`
+    fn back_page(&mut self, page: Page, flags: PageTableEntryFlags) -> Result<(), MemoryError> {
+        let frame = self.frame_allocator.try_allocate(1, Size4KiB::SIZE)?;
+
+        self.zero_frame(frame);
+
+        self.page_table
+            .map_to(
+                frame,
+                page,
+                flags,
+                CacheAttribute::WriteBack,
+                &mut self.frame_allocator,
+            )?
+            .flush();
+
+        Ok(())
+    }
+
+    /// Maps a frame filled with the file contents at `file_offset` to `page`.
+    /// Bytes past the end of the file are zero.
+    fn back_page_from_file(
+        &mut self,
+        page: Page,
+        flags: PageTableEntryFlags,
+        file: &dyn MappableFile,
+        file_offset: u64,
+    ) -> Result<(), MemoryError> {
+        let frame = self.frame_allocator.try_allocate(1, Size4KiB::SIZE)?;
+
+        self.zero_frame(frame);
+        file.read_at(file_offset, unsafe { self.frame_as_slice(frame) });
+
+        self.page_table
+            .map_to(
+                frame,
+                page,
+                flags,
+                CacheAttribute::WriteBack,
+                &mut self.frame_allocator,
+            )?
+            .flush();
+
+        Ok(())
+    }
+
+    /// Writes the dirty pages of a file backed region back to its file.
+    /// If `clear_dirty` is set, the pages are remapped without the dirty flag
+    /// so that later writes can be detected again.
+    fn write_back(&mut self, region: &VirtualMemoryRegion, clear_dirty: bool) {
+        let (file, offset) = match region.object() {
+            VirtualMemoryObject::FileBacked { file, offset } => (file, *offset),
+            _ => return,
+        };
+
+        for page in region.pages() {
+            let (frame, flags) = match self.page_table.translate(page) {
+                Ok(mapping) => mapping,
+                Err(_) => continue,
+            };
+
+            if !flags.contains(PageTableEntryFlags::DIRTY) {
+                continue;
+            }
+
+            // don't grow the file by writing back the zeroed tail of the last page
+            let file_offset = offset + (page.address() - region.start());
+            if file_offset >= file.size() {
+                continue;
+            }
+            let len = min(Size4KiB::SIZE, file.size() - file_offset) as usize;
+            file.write_at(file_offset, unsafe { &self.frame_as_slice(frame)[..len] });
+
+            if clear_dirty {
+                let (frame, flusher) = self
+                    .page_table
+                    .unmap(page)
+                    .expect("Failed to unmap page for dirty bit reset");
+                flusher.ignore();
+
+                self.page_table
+                    .map_to(
+                        frame,
+                        page,
+                        region.flags(),
+                        region.cache(),
+                        &mut self.frame_allocator,
+                    )
+                    .expect("Failed to remap page after write back")
+                    .flush();
+            }
+        }
+    }
+
+    /// Returns the contents of `frame` accessed through the physical memory
+    /// mapping.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure no other references to the frame contents exist
+    unsafe fn frame_as_slice(&self, frame: PhysicalFrame) -> &mut [u8] {
+        let address = self.physical_memory_offset.frame_to_virtual(frame);
+        unsafe { slice::from_raw_parts_mut(address.as_mut_ptr::<u8>(), frame.size()) }
+    }
+
+    fn zero_frame(&self, frame: PhysicalFrame) {
+        let address = self.physical_memory_offset.frame_to_virtual(frame);
+        unsafe { ptr::write_bytes(address.as_mut_ptr::<u8>(), 0, frame.size()) };
+    }
+}
+
+pub fn init(memory_manager: MemoryManager) {
+    *MEMORY_MANAGER.lock() = Some(memory_manager);
+}
+
+/// Runs `f` with exclusive access to the kernel memory manager
+pub fn with_memory_manager<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut MemoryManager) -> R,
+{
+    let mut guard = MEMORY_MANAGER.lock();
+    let memory_manager = guard.as_mut().expect("Memory manager not initialized");
+    f(memory_manager)
+}
+
+/// Like [`with_memory_manager`], but returns None instead of spinning if the
+/// memory manager is locked. For exception handlers, the interrupted code
+/// might hold the lock.
+pub fn try_with_memory_manager<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut MemoryManager) -> R,
+{
+    let mut guard = MEMORY_MANAGER.try_lock()?;
+    guard.as_mut().map(f)
+}
+
+/// Frame counts of the kernel frame allocator
+pub fn frame_stats() -> FrameStats {
+    with_memory_manager(|mm| mm.frame_allocator().stats())
+}
+
+/// Entry point for the page fault handler
+pub fn handle_page_fault(
+    address: VirtualAddress,
+    error: &PageFaultErrorCode,
+) -> PageFaultResolution {
+    // A fault while the memory manager is locked can't be a demand fault
+    // we are able to handle. Spinning here would deadlock.
+    let mut guard = match MEMORY_MANAGER.try_lock() {
+        Some(guard) => guard,
+        None => return PageFaultResolution::Unhandled,
+    };
+
+    match guard.as_mut() {
+        Some(memory_manager) => memory_manager.handle_page_fault(address, error),
+        None => PageFaultResolution::Unhandled,
+    }
+}
@@ -0,0 +1,201 @@
+extern crate alloc;
+use super::{file::MappableFile, shared::SharedMemory};
+use alloc::sync::Arc;
+use core::fmt;
+use x86_64::{
+    memory::{Address, Page, PageSize, Size4KiB, VirtualAddress},
+    paging::{CacheAttribute, PageTableEntryFlags},
+};
+
+/// Describes what backs the pages of a virtual memory region
+#[derive(Clone)]
+pub enum VirtualMemoryObject {
+    /// Anonymous memory which is backed by frames at allocation time
+    Anonymous,
+    /// Anonymous memory which is only backed by zeroed frames on first access.
+    /// The frames are populated by the page fault handler.
+    LazyAnonymous,
+    /// Memory backed by the contents of a file starting at `offset`.
+    /// Pages are populated lazily and dirty pages are written back on
+    /// unmap or sync.
+    FileBacked {
+        file: Arc<dyn MappableFile>,
+        offset: u64,
+    },
+    /// Memory backed by the frames of a shared memory segment, which are
+    /// mapped at allocation time
+    Shared(Arc<SharedMemory>),
+}
+
+impl VirtualMemoryObject {
+    pub fn is_lazy(&self) -> bool {
+        match self {
+            VirtualMemoryObject::Anonymous | VirtualMemoryObject::Shared(_) => false,
+            VirtualMemoryObject::LazyAnonymous | VirtualMemoryObject::FileBacked { .. } => true,
+        }
+    }
+}
+
+impl fmt::Debug for VirtualMemoryObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VirtualMemoryObject::Anonymous => write!(f, "Anonymous"),
+            VirtualMemoryObject::LazyAnonymous => write!(f, "LazyAnonymous"),
+            VirtualMemoryObject::FileBacked { file, offset } => write!(
+                f,
+                "FileBacked {{ size: {:#x}, offset: {:#x} }}",
+                file.size(),
+                offset
+            ),
+            VirtualMemoryObject::Shared(segment) => match segment.key() {
+                Some(key) => write!(f, "Shared {{ key: {:#x} }}", key.as_u64()),
+                None => write!(f, "Shared {{ anonymous }}"),
+            },
+        }
+    }
+}
+
+/// Describes how far a stack region may grow downwards.
+///
+/// The lowest page of the reserved range is a guard page which is never
+/// mapped. Accesses to it are treated as stack overflows.
+#[derive(Debug, Clone, Copy)]
+pub struct StackGrowth {
+    guard_page: Page,
+}
+
+impl StackGrowth {
+    pub fn new(guard_page: Page) -> Self {
+        Self { guard_page }
+    }
+
+    pub fn guard_page(&self) -> Page {
+        self.guard_page
+    }
+
+    /// Lowest address the stack can grow to
+    pub fn limit(&self) -> VirtualAddress {
+        self.guard_page.end()
+    }
+}
+
+/// A contiguous range of virtual memory managed by the [`MemoryManager`]
+///
+/// [`MemoryManager`]: super::MemoryManager
+#[derive(Debug, Clone)]
+pub struct VirtualMemoryRegion {
+    start: VirtualAddress,
+    size: u64,
+    flags: PageTableEntryFlags,
+    cache: CacheAttribute,
+    object: VirtualMemoryObject,
+    growth: Option<StackGrowth>,
+}
+
+impl VirtualMemoryRegion {
+    pub fn new(
+        start: VirtualAddress,
+        size: u64,
+        flags: PageTableEntryFlags,
+        object: VirtualMemoryObject,
+    ) -> Self {
+        Self {
+            start,
+            size,
+            flags,
+            cache: CacheAttribute::WriteBack,
+            object,
+            growth: None,
+        }
+    }
+
+    /// Creates a lazily backed stack region that is able to grow downwards
+    /// until it hits the guard page
+    pub fn new_stack(
+        start: VirtualAddress,
+        size: u64,
+        flags: PageTableEntryFlags,
+        guard_page: Page,
+    ) -> Self {
+        assert!(guard_page.end() <= start);
+        Self {
+            start,
+            size,
+            flags,
+            cache: CacheAttribute::WriteBack,
+            object: VirtualMemoryObject::LazyAnonymous,
+            growth: Some(StackGrowth::new(guard_page)),
+        }
+    }
+
+    /// Makes `segment` the owner of the frames backing the region
+    pub fn set_shared(&mut self, segment: Arc<SharedMemory>) {
+        assert!(segment.size() == self.size);
+        self.object = VirtualMemoryObject::Shared(segment);
+    }
+
+    /// Maps the region with `cache`. Only applies to shared frames, memory
+    /// allocated by the memory manager is always write back.
+    pub fn with_cache(mut self, cache: CacheAttribute) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn start(&self) -> VirtualAddress {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtualAddress {
+        self.start + self.size
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn flags(&self) -> PageTableEntryFlags {
+        self.flags
+    }
+
+    /// Start of the address range reserved for the region, including the
+    /// guard page and the growth space of stacks
+    pub fn reservation_start(&self) -> VirtualAddress {
+        self.growth
+            .map_or(self.start, |growth| growth.guard_page().address())
+    }
+
+    pub fn cache(&self) -> CacheAttribute {
+        self.cache
+    }
+
+    pub fn object(&self) -> &VirtualMemoryObject {
+        &self.object
+    }
+
+    pub fn growth(&self) -> Option<StackGrowth> {
+        self.growth
+    }
+
+    /// Extends the region downwards so that it starts at the page containing
+    /// `address`
+    pub fn grow_down_to(&mut self, address: VirtualAddress) {
+        let new_start = address.align_down(Size4KiB::SIZE);
+        assert!(new_start < self.start);
+        self.size += self.start - new_start;
+        self.start = new_start;
+    }
+
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        self.start <= address && address < self.end()
+    }
+
+    pub fn overlaps(&self, start: VirtualAddress, size: u64) -> bool {
+        self.start.as_u64() < start.as_u64() + size && start < self.end()
+    }
+
+    pub fn pages(&self) -> impl Iterator<Item = Page> {
+        let start_page = Page::containing_address(self.start);
+        let end_page = Page::containing_address(self.end() - 1u64);
+        Page::range_inclusive(start_page, end_page)
+    }
+}
@@ -0,0 +1,98 @@
+//! Memory the kernel never allocates, whatever the memory map says.
+//!
+//! Firmware doesn't always report legacy areas and MMIO ranges in its memory
+//! map, or reports them as usable. The kernel marks them as reserved in its
+//! own copy of the memory map before any frame allocator sees it.
+use crate::{acpi, paging, warn};
+use core::{
+    ptr::{self, addr_of, addr_of_mut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::{
+    cpuid::{self, Features},
+    memory::{PageSize, PhysicalAddress, PhysicalMemoryRegion, PhysicalMemoryRegionType, Size4KiB},
+    register::ApicBase,
+};
+
+/// Real mode interrupt vector table and BIOS data area
+const BIOS_DATA_AREA: (u64, u64) = (0, 0x1000);
+/// The EBDA segment is stored at this address in the BIOS data area
+const EBDA_POINTER: u64 = 0x40e;
+/// The EBDA ends where the legacy VGA memory starts
+const EBDA_END: u64 = 0xa0000;
+/// Legacy VGA framebuffer and the BIOS ROM area
+const LEGACY_AREA: (u64, u64) = (0xa0000, 0x100000);
+const IO_APIC_SIZE: u64 = 0x20;
+/// Regions of the kernel memory map. The boot memory map is normalized, so
+/// each carve out adds at most two regions.
+const MAX_REGIONS: usize = 256;
+const MAX_CARVE_OUTS: usize = 32;
+
+static mut MEMORY_MAP: [PhysicalMemoryRegion; MAX_REGIONS] = [PhysicalMemoryRegion {
+    start: 0,
+    size: 0,
+    typ: PhysicalMemoryRegionType::Reserved,
+}; MAX_REGIONS];
+static MEMORY_MAP_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Copy of `regions` with all ranges the kernel must not allocate marked as
+/// reserved. Requires the physical memory mapping and [`acpi::init`].
+///
+/// # Safety
+///
+/// Must only be called once, the previously returned map is overwritten
+pub unsafe fn kernel_memory_map(
+    regions: &[PhysicalMemoryRegion],
+) -> &'static [PhysicalMemoryRegion] {
+    let mut input = [PhysicalMemoryRegion::default(); MAX_REGIONS + MAX_CARVE_OUTS];
+    assert!(regions.len() <= MAX_REGIONS, "Boot memory map is too large");
+    input[..regions.len()].copy_from_slice(regions);
+
+    let mut len = regions.len();
+    for (start, end) in carve_outs() {
+        if len == input.len() {
+            warn!(
+                "Too many reserved ranges, ignoring {:#x}..{:#x}",
+                start, end
+            );
+            continue;
+        }
+        // whole frames, a frame is never partially usable
+        let start = start & !(Size4KiB::SIZE - 1);
+        let end = end.next_multiple_of(Size4KiB::SIZE);
+        input[len] =
+            PhysicalMemoryRegion::new(start, end - start, PhysicalMemoryRegionType::Reserved);
+        len += 1;
+    }
+
+    let out = unsafe { &mut *addr_of_mut!(MEMORY_MAP) };
+    let len = memory_map::normalize(&input[..len], out).expect("Kernel memory map is too large");
+    MEMORY_MAP_LEN.store(len, Ordering::SeqCst);
+    &out[..len]
+}
+
+/// The map built by [`kernel_memory_map`], empty before it was called
+pub fn kernel_memory_regions() -> &'static [PhysicalMemoryRegion] {
+    let len = MEMORY_MAP_LEN.load(Ordering::SeqCst);
+    let map = unsafe { &*addr_of!(MEMORY_MAP) };
+    &map[..len]
+}
+
+/// Start and end of every range that must stay reserved
+fn carve_outs() -> impl Iterator<Item = (u64, u64)> {
+    let pointer = paging::physical_to_virtual(PhysicalAddress::new(EBDA_POINTER));
+    let ebda = (unsafe { ptr::read_volatile(pointer.as_ptr::<u16>()) } as u64) << 4;
+    let ebda = (ebda != 0 && ebda < EBDA_END).then_some((ebda, EBDA_END));
+
+    let local_apic = cpuid::has(Features::APIC).then(|| {
+        let start = ApicBase::read() & ApicBase::ADDRESS_MASK;
+        (start, start + Size4KiB::SIZE)
+    });
+    let io_apics = acpi::io_apics().map(|start| (start, start + IO_APIC_SIZE));
+
+    [BIOS_DATA_AREA, LEGACY_AREA]
+        .into_iter()
+        .chain(ebda)
+        .chain(local_apic)
+        .chain(io_apics)
+}
@@ -0,0 +1,192 @@
+//! Shared memory segments.
+//!
+//! A segment is a set of physical frames registered under a key. Every
+//! [`shm_map`] maps the same frames into the address space of the calling
+//! process, so writes through one mapping are visible through all others.
+//! Each mapping has its own page table flags, e.g. one process can map a
+//! segment writable while another one only gets read access.
+//!
+//! The frames of a segment are freed once the segment is removed and the last
+//! mapping is gone. Segments wrapping device memory, e.g. MMIO registers or a
+//! framebuffer, don't own their frames and never free them.
+extern crate alloc;
+use super::{with_memory_manager, MemoryError, VirtualMemoryObject, Zone};
+use crate::{process, scheduler::finalizer};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::mem;
+use hashmap::HashMap;
+use x86_64::{
+    memory::{
+        FrameDeallocator, PageSize, PhysicalAddress, PhysicalFrame, Size4KiB, VirtualAddress,
+    },
+    mutex::Mutex,
+    paging::{CacheAttribute, PageTableEntryFlags},
+};
+
+// Held while allocating frames, so always lock it before the memory manager
+static SEGMENTS: Mutex<HashMap<ShmKey, Arc<SharedMemory>>> = Mutex::new(HashMap::new());
+
+/// Key a shared memory segment is registered under
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShmKey(u64);
+
+impl ShmKey {
+    pub const fn new(key: u64) -> Self {
+        Self(key)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Physical frames backing a shared memory segment
+#[derive(Debug)]
+pub struct SharedMemory {
+    key: Option<ShmKey>,
+    frames: Vec<PhysicalFrame>,
+    /// Whether the frames are freed with the segment
+    owned: bool,
+}
+
+impl SharedMemory {
+    /// Creates an anonymous segment from frames it doesn't own, e.g. device
+    /// memory
+    pub(crate) fn from_frames(frames: Vec<PhysicalFrame>) -> Self {
+        Self {
+            key: None,
+            frames,
+            owned: false,
+        }
+    }
+
+    /// Creates an anonymous segment that frees `frames` once it is dropped
+    pub(super) fn from_owned_frames(frames: Vec<PhysicalFrame>) -> Self {
+        Self {
+            key: None,
+            frames,
+            owned: true,
+        }
+    }
+
+    /// Key the segment was created with, None for anonymous segments
+    pub fn key(&self) -> Option<ShmKey> {
+        self.key
+    }
+
+    pub fn frames(&self) -> &[PhysicalFrame] {
+        &self.frames
+    }
+
+    /// Size of the segment in bytes
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * Size4KiB::SIZE
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        let frames = mem::take(&mut self.frames);
+        // the last reference is usually dropped by munmap, which holds the
+        // memory manager
+        finalizer::defer(Box::new(move || {
+            with_memory_manager(|mm| {
+                for frame in frames {
+                    unsafe { mm.frame_allocator.deallocate_frame(frame) };
+                }
+            })
+        }));
+    }
+}
+
+/// Creates a zeroed segment of at least `size` bytes registered under `key`
+pub fn shm_create(key: ShmKey, size: u64) -> Result<Arc<SharedMemory>, MemoryError> {
+    let mut segments = SEGMENTS.lock();
+    if segments.contains_key(&key) {
+        return Err(MemoryError::SegmentExists);
+    }
+
+    let count = size.div_ceil(Size4KiB::SIZE);
+    let frames = with_memory_manager(|mm| {
+        (0..count)
+            .map(|_| {
+                let frame = mm.frame_allocator.try_allocate(1, Size4KiB::SIZE)?;
+                mm.zero_frame(frame);
+                Ok(frame)
+            })
+            .collect::<Result<Vec<_>, MemoryError>>()
+    })?;
+
+    let segment = Arc::new(SharedMemory {
+        key: Some(key),
+        frames,
+        owned: true,
+    });
+    segments.insert(key, segment.clone());
+    Ok(segment)
+}
+
+/// Looks up the segment registered under `key`
+pub fn shm_get(key: ShmKey) -> Option<Arc<SharedMemory>> {
+    SEGMENTS.lock().get(&key).cloned()
+}
+
+/// Removes `key` from the registry. Existing mappings stay valid.
+pub fn shm_remove(key: ShmKey) -> Result<(), MemoryError> {
+    SEGMENTS
+        .lock()
+        .remove(&key)
+        .map(|_| ())
+        .ok_or(MemoryError::SegmentNotFound)
+}
+
+/// Maps the segment `key` into the address space of the current process
+/// using `flags`. Returns the start of the mapping.
+pub fn shm_map(key: ShmKey, flags: PageTableEntryFlags) -> Result<VirtualAddress, MemoryError> {
+    let segment = shm_get(key).ok_or(MemoryError::SegmentNotFound)?;
+    map_segment(segment, flags)
+}
+
+/// Maps `segment` into the address space of the current process using
+/// `flags`. Returns the start of the mapping.
+pub fn map_segment(
+    segment: Arc<SharedMemory>,
+    flags: PageTableEntryFlags,
+) -> Result<VirtualAddress, MemoryError> {
+    process::allocate(segment.size(), flags, VirtualMemoryObject::Shared(segment))
+}
+
+/// Maps the physical range `start..start + size`, e.g. a framebuffer, into
+/// kernel memory with the caching policy `cache`. The mapping is never freed.
+/// Returns the virtual address of `start`.
+pub fn map_physical(
+    start: PhysicalAddress,
+    size: u64,
+    cache: CacheAttribute,
+) -> Result<VirtualAddress, MemoryError> {
+    let first = PhysicalFrame::containing_address(start);
+    let last = PhysicalFrame::containing_address(start + (size - 1));
+    let segment = SharedMemory::from_frames(PhysicalFrame::range_inclusive(first, last).collect());
+
+    let mapping = with_memory_manager(|mm| {
+        mm.mmap_in(
+            Zone::Mmio,
+            segment.size(),
+            PageTableEntryFlags::PRESENT
+                | PageTableEntryFlags::WRITABLE
+                | PageTableEntryFlags::NO_EXECUTE,
+            cache,
+            VirtualMemoryObject::Shared(Arc::new(segment)),
+        )
+    })?;
+
+    Ok(mapping + (start - first.address))
+}
+
+/// Unmaps a mapping created by [`shm_map`]. The segment itself stays alive.
+pub fn shm_unmap(start: VirtualAddress) -> Result<(), MemoryError> {
+    process::unmap(start)
+}
@@ -0,0 +1,260 @@
+//! Allocation of kernel virtual address ranges.
+//!
+//! The kernel half is split into the zones of [`api::layout`]. The physical
+//! memory mapping and the heap sit at fixed addresses, the zones here hand out
+//! ranges on demand and take them back once they are unmapped. Addresses
+//! outside of them, e.g. fixed mappings in the lower half, are not tracked.
+//!
+//! The ranges in use are kept in an intrusive red-black tree per zone, ordered
+//! by address. Every node caches the largest gap between two ranges of its
+//! subtree, so the lowest gap that fits is found without looking at subtrees
+//! that are too fragmented.
+extern crate alloc;
+use alloc::{boxed::Box, vec::Vec};
+use api::layout;
+use core::ptr::{addr_of_mut, NonNull};
+use rbtree::{container_of, Augment, RbLink, RbTree};
+use x86_64::memory::{Address, VirtualAddress};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    Vmalloc,
+    Mmio,
+    PerCpu,
+    Modules,
+}
+
+impl Zone {
+    pub const ALL: [Zone; 4] = [Zone::Vmalloc, Zone::Mmio, Zone::PerCpu, Zone::Modules];
+
+    pub fn range(self) -> layout::Zone {
+        match self {
+            Zone::Vmalloc => layout::VMALLOC,
+            Zone::Mmio => layout::MMIO,
+            Zone::PerCpu => layout::PER_CPU,
+            Zone::Modules => layout::MODULES,
+        }
+    }
+
+    /// The zone `address` lies in
+    pub fn containing(address: VirtualAddress) -> Option<Zone> {
+        Zone::ALL
+            .into_iter()
+            .find(|zone| zone.range().contains(address.as_u64()))
+    }
+}
+
+/// A range in use
+struct UsedRange {
+    link: RbLink,
+    start: u64,
+    end: u64,
+    // start of the lowest and end of the highest range in the subtree
+    first: u64,
+    last: u64,
+    // largest gap between two ranges of the subtree
+    gap: u64,
+}
+
+impl UsedRange {
+    fn pointer(link: NonNull<RbLink>) -> *mut UsedRange {
+        unsafe { container_of!(link.as_ptr(), UsedRange, link) }
+    }
+
+    unsafe fn from_link<'a>(link: NonNull<RbLink>) -> &'a mut UsedRange {
+        &mut *Self::pointer(link)
+    }
+}
+
+struct Gaps;
+
+impl Augment for Gaps {
+    unsafe fn update(link: NonNull<RbLink>) {
+        let range = UsedRange::from_link(link);
+        let left = range.link.left().map(|l| UsedRange::from_link(l));
+        let right = range.link.right().map(|r| UsedRange::from_link(r));
+
+        range.first = left.as_ref().map_or(range.start, |l| l.first);
+        range.last = right.as_ref().map_or(range.end, |r| r.last);
+        range.gap = 0;
+        if let Some(left) = left {
+            range.gap = range.gap.max(left.gap).max(range.start - left.last);
+        }
+        if let Some(right) = right {
+            range.gap = range.gap.max(right.gap).max(right.first - range.end);
+        }
+    }
+}
+
+/// Start of `size` bytes aligned to `alignment` within `start..end`
+fn fit(start: u64, end: u64, size: u64, alignment: u64) -> Option<u64> {
+    let aligned = VirtualAddress::new(start).align_up(alignment).as_u64();
+    (aligned.checked_add(size)? <= end).then_some(aligned)
+}
+
+/// Lowest fitting gap between two ranges of the subtree of `link`
+unsafe fn find_gap(link: Option<NonNull<RbLink>>, size: u64, alignment: u64) -> Option<u64> {
+    let range = UsedRange::from_link(link?);
+    if range.gap < size {
+        return None;
+    }
+
+    if let Some(left) = range.link.left() {
+        if let Some(start) = find_gap(Some(left), size, alignment) {
+            return Some(start);
+        }
+        let left = UsedRange::from_link(left);
+        if let Some(start) = fit(left.last, range.start, size, alignment) {
+            return Some(start);
+        }
+    }
+    if let Some(right) = range.link.right() {
+        if let Some(start) = fit(
+            range.end,
+            UsedRange::from_link(right).first,
+            size,
+            alignment,
+        ) {
+            return Some(start);
+        }
+        return find_gap(Some(right), size, alignment);
+    }
+    None
+}
+
+pub struct VirtualRangeAllocator {
+    /// Ranges in use of every zone
+    used: [RbTree<Gaps>; Zone::ALL.len()],
+    /// Bytes in use of every zone
+    used_size: [u64; Zone::ALL.len()],
+}
+
+impl VirtualRangeAllocator {
+    pub fn new() -> Self {
+        Self {
+            used: Zone::ALL.map(|_| RbTree::new()),
+            used_size: [0; Zone::ALL.len()],
+        }
+    }
+
+    /// Takes the lowest free range of `size` bytes in `zone` that starts at a
+    /// multiple of `alignment`
+    pub fn allocate(&mut self, zone: Zone, size: u64, alignment: u64) -> Option<VirtualAddress> {
+        assert!(size > 0 && alignment.is_power_of_two());
+        let (zone_start, zone_end) = (zone.range().start, zone.range().end());
+        let tree = &self.used[zone as usize];
+
+        let start = match tree.root() {
+            None => fit(zone_start, zone_end, size, alignment),
+            Some(link) => unsafe {
+                let root = UsedRange::from_link(link);
+                fit(zone_start, root.first, size, alignment)
+                    .or_else(|| find_gap(Some(link), size, alignment))
+                    .or_else(|| fit(root.last, zone_end, size, alignment))
+            },
+        }?;
+        self.insert(zone, start, start + size);
+        Some(VirtualAddress::new(start))
+    }
+
+    /// Marks a range at a fixed address as used. Returns false if a part of
+    /// it is in use already or it crosses the end of a zone.
+    pub fn reserve(&mut self, start: VirtualAddress, size: u64) -> bool {
+        let Some(zone) = Zone::containing(start) else {
+            return true;
+        };
+        let (start, end) = (start.as_u64(), start.as_u64() + size);
+        if end > zone.range().end() {
+            return false;
+        }
+
+        // ranges don't overlap, so only the last one starting below the end
+        // can reach into the new one
+        let overlaps = self
+            .last_starting_below(zone, end)
+            .is_some_and(|link| unsafe { UsedRange::from_link(link).end > start });
+        if overlaps {
+            return false;
+        }
+        self.insert(zone, start, end);
+        true
+    }
+
+    /// Returns a range handed out by [`allocate`](Self::allocate) or
+    /// [`reserve`](Self::reserve)
+    pub fn free(&mut self, start: VirtualAddress, size: u64) {
+        let Some(zone) = Zone::containing(start) else {
+            return;
+        };
+        let (start, end) = (start.as_u64(), start.as_u64() + size);
+
+        let link = self
+            .last_starting_below(zone, start + 1)
+            .filter(|link| unsafe {
+                let range = UsedRange::from_link(*link);
+                range.start == start && range.end == end
+            })
+            .unwrap_or_else(|| panic!("Range {:#x} - {:#x} is not in use", start, end));
+        unsafe {
+            self.used[zone as usize].remove(link);
+            drop(Box::from_raw(UsedRange::pointer(link)));
+        }
+        self.used_size[zone as usize] -= size;
+    }
+
+    /// Bytes of `zone` that are not in use
+    pub fn free_size(&self, zone: Zone) -> u64 {
+        zone.range().size - self.used_size[zone as usize]
+    }
+
+    /// The range in use with the highest start below `address`
+    fn last_starting_below(&self, zone: Zone, address: u64) -> Option<NonNull<RbLink>> {
+        let mut current = self.used[zone as usize].root();
+        let mut best = None;
+        while let Some(link) = current {
+            let range = unsafe { UsedRange::from_link(link) };
+            if range.start < address {
+                best = Some(link);
+                current = range.link.right();
+            } else {
+                current = range.link.left();
+            }
+        }
+        best
+    }
+
+    fn insert(&mut self, zone: Zone, start: u64, end: u64) {
+        let range = Box::into_raw(Box::new(UsedRange {
+            link: RbLink::new(),
+            start,
+            end,
+            first: start,
+            last: end,
+            gap: 0,
+        }));
+        unsafe {
+            let link = NonNull::new_unchecked(addr_of_mut!((*range).link));
+            self.used[zone as usize]
+                .insert_by(link, |link| start < UsedRange::from_link(link).start);
+        }
+        self.used_size[zone as usize] += end - start;
+    }
+}
+
+impl Default for VirtualRangeAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for VirtualRangeAllocator {
+    fn drop(&mut self) {
+        for tree in self.used.iter_mut() {
+            let links: Vec<_> = tree.iter().collect();
+            tree.clear();
+            for link in links {
+                drop(unsafe { Box::from_raw(UsedRange::pointer(link)) });
+            }
+        }
+    }
+}
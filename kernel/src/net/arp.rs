@@ -0,0 +1,168 @@
+//! Address Resolution Protocol.
+//!
+//! Every interface has an [`ArpCache`] mapping IPv4 addresses of its network
+//! to MAC addresses. Packets to an unresolved address wait in the cache while
+//! a request is broadcast, the reply sends them. Requests for the address of
+//! the interface are answered, the sender of any ARP packet is learned.
+extern crate alloc;
+use super::{ethernet::MacAddress, ipv4::Ipv4Address, NetError};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::time::Duration;
+
+pub const PACKET_SIZE: usize = 28;
+/// Time a resolved address is trusted
+pub const ENTRY_LIFETIME: Duration = Duration::from_secs(60);
+/// Time until a request is repeated
+pub const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+/// Packets that can wait for an address, older ones are dropped
+pub const MAX_PENDING: usize = 8;
+
+const HARDWARE_ETHERNET: u16 = 1;
+const PROTOCOL_IPV4: u16 = 0x0800;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpOperation {
+    Request,
+    Reply,
+}
+
+/// ARP packet for IPv4 over Ethernet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: ArpOperation,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Address,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Address,
+}
+
+impl ArpPacket {
+    pub fn parse(data: &[u8]) -> Result<Self, NetError> {
+        if data.len() < PACKET_SIZE {
+            return Err(NetError::Truncated);
+        }
+        let hardware = u16::from_be_bytes([data[0], data[1]]);
+        let protocol = u16::from_be_bytes([data[2], data[3]]);
+        if hardware != HARDWARE_ETHERNET
+            || protocol != PROTOCOL_IPV4
+            || data[4] != 6
+            || data[5] != 4
+        {
+            return Err(NetError::Unsupported);
+        }
+        let operation = match u16::from_be_bytes([data[6], data[7]]) {
+            1 => ArpOperation::Request,
+            2 => ArpOperation::Reply,
+            _ => return Err(NetError::Unsupported),
+        };
+
+        let mac = |offset: usize| MacAddress(data[offset..offset + 6].try_into().unwrap());
+        let ip = |offset: usize| Ipv4Address(data[offset..offset + 4].try_into().unwrap());
+        Ok(Self {
+            operation,
+            sender_mac: mac(8),
+            sender_ip: ip(14),
+            target_mac: mac(18),
+            target_ip: ip(24),
+        })
+    }
+
+    pub fn serialize(&self) -> [u8; PACKET_SIZE] {
+        let operation: u16 = match self.operation {
+            ArpOperation::Request => 1,
+            ArpOperation::Reply => 2,
+        };
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        packet[2..4].copy_from_slice(&PROTOCOL_IPV4.to_be_bytes());
+        packet[4] = 6;
+        packet[5] = 4;
+        packet[6..8].copy_from_slice(&operation.to_be_bytes());
+        packet[8..14].copy_from_slice(&self.sender_mac.0);
+        packet[14..18].copy_from_slice(&self.sender_ip.0);
+        packet[18..24].copy_from_slice(&self.target_mac.0);
+        packet[24..28].copy_from_slice(&self.target_ip.0);
+        packet
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpEntry {
+    pub mac: MacAddress,
+    /// Uptime the entry becomes stale at
+    pub expires: Duration,
+}
+
+/// Packets waiting for an address
+struct Pending {
+    packets: Vec<Vec<u8>>,
+    /// Uptime of the last request
+    requested: Duration,
+}
+
+#[derive(Default)]
+pub struct ArpCache {
+    entries: BTreeMap<Ipv4Address, ArpEntry>,
+    pending: BTreeMap<Ipv4Address, Pending>,
+}
+
+impl ArpCache {
+    pub const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// MAC address of `ip` unless it is unknown or stale at uptime `now`
+    pub fn lookup(&self, ip: Ipv4Address, now: Duration) -> Option<MacAddress> {
+        self.entries
+            .get(&ip)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.mac)
+    }
+
+    /// Learns that `ip` belongs to `mac`, returns the packets that waited for it
+    pub fn insert(&mut self, ip: Ipv4Address, mac: MacAddress, now: Duration) -> Vec<Vec<u8>> {
+        self.entries.insert(
+            ip,
+            ArpEntry {
+                mac,
+                expires: now + ENTRY_LIFETIME,
+            },
+        );
+        self.pending
+            .remove(&ip)
+            .map(|pending| pending.packets)
+            .unwrap_or_default()
+    }
+
+    /// Stores `packet` until `ip` is resolved. Returns whether a request has
+    /// to be sent.
+    pub fn queue(&mut self, ip: Ipv4Address, packet: Vec<u8>, now: Duration) -> bool {
+        let pending = self.pending.entry(ip).or_insert(Pending {
+            packets: Vec::new(),
+            requested: Duration::ZERO,
+        });
+        if pending.packets.len() == MAX_PENDING {
+            pending.packets.remove(0);
+        }
+        pending.packets.push(packet);
+
+        let request = pending.packets.len() == 1 || now >= pending.requested + REQUEST_INTERVAL;
+        if request {
+            pending.requested = now;
+        }
+        request
+    }
+
+    /// Resolved addresses, including stale ones
+    pub fn entries(&self) -> impl Iterator<Item = (Ipv4Address, ArpEntry)> + '_ {
+        self.entries.iter().map(|(ip, entry)| (*ip, *entry))
+    }
+
+    /// Amount of packets waiting for an address
+    pub fn pending(&self) -> usize {
+        self.pending.values().map(|p| p.packets.len()).sum()
+    }
+}
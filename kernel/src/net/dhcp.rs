@@ -0,0 +1,333 @@
+//! DHCP client.
+//!
+//! [`configure`] broadcasts a DISCOVER on an interface, requests the first
+//! address offered and applies the lease once the server acknowledged it:
+//! the interface gets the address, netmask and router, [`dns`] the servers
+//! of option 6. Every message gets [`TIMEOUT`] to be answered, the exchange
+//! is retried [`ATTEMPTS`] times.
+//!
+//! [`maintain`] keeps an interface configured: the lease is renewed after
+//! half of its time, failed attempts are repeated every [`RETRY_DELAY`] and
+//! the configuration is dropped once the lease expired.
+extern crate alloc;
+use super::{
+    dns,
+    ethernet::MacAddress,
+    interface::{Interface, Ipv4Config},
+    ipv4::{self, Ipv4Address, Protocol},
+    udp::{SocketAddress, UdpDatagram, UdpSocket},
+    NetError,
+};
+use crate::{executor::sleep, info, random, time};
+use alloc::vec::Vec;
+use core::time::Duration;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+/// Time a server has to answer a message
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+/// Exchanges started before giving up
+pub const ATTEMPTS: usize = 3;
+/// Time between failed attempts of [`maintain`] to obtain a lease
+pub const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Fixed fields up to the options
+const HEADER_SIZE: usize = 236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+/// Asks the server to broadcast its replies, there is no address to send
+/// them to yet
+const FLAG_BROADCAST: u16 = 1 << 15;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Ack,
+    Nak,
+    Unknown(u8),
+}
+
+impl From<u8> for MessageType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            other => MessageType::Unknown(other),
+        }
+    }
+}
+
+impl From<MessageType> for u8 {
+    fn from(message_type: MessageType) -> Self {
+        match message_type {
+            MessageType::Discover => 1,
+            MessageType::Offer => 2,
+            MessageType::Request => 3,
+            MessageType::Ack => 5,
+            MessageType::Nak => 6,
+            MessageType::Unknown(other) => other,
+        }
+    }
+}
+
+/// Configuration offered or acknowledged by a server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub message_type: MessageType,
+    /// Transaction the message answers
+    pub xid: u32,
+    pub address: Ipv4Address,
+    pub server: Ipv4Address,
+    pub netmask: Ipv4Address,
+    pub router: Option<Ipv4Address>,
+    pub dns_servers: Vec<Ipv4Address>,
+    pub lease_time: Option<Duration>,
+}
+
+impl Lease {
+    pub fn config(&self) -> Ipv4Config {
+        Ipv4Config {
+            address: self.address,
+            netmask: self.netmask,
+            gateway: self.router,
+        }
+    }
+}
+
+/// Message of the client, `request` is the offered address and the server
+/// that offered it for a REQUEST
+pub fn message(
+    message_type: MessageType,
+    xid: u32,
+    mac: MacAddress,
+    request: Option<(Ipv4Address, Ipv4Address)>,
+) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + 32);
+    message.extend_from_slice(&[OP_REQUEST, HTYPE_ETHERNET, 6, 0]);
+    message.extend_from_slice(&xid.to_be_bytes());
+    // seconds elapsed
+    message.extend_from_slice(&[0, 0]);
+    message.extend_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    // client, offered, next server and relay address
+    message.resize(28, 0);
+    message.extend_from_slice(&mac.0);
+    // rest of the hardware address, server name and boot file
+    message.resize(HEADER_SIZE, 0);
+
+    message.extend_from_slice(&MAGIC_COOKIE);
+    message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type.into()]);
+    if let Some((address, server)) = request {
+        message.extend_from_slice(&[OPTION_REQUESTED_ADDRESS, 4]);
+        message.extend_from_slice(&address.0);
+        message.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+        message.extend_from_slice(&server.0);
+    }
+    message.extend_from_slice(&[
+        OPTION_PARAMETERS,
+        3,
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS_SERVERS,
+    ]);
+    message.push(OPTION_END);
+    message
+}
+
+/// Parses the reply of a server. Replies without a message type or server
+/// identifier are [`NetError::Unsupported`].
+pub fn parse_reply(data: &[u8]) -> Result<Lease, NetError> {
+    if data.len() < HEADER_SIZE + MAGIC_COOKIE.len() {
+        return Err(NetError::Truncated);
+    }
+    if data[0] != OP_REPLY || data[HEADER_SIZE..HEADER_SIZE + 4] != MAGIC_COOKIE {
+        return Err(NetError::Unsupported);
+    }
+    let address = |offset: usize| Ipv4Address(data[offset..offset + 4].try_into().unwrap());
+
+    let mut message_type = None;
+    let mut server = None;
+    let mut netmask = Ipv4Address::BROADCAST;
+    let mut router = None;
+    let mut dns_servers = Vec::new();
+    let mut lease_time = None;
+    let mut offset = HEADER_SIZE + MAGIC_COOKIE.len();
+    loop {
+        let option = *data.get(offset).ok_or(NetError::Truncated)?;
+        match option {
+            OPTION_END => break,
+            OPTION_PAD => {
+                offset += 1;
+                continue;
+            }
+            _ => (),
+        }
+        let len = *data.get(offset + 1).ok_or(NetError::Truncated)? as usize;
+        let start = offset + 2;
+        let value = data.get(start..start + len).ok_or(NetError::Truncated)?;
+        match (option, len) {
+            (OPTION_MESSAGE_TYPE, 1) => message_type = Some(MessageType::from(value[0])),
+            (OPTION_SERVER_ID, 4) => server = Some(address(start)),
+            (OPTION_SUBNET_MASK, 4) => netmask = address(start),
+            // the first router is the preferred one
+            (OPTION_ROUTER, 4..) => router = Some(address(start)),
+            (OPTION_DNS_SERVERS, _) => {
+                dns_servers = (0..len / 4).map(|i| address(start + i * 4)).collect()
+            }
+            (OPTION_LEASE_TIME, 4) => {
+                lease_time = Some(Duration::from_secs(
+                    u32::from_be_bytes(value.try_into().unwrap()) as u64,
+                ))
+            }
+            _ => (),
+        }
+        offset = start + len;
+    }
+
+    Ok(Lease {
+        message_type: message_type.ok_or(NetError::Unsupported)?,
+        xid: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+        address: address(16),
+        server: server.ok_or(NetError::Unsupported)?,
+        netmask,
+        router,
+        dns_servers,
+        lease_time,
+    })
+}
+
+/// Obtains a lease for `interface` and applies it, see the module
+/// documentation
+pub async fn configure(interface: &Interface) -> Result<Lease, NetError> {
+    let socket = UdpSocket::new();
+    socket.bind(SocketAddress::new(Ipv4Address::UNSPECIFIED, CLIENT_PORT))?;
+    let mut error = NetError::Timeout;
+    for _ in 0..ATTEMPTS {
+        match request_lease(interface, &socket).await {
+            Ok(lease) => {
+                interface.set_config(lease.config());
+                dns::set_servers(&lease.dns_servers);
+                info!(
+                    "{}: leased {} from {}",
+                    interface.name(),
+                    lease.address,
+                    lease.server
+                );
+                return Ok(lease);
+            }
+            Err(exchange_error) => error = exchange_error,
+        }
+    }
+    Err(error)
+}
+
+/// Configures `interface` and renews its lease until the system shuts
+/// down, see the module documentation
+pub async fn maintain(interface: &Interface) {
+    let mut expiry: Option<Duration> = None;
+    loop {
+        match configure(interface).await {
+            Ok(lease) => {
+                // leases without a time don't expire
+                let Some(lease_time) = lease.lease_time else {
+                    return;
+                };
+                let now = time::uptime();
+                expiry = Some(now + lease_time);
+                sleep::sleep_until(now + lease_time / 2).await;
+            }
+            Err(error) => {
+                info!("{}: no DHCP lease: {:?}", interface.name(), error);
+                let now = time::uptime();
+                match expiry {
+                    Some(deadline) if deadline <= now => {
+                        info!("{}: lease expired", interface.name());
+                        interface.set_config(Ipv4Config::UNCONFIGURED);
+                        dns::set_servers(&[]);
+                        expiry = None;
+                        sleep::sleep(RETRY_DELAY).await;
+                    }
+                    // the configuration is dropped on time
+                    Some(deadline) => sleep::sleep_until(deadline.min(now + RETRY_DELAY)).await,
+                    None => sleep::sleep(RETRY_DELAY).await,
+                }
+            }
+        }
+    }
+}
+
+/// DISCOVER and REQUEST of one transaction
+async fn request_lease(interface: &Interface, socket: &UdpSocket) -> Result<Lease, NetError> {
+    let xid = random::random_u64() as u32;
+    let mac = interface.mac();
+    let discover = message(MessageType::Discover, xid, mac, None);
+    let offer = exchange(interface, socket, &discover, xid, MessageType::Offer).await?;
+    let request = message(
+        MessageType::Request,
+        xid,
+        mac,
+        Some((offer.address, offer.server)),
+    );
+    exchange(interface, socket, &request, xid, MessageType::Ack).await
+}
+
+/// Broadcasts `message` and waits for the reply of type `expected` to
+/// transaction `xid`. A NAK fails the exchange, other replies are dropped.
+async fn exchange(
+    interface: &Interface,
+    socket: &UdpSocket,
+    message: &[u8],
+    xid: u32,
+    expected: MessageType,
+) -> Result<Lease, NetError> {
+    let source = interface.config().address;
+    let datagram = UdpDatagram {
+        source_port: CLIENT_PORT,
+        destination_port: SERVER_PORT,
+        payload: message,
+    };
+    // without an address there is no route, the datagram is sent on the
+    // interface directly
+    ipv4::broadcast(
+        interface,
+        Protocol::Udp,
+        &datagram.serialize(source, Ipv4Address::BROADCAST),
+    )?;
+
+    let deadline = time::uptime() + TIMEOUT;
+    loop {
+        let remaining = deadline.saturating_sub(time::uptime());
+        let (_, data) = sleep::timeout(remaining, socket.recv())
+            .await
+            .ok_or(NetError::Timeout)??;
+        let Ok(reply) = parse_reply(&data) else {
+            continue;
+        };
+        if reply.xid != xid {
+            continue;
+        }
+        if reply.message_type == MessageType::Nak {
+            return Err(NetError::ServerFailure);
+        }
+        if reply.message_type == expected {
+            return Ok(reply);
+        }
+    }
+}
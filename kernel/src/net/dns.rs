@@ -0,0 +1,283 @@
+//! DNS stub resolver.
+//!
+//! [`resolve`] asks the configured servers for the A records of a name over
+//! UDP. Every server gets [`TIMEOUT`] to answer, all servers are tried
+//! [`ATTEMPTS`] times. Answers are cached for their time to live. The
+//! resolver is async so tasks can use it directly, threads and the
+//! [`sys_resolve`] system call use [`resolve_blocking`].
+//!
+//! The servers are set with [`set_servers`], usually by [`dhcp`](super::dhcp)
+//! from option 6 of its lease.
+extern crate alloc;
+use super::{
+    ipv4::Ipv4Address,
+    udp::{SocketAddress, UdpSocket},
+    NetError,
+};
+use crate::{
+    executor::{self, sleep},
+    random,
+    syscall::{Errno, SyscallResult},
+    time,
+};
+use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+use core::{slice, str, time::Duration};
+use x86_64::mutex::Mutex;
+
+pub const PORT: u16 = 53;
+pub const MAX_NAME_LENGTH: usize = 253;
+const MAX_LABEL_LENGTH: usize = 63;
+/// Time a server has to answer a query
+pub const TIMEOUT: Duration = Duration::from_secs(1);
+/// Rounds over all servers before giving up
+pub const ATTEMPTS: usize = 3;
+pub const MAX_CACHED: usize = 64;
+/// Answers aren't cached longer, whatever their time to live
+pub const MAX_TTL: Duration = Duration::from_secs(60 * 60);
+
+const HEADER_SIZE: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 1 << 15;
+const FLAG_RECURSION_DESIRED: u16 = 1 << 8;
+const RCODE_MASK: u16 = 0xf;
+const RCODE_NAME_ERROR: u16 = 3;
+
+/// Addresses of a name and how long they may be cached
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Answer {
+    pub addresses: Vec<Ipv4Address>,
+    pub ttl: Duration,
+}
+
+/// Lowercase `name` without a trailing dot, if it is a valid host name
+fn normalize(name: &str) -> Result<String, NetError> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(NetError::InvalidName);
+    }
+    let valid_label = |label: &str| {
+        (1..=MAX_LABEL_LENGTH).contains(&label.len())
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    };
+    if !name.split('.').all(valid_label) {
+        return Err(NetError::InvalidName);
+    }
+    Ok(name.to_ascii_lowercase())
+}
+
+/// Query for the A records of `name` with the recursion desired flag
+pub fn query(id: u16, name: &str) -> Result<Vec<u8>, NetError> {
+    let name = normalize(name)?;
+    let mut message = Vec::with_capacity(HEADER_SIZE + name.len() + 6);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // one question, no answer, authority or additional records
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&TYPE_A.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// Offset after the name at `offset`, names may end in a compression pointer
+fn skip_name(data: &[u8], mut offset: usize) -> Result<usize, NetError> {
+    loop {
+        let len = *data.get(offset).ok_or(NetError::Truncated)?;
+        match len >> 6 {
+            0 if len == 0 => return Ok(offset + 1),
+            0 => offset += 1 + len as usize,
+            0b11 if offset + 2 <= data.len() => return Ok(offset + 2),
+            0b11 => return Err(NetError::Truncated),
+            _ => return Err(NetError::Unsupported),
+        }
+    }
+}
+
+/// Parses a response to a [`query`]. Responses without A records are
+/// [`NetError::NameNotFound`], like the ones for names that don't exist.
+pub fn parse_response(data: &[u8]) -> Result<Answer, NetError> {
+    if data.len() < HEADER_SIZE {
+        return Err(NetError::Truncated);
+    }
+    let word = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+    let flags = word(2);
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(NetError::Unsupported);
+    }
+    match flags & RCODE_MASK {
+        0 => (),
+        RCODE_NAME_ERROR => return Err(NetError::NameNotFound),
+        _ => return Err(NetError::ServerFailure),
+    }
+
+    let mut offset = HEADER_SIZE;
+    for _ in 0..word(4) {
+        offset = skip_name(data, offset)? + 4;
+    }
+    let mut addresses = Vec::new();
+    let mut ttl = MAX_TTL;
+    for _ in 0..word(6) {
+        offset = skip_name(data, offset)?;
+        if data.len() < offset + 10 {
+            return Err(NetError::Truncated);
+        }
+        let record_type = word(offset);
+        let class = word(offset + 2);
+        let record_ttl = u32::from_be_bytes(data[offset + 4..offset + 8].try_into().unwrap());
+        let len = word(offset + 8) as usize;
+        offset += 10;
+        let record = data.get(offset..offset + len).ok_or(NetError::Truncated)?;
+        offset += len;
+
+        // CNAME records the server followed precede the A records
+        if record_type == TYPE_A && class == CLASS_IN && len == 4 {
+            addresses.push(Ipv4Address::new(record[0], record[1], record[2], record[3]));
+            ttl = ttl.min(Duration::from_secs(record_ttl as u64));
+        }
+    }
+    if addresses.is_empty() {
+        return Err(NetError::NameNotFound);
+    }
+    Ok(Answer { addresses, ttl })
+}
+
+static SERVERS: Mutex<Vec<Ipv4Address>> = Mutex::new(Vec::new());
+/// Addresses and when they expire by name
+static CACHE: Mutex<BTreeMap<String, (Vec<Ipv4Address>, Duration)>> = Mutex::new(BTreeMap::new());
+
+/// Replaces the servers queries are sent to, in the order they are tried
+pub fn set_servers(servers: &[Ipv4Address]) {
+    *SERVERS.lock() = servers.to_vec();
+}
+
+pub fn servers() -> Vec<Ipv4Address> {
+    SERVERS.lock().clone()
+}
+
+/// Cached addresses of `name` unless they expired
+pub fn cached(name: &str) -> Option<Vec<Ipv4Address>> {
+    let name = normalize(name).ok()?;
+    let mut cache = CACHE.lock();
+    let (addresses, expires) = cache.get(&name)?;
+    if time::uptime() < *expires {
+        return Some(addresses.clone());
+    }
+    cache.remove(&name);
+    None
+}
+
+fn insert_cached(name: String, answer: &Answer) {
+    if answer.ttl.is_zero() {
+        return;
+    }
+    let now = time::uptime();
+    let mut cache = CACHE.lock();
+    cache.retain(|_, (_, expires)| now < *expires);
+    if cache.len() >= MAX_CACHED {
+        let first_to_expire = cache
+            .iter()
+            .min_by_key(|(_, (_, expires))| *expires)
+            .map(|(name, _)| name.clone());
+        if let Some(name) = first_to_expire {
+            cache.remove(&name);
+        }
+    }
+    cache.insert(
+        name,
+        (answer.addresses.clone(), now + answer.ttl.min(MAX_TTL)),
+    );
+}
+
+pub fn flush_cache() {
+    CACHE.lock().clear();
+}
+
+/// Resolves `name` to its addresses. Addresses in dotted decimal notation
+/// and `localhost` resolve without a query. Fails with
+/// [`NetError::NoRoute`] if no servers are configured.
+pub async fn resolve(name: &str) -> Result<Vec<Ipv4Address>, NetError> {
+    if let Ok(address) = name.parse::<Ipv4Address>() {
+        return Ok(vec![address]);
+    }
+    let name = normalize(name)?;
+    if name == "localhost" {
+        return Ok(vec![Ipv4Address::LOCALHOST]);
+    }
+    if let Some(addresses) = cached(&name) {
+        return Ok(addresses);
+    }
+
+    let servers = servers();
+    if servers.is_empty() {
+        return Err(NetError::NoRoute);
+    }
+    let socket = UdpSocket::new();
+    let mut error = NetError::Timeout;
+    for _ in 0..ATTEMPTS {
+        for server in &servers {
+            let server = SocketAddress::new(*server, PORT);
+            let id = random::random_u64() as u16;
+            if let Err(send_error) = socket.send_to(&query(id, &name)?, server) {
+                error = send_error;
+                continue;
+            }
+            match reply(&socket, server, id).await {
+                Ok(answer) => {
+                    insert_cached(name, &answer);
+                    return Ok(answer.addresses);
+                }
+                Err(NetError::NameNotFound) => return Err(NetError::NameNotFound),
+                Err(reply_error) => error = reply_error,
+            }
+        }
+    }
+    Err(error)
+}
+
+/// Waits for the response of `server` to query `id`, other datagrams and
+/// late responses to earlier queries are dropped
+async fn reply(socket: &UdpSocket, server: SocketAddress, id: u16) -> Result<Answer, NetError> {
+    let deadline = time::uptime() + TIMEOUT;
+    loop {
+        let remaining = deadline.saturating_sub(time::uptime());
+        let (source, data) = sleep::timeout(remaining, socket.recv())
+            .await
+            .ok_or(NetError::Timeout)??;
+        if source == server && data.get(..2) == Some(&id.to_be_bytes()[..]) {
+            return parse_response(&data);
+        }
+    }
+}
+
+/// [`resolve`] for threads, blocks until it completes
+pub fn resolve_blocking(name: &str) -> Result<Vec<Ipv4Address>, NetError> {
+    let name = String::from(name);
+    // the executor only refuses tasks if there are too many
+    executor::block_on(async move { resolve(&name).await }).map_err(|_| NetError::QueueFull)?
+}
+
+/// `resolve(name, len, addresses, count)` system call. Stores up to `count`
+/// addresses of the name in host byte order into the `u32` array at
+/// `addresses` and returns how many.
+pub fn sys_resolve(name: u64, len: u64, addresses: u64, count: u64) -> SyscallResult {
+    if name == 0 || addresses == 0 || !addresses.is_multiple_of(core::mem::align_of::<u32>() as u64)
+    {
+        return Err(Errno::EFAULT);
+    }
+    let name = unsafe { slice::from_raw_parts(name as *const u8, len as usize) };
+    let name = str::from_utf8(name).map_err(|_| Errno::EINVAL)?;
+    let resolved = resolve_blocking(name)?;
+
+    let addresses = unsafe { slice::from_raw_parts_mut(addresses as *mut u32, count as usize) };
+    for (raw, address) in addresses.iter_mut().zip(&resolved) {
+        *raw = address.as_u32();
+    }
+    Ok(resolved.len().min(addresses.len()) as u64)
+}
@@ -0,0 +1,342 @@
+//! Driver for the Intel 82540EM, the network card QEMU emulates by default.
+//!
+//! Frames are exchanged through rings of legacy descriptors in DMA memory,
+//! each pointing to a buffer of [`BUFFER_SIZE`] bytes. The card raises its
+//! PCI interrupt when frames were received, the handler only acknowledges it
+//! and [`notify`](super::notify)s the stack, which then polls the receive
+//! ring. Sent descriptors are reclaimed once the card wrote them back.
+//!
+//! https://wiki.osdev.org/Intel_Ethernet_i217
+extern crate alloc;
+use super::{ethernet::MacAddress, NetDevice, NetError};
+use crate::{
+    interrupts::{self, PciIrqError},
+    memory::{self, DmaBuffer, MemoryError, Mmio},
+    pci::{self, Bar},
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::{hint::spin_loop, mem::size_of, ptr};
+use x86_64::{
+    interrupts::without_interrupts, memory::Address, mutex::Mutex, paging::CacheAttribute,
+};
+
+const VENDOR_INTEL: u16 = 0x8086;
+const DEVICE_82540EM: u16 = 0x100e;
+
+const CTRL: u64 = 0x0000;
+const ICR: u64 = 0x00c0;
+const IMS: u64 = 0x00d0;
+const IMC: u64 = 0x00d8;
+const RCTL: u64 = 0x0100;
+const TCTL: u64 = 0x0400;
+const TIPG: u64 = 0x0410;
+const RDBAL: u64 = 0x2800;
+const RDBAH: u64 = 0x2804;
+const RDLEN: u64 = 0x2808;
+const RDH: u64 = 0x2810;
+const RDT: u64 = 0x2818;
+const TDBAL: u64 = 0x3800;
+const TDBAH: u64 = 0x3804;
+const TDLEN: u64 = 0x3808;
+const TDH: u64 = 0x3810;
+const TDT: u64 = 0x3818;
+/// Multicast table, 128 entries
+const MTA: u64 = 0x5200;
+const RAL0: u64 = 0x5400;
+const RAH0: u64 = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+const RCTL_EN: u32 = 1 << 1;
+/// Accept broadcast frames
+const RCTL_BAM: u32 = 1 << 15;
+/// Strip the CRC, the buffer size bits are left at 0 for 2048 bytes
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+/// Pad short packets
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+/// Inter packet gap recommended for IEEE 802.3
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+const ICR_RXDMT0: u32 = 1 << 4;
+const ICR_RXO: u32 = 1 << 6;
+const ICR_RXT0: u32 = 1 << 7;
+const ICR_RX: u32 = ICR_RXDMT0 | ICR_RXO | ICR_RXT0;
+
+/// Address valid bit of RAH
+const RAH_AV: u32 = 1 << 31;
+
+const DESCRIPTOR_DD: u8 = 1 << 0;
+const DESCRIPTOR_EOP: u8 = 1 << 1;
+const COMMAND_EOP: u8 = 1 << 0;
+const COMMAND_IFCS: u8 = 1 << 1;
+/// Report the status, sets DD once the frame was sent
+const COMMAND_RS: u8 = 1 << 3;
+
+/// Descriptors per ring, the ring size has to be a multiple of 128 bytes
+const RING_SIZE: usize = 32;
+pub const BUFFER_SIZE: usize = 2048;
+
+/// Polls of the control register after a reset before giving up
+const RESET_TIMEOUT: usize = 1_000_000;
+
+#[derive(Debug)]
+pub enum E1000Error {
+    /// BAR 0 isn't a memory BAR
+    NoRegisters,
+    ResetTimeout,
+    Memory(MemoryError),
+    Irq(PciIrqError),
+}
+
+impl From<MemoryError> for E1000Error {
+    fn from(error: MemoryError) -> Self {
+        E1000Error::Memory(error)
+    }
+}
+
+impl From<PciIrqError> for E1000Error {
+    fn from(error: PciIrqError) -> Self {
+        E1000Error::Irq(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct RxDescriptor {
+    address: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct TxDescriptor {
+    address: u64,
+    length: u16,
+    checksum_offset: u8,
+    command: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+/// Descriptors and their buffers
+struct Ring {
+    descriptors: DmaBuffer,
+    buffers: DmaBuffer,
+    /// Next descriptor to receive into or send from
+    next: usize,
+}
+
+impl Ring {
+    fn new<T>() -> Result<Self, MemoryError> {
+        memory::with_memory_manager(|mm| {
+            let descriptors = mm.allocate_dma(RING_SIZE * size_of::<T>(), u64::MAX)?;
+            match mm.allocate_dma(RING_SIZE * BUFFER_SIZE, u64::MAX) {
+                Ok(buffers) => Ok(Self {
+                    descriptors,
+                    buffers,
+                    next: 0,
+                }),
+                Err(error) => {
+                    mm.free_dma(descriptors);
+                    Err(error)
+                }
+            }
+        })
+    }
+
+    fn descriptor<T>(&self, index: usize) -> *mut T {
+        (self.descriptors.virtual_address() + index * size_of::<T>()).as_mut_ptr()
+    }
+
+    fn buffer_address(&self, index: usize) -> u64 {
+        self.buffers.physical_address().as_u64() + (index * BUFFER_SIZE) as u64
+    }
+
+    fn buffer(&mut self, index: usize) -> &mut [u8] {
+        &mut self.buffers.as_mut_slice()[index * BUFFER_SIZE..(index + 1) * BUFFER_SIZE]
+    }
+}
+
+pub struct E1000 {
+    registers: Mmio,
+    mac: MacAddress,
+    rx: Mutex<Ring>,
+    tx: Mutex<Ring>,
+}
+
+/// Registers of the card whose interrupt is handled, locked with interrupts
+/// disabled
+static INTERRUPT_REGISTERS: Mutex<Option<Mmio>> = Mutex::new(None);
+
+/// Initializes the first 82540EM on the PCI bus, None if there is none
+pub fn probe() -> Result<Option<Arc<E1000>>, E1000Error> {
+    let Some(device) = pci::find(VENDOR_INTEL, DEVICE_82540EM) else {
+        return Ok(None);
+    };
+    let Some(Bar::Memory { address, size }) = device.bar(0) else {
+        return Err(E1000Error::NoRegisters);
+    };
+    device.enable_bus_master();
+    let registers = memory::ioremap(address, size, CacheAttribute::Uncached)?;
+
+    let e1000 = Arc::new(E1000::new(registers)?);
+    without_interrupts(|| *INTERRUPT_REGISTERS.lock() = Some(registers));
+    interrupts::register_pci_irq(device.interrupt_line(), interrupt)?;
+    registers.write::<u32>(IMS, ICR_RX);
+    Ok(Some(e1000))
+}
+
+fn interrupt() {
+    let Some(registers) = *INTERRUPT_REGISTERS.lock() else {
+        return;
+    };
+    // reading the causes acknowledges them and deasserts the line
+    let causes = registers.read::<u32>(ICR);
+    if causes & ICR_RX != 0 {
+        super::notify();
+    }
+}
+
+impl E1000 {
+    fn new(registers: Mmio) -> Result<Self, E1000Error> {
+        registers.write::<u32>(IMC, u32::MAX);
+        registers.write::<u32>(CTRL, registers.read::<u32>(CTRL) | CTRL_RST);
+        let mut polls = 0;
+        while registers.read::<u32>(CTRL) & CTRL_RST != 0 {
+            polls += 1;
+            if polls == RESET_TIMEOUT {
+                return Err(E1000Error::ResetTimeout);
+            }
+            spin_loop();
+        }
+        registers.write::<u32>(IMC, u32::MAX);
+        registers.read::<u32>(ICR);
+        registers.write::<u32>(CTRL, registers.read::<u32>(CTRL) | CTRL_SLU | CTRL_ASDE);
+
+        // the card loads its address from the EEPROM into the first receive
+        // address register
+        let low = registers.read::<u32>(RAL0).to_le_bytes();
+        let high = registers.read::<u32>(RAH0);
+        registers.write::<u32>(RAH0, high | RAH_AV);
+        let high = (high as u16).to_le_bytes();
+        let mac = MacAddress([low[0], low[1], low[2], low[3], high[0], high[1]]);
+        for i in 0..128 {
+            registers.write::<u32>(MTA + i * 4, 0);
+        }
+
+        let rx = Ring::new::<RxDescriptor>()?;
+        for index in 0..RING_SIZE {
+            let descriptor = RxDescriptor {
+                address: rx.buffer_address(index),
+                ..Default::default()
+            };
+            unsafe { ptr::write_volatile(rx.descriptor(index), descriptor) };
+        }
+        let tx = Ring::new::<TxDescriptor>()?;
+        for index in 0..RING_SIZE {
+            // free descriptors look like sent ones
+            let descriptor = TxDescriptor {
+                status: DESCRIPTOR_DD,
+                ..Default::default()
+            };
+            unsafe { ptr::write_volatile(tx.descriptor(index), descriptor) };
+        }
+
+        let rx_base = rx.descriptors.physical_address().as_u64();
+        registers.write::<u32>(RDBAL, rx_base as u32);
+        registers.write::<u32>(RDBAH, (rx_base >> 32) as u32);
+        registers.write::<u32>(RDLEN, (RING_SIZE * size_of::<RxDescriptor>()) as u32);
+        registers.write::<u32>(RDH, 0);
+        // the descriptor at the tail is owned by the driver
+        registers.write::<u32>(RDT, RING_SIZE as u32 - 1);
+        registers.write::<u32>(RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        let tx_base = tx.descriptors.physical_address().as_u64();
+        registers.write::<u32>(TDBAL, tx_base as u32);
+        registers.write::<u32>(TDBAH, (tx_base >> 32) as u32);
+        registers.write::<u32>(TDLEN, (RING_SIZE * size_of::<TxDescriptor>()) as u32);
+        registers.write::<u32>(TDH, 0);
+        registers.write::<u32>(TDT, 0);
+        registers.write::<u32>(TIPG, TIPG_DEFAULT);
+        registers.write::<u32>(TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+
+        Ok(Self {
+            registers,
+            mac,
+            rx: Mutex::new(rx),
+            tx: Mutex::new(tx),
+        })
+    }
+}
+
+impl NetDevice for E1000 {
+    fn name(&self) -> &str {
+        "eth0"
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > BUFFER_SIZE {
+            return Err(NetError::PacketTooLarge);
+        }
+        let mut tx = self.tx.lock();
+        let index = tx.next;
+        let descriptor = tx.descriptor::<TxDescriptor>(index);
+        // the ring is full if the card didn't send the oldest frame yet
+        if unsafe { ptr::read_volatile(descriptor) }.status & DESCRIPTOR_DD == 0 {
+            return Err(NetError::DeviceError);
+        }
+
+        tx.buffer(index)[..frame.len()].copy_from_slice(frame);
+        let sent = TxDescriptor {
+            address: tx.buffer_address(index),
+            length: frame.len() as u16,
+            command: COMMAND_EOP | COMMAND_IFCS | COMMAND_RS,
+            ..Default::default()
+        };
+        unsafe { ptr::write_volatile(descriptor, sent) };
+        tx.next = (index + 1) % RING_SIZE;
+        self.registers.write::<u32>(TDT, tx.next as u32);
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut rx = self.rx.lock();
+        loop {
+            let index = rx.next;
+            let descriptor = rx.descriptor::<RxDescriptor>(index);
+            let received = unsafe { ptr::read_volatile(descriptor) };
+            if received.status & DESCRIPTOR_DD == 0 {
+                return None;
+            }
+
+            // frames spanning several buffers don't fit the MTU and are dropped
+            let frame = (received.status & DESCRIPTOR_EOP != 0 && received.errors == 0)
+                .then(|| rx.buffer(index)[..received.length as usize].to_vec());
+            let free = RxDescriptor {
+                address: rx.buffer_address(index),
+                ..Default::default()
+            };
+            unsafe { ptr::write_volatile(descriptor, free) };
+            rx.next = (index + 1) % RING_SIZE;
+            self.registers.write::<u32>(RDT, index as u32);
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+}
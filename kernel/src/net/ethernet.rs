@@ -0,0 +1,99 @@
+//! Ethernet II frames.
+extern crate alloc;
+use super::NetError;
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const HEADER_SIZE: usize = 14;
+/// Frames are padded to this size, the checksum isn't included
+pub const MIN_FRAME_SIZE: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const ZERO: MacAddress = MacAddress([0; 6]);
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+
+    /// Multicast addresses have the lowest bit of the first byte set
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EtherType {
+    Ipv4,
+    Arp,
+    Unknown(u16),
+}
+
+impl From<u16> for EtherType {
+    fn from(value: u16) -> Self {
+        match value {
+            0x0800 => EtherType::Ipv4,
+            0x0806 => EtherType::Arp,
+            other => EtherType::Unknown(other),
+        }
+    }
+}
+
+impl From<EtherType> for u16 {
+    fn from(ether_type: EtherType) -> Self {
+        match ether_type {
+            EtherType::Ipv4 => 0x0800,
+            EtherType::Arp => 0x0806,
+            EtherType::Unknown(other) => other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EthernetFrame<'a> {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ether_type: EtherType,
+    /// Includes the padding of short frames
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, NetError> {
+        if data.len() < HEADER_SIZE {
+            return Err(NetError::Truncated);
+        }
+        let mac = |offset: usize| MacAddress(data[offset..offset + 6].try_into().unwrap());
+        Ok(Self {
+            destination: mac(0),
+            source: mac(6),
+            ether_type: EtherType::from(u16::from_be_bytes([data[12], data[13]])),
+            payload: &data[HEADER_SIZE..],
+        })
+    }
+
+    /// The frame padded to [`MIN_FRAME_SIZE`]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity((HEADER_SIZE + self.payload.len()).max(MIN_FRAME_SIZE));
+        frame.extend_from_slice(&self.destination.0);
+        frame.extend_from_slice(&self.source.0);
+        frame.extend_from_slice(&u16::from(self.ether_type).to_be_bytes());
+        frame.extend_from_slice(self.payload);
+        frame.resize(frame.len().max(MIN_FRAME_SIZE), 0);
+        frame
+    }
+}
@@ -0,0 +1,208 @@
+//! Minimal HTTP client.
+//!
+//! Downloads files with `GET` requests over [`tcp`](super::tcp), e.g. to
+//! pull test binaries or kernel modules into the running system without
+//! rebuilding the disk image. Only `http://` URLs and HTTP/1.0 are supported,
+//! so the server closes the connection after the body and never uses chunked
+//! encoding. Redirects aren't followed.
+extern crate alloc;
+use super::{dns, tcp::TcpStream, udp::SocketAddress, NetError};
+use crate::fs::{
+    vfs::{self, NodeKind},
+    FsError,
+};
+use alloc::{format, string::String, vec::Vec};
+
+pub const DEFAULT_PORT: u16 = 80;
+/// Longest status line and headers accepted
+pub const MAX_HEADER_SIZE: usize = 8 * 1024;
+const BUFFER_SIZE: usize = 2048;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    /// Absolute path including the query
+    pub path: String,
+}
+
+impl Url {
+    /// Parses `http://host[:port][/path]`
+    pub fn parse(url: &str) -> Result<Self, NetError> {
+        let rest = url.strip_prefix("http://").ok_or(NetError::InvalidUrl)?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| NetError::InvalidUrl)?),
+            None => (authority, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(NetError::InvalidUrl);
+        }
+        Ok(Self {
+            host: String::from(host),
+            port,
+            path: String::from(path),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadError {
+    Net(NetError),
+    Fs(FsError),
+}
+
+impl From<NetError> for DownloadError {
+    fn from(error: NetError) -> Self {
+        DownloadError::Net(error)
+    }
+}
+
+impl From<FsError> for DownloadError {
+    fn from(error: FsError) -> Self {
+        DownloadError::Fs(error)
+    }
+}
+
+/// Status line and headers of a response
+struct Head {
+    status: u16,
+    content_length: Option<usize>,
+}
+
+fn parse_head(head: &[u8]) -> Result<Head, NetError> {
+    let head = core::str::from_utf8(head).map_err(|_| NetError::ServerFailure)?;
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().ok_or(NetError::ServerFailure)?;
+    let status = match status_line.split(' ').collect::<Vec<_>>()[..] {
+        [version, status, ..] if version.starts_with("HTTP/1.") => status.parse().ok(),
+        _ => None,
+    }
+    .ok_or(NetError::ServerFailure)?;
+
+    let mut content_length = None;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = Some(value.trim().parse().map_err(|_| NetError::ServerFailure)?);
+            }
+        }
+    }
+    Ok(Head {
+        status,
+        content_length,
+    })
+}
+
+/// Requests `url` and passes the body to `sink` as it arrives. Returns the
+/// length of the body.
+pub fn get_with<F>(url: &str, mut sink: F) -> Result<usize, DownloadError>
+where
+    F: FnMut(&[u8]) -> Result<(), DownloadError>,
+{
+    let url = Url::parse(url)?;
+    let address = *dns::resolve_blocking(&url.host)?
+        .first()
+        .ok_or(NetError::NameNotFound)?;
+    let stream = TcpStream::connect(SocketAddress::new(address, url.port))?;
+    let host = match url.port {
+        DEFAULT_PORT => url.host.clone(),
+        port => format!("{}:{}", url.host, port),
+    };
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: MiniatureOs\r\nConnection: close\r\n\r\n",
+        url.path, host
+    );
+    stream.write_all(request.as_bytes())?;
+
+    // the head, the body might follow in the same segment
+    let mut buf = [0u8; BUFFER_SIZE];
+    let mut received = Vec::new();
+    let head_end = loop {
+        if let Some(idx) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+            break idx;
+        }
+        if received.len() > MAX_HEADER_SIZE {
+            return Err(NetError::Unsupported.into());
+        }
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            return Err(NetError::ServerFailure.into());
+        }
+        received.extend_from_slice(&buf[..len]);
+    };
+    let head = parse_head(&received[..head_end])?;
+    if !(200..300).contains(&head.status) {
+        return Err(NetError::HttpStatus(head.status).into());
+    }
+
+    let mut body = &received[head_end + 4..];
+    let mut length = 0;
+    loop {
+        // anything after the announced length isn't part of the body
+        if let Some(expected) = head.content_length {
+            body = &body[..body.len().min(expected - length)];
+        }
+        if !body.is_empty() {
+            sink(body)?;
+            length += body.len();
+        }
+        if head
+            .content_length
+            .is_some_and(|expected| length >= expected)
+        {
+            break;
+        }
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        body = &buf[..len];
+    }
+    match head.content_length {
+        Some(expected) if length < expected => Err(NetError::Truncated.into()),
+        _ => Ok(length),
+    }
+}
+
+/// Requests `url` and returns the body
+pub fn get(url: &str) -> Result<Vec<u8>, DownloadError> {
+    let mut body = Vec::new();
+    get_with(url, |data| {
+        body.extend_from_slice(data);
+        Ok(())
+    })?;
+    Ok(body)
+}
+
+/// Downloads `url` into the file at `path`, replacing an existing file.
+/// The body is written to `<path>.part` first, so `path` is only replaced
+/// by complete downloads. Returns the size of the file.
+pub fn download(url: &str, path: &str) -> Result<usize, DownloadError> {
+    let partial = format!("{}.part", path);
+    // left over by an interrupted download
+    let _ = vfs::unlink(&partial);
+    let file = vfs::create(&partial, NodeKind::File)?.open()?;
+    let result = get_with(url, |mut data| {
+        while !data.is_empty() {
+            let written = file.write(data)?;
+            data = &data[written..];
+        }
+        Ok(())
+    });
+    drop(file);
+
+    match result {
+        Ok(length) => {
+            vfs::rename(&partial, path)?;
+            Ok(length)
+        }
+        Err(error) => {
+            let _ = vfs::unlink(&partial);
+            Err(error)
+        }
+    }
+}
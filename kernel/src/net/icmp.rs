@@ -0,0 +1,204 @@
+//! Internet Control Message Protocol.
+//!
+//! Echo requests to the address of an interface are answered. [`ping`] sends
+//! an echo request and blocks until the reply arrives or it times out.
+extern crate alloc;
+use super::{
+    interface::Interface,
+    ipv4::{self, Ipv4Address, Ipv4Packet, Protocol},
+    NetError,
+};
+use crate::{
+    scheduler::{self, ThreadId},
+    time,
+};
+use alloc::vec::Vec;
+use core::{
+    sync::atomic::{AtomicU16, Ordering},
+    time::Duration,
+};
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+pub const HEADER_SIZE: usize = 8;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Payload of the echo requests sent by [`ping`]
+const PING_PAYLOAD: &[u8; 32] = b"MiniatureOs ping payload 0123456";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpType {
+    EchoReply,
+    DestinationUnreachable,
+    EchoRequest,
+    TimeExceeded,
+    Unknown(u8),
+}
+
+impl From<u8> for IcmpType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => IcmpType::EchoReply,
+            3 => IcmpType::DestinationUnreachable,
+            8 => IcmpType::EchoRequest,
+            11 => IcmpType::TimeExceeded,
+            other => IcmpType::Unknown(other),
+        }
+    }
+}
+
+impl From<IcmpType> for u8 {
+    fn from(icmp_type: IcmpType) -> Self {
+        match icmp_type {
+            IcmpType::EchoReply => 0,
+            IcmpType::DestinationUnreachable => 3,
+            IcmpType::EchoRequest => 8,
+            IcmpType::TimeExceeded => 11,
+            IcmpType::Unknown(other) => other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IcmpMessage<'a> {
+    pub icmp_type: IcmpType,
+    pub code: u8,
+    /// Rest of the header, identifier and sequence number of echo messages
+    pub rest: [u8; 4],
+    pub payload: &'a [u8],
+}
+
+impl<'a> IcmpMessage<'a> {
+    pub fn echo(icmp_type: IcmpType, identifier: u16, sequence: u16, payload: &'a [u8]) -> Self {
+        let [a, b] = identifier.to_be_bytes();
+        let [c, d] = sequence.to_be_bytes();
+        Self {
+            icmp_type,
+            code: 0,
+            rest: [a, b, c, d],
+            payload,
+        }
+    }
+
+    pub fn identifier(&self) -> u16 {
+        u16::from_be_bytes([self.rest[0], self.rest[1]])
+    }
+
+    pub fn sequence(&self) -> u16 {
+        u16::from_be_bytes([self.rest[2], self.rest[3]])
+    }
+
+    pub fn parse(data: &'a [u8]) -> Result<Self, NetError> {
+        if data.len() < HEADER_SIZE {
+            return Err(NetError::Truncated);
+        }
+        if ipv4::checksum(data) != 0 {
+            return Err(NetError::InvalidChecksum);
+        }
+        Ok(Self {
+            icmp_type: IcmpType::from(data[0]),
+            code: data[1],
+            rest: data[4..8].try_into().unwrap(),
+            payload: &data[HEADER_SIZE..],
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        message.push(u8::from(self.icmp_type));
+        message.push(self.code);
+        message.extend_from_slice(&[0, 0]);
+        message.extend_from_slice(&self.rest);
+        message.extend_from_slice(self.payload);
+        let checksum = ipv4::checksum(&message);
+        message[2..4].copy_from_slice(&checksum.to_be_bytes());
+        message
+    }
+}
+
+/// Echo request waiting for its reply
+struct PendingPing {
+    identifier: u16,
+    sequence: u16,
+    thread: ThreadId,
+    /// Uptime the reply arrived at
+    replied: Option<Duration>,
+}
+
+static NEXT_IDENTIFIER: AtomicU16 = AtomicU16::new(1);
+// locked with interrupts disabled, the pinging thread sleeps in between
+static PINGS: Mutex<Vec<PendingPing>> = Mutex::new(Vec::new());
+
+/// Sends an echo request to `destination` and returns the round trip time
+pub fn ping(
+    destination: Ipv4Address,
+    sequence: u16,
+    timeout: Duration,
+) -> Result<Duration, NetError> {
+    let identifier = NEXT_IDENTIFIER.fetch_add(1, Ordering::Relaxed);
+    let start = time::uptime();
+    without_interrupts(|| {
+        PINGS.lock().push(PendingPing {
+            identifier,
+            sequence,
+            thread: scheduler::current(),
+            replied: None,
+        })
+    });
+
+    let request = IcmpMessage::echo(IcmpType::EchoRequest, identifier, sequence, PING_PAYLOAD);
+    let result = ipv4::send(destination, Protocol::Icmp, &request.serialize())
+        .and_then(|()| wait_for_reply(identifier, start + timeout));
+    without_interrupts(|| PINGS.lock().retain(|p| p.identifier != identifier));
+    Ok(result?.saturating_sub(start))
+}
+
+/// Returns the uptime the reply arrived at
+fn wait_for_reply(identifier: u16, deadline: Duration) -> Result<Duration, NetError> {
+    loop {
+        // the reply is handled by another thread, which can't run in between
+        // the check and sleeping
+        let replied = without_interrupts(|| {
+            let replied = PINGS
+                .lock()
+                .iter()
+                .find(|p| p.identifier == identifier)
+                .and_then(|p| p.replied);
+            let now = time::uptime();
+            if replied.is_none() && now < deadline {
+                scheduler::sleep(deadline - now);
+            }
+            replied
+        });
+        if let Some(replied) = replied {
+            return Ok(replied);
+        }
+        if time::uptime() >= deadline {
+            return Err(NetError::Timeout);
+        }
+    }
+}
+
+/// Handles a message received by `interface`
+pub(super) fn receive(interface: &Interface, packet: &Ipv4Packet) -> Result<(), NetError> {
+    let message = IcmpMessage::parse(packet.payload)?;
+    match message.icmp_type {
+        // broadcast pings are ignored like Linux does by default
+        IcmpType::EchoRequest if packet.destination == interface.config().address => {
+            let reply = IcmpMessage {
+                icmp_type: IcmpType::EchoReply,
+                ..message
+            };
+            ipv4::send(packet.source, Protocol::Icmp, &reply.serialize())
+        }
+        IcmpType::EchoReply => without_interrupts(|| {
+            let mut pings = PINGS.lock();
+            let ping = pings
+                .iter_mut()
+                .find(|p| p.identifier == message.identifier() && p.sequence == message.sequence())
+                .ok_or(NetError::Unsupported)?;
+            ping.replied = Some(time::uptime());
+            scheduler::wake(ping.thread);
+            Ok(())
+        }),
+        _ => Err(NetError::Unsupported),
+    }
+}
@@ -0,0 +1,272 @@
+//! Network interfaces.
+extern crate alloc;
+use super::{
+    arp::{ArpCache, ArpEntry, ArpOperation, ArpPacket},
+    ethernet::{EtherType, EthernetFrame, MacAddress},
+    ipv4::{self, Ipv4Address, Ipv4Packet},
+    NetError,
+};
+use crate::time;
+use alloc::{sync::Arc, vec::Vec};
+use x86_64::mutex::Mutex;
+
+/// Driver of a network card
+pub trait NetDevice: Send + Sync {
+    /// Name of the interface the device is bound to
+    fn name(&self) -> &str;
+
+    fn mac(&self) -> MacAddress;
+
+    /// Largest payload of a frame
+    fn mtu(&self) -> usize {
+        1500
+    }
+
+    /// Whether peers have to be resolved with ARP
+    fn uses_arp(&self) -> bool {
+        true
+    }
+
+    /// Sends an Ethernet frame
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Next received Ethernet frame
+    fn receive(&self) -> Option<Vec<u8>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub address: Ipv4Address,
+    pub netmask: Ipv4Address,
+    /// Next hop for destinations outside of the network
+    pub gateway: Option<Ipv4Address>,
+}
+
+impl Ipv4Config {
+    /// Configuration of an interface waiting for DHCP. It isn't part of any
+    /// network, so nothing is routed to it.
+    pub const UNCONFIGURED: Ipv4Config = Ipv4Config {
+        address: Ipv4Address::UNSPECIFIED,
+        netmask: Ipv4Address::BROADCAST,
+        gateway: None,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InterfaceStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Frames that were malformed or not handled
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_dropped: u64,
+}
+
+/// A device bound to an IPv4 configuration
+pub struct Interface {
+    device: Arc<dyn NetDevice>,
+    config: Mutex<Ipv4Config>,
+    arp: Mutex<ArpCache>,
+    stats: Mutex<InterfaceStats>,
+}
+
+static INTERFACES: Mutex<Vec<Arc<Interface>>> = Mutex::new(Vec::new());
+
+/// Binds `device` to `config`, the name of the device has to be unique
+pub fn add(device: Arc<dyn NetDevice>, config: Ipv4Config) -> Result<Arc<Interface>, NetError> {
+    let mut interfaces = INTERFACES.lock();
+    if interfaces.iter().any(|i| i.name() == device.name()) {
+        return Err(NetError::AlreadyExists);
+    }
+    let interface = Arc::new(Interface {
+        device,
+        config: Mutex::new(config),
+        arp: Mutex::new(ArpCache::new()),
+        stats: Mutex::new(InterfaceStats::default()),
+    });
+    interfaces.push(interface.clone());
+    Ok(interface)
+}
+
+pub fn remove(name: &str) -> Result<(), NetError> {
+    let mut interfaces = INTERFACES.lock();
+    let index = interfaces
+        .iter()
+        .position(|i| i.name() == name)
+        .ok_or(NetError::NoSuchInterface)?;
+    interfaces.remove(index);
+    Ok(())
+}
+
+pub fn get(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES.lock().iter().find(|i| i.name() == name).cloned()
+}
+
+pub fn all() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+/// Interface and next hop to reach `destination`. Local addresses are
+/// reached over loopback, otherwise the interface with the most specific
+/// matching network is picked, then the first one with a gateway.
+pub fn route(destination: Ipv4Address) -> Option<(Arc<Interface>, Ipv4Address)> {
+    let interfaces = all();
+    let loopback = interfaces.iter().find(|i| !i.device.uses_arp());
+    if let Some(loopback) = loopback {
+        if interfaces.iter().any(|i| i.config().address == destination) {
+            return Some((loopback.clone(), destination));
+        }
+    }
+
+    let local = interfaces
+        .iter()
+        .filter(|i| {
+            let config = i.config();
+            destination.in_subnet(config.address, config.netmask)
+        })
+        .max_by_key(|i| i.config().netmask.as_u32().count_ones());
+    if let Some(interface) = local {
+        return Some((interface.clone(), destination));
+    }
+    interfaces
+        .iter()
+        .find_map(|i| Some((i.clone(), i.config().gateway?)))
+}
+
+impl Interface {
+    pub fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    pub fn device(&self) -> &Arc<dyn NetDevice> {
+        &self.device
+    }
+
+    pub fn mac(&self) -> MacAddress {
+        self.device.mac()
+    }
+
+    pub fn config(&self) -> Ipv4Config {
+        *self.config.lock()
+    }
+
+    pub fn set_config(&self, config: Ipv4Config) {
+        *self.config.lock() = config;
+    }
+
+    pub fn stats(&self) -> InterfaceStats {
+        *self.stats.lock()
+    }
+
+    pub fn arp_entries(&self) -> Vec<(Ipv4Address, ArpEntry)> {
+        self.arp.lock().entries().collect()
+    }
+
+    /// Sends the IPv4 `packet` to `next_hop`, resolving it first if needed
+    pub fn send_ipv4(&self, next_hop: Ipv4Address, packet: Vec<u8>) -> Result<(), NetError> {
+        if !self.device.uses_arp() {
+            return self.transmit(self.mac(), EtherType::Ipv4, &packet);
+        }
+        if next_hop.is_broadcast() {
+            return self.transmit(MacAddress::BROADCAST, EtherType::Ipv4, &packet);
+        }
+
+        let now = time::uptime();
+        let mut arp = self.arp.lock();
+        if let Some(mac) = arp.lookup(next_hop, now) {
+            drop(arp);
+            return self.transmit(mac, EtherType::Ipv4, &packet);
+        }
+        let request = arp.queue(next_hop, packet, now);
+        drop(arp);
+        match request {
+            true => self.send_arp_request(next_hop),
+            false => Ok(()),
+        }
+    }
+
+    /// Processes a frame received by the device
+    pub fn receive(&self, data: &[u8]) {
+        let result = EthernetFrame::parse(data).and_then(|frame| {
+            if frame.destination != self.mac() && !frame.destination.is_broadcast() {
+                return Err(NetError::NoRoute);
+            }
+            match frame.ether_type {
+                EtherType::Arp => self.receive_arp(&ArpPacket::parse(frame.payload)?),
+                EtherType::Ipv4 => ipv4::receive(self, &Ipv4Packet::parse(frame.payload)?),
+                EtherType::Unknown(_) => Err(NetError::Unsupported),
+            }
+        });
+
+        let mut stats = self.stats.lock();
+        stats.rx_packets += 1;
+        stats.rx_bytes += data.len() as u64;
+        if result.is_err() {
+            stats.rx_dropped += 1;
+        }
+    }
+
+    fn receive_arp(&self, packet: &ArpPacket) -> Result<(), NetError> {
+        let address = self.config().address;
+        // probes don't have a sender address yet
+        if packet.sender_ip != Ipv4Address::UNSPECIFIED {
+            let waiting =
+                self.arp
+                    .lock()
+                    .insert(packet.sender_ip, packet.sender_mac, time::uptime());
+            for ipv4 in waiting {
+                self.transmit(packet.sender_mac, EtherType::Ipv4, &ipv4)?;
+            }
+        }
+
+        if packet.operation == ArpOperation::Request && packet.target_ip == address {
+            let reply = ArpPacket {
+                operation: ArpOperation::Reply,
+                sender_mac: self.mac(),
+                sender_ip: address,
+                target_mac: packet.sender_mac,
+                target_ip: packet.sender_ip,
+            };
+            self.transmit(packet.sender_mac, EtherType::Arp, &reply.serialize())?;
+        }
+        Ok(())
+    }
+
+    fn send_arp_request(&self, target_ip: Ipv4Address) -> Result<(), NetError> {
+        let packet = ArpPacket {
+            operation: ArpOperation::Request,
+            sender_mac: self.mac(),
+            sender_ip: self.config().address,
+            target_mac: MacAddress::ZERO,
+            target_ip,
+        };
+        self.transmit(MacAddress::BROADCAST, EtherType::Arp, &packet.serialize())
+    }
+
+    fn transmit(
+        &self,
+        destination: MacAddress,
+        ether_type: EtherType,
+        payload: &[u8],
+    ) -> Result<(), NetError> {
+        let frame = EthernetFrame {
+            destination,
+            source: self.mac(),
+            ether_type,
+            payload,
+        }
+        .serialize();
+        let result = self.device.transmit(&frame);
+
+        let mut stats = self.stats.lock();
+        match result {
+            Ok(()) => {
+                stats.tx_packets += 1;
+                stats.tx_bytes += frame.len() as u64;
+            }
+            Err(_) => stats.tx_dropped += 1,
+        }
+        result
+    }
+}
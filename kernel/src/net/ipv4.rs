@@ -0,0 +1,271 @@
+//! Internet Protocol version 4.
+//!
+//! Fragmented packets are dropped, packets are sent with the don't fragment
+//! flag set instead. Options of received packets are ignored.
+extern crate alloc;
+use super::{icmp, interface, tcp, udp, NetError};
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+/// Size of a header without options
+pub const HEADER_SIZE: usize = 20;
+pub const DEFAULT_TTL: u8 = 64;
+
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0; 4]);
+    pub const BROADCAST: Ipv4Address = Ipv4Address([255; 4]);
+    pub const LOCALHOST: Ipv4Address = Ipv4Address([127, 0, 0, 1]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Self([a, b, c, d])
+    }
+
+    pub fn from_u32(address: u32) -> Self {
+        Self(address.to_be_bytes())
+    }
+
+    pub fn as_u32(&self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.0[0] == 127
+    }
+
+    /// Whether the address is in the network of `address` with `netmask`
+    pub fn in_subnet(&self, address: Ipv4Address, netmask: Ipv4Address) -> bool {
+        self.as_u32() & netmask.as_u32() == address.as_u32() & netmask.as_u32()
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/// Parses dotted decimal notation
+impl FromStr for Ipv4Address {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut octets = [0u8; 4];
+        let mut parts = s.split('.');
+        for octet in octets.iter_mut() {
+            *octet = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        }
+        match parts.next() {
+            Some(_) => Err(()),
+            None => Ok(Self(octets)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Icmp,
+    Tcp,
+    Udp,
+    Unknown(u8),
+}
+
+impl From<u8> for Protocol {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Protocol::Icmp,
+            6 => Protocol::Tcp,
+            17 => Protocol::Udp,
+            other => Protocol::Unknown(other),
+        }
+    }
+}
+
+impl From<Protocol> for u8 {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Icmp => 1,
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+            Protocol::Unknown(other) => other,
+        }
+    }
+}
+
+/// The internet checksum, the ones' complement of the ones' complement sum
+/// of all 16 bit words
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|chunk| match chunk {
+            [high, low] => u16::from_be_bytes([*high, *low]) as u32,
+            [high] => u16::from_be_bytes([*high, 0]) as u32,
+            _ => unreachable!(),
+        })
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Checksum over the pseudo header of transport protocols and `segment`
+pub fn pseudo_header_checksum(
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    protocol: Protocol,
+    segment: &[u8],
+) -> u16 {
+    let mut data = Vec::with_capacity(12 + segment.len());
+    data.extend_from_slice(&source.0);
+    data.extend_from_slice(&destination.0);
+    data.extend_from_slice(&[0, u8::from(protocol)]);
+    data.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    data.extend_from_slice(segment);
+    checksum(&data)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Packet<'a> {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub protocol: Protocol,
+    pub ttl: u8,
+    pub identification: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    /// Parses a packet, trailing bytes like Ethernet padding are ignored
+    pub fn parse(data: &'a [u8]) -> Result<Self, NetError> {
+        if data.len() < HEADER_SIZE {
+            return Err(NetError::Truncated);
+        }
+        let version = data[0] >> 4;
+        let header_size = (data[0] & 0xf) as usize * 4;
+        let total_size = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if version != 4 || header_size < HEADER_SIZE {
+            return Err(NetError::Unsupported);
+        }
+        if total_size < header_size || data.len() < total_size {
+            return Err(NetError::Truncated);
+        }
+        if checksum(&data[..header_size]) != 0 {
+            return Err(NetError::InvalidChecksum);
+        }
+        let flags = u16::from_be_bytes([data[6], data[7]]);
+        if flags & FLAG_MORE_FRAGMENTS != 0 || flags & FRAGMENT_OFFSET_MASK != 0 {
+            return Err(NetError::Unsupported);
+        }
+
+        let address = |offset: usize| Ipv4Address(data[offset..offset + 4].try_into().unwrap());
+        Ok(Self {
+            source: address(12),
+            destination: address(16),
+            protocol: Protocol::from(data[9]),
+            ttl: data[8],
+            identification: u16::from_be_bytes([data[4], data[5]]),
+            payload: &data[header_size..total_size],
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let total_size = (HEADER_SIZE + self.payload.len()) as u16;
+        let mut packet = Vec::with_capacity(total_size as usize);
+        packet.push(0x45);
+        packet.push(0);
+        packet.extend_from_slice(&total_size.to_be_bytes());
+        packet.extend_from_slice(&self.identification.to_be_bytes());
+        packet.extend_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+        packet.push(self.ttl);
+        packet.push(u8::from(self.protocol));
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&self.source.0);
+        packet.extend_from_slice(&self.destination.0);
+        let checksum = checksum(&packet);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet.extend_from_slice(self.payload);
+        packet
+    }
+}
+
+static NEXT_IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+
+/// Sends `payload` to `destination`. Returns once the packet is handed to
+/// the device or queued until the next hop is resolved.
+pub fn send(destination: Ipv4Address, protocol: Protocol, payload: &[u8]) -> Result<(), NetError> {
+    let (interface, next_hop) = interface::route(destination).ok_or(NetError::NoRoute)?;
+    if HEADER_SIZE + payload.len() > interface.device().mtu() {
+        return Err(NetError::PacketTooLarge);
+    }
+
+    let packet = Ipv4Packet {
+        source: interface.config().address,
+        destination,
+        protocol,
+        ttl: DEFAULT_TTL,
+        identification: NEXT_IDENTIFICATION.fetch_add(1, Ordering::Relaxed),
+        payload,
+    };
+    interface.send_ipv4(next_hop, packet.serialize())
+}
+
+/// Sends `payload` to the broadcast address on `interface`, which doesn't
+/// need an address for that
+pub fn broadcast(
+    interface: &interface::Interface,
+    protocol: Protocol,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    if HEADER_SIZE + payload.len() > interface.device().mtu() {
+        return Err(NetError::PacketTooLarge);
+    }
+
+    let packet = Ipv4Packet {
+        source: interface.config().address,
+        destination: Ipv4Address::BROADCAST,
+        protocol,
+        ttl: DEFAULT_TTL,
+        identification: NEXT_IDENTIFICATION.fetch_add(1, Ordering::Relaxed),
+        payload,
+    };
+    interface.send_ipv4(Ipv4Address::BROADCAST, packet.serialize())
+}
+
+/// Handles a packet received by `interface`
+pub(super) fn receive(
+    interface: &interface::Interface,
+    packet: &Ipv4Packet,
+) -> Result<(), NetError> {
+    let config = interface.config();
+    let broadcast = Ipv4Address::from_u32(config.address.as_u32() | !config.netmask.as_u32());
+    if packet.destination != config.address
+        && !packet.destination.is_broadcast()
+        && packet.destination != broadcast
+    {
+        // not forwarding
+        return Err(NetError::NoRoute);
+    }
+
+    match packet.protocol {
+        Protocol::Icmp => icmp::receive(interface, packet),
+        Protocol::Tcp => tcp::receive(interface, packet),
+        Protocol::Udp => udp::receive(interface, packet),
+        _ => Err(NetError::Unsupported),
+    }
+}
@@ -0,0 +1,51 @@
+//! Loopback device, frames sent are received by the same device.
+extern crate alloc;
+use super::{ethernet::MacAddress, NetDevice, NetError};
+use alloc::{collections::VecDeque, vec::Vec};
+use x86_64::mutex::Mutex;
+
+pub struct LoopbackDevice {
+    frames: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl LoopbackDevice {
+    pub const fn new() -> Self {
+        Self {
+            frames: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+impl Default for LoopbackDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NetDevice for LoopbackDevice {
+    fn name(&self) -> &str {
+        "lo"
+    }
+
+    fn mac(&self) -> MacAddress {
+        MacAddress::ZERO
+    }
+
+    fn mtu(&self) -> usize {
+        u16::MAX as usize
+    }
+
+    fn uses_arp(&self) -> bool {
+        false
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.frames.lock().push_back(frame.to_vec());
+        super::notify();
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        self.frames.lock().pop_front()
+    }
+}
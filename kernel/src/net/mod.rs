@@ -0,0 +1,178 @@
+//! Network stack.
+//!
+//! Network devices exchange Ethernet frames, see [`NetDevice`]. Each device
+//! is bound to an [`Interface`] with an IPv4 configuration. Peers on the local
+//! network are resolved with [`arp`], packets to other networks are sent to
+//! the gateway of an interface.
+//!
+//! Drivers [`notify`] the stack when frames arrive, a kernel thread then
+//! [`poll`]s all devices and processes their frames. The loopback interface
+//! `lo` with 127.0.0.1 always exists. An [`e1000`] card becomes `eth0`, which
+//! is configured by [`dhcp`].
+//!
+//! Above IPv4 there are [`icmp`] echo, [`udp`] sockets and [`tcp`] streams.
+//! Host names are resolved with [`dns`], files are downloaded with [`http`].
+extern crate alloc;
+pub mod arp;
+pub mod dhcp;
+pub mod dns;
+pub mod e1000;
+pub mod ethernet;
+pub mod http;
+pub mod icmp;
+pub mod interface;
+pub mod ipv4;
+pub mod loopback;
+pub mod tcp;
+pub mod udp;
+
+use crate::{
+    executor, info,
+    memory::MemoryError,
+    scheduler::{self, ThreadPriority},
+    sync::WaitQueue,
+    syscall::Errno,
+};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+pub use ethernet::MacAddress;
+pub use interface::{Interface, Ipv4Config, NetDevice};
+pub use ipv4::Ipv4Address;
+use loopback::LoopbackDevice;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// A packet is shorter than its headers claim
+    Truncated,
+    InvalidChecksum,
+    /// A packet uses a feature the stack doesn't implement, e.g. fragments
+    Unsupported,
+    /// No interface can reach the destination
+    NoRoute,
+    /// An interface with the name exists already
+    AlreadyExists,
+    NoSuchInterface,
+    /// The packet doesn't fit into the MTU of the device
+    PacketTooLarge,
+    /// The device failed to send the frame
+    DeviceError,
+    /// No reply arrived in time
+    Timeout,
+    /// Another socket is bound to the port
+    AddressInUse,
+    /// Binding a socket a second time
+    AlreadyBound,
+    /// The receive queue of a socket is full
+    QueueFull,
+    /// The peer answered a connection attempt with a reset
+    ConnectionRefused,
+    /// The peer reset an established connection
+    ConnectionReset,
+    /// Writing to a connection that was closed
+    NotConnected,
+    /// A host name that isn't valid in DNS
+    InvalidName,
+    /// The name doesn't exist or has no addresses
+    NameNotFound,
+    /// A server failed to answer a request or sent a malformed response
+    ServerFailure,
+    InvalidUrl,
+    /// An HTTP server answered with an unsuccessful status code
+    HttpStatus(u16),
+}
+
+impl From<NetError> for Errno {
+    fn from(error: NetError) -> Self {
+        match error {
+            NetError::Truncated | NetError::InvalidChecksum | NetError::Unsupported => {
+                Errno::EINVAL
+            }
+            NetError::NoRoute => Errno::ENETUNREACH,
+            NetError::AlreadyExists => Errno::EEXIST,
+            NetError::NoSuchInterface => Errno::ENOENT,
+            NetError::PacketTooLarge => Errno::EMSGSIZE,
+            NetError::DeviceError => Errno::EIO,
+            NetError::Timeout => Errno::ETIMEDOUT,
+            NetError::AddressInUse => Errno::EADDRINUSE,
+            NetError::AlreadyBound => Errno::EINVAL,
+            NetError::QueueFull => Errno::EAGAIN,
+            NetError::ConnectionRefused => Errno::ECONNREFUSED,
+            NetError::ConnectionReset => Errno::ECONNRESET,
+            NetError::NotConnected => Errno::ENOTCONN,
+            NetError::InvalidName => Errno::EINVAL,
+            NetError::NameNotFound => Errno::ENOENT,
+            NetError::ServerFailure => Errno::EIO,
+            NetError::InvalidUrl => Errno::EINVAL,
+            NetError::HttpStatus(_) => Errno::EIO,
+        }
+    }
+}
+
+static RX_PENDING: AtomicBool = AtomicBool::new(false);
+static RX_WAITERS: WaitQueue = WaitQueue::named("net-rx");
+
+/// Adds the loopback interface and the network card if there is one, then
+/// starts the receive and TCP timer threads. The card is configured by DHCP
+/// in the background.
+pub fn init() -> Result<(), MemoryError> {
+    let config = Ipv4Config {
+        address: Ipv4Address::LOCALHOST,
+        netmask: Ipv4Address::new(255, 0, 0, 0),
+        gateway: None,
+    };
+    if let Err(error) = interface::add(Arc::new(LoopbackDevice::new()), config) {
+        info!("No loopback interface: {:?}", error);
+    }
+    let card = match e1000::probe() {
+        Ok(Some(device)) => {
+            info!("Network card {}", device.mac());
+            interface::add(device, Ipv4Config::UNCONFIGURED)
+                .map_err(|error| info!("No eth0 interface: {:?}", error))
+                .ok()
+        }
+        Ok(None) => {
+            info!("No network card");
+            None
+        }
+        Err(error) => {
+            info!("Network card not usable: {:?}", error);
+            None
+        }
+    };
+
+    let id = scheduler::spawn(rx_loop, ThreadPriority::Normal)?;
+    scheduler::set_name(id, "net-rx");
+    if let Some(interface) = card {
+        let maintain = async move { dhcp::maintain(&interface).await };
+        if let Err(error) = executor::spawn(maintain) {
+            info!("DHCP not started: {:?}", error);
+        }
+    }
+    tcp::init()
+}
+
+/// Called by drivers when frames were received. Safe to call from interrupt
+/// handlers.
+pub fn notify() {
+    RX_PENDING.store(true, Ordering::SeqCst);
+    RX_WAITERS.wake_one();
+}
+
+/// Processes the frames received by all devices, returns how many
+pub fn poll() -> usize {
+    let mut frames = 0;
+    for interface in interface::all() {
+        while let Some(frame) = interface.device().receive() {
+            interface.receive(&frame);
+            frames += 1;
+        }
+    }
+    frames
+}
+
+fn rx_loop() {
+    loop {
+        RX_WAITERS.wait_until(|| RX_PENDING.swap(false, Ordering::SeqCst));
+        poll();
+    }
+}
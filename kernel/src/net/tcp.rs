@@ -0,0 +1,1065 @@
+//! Transmission Control Protocol.
+//!
+//! Connections follow the state machine of RFC 793. Sent data stays in the
+//! send buffer until it is acknowledged, the oldest unacknowledged segment is
+//! retransmitted when the retransmission timeout expires. The timeout is
+//! estimated from round trip times as described in RFC 6298 and doubles with
+//! every retransmission. Segments arriving out of order are kept until the
+//! gap is filled. The receive window is the free space of the receive buffer,
+//! the amount of data in flight is limited by the window of the peer.
+//!
+//! [`TcpListener`] accepts connections on a port, [`TcpStream::connect`]
+//! opens one. A kernel thread drives the timers and removes closed
+//! connections. Simplifications: no congestion control, no urgent data,
+//! no selective acknowledgments and a shortened `TIME-WAIT`.
+extern crate alloc;
+use super::{
+    interface::{self, Interface},
+    ipv4::{self, Ipv4Address, Ipv4Packet, Protocol},
+    udp::{SocketAddress, EPHEMERAL_PORTS},
+    NetError,
+};
+use crate::{
+    memory::MemoryError,
+    random,
+    scheduler::{self, ThreadPriority},
+    sync::WaitQueue,
+    time,
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use bitflags::bitflags;
+use core::time::Duration;
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+/// Size of a header without options
+pub const HEADER_SIZE: usize = 20;
+/// Segment size assumed if the peer doesn't announce one
+pub const DEFAULT_MSS: usize = 536;
+pub const SEND_BUFFER_SIZE: usize = 16 * 1024;
+pub const RECEIVE_BUFFER_SIZE: usize = 16 * 1024;
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+pub const MIN_RTO: Duration = Duration::from_millis(200);
+pub const MAX_RTO: Duration = Duration::from_secs(10);
+/// Retransmissions of a segment before the connection is given up
+pub const MAX_RETRANSMISSIONS: u32 = 8;
+/// Time spent in `TIME-WAIT`, much shorter than the 2 MSL of the RFC
+pub const TIME_WAIT: Duration = Duration::from_secs(2);
+/// Established connections waiting to be accepted
+pub const BACKLOG: usize = 16;
+const TIMER_INTERVAL: Duration = Duration::from_millis(50);
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct TcpFlags: u8 {
+        const FIN = 1;
+        const SYN = 1 << 1;
+        const RST = 1 << 2;
+        const PSH = 1 << 3;
+        const ACK = 1 << 4;
+    }
+}
+
+/// `a` is before `b` in sequence space
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpSegment<'a> {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub sequence: u32,
+    pub acknowledgment: u32,
+    pub flags: TcpFlags,
+    pub window: u16,
+    /// Maximum segment size option, only sent with SYN
+    pub mss: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> TcpSegment<'a> {
+    pub fn parse(packet: &Ipv4Packet<'a>) -> Result<Self, NetError> {
+        let data = packet.payload;
+        if data.len() < HEADER_SIZE {
+            return Err(NetError::Truncated);
+        }
+        let header_size = (data[12] >> 4) as usize * 4;
+        if header_size < HEADER_SIZE || data.len() < header_size {
+            return Err(NetError::Truncated);
+        }
+        if ipv4::pseudo_header_checksum(packet.source, packet.destination, Protocol::Tcp, data) != 0
+        {
+            return Err(NetError::InvalidChecksum);
+        }
+
+        let mut mss = None;
+        let mut options = &data[HEADER_SIZE..header_size];
+        while let [kind, rest @ ..] = options {
+            match *kind {
+                OPTION_END => break,
+                OPTION_NOP => options = rest,
+                _ => {
+                    let [len, ..] = rest else { break };
+                    let len = *len as usize;
+                    if len < 2 || options.len() < len {
+                        break;
+                    }
+                    if *kind == OPTION_MSS && len == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+
+        let word = |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+        Ok(Self {
+            source_port: u16::from_be_bytes([data[0], data[1]]),
+            destination_port: u16::from_be_bytes([data[2], data[3]]),
+            sequence: word(4),
+            acknowledgment: word(8),
+            flags: TcpFlags::from_bits_truncate(data[13]),
+            window: u16::from_be_bytes([data[14], data[15]]),
+            mss,
+            payload: &data[header_size..],
+        })
+    }
+
+    pub fn serialize(&self, source: Ipv4Address, destination: Ipv4Address) -> Vec<u8> {
+        let header_size = HEADER_SIZE + if self.mss.is_some() { 4 } else { 0 };
+        let mut segment = Vec::with_capacity(header_size + self.payload.len());
+        segment.extend_from_slice(&self.source_port.to_be_bytes());
+        segment.extend_from_slice(&self.destination_port.to_be_bytes());
+        segment.extend_from_slice(&self.sequence.to_be_bytes());
+        segment.extend_from_slice(&self.acknowledgment.to_be_bytes());
+        segment.push(((header_size / 4) as u8) << 4);
+        segment.push(self.flags.bits());
+        segment.extend_from_slice(&self.window.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0]);
+        if let Some(mss) = self.mss {
+            segment.extend_from_slice(&[OPTION_MSS, 4]);
+            segment.extend_from_slice(&mss.to_be_bytes());
+        }
+        segment.extend_from_slice(self.payload);
+        let checksum = ipv4::pseudo_header_checksum(source, destination, Protocol::Tcp, &segment);
+        segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+        segment
+    }
+
+    /// Sequence numbers the segment occupies, SYN and FIN count as one
+    pub fn sequence_len(&self) -> u32 {
+        self.payload.len() as u32
+            + self.flags.contains(TcpFlags::SYN) as u32
+            + self.flags.contains(TcpFlags::FIN) as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+/// A segment to send, built while the connection is locked and sent after
+struct Outgoing {
+    flags: TcpFlags,
+    sequence: u32,
+    acknowledgment: u32,
+    window: u16,
+    mss: Option<u16>,
+    payload: Vec<u8>,
+}
+
+struct Connection {
+    state: TcpState,
+    local: SocketAddress,
+    remote: SocketAddress,
+    initial_sequence: u32,
+    /// Oldest unacknowledged sequence number
+    send_unacked: u32,
+    send_next: u32,
+    /// Sequence number of the first byte of `send_buffer`
+    send_base: u32,
+    /// Unacknowledged and unsent data
+    send_buffer: VecDeque<u8>,
+    send_window: u32,
+    /// Closed by the user, a FIN follows the buffered data
+    closing: bool,
+    receive_next: u32,
+    /// In order data not read yet
+    receive_buffer: VecDeque<u8>,
+    /// Segments after a gap, with their sequence number
+    out_of_order: Vec<(u32, Vec<u8>)>,
+    fin_received: bool,
+    /// Segment size of the peer
+    mss: usize,
+    /// Segment size announced to the peer
+    advertised_mss: u16,
+    rto: Duration,
+    smoothed_rtt: Option<Duration>,
+    rtt_variance: Duration,
+    /// Sequence number acknowledging the timed segment and its send time
+    rtt_sample: Option<(u32, Duration)>,
+    retransmit_at: Option<Duration>,
+    retransmissions: u32,
+    time_wait_until: Option<Duration>,
+    error: Option<NetError>,
+    /// Listener the connection is handed to once it is established
+    listener: Option<Arc<Listener>>,
+}
+
+impl Connection {
+    fn new(local: SocketAddress, remote: SocketAddress, state: TcpState) -> Self {
+        let initial_sequence = random::random_u64() as u32;
+        let mtu = interface::route(remote.ip).map_or(1500, |(i, _)| i.device().mtu());
+        Self {
+            state,
+            local,
+            remote,
+            initial_sequence,
+            send_unacked: initial_sequence,
+            send_next: initial_sequence,
+            send_base: initial_sequence.wrapping_add(1),
+            send_buffer: VecDeque::new(),
+            send_window: DEFAULT_MSS as u32,
+            closing: false,
+            receive_next: 0,
+            receive_buffer: VecDeque::new(),
+            out_of_order: Vec::new(),
+            fin_received: false,
+            mss: DEFAULT_MSS,
+            advertised_mss: (mtu - ipv4::HEADER_SIZE - HEADER_SIZE).min(u16::MAX as usize) as u16,
+            rto: INITIAL_RTO,
+            smoothed_rtt: None,
+            rtt_variance: Duration::ZERO,
+            rtt_sample: None,
+            retransmit_at: None,
+            retransmissions: 0,
+            time_wait_until: None,
+            error: None,
+            listener: None,
+        }
+    }
+
+    fn receive_window(&self) -> u16 {
+        (RECEIVE_BUFFER_SIZE - self.receive_buffer.len()).min(u16::MAX as usize) as u16
+    }
+
+    fn segment(&self, flags: TcpFlags, sequence: u32, payload: Vec<u8>) -> Outgoing {
+        let synchronized = self.state != TcpState::SynSent;
+        Outgoing {
+            flags: match synchronized {
+                true => flags | TcpFlags::ACK,
+                false => flags,
+            },
+            sequence,
+            acknowledgment: if synchronized { self.receive_next } else { 0 },
+            window: self.receive_window(),
+            mss: None,
+            payload,
+        }
+    }
+
+    fn ack(&self) -> Outgoing {
+        self.segment(TcpFlags::empty(), self.send_next, Vec::new())
+    }
+
+    /// SYN, or SYN-ACK in `SYN-RECEIVED`
+    fn syn(&self) -> Outgoing {
+        Outgoing {
+            mss: Some(self.advertised_mss),
+            ..self.segment(TcpFlags::SYN, self.initial_sequence, Vec::new())
+        }
+    }
+
+    fn arm_retransmit(&mut self, now: Duration) {
+        if self.retransmit_at.is_none() {
+            self.retransmit_at = Some(now + self.rto);
+        }
+    }
+
+    /// Sends the SYN of an active open
+    fn connect(&mut self, now: Duration) -> Vec<Outgoing> {
+        self.send_next = self.initial_sequence.wrapping_add(1);
+        self.arm_retransmit(now);
+        alloc::vec![self.syn()]
+    }
+
+    /// Unacknowledged data is in flight
+    fn in_flight(&self) -> u32 {
+        self.send_next.wrapping_sub(self.send_unacked)
+    }
+
+    /// Sends buffered data the window of the peer allows and the FIN once
+    /// all data is sent
+    fn output(&mut self, now: Duration) -> Vec<Outgoing> {
+        let mut segments = Vec::new();
+        if !matches!(self.state, TcpState::Established | TcpState::CloseWait) {
+            return segments;
+        }
+
+        loop {
+            let offset = self.send_next.wrapping_sub(self.send_base) as usize;
+            let unsent = self.send_buffer.len() - offset;
+            let window = self.send_window.saturating_sub(self.in_flight()) as usize;
+            let len = unsent.min(window).min(self.mss);
+            if len == 0 {
+                break;
+            }
+            let payload: Vec<u8> = self
+                .send_buffer
+                .range(offset..offset + len)
+                .copied()
+                .collect();
+            if self.rtt_sample.is_none() {
+                self.rtt_sample = Some((self.send_next.wrapping_add(len as u32), now));
+            }
+            segments.push(self.segment(TcpFlags::PSH, self.send_next, payload));
+            self.send_next = self.send_next.wrapping_add(len as u32);
+        }
+
+        let all_sent =
+            self.send_next.wrapping_sub(self.send_base) as usize == self.send_buffer.len();
+        if self.closing && all_sent {
+            segments.push(self.segment(TcpFlags::FIN, self.send_next, Vec::new()));
+            self.send_next = self.send_next.wrapping_add(1);
+            self.state = match self.state {
+                TcpState::Established => TcpState::FinWait1,
+                _ => TcpState::LastAck,
+            };
+        }
+
+        // a zero window is probed once the timer expires
+        if self.in_flight() > 0 || !all_sent {
+            self.arm_retransmit(now);
+        }
+        segments
+    }
+
+    fn update_rto(&mut self, rtt: Duration) {
+        // RFC 6298 with alpha 1/8 and beta 1/4
+        match self.smoothed_rtt {
+            None => {
+                self.smoothed_rtt = Some(rtt);
+                self.rtt_variance = rtt / 2;
+            }
+            Some(smoothed) => {
+                let deviation = smoothed.abs_diff(rtt);
+                self.rtt_variance = self.rtt_variance * 3 / 4 + deviation / 4;
+                self.smoothed_rtt = Some(smoothed * 7 / 8 + rtt / 8);
+            }
+        }
+        self.rto = (self.smoothed_rtt.unwrap() + self.rtt_variance * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Handles an acknowledgment of a synchronized connection
+    fn acknowledged(&mut self, acknowledgment: u32, window: u16, now: Duration) {
+        if before(self.send_unacked, acknowledgment) && !before(self.send_next, acknowledgment) {
+            let acked_data = acknowledgment.wrapping_sub(self.send_base) as usize;
+            if !before(acknowledgment, self.send_base) {
+                let acked_data = acked_data.min(self.send_buffer.len());
+                self.send_buffer.drain(..acked_data);
+                self.send_base = self.send_base.wrapping_add(acked_data as u32);
+            }
+            self.send_unacked = acknowledgment;
+            self.retransmissions = 0;
+            self.retransmit_at = None;
+
+            if let Some((sequence, sent)) = self.rtt_sample {
+                if !before(acknowledgment, sequence) {
+                    self.update_rto(now - sent);
+                    self.rtt_sample = None;
+                }
+            }
+        }
+        if !before(acknowledgment, self.send_unacked) {
+            self.send_window = window as u32;
+        }
+    }
+
+    /// Queues in order data and reassembles segments that arrived early
+    fn receive_data(&mut self, sequence: u32, payload: &[u8]) {
+        let mut sequence = sequence;
+        let mut payload = payload;
+        // already received in part
+        if before(sequence, self.receive_next) {
+            let duplicate = self.receive_next.wrapping_sub(sequence) as usize;
+            if duplicate >= payload.len() {
+                return;
+            }
+            payload = &payload[duplicate..];
+            sequence = self.receive_next;
+        }
+        let space = RECEIVE_BUFFER_SIZE - self.receive_buffer.len();
+        let offset = sequence.wrapping_sub(self.receive_next) as usize;
+        if offset >= space {
+            return;
+        }
+        let payload = &payload[..payload.len().min(space - offset)];
+
+        if offset > 0 {
+            // overlapping retransmissions must not grow the queue past the
+            // receive buffer
+            let queued: usize = self.out_of_order.iter().map(|(_, data)| data.len()).sum();
+            if queued + payload.len() <= RECEIVE_BUFFER_SIZE
+                && !self.out_of_order.iter().any(|(s, _)| *s == sequence)
+            {
+                self.out_of_order.push((sequence, payload.to_vec()));
+            }
+            return;
+        }
+        self.receive_buffer.extend(payload);
+        self.receive_next = self.receive_next.wrapping_add(payload.len() as u32);
+
+        // segments that are in order now
+        while let Some(index) = self.out_of_order.iter().position(|(s, data)| {
+            !before(self.receive_next, *s)
+                && before(self.receive_next, s.wrapping_add(data.len() as u32))
+        }) {
+            let (sequence, data) = self.out_of_order.swap_remove(index);
+            let skip = self.receive_next.wrapping_sub(sequence) as usize;
+            let space = RECEIVE_BUFFER_SIZE - self.receive_buffer.len();
+            let data = &data[skip..data.len().min(skip + space)];
+            self.receive_buffer.extend(data);
+            self.receive_next = self.receive_next.wrapping_add(data.len() as u32);
+        }
+        let receive_next = self.receive_next;
+        self.out_of_order
+            .retain(|(s, data)| before(receive_next, s.wrapping_add(data.len() as u32)));
+    }
+
+    fn close(&mut self, error: Option<NetError>) {
+        self.state = TcpState::Closed;
+        self.error = self.error.or(error);
+        self.retransmit_at = None;
+    }
+
+    fn enter_time_wait(&mut self, now: Duration) {
+        self.state = TcpState::TimeWait;
+        self.retransmit_at = None;
+        self.time_wait_until = Some(now + TIME_WAIT);
+    }
+
+    /// Handles a segment of the connection, returns the segments to send
+    fn segment_arrives(&mut self, segment: &TcpSegment, now: Duration) -> Vec<Outgoing> {
+        let flags = segment.flags;
+        if self.state == TcpState::SynSent {
+            return self.syn_sent(segment, now);
+        }
+        if self.state == TcpState::Closed {
+            return Vec::new();
+        }
+
+        // anything outside of the receive window is only acknowledged
+        let window = (self.receive_window() as u32).max(1);
+        let offset = segment.sequence.wrapping_sub(self.receive_next);
+        let end_offset = offset.wrapping_add(segment.sequence_len().max(1) - 1);
+        let acceptable = offset < window
+            || end_offset < window
+            || (before(segment.sequence, self.receive_next)
+                && before(
+                    self.receive_next,
+                    segment.sequence.wrapping_add(segment.sequence_len()),
+                ));
+        if !acceptable {
+            return match flags.contains(TcpFlags::RST) {
+                true => Vec::new(),
+                false => alloc::vec![self.ack()],
+            };
+        }
+
+        if flags.contains(TcpFlags::RST) {
+            let error = match self.state {
+                TcpState::SynReceived => None,
+                _ => Some(NetError::ConnectionReset),
+            };
+            self.close(error);
+            return Vec::new();
+        }
+        if flags.contains(TcpFlags::SYN) {
+            // a retransmitted SYN of the peer, our SYN-ACK got lost
+            if self.state == TcpState::SynReceived {
+                return alloc::vec![self.syn()];
+            }
+            return alloc::vec![self.ack()];
+        }
+        if !flags.contains(TcpFlags::ACK) {
+            return Vec::new();
+        }
+
+        if self.state == TcpState::SynReceived {
+            if segment.acknowledgment != self.initial_sequence.wrapping_add(1) {
+                return alloc::vec![reset_for(segment)];
+            }
+            self.state = TcpState::Established;
+            self.send_unacked = segment.acknowledgment;
+            self.retransmit_at = None;
+            self.retransmissions = 0;
+        }
+        self.acknowledged(segment.acknowledgment, segment.window, now);
+
+        let fin_acked = self.send_unacked == self.send_next;
+        match self.state {
+            TcpState::FinWait1 if fin_acked => self.state = TcpState::FinWait2,
+            TcpState::Closing if fin_acked => self.enter_time_wait(now),
+            TcpState::LastAck if fin_acked => {
+                self.close(None);
+                return Vec::new();
+            }
+            TcpState::TimeWait => return alloc::vec![self.ack()],
+            _ => (),
+        }
+
+        let mut acknowledge = false;
+        if !segment.payload.is_empty()
+            && matches!(
+                self.state,
+                TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+            )
+        {
+            self.receive_data(segment.sequence, segment.payload);
+            acknowledge = true;
+        }
+
+        let fin_sequence = segment.sequence.wrapping_add(segment.payload.len() as u32);
+        if flags.contains(TcpFlags::FIN) && fin_sequence == self.receive_next && !self.fin_received
+        {
+            self.fin_received = true;
+            self.receive_next = self.receive_next.wrapping_add(1);
+            acknowledge = true;
+            match self.state {
+                TcpState::Established => self.state = TcpState::CloseWait,
+                TcpState::FinWait1 => self.state = TcpState::Closing,
+                TcpState::FinWait2 => self.enter_time_wait(now),
+                _ => (),
+            }
+        }
+
+        let mut segments = self.output(now);
+        if acknowledge && segments.is_empty() {
+            segments.push(self.ack());
+        }
+        segments
+    }
+
+    fn syn_sent(&mut self, segment: &TcpSegment, now: Duration) -> Vec<Outgoing> {
+        let flags = segment.flags;
+        let acceptable = flags.contains(TcpFlags::ACK)
+            && segment.acknowledgment == self.initial_sequence.wrapping_add(1);
+        if flags.contains(TcpFlags::ACK) && !acceptable {
+            return match flags.contains(TcpFlags::RST) {
+                true => Vec::new(),
+                false => alloc::vec![reset_for(segment)],
+            };
+        }
+        if flags.contains(TcpFlags::RST) {
+            if acceptable {
+                self.close(Some(NetError::ConnectionRefused));
+            }
+            return Vec::new();
+        }
+        if !flags.contains(TcpFlags::SYN) || !acceptable {
+            // simultaneous opens aren't supported
+            return Vec::new();
+        }
+
+        self.receive_next = segment.sequence.wrapping_add(1);
+        self.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize);
+        self.state = TcpState::Established;
+        self.retransmit_at = None;
+        self.retransmissions = 0;
+        self.acknowledged(segment.acknowledgment, segment.window, now);
+        let mut segments = self.output(now);
+        if segments.is_empty() {
+            segments.push(self.ack());
+        }
+        segments
+    }
+
+    /// Retransmits the oldest unacknowledged segment if the timer expired
+    fn timer(&mut self, now: Duration) -> Vec<Outgoing> {
+        if let Some(until) = self.time_wait_until {
+            if now >= until {
+                self.close(None);
+            }
+            return Vec::new();
+        }
+        match self.retransmit_at {
+            Some(at) if now >= at => (),
+            _ => return Vec::new(),
+        }
+
+        self.retransmissions += 1;
+        if self.retransmissions > MAX_RETRANSMISSIONS {
+            self.close(Some(NetError::Timeout));
+            return Vec::new();
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.retransmit_at = Some(now + self.rto);
+        // Karn's algorithm, retransmitted segments aren't timed
+        self.rtt_sample = None;
+
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived => alloc::vec![self.syn()],
+            _ if self.in_flight() == 0 => {
+                // probe a zero window with a single byte
+                let offset = self.send_next.wrapping_sub(self.send_base) as usize;
+                match self.send_buffer.get(offset) {
+                    Some(byte) => {
+                        let probe = self.segment(TcpFlags::PSH, self.send_next, alloc::vec![*byte]);
+                        self.send_next = self.send_next.wrapping_add(1);
+                        alloc::vec![probe]
+                    }
+                    None => {
+                        self.retransmit_at = None;
+                        Vec::new()
+                    }
+                }
+            }
+            _ => {
+                let offset = self.send_unacked.wrapping_sub(self.send_base) as usize;
+                let in_flight =
+                    (self.in_flight() as usize).min(self.send_buffer.len().saturating_sub(offset));
+                let len = in_flight.min(self.mss);
+                if len == 0 {
+                    // only the FIN is unacknowledged
+                    return alloc::vec![self.segment(TcpFlags::FIN, self.send_unacked, Vec::new())];
+                }
+                let payload = self
+                    .send_buffer
+                    .range(offset..offset + len)
+                    .copied()
+                    .collect();
+                alloc::vec![self.segment(TcpFlags::PSH, self.send_unacked, payload)]
+            }
+        }
+    }
+}
+
+/// Reset answering `segment`
+fn reset_for(segment: &TcpSegment) -> Outgoing {
+    match segment.flags.contains(TcpFlags::ACK) {
+        true => Outgoing {
+            flags: TcpFlags::RST,
+            sequence: segment.acknowledgment,
+            acknowledgment: 0,
+            window: 0,
+            mss: None,
+            payload: Vec::new(),
+        },
+        false => Outgoing {
+            flags: TcpFlags::RST | TcpFlags::ACK,
+            sequence: 0,
+            acknowledgment: segment.sequence.wrapping_add(segment.sequence_len()),
+            window: 0,
+            mss: None,
+            payload: Vec::new(),
+        },
+    }
+}
+
+/// Transmission control block, a connection shared by its stream, the
+/// connection table and the timer thread
+struct Tcb {
+    // locked with interrupts disabled since it is checked from wait queue
+    // conditions
+    connection: Mutex<Connection>,
+    /// Data, a FIN or an error arrived
+    readable: WaitQueue,
+    /// The connection was established, data was acknowledged or an error
+    /// occurred
+    writable: WaitQueue,
+}
+
+impl Tcb {
+    fn new(connection: Connection) -> Arc<Self> {
+        Arc::new(Self {
+            connection: Mutex::new(connection),
+            readable: WaitQueue::named("tcp receive"),
+            writable: WaitQueue::named("tcp send"),
+        })
+    }
+
+    fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Connection) -> R,
+    {
+        without_interrupts(|| f(&mut self.connection.lock()))
+    }
+
+    /// Runs `f` on the connection, sends the segments it returns and wakes
+    /// the waiting threads
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Connection) -> Vec<Outgoing>,
+    {
+        let (local, remote, segments) = self.with(|c| (c.local, c.remote, f(c)));
+        transmit(local, remote, segments);
+        self.readable.wake_all();
+        self.writable.wake_all();
+    }
+}
+
+fn transmit(local: SocketAddress, remote: SocketAddress, segments: Vec<Outgoing>) {
+    for outgoing in segments {
+        let segment = TcpSegment {
+            source_port: local.port,
+            destination_port: remote.port,
+            sequence: outgoing.sequence,
+            acknowledgment: outgoing.acknowledgment,
+            flags: outgoing.flags,
+            window: outgoing.window,
+            mss: outgoing.mss,
+            payload: &outgoing.payload,
+        };
+        // lost segments are retransmitted
+        let _ = ipv4::send(
+            remote.ip,
+            Protocol::Tcp,
+            &segment.serialize(local.ip, remote.ip),
+        );
+    }
+}
+
+struct Listener {
+    local: SocketAddress,
+    // locked with interrupts disabled
+    backlog: Mutex<VecDeque<Arc<Tcb>>>,
+    accepting: WaitQueue,
+}
+
+struct Tables {
+    connections: BTreeMap<(SocketAddress, SocketAddress), Arc<Tcb>>,
+    listeners: BTreeMap<u16, Arc<Listener>>,
+    next_ephemeral: u16,
+}
+
+// never locked while a connection is locked
+static TABLES: Mutex<Tables> = Mutex::new(Tables {
+    connections: BTreeMap::new(),
+    listeners: BTreeMap::new(),
+    next_ephemeral: *EPHEMERAL_PORTS.start(),
+});
+
+impl Tables {
+    fn port_in_use(&self, port: u16) -> bool {
+        self.listeners.contains_key(&port) || self.connections.keys().any(|(l, _)| l.port == port)
+    }
+
+    fn allocate_port(&mut self) -> Result<u16, NetError> {
+        for _ in EPHEMERAL_PORTS {
+            let port = self.next_ephemeral;
+            self.next_ephemeral = match port == *EPHEMERAL_PORTS.end() {
+                true => *EPHEMERAL_PORTS.start(),
+                false => port + 1,
+            };
+            if !self.port_in_use(port) {
+                return Ok(port);
+            }
+        }
+        Err(NetError::AddressInUse)
+    }
+}
+
+/// Starts the thread driving the timers of all connections
+pub fn init() -> Result<(), MemoryError> {
+    let id = scheduler::spawn(timer_loop, ThreadPriority::Normal)?;
+    scheduler::set_name(id, "tcp-timer");
+    Ok(())
+}
+
+fn timer_loop() {
+    loop {
+        scheduler::sleep(TIMER_INTERVAL);
+        let connections: Vec<_> = TABLES.lock().connections.values().cloned().collect();
+        let now = time::uptime();
+        for tcb in &connections {
+            tcb.update(|c| c.timer(now));
+        }
+
+        let closed: Vec<_> = connections
+            .iter()
+            .filter(|tcb| tcb.with(|c| c.state == TcpState::Closed))
+            .map(|tcb| tcb.with(|c| (c.local, c.remote)))
+            .collect();
+        let mut tables = TABLES.lock();
+        for key in closed {
+            tables.connections.remove(&key);
+        }
+    }
+}
+
+/// Handles a segment received by `interface`
+pub(super) fn receive(_interface: &Interface, packet: &Ipv4Packet) -> Result<(), NetError> {
+    let segment = TcpSegment::parse(packet)?;
+    let local = SocketAddress::new(packet.destination, segment.destination_port);
+    let remote = SocketAddress::new(packet.source, segment.source_port);
+    let now = time::uptime();
+
+    let (tcb, listener) = {
+        let tables = TABLES.lock();
+        (
+            tables.connections.get(&(local, remote)).cloned(),
+            tables.listeners.get(&local.port).cloned(),
+        )
+    };
+    if let Some(tcb) = tcb {
+        tcb.update(|c| c.segment_arrives(&segment, now));
+        hand_over(&tcb);
+        return Ok(());
+    }
+
+    let listener =
+        listener.filter(|l| l.local.ip == Ipv4Address::UNSPECIFIED || l.local.ip == local.ip);
+    let flags = segment.flags;
+    match listener {
+        Some(listener)
+            if flags.contains(TcpFlags::SYN)
+                && !flags.intersects(TcpFlags::ACK | TcpFlags::RST) =>
+        {
+            if without_interrupts(|| listener.backlog.lock().len()) >= BACKLOG {
+                // the peer retries
+                return Err(NetError::QueueFull);
+            }
+            let mut connection = Connection::new(local, remote, TcpState::SynReceived);
+            connection.receive_next = segment.sequence.wrapping_add(1);
+            connection.send_next = connection.initial_sequence.wrapping_add(1);
+            connection.send_window = segment.window as u32;
+            connection.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize);
+            connection.listener = Some(listener);
+            connection.arm_retransmit(now);
+            let syn_ack = connection.syn();
+            TABLES
+                .lock()
+                .connections
+                .insert((local, remote), Tcb::new(connection));
+            transmit(local, remote, alloc::vec![syn_ack]);
+            Ok(())
+        }
+        _ => {
+            if !flags.contains(TcpFlags::RST) {
+                transmit(local, remote, alloc::vec![reset_for(&segment)]);
+            }
+            Err(NetError::NoRoute)
+        }
+    }
+}
+
+/// Hands newly established connections to their listener
+fn hand_over(tcb: &Arc<Tcb>) {
+    let listener = tcb.with(|c| match c.state {
+        TcpState::SynReceived | TcpState::Closed => None,
+        _ => c.listener.take(),
+    });
+    if let Some(listener) = listener {
+        without_interrupts(|| listener.backlog.lock().push_back(tcb.clone()));
+        listener.accepting.wake_one();
+    }
+}
+
+/// Accepts connections on a port
+pub struct TcpListener {
+    listener: Arc<Listener>,
+}
+
+impl TcpListener {
+    /// Listens on `address`, the unspecified address accepts connections to
+    /// all local addresses
+    pub fn bind(address: SocketAddress) -> Result<Self, NetError> {
+        let mut tables = TABLES.lock();
+        let port = match address.port {
+            0 => tables.allocate_port()?,
+            port if tables.port_in_use(port) => return Err(NetError::AddressInUse),
+            port => port,
+        };
+        let listener = Arc::new(Listener {
+            local: SocketAddress::new(address.ip, port),
+            backlog: Mutex::new(VecDeque::new()),
+            accepting: WaitQueue::named("tcp accept"),
+        });
+        tables.listeners.insert(port, listener.clone());
+        Ok(Self { listener })
+    }
+
+    pub fn local_address(&self) -> SocketAddress {
+        self.listener.local
+    }
+
+    /// Blocks until a connection is established
+    pub fn accept(&self) -> Result<TcpStream, NetError> {
+        let mut tcb = None;
+        self.listener.accepting.wait_until(|| {
+            tcb = self.listener.backlog.lock().pop_front();
+            tcb.is_some()
+        });
+        Ok(TcpStream { tcb: tcb.unwrap() })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        TABLES.lock().listeners.remove(&self.listener.local.port);
+        // connections not accepted yet are reset
+        let pending: Vec<_> =
+            without_interrupts(|| self.listener.backlog.lock().drain(..).collect());
+        for tcb in pending {
+            tcb.update(|c| {
+                let reset = c.segment(TcpFlags::RST, c.send_next, Vec::new());
+                c.close(None);
+                alloc::vec![reset]
+            });
+        }
+    }
+}
+
+/// An open connection
+pub struct TcpStream {
+    tcb: Arc<Tcb>,
+}
+
+impl TcpStream {
+    /// Opens a connection to `remote`, blocks until it is established
+    pub fn connect(remote: SocketAddress) -> Result<Self, NetError> {
+        let (interface, _) = interface::route(remote.ip).ok_or(NetError::NoRoute)?;
+        let tcb = {
+            let mut tables = TABLES.lock();
+            let local = SocketAddress::new(interface.config().address, tables.allocate_port()?);
+            let tcb = Tcb::new(Connection::new(local, remote, TcpState::SynSent));
+            tables.connections.insert((local, remote), tcb.clone());
+            tcb
+        };
+        let now = time::uptime();
+        tcb.update(|c| c.connect(now));
+
+        tcb.writable
+            .wait_until(|| tcb.with(|c| c.state != TcpState::SynSent));
+        match tcb.with(|c| c.error) {
+            Some(error) => Err(error),
+            None => Ok(Self { tcb }),
+        }
+    }
+
+    pub fn local_address(&self) -> SocketAddress {
+        self.tcb.with(|c| c.local)
+    }
+
+    pub fn remote_address(&self) -> SocketAddress {
+        self.tcb.with(|c| c.remote)
+    }
+
+    pub fn state(&self) -> TcpState {
+        self.tcb.with(|c| c.state)
+    }
+
+    /// Blocks until data arrives and reads up to `buf.len()` bytes. Returns
+    /// 0 once the peer closed the connection and all data was read.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, NetError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut result = Ok(0);
+        let mut window_update = false;
+        self.tcb.readable.wait_until(|| {
+            self.tcb.with(|c| {
+                if !c.receive_buffer.is_empty() {
+                    let window = c.receive_window() as usize;
+                    let len = buf.len().min(c.receive_buffer.len());
+                    for (dst, src) in buf.iter_mut().zip(c.receive_buffer.drain(..len)) {
+                        *dst = src;
+                    }
+                    // tell the peer once a closed window opened again
+                    let threshold = c.mss.min(RECEIVE_BUFFER_SIZE / 2);
+                    window_update = window < threshold && c.receive_window() as usize >= threshold;
+                    result = Ok(len);
+                    return true;
+                }
+                if let Some(error) = c.error {
+                    result = Err(error);
+                    return true;
+                }
+                c.fin_received || c.state == TcpState::Closed
+            })
+        });
+
+        if window_update {
+            self.tcb.update(|c| alloc::vec![c.ack()]);
+        }
+        result
+    }
+
+    /// Blocks until there is space in the send buffer and queues up to
+    /// `buf.len()` bytes. Returns the amount queued.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, NetError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut result = Ok(0);
+        self.tcb.writable.wait_until(|| {
+            self.tcb.with(|c| {
+                if let Some(error) = c.error {
+                    result = Err(error);
+                    return true;
+                }
+                if c.closing || !matches!(c.state, TcpState::Established | TcpState::CloseWait) {
+                    result = Err(NetError::NotConnected);
+                    return true;
+                }
+                let len = buf.len().min(SEND_BUFFER_SIZE - c.send_buffer.len());
+                if len == 0 {
+                    return false;
+                }
+                c.send_buffer.extend(&buf[..len]);
+                result = Ok(len);
+                true
+            })
+        });
+
+        if result.is_ok() {
+            let now = time::uptime();
+            self.tcb.update(|c| c.output(now));
+        }
+        result
+    }
+
+    /// Writes all of `buf`
+    pub fn write_all(&self, mut buf: &[u8]) -> Result<(), NetError> {
+        while !buf.is_empty() {
+            let written = self.write(buf)?;
+            buf = &buf[written..];
+        }
+        Ok(())
+    }
+
+    /// Sends a FIN after the buffered data, reading is still possible
+    pub fn close(&self) {
+        let now = time::uptime();
+        self.tcb.update(|c| {
+            c.closing = true;
+            c.output(now)
+        });
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
@@ -0,0 +1,421 @@
+//! User Datagram Protocol.
+//!
+//! Sockets are bound to a local port, explicitly with [`UdpSocket::bind`] or
+//! to an ephemeral port on the first send. Received datagrams are queued per
+//! socket, [`UdpSocket::recv_from`] blocks until one arrives and
+//! [`UdpSocket::recv`] is its async version for tasks. Datagrams to
+//! ports without a socket and datagrams exceeding a full queue are dropped.
+//!
+//! Sockets are files, so processes refer to them with file descriptors. See
+//! [`sys_socket`] and the following system calls.
+extern crate alloc;
+use super::{
+    interface::Interface,
+    ipv4::{self, Ipv4Address, Ipv4Packet, Protocol},
+    NetError,
+};
+use crate::{
+    fs::{self, File, FileDescriptor, FsError},
+    sync::WaitQueue,
+    syscall::{Errno, SyscallResult},
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    slice,
+    task::{Context, Poll, Waker},
+};
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+pub const HEADER_SIZE: usize = 8;
+/// Datagrams queued per socket
+pub const MAX_QUEUED: usize = 64;
+/// Ports assigned to sockets that aren't bound explicitly
+pub const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct SocketAddress {
+    pub ip: Ipv4Address,
+    pub port: u16,
+}
+
+impl SocketAddress {
+    pub const fn new(ip: Ipv4Address, port: u16) -> Self {
+        Self { ip, port }
+    }
+}
+
+impl fmt::Display for SocketAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.ip, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpDatagram<'a> {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> UdpDatagram<'a> {
+    /// Parses the payload of `packet`, the checksum is optional
+    pub fn parse(packet: &Ipv4Packet<'a>) -> Result<Self, NetError> {
+        let data = packet.payload;
+        if data.len() < HEADER_SIZE {
+            return Err(NetError::Truncated);
+        }
+        let length = u16::from_be_bytes([data[4], data[5]]) as usize;
+        if length < HEADER_SIZE || data.len() < length {
+            return Err(NetError::Truncated);
+        }
+        let sent_checksum = u16::from_be_bytes([data[6], data[7]]);
+        if sent_checksum != 0
+            && ipv4::pseudo_header_checksum(
+                packet.source,
+                packet.destination,
+                Protocol::Udp,
+                &data[..length],
+            ) != 0
+        {
+            return Err(NetError::InvalidChecksum);
+        }
+        Ok(Self {
+            source_port: u16::from_be_bytes([data[0], data[1]]),
+            destination_port: u16::from_be_bytes([data[2], data[3]]),
+            payload: &data[HEADER_SIZE..length],
+        })
+    }
+
+    pub fn serialize(&self, source: Ipv4Address, destination: Ipv4Address) -> Vec<u8> {
+        let length = (HEADER_SIZE + self.payload.len()) as u16;
+        let mut datagram = Vec::with_capacity(length as usize);
+        datagram.extend_from_slice(&self.source_port.to_be_bytes());
+        datagram.extend_from_slice(&self.destination_port.to_be_bytes());
+        datagram.extend_from_slice(&length.to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(self.payload);
+        // 0 means no checksum, 0xffff is the same in ones' complement
+        let checksum =
+            match ipv4::pseudo_header_checksum(source, destination, Protocol::Udp, &datagram) {
+                0 => 0xffff,
+                checksum => checksum,
+            };
+        datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+        datagram
+    }
+}
+
+/// State shared between a socket and the port table
+struct Endpoint {
+    local: SocketAddress,
+    // locked with interrupts disabled since it is checked from wait queue
+    // conditions
+    queue: Mutex<VecDeque<(SocketAddress, Vec<u8>)>>,
+    readable: WaitQueue,
+    /// Waker of a pending [`Recv`], locked with interrupts disabled
+    waker: Mutex<Option<Waker>>,
+}
+
+struct Ports {
+    endpoints: BTreeMap<u16, Arc<Endpoint>>,
+    next_ephemeral: u16,
+}
+
+static PORTS: Mutex<Ports> = Mutex::new(Ports {
+    endpoints: BTreeMap::new(),
+    next_ephemeral: *EPHEMERAL_PORTS.start(),
+});
+
+impl Ports {
+    /// Port `requested` or a free ephemeral port if it is 0
+    fn allocate(&mut self, requested: u16) -> Result<u16, NetError> {
+        if requested != 0 {
+            return match self.endpoints.contains_key(&requested) {
+                true => Err(NetError::AddressInUse),
+                false => Ok(requested),
+            };
+        }
+        for _ in EPHEMERAL_PORTS {
+            let port = self.next_ephemeral;
+            self.next_ephemeral = match port == *EPHEMERAL_PORTS.end() {
+                true => *EPHEMERAL_PORTS.start(),
+                false => port + 1,
+            };
+            if !self.endpoints.contains_key(&port) {
+                return Ok(port);
+            }
+        }
+        Err(NetError::AddressInUse)
+    }
+}
+
+pub struct UdpSocket {
+    endpoint: Mutex<Option<Arc<Endpoint>>>,
+}
+
+impl UdpSocket {
+    pub const fn new() -> Self {
+        Self {
+            endpoint: Mutex::new(None),
+        }
+    }
+
+    /// Binds the socket to `address`, port 0 picks an ephemeral port. The
+    /// unspecified address receives datagrams to all local addresses.
+    pub fn bind(&self, address: SocketAddress) -> Result<SocketAddress, NetError> {
+        let mut endpoint = self.endpoint.lock();
+        if endpoint.is_some() {
+            return Err(NetError::AlreadyBound);
+        }
+        let bound = Self::register(address)?;
+        *endpoint = Some(bound.clone());
+        Ok(bound.local)
+    }
+
+    /// Address the socket is bound to
+    pub fn local_address(&self) -> Option<SocketAddress> {
+        self.endpoint.lock().as_ref().map(|e| e.local)
+    }
+
+    /// Endpoint of the socket, binding it to an ephemeral port if needed
+    fn bound(&self) -> Result<Arc<Endpoint>, NetError> {
+        let mut endpoint = self.endpoint.lock();
+        if endpoint.is_none() {
+            *endpoint = Some(Self::register(SocketAddress::default())?);
+        }
+        Ok(endpoint.clone().unwrap())
+    }
+
+    fn register(address: SocketAddress) -> Result<Arc<Endpoint>, NetError> {
+        let mut ports = PORTS.lock();
+        let port = ports.allocate(address.port)?;
+        let endpoint = Arc::new(Endpoint {
+            local: SocketAddress::new(address.ip, port),
+            queue: Mutex::new(VecDeque::new()),
+            readable: WaitQueue::named("udp receive"),
+            waker: Mutex::new(None),
+        });
+        ports.endpoints.insert(port, endpoint.clone());
+        Ok(endpoint)
+    }
+
+    /// Sends `buf` as one datagram to `destination`
+    pub fn send_to(&self, buf: &[u8], destination: SocketAddress) -> Result<usize, NetError> {
+        let endpoint = self.bound()?;
+        let (interface, _) = super::interface::route(destination.ip).ok_or(NetError::NoRoute)?;
+        let source = match endpoint.local.ip {
+            Ipv4Address::UNSPECIFIED => interface.config().address,
+            ip => ip,
+        };
+
+        let datagram = UdpDatagram {
+            source_port: endpoint.local.port,
+            destination_port: destination.port,
+            payload: buf,
+        };
+        ipv4::send(
+            destination.ip,
+            Protocol::Udp,
+            &datagram.serialize(source, destination.ip),
+        )?;
+        Ok(buf.len())
+    }
+
+    /// Blocks until a datagram arrives, copies it into `buf` and returns its
+    /// length and sender. The rest of datagrams longer than `buf` is lost.
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddress), NetError> {
+        let endpoint = self.bound()?;
+        let mut datagram = None;
+        endpoint.readable.wait_until(|| {
+            datagram = without_interrupts(|| endpoint.queue.lock().pop_front());
+            datagram.is_some()
+        });
+
+        let (source, data) = datagram.unwrap();
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, source))
+    }
+
+    /// Next datagram and its sender, the async version of
+    /// [`recv_from`](Self::recv_from)
+    pub fn recv(&self) -> Recv<'_> {
+        Recv(self)
+    }
+
+    /// Amount of queued datagrams
+    pub fn pending(&self) -> usize {
+        self.endpoint
+            .lock()
+            .as_ref()
+            .map_or(0, |e| without_interrupts(|| e.queue.lock().len()))
+    }
+}
+
+/// Returned by [`UdpSocket::recv`]
+pub struct Recv<'a>(&'a UdpSocket);
+
+impl Future for Recv<'_> {
+    type Output = Result<(SocketAddress, Vec<u8>), NetError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let endpoint = self.0.bound()?;
+        without_interrupts(|| {
+            // the waker is set before the queue is unlocked, so a datagram
+            // queued afterwards finds it
+            let mut queue = endpoint.queue.lock();
+            match queue.pop_front() {
+                Some(datagram) => Poll::Ready(Ok(datagram)),
+                None => {
+                    *endpoint.waker.lock() = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+impl Default for UdpSocket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        if let Some(endpoint) = self.endpoint.lock().take() {
+            PORTS.lock().endpoints.remove(&endpoint.local.port);
+        }
+    }
+}
+
+impl File for UdpSocket {
+    /// Receives a datagram, dropping its sender
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.recv_from(buf)
+            .map(|(len, _)| len)
+            .map_err(|_| FsError::NotSupported)
+    }
+
+    fn as_socket(&self) -> Option<&UdpSocket> {
+        Some(self)
+    }
+}
+
+/// Handles a datagram received by `interface`
+pub(super) fn receive(_interface: &Interface, packet: &Ipv4Packet) -> Result<(), NetError> {
+    let datagram = UdpDatagram::parse(packet)?;
+    let endpoint = PORTS
+        .lock()
+        .endpoints
+        .get(&datagram.destination_port)
+        .cloned()
+        .ok_or(NetError::NoRoute)?;
+    if endpoint.local.ip != Ipv4Address::UNSPECIFIED && endpoint.local.ip != packet.destination {
+        return Err(NetError::NoRoute);
+    }
+
+    let source = SocketAddress::new(packet.source, datagram.source_port);
+    let queued = without_interrupts(|| {
+        let mut queue = endpoint.queue.lock();
+        if queue.len() == MAX_QUEUED {
+            return false;
+        }
+        queue.push_back((source, datagram.payload.to_vec()));
+        true
+    });
+    if !queued {
+        return Err(NetError::QueueFull);
+    }
+    endpoint.readable.wake_one();
+    if let Some(waker) = without_interrupts(|| endpoint.waker.lock().take()) {
+        waker.wake();
+    }
+    Ok(())
+}
+
+/// Socket address layout used by the system call interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct RawSocketAddress {
+    /// The address in host byte order
+    pub ip: u32,
+    pub port: u16,
+}
+
+impl From<RawSocketAddress> for SocketAddress {
+    fn from(raw: RawSocketAddress) -> Self {
+        SocketAddress::new(Ipv4Address::from_u32(raw.ip), raw.port)
+    }
+}
+
+impl From<SocketAddress> for RawSocketAddress {
+    fn from(address: SocketAddress) -> Self {
+        RawSocketAddress {
+            ip: address.ip.as_u32(),
+            port: address.port,
+        }
+    }
+}
+
+fn raw_address<'a>(address: u64) -> Result<&'a mut RawSocketAddress, Errno> {
+    if address == 0 || !address.is_multiple_of(core::mem::align_of::<RawSocketAddress>() as u64) {
+        return Err(Errno::EFAULT);
+    }
+    Ok(unsafe { &mut *(address as *mut RawSocketAddress) })
+}
+
+fn buffer<'a>(address: u64, len: u64) -> Result<&'a mut [u8], Errno> {
+    if address == 0 {
+        return Err(Errno::EFAULT);
+    }
+    Ok(unsafe { slice::from_raw_parts_mut(address as *mut u8, len as usize) })
+}
+
+fn with_socket<F, R>(fd: u64, f: F) -> Result<R, Errno>
+where
+    F: FnOnce(&UdpSocket) -> Result<R, NetError>,
+{
+    let file = fs::get(FileDescriptor::from_u64(fd))?;
+    let socket = file.as_socket().ok_or(Errno::ENOTSOCK)?;
+    Ok(f(socket)?)
+}
+
+/// `socket()` system call. Returns the descriptor of a new UDP socket.
+pub fn sys_socket() -> SyscallResult {
+    Ok(fs::install(Arc::new(UdpSocket::new())).as_u64())
+}
+
+/// `bind(fd, address)` system call. `address` points to a
+/// [`RawSocketAddress`], the bound port is stored back into it.
+pub fn sys_bind(fd: u64, address: u64) -> SyscallResult {
+    let raw = raw_address(address)?;
+    let bound = with_socket(fd, |socket| socket.bind((*raw).into()))?;
+    *raw = bound.into();
+    Ok(0)
+}
+
+/// `sendto(fd, buf, len, address)` system call. Returns the bytes sent.
+pub fn sys_sendto(fd: u64, buf: u64, len: u64, address: u64) -> SyscallResult {
+    let buf = buffer(buf, len)?;
+    let destination = (*raw_address(address)?).into();
+    Ok(with_socket(fd, |socket| socket.send_to(buf, destination))? as u64)
+}
+
+/// `recvfrom(fd, buf, len, address)` system call. Stores the sender in
+/// `address` unless it is 0 and returns the length of the datagram.
+pub fn sys_recvfrom(fd: u64, buf: u64, len: u64, address: u64) -> SyscallResult {
+    let buf = buffer(buf, len)?;
+    let (len, source) = with_socket(fd, |socket| socket.recv_from(buf))?;
+    if address != 0 {
+        *raw_address(address)? = source.into();
+    }
+    Ok(len as u64)
+}
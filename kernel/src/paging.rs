@@ -1,15 +1,229 @@
-use api::BootInfo;
+//! Access to the active page table.
+//!
+//! The page tables are reached through the physical memory mapping by
+//! default. With the `recursive-paging` feature the recursive PML4 entry set
+//! up by the bootloader is used instead, so the page table code no longer
+//! depends on the physical memory mapping.
+use crate::{
+    debug,
+    memory::{self, KernelPageTable},
+};
+use alloc::vec::Vec;
+use api::{layout::PHYSICAL_MEMORY, BootInfo};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU16, AtomicU64, Ordering},
+};
 use x86_64::{
-    memory::{Address, VirtualAddress},
-    paging::PageTable,
+    memory::{
+        Address, Page, PageSize, PhysicalAddress, PhysicalMemoryRegionType, Size1GiB, Size2MiB,
+        Size4KiB, VirtualAddress,
+    },
+    paging::{
+        self as x86_paging, mapped_page_table::Mapping, Mappings, PageTableEntryFlags,
+        PagingLevels, Translator, TranslatorAllSizes,
+    },
     println,
-    register::Cr3,
+    register::{MemoryType, Mtrr},
 };
 
-pub unsafe fn init(bios_info: &'static BootInfo) -> &'static mut PageTable {
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static RECURSIVE_INDEX: AtomicU16 = AtomicU16::new(0);
+
+/// Stores where the bootloader mapped physical memory and the page tables
+/// and programs the PAT.
+///
+/// # Safety
+///
+/// Has to be called once, before any page table is accessed. `bios_info` has
+/// to describe the page tables that are currently active.
+pub unsafe fn init(bios_info: &'static BootInfo) {
+    PHYSICAL_MEMORY_OFFSET.store(bios_info.physical_memory_offset, Ordering::SeqCst);
+    RECURSIVE_INDEX.store(bios_info.recursive_index, Ordering::SeqCst);
+    x86_paging::init_pat();
+    log_mtrrs();
+}
+
+/// The active page table
+///
+/// # Safety
+///
+/// Modifications through the returned table must not race with the memory
+/// manager
+#[cfg(not(feature = "recursive-paging"))]
+pub unsafe fn kernel_page_table() -> KernelPageTable {
+    use x86_64::paging::offset_page_table::{OffsetPageTable, PhysicalOffset};
+    OffsetPageTable::new(
+        active_page_table(),
+        PhysicalOffset::new(physical_memory_offset()),
+    )
+}
+
+/// The active page table
+///
+/// # Safety
+///
+/// Modifications through the returned table must not race with the memory
+/// manager
+#[cfg(feature = "recursive-paging")]
+pub unsafe fn kernel_page_table() -> KernelPageTable {
+    use x86_64::paging::recursive_page_table::RecursivePageTable;
+    RecursivePageTable::active(RECURSIVE_INDEX.load(Ordering::SeqCst))
+        .expect("Recursive page table entry missing")
+}
+
+/// Index of the PML4 entry pointing to the PML4 table itself
+pub fn recursive_index() -> u16 {
+    RECURSIVE_INDEX.load(Ordering::SeqCst)
+}
+
+fn log_mtrrs() {
+    debug!(
+        "MTRR default type: {:?}, write combining: {}",
+        Mtrr::default_type(),
+        Mtrr::write_combining()
+    );
+    for range in Mtrr::variable_ranges() {
+        debug!(
+            "MTRR {:#x} mask {:#x}: {:?}",
+            range.base.as_u64(),
+            range.mask,
+            range.memory_type
+        );
+    }
+}
+
+#[cfg(not(feature = "recursive-paging"))]
+unsafe fn active_page_table() -> &'static mut x86_paging::PageTable {
+    use x86_64::register::Cr3;
     let (plm4t, _) = Cr3::read();
 
-    let virtual_base = VirtualAddress::new(plm4t.start() + bios_info.physical_memory_offset);
-    let page_table_ptr: *mut PageTable = virtual_base.as_mut_ptr();
+    let virtual_base = VirtualAddress::new(plm4t.start() + physical_memory_offset());
+    let page_table_ptr: *mut x86_paging::PageTable = virtual_base.as_mut_ptr();
     &mut *page_table_ptr
 }
+
+/// Offset at which all of physical memory is mapped
+pub fn physical_memory_offset() -> u64 {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst)
+}
+
+/// Virtual address of `address` in the physical memory mapping
+pub fn physical_to_virtual(address: PhysicalAddress) -> VirtualAddress {
+    VirtualAddress::new(address.as_u64() + physical_memory_offset())
+}
+
+/// Translates `address` using the active page table. Does not lock the memory
+/// manager, so it is usable from exception handlers.
+pub fn translate(address: VirtualAddress) -> Option<PhysicalAddress> {
+    let page_table = unsafe { kernel_page_table() };
+    translate_in(&page_table, address).map(|(physical, _)| physical)
+}
+
+/// Physical address and flags of the mapping of `address` in `page_table`,
+/// whatever the size of the page containing it
+pub fn translate_in(
+    page_table: &impl TranslatorAllSizes,
+    address: VirtualAddress,
+) -> Option<(PhysicalAddress, PageTableEntryFlags)> {
+    translate_huge::<Size1GiB>(page_table, address)
+        .or_else(|| translate_huge::<Size2MiB>(page_table, address))
+        .or_else(|| {
+            let page = Page::<Size4KiB>::containing_address(address);
+            Translator::<Size4KiB>::translate(page_table, page)
+                .ok()
+                .map(|(frame, flags)| (frame.address + (address - page.address), flags))
+        })
+}
+
+fn translate_huge<S: PageSize>(
+    page_table: &impl Translator<S>,
+    address: VirtualAddress,
+) -> Option<(PhysicalAddress, PageTableEntryFlags)> {
+    let page = Page::<S>::containing_address(address);
+    match page_table.translate(page) {
+        Ok((frame, flags)) if flags.contains(PageTableEntryFlags::HUGE_PAGE) => {
+            Some((frame.address + (address - page.address), flags))
+        }
+        _ => None,
+    }
+}
+
+/// What is wrong with a mapping found by [`verify`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueKind {
+    /// The mapping starts or ends outside the canonical address space
+    NonCanonical,
+    WritableExecutable,
+    /// A kernel address that user space can access
+    UserAccessibleKernel,
+    /// Cached access to memory the firmware or the kernel reserved, e.g.
+    /// MMIO ranges
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub mapping: Mapping,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}: {:#x} -> {:#x} ({} KiB, {:?})",
+            self.kind,
+            self.mapping.start.as_u64(),
+            self.mapping.frame.as_u64(),
+            self.mapping.size / 1024,
+            self.mapping.flags
+        )
+    }
+}
+
+/// Walks all levels of `page_table` and reports mappings that are insecure
+/// or point where they shouldn't. Reserved memory is looked up in the kernel
+/// memory map, the physical memory mapping is exempt as it covers all of it.
+pub fn verify<T: Mappings>(page_table: &T) -> Vec<Issue> {
+    let levels = PagingLevels::current();
+    let reserved = memory::kernel_memory_regions()
+        .iter()
+        .filter(|region| region.typ == PhysicalMemoryRegionType::Reserved);
+
+    let mut issues = Vec::new();
+    page_table.for_each_mapping(|mapping| {
+        let start = mapping.start;
+        let last = VirtualAddress::new(start.as_u64().wrapping_add(mapping.size - 1));
+        let flags = mapping.flags;
+        let frame = mapping.frame.as_u64();
+
+        let kinds = [
+            (!levels.is_canonical(start) || !levels.is_canonical(last))
+                .then_some(IssueKind::NonCanonical),
+            (flags.contains(PageTableEntryFlags::WRITABLE)
+                && !flags.contains(PageTableEntryFlags::NO_EXECUTE))
+            .then_some(IssueKind::WritableExecutable),
+            (is_kernel_address(start) && flags.contains(PageTableEntryFlags::USER_ACCESSIBLE))
+                .then_some(IssueKind::UserAccessibleKernel),
+            (mapping.memory_type == MemoryType::WriteBack
+                && !PHYSICAL_MEMORY.contains(start.as_u64())
+                && reserved.clone().any(|region| {
+                    region.start < frame + mapping.size && frame < region.start + region.size
+                }))
+            .then_some(IssueKind::Reserved),
+        ];
+        issues.extend(
+            kinds
+                .into_iter()
+                .flatten()
+                .map(|kind| Issue { kind, mapping }),
+        );
+    });
+    issues
+}
+
+/// Whether `address` lies in the upper half of the address space
+fn is_kernel_address(address: VirtualAddress) -> bool {
+    address.as_u64() >> 63 == 1
+}
@@ -0,0 +1,168 @@
+//! PCI configuration space access.
+//!
+//! Devices are found by probing every bus, device and function through the
+//! legacy configuration mechanism (I/O ports 0xcf8 and 0xcfc). The BIOS
+//! already assigned the BARs and interrupt lines, they are used as is.
+//!
+//! https://wiki.osdev.org/PCI
+extern crate alloc;
+use alloc::vec::Vec;
+use x86_64::{memory::PhysicalAddress, mutex::Mutex, port::Port};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+const VENDOR_ID: u8 = 0x00;
+const DEVICE_ID: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+const CLASS: u8 = 0x08;
+const HEADER_TYPE: u8 = 0x0e;
+const BAR0: u8 = 0x10;
+const INTERRUPT_LINE: u8 = 0x3c;
+
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+/// Vendor ID read for functions that don't exist
+const NO_DEVICE: u16 = 0xffff;
+
+/// Serializes the two step accesses through the address and data ports
+static CONFIG: Mutex<(Port<u32>, Port<u32>)> =
+    Mutex::new((Port::new(CONFIG_ADDRESS), Port::new(CONFIG_DATA)));
+
+/// A function of a device on a bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+}
+
+/// Base address register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { address: PhysicalAddress, size: u64 },
+    Io { port: u16 },
+}
+
+impl PciDevice {
+    fn at(bus: u8, device: u8, function: u8) -> Option<Self> {
+        let mut pci = Self {
+            bus,
+            device,
+            function,
+            vendor_id: 0,
+            device_id: 0,
+        };
+        pci.vendor_id = pci.read_u16(VENDOR_ID);
+        if pci.vendor_id == NO_DEVICE {
+            return None;
+        }
+        pci.device_id = pci.read_u16(DEVICE_ID);
+        Some(pci)
+    }
+
+    /// Class code, subclass and programming interface
+    pub fn class(&self) -> (u8, u8, u8) {
+        let class = self.read_u32(CLASS);
+        ((class >> 24) as u8, (class >> 16) as u8, (class >> 8) as u8)
+    }
+
+    /// Line of the legacy PIC the BIOS routed the interrupt to
+    pub fn interrupt_line(&self) -> u8 {
+        self.read_u32(INTERRUPT_LINE) as u8
+    }
+
+    /// Decodes base address register `index` (0-5), None if it's unused
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        assert!(index < 6, "BAR {} doesn't exist", index);
+        let offset = BAR0 + index * 4;
+        let value = self.read_u32(offset);
+        if value & 1 == 1 {
+            return Some(Bar::Io {
+                port: (value & !0x3) as u16,
+            });
+        }
+
+        let is_64bit = (value >> 1) & 0x3 == 0x2;
+        let mut address = (value & !0xf) as u64;
+        if is_64bit {
+            address |= (self.read_u32(offset + 4) as u64) << 32;
+        }
+        // the size is found by writing all ones and reading back the mask
+        self.write_u32(offset, u32::MAX);
+        let mask = self.read_u32(offset) & !0xf;
+        self.write_u32(offset, value);
+        if mask == 0 {
+            return None;
+        }
+        Some(Bar::Memory {
+            address: PhysicalAddress::new(address),
+            size: (!mask).wrapping_add(1) as u64,
+        })
+    }
+
+    /// Lets the device decode its memory BARs and access memory by DMA
+    pub fn enable_bus_master(&self) {
+        let command = self.read_u16(COMMAND);
+        self.write_u16(COMMAND, command | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        let config = CONFIG.lock();
+        config.0.write(self.config_address(offset));
+        config.1.read()
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        let config = CONFIG.lock();
+        config.0.write(self.config_address(offset));
+        config.1.write(value);
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset & !0x3) >> ((offset & 0x2) * 8)) as u16
+    }
+
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 0x2) * 8;
+        let old = self.read_u32(offset & !0x3) & !(0xffff << shift);
+        self.write_u32(offset & !0x3, old | (value as u32) << shift);
+    }
+
+    fn config_address(&self, offset: u8) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & !0x3) as u32
+    }
+}
+
+/// All functions on all buses
+pub fn devices() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=u8::MAX {
+        for device in 0..32 {
+            let Some(first) = PciDevice::at(bus, device, 0) else {
+                continue;
+            };
+            devices.push(first);
+            if first.read_u16(HEADER_TYPE) as u8 & HEADER_MULTI_FUNCTION == 0 {
+                continue;
+            }
+            devices.extend((1..8).filter_map(|function| PciDevice::at(bus, device, function)));
+        }
+    }
+    devices
+}
+
+/// First function with the vendor and device ID
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    devices()
+        .into_iter()
+        .find(|d| d.vendor_id == vendor_id && d.device_id == device_id)
+}
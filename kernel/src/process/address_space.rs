@@ -0,0 +1,49 @@
+extern crate alloc;
+use crate::memory;
+use alloc::vec::Vec;
+use x86_64::memory::VirtualAddress;
+
+/// Memory regions owned by a process.
+///
+/// All processes share the kernel page table for now, the address space only
+/// keeps track of which regions have to be unmapped once the process is gone.
+#[derive(Debug, Default)]
+pub struct AddressSpace {
+    regions: Vec<VirtualAddress>,
+}
+
+impl AddressSpace {
+    pub const fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
+
+    /// Start addresses of all regions
+    pub fn regions(&self) -> &[VirtualAddress] {
+        &self.regions
+    }
+
+    /// Registers a region mapped by the memory manager
+    pub fn add(&mut self, start: VirtualAddress) {
+        self.regions.push(start);
+    }
+
+    /// Forgets about a region. Returns whether the region was part of the
+    /// address space.
+    pub fn remove(&mut self, start: VirtualAddress) -> bool {
+        let len = self.regions.len();
+        self.regions.retain(|r| *r != start);
+        len != self.regions.len()
+    }
+
+    /// Unmaps all regions
+    pub fn free(self) {
+        memory::with_memory_manager(|mm| {
+            for start in self.regions {
+                mm.munmap(start)
+                    .expect("Failed to unmap region of address space");
+            }
+        });
+    }
+}
@@ -0,0 +1,440 @@
+//! This module implements processes on top of the scheduler.
+//!
+//! A process consists of a main thread, an address space and its position in
+//! the process tree. Every process except the kernel process has a parent.
+//! Threads that are not part of any process belong to the kernel process.
+//!
+//! Once a process exits it becomes a zombie: its address space is handed to
+//! the finalizer thread for reclamation, but the process table entry with the
+//! exit code is kept until the parent collected it using [`waitpid`].
+//! Children of an exiting process are reparented to the kernel process. Since
+//! nobody is going to wait for them anymore they are reaped as soon as they
+//! exit.
+extern crate alloc;
+use crate::{
+    allocator::{CacheBox, KmemCache, ObjectCache},
+    backtrace,
+    fs::FileTable,
+    memory::{self, MemoryError, VirtualMemoryObject},
+    scheduler::{self, finalizer, ThreadId, ThreadPriority},
+    sync::WaitQueue,
+};
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use hashmap::HashMap;
+use x86_64::{
+    interrupts::without_interrupts,
+    memory::VirtualAddress,
+    mutex::{InterruptSafeMutex, Mutex},
+    paging::PageTableEntryFlags,
+};
+
+mod address_space;
+pub mod signal;
+pub use address_space::AddressSpace;
+use signal::{Signal, SignalState};
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+// Locked with interrupts disabled since it is accessed while blocking on
+// wait queues
+static PROCESS_TABLE: Mutex<ProcessTable> = Mutex::new(ProcessTable::new());
+static PROCESS_CACHE: ObjectCache<Process> = InterruptSafeMutex::new(KmemCache::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    /// Process all threads that are not part of any process belong to
+    pub const KERNEL: Pid = Pid(0);
+
+    fn new() -> Self {
+        Self(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(pid: u64) -> Self {
+        Self(pid)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    /// Exited, waiting for the parent to collect the exit code
+    Zombie {
+        exit_code: i32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    /// The caller has no child matching the request
+    NoChild,
+    OutOfMemory,
+}
+
+impl From<MemoryError> for ProcessError {
+    fn from(_: MemoryError) -> Self {
+        ProcessError::OutOfMemory
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    parent: Pid,
+    name: String,
+    children: Vec<Pid>,
+    main_thread: Option<ThreadId>,
+    entry: Option<fn() -> i32>,
+    state: ProcessState,
+    address_space: AddressSpace,
+    /// Signaled whenever a child of this process exits
+    child_exited: Arc<WaitQueue>,
+    /// Set for orphans, nobody is going to wait for them
+    detached: bool,
+    signals: SignalState,
+    files: FileTable,
+}
+
+impl Process {
+    fn new(
+        pid: Pid,
+        parent: Pid,
+        name: String,
+        main_thread: Option<ThreadId>,
+        entry: Option<fn() -> i32>,
+        files: FileTable,
+    ) -> Self {
+        Self {
+            pid,
+            parent,
+            name,
+            children: Vec::new(),
+            main_thread,
+            entry,
+            state: ProcessState::Running,
+            address_space: AddressSpace::new(),
+            child_exited: Arc::new(WaitQueue::named("child exit")),
+            detached: false,
+            signals: SignalState::new(),
+            files,
+        }
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Pid {
+        self.parent
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = String::from(name);
+    }
+
+    pub fn children(&self) -> &[Pid] {
+        &self.children
+    }
+
+    pub fn main_thread(&self) -> Option<ThreadId> {
+        self.main_thread
+    }
+
+    pub fn state(&self) -> ProcessState {
+        self.state
+    }
+
+    pub fn address_space(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+
+    pub fn signals(&self) -> &SignalState {
+        &self.signals
+    }
+
+    pub fn files(&mut self) -> &mut FileTable {
+        &mut self.files
+    }
+}
+
+pub struct ProcessTable {
+    processes: HashMap<Pid, CacheBox<Process>>,
+    /// Process every thread belongs to
+    owners: HashMap<ThreadId, Pid>,
+}
+
+impl ProcessTable {
+    const fn new() -> Self {
+        Self {
+            processes: HashMap::new(),
+            owners: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, pid: Pid) -> Option<&Process> {
+        self.processes.get(&pid).map(|p| &**p)
+    }
+
+    pub fn get_mut(&mut self, pid: Pid) -> Option<&mut Process> {
+        self.processes.get_mut(&pid).map(|p| &mut **p)
+    }
+
+    pub fn processes(&self) -> impl Iterator<Item = &Process> {
+        self.processes.values().map(|p| &**p)
+    }
+
+    pub fn owner(&self, thread: ThreadId) -> Pid {
+        self.owners.get(&thread).copied().unwrap_or(Pid::KERNEL)
+    }
+
+    /// Removes a zombie from the table and its parents list of children
+    fn reap(&mut self, pid: Pid) -> i32 {
+        let process = self.processes.remove(&pid).unwrap();
+        let exit_code = match process.state {
+            ProcessState::Zombie { exit_code } => exit_code,
+            ProcessState::Running => panic!("Tried to reap running process {}", pid),
+        };
+
+        if let Some(parent) = self.processes.get_mut(&process.parent) {
+            parent.children.retain(|c| *c != pid);
+        }
+
+        exit_code
+    }
+}
+
+pub fn init() {
+    with_process_table(|table| {
+        table.processes.insert(
+            Pid::KERNEL,
+            CacheBox::new(
+                &PROCESS_CACHE,
+                Process::new(
+                    Pid::KERNEL,
+                    Pid::KERNEL,
+                    String::from("kernel"),
+                    None,
+                    None,
+                    FileTable::new(),
+                ),
+            ),
+        );
+    });
+}
+
+/// Runs `f` with exclusive access to the process table and interrupts disabled
+pub fn with_process_table<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut ProcessTable) -> R,
+{
+    without_interrupts(|| f(&mut PROCESS_TABLE.lock()))
+}
+
+/// Process whose address space contains the region starting at `start`. None
+/// if there is none or the process table is locked, so it can be used from
+/// exception handlers.
+pub fn try_region_owner(start: VirtualAddress) -> Option<Pid> {
+    let table = PROCESS_TABLE.try_lock()?;
+    let owner = table
+        .processes()
+        .find(|process| process.address_space.regions().contains(&start))
+        .map(|process| process.pid);
+    owner
+}
+
+/// Process the current thread belongs to
+pub fn current() -> Pid {
+    let thread = scheduler::current();
+    with_process_table(|table| table.owner(thread))
+}
+
+/// Creates a child process of the current process running `entry`. The return
+/// value of `entry` is the exit code of the process. The child inherits all
+/// open files of the parent. The process and its main thread are named after
+/// `entry`.
+pub fn spawn(entry: fn() -> i32, priority: ThreadPriority) -> Result<Pid, ProcessError> {
+    let parent = current();
+    let pid = Pid::new();
+    let name = match backtrace::function_name(entry as usize as u64) {
+        Some(name) => String::from(name),
+        None => format!("process-{}", pid),
+    };
+
+    let mut thread = scheduler::create_thread(process_start, priority)?;
+    thread.set_name(&name);
+    let main_thread = thread.id();
+
+    with_process_table(|table| {
+        let parent_process = table
+            .processes
+            .get_mut(&parent)
+            .expect("Parent process vanished");
+        parent_process.children.push(pid);
+        let files = parent_process.files.clone();
+
+        table.owners.insert(main_thread, pid);
+        table.processes.insert(
+            pid,
+            CacheBox::new(
+                &PROCESS_CACHE,
+                Process::new(pid, parent, name, Some(main_thread), Some(entry), files),
+            ),
+        );
+    });
+
+    scheduler::start(thread);
+    Ok(pid)
+}
+
+fn process_start() {
+    let pid = current();
+    let entry = with_process_table(|table| table.get(pid).and_then(|p| p.entry))
+        .expect("Process started without entry");
+
+    exit(entry());
+}
+
+/// Allocates memory that belongs to the current process and is freed once it
+/// exits
+pub fn allocate(
+    size: u64,
+    flags: PageTableEntryFlags,
+    object: VirtualMemoryObject,
+) -> Result<VirtualAddress, MemoryError> {
+    let pid = current();
+    // the memory manager must not be locked with interrupts disabled, so it
+    // can't be called from inside of the process table lock
+    let start = memory::with_memory_manager(|mm| mm.allocate(size, flags, object))?;
+    with_process_table(|table| table.get_mut(pid).unwrap().address_space.add(start));
+    Ok(start)
+}
+
+/// Unmaps a region of the current process
+pub fn unmap(start: VirtualAddress) -> Result<(), MemoryError> {
+    let pid = current();
+    if !with_process_table(|table| table.get_mut(pid).unwrap().address_space.remove(start)) {
+        return Err(MemoryError::RegionNotFound);
+    }
+    memory::with_memory_manager(|mm| mm.munmap(start))
+}
+
+/// Terminates the current process with `exit_code`
+pub fn exit(exit_code: i32) -> ! {
+    let thread = scheduler::current();
+
+    let (address_space, files, parent_waiters) = with_process_table(|table| {
+        let pid = table.owner(thread);
+        assert!(pid != Pid::KERNEL, "Kernel process can't exit");
+        table.owners.remove(&thread);
+
+        let process = table.get_mut(pid).unwrap();
+        process.state = ProcessState::Zombie { exit_code };
+        process.main_thread = None;
+        let address_space = core::mem::take(&mut process.address_space);
+        let files = core::mem::take(&mut process.files);
+        let children = core::mem::take(&mut process.children);
+        let parent = process.parent;
+        let detached = process.detached;
+
+        // reparent children to the kernel process, zombies among them will
+        // never be waited for
+        for child in children {
+            let child_process = table.get_mut(child).unwrap();
+            child_process.parent = Pid::KERNEL;
+            child_process.detached = true;
+            if let ProcessState::Zombie { .. } = child_process.state {
+                table.processes.remove(&child);
+            } else {
+                table.get_mut(Pid::KERNEL).unwrap().children.push(child);
+            }
+        }
+
+        if detached {
+            table.reap(pid);
+            return (address_space, files, None);
+        }
+
+        let waiters = table.get(parent).map(|p| p.child_exited.clone());
+        (address_space, files, waiters)
+    });
+
+    finalizer::defer(Box::new(move || address_space.free()));
+    // closing files might wake up other threads, which can't happen while
+    // the process table is locked
+    drop(files);
+
+    if let Some(waiters) = parent_waiters {
+        waiters.wake_all();
+    }
+
+    scheduler::exit();
+}
+
+/// Terminates the current thread after an exception it caused. A process
+/// exits as if killed by `signal`, kernel threads just exit.
+pub fn terminate_current(signal: Signal) -> ! {
+    if current() != Pid::KERNEL {
+        exit(128 + signal.number() as i32);
+    }
+    scheduler::exit()
+}
+
+/// Blocks until a child terminates. Returns its pid and exit code.
+pub fn wait() -> Result<(Pid, i32), ProcessError> {
+    waitpid(None)
+}
+
+/// Blocks until the child `pid` terminates, or any child if `pid` is None.
+/// Returns the pid and exit code of the child and removes it from the
+/// process table.
+pub fn waitpid(pid: Option<Pid>) -> Result<(Pid, i32), ProcessError> {
+    let parent = current();
+    let waiters = with_process_table(|table| table.get(parent).unwrap().child_exited.clone());
+
+    let mut result = Err(ProcessError::NoChild);
+    waiters.wait_until(|| {
+        with_process_table(|table| {
+            let children = &table.get(parent).unwrap().children;
+            let candidates = children.iter().filter(|c| pid.is_none_or(|p| p == **c));
+
+            let mut any = false;
+            let mut zombie = None;
+            for child in candidates {
+                any = true;
+                if let ProcessState::Zombie { .. } = table.get(*child).unwrap().state {
+                    zombie = Some(*child);
+                    break;
+                }
+            }
+
+            match (any, zombie) {
+                (false, _) => true,
+                (true, None) => false,
+                (true, Some(child)) => {
+                    result = Ok((child, table.reap(child)));
+                    true
+                }
+            }
+        })
+    });
+
+    result
+}
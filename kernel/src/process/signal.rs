@@ -0,0 +1,302 @@
+//! POSIX style signals.
+//!
+//! Every process has a set of pending and a set of blocked signals plus an
+//! action per signal. Sending a signal only marks it pending, it is delivered
+//! the next time the process passes a delivery point:
+//! - on return from a system call
+//! - when the process explicitly calls [`deliver_pending`]
+//!
+//! There is no user mode yet, so handlers are kernel functions. They are called
+//! from the delivery point on the stack of the interrupted thread, which saves
+//! the interrupted context (e.g. the system call return value) across the
+//! handler and restores it afterwards.
+//!
+//! `SIGKILL` and `SIGSTOP` can neither be blocked nor handled. A process
+//! stopped by `SIGSTOP` waits at the delivery point until it is sent
+//! `SIGCONT` or `SIGKILL`.
+use super::{with_process_table, Pid};
+use crate::{
+    sync::WaitQueue,
+    syscall::{Errno, SyscallResult},
+};
+use core::fmt;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+/// Highest supported signal number
+pub const MAX_SIGNAL: usize = 31;
+
+/// Woken whenever a stopped process is continued or killed
+static CONTINUED: WaitQueue = WaitQueue::named("stopped");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+#[num_enum(from = u64, error_type = Errno, error = Errno::EINVAL)]
+pub enum Signal {
+    SIGHUP = 1,
+    SIGINT = 2,
+    SIGQUIT = 3,
+    SIGILL = 4,
+    SIGABRT = 6,
+    SIGBUS = 7,
+    SIGFPE = 8,
+    SIGKILL = 9,
+    SIGUSR1 = 10,
+    SIGSEGV = 11,
+    SIGUSR2 = 12,
+    SIGPIPE = 13,
+    SIGALRM = 14,
+    SIGTERM = 15,
+    SIGCHLD = 17,
+    SIGCONT = 18,
+    SIGSTOP = 19,
+}
+
+impl Signal {
+    pub fn number(self) -> u8 {
+        u8::from(self)
+    }
+
+    /// Signals that can neither be blocked, ignored nor handled
+    pub fn is_unblockable(self) -> bool {
+        matches!(self, Signal::SIGKILL | Signal::SIGSTOP)
+    }
+
+    /// What happens if the signal is delivered without a handler installed
+    pub fn default_action(self) -> DefaultAction {
+        match self {
+            // continuing happens when the signal is sent
+            Signal::SIGCHLD | Signal::SIGCONT => DefaultAction::Ignore,
+            Signal::SIGSTOP => DefaultAction::Stop,
+            _ => DefaultAction::Terminate,
+        }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SignalAction {
+    Default,
+    Ignore,
+    Handler(fn(Signal)),
+}
+
+/// Set of signals, bit n represents signal n
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SignalSet(u64);
+
+impl SignalSet {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn contains(&self, signal: Signal) -> bool {
+        self.0 & (1 << signal.number()) != 0
+    }
+
+    pub fn insert(&mut self, signal: Signal) {
+        self.0 |= 1 << signal.number();
+    }
+
+    pub fn remove(&mut self, signal: Signal) {
+        self.0 &= !(1 << signal.number());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Lowest signal in the set that is not in `blocked`
+    fn first_unblocked(&self, blocked: SignalSet) -> Option<Signal> {
+        let deliverable = self.0 & !blocked.0;
+        (1..=MAX_SIGNAL as u64)
+            .filter(|n| deliverable & (1 << n) != 0)
+            .find_map(|n| Signal::try_from(n).ok())
+    }
+}
+
+/// Per process signal state
+#[derive(Debug, Clone)]
+pub struct SignalState {
+    pending: SignalSet,
+    blocked: SignalSet,
+    actions: [SignalAction; MAX_SIGNAL + 1],
+    /// Stopped by `SIGSTOP` and not continued yet
+    stopped: bool,
+}
+
+impl SignalState {
+    pub const fn new() -> Self {
+        Self {
+            pending: SignalSet::empty(),
+            blocked: SignalSet::empty(),
+            actions: [SignalAction::Default; MAX_SIGNAL + 1],
+            stopped: false,
+        }
+    }
+
+    pub fn pending(&self) -> SignalSet {
+        self.pending
+    }
+
+    pub fn blocked(&self) -> SignalSet {
+        self.blocked
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    fn action(&self, signal: Signal) -> SignalAction {
+        self.actions[signal.number() as usize]
+    }
+
+    /// Removes the next deliverable signal from the pending set
+    fn take_deliverable(&mut self) -> Option<(Signal, SignalAction)> {
+        let signal = self.pending.first_unblocked(self.blocked)?;
+        self.pending.remove(signal);
+        Some((signal, self.action(signal)))
+    }
+}
+
+impl Default for SignalState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    NoSuchProcess,
+    /// The signal can't be blocked or handled
+    InvalidSignal,
+}
+
+impl From<SignalError> for Errno {
+    fn from(error: SignalError) -> Self {
+        match error {
+            SignalError::NoSuchProcess => Errno::ESRCH,
+            SignalError::InvalidSignal => Errno::EINVAL,
+        }
+    }
+}
+
+/// Sends `signal` to the process `pid`
+pub fn kill(pid: Pid, signal: Signal) -> Result<(), SignalError> {
+    if pid == Pid::KERNEL {
+        return Err(SignalError::NoSuchProcess);
+    }
+
+    with_process_table(|table| {
+        let process = table.get_mut(pid).ok_or(SignalError::NoSuchProcess)?;
+        if let super::ProcessState::Zombie { .. } = process.state() {
+            return Ok(());
+        }
+        let signals = &mut process.signals;
+        match signal {
+            Signal::SIGCONT => {
+                signals.stopped = false;
+                signals.pending.remove(Signal::SIGSTOP);
+            }
+            Signal::SIGSTOP => signals.pending.remove(Signal::SIGCONT),
+            _ => (),
+        }
+        signals.pending.insert(signal);
+        Ok(())
+    })?;
+
+    if matches!(signal, Signal::SIGCONT | Signal::SIGKILL) {
+        CONTINUED.wake_all();
+    }
+    Ok(())
+}
+
+/// Installs `action` for `signal` in the current process and returns the
+/// previous one
+pub fn set_action(signal: Signal, action: SignalAction) -> Result<SignalAction, SignalError> {
+    if signal.is_unblockable() {
+        return Err(SignalError::InvalidSignal);
+    }
+
+    let pid = super::current();
+    Ok(with_process_table(|table| {
+        let signals = &mut table.get_mut(pid).unwrap().signals;
+        core::mem::replace(&mut signals.actions[signal.number() as usize], action)
+    }))
+}
+
+/// Blocks or unblocks `signal` for the current process
+pub fn set_blocked(signal: Signal, blocked: bool) -> Result<(), SignalError> {
+    if signal.is_unblockable() {
+        return Err(SignalError::InvalidSignal);
+    }
+
+    let pid = super::current();
+    with_process_table(|table| {
+        let signals = &mut table.get_mut(pid).unwrap().signals;
+        if blocked {
+            signals.blocked.insert(signal);
+        } else {
+            signals.blocked.remove(signal);
+        }
+    });
+    Ok(())
+}
+
+/// Delivers all pending, unblocked signals of the current process. Does not
+/// return if a signal terminates the process.
+pub fn deliver_pending() {
+    let pid = super::current();
+    if pid == Pid::KERNEL {
+        return;
+    }
+
+    while let Some((signal, action)) =
+        with_process_table(|table| table.get_mut(pid).unwrap().signals.take_deliverable())
+    {
+        match (action, signal.default_action()) {
+            (SignalAction::Ignore, _) | (SignalAction::Default, DefaultAction::Ignore) => (),
+            (SignalAction::Default, DefaultAction::Terminate) => {
+                super::exit(128 + signal.number() as i32)
+            }
+            (SignalAction::Default, DefaultAction::Stop) => stop(pid),
+            (SignalAction::Handler(handler), _) => {
+                // unhandleable signals never have a handler installed
+                handler(signal)
+            }
+        }
+    }
+}
+
+/// Waits until the process `pid` is continued or has `SIGKILL` pending
+fn stop(pid: Pid) {
+    with_process_table(|table| table.get_mut(pid).unwrap().signals.stopped = true);
+    CONTINUED.wait_until(|| {
+        with_process_table(|table| {
+            let signals = &table.get(pid).unwrap().signals;
+            !signals.stopped || signals.pending.contains(Signal::SIGKILL)
+        })
+    });
+}
+
+/// `kill(pid, signal)` system call
+pub fn sys_kill(pid: u64, signal: u64) -> SyscallResult {
+    let signal = Signal::try_from(signal)?;
+    kill(Pid::from_u64(pid), signal)?;
+    Ok(0)
+}
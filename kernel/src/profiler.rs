@@ -0,0 +1,153 @@
+//! Sampling profiler.
+//!
+//! While the profiler runs, every timer interrupt records the interrupted RIP
+//! together with up to [`STACK_DEPTH`] return addresses of the interrupted
+//! call stack. Samples go into a ring of the last [`MAX_SAMPLES`], there is
+//! only one since the kernel only uses the boot CPU. There are no timer
+//! interrupts while idling, so idle time hardly shows up.
+//!
+//! [`folded`] aggregates the samples into the folded stack format of
+//! `flamegraph.pl` and inferno, one `outer;...;inner count` line per distinct
+//! stack.
+extern crate alloc;
+use crate::backtrace::{self, Frames};
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    interrupts::{without_interrupts, ExceptionStackFrame},
+    memory::Address,
+    mutex::Mutex,
+    PrivilegeLevel,
+};
+
+pub const MAX_SAMPLES: usize = 1024;
+/// Return addresses recorded per sample in addition to the RIP
+pub const STACK_DEPTH: usize = 8;
+
+#[derive(Clone, Copy)]
+struct Sample {
+    /// Innermost first, starting with the RIP
+    addresses: [u64; STACK_DEPTH + 1],
+    len: usize,
+}
+
+impl Sample {
+    const EMPTY: Self = Self {
+        addresses: [0; STACK_DEPTH + 1],
+        len: 0,
+    };
+
+    fn addresses(&self) -> &[u64] {
+        &self.addresses[..self.len]
+    }
+}
+
+struct Samples {
+    samples: [Sample; MAX_SAMPLES],
+    /// Samples taken since the last reset, the next one is stored at this
+    /// modulo [`MAX_SAMPLES`]
+    taken: usize,
+}
+
+impl Samples {
+    fn stored(&self) -> &[Sample] {
+        &self.samples[..self.taken.min(MAX_SAMPLES)]
+    }
+}
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// only the timer interrupt writes, readers lock with interrupts disabled
+static SAMPLES: Mutex<Samples> = Mutex::new(Samples {
+    samples: [Sample::EMPTY; MAX_SAMPLES],
+    taken: 0,
+});
+
+pub fn start() {
+    RUNNING.store(true, Ordering::SeqCst);
+}
+
+pub fn stop() {
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Drops all samples
+pub fn reset() {
+    without_interrupts(|| SAMPLES.lock().taken = 0);
+}
+
+/// Amount of samples currently stored
+pub fn sample_count() -> usize {
+    without_interrupts(|| SAMPLES.lock().stored().len())
+}
+
+/// Called by the timer interrupt handlers
+#[inline(never)]
+pub fn sample(frame: &ExceptionStackFrame) {
+    if !is_running() {
+        return;
+    }
+    let Some(mut samples) = SAMPLES.try_lock() else {
+        return;
+    };
+
+    let mut sample = Sample::EMPTY;
+    sample.addresses[0] = frame.instruction_pointer().as_u64();
+    sample.len = 1;
+    if frame.privilege_level() == PrivilegeLevel::Ring0 {
+        // neither the interrupt handler nor its wrapper touch rbp, so the
+        // frame of the handler links to the one of the interrupted code
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        // skip the return addresses into the handler and into the wrapper
+        let frames = unsafe { Frames::from_rbp(rbp) }.skip(2).take(STACK_DEPTH);
+        for address in frames {
+            sample.addresses[sample.len] = address;
+            sample.len += 1;
+        }
+    }
+
+    let index = samples.taken % MAX_SAMPLES;
+    samples.samples[index] = sample;
+    samples.taken += 1;
+}
+
+/// Name of the function containing `address`, its address if it is unknown
+fn function_name(address: u64) -> String {
+    match backtrace::symbolize(address) {
+        Some((symbol, _)) => String::from(symbol.name),
+        None => format!("{:#x}", address),
+    }
+}
+
+/// The stored samples aggregated by stack, outermost function first
+pub fn folded() -> Vec<(String, usize)> {
+    let stacks: Vec<Vec<u64>> = without_interrupts(|| {
+        SAMPLES
+            .lock()
+            .stored()
+            .iter()
+            .map(|s| s.addresses().to_vec())
+            .collect()
+    });
+
+    let mut counts = BTreeMap::new();
+    for stack in stacks {
+        // return addresses point behind the call, which might already be the
+        // next function
+        let names: Vec<String> = stack
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, address)| function_name(if i == 0 { *address } else { address - 1 }))
+            .collect();
+        *counts.entry(names.join(";")).or_insert(0) += 1;
+    }
+    counts.into_iter().collect()
+}
@@ -0,0 +1,169 @@
+//! Crash dumps that survive a reboot.
+//!
+//! On panic the message, the register state and the backtrace are written to
+//! the pstore partition of the boot disk, see `api::pstore`. The next boot
+//! logs and clears the dump in [`init`], the host can read it from the disk
+//! image with `bootloader::pstore::read_crash_dump`.
+extern crate alloc;
+use crate::{
+    ata::{AtaError, PRIMARY_MASTER, SECTOR_SIZE},
+    backtrace, error, warn,
+};
+use alloc::vec;
+use api::pstore::{self, HEADER_SIZE, PARTITION_TYPE};
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    panic::PanicInfo,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+use x86_64::register::{Cr0, Cr2, Cr3, Cr4};
+
+const PARTITION_TABLE_OFFSET: usize = 446;
+const PARTITION_ENTRY_SIZE: usize = 16;
+
+/// First sector of the partition, 0 if there is none
+static PARTITION_START: AtomicU32 = AtomicU32::new(0);
+/// Set once a dump is being written, a panic while writing is ignored
+static WRITING: AtomicBool = AtomicBool::new(false);
+/// The heap might be broken when panicking
+static mut DUMP: [u8; pstore::SIZE] = [0; pstore::SIZE];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PstoreError {
+    NoPartition,
+    Ata(AtaError),
+}
+
+impl From<AtaError> for PstoreError {
+    fn from(error: AtaError) -> Self {
+        PstoreError::Ata(error)
+    }
+}
+
+/// Finds the pstore partition and reports the dump of the previous boot
+pub fn init() -> Result<(), PstoreError> {
+    let mut drive = PRIMARY_MASTER.lock();
+    drive.identify()?;
+
+    let mut mbr = [0; SECTOR_SIZE];
+    drive.read(0, &mut mbr)?;
+    let start = mbr[PARTITION_TABLE_OFFSET..]
+        .chunks_exact(PARTITION_ENTRY_SIZE)
+        .take(4)
+        .find(|entry| entry[4] == PARTITION_TYPE)
+        .map(|entry| u32::from_le_bytes(entry[8..12].try_into().unwrap()))
+        .ok_or(PstoreError::NoPartition)?;
+
+    let mut data = vec![0; pstore::SIZE];
+    drive.read(start, &mut data)?;
+    if let Ok(dump) = pstore::parse(&data) {
+        warn!("Crash dump of the previous boot:");
+        for line in dump.lines() {
+            warn!("{}", line);
+        }
+        // only report it once
+        drive.write(start, &[0; SECTOR_SIZE])?;
+    }
+
+    PARTITION_START.store(start, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Writes a dump of the panic to the pstore partition
+pub fn save_panic(info: &PanicInfo) {
+    let start = PARTITION_START.load(Ordering::SeqCst);
+    if start == 0 || WRITING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    // don't wait for a transfer the panicking code might have started
+    let Some(mut drive) = PRIMARY_MASTER.try_lock() else {
+        return;
+    };
+
+    let data = unsafe { &mut *addr_of_mut!(DUMP) };
+    let mut writer = DumpWriter {
+        buffer: &mut data[HEADER_SIZE..],
+        len: 0,
+    };
+    // a full buffer truncates the dump
+    let _ = write_dump(&mut writer, info);
+    let len = writer.len;
+    pstore::write_header(data, len);
+
+    let sectors = (HEADER_SIZE + len).div_ceil(SECTOR_SIZE);
+    match drive.write(start, &data[..sectors * SECTOR_SIZE]) {
+        Ok(()) => error!("Crash dump written to disk"),
+        Err(error) => error!("Failed to write crash dump: {:?}", error),
+    }
+}
+
+fn write_dump(writer: &mut DumpWriter, info: &PanicInfo) -> fmt::Result {
+    let (rsp, rbp, rflags): (u64, u64, u64);
+    unsafe {
+        asm!(
+            "mov {}, rsp",
+            "mov {}, rbp",
+            "pushfq",
+            "pop {}",
+            out(reg) rsp,
+            out(reg) rbp,
+            out(reg) rflags,
+        );
+    }
+
+    writeln!(writer, "Kernel PANIC: {}", info)?;
+    writeln!(
+        writer,
+        "rsp: {:#018x} rbp: {:#018x} rflags: {:#x}",
+        rsp, rbp, rflags
+    )?;
+    writeln!(
+        writer,
+        "cr0: {:#x} cr2: {:#x} cr3: {:#x} cr4: {:#x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        Cr3::read_raw(),
+        Cr4::read_raw()
+    )?;
+
+    writeln!(writer, "Backtrace:")?;
+    for (i, address) in backtrace::frames().enumerate() {
+        match backtrace::symbolize(address - 1) {
+            Some((symbol, offset)) => writeln!(
+                writer,
+                "{:>4}: {:#018x} {}+{:#x}",
+                i,
+                address,
+                symbol.name,
+                offset + 1
+            )?,
+            None => writeln!(writer, "{:>4}: {:#018x} <unknown>", i, address)?,
+        }
+    }
+    Ok(())
+}
+
+struct DumpWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for DumpWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let free = self.buffer.len() - self.len;
+        // cut at a character boundary to keep the dump valid UTF-8
+        let mut count = s.len().min(free);
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+        self.buffer[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+
+        if count < s.len() {
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! Kernel random number generator.
+//!
+//! Random numbers come from `rdrand` if the CPU supports it. Otherwise, or if
+//! the hardware generator is exhausted, they are taken from a ChaCha20 based
+//! generator seeded from an entropy pool. The pool collects TSC jitter during
+//! [`init`], the arrival times of interrupts and `rdseed` output if available.
+//!
+//! The generator replaces its key with fresh key stream after every request
+//! (fast key erasure), so earlier output can't be reconstructed from its state.
+//! Entropy collected since the previous request is mixed into the key first.
+//!
+//! https://blog.cr.yp.to/20170723-random.html
+pub mod chacha;
+
+use crate::info;
+use chacha::{ChaCha20, BLOCK_SIZE, KEY_SIZE, NONCE_SIZE};
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+use x86_64::{
+    cpuid::Features,
+    instructions::{rdrand, rdseed, rdtsc},
+    interrupts::without_interrupts,
+    mutex::Mutex,
+};
+
+const POOL_WORDS: usize = 8;
+/// Amount of timing samples taken during initialization
+const JITTER_SAMPLES: usize = 512;
+/// Retries before the hardware generator is considered exhausted, as
+/// recommended by Intel
+const RDRAND_RETRIES: usize = 10;
+/// Bytes generated per lock acquisition, bounds the time spent with
+/// interrupts disabled
+const CHUNK_SIZE: usize = 16 * BLOCK_SIZE;
+
+static POOL: [AtomicU64; POOL_WORDS] = [const { AtomicU64::new(0) }; POOL_WORDS];
+static POOL_POSITION: AtomicUsize = AtomicUsize::new(0);
+static RDRAND: AtomicBool = AtomicBool::new(false);
+static GENERATOR: Mutex<Generator> = Mutex::new(Generator { key: [0; KEY_SIZE] });
+
+struct Generator {
+    key: [u8; KEY_SIZE],
+}
+
+impl Generator {
+    /// Mixes the entropy collected in the pool into the key
+    fn reseed(&mut self) {
+        let mut seed = [0u8; POOL_WORDS * 8];
+        for (word, bytes) in POOL.iter().zip(seed.chunks_exact_mut(8)) {
+            bytes.copy_from_slice(&word.swap(0, Ordering::Relaxed).to_le_bytes());
+        }
+
+        let mut key = self.key;
+        for (k, s) in key.iter_mut().zip(&seed[..KEY_SIZE]) {
+            *k ^= s;
+        }
+        let nonce: [u8; NONCE_SIZE] = seed[KEY_SIZE..KEY_SIZE + NONCE_SIZE].try_into().unwrap();
+        let block = ChaCha20::new(&key, &nonce).block(0);
+        self.key.copy_from_slice(&block[..KEY_SIZE]);
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        self.reseed();
+
+        let cipher = ChaCha20::new(&self.key, &[0; NONCE_SIZE]);
+        // block 0 becomes the next key, the rest is output
+        self.key.copy_from_slice(&cipher.block(0)[..KEY_SIZE]);
+        for (counter, chunk) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+            let block = cipher.block(counter as u32 + 1);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+    }
+}
+
+/// Mixes `value` into the entropy pool. Lock free, so it can be called from
+/// interrupt handlers.
+pub fn add_entropy(value: u64) {
+    let position = POOL_POSITION.fetch_add(1, Ordering::Relaxed) % POOL_WORDS;
+    // spread the low bits, which carry most of the entropy of timestamps
+    let value = value.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let _ = POOL[position].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |word| {
+        Some(word.rotate_left(13) ^ value)
+    });
+}
+
+/// Seeds the generator. Requires the TSC.
+pub fn init() {
+    // the exact duration of the same work varies due to caches, pipelines
+    // and interrupts
+    for _ in 0..JITTER_SAMPLES {
+        let start = rdtsc();
+        for _ in 0..(start & 0xf) {
+            spin_loop();
+        }
+        add_entropy(rdtsc().wrapping_sub(start) ^ start);
+    }
+
+    let features = Features::detect();
+    if features.contains(Features::RDSEED) {
+        for _ in 0..POOL_WORDS {
+            if let Some(seed) = rdseed() {
+                add_entropy(seed);
+            }
+        }
+    }
+    without_interrupts(|| GENERATOR.lock().reseed());
+
+    let rdrand = features.contains(Features::RDRAND);
+    RDRAND.store(rdrand, Ordering::SeqCst);
+    info!(
+        "Random numbers from {}",
+        if rdrand { "rdrand" } else { "chacha20" }
+    );
+}
+
+fn hardware_random() -> Option<u64> {
+    if !RDRAND.load(Ordering::Relaxed) {
+        return None;
+    }
+    (0..RDRAND_RETRIES).find_map(|_| rdrand())
+}
+
+/// Fills `buf` with cryptographically secure random bytes
+pub fn random_bytes(buf: &mut [u8]) {
+    let mut filled = 0;
+    for chunk in buf.chunks_mut(8) {
+        match hardware_random() {
+            Some(value) => chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]),
+            None => break,
+        }
+        filled += chunk.len();
+    }
+
+    for chunk in buf[filled..].chunks_mut(CHUNK_SIZE) {
+        without_interrupts(|| GENERATOR.lock().fill(chunk));
+    }
+}
+
+pub fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    random_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
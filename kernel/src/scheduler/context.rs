@@ -0,0 +1,117 @@
+//! Architecture specific part of the scheduler: saving and restoring the
+//! execution context of a thread.
+//!
+//! A context switch only saves the callee-saved registers and RFLAGS on the
+//! stack of the thread that is switched away from and stores its stack pointer.
+//! All other registers are either caller-saved (and therefore already saved by
+//! the compiler) or, if the switch happens inside of an interrupt handler,
+//! saved by the interrupt handler wrapper.
+//!
+//! Stack layout of a switched out thread:
+//!
+//! | return address | rbp | rbx | r12 | r13 | r14 | r15 | rflags | <- saved rsp
+use core::arch::global_asm;
+use x86_64::{
+    interrupts,
+    memory::{Address, VirtualAddress},
+};
+
+/// RFLAGS of a new thread: reserved bit 1 set, interrupts disabled. Interrupts
+/// get enabled once the thread started.
+const INITIAL_RFLAGS: u64 = 0x2;
+
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "mov [rdi], rsp",
+    "mov rsp, [rsi]",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    // first return address of a new thread. The entry function was placed in r12
+    ".global thread_trampoline",
+    "thread_trampoline:",
+    "mov rdi, r12",
+    "jmp {}",
+    sym thread_start,
+);
+
+extern "C" {
+    fn switch_context(old: *mut Context, new: *const Context);
+    fn thread_trampoline();
+}
+
+/// Saved execution context of a thread that is currently not running
+#[derive(Debug)]
+#[repr(C)]
+pub struct Context {
+    rsp: u64,
+}
+
+impl Context {
+    /// Context of a thread that is currently running. Filled in on the first
+    /// switch away from it.
+    pub const fn empty() -> Self {
+        Self { rsp: 0 }
+    }
+
+    /// Prepares the stack at `stack_top` so that switching to the context
+    /// starts executing `entry`
+    pub fn new(stack_top: VirtualAddress, entry: fn()) -> Self {
+        let frame = [
+            INITIAL_RFLAGS,
+            0,                     // r15
+            0,                     // r14
+            0,                     // r13
+            entry as usize as u64, // r12
+            0,                     // rbx
+            0,                     // rbp
+            thread_trampoline as unsafe extern "C" fn() as usize as u64,
+            // keeps the stack aligned like after a call instruction once
+            // the trampoline got "returned" to
+            0,
+        ];
+
+        let stack_top = stack_top.align_down(16u64);
+        let rsp = stack_top - (frame.len() * 8) as u64;
+        unsafe {
+            rsp.as_mut_ptr::<[u64; 9]>().write(frame);
+        }
+
+        Self { rsp: rsp.as_u64() }
+    }
+}
+
+/// Saves the current context into `old` and resumes `new`.
+///
+/// # Safety
+///
+/// Interrupts need to be disabled and both contexts must stay valid until
+/// the switch happened. `new` must have been created with [`Context::new`] or
+/// saved by a previous switch.
+pub unsafe fn switch(old: *mut Context, new: *const Context) {
+    debug_assert!(!interrupts::are_enabled());
+    switch_context(old, new);
+}
+
+extern "C" fn thread_start(entry: usize) -> ! {
+    // threads are always switched to with interrupts disabled
+    unsafe { interrupts::enable() };
+
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+    entry();
+
+    super::exit();
+}
@@ -0,0 +1,171 @@
+//! Idling the CPU.
+//!
+//! The idle thread calls [`enter`] whenever no other thread is ready. Right
+//! before idling the clock event device is reprogrammed for the next event of
+//! the scheduler, the time until then is how long the CPU is expected to
+//! idle. The governor picks the deepest idle state whose target residency
+//! fits into it.
+//!
+//! If the CPU supports `mwait` with interrupts as break events its C-states
+//! are used, otherwise `hlt`. `idle=halt` on the command line forces `hlt`.
+//! Entries and time spent per state are counted for the boot CPU, the only
+//! one the kernel uses.
+extern crate alloc;
+use crate::{cmdline, info, time};
+use alloc::vec::Vec;
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::{
+    cpuid::MonitorMwait,
+    instructions::{monitor, mwait},
+    interrupts::{self, without_interrupts},
+    memory::VirtualAddress,
+    mutex::Mutex,
+};
+
+/// C1 to C7
+pub const MAX_STATES: usize = 7;
+
+const NAMES: [&str; MAX_STATES] = ["C1", "C2", "C3", "C4", "C5", "C6", "C7"];
+/// Target residencies of C1 to C7 in µs. The CPU doesn't report exit
+/// latencies, so these are conservative guesses.
+const TARGET_RESIDENCY: [u64; MAX_STATES] = [0, 20, 100, 400, 1000, 2000, 5000];
+/// Break on interrupts even if they are disabled
+const MWAIT_INTERRUPT_BREAK: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleMethod {
+    Halt,
+    Mwait,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IdleState {
+    pub name: &'static str,
+    /// `mwait` hint, unused when halting
+    hint: u32,
+    /// Shortest idle period the state is worth entering for
+    pub target_residency: Duration,
+    pub entries: u64,
+    /// Time spent in the state
+    pub residency: Duration,
+}
+
+impl IdleState {
+    const fn new(name: &'static str, hint: u32, target_residency: Duration) -> Self {
+        Self {
+            name,
+            hint,
+            target_residency,
+            entries: 0,
+            residency: Duration::ZERO,
+        }
+    }
+}
+
+struct Idle {
+    method: IdleMethod,
+    /// Ordered from shallowest to deepest
+    states: Vec<IdleState>,
+}
+
+// locked with interrupts disabled, never while waiting
+static IDLE: Mutex<Idle> = Mutex::new(Idle {
+    method: IdleMethod::Halt,
+    states: Vec::new(),
+});
+
+/// Monitored by `mwait`, [`kick`] writes it when a thread becomes ready
+#[repr(align(64))]
+struct WakeupLine(AtomicU64);
+
+static WAKEUP: WakeupLine = WakeupLine(AtomicU64::new(0));
+
+/// Selects the idle method and the available states
+pub fn init() {
+    let mwait =
+        MonitorMwait::read().filter(|m| m.interrupt_break && cmdline::get("idle") != Some("halt"));
+
+    let mut states = Vec::new();
+    if let Some(mwait) = mwait {
+        // substates[0] is C0, which isn't an idle state
+        for (i, substates) in mwait.substates.iter().skip(1).enumerate() {
+            if *substates > 0 {
+                let residency = Duration::from_micros(TARGET_RESIDENCY[i]);
+                states.push(IdleState::new(NAMES[i], (i as u32) << 4, residency));
+            }
+        }
+    }
+    let method = match states.is_empty() {
+        true => {
+            states.push(IdleState::new("HLT", 0, Duration::ZERO));
+            IdleMethod::Halt
+        }
+        false => IdleMethod::Mwait,
+    };
+
+    info!("Idling with {:?}, {} idle states", method, states.len());
+    without_interrupts(|| *IDLE.lock() = Idle { method, states });
+}
+
+pub fn method() -> IdleMethod {
+    without_interrupts(|| IDLE.lock().method)
+}
+
+/// The idle states with their counters, shallowest first
+pub fn states() -> Vec<IdleState> {
+    without_interrupts(|| IDLE.lock().states.clone())
+}
+
+/// Ends an `mwait` on the monitored line
+pub fn kick() {
+    WAKEUP.0.fetch_add(1, Ordering::Release);
+}
+
+/// Idles until the next interrupt or [`kick`]. Returns right away if a
+/// thread is ready.
+pub fn enter() {
+    // an interrupt waking a thread between the check and the wait would
+    // otherwise be missed until the next one
+    unsafe { interrupts::disable() };
+    let Some(next_event) = super::with_scheduler(|s| s.prepare_idle()) else {
+        unsafe { interrupts::enable() };
+        return;
+    };
+
+    let start = time::uptime();
+    let expected = next_event.saturating_sub(start);
+    let (method, index, hint) = {
+        let idle = IDLE.lock();
+        let index = idle
+            .states
+            .iter()
+            .rposition(|s| s.target_residency <= expected)
+            .unwrap_or(0);
+        (
+            idle.method,
+            index,
+            idle.states.get(index).map_or(0, |s| s.hint),
+        )
+    };
+
+    match method {
+        IdleMethod::Halt => unsafe { interrupts::enable_and_hlt() },
+        IdleMethod::Mwait => {
+            monitor(VirtualAddress::new(&WAKEUP as *const _ as u64));
+            mwait(hint, MWAIT_INTERRUPT_BREAK);
+            unsafe { interrupts::enable() };
+        }
+    }
+
+    // includes the interrupt that woke the CPU up
+    let residency = time::uptime().saturating_sub(start);
+    without_interrupts(|| {
+        if let Some(state) = IDLE.lock().states.get_mut(index) {
+            state.entries += 1;
+            state.residency += residency;
+        }
+    });
+}
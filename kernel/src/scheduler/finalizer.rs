@@ -0,0 +1,50 @@
+//! The finalizer thread reclaims resources of threads and processes that
+//! exited.
+//!
+//! An exiting thread can't free its own kernel stack since it is still running
+//! on it. Instead it is left to the finalizer, which runs once the thread
+//! switched away for good. Other cleanup work that should not happen in the
+//! context of the exiting thread can be deferred to the finalizer as well, as
+//! are RCU callbacks once their grace period completed.
+extern crate alloc;
+use crate::sync::{rcu, WaitQueue};
+use alloc::{boxed::Box, vec::Vec};
+use core::mem;
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+type Work = Box<dyn FnOnce() + Send>;
+
+static WORK_AVAILABLE: WaitQueue = WaitQueue::named("finalizer work");
+static DEFERRED: Mutex<Vec<Work>> = Mutex::new(Vec::new());
+
+/// Runs `work` in the context of the finalizer thread
+pub fn defer(work: Work) {
+    without_interrupts(|| DEFERRED.lock().push(work));
+    notify();
+}
+
+/// Wakes up the finalizer. Safe to call with interrupts disabled.
+pub fn notify() {
+    WORK_AVAILABLE.wake_one();
+}
+
+fn has_work() -> bool {
+    !DEFERRED.lock().is_empty()
+        || super::with_scheduler(|s| s.has_dead())
+        || rcu::has_ready_callbacks()
+}
+
+pub(super) fn finalizer_loop() {
+    loop {
+        WORK_AVAILABLE.wait_until(has_work);
+
+        super::reap();
+
+        let work = without_interrupts(|| mem::take(&mut *DEFERRED.lock()));
+        for work in work {
+            work();
+        }
+
+        rcu::run_callbacks();
+    }
+}
@@ -0,0 +1,115 @@
+//! Architecture specific part of the scheduler: saving and restoring the
+//! x87, SSE and AVX registers of a thread.
+//!
+//! The kernel itself is compiled without floating point support, so the
+//! registers always belong to the thread that was running last. They are
+//! switched eagerly on every context switch, with `xsave` if the CPU supports
+//! it and `fxsave` otherwise. Switching lazily by trapping the first use via
+//! CR0.TS would require tracking which thread the registers belong to across
+//! thread exits.
+use crate::info;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{
+    cpuid::Features,
+    instructions::{cpuid, fninit, fxrstor, fxsave, ldmxcsr, xrstor, xsave},
+    mutex::Mutex,
+    register::{Cr0, Cr0Flags, Cr4, Cr4Flags, Xcr0, Xcr0Flags},
+};
+
+/// Size of the save area, enough for the x87, SSE and AVX components
+const STATE_SIZE: usize = 1024;
+/// Leaf reporting the size of the xsave area for the components in XCR0
+const XSAVE_LEAF: u32 = 0xd;
+/// All exceptions masked, round to nearest
+pub const DEFAULT_MXCSR: u32 = 0x1f80;
+
+static XSAVE: AtomicBool = AtomicBool::new(false);
+
+/// State every new thread starts with, saved right after initialization
+static INITIAL_STATE: Mutex<FpuState> = Mutex::new(FpuState::zeroed());
+
+/// Saved x87, SSE and AVX registers of a thread
+#[repr(C, align(64))]
+#[derive(Clone)]
+pub struct FpuState([u8; STATE_SIZE]);
+
+impl FpuState {
+    const fn zeroed() -> Self {
+        Self([0; STATE_SIZE])
+    }
+
+    /// State of a thread that hasn't used the FPU yet
+    pub fn initial() -> Self {
+        INITIAL_STATE.lock().clone()
+    }
+
+    /// Saves the registers of the CPU
+    pub fn save(&mut self) {
+        let area = self.0.as_mut_ptr();
+        unsafe {
+            if XSAVE.load(Ordering::Relaxed) {
+                xsave(area, Xcr0::read().bits());
+            } else {
+                fxsave(area);
+            }
+        }
+    }
+
+    /// Loads the saved registers into the CPU
+    pub fn restore(&self) {
+        let area = self.0.as_ptr();
+        unsafe {
+            if XSAVE.load(Ordering::Relaxed) {
+                xrstor(area, Xcr0::read().bits());
+            } else {
+                fxrstor(area);
+            }
+        }
+    }
+}
+
+/// Enables the FPU, SSE and, if supported, AVX with `xsave`. Has to be
+/// called before the first thread is created.
+pub fn init() {
+    let features = Features::detect();
+    assert!(
+        features.contains(Features::FPU | Features::FXSR | Features::SSE),
+        "CPU doesn't support SSE"
+    );
+
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+
+    if features.contains(Features::XSAVE) {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE)) };
+
+        let mut components = Xcr0Flags::X87 | Xcr0Flags::SSE;
+        if features.contains(Features::AVX) {
+            components |= Xcr0Flags::AVX;
+        }
+        unsafe { Xcr0::write(components) };
+
+        // ebx is the size required for the components enabled in XCR0
+        let size = cpuid(XSAVE_LEAF, 0).ebx as usize;
+        assert!(size <= STATE_SIZE, "xsave area of {} bytes too large", size);
+        XSAVE.store(true, Ordering::SeqCst);
+    }
+
+    fninit();
+    unsafe { ldmxcsr(DEFAULT_MXCSR) };
+    INITIAL_STATE.lock().save();
+
+    info!(
+        "Saving FPU state with {}",
+        if XSAVE.load(Ordering::SeqCst) {
+            "xsave"
+        } else {
+            "fxsave"
+        }
+    );
+}
@@ -0,0 +1,607 @@
+//! This module implements preemptive scheduling of kernel threads.
+//!
+//! The scheduler is split into three parts:
+//! - [`policy`]: decides which thread runs next. Pure bookkeeping on thread ids.
+//! - [`context`] and [`fpu`]: architecture specific saving / restoring of
+//!   thread state.
+//! - [`Scheduler`]: glues both together, owns the threads and their stacks.
+//!
+//! Time slices are accounted in scheduler ticks. If the policy decides that
+//! the running thread used up its time slice, or a more important thread
+//! became ready, the timer interrupt handler switches to the next thread.
+//!
+//! Without a clock event device every periodic timer interrupt is a tick.
+//! Otherwise the clock event device is programmed for the next tick or the
+//! next expiring timer, whichever comes first. While the idle thread runs
+//! there are no ticks at all, the CPU only wakes up for the next timer. How
+//! the CPU idles is up to [`cpuidle`].
+//!
+//! The thread the kernel booted on is registered as a normal thread. A
+//! separate idle thread, which is never put into a run queue, runs if no other
+//! thread is ready.
+//!
+//! Exited threads are cleaned up by the [`finalizer`] thread.
+//!
+//! Scheduling latencies, context switches and run queue lengths are collected
+//! in [`stats`].
+//!
+//! Sleeping threads are blocked and armed in a [`TimerQueue`] which wakes them
+//! up from the timer interrupt once their deadline passed.
+//!
+//! The scheduler lock is only ever taken with interrupts disabled, else the
+//! timer interrupt could try to schedule while the lock is held.
+extern crate alloc;
+#[cfg(feature = "unmap-on-free")]
+use crate::memory::freed::{self, FreedRange, Owner};
+use crate::{
+    allocator::{CacheBox, KmemCache, ObjectCache},
+    interrupts::{watchdog, TIMER_FREQUENCY},
+    memory::MemoryError,
+    sync::rcu,
+    time, trace_event,
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::{
+    interrupts,
+    mutex::{InterruptSafeMutex, Mutex},
+};
+
+pub mod context;
+pub mod cpuidle;
+pub mod finalizer;
+pub mod fpu;
+pub mod policy;
+pub mod stats;
+pub mod thread;
+pub mod timer;
+
+use context::Context;
+pub use policy::MultilevelPolicy;
+use stats::SchedulerStats;
+use thread::KernelStack;
+pub use thread::{Thread, ThreadId, ThreadPriority, ThreadState, KERNEL_STACK_SIZE};
+pub use timer::TimerQueue;
+
+/// Time between two scheduler ticks
+pub const TICK_PERIOD: Duration = Duration::from_nanos(1_000_000_000 / TIMER_FREQUENCY as u64);
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+/// Id of the running thread, readable without locking the scheduler
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+static THREAD_CACHE: ObjectCache<Thread> = InterruptSafeMutex::new(KmemCache::new());
+
+pub struct Scheduler {
+    /// Not stored inline so that saved contexts don't move while a switch is
+    /// in progress
+    threads: BTreeMap<ThreadId, CacheBox<Thread>>,
+    policy: MultilevelPolicy,
+    timers: TimerQueue,
+    current: ThreadId,
+    idle: ThreadId,
+    /// Exited threads whose stacks still need to be freed
+    dead: Vec<ThreadId>,
+    ticks: u64,
+    /// Uptime the next tick is due at if ticks are generated by the clock
+    /// event device
+    next_tick: Duration,
+    /// Uptime the current thread was switched to at
+    switched_at: Duration,
+    stats: SchedulerStats,
+}
+
+impl Scheduler {
+    /// Creates a scheduler with the currently running context as current
+    /// thread. `idle` runs whenever no other thread is ready.
+    pub fn new(idle: CacheBox<Thread>) -> Self {
+        let mut scheduler = Self {
+            threads: BTreeMap::new(),
+            policy: MultilevelPolicy::new(),
+            timers: TimerQueue::new(),
+            current: ThreadId::BOOT,
+            idle: idle.id(),
+            dead: Vec::new(),
+            ticks: 0,
+            next_tick: Duration::ZERO,
+            switched_at: Duration::ZERO,
+            stats: SchedulerStats::new(),
+        };
+
+        scheduler.threads.insert(idle.id(), idle);
+        scheduler.add(CacheBox::new(&THREAD_CACHE, Thread::boot()));
+        // the boot thread is already running
+        assert_eq!(scheduler.policy.pick_next(), Some(ThreadId::BOOT));
+
+        scheduler
+    }
+
+    pub fn current(&self) -> ThreadId {
+        self.current
+    }
+
+    pub fn idle(&self) -> ThreadId {
+        self.idle
+    }
+
+    /// Amount of scheduler ticks since the scheduler was initialized
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn policy(&mut self) -> &mut MultilevelPolicy {
+        &mut self.policy
+    }
+
+    pub fn thread(&self, id: ThreadId) -> Option<&Thread> {
+        self.threads.get(&id).map(|t| &**t)
+    }
+
+    pub fn threads(&self) -> impl Iterator<Item = &Thread> {
+        self.threads.values().map(|t| &**t)
+    }
+
+    pub fn stats(&self) -> &SchedulerStats {
+        &self.stats
+    }
+
+    /// Time thread `id` spent running, including the current time slice
+    pub fn cpu_time(&self, id: ThreadId) -> Option<Duration> {
+        let thread = self.threads.get(&id)?;
+        let running = match id == self.current {
+            true => time::uptime().saturating_sub(self.switched_at),
+            false => Duration::ZERO,
+        };
+        Some(thread.cpu_time() + running)
+    }
+
+    pub fn add(&mut self, mut thread: CacheBox<Thread>) -> ThreadId {
+        let id = thread.id();
+        if thread.state() == ThreadState::Ready {
+            thread.stats_mut().runnable(time::uptime());
+        }
+        self.policy.add(id, thread.priority());
+        self.threads.insert(id, thread);
+        id
+    }
+
+    pub fn set_priority(&mut self, id: ThreadId, priority: ThreadPriority) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            thread.set_priority(priority);
+            self.policy.set_priority(id, priority);
+        }
+    }
+
+    /// Accounts a scheduler tick at uptime `now`. Returns whether the current
+    /// thread should be preempted.
+    pub fn tick(&mut self, now: Duration) -> bool {
+        self.ticks += 1;
+        self.next_tick = now + TICK_PERIOD;
+        self.expire_timers(now);
+
+        let ready = self.policy.ready_count() as u64;
+        self.stats.queued += ready;
+        self.stats.max_queued = self.stats.max_queued.max(ready);
+
+        let current = (self.current != self.idle).then_some(self.current);
+        if let Some(current) = current {
+            watchdog::check_runaway(current, ready > 0);
+        }
+        self.policy.tick(current)
+    }
+
+    /// Handles an interrupt of the clock event device at uptime `now`, which
+    /// is a tick if one is due. Returns whether the current thread should be
+    /// preempted.
+    pub fn clock_event(&mut self, now: Duration) -> bool {
+        if now >= self.next_tick {
+            return self.tick(now);
+        }
+
+        self.expire_timers(now);
+        self.current == self.idle && self.policy.ready_count() > 0
+    }
+
+    fn expire_timers(&mut self, now: Duration) {
+        let mut expired = Vec::new();
+        self.timers.advance(now, |id| expired.push(id));
+        for id in expired {
+            self.unblock(id, false);
+        }
+    }
+
+    /// Uptime the clock event device has to fire at next, None if nothing
+    /// is pending
+    pub fn next_event(&self) -> Option<Duration> {
+        // no ticks are needed while idling
+        let busy = self.current != self.idle || self.policy.ready_count() > 0;
+        let tick = busy.then_some(self.next_tick);
+        tick.into_iter().chain(self.timers.next_deadline()).min()
+    }
+
+    /// Reprograms the clock event device right before the CPU idles. Returns
+    /// the uptime of the next event, [`Duration::MAX`] if nothing is pending,
+    /// or None if a thread is ready and the CPU shouldn't idle.
+    pub fn prepare_idle(&self) -> Option<Duration> {
+        if self.policy.ready_count() > 0 {
+            return None;
+        }
+        if time::clock_event().is_none() {
+            // the periodic timer interrupt wakes the CPU up on every tick
+            return Some(time::uptime() + TICK_PERIOD);
+        }
+        self.program_clock_event();
+        Some(self.next_event().unwrap_or(Duration::MAX))
+    }
+
+    /// Programs the clock event device for the next event
+    fn program_clock_event(&self) {
+        let Some(event) = time::clock_event() else {
+            return;
+        };
+
+        match self.next_event() {
+            Some(deadline) => event.program(deadline.saturating_sub(time::uptime())),
+            None => event.stop(),
+        }
+    }
+
+    /// Marks the current thread as blocked on `reason`. It won't be
+    /// scheduled until [`wake`](Scheduler::wake) is called for it.
+    pub fn block_current(&mut self, reason: &'static str) {
+        assert!(self.current != self.idle, "Idle thread can't block");
+        if let Some(thread) = self.threads.get_mut(&self.current) {
+            thread.block(reason);
+        }
+        self.policy.block(self.current);
+    }
+
+    /// Blocks the current thread until uptime `deadline`
+    pub fn sleep_current(&mut self, deadline: Duration) {
+        self.timers.insert(deadline, self.current);
+        self.block_current("sleep");
+    }
+
+    /// Makes a blocked thread runnable again. Threads that waited for an
+    /// event get a priority boost.
+    pub fn wake(&mut self, id: ThreadId) {
+        trace_event!(sched, "wake {}", id);
+        self.unblock(id, true);
+    }
+
+    pub fn exit_current(&mut self) {
+        assert!(self.current != self.idle, "Idle thread can't exit");
+        self.set_state(self.current, ThreadState::Exited);
+        self.policy.remove(self.current);
+        self.timers.cancel(self.current);
+        self.dead.push(self.current);
+    }
+
+    fn unblock(&mut self, id: ThreadId, boost: bool) {
+        match self.threads.get_mut(&id) {
+            Some(thread) if thread.state() == ThreadState::Blocked => {
+                thread.set_state(ThreadState::Ready);
+                thread.stats_mut().runnable(time::uptime());
+            }
+            _ => return,
+        }
+        self.policy.wake(id, boost);
+        cpuidle::kick();
+    }
+
+    pub fn has_dead(&self) -> bool {
+        self.dead.iter().any(|id| *id != self.current)
+    }
+
+    /// Removes all exited threads except the current one
+    pub fn take_dead(&mut self) -> Vec<CacheBox<Thread>> {
+        let current = self.current;
+        let mut dead = Vec::new();
+        self.dead.retain(|id| {
+            if *id == current {
+                return true;
+            }
+            dead.extend(self.threads.remove(id));
+            false
+        });
+        dead
+    }
+
+    /// Picks the next thread and marks it running.
+    ///
+    /// Returns the contexts to switch between, or None if the current thread
+    /// keeps running.
+    pub fn schedule(&mut self) -> Option<(*mut Context, *const Context)> {
+        let now = time::uptime();
+        let previous = self.current;
+        let previous_thread = self.threads.get_mut(&previous).unwrap();
+        let preempted = previous_thread.state() == ThreadState::Running;
+        if preempted {
+            previous_thread.set_state(ThreadState::Ready);
+            if previous != self.idle {
+                previous_thread.stats_mut().runnable(now);
+                self.policy.requeue(previous);
+            }
+        }
+
+        let next = self.policy.pick_next().unwrap_or(self.idle);
+        self.set_state(next, ThreadState::Running);
+        if previous == self.idle && next != self.idle {
+            // the tick stopped while idling
+            self.next_tick = time::uptime() + TICK_PERIOD;
+        }
+        self.current = next;
+        CURRENT.store(next.as_u64(), Ordering::SeqCst);
+        self.program_clock_event();
+
+        if next == previous {
+            self.threads.get_mut(&next).unwrap().stats_mut().continued();
+            return None;
+        }
+        watchdog::switched();
+        trace_event!(sched, "switch {} -> {}", previous, next);
+
+        let next_thread = self.threads.get_mut(&next).unwrap();
+        if let Some(latency) = next_thread.stats_mut().running(now) {
+            self.stats.latency.record(latency);
+        }
+        self.stats.context_switches += 1;
+
+        let ran = now.saturating_sub(self.switched_at);
+        self.switched_at = now;
+
+        // nothing between here and the context switch touches the FPU
+        let previous_thread = self.threads.get_mut(&previous).unwrap();
+        previous_thread.add_cpu_time(ran);
+        match preempted {
+            true => previous_thread.stats_mut().involuntary_switches += 1,
+            false => previous_thread.stats_mut().voluntary_switches += 1,
+        }
+        if let Some(stack) = previous_thread.stack() {
+            assert!(
+                stack.canary_intact(),
+                "Kernel stack overflow in thread {}",
+                previous
+            );
+        }
+        if previous_thread.state() != ThreadState::Exited {
+            previous_thread.fpu_state_mut().save();
+        }
+        self.threads.get(&next).unwrap().fpu_state().restore();
+
+        let old = self.threads.get_mut(&previous).unwrap().context_mut() as *mut Context;
+        let new = self.threads.get_mut(&next).unwrap().context_mut() as *const Context;
+
+        Some((old, new))
+    }
+
+    fn set_state(&mut self, id: ThreadId, state: ThreadState) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            thread.set_state(state);
+        }
+    }
+}
+
+pub fn init() -> Result<(), MemoryError> {
+    cpuidle::init();
+    let stack = KernelStack::allocate(KERNEL_STACK_SIZE)?;
+    let mut idle = CacheBox::new(
+        &THREAD_CACHE,
+        Thread::new(idle_loop, ThreadPriority::Low, stack),
+    );
+    idle.set_name("idle");
+
+    interrupts::without_interrupts(|| *SCHEDULER.lock() = Some(Scheduler::new(idle)));
+    #[cfg(feature = "lock-debug")]
+    x86_64::mutex::debug::set_owner_id(|| CURRENT.load(Ordering::SeqCst));
+
+    spawn(finalizer::finalizer_loop, ThreadPriority::Normal)?;
+    Ok(())
+}
+
+fn idle_loop() {
+    loop {
+        watchdog::touch();
+        cpuidle::enter();
+        // without ticks nobody else switches away once a thread was woken
+        yield_now();
+    }
+}
+
+/// Runs `f` with exclusive access to the scheduler and interrupts disabled
+pub fn with_scheduler<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Scheduler) -> R,
+{
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("Scheduler not initialized");
+        f(scheduler)
+    })
+}
+
+/// Switches to the next thread if the policy asks for it.
+///
+/// Must be called with interrupts disabled.
+fn reschedule() {
+    let switch = SCHEDULER.lock().as_mut().and_then(|s| s.schedule());
+
+    if let Some((old, new)) = switch {
+        unsafe { context::switch(old, new) };
+    }
+}
+
+/// Spawns a new kernel thread executing `entry`
+pub fn spawn(entry: fn(), priority: ThreadPriority) -> Result<ThreadId, MemoryError> {
+    let thread = create_thread(entry, priority)?;
+    Ok(start(thread))
+}
+
+/// Creates a thread without starting it. Useful if the thread id needs to be
+/// known before the thread runs.
+pub fn create_thread(
+    entry: fn(),
+    priority: ThreadPriority,
+) -> Result<CacheBox<Thread>, MemoryError> {
+    let stack = KernelStack::allocate(KERNEL_STACK_SIZE)?;
+    Ok(CacheBox::new(
+        &THREAD_CACHE,
+        Thread::new(entry, priority, stack),
+    ))
+}
+
+/// Makes a thread created by [`create_thread`] runnable
+pub fn start(thread: CacheBox<Thread>) -> ThreadId {
+    with_scheduler(|s| s.add(thread))
+}
+
+/// Frees the stacks of exited threads
+fn reap() {
+    let dead = with_scheduler(|s| s.take_dead());
+    for mut thread in dead {
+        if let Some(stack) = thread.take_stack() {
+            #[cfg(feature = "unmap-on-free")]
+            freed::record(FreedRange {
+                start: stack.bottom(),
+                size: stack.size(),
+                owner: Owner::Thread(thread.id()),
+            });
+            stack.free();
+        }
+    }
+}
+
+pub fn current() -> ThreadId {
+    ThreadId::from_u64(CURRENT.load(Ordering::SeqCst))
+}
+
+/// The idle thread, None before the scheduler is initialized
+pub fn idle() -> Option<ThreadId> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().as_ref().map(|s| s.idle()))
+}
+
+/// Renames thread `id`
+pub fn set_name(id: ThreadId, name: &str) {
+    with_scheduler(|s| {
+        if let Some(thread) = s.threads.get_mut(&id) {
+            thread.set_name(name);
+        }
+    })
+}
+
+pub fn set_priority(id: ThreadId, priority: ThreadPriority) {
+    with_scheduler(|s| s.set_priority(id, priority))
+}
+
+/// Gives up the rest of the time slice
+pub fn yield_now() {
+    interrupts::without_interrupts(reschedule);
+}
+
+/// Blocks the current thread until someone calls [`wake`] for it. `reason`
+/// is shown as what the thread waits for.
+pub fn block_current(reason: &'static str) {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_mut()
+            .expect("Scheduler not initialized")
+            .block_current(reason);
+        reschedule();
+    });
+}
+
+pub fn wake(id: ThreadId) {
+    with_scheduler(|s| s.wake(id))
+}
+
+/// Blocks the current thread for at least `duration`
+pub fn sleep(duration: Duration) {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_mut()
+            .expect("Scheduler not initialized")
+            .sleep_current(time::uptime() + duration);
+        reschedule();
+    });
+}
+
+/// Highest amount of bytes ever used on the kernel stack of `id`, None for
+/// the boot thread and unknown threads
+pub fn stack_usage(id: ThreadId) -> Option<u64> {
+    with_scheduler(|s| s.thread(id).and_then(|t| t.stack()).map(|s| s.usage()))
+}
+
+/// Amount of scheduler ticks since the scheduler was initialized
+pub fn ticks() -> u64 {
+    with_scheduler(|s| s.ticks())
+}
+
+/// Terminates the current thread
+pub fn exit() -> ! {
+    unsafe { interrupts::disable() };
+
+    SCHEDULER
+        .lock()
+        .as_mut()
+        .expect("Scheduler not initialized")
+        .exit_current();
+    finalizer::notify();
+    reschedule();
+
+    unreachable!("Exited thread was scheduled again");
+}
+
+/// Called by the periodic timer interrupt handler on every tick
+pub fn tick() {
+    let now = time::uptime();
+    // the lock is never held with interrupts enabled, but the tick might
+    // arrive before the scheduler is initialized
+    let preempt = match SCHEDULER.try_lock() {
+        Some(mut guard) => guard.as_mut().is_some_and(|s| s.tick(now)),
+        None => false,
+    };
+    rcu::tick();
+
+    if preempt {
+        reschedule();
+    }
+}
+
+/// Called by the interrupt handler of the clock event device
+pub fn clock_event() {
+    let now = time::uptime();
+    let (ticked, preempt) = match SCHEDULER.try_lock() {
+        Some(mut guard) => match guard.as_mut() {
+            Some(scheduler) => {
+                let ticks = scheduler.ticks();
+                let preempt = scheduler.clock_event(now);
+                if !preempt {
+                    scheduler.program_clock_event();
+                }
+                (scheduler.ticks() != ticks, preempt)
+            }
+            None => (false, false),
+        },
+        None => (false, false),
+    };
+
+    if ticked {
+        time::tick();
+        rcu::tick();
+    }
+    if preempt {
+        reschedule();
+    }
+}
+
+/// Starts generating ticks with the clock event device
+pub fn start_clock_events() {
+    with_scheduler(|s| {
+        s.next_tick = time::uptime() + TICK_PERIOD;
+        s.program_clock_event();
+    })
+}
@@ -0,0 +1,215 @@
+//! Scheduling policy of the kernel scheduler.
+//!
+//! The policy only decides which thread runs next and when the running thread
+//! has to be preempted. It knows nothing about stacks or context switches,
+//! threads are only referred to by their [`ThreadId`]. This makes it possible
+//! to exercise the policy without actually running any threads.
+//!
+//! The policy is a multilevel feedback queue:
+//! - There is one run queue per [`ThreadPriority`]. The highest non empty
+//!   queue is always served first, round robin within a queue.
+//! - Every priority has its own time slice. Once a thread used up its slice,
+//!   or a thread with a higher priority becomes ready, it gets preempted.
+//! - Threads that wake up after blocking (e.g. waiting for I/O) are boosted by
+//!   one priority level. The boost decays again once the thread used up a
+//!   full time slice, so CPU bound threads fall back to their base priority.
+//! - Threads that waited for `starvation_threshold` ticks in a run queue
+//!   are promoted by one level (aging) so low priority threads can't starve.
+extern crate alloc;
+use super::thread::{ThreadId, ThreadPriority};
+use alloc::collections::{BTreeMap, VecDeque};
+
+/// Ticks a thread may wait in a run queue before it gets promoted
+pub const DEFAULT_STARVATION_THRESHOLD: u64 = 100;
+
+/// Per thread scheduling state
+#[derive(Debug, Clone, Copy)]
+struct Entity {
+    base: ThreadPriority,
+    effective: ThreadPriority,
+    /// Ticks left of the current time slice
+    remaining: u64,
+    /// Ticks spent waiting in a run queue since the last promotion
+    waited: u64,
+    queued: bool,
+}
+
+pub struct MultilevelPolicy {
+    entities: BTreeMap<ThreadId, Entity>,
+    queues: [VecDeque<ThreadId>; ThreadPriority::LEVELS],
+    starvation_threshold: u64,
+}
+
+impl MultilevelPolicy {
+    pub const fn new() -> Self {
+        Self {
+            entities: BTreeMap::new(),
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            starvation_threshold: DEFAULT_STARVATION_THRESHOLD,
+        }
+    }
+
+    pub fn set_starvation_threshold(&mut self, ticks: u64) {
+        self.starvation_threshold = ticks;
+    }
+
+    /// Registers a new runnable thread
+    pub fn add(&mut self, id: ThreadId, priority: ThreadPriority) {
+        self.entities.insert(
+            id,
+            Entity {
+                base: priority,
+                effective: priority,
+                remaining: priority.time_slice(),
+                waited: 0,
+                queued: false,
+            },
+        );
+        self.enqueue(id);
+    }
+
+    /// Forgets about a thread, e.g. because it exited
+    pub fn remove(&mut self, id: ThreadId) {
+        self.dequeue(id);
+        self.entities.remove(&id);
+    }
+
+    /// Changes the base priority of a thread. Any boost is dropped.
+    pub fn set_priority(&mut self, id: ThreadId, priority: ThreadPriority) {
+        let queued = self.dequeue(id);
+        if let Some(entity) = self.entities.get_mut(&id) {
+            entity.base = priority;
+            entity.effective = priority;
+        }
+        if queued {
+            self.enqueue(id);
+        }
+    }
+
+    /// Priority the thread is currently scheduled with, including boosts
+    pub fn effective_priority(&self, id: ThreadId) -> Option<ThreadPriority> {
+        self.entities.get(&id).map(|e| e.effective)
+    }
+
+    /// Amount of threads waiting in the run queues
+    pub fn ready_count(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+
+    /// Removes the thread that should run next from its run queue and starts
+    /// a new time slice for it
+    pub fn pick_next(&mut self) -> Option<ThreadId> {
+        let id = self.queues.iter_mut().rev().find_map(|q| q.pop_front())?;
+
+        let entity = self.entities.get_mut(&id).unwrap();
+        entity.queued = false;
+        entity.waited = 0;
+        entity.remaining = entity.effective.time_slice();
+
+        Some(id)
+    }
+
+    /// Accounts a timer tick to `current`, which is None if the idle thread
+    /// is running. Returns whether `current` should be preempted.
+    pub fn tick(&mut self, current: Option<ThreadId>) -> bool {
+        self.age();
+
+        let current = match current.and_then(|id| self.entities.get_mut(&id)) {
+            Some(entity) => entity,
+            None => return self.ready_count() > 0,
+        };
+
+        current.remaining = current.remaining.saturating_sub(1);
+        let effective = current.effective;
+        current.remaining == 0 || self.higher_priority_ready(effective)
+    }
+
+    /// Puts a preempted or yielding thread back into its run queue
+    pub fn requeue(&mut self, id: ThreadId) {
+        if let Some(entity) = self.entities.get_mut(&id) {
+            // boosts only last for a single full time slice
+            if entity.remaining == 0 && entity.effective > entity.base {
+                entity.effective = entity.effective.lowered();
+            }
+            self.enqueue(id);
+        }
+    }
+
+    /// Takes the thread out of the run queues until it is woken up again
+    pub fn block(&mut self, id: ThreadId) {
+        self.dequeue(id);
+    }
+
+    /// Makes a blocked thread runnable again. If `boost` is set the thread gets
+    /// scheduled one level above its current priority.
+    pub fn wake(&mut self, id: ThreadId, boost: bool) {
+        let entity = match self.entities.get_mut(&id) {
+            Some(entity) if !entity.queued => entity,
+            _ => return,
+        };
+
+        if boost {
+            entity.effective = entity.effective.raised();
+            entity.remaining = entity.effective.time_slice();
+        }
+        self.enqueue(id);
+    }
+
+    fn higher_priority_ready(&self, priority: ThreadPriority) -> bool {
+        self.queues[priority.as_usize() + 1..]
+            .iter()
+            .any(|q| !q.is_empty())
+    }
+
+    fn enqueue(&mut self, id: ThreadId) {
+        let entity = self.entities.get_mut(&id).unwrap();
+        if !entity.queued {
+            entity.queued = true;
+            entity.waited = 0;
+            self.queues[entity.effective.as_usize()].push_back(id);
+        }
+    }
+
+    /// Returns whether the thread was queued
+    fn dequeue(&mut self, id: ThreadId) -> bool {
+        let entity = match self.entities.get_mut(&id) {
+            Some(entity) if entity.queued => entity,
+            _ => return false,
+        };
+
+        entity.queued = false;
+        self.queues[entity.effective.as_usize()].retain(|t| *t != id);
+        true
+    }
+
+    /// Promotes threads that waited too long in their run queue by one level.
+    ///
+    /// Levels are processed from the top so a promoted thread is only aged
+    /// once per tick.
+    fn age(&mut self) {
+        let threshold = self.starvation_threshold;
+        let entities = &mut self.entities;
+
+        for level in (0..ThreadPriority::LEVELS - 1).rev() {
+            let (lower, upper) = self.queues.split_at_mut(level + 1);
+            lower[level].retain(|id| {
+                let entity = entities.get_mut(id).unwrap();
+                entity.waited += 1;
+                if entity.waited < threshold {
+                    return true;
+                }
+
+                entity.waited = 0;
+                entity.effective = entity.effective.raised();
+                upper[0].push_back(*id);
+                false
+            });
+        }
+    }
+}
+
+impl Default for MultilevelPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
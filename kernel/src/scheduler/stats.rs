@@ -0,0 +1,167 @@
+//! Scheduling statistics.
+//!
+//! The scheduling latency of a thread is the time from becoming runnable,
+//! i.e. being created, woken up or preempted, until it runs again. Latencies
+//! are collected in [`Histogram`]s with power of two microsecond buckets, one
+//! per thread and one for all threads together.
+extern crate alloc;
+use alloc::format;
+use core::{fmt, time::Duration};
+
+/// Buckets of a [`Histogram`], the last one holds everything longer
+pub const BUCKETS: usize = 16;
+
+/// Distribution of durations. Bucket 0 counts durations below 1µs, bucket `i`
+/// those from 2^(i-1) up to 2^i µs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    total: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+
+    /// Bucket `duration` falls into
+    pub fn bucket(duration: Duration) -> usize {
+        let micros = duration.as_micros();
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+        bucket.min(BUCKETS - 1)
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        self.buckets[Self::bucket(duration)] += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+    }
+
+    pub fn buckets(&self) -> &[u64; BUCKETS] {
+        &self.buckets
+    }
+
+    /// Amount of recorded durations
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::ZERO,
+            count => self.total / count as u32,
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One line per non-empty bucket with its range in µs
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "count {} mean {}us max {}us",
+            self.count(),
+            self.mean().as_micros(),
+            self.max.as_micros()
+        )?;
+        for (i, count) in self.buckets.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            match i {
+                0 => writeln!(f, "{:>12}us {}", "<1", count)?,
+                // padding is ignored for format_args!, so the range is
+                // formatted first
+                i if i == BUCKETS - 1 => {
+                    writeln!(f, "{:>12}us {}", format!(">={}", 1u64 << (i - 1)), count)?
+                }
+                i => writeln!(
+                    f,
+                    "{:>12}us {}",
+                    format!("{}-{}", 1u64 << (i - 1), 1u64 << i),
+                    count
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Scheduling statistics of a thread
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadStats {
+    /// Uptime the thread became runnable at, None while it runs or is blocked
+    runnable_since: Option<Duration>,
+    pub latency: Histogram,
+    /// Switches away from the thread because it blocked or exited
+    pub voluntary_switches: u64,
+    /// Switches away from the thread while it could have kept running
+    pub involuntary_switches: u64,
+}
+
+impl ThreadStats {
+    pub const fn new() -> Self {
+        Self {
+            runnable_since: None,
+            latency: Histogram::new(),
+            voluntary_switches: 0,
+            involuntary_switches: 0,
+        }
+    }
+
+    /// The thread became runnable at uptime `now`
+    pub fn runnable(&mut self, now: Duration) {
+        self.runnable_since.get_or_insert(now);
+    }
+
+    /// The thread started running at uptime `now`, returns how long it waited
+    pub fn running(&mut self, now: Duration) -> Option<Duration> {
+        let latency = now.saturating_sub(self.runnable_since.take()?);
+        self.latency.record(latency);
+        Some(latency)
+    }
+
+    /// The thread kept running, it didn't actually wait
+    pub fn continued(&mut self) {
+        self.runnable_since = None;
+    }
+}
+
+/// Statistics of the whole scheduler
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SchedulerStats {
+    /// Latencies of all threads except the idle thread
+    pub latency: Histogram,
+    pub context_switches: u64,
+    /// Sum of the ready threads at every tick, divided by the ticks it is the
+    /// mean run queue length
+    pub queued: u64,
+    /// Most threads ready at a tick
+    pub max_queued: u64,
+}
+
+impl SchedulerStats {
+    pub const fn new() -> Self {
+        Self {
+            latency: Histogram::new(),
+            context_switches: 0,
+            queued: 0,
+            max_queued: 0,
+        }
+    }
+}
@@ -0,0 +1,357 @@
+extern crate alloc;
+use super::{context::Context, fpu::FpuState, stats::ThreadStats};
+use crate::{
+    backtrace,
+    memory::{self, MemoryError, VirtualMemoryObject},
+    random,
+};
+use alloc::{format, string::String};
+use core::{
+    fmt, slice,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::{memory::VirtualAddress, paging::PageTableEntryFlags};
+
+/// Size of the kernel stack of every spawned thread
+pub const KERNEL_STACK_SIZE: u64 = 16 * 1024;
+/// Written over new kernel stacks, words still containing it were never used
+const STACK_FILL_PATTERN: u64 = 0x57ac_57ac_57ac_57ac;
+/// Words at the bottom of every kernel stack holding its canary
+const CANARY_WORDS: usize = 8;
+
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// Id of the thread the kernel booted on
+    pub const BOOT: ThreadId = ThreadId(0);
+
+    fn new() -> Self {
+        Self(NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Refers to an existing thread by its raw id
+    pub const fn from_u64(id: u64) -> Self {
+        Self(id)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Puts the current thread to sleep for at least `ms` milliseconds. The
+/// thread is not scheduled until the timer interrupt wakes it up again.
+pub fn sleep_ms(ms: u64) {
+    super::sleep(Duration::from_millis(ms));
+}
+
+/// Puts the current thread to sleep for at least `us` microseconds. Without a
+/// clock event device the sleep is rounded up to the next tick.
+pub fn sleep_us(us: u64) {
+    super::sleep(Duration::from_micros(us));
+}
+
+/// Scheduling priority of a thread. Higher priorities are always served first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum ThreadPriority {
+    Low = 0,
+    Normal,
+    High,
+}
+
+impl ThreadPriority {
+    /// Amount of priority levels, one run queue per level
+    pub const LEVELS: usize = 3;
+
+    pub fn as_usize(self) -> usize {
+        self as usize
+    }
+
+    /// Time slice in timer ticks. Higher priorities get shorter slices since
+    /// they are meant for latency sensitive threads.
+    pub fn time_slice(self) -> u64 {
+        match self {
+            ThreadPriority::Low => 20,
+            ThreadPriority::Normal => 10,
+            ThreadPriority::High => 5,
+        }
+    }
+
+    /// Next higher priority, saturating at the highest one
+    pub fn raised(self) -> Self {
+        match self {
+            ThreadPriority::Low => ThreadPriority::Normal,
+            ThreadPriority::Normal | ThreadPriority::High => ThreadPriority::High,
+        }
+    }
+
+    /// Next lower priority, saturating at the lowest one
+    pub fn lowered(self) -> Self {
+        match self {
+            ThreadPriority::Low | ThreadPriority::Normal => ThreadPriority::Low,
+            ThreadPriority::High => ThreadPriority::Normal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// Waiting in a run queue
+    Ready,
+    Running,
+    /// Waiting for an event, not in any run queue
+    Blocked,
+    /// Finished, waiting for its resources to be freed
+    Exited,
+}
+
+/// Kernel stack of a thread, eagerly backed so that the context switch code
+/// never page faults.
+///
+/// Kernel stacks have no guard page. Instead the lowest words hold a random
+/// canary which the scheduler checks whenever the thread is switched away
+/// from. The rest is filled with a pattern to measure the stack usage.
+#[derive(Debug)]
+pub struct KernelStack {
+    bottom: VirtualAddress,
+    size: u64,
+    canary: u64,
+}
+
+impl KernelStack {
+    pub fn allocate(size: u64) -> Result<Self, MemoryError> {
+        let bottom = memory::with_memory_manager(|mm| {
+            mm.allocate(
+                size,
+                PageTableEntryFlags::PRESENT
+                    | PageTableEntryFlags::WRITABLE
+                    | PageTableEntryFlags::NO_EXECUTE,
+                VirtualMemoryObject::Anonymous,
+            )
+        })?;
+
+        let stack = Self {
+            bottom,
+            size,
+            canary: random::random_u64(),
+        };
+        let words = stack.words();
+        words.fill(STACK_FILL_PATTERN);
+        words[..CANARY_WORDS].fill(stack.canary);
+        Ok(stack)
+    }
+
+    fn words(&self) -> &mut [u64] {
+        unsafe { slice::from_raw_parts_mut(self.bottom.as_mut_ptr(), (self.size / 8) as usize) }
+    }
+
+    pub fn top(&self) -> VirtualAddress {
+        self.bottom + self.size
+    }
+
+    pub fn bottom(&self) -> VirtualAddress {
+        self.bottom
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether the canary at the bottom of the stack was not overwritten
+    pub fn canary_intact(&self) -> bool {
+        self.words()[..CANARY_WORDS]
+            .iter()
+            .all(|word| *word == self.canary)
+    }
+
+    /// Highest amount of bytes ever used, assuming the thread didn't write
+    /// the fill pattern itself
+    pub fn usage(&self) -> u64 {
+        if !self.canary_intact() {
+            return self.size;
+        }
+
+        let words = self.words();
+        let unused = words[CANARY_WORDS..]
+            .iter()
+            .take_while(|word| **word == STACK_FILL_PATTERN)
+            .count();
+        self.size - ((CANARY_WORDS + unused) * 8) as u64
+    }
+
+    pub fn free(self) {
+        memory::with_memory_manager(|mm| mm.munmap(self.bottom))
+            .expect("Failed to free kernel stack");
+    }
+}
+
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    priority: ThreadPriority,
+    state: ThreadState,
+    /// What a blocked thread waits for
+    blocked_on: Option<&'static str>,
+    context: Context,
+    fpu: FpuState,
+    /// None for the boot thread which runs on the stack set up by the bootloader
+    stack: Option<KernelStack>,
+    /// Time spent running, not including the current time slice
+    cpu_time: Duration,
+    stats: ThreadStats,
+}
+
+impl Thread {
+    /// Creates a thread that starts executing `entry` the first time it is
+    /// switched to. The thread is named after `entry`.
+    pub fn new(entry: fn(), priority: ThreadPriority, stack: KernelStack) -> Self {
+        let id = ThreadId::new();
+        let name = match backtrace::function_name(entry as usize as u64) {
+            Some(name) => String::from(name),
+            None => format!("thread-{}", id),
+        };
+        Self {
+            id,
+            name,
+            priority,
+            state: ThreadState::Ready,
+            blocked_on: None,
+            context: Context::new(stack.top(), entry),
+            fpu: FpuState::initial(),
+            stack: Some(stack),
+            cpu_time: Duration::ZERO,
+            stats: ThreadStats::new(),
+        }
+    }
+
+    /// Wraps the currently executing boot context
+    pub fn boot() -> Self {
+        Self {
+            id: ThreadId::BOOT,
+            name: String::from("boot"),
+            priority: ThreadPriority::Normal,
+            state: ThreadState::Running,
+            blocked_on: None,
+            context: Context::empty(),
+            // saved on the first switch away from it
+            fpu: FpuState::initial(),
+            stack: None,
+            cpu_time: Duration::ZERO,
+            stats: ThreadStats::new(),
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = String::from(name);
+    }
+
+    pub fn priority(&self) -> ThreadPriority {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: ThreadPriority) {
+        self.priority = priority;
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: ThreadState) {
+        self.state = state;
+        if state != ThreadState::Blocked {
+            self.blocked_on = None;
+        }
+    }
+
+    /// Blocks the thread until it is set ready again, `reason` names what it
+    /// waits for
+    pub fn block(&mut self, reason: &'static str) {
+        self.state = ThreadState::Blocked;
+        self.blocked_on = Some(reason);
+    }
+
+    pub fn blocked_on(&self) -> Option<&'static str> {
+        self.blocked_on
+    }
+
+    /// State as shown to users, e.g. `blocked (sleep)`
+    pub fn describe_state(&self) -> String {
+        match (self.state, self.blocked_on) {
+            (ThreadState::Ready, _) => String::from("ready"),
+            (ThreadState::Running, _) => String::from("running"),
+            (ThreadState::Blocked, Some(reason)) => format!("blocked ({})", reason),
+            (ThreadState::Blocked, None) => String::from("blocked"),
+            (ThreadState::Exited, _) => String::from("exited"),
+        }
+    }
+
+    pub fn context_mut(&mut self) -> &mut Context {
+        &mut self.context
+    }
+
+    pub fn fpu_state(&self) -> &FpuState {
+        &self.fpu
+    }
+
+    pub fn fpu_state_mut(&mut self) -> &mut FpuState {
+        &mut self.fpu
+    }
+
+    pub fn stack(&self) -> Option<&KernelStack> {
+        self.stack.as_ref()
+    }
+
+    pub fn take_stack(&mut self) -> Option<KernelStack> {
+        self.stack.take()
+    }
+
+    /// Time the thread ran until it was last switched away from, see
+    /// [`Scheduler::cpu_time`](super::Scheduler::cpu_time)
+    pub fn cpu_time(&self) -> Duration {
+        self.cpu_time
+    }
+
+    pub fn add_cpu_time(&mut self, time: Duration) {
+        self.cpu_time += time;
+    }
+
+    pub fn stats(&self) -> &ThreadStats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut ThreadStats {
+        &mut self.stats
+    }
+}
+
+impl fmt::Debug for Thread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Thread")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("priority", &self.priority)
+            .field("state", &self.state)
+            .field("blocked_on", &self.blocked_on)
+            .finish()
+    }
+}
@@ -0,0 +1,62 @@
+//! Timers used to wake up sleeping threads.
+//!
+//! Timers are kept sorted by their deadline, so the next one to expire is
+//! known without scanning all of them. The scheduler programs the clock event
+//! device for it, which makes sleeps independent of the scheduler tick.
+extern crate alloc;
+use super::thread::ThreadId;
+use alloc::collections::BTreeSet;
+use core::time::Duration;
+
+pub struct TimerQueue {
+    /// Deadlines are uptimes, the thread id makes equal deadlines unique
+    timers: BTreeSet<(Duration, ThreadId)>,
+}
+
+impl TimerQueue {
+    pub const fn new() -> Self {
+        Self {
+            timers: BTreeSet::new(),
+        }
+    }
+
+    /// Amount of armed timers
+    pub fn pending(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// Arms a timer that expires at uptime `deadline`
+    pub fn insert(&mut self, deadline: Duration, thread: ThreadId) {
+        self.timers.insert((deadline, thread));
+    }
+
+    /// Disarms all timers of `thread`
+    pub fn cancel(&mut self, thread: ThreadId) {
+        self.timers.retain(|(_, t)| *t != thread);
+    }
+
+    /// Deadline of the timer that expires next
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.timers.first().map(|(deadline, _)| *deadline)
+    }
+
+    /// Calls `expired` for every thread whose timer expired at uptime `now`
+    pub fn advance<F>(&mut self, now: Duration, mut expired: F)
+    where
+        F: FnMut(ThreadId),
+    {
+        while let Some(&(deadline, thread)) = self.timers.first() {
+            if deadline > now {
+                break;
+            }
+            self.timers.pop_first();
+            expired(thread);
+        }
+    }
+}
+
+impl Default for TimerQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,627 @@
+//! Minimal interactive kernel shell on COM1.
+//!
+//! Reads lines from the serial port, e.g. `qemu -serial stdio`, and runs
+//! simple inspection commands on them. Input is echoed since the terminal on
+//! the other side is expected to be in raw mode.
+extern crate alloc;
+use crate::{
+    allocator::{HEAP_SIZE, HEAP_START},
+    fs::vfs,
+    interrupts, log, memory,
+    net::{dns, http, icmp, Ipv4Address},
+    paging,
+    process::{
+        self,
+        signal::{self, Signal},
+        Pid, ProcessState,
+    },
+    profiler,
+    scheduler::{self, ThreadId, ThreadPriority},
+    time, tracepoint,
+};
+use alloc::{format, string::String, vec::Vec};
+use core::time::Duration;
+use x86_64::{
+    cpuid::CpuInfo,
+    instructions::hlt,
+    memory::{PageSize, Size4KiB, VirtualAddress},
+    paging::dump::dump_address_space,
+    port::Port,
+    print, println,
+};
+
+const PROMPT: &str = "> ";
+const MAX_LINE_LENGTH: usize = 128;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    run: fn(&[&str]),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help",
+        run: help,
+    },
+    Command {
+        name: "ps",
+        usage: "ps",
+        run: ps,
+    },
+    Command {
+        name: "threads",
+        usage: "threads",
+        run: threads,
+    },
+    Command {
+        name: "mem",
+        usage: "mem",
+        run: mem,
+    },
+    Command {
+        name: "regions",
+        usage: "regions",
+        run: regions,
+    },
+    Command {
+        name: "vmmap",
+        usage: "vmmap",
+        run: vmmap,
+    },
+    Command {
+        name: "translate",
+        usage: "translate <address>",
+        run: translate,
+    },
+    Command {
+        name: "ptcheck",
+        usage: "ptcheck",
+        run: ptcheck,
+    },
+    Command {
+        name: "gdt",
+        usage: "gdt",
+        run: gdt,
+    },
+    Command {
+        name: "idt",
+        usage: "idt",
+        run: idt,
+    },
+    Command {
+        name: "uptime",
+        usage: "uptime",
+        run: uptime,
+    },
+    Command {
+        name: "date",
+        usage: "date",
+        run: date,
+    },
+    Command {
+        name: "cpuinfo",
+        usage: "cpuinfo",
+        run: cpuinfo,
+    },
+    Command {
+        name: "kill",
+        usage: "kill <pid> [signal]",
+        run: kill,
+    },
+    Command {
+        name: "dmesg",
+        usage: "dmesg [-c | -w <path>]",
+        run: dmesg,
+    },
+    Command {
+        name: "log",
+        usage: "log level [target] [level | reset]",
+        run: log,
+    },
+    Command {
+        name: "profile",
+        usage: "profile <start | stop | dump | reset>",
+        run: profile,
+    },
+    Command {
+        name: "trace",
+        usage: "trace <list | enable <id | subsystem> | disable <id | subsystem> | dump | clear>",
+        run: trace,
+    },
+    Command {
+        name: "cat",
+        usage: "cat <path>",
+        run: cat,
+    },
+    Command {
+        name: "ping",
+        usage: "ping <address> [count]",
+        run: ping,
+    },
+    Command {
+        name: "resolve",
+        usage: "resolve <name>",
+        run: resolve,
+    },
+    Command {
+        name: "fetch",
+        usage: "fetch <url> <path>",
+        run: fetch,
+    },
+    Command {
+        name: "nameserver",
+        usage: "nameserver [address...]",
+        run: nameserver,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        run: reboot,
+    },
+];
+
+/// Starts the shell in its own kernel thread
+pub fn spawn() -> Result<ThreadId, memory::MemoryError> {
+    scheduler::spawn(shell_loop, ThreadPriority::Normal)
+}
+
+fn shell_loop() {
+    loop {
+        print!("{}", PROMPT);
+        let line = read_line();
+        run(&line);
+    }
+}
+
+/// Reads a line from the serial port, echoing the input and handling
+/// backspace
+fn read_line() -> String {
+    let mut line = String::new();
+    loop {
+        match interrupts::read_serial() {
+            b'\r' | b'\n' => {
+                println!();
+                return line;
+            }
+            BACKSPACE | DELETE => {
+                if line.pop().is_some() {
+                    print!("\x08 \x08");
+                }
+            }
+            byte @ 0x20..=0x7e if line.len() < MAX_LINE_LENGTH => {
+                line.push(byte as char);
+                print!("{}", byte as char);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn run(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some(name) = args.first() else {
+        return;
+    };
+
+    match COMMANDS.iter().find(|c| c.name == *name) {
+        Some(command) => (command.run)(&args[1..]),
+        None => println!("Unknown command: {}, try help", name),
+    }
+}
+
+fn help(_args: &[&str]) {
+    for command in COMMANDS {
+        println!("{}", command.usage);
+    }
+}
+
+fn ps(_args: &[&str]) {
+    let mut processes: Vec<_> = process::with_process_table(|table| {
+        table
+            .processes()
+            .map(|p| {
+                let name = String::from(p.name());
+                (p.pid(), p.parent(), p.state(), p.main_thread(), name)
+            })
+            .collect()
+    });
+    processes.sort_by_key(|p| p.0);
+
+    println!("PID  PPID  THREAD  NAME             STATE");
+    for (pid, parent, state, thread, name) in processes {
+        let thread = thread.map(|t| t.as_u64() as i64).unwrap_or(-1);
+        let state = match state {
+            ProcessState::Running => String::from("running"),
+            ProcessState::Zombie { exit_code } => format!("zombie ({})", exit_code),
+        };
+        println!(
+            "{:<4} {:<5} {:<7} {:<16} {}",
+            pid, parent, thread, name, state
+        );
+    }
+}
+
+fn threads(_args: &[&str]) {
+    let mut threads: Vec<_> = scheduler::with_scheduler(|s| {
+        s.threads()
+            .map(|t| {
+                let stack = t.stack().map(|s| (s.usage(), s.size()));
+                let name = String::from(t.name());
+                (t.id(), name, t.priority(), t.describe_state(), stack)
+            })
+            .collect()
+    });
+    threads.sort_by_key(|t| t.0);
+    let owners: Vec<Pid> =
+        process::with_process_table(|table| threads.iter().map(|t| table.owner(t.0)).collect());
+
+    println!("TID  PID  NAME             PRIORITY  STACK        STATE");
+    for ((id, name, priority, state, stack), owner) in threads.into_iter().zip(owners) {
+        let stack = match stack {
+            Some((used, size)) => format!("{}/{}", used, size),
+            None => String::from("-"),
+        };
+        println!(
+            "{:<4} {:<4} {:<16} {:<9?} {:<12} {}",
+            id, owner, name, priority, stack, state
+        );
+    }
+}
+
+fn mem(_args: &[&str]) {
+    let (stats, usage, region_count, mapped) = memory::with_memory_manager(|mm| {
+        let allocator = mm.frame_allocator();
+        let stats = allocator.stats();
+        let usage = allocator.region_usage().collect::<Vec<_>>();
+        (
+            stats,
+            usage,
+            mm.region_count(),
+            mm.regions().map(|r| r.size()).sum::<u64>(),
+        )
+    });
+    let kib = |frames: usize| frames as u64 * Size4KiB::SIZE / 1024;
+
+    println!(
+        "heap:    {:#x} - {:#x} ({} KiB)",
+        HEAP_START,
+        HEAP_START + HEAP_SIZE,
+        HEAP_SIZE / 1024
+    );
+    println!(
+        "frames:  {} total, {} free, {} allocated, {} reserved ({} KiB free)",
+        stats.total,
+        stats.free,
+        stats.allocated,
+        stats.reserved,
+        kib(stats.free)
+    );
+    println!(
+        "free:    {} ranges, largest {} frames ({} KiB)",
+        stats.free_ranges,
+        stats.largest_free,
+        kib(stats.largest_free)
+    );
+    for usage in usage {
+        println!(
+            "  {:#014x} - {:#014x} {:>8} / {:>8} frames free",
+            usage.region.start,
+            usage.region.start + usage.region.size,
+            usage.free,
+            usage.total
+        );
+    }
+    println!("regions: {} ({} KiB reserved)", region_count, mapped / 1024);
+}
+
+fn regions(_args: &[&str]) {
+    memory::with_memory_manager(|mm| {
+        for region in mm.regions() {
+            println!(
+                "{:#x} - {:#x} {:?} {:?}",
+                region.start(),
+                region.end(),
+                region.object(),
+                region.flags()
+            );
+        }
+    });
+}
+
+fn vmmap(_args: &[&str]) {
+    memory::with_memory_manager(|mm| dump_address_space(mm.page_table()));
+}
+
+fn translate(args: &[&str]) {
+    let [address] = args else {
+        println!("usage: translate <address>");
+        return;
+    };
+    let Ok(address) = u64::from_str_radix(address.trim_start_matches("0x"), 16) else {
+        println!("Invalid address");
+        return;
+    };
+
+    match memory::with_memory_manager(|mm| mm.translate(VirtualAddress::new(address))) {
+        Some((physical, flags)) => println!("{:#x} -> {:#x} {:?}", address, physical, flags),
+        None => println!("{:#x} is not mapped", address),
+    }
+}
+
+fn ptcheck(_args: &[&str]) {
+    let issues = memory::with_memory_manager(|mm| paging::verify(mm.page_table()));
+    for issue in &issues {
+        println!("{}", issue);
+    }
+    println!("{} issues", issues.len());
+}
+
+fn gdt(_args: &[&str]) {
+    x86_64::gdt::dump();
+}
+
+fn idt(_args: &[&str]) {
+    x86_64::idt::dump();
+}
+
+fn uptime(_args: &[&str]) {
+    let uptime = time::uptime();
+    println!(
+        "{}.{:03}s ({} ticks, clock source {})",
+        uptime.as_secs(),
+        uptime.subsec_millis(),
+        time::ticks(),
+        time::clock_source().name()
+    );
+}
+
+fn date(_args: &[&str]) {
+    println!("{} UTC", time::wall_clock());
+}
+
+fn cpuinfo(_args: &[&str]) {
+    let cpu = CpuInfo::read();
+    println!("{}", cpu);
+    for (name, _) in cpu.features.iter_names() {
+        print!("{} ", name.to_lowercase());
+    }
+    println!();
+}
+
+fn kill(args: &[&str]) {
+    let (pid, signal) = match args {
+        [pid] => (pid.parse(), Ok(Signal::SIGTERM)),
+        [pid, signal] => (
+            pid.parse(),
+            signal
+                .parse::<u64>()
+                .map_err(|_| ())
+                .and_then(|s| Signal::try_from(s).map_err(|_| ())),
+        ),
+        _ => {
+            println!("usage: kill <pid> [signal]");
+            return;
+        }
+    };
+
+    let (Ok(pid), Ok(signal)) = (pid, signal) else {
+        println!("Invalid pid or signal");
+        return;
+    };
+
+    if let Err(error) = signal::kill(Pid::from_u64(pid), signal) {
+        println!("kill: {:?}", error);
+    }
+}
+
+fn dmesg(args: &[&str]) {
+    let ring = &log::ring::RING;
+    match args {
+        [] => print!("{}", String::from_utf8_lossy(&ring.to_vec())),
+        ["-c"] => {
+            print!("{}", String::from_utf8_lossy(&ring.to_vec()));
+            ring.clear();
+        }
+        ["-w", path] => match ring.flush(path) {
+            Ok(written) => println!("Wrote {} bytes to {}", written, path),
+            Err(error) => println!("dmesg: {:?}", error),
+        },
+        _ => println!("usage: dmesg [-c | -w <path>]"),
+    }
+}
+
+fn log(args: &[&str]) {
+    match args {
+        ["level"] => {
+            println!("{}", log::level());
+            log::for_each_filter(|target, level| println!("{}: {}", target, level));
+        }
+        ["level", level] => match level.parse() {
+            Ok(level) => log::set_level(level),
+            Err(()) => println!("Invalid level {}", level),
+        },
+        ["level", target, "reset"] => {
+            if let Err(error) = log::set_target_level(target, None) {
+                println!("log: {:?}", error);
+            }
+        }
+        ["level", target, level] => match level.parse() {
+            Ok(level) => {
+                if let Err(error) = log::set_target_level(target, Some(level)) {
+                    println!("log: {:?}", error);
+                }
+            }
+            Err(()) => println!("Invalid level {}", level),
+        },
+        _ => println!("usage: log level [target] [level | reset]"),
+    }
+}
+
+fn profile(args: &[&str]) {
+    match args {
+        ["start"] => profiler::start(),
+        ["stop"] => profiler::stop(),
+        // folded stacks for flamegraph.pl or inferno-flamegraph
+        ["dump"] => {
+            for (stack, count) in profiler::folded() {
+                println!("{} {}", stack, count);
+            }
+        }
+        ["reset"] => profiler::reset(),
+        _ => println!("usage: profile <start | stop | dump | reset>"),
+    }
+}
+
+fn trace(args: &[&str]) {
+    match args {
+        ["list"] => {
+            for (id, tracepoint) in tracepoint::tracepoints().iter().enumerate() {
+                println!(
+                    "{:<4} {:<3} {:<8} {:?} ({}:{})",
+                    id,
+                    if tracepoint.is_enabled() { "on" } else { "off" },
+                    tracepoint.subsystem,
+                    tracepoint.format,
+                    tracepoint.file,
+                    tracepoint.line
+                );
+            }
+        }
+        [action @ ("enable" | "disable"), target] => {
+            let enabled = *action == "enable";
+            let tracepoints = tracepoint::tracepoints();
+            match target.parse::<usize>() {
+                Ok(id) => match tracepoints.get(id) {
+                    Some(tracepoint) => tracepoint.set_enabled(enabled),
+                    None => println!("No tracepoint {}", id),
+                },
+                Err(_) => {
+                    if tracepoint::set_subsystem_enabled(target, enabled) == 0 {
+                        println!("No tracepoints in {}", target);
+                    }
+                }
+            }
+        }
+        ["dump"] => print!("{}", tracepoint::dump()),
+        ["clear"] => tracepoint::clear(),
+        _ => println!(
+            "usage: trace <list | enable <id | subsystem> | disable <id | subsystem> | dump | clear>"
+        ),
+    }
+}
+
+fn cat(args: &[&str]) {
+    let [path] = args else {
+        println!("usage: cat <path>");
+        return;
+    };
+    let file = match vfs::open(path) {
+        Ok(file) => file,
+        Err(error) => {
+            println!("cat: {:?}", error);
+            return;
+        }
+    };
+
+    let mut buf = [0; 256];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => print!("{}", String::from_utf8_lossy(&buf[..len])),
+            Err(error) => {
+                println!("cat: {:?}", error);
+                break;
+            }
+        }
+    }
+}
+
+fn ping(args: &[&str]) {
+    let (address, count) = match args {
+        [address] => (address.parse(), Ok(4)),
+        [address, count] => (address.parse(), count.parse()),
+        _ => {
+            println!("usage: ping <address> [count]");
+            return;
+        }
+    };
+    let (Ok(address), Ok(count)) = (address, count) else {
+        println!("Invalid address or count");
+        return;
+    };
+
+    for sequence in 0..count {
+        if sequence > 0 {
+            scheduler::sleep(Duration::from_secs(1));
+        }
+        match icmp::ping(address, sequence, icmp::DEFAULT_TIMEOUT) {
+            Ok(rtt) => println!(
+                "Reply from {}: seq={} time={}.{:03} ms",
+                address,
+                sequence,
+                rtt.as_millis(),
+                rtt.as_micros() % 1000
+            ),
+            Err(error) => println!("seq={} {:?}", sequence, error),
+        }
+    }
+}
+
+fn resolve(args: &[&str]) {
+    let [name] = args else {
+        println!("usage: resolve <name>");
+        return;
+    };
+    match dns::resolve_blocking(name) {
+        Ok(addresses) => {
+            for address in addresses {
+                println!("{}", address);
+            }
+        }
+        Err(error) => println!("{:?}", error),
+    }
+}
+
+fn fetch(args: &[&str]) {
+    let [url, path] = args else {
+        println!("usage: fetch <url> <path>");
+        return;
+    };
+    match http::download(url, path) {
+        Ok(size) => println!("{}: {} bytes", path, size),
+        Err(error) => println!("{:?}", error),
+    }
+}
+
+fn nameserver(args: &[&str]) {
+    if args.is_empty() {
+        for server in dns::servers() {
+            println!("{}", server);
+        }
+        return;
+    }
+    let servers: Result<Vec<Ipv4Address>, _> = args.iter().map(|a| a.parse()).collect();
+    match servers {
+        Ok(servers) => {
+            dns::set_servers(&servers);
+            dns::flush_cache();
+        }
+        Err(_) => println!("Invalid address"),
+    }
+}
+
+fn reboot(_args: &[&str]) {
+    println!("Rebooting");
+    // pulse the reset line through the keyboard controller
+    let port: Port<u8> = Port::new(0x64);
+    port.write(0xfe);
+
+    loop {
+        hlt();
+    }
+}
@@ -0,0 +1,65 @@
+use super::WaitQueue;
+use crate::scheduler;
+use x86_64::{
+    interrupts::without_interrupts,
+    mutex::{Mutex, MutexGuard},
+};
+
+/// Condition variable used together with a [`Mutex`].
+///
+/// Waiting atomically releases the mutex and blocks the current thread. The
+/// mutex is reacquired before returning. As usual, spurious wake ups are
+/// possible, so the condition needs to be checked in a loop or
+/// [`wait_while`](Condvar::wait_while) should be used.
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::named("condvar"),
+        }
+    }
+
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex: &'a Mutex<T> = MutexGuard::mutex(&guard);
+
+        without_interrupts(|| {
+            self.waiters.enqueue_current();
+            drop(guard);
+            scheduler::block_current(self.waiters.name());
+        });
+
+        mutex.lock()
+    }
+
+    /// Blocks as long as `condition` returns true
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
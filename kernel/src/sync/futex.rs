@@ -0,0 +1,125 @@
+//! Fast user space mutex (futex) support.
+//!
+//! A futex is a 32 bit word in memory. Lock implementations only call into the
+//! kernel if there is contention: [`wait`] blocks the calling thread as long as
+//! the word still contains the expected value, [`wake`] wakes up threads
+//! waiting on the word.
+//!
+//! Wait queues are kept in a hash table keyed on the physical address of the
+//! word, so the same futex mapped at different virtual addresses (e.g. shared
+//! memory between processes) refers to the same queue.
+extern crate alloc;
+use super::WaitQueue;
+use crate::{
+    memory,
+    syscall::{Errno, SyscallResult},
+};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, Ordering};
+use hashmap::HashMap;
+use x86_64::{
+    interrupts::without_interrupts,
+    memory::{PhysicalAddress, VirtualAddress},
+    mutex::Mutex,
+};
+
+pub const FUTEX_WAIT: u64 = 0;
+pub const FUTEX_WAKE: u64 = 1;
+
+static FUTEXES: Mutex<HashMap<PhysicalAddress, Arc<WaitQueue>>> = Mutex::new(HashMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexError {
+    /// The address is not mapped or not 4 byte aligned
+    InvalidAddress,
+    /// The futex word did not contain the expected value
+    WouldBlock,
+}
+
+impl From<FutexError> for Errno {
+    fn from(error: FutexError) -> Self {
+        match error {
+            FutexError::InvalidAddress => Errno::EFAULT,
+            FutexError::WouldBlock => Errno::EAGAIN,
+        }
+    }
+}
+
+fn check_alignment(address: VirtualAddress) -> Result<(), FutexError> {
+    match address.is_aligned(4u64) {
+        true => Ok(()),
+        false => Err(FutexError::InvalidAddress),
+    }
+}
+
+fn resolve(address: VirtualAddress) -> Result<PhysicalAddress, FutexError> {
+    check_alignment(address)?;
+    memory::with_memory_manager(|mm| mm.translate(address))
+        .map(|(physical, _)| physical)
+        .ok_or(FutexError::InvalidAddress)
+}
+
+/// Blocks the current thread as long as the word at `address` contains
+/// `expected`
+pub fn wait(address: VirtualAddress, expected: u32) -> Result<(), FutexError> {
+    check_alignment(address)?;
+    let word = unsafe { &*address.as_ptr::<AtomicU32>() };
+    // touch the word first so a lazily backed page is populated
+    if word.load(Ordering::SeqCst) != expected {
+        return Err(FutexError::WouldBlock);
+    }
+
+    let key = resolve(address)?;
+
+    without_interrupts(|| {
+        // a wake up in between the check and blocking can't get lost since
+        // interrupts are disabled
+        if word.load(Ordering::SeqCst) != expected {
+            return Err(FutexError::WouldBlock);
+        }
+
+        let queue = FUTEXES
+            .lock()
+            .entry(key)
+            .or_insert_with(|| Arc::new(WaitQueue::named("futex")))
+            .clone();
+        queue.wait();
+        Ok(())
+    })
+}
+
+/// Wakes up at most `count` threads waiting on the word at `address`.
+/// Returns the amount of woken threads.
+pub fn wake(address: VirtualAddress, count: usize) -> Result<usize, FutexError> {
+    let key = resolve(address)?;
+
+    without_interrupts(|| {
+        let mut futexes = FUTEXES.lock();
+        let queue = match futexes.get(&key) {
+            Some(queue) => queue.clone(),
+            None => return Ok(0),
+        };
+
+        let mut woken = 0;
+        while woken < count && queue.wake_one() {
+            woken += 1;
+        }
+
+        if queue.is_empty() {
+            futexes.remove(&key);
+        }
+
+        Ok(woken)
+    })
+}
+
+/// `futex(address, op, value)` system call
+pub fn sys_futex(address: u64, op: u64, value: u64) -> SyscallResult {
+    let address = VirtualAddress::new(address);
+    match op {
+        FUTEX_WAIT => wait(address, value as u32).map(|_| 0),
+        FUTEX_WAKE => wake(address, value as usize).map(|woken| woken as u64),
+        _ => return Err(Errno::EINVAL),
+    }
+    .map_err(Errno::from)
+}
@@ -0,0 +1,16 @@
+//! Blocking synchronization primitives built on top of the scheduler.
+//!
+//! In contrast to the spinning [`Mutex`](x86_64::mutex::Mutex) these put the
+//! waiting thread to sleep until the event it is waiting for happened.
+pub mod condvar;
+pub mod futex;
+pub mod mutex;
+pub mod rcu;
+pub mod rwlock;
+pub mod wait_queue;
+
+pub use condvar::Condvar;
+pub use mutex::{BlockingMutex, BlockingMutexGuard};
+pub use rcu::{call_rcu, rcu_read_lock, synchronize_rcu, RcuCell, RcuReadGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use wait_queue::WaitQueue;
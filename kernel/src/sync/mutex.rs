@@ -0,0 +1,76 @@
+use super::WaitQueue;
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Mutex that blocks the current thread while another one holds it.
+///
+/// Only usable from thread context, interrupt handlers can't block. Waiters
+/// are woken one at a time on unlock, a woken thread may still lose the lock
+/// to one that didn't have to wait and goes back to sleep then.
+pub struct BlockingMutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+impl<T> BlockingMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::named("mutex"),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> BlockingMutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            self.waiters
+                .wait_until(|| !self.locked.load(Ordering::Relaxed));
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<BlockingMutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| BlockingMutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+unsafe impl<T: Send> Send for BlockingMutex<T> {}
+unsafe impl<T: Send> Sync for BlockingMutex<T> {}
+
+pub struct BlockingMutexGuard<'a, T> {
+    mutex: &'a BlockingMutex<T>,
+}
+
+impl<T> Deref for BlockingMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for BlockingMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for BlockingMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
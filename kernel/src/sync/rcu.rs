@@ -0,0 +1,181 @@
+//! Read-copy-update for read-mostly data.
+//!
+//! Readers enter a read side critical section with [`rcu_read_lock`] and never
+//! block writers. Writers publish a new version and retire the old one with
+//! [`call_rcu`] or wait for all readers with [`synchronize_rcu`].
+//!
+//! The scheduler tick reports a quiescent state for a CPU whenever no read
+//! side critical section is active on it. A reader that is preempted only
+//! delays the grace period, but read side critical sections must never block.
+extern crate alloc;
+use super::{BlockingMutex, WaitQueue};
+use crate::scheduler::finalizer;
+use ::rcu::GracePeriods;
+use alloc::boxed::Box;
+use core::{
+    marker::PhantomData,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+// the kernel only runs on the bootstrap processor
+const CPUS: usize = 1;
+
+/// Active read side critical sections per CPU
+static READERS: [AtomicUsize; CPUS] = [const { AtomicUsize::new(0) }; CPUS];
+/// Only locked with interrupts disabled since the tick updates it
+static GRACE_PERIODS: Mutex<GracePeriods<Callback>> = Mutex::new(GracePeriods::new(CPUS));
+static GRACE_PERIOD_COMPLETED: WaitQueue = WaitQueue::named("rcu grace period");
+
+fn current_cpu() -> usize {
+    0
+}
+
+/// Marks a read side critical section, which ends when the guard is dropped
+pub struct RcuReadGuard {
+    cpu: usize,
+    // must be dropped on the CPU that created it
+    _marker: PhantomData<*const ()>,
+}
+
+impl Drop for RcuReadGuard {
+    fn drop(&mut self) {
+        READERS[self.cpu].fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Enters a read side critical section. Critical sections can be nested.
+pub fn rcu_read_lock() -> RcuReadGuard {
+    let cpu = current_cpu();
+    READERS[cpu].fetch_add(1, Ordering::Acquire);
+    RcuReadGuard {
+        cpu,
+        _marker: PhantomData,
+    }
+}
+
+/// Runs `callback` in the finalizer thread once all read side critical
+/// sections that are active now ended
+pub fn call_rcu(callback: Callback) {
+    without_interrupts(|| GRACE_PERIODS.lock().defer(callback));
+}
+
+/// Blocks until all read side critical sections that are active now ended.
+/// Must not be called from within a read side critical section.
+pub fn synchronize_rcu() {
+    let target = without_interrupts(|| GRACE_PERIODS.lock().request());
+    GRACE_PERIOD_COMPLETED.wait_until(|| GRACE_PERIODS.lock().is_complete(target));
+}
+
+/// Called by the scheduler on every tick
+pub(crate) fn tick() {
+    let cpu = current_cpu();
+    if READERS[cpu].load(Ordering::Acquire) != 0 {
+        return;
+    }
+
+    let (completed, ready) = {
+        let mut grace_periods = GRACE_PERIODS.lock();
+        (
+            grace_periods.quiescent_state(cpu),
+            grace_periods.has_ready(),
+        )
+    };
+    if completed {
+        GRACE_PERIOD_COMPLETED.wake_all();
+    }
+    if ready {
+        finalizer::notify();
+    }
+}
+
+pub(crate) fn has_ready_callbacks() -> bool {
+    without_interrupts(|| GRACE_PERIODS.lock().has_ready())
+}
+
+/// Runs all callbacks whose grace period completed
+pub(crate) fn run_callbacks() {
+    while let Some(callback) = without_interrupts(|| GRACE_PERIODS.lock().pop_ready()) {
+        callback();
+    }
+}
+
+/// Amount of callbacks waiting for a grace period to complete
+pub fn pending_callbacks() -> usize {
+    without_interrupts(|| GRACE_PERIODS.lock().pending_callbacks())
+}
+
+/// Pointer to a value that is read without locking and replaced as a whole.
+///
+/// Writers are serialized with each other. The replaced value is dropped
+/// after a grace period.
+pub struct RcuCell<T: Send + Sync + 'static> {
+    value: AtomicPtr<T>,
+    writer: BlockingMutex<()>,
+}
+
+impl<T: Send + Sync + 'static> RcuCell<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            value: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            writer: BlockingMutex::new(()),
+        }
+    }
+
+    /// Returns the current value, which stays valid until the read side
+    /// critical section ends
+    pub fn read<'a>(&'a self, _guard: &'a RcuReadGuard) -> &'a T {
+        unsafe { &*self.value.load(Ordering::Acquire) }
+    }
+
+    /// Publishes `value` and drops the previous one after a grace period
+    pub fn replace(&self, value: T) {
+        let _writer = self.writer.lock();
+        self.publish(value);
+    }
+
+    /// Publishes a modified copy of the current value, nothing is published
+    /// if `f` fails
+    pub fn update<F, E>(&self, f: F) -> Result<(), E>
+    where
+        T: Clone,
+        F: FnOnce(&mut T) -> Result<(), E>,
+    {
+        let _writer = self.writer.lock();
+        // writers are serialized, the value can't be retired while copying it
+        let mut value = unsafe { (*self.value.load(Ordering::Acquire)).clone() };
+        f(&mut value)?;
+        self.publish(value);
+        Ok(())
+    }
+
+    fn publish(&self, value: T) {
+        let old = self
+            .value
+            .swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+        let old = RetiredValue(old);
+        call_rcu(Box::new(move || drop(old.into_box())));
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        // no reader can borrow the cell anymore
+        let value = self.value.swap(ptr::null_mut(), Ordering::Acquire);
+        drop(unsafe { Box::from_raw(value) });
+    }
+}
+
+/// Value that was replaced and waits for its grace period
+struct RetiredValue<T>(*mut T);
+
+unsafe impl<T: Send> Send for RetiredValue<T> {}
+
+impl<T> RetiredValue<T> {
+    fn into_box(self) -> Box<T> {
+        unsafe { Box::from_raw(self.0) }
+    }
+}
@@ -0,0 +1,106 @@
+extern crate alloc;
+use crate::scheduler::{self, ThreadId};
+use alloc::collections::VecDeque;
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+/// A queue of threads waiting for an event.
+///
+/// Waiting blocks the current thread. It can be woken up from thread as well
+/// as from interrupt context, therefore the queue is only ever locked with
+/// interrupts disabled.
+///
+/// Enqueuing and blocking happen with interrupts disabled so a wake up can't
+/// get lost in between.
+pub struct WaitQueue {
+    /// Shown as what the waiting threads are blocked on
+    name: &'static str,
+    waiters: Mutex<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self::named("wait queue")
+    }
+
+    /// Creates a queue whose waiting threads show up as blocked on `name`
+    pub const fn named(name: &'static str) -> Self {
+        Self {
+            name,
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Amount of threads currently waiting
+    pub fn len(&self) -> usize {
+        without_interrupts(|| self.waiters.lock().len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Blocks the current thread until it is woken up
+    pub fn wait(&self) {
+        without_interrupts(|| {
+            self.enqueue_current();
+            scheduler::block_current(self.name);
+        });
+    }
+
+    /// Blocks the current thread until `condition` returns true. The
+    /// condition is checked with interrupts disabled and rechecked after
+    /// every wake up.
+    pub fn wait_until<F>(&self, mut condition: F)
+    where
+        F: FnMut() -> bool,
+    {
+        while !without_interrupts(|| {
+            if condition() {
+                return true;
+            }
+            self.enqueue_current();
+            scheduler::block_current(self.name);
+            false
+        }) {}
+    }
+
+    /// Wakes up the longest waiting thread. Returns whether a thread was woken.
+    pub fn wake_one(&self) -> bool {
+        match without_interrupts(|| self.waiters.lock().pop_front()) {
+            Some(id) => {
+                scheduler::wake(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes up all waiting threads and returns their amount
+    pub fn wake_all(&self) -> usize {
+        let waiters = without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        let count = waiters.len();
+        for id in waiters {
+            scheduler::wake(id);
+        }
+        count
+    }
+
+    /// Adds the current thread to the queue without blocking it.
+    ///
+    /// Must be followed by blocking the thread before interrupts are enabled
+    /// again.
+    pub(crate) fn enqueue_current(&self) {
+        let id = scheduler::current();
+        self.waiters.lock().push_back(id);
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
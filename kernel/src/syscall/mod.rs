@@ -0,0 +1,267 @@
+//! System call interface.
+//!
+//! System calls are issued using `int 0x80`. The calling convention follows the
+//! Linux one:
+//! - rax: system call number
+//! - rdi, rsi, rdx, r10, r8: arguments
+//! - rax: return value. Negative values are an [`Errno`]
+//!
+//! All other registers are preserved. There is no user mode yet, so for now
+//! only kernel threads issue system calls, but the gate is already callable
+//! from ring 3.
+use crate::{
+    fs::{self, pipe},
+    ipc,
+    net::{dns, udp},
+    process::signal,
+    sync::futex,
+};
+use core::{
+    arch::{asm, global_asm},
+    mem,
+};
+use num_enum::TryFromPrimitive;
+use x86_64::{idt::HandlerFunc, interrupts};
+
+/// Interrupt vector used for system calls
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u64)]
+#[num_enum(error_type = Errno, error = Errno::ENOSYS)]
+pub enum Syscall {
+    Futex = 0,
+    Kill = 1,
+    Pipe = 2,
+    Read = 3,
+    Write = 4,
+    Close = 5,
+    IpcCreate = 6,
+    IpcDestroy = 7,
+    IpcSend = 8,
+    IpcReceive = 9,
+    Open = 10,
+    Socket = 11,
+    Bind = 12,
+    SendTo = 13,
+    RecvFrom = 14,
+    Resolve = 15,
+}
+
+/// Error numbers returned by system calls. Values match the Linux ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    /// No such file or directory
+    ENOENT = 2,
+    /// No such process
+    ESRCH = 3,
+    /// I/O error
+    EIO = 5,
+    /// Bad file descriptor
+    EBADF = 9,
+    /// Try again
+    EAGAIN = 11,
+    /// Out of memory
+    ENOMEM = 12,
+    /// Bad address
+    EFAULT = 14,
+    /// File exists
+    EEXIST = 17,
+    /// Cross-device link
+    EXDEV = 18,
+    /// Not a directory
+    ENOTDIR = 20,
+    /// Is a directory
+    EISDIR = 21,
+    /// Invalid argument
+    EINVAL = 22,
+    /// Broken pipe
+    EPIPE = 32,
+    /// Function not implemented
+    ENOSYS = 38,
+    /// Directory not empty
+    ENOTEMPTY = 39,
+    /// Identifier removed
+    EIDRM = 43,
+    /// Socket operation on non-socket
+    ENOTSOCK = 88,
+    /// Message too long
+    EMSGSIZE = 90,
+    /// Address already in use
+    EADDRINUSE = 98,
+    /// Network is unreachable
+    ENETUNREACH = 101,
+    /// Connection reset by peer
+    ECONNRESET = 104,
+    /// Transport endpoint is not connected
+    ENOTCONN = 107,
+    /// Connection timed out
+    ETIMEDOUT = 110,
+    /// Connection refused
+    ECONNREFUSED = 111,
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+// The CPU pushes 5 registers, together with the 8 saved ones the stack needs
+// to be realigned before calling into Rust.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    // shift syscall arguments into C calling convention registers
+    "mov r9, r8",
+    "mov r8, r10",
+    "mov rcx, rdx",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "sub rsp, 8",
+    "call {}",
+    "add rsp, 8",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "iretq",
+    sym dispatch,
+);
+
+extern "C" {
+    fn syscall_entry() -> !;
+}
+
+/// Handler to register in the IDT for [`SYSCALL_VECTOR`]
+pub fn entry() -> HandlerFunc {
+    unsafe { mem::transmute::<unsafe extern "C" fn() -> !, HandlerFunc>(syscall_entry) }
+}
+
+extern "C" fn dispatch(number: u64, arg0: u64, arg1: u64, arg2: u64, arg3: u64, _arg4: u64) -> i64 {
+    // the interrupt gate disabled interrupts, system calls might block for a
+    // long time though. iretq restores the callers flags.
+    unsafe { interrupts::enable() };
+
+    let result = Syscall::try_from(number).and_then(|syscall| match syscall {
+        Syscall::Futex => futex::sys_futex(arg0, arg1, arg2),
+        Syscall::Kill => signal::sys_kill(arg0, arg1),
+        Syscall::Pipe => pipe::sys_pipe(arg0),
+        Syscall::Read => fs::sys_read(arg0, arg1, arg2),
+        Syscall::Write => fs::sys_write(arg0, arg1, arg2),
+        Syscall::Close => fs::sys_close(arg0),
+        Syscall::IpcCreate => ipc::sys_create(),
+        Syscall::IpcDestroy => ipc::sys_destroy(arg0),
+        Syscall::IpcSend => ipc::sys_send(arg0, arg1),
+        Syscall::IpcReceive => ipc::sys_receive(arg0, arg1),
+        Syscall::Open => fs::sys_open(arg0, arg1),
+        Syscall::Socket => udp::sys_socket(),
+        Syscall::Bind => udp::sys_bind(arg0, arg1),
+        Syscall::SendTo => udp::sys_sendto(arg0, arg1, arg2, arg3),
+        Syscall::RecvFrom => udp::sys_recvfrom(arg0, arg1, arg2, arg3),
+        Syscall::Resolve => dns::sys_resolve(arg0, arg1, arg2, arg3),
+    });
+
+    // return to the caller is a signal delivery point. The result stays on
+    // this stack while handlers run and is returned afterwards.
+    signal::deliver_pending();
+
+    unsafe { interrupts::disable() };
+
+    match result {
+        Ok(value) => value as i64,
+        Err(errno) => -(errno as i64),
+    }
+}
+
+/// Issues a system call with up to three arguments
+///
+/// # Safety
+///
+/// The arguments need to be valid for the given system call, e.g. pointers
+/// need to point to valid memory
+pub unsafe fn syscall3(syscall: Syscall, arg0: u64, arg1: u64, arg2: u64) -> SyscallResult {
+    let ret: i64;
+    asm!(
+        "int 0x80",
+        inlateout("rax") syscall as u64 => ret,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+    );
+
+    if ret < 0 {
+        Err(errno_from(-ret))
+    } else {
+        Ok(ret as u64)
+    }
+}
+
+/// Issues a system call with up to four arguments
+///
+/// # Safety
+///
+/// The arguments need to be valid for the given system call, e.g. pointers
+/// need to point to valid memory
+pub unsafe fn syscall4(
+    syscall: Syscall,
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+) -> SyscallResult {
+    let ret: i64;
+    asm!(
+        "int 0x80",
+        inlateout("rax") syscall as u64 => ret,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        in("r10") arg3,
+    );
+
+    if ret < 0 {
+        Err(errno_from(-ret))
+    } else {
+        Ok(ret as u64)
+    }
+}
+
+fn errno_from(value: i64) -> Errno {
+    match value {
+        2 => Errno::ENOENT,
+        3 => Errno::ESRCH,
+        5 => Errno::EIO,
+        9 => Errno::EBADF,
+        11 => Errno::EAGAIN,
+        12 => Errno::ENOMEM,
+        14 => Errno::EFAULT,
+        17 => Errno::EEXIST,
+        18 => Errno::EXDEV,
+        20 => Errno::ENOTDIR,
+        21 => Errno::EISDIR,
+        22 => Errno::EINVAL,
+        32 => Errno::EPIPE,
+        39 => Errno::ENOTEMPTY,
+        43 => Errno::EIDRM,
+        88 => Errno::ENOTSOCK,
+        90 => Errno::EMSGSIZE,
+        98 => Errno::EADDRINUSE,
+        101 => Errno::ENETUNREACH,
+        104 => Errno::ECONNRESET,
+        107 => Errno::ENOTCONN,
+        110 => Errno::ETIMEDOUT,
+        111 => Errno::ECONNREFUSED,
+        _ => Errno::ENOSYS,
+    }
+}
@@ -0,0 +1,266 @@
+//! Test framework of the test kernels.
+//!
+//! Tests are registered with [`kernel_test!`](crate::kernel_test), which
+//! places a [`TestCase`] into the `kernel_tests` section. The linker collects
+//! the entries of all crates and [`run`] finds them through the
+//! `__start_kernel_tests` / `__stop_kernel_tests` symbols, so one test kernel
+//! can contain any number of tests.
+//!
+//! A test fails by panicking or by causing a CPU exception, unless it was
+//! registered to expect exactly that. The panic handler of the test kernel has
+//! to call [`panicked`], the exception handlers call [`exception`]. Both
+//! report the result and continue with the next test on the stack of the
+//! runner. Locks held by the test at that point stay locked.
+//!
+//! Results are printed over serial in the format the host runner parses:
+//!
+//! ```text
+//! test memory::test_mmap ... ok
+//! test memory::test_munmap ... FAILED
+//! test result: FAILED. 1 passed; 1 failed
+//! ```
+use crate::{
+    qemu::{self, QemuExitCode},
+    scheduler,
+};
+use core::{
+    arch::asm,
+    panic::PanicInfo,
+    slice,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+use x86_64::{interrupts, print, println};
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct TestCase {
+    pub name: &'static str,
+    pub func: fn(),
+    pub expect: Expect,
+}
+
+/// How a test is supposed to end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expect {
+    Return,
+    Panic,
+    Exception(Exception),
+}
+
+/// CPU exceptions the kernel can't recover from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    DivideByZero,
+    InvalidOpcode,
+    GeneralProtection,
+    SegmentNotPresent,
+    /// Page fault that isn't a guard page hit
+    PageFault,
+    /// Page fault on the guard page of a stack
+    StackOverflow,
+    AlignmentCheck,
+    InvalidTss,
+    StackSegmentFault,
+    DoubleFault,
+    Overflow,
+    BoundRangeExceeded,
+    X87FloatingPoint,
+    SimdFloatingPoint,
+    MachineCheck,
+    Virtualization,
+    ControlProtection,
+    HypervisorInjection,
+    VmmCommunication,
+    Security,
+}
+
+/// Registers `fn()` items as tests. Tests expecting a panic or an exception
+/// are registered with `should_panic:` or `should_fault(<Exception>):` in
+/// front of the list.
+///
+/// ```ignore
+/// kernel_test!(test_mmap, test_munmap);
+/// kernel_test!(should_panic: test_double_free);
+/// kernel_test!(should_fault(PageFault): test_null_deref);
+/// ```
+#[macro_export]
+macro_rules! kernel_test {
+    (should_panic: $($func:path),+ $(,)?) => {
+        $crate::kernel_test!(@register $crate::test::Expect::Panic; $($func),+);
+    };
+    (should_fault($exception:ident): $($func:path),+ $(,)?) => {
+        $crate::kernel_test!(
+            @register $crate::test::Expect::Exception($crate::test::Exception::$exception);
+            $($func),+
+        );
+    };
+    (@register $expect:expr; $($func:path),+) => {
+        $(
+            const _: () = {
+                #[used]
+                #[link_section = "kernel_tests"]
+                static TEST_CASE: $crate::test::TestCase = $crate::test::TestCase {
+                    name: concat!(module_path!(), "::", stringify!($func)),
+                    func: $func,
+                    expect: $expect,
+                };
+            };
+        )+
+    };
+    ($($func:path),+ $(,)?) => {
+        $crate::kernel_test!(@register $crate::test::Expect::Return; $($func),+);
+    };
+}
+
+// weak since the section doesn't exist in kernels without tests
+extern "C" {
+    #[linkage = "extern_weak"]
+    static __start_kernel_tests: *const u8;
+    #[linkage = "extern_weak"]
+    static __stop_kernel_tests: *const u8;
+}
+
+/// Whether a test is running, exceptions outside of tests are fatal
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Index of the test that is running
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PASSED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+/// Stack pointer of [`run`], tests are continued there after a panic or an
+/// exception
+static RUNNER_STACK: AtomicU64 = AtomicU64::new(0);
+/// Thread the tests run on, faults of threads started by a test are handled
+/// like outside of tests
+static RUNNER_THREAD: AtomicU64 = AtomicU64::new(0);
+static INTERRUPTS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// All registered tests
+pub fn tests() -> &'static [TestCase] {
+    unsafe {
+        let start = __start_kernel_tests as *const TestCase;
+        let end = __stop_kernel_tests as *const TestCase;
+        if start.is_null() {
+            return &[];
+        }
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Runs all registered tests and exits QEMU with the result
+pub fn run() -> ! {
+    println!("running {} tests", tests().len());
+
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+    RUNNER_STACK.store(rsp & !0xf, Ordering::SeqCst);
+    RUNNER_THREAD.store(scheduler::current().as_u64(), Ordering::SeqCst);
+    INTERRUPTS_ENABLED.store(interrupts::are_enabled(), Ordering::SeqCst);
+
+    run_from(0)
+}
+
+/// Reports the end of the running test by a panic and continues with the
+/// next one. Has to be called by the panic handler of the test kernel.
+pub fn panicked(info: &PanicInfo) -> ! {
+    if !RUNNING.load(Ordering::SeqCst) {
+        println!("panic outside of a test: {}", info);
+        qemu::exit(QemuExitCode::Failed);
+    }
+
+    if current().expect == Expect::Panic {
+        pass();
+    } else {
+        fail();
+        println!("{}", info);
+    }
+    resume()
+}
+
+/// Reports the end of the running test by `exception` and continues with the
+/// next one. Returns if no test is running or the exception was raised by
+/// another thread than the test, e.g. a process the test started.
+pub fn exception(exception: Exception) {
+    if !RUNNING.load(Ordering::SeqCst)
+        || scheduler::current().as_u64() != RUNNER_THREAD.load(Ordering::SeqCst)
+    {
+        return;
+    }
+
+    if current().expect == Expect::Exception(exception) {
+        pass();
+    } else {
+        fail();
+        println!("unexpected exception: {:?}", exception);
+    }
+    resume()
+}
+
+fn current() -> &'static TestCase {
+    &tests()[CURRENT.load(Ordering::SeqCst)]
+}
+
+fn pass() {
+    println!("ok");
+    PASSED.fetch_add(1, Ordering::SeqCst);
+}
+
+fn fail() {
+    println!("FAILED");
+    FAILED.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Continues with the next test on the stack of the runner, the stack of the
+/// failed test might be the exception stack or broken
+fn resume() -> ! {
+    let next = CURRENT.load(Ordering::SeqCst) + 1;
+    unsafe {
+        asm!(
+            "mov rsp, {stack}",
+            // ends the frame pointer chain for backtraces
+            "xor ebp, ebp",
+            "call {resume}",
+            stack = in(reg) RUNNER_STACK.load(Ordering::SeqCst),
+            resume = sym resume_on_runner_stack,
+            in("rdi") next,
+            options(noreturn)
+        )
+    }
+}
+
+extern "C" fn resume_on_runner_stack(next: usize) -> ! {
+    // exception handlers run with interrupts disabled
+    if INTERRUPTS_ENABLED.load(Ordering::SeqCst) {
+        unsafe { interrupts::enable() };
+    }
+    run_from(next)
+}
+
+fn run_from(first: usize) -> ! {
+    for (i, test) in tests().iter().enumerate().skip(first) {
+        CURRENT.store(i, Ordering::SeqCst);
+        RUNNING.store(true, Ordering::SeqCst);
+        print!("test {} ... ", test.name);
+        (test.func)();
+
+        if test.expect == Expect::Return {
+            pass();
+        } else {
+            fail();
+            println!("returned, expected {:?}", test.expect);
+        }
+    }
+    RUNNING.store(false, Ordering::SeqCst);
+
+    let passed = PASSED.load(Ordering::SeqCst);
+    let failed = FAILED.load(Ordering::SeqCst);
+    let (result, exit_code) = if failed == 0 {
+        ("ok", QemuExitCode::Success)
+    } else {
+        ("FAILED", QemuExitCode::Failed)
+    };
+    println!(
+        "test result: {}. {} passed; {} failed",
+        result, passed, failed
+    );
+    qemu::exit(exit_code)
+}
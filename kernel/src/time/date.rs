@@ -0,0 +1,84 @@
+//! Calendar dates.
+//!
+//! Conversions between dates and unix timestamps use the proleptic Gregorian
+//! calendar and ignore leap seconds, like unix time itself.
+//!
+//! http://howardhinnant.github.io/date_algorithms.html
+use core::fmt;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+/// Days from 0000-03-01 to 1970-01-01
+const UNIX_EPOCH_DAYS: u64 = 719_468;
+const DAYS_PER_ERA: u64 = 146_097;
+
+/// A date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Date of `timestamp` seconds since 1970-01-01 00:00:00
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let seconds = timestamp % SECONDS_PER_DAY;
+        let days = timestamp / SECONDS_PER_DAY + UNIX_EPOCH_DAYS;
+
+        // eras of 400 years starting in March, so leap days are at the end
+        let era = days / DAYS_PER_ERA;
+        let day_of_era = days % DAYS_PER_ERA;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+        let month = if month_from_march < 10 {
+            month_from_march + 3
+        } else {
+            month_from_march - 9
+        };
+        let year = year_of_era + era * 400 + (month <= 2) as u64;
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    /// Seconds since 1970-01-01 00:00:00, saturates at 0 for earlier dates
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let month = self.month as u64;
+        let year = (self.year as u64).saturating_sub((month <= 2) as u64);
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let month_from_march = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * month_from_march + 2) / 5 + (self.day as u64).saturating_sub(1);
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = (era * DAYS_PER_ERA + day_of_era).saturating_sub(UNIX_EPOCH_DAYS);
+
+        days * SECONDS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
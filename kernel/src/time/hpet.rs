@@ -0,0 +1,115 @@
+//! High precision event timer (HPET).
+//!
+//! Only the main counter is used, as a clock source. The timers of the HPET
+//! are left disabled.
+//!
+//! https://wiki.osdev.org/HPET
+extern crate alloc;
+use super::ClockSource;
+use crate::{
+    acpi,
+    memory::{self, MemoryError, Mmio},
+};
+use alloc::boxed::Box;
+use x86_64::{memory::PhysicalAddress, paging::CacheAttribute};
+
+const CAPABILITIES: u64 = 0x0;
+const CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+const REGISTERS_SIZE: u64 = 0x400;
+
+const COUNTER_64BIT: u64 = 1 << 13;
+const ENABLE: u64 = 1;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+/// Longest counter period allowed by the specification
+const MAX_PERIOD: u64 = 100_000_000;
+
+#[derive(Debug)]
+pub enum HpetError {
+    NotPresent,
+    /// The registers are not memory mapped
+    NotMemoryMapped,
+    /// Only HPETs with a 64 bit main counter are supported, a 32 bit one
+    /// would wrap after a few seconds
+    Counter32Bit,
+    InvalidPeriod,
+    Memory(MemoryError),
+}
+
+impl From<MemoryError> for HpetError {
+    fn from(error: MemoryError) -> Self {
+        HpetError::Memory(error)
+    }
+}
+
+pub struct Hpet {
+    registers: Mmio,
+    frequency: u64,
+}
+
+impl Hpet {
+    /// # Safety
+    ///
+    /// `registers` has to map the register block of an HPET
+    unsafe fn new(registers: Mmio) -> Result<Self, HpetError> {
+        let mut hpet = Self {
+            registers,
+            frequency: 0,
+        };
+
+        let capabilities = hpet.read_register(CAPABILITIES);
+        if capabilities & COUNTER_64BIT == 0 {
+            return Err(HpetError::Counter32Bit);
+        }
+
+        let period = capabilities >> 32;
+        if period == 0 || period > MAX_PERIOD {
+            return Err(HpetError::InvalidPeriod);
+        }
+        hpet.frequency = FEMTOSECONDS_PER_SECOND / period;
+
+        let configuration = hpet.read_register(CONFIGURATION);
+        hpet.write_register(CONFIGURATION, configuration | ENABLE);
+        Ok(hpet)
+    }
+
+    fn read_register(&self, offset: u64) -> u64 {
+        self.registers.read(offset)
+    }
+
+    fn write_register(&self, offset: u64, value: u64) {
+        self.registers.write(offset, value)
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn read(&self) -> u64 {
+        self.read_register(MAIN_COUNTER)
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+/// Maps and enables the HPET described by the ACPI tables
+pub fn init() -> Result<&'static Hpet, HpetError> {
+    let table = acpi::hpet().ok_or(HpetError::NotPresent)?;
+    let base = table.base_address;
+    if base.address_space != 0 {
+        return Err(HpetError::NotMemoryMapped);
+    }
+
+    let registers = memory::ioremap(
+        PhysicalAddress::new(base.address),
+        REGISTERS_SIZE,
+        CacheAttribute::Uncached,
+    )?;
+    let hpet = unsafe { Hpet::new(registers)? };
+    Ok(Box::leak(Box::new(hpet)))
+}
@@ -0,0 +1,229 @@
+//! Kernel time keeping.
+//!
+//! Time is read from a [`ClockSource`], a free running counter of known
+//! frequency. Until [`init`] selected a better one, the number of timer
+//! interrupts is used, which is counted independently of the scheduler so it
+//! is available as soon as interrupts are enabled.
+//!
+//! The time stamp counter is only used if it is invariant, i.e. runs at a
+//! constant rate regardless of frequency scaling and sleep states. Its
+//! frequency is calibrated against the HPET if there is one, otherwise against
+//! the timer interrupt.
+//!
+//! Timer interrupts at arbitrary points in time are generated by a
+//! [`ClockEvent`]. If there is one the scheduler programs it for the next
+//! event instead of relying on the periodic timer interrupt.
+//!
+//! The wall clock is the uptime plus the offset to the unix epoch, which is
+//! taken from the RTC. It only has a resolution of a second.
+pub mod date;
+pub mod hpet;
+pub mod rtc;
+pub mod tsc;
+
+use crate::{info, warn};
+use core::{
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+pub use date::DateTime;
+use x86_64::{interrupts::without_interrupts, mutex::Mutex};
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+/// Nanoseconds since the unix epoch at uptime 0
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// A monotonic counter time is derived from
+pub trait ClockSource: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Current value of the counter
+    fn read(&self) -> u64;
+    /// Increments of the counter per second
+    fn frequency(&self) -> u64;
+    /// Whether the counter is driven by the periodic timer interrupt, which
+    /// then has to keep running
+    fn needs_tick(&self) -> bool {
+        false
+    }
+}
+
+/// A timer that fires an interrupt once after a programmed delay
+pub trait ClockEvent: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Fires the interrupt after `delay`, replacing the pending event
+    fn program(&self, delay: Duration);
+    /// Cancels the pending event
+    fn stop(&self);
+}
+
+/// Counts timer interrupts
+struct TickClock;
+
+impl ClockSource for TickClock {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn read(&self) -> u64 {
+        ticks()
+    }
+
+    fn frequency(&self) -> u64 {
+        FREQUENCY.load(Ordering::Relaxed) as u64
+    }
+
+    fn needs_tick(&self) -> bool {
+        true
+    }
+}
+
+static TICK_CLOCK: TickClock = TickClock;
+
+#[derive(Clone, Copy)]
+struct Clock {
+    source: &'static dyn ClockSource,
+    /// Counter value when the source was selected
+    base: u64,
+    /// Uptime when the source was selected
+    offset: Duration,
+}
+
+impl Clock {
+    fn now(&self) -> Duration {
+        let frequency = self.source.frequency() as u128;
+        if frequency == 0 {
+            return self.offset;
+        }
+
+        let elapsed = self.source.read().wrapping_sub(self.base) as u128;
+        self.offset + Duration::from_nanos((elapsed * NANOS_PER_SECOND / frequency) as u64)
+    }
+}
+
+static CLOCK: Mutex<Clock> = Mutex::new(Clock {
+    source: &TICK_CLOCK,
+    base: 0,
+    offset: Duration::ZERO,
+});
+
+static CLOCK_EVENT: Mutex<Option<&'static dyn ClockEvent>> = Mutex::new(None);
+
+/// Sets the frequency of the timer interrupt in Hz
+pub(crate) fn set_tick_frequency(frequency: u32) {
+    FREQUENCY.store(frequency, Ordering::SeqCst);
+}
+
+/// Called by the timer interrupt handler on every tick
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Selects the most precise clock source available. Requires the timer
+/// interrupt, the ACPI tables and the memory manager.
+pub fn init() {
+    let hpet = match hpet::init() {
+        Ok(hpet) => Some(hpet),
+        Err(error) => {
+            info!("No usable HPET: {:?}", error);
+            None
+        }
+    };
+
+    let reference: &'static dyn ClockSource = match hpet {
+        Some(hpet) => hpet,
+        None => &TICK_CLOCK,
+    };
+
+    let source = if tsc::is_invariant() {
+        match tsc::init(reference) {
+            Ok(tsc) => tsc,
+            Err(error) => {
+                warn!("Failed to calibrate TSC: {:?}", error);
+                reference
+            }
+        }
+    } else {
+        reference
+    };
+
+    set_clock_source(source);
+    info!(
+        "Using {} clock source at {} Hz",
+        source.name(),
+        source.frequency()
+    );
+
+    sync_wall_clock();
+    info!("Wall clock time is {}", wall_clock());
+}
+
+/// Sets the offset of the wall clock from the RTC
+pub fn sync_wall_clock() {
+    let now = Duration::from_secs(rtc::read().to_unix_timestamp());
+    let boot_time = now.saturating_sub(uptime());
+    BOOT_TIME.store(boot_time.as_nanos() as u64, Ordering::SeqCst);
+}
+
+/// Switches to `source`. Time continues from where the previous source
+/// stopped.
+pub fn set_clock_source(source: &'static dyn ClockSource) {
+    without_interrupts(|| {
+        let mut clock = CLOCK.lock();
+        let now = clock.now();
+        *clock = Clock {
+            source,
+            base: source.read(),
+            offset: now,
+        };
+    });
+}
+
+/// The clock source currently in use
+pub fn clock_source() -> &'static dyn ClockSource {
+    without_interrupts(|| CLOCK.lock().source)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockEventError {
+    /// The clock source only advances with the periodic timer interrupt
+    ClockSourceNeedsTick,
+}
+
+/// Starts using `event` for timer interrupts. The caller stops the periodic
+/// timer interrupt afterwards.
+pub fn set_clock_event(event: &'static dyn ClockEvent) -> Result<(), ClockEventError> {
+    if clock_source().needs_tick() {
+        return Err(ClockEventError::ClockSourceNeedsTick);
+    }
+    without_interrupts(|| *CLOCK_EVENT.lock() = Some(event));
+    Ok(())
+}
+
+/// The clock event device, None if only the periodic timer interrupt is
+/// available
+pub fn clock_event() -> Option<&'static dyn ClockEvent> {
+    without_interrupts(|| *CLOCK_EVENT.lock())
+}
+
+/// Amount of scheduler ticks since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time since the timer interrupt was set up
+pub fn uptime() -> Duration {
+    without_interrupts(|| *CLOCK.lock()).now()
+}
+
+/// Time since 1970-01-01 00:00:00 UTC
+pub fn unix_time() -> Duration {
+    Duration::from_nanos(BOOT_TIME.load(Ordering::SeqCst)) + uptime()
+}
+
+/// Current date and time in UTC
+pub fn wall_clock() -> DateTime {
+    DateTime::from_unix_timestamp(unix_time().as_secs())
+}
@@ -0,0 +1,136 @@
+//! Real time clock in the CMOS.
+//!
+//! The RTC keeps the calendar date and time while the machine is off. Its
+//! registers are updated once per second, reads during an update can return
+//! inconsistent values, so the time is read until two reads in a row match.
+//! Depending on status register B the values are BCD or binary and the hour
+//! uses a 12 or 24 hour format.
+//!
+//! https://wiki.osdev.org/CMOS
+use super::DateTime;
+use crate::acpi;
+use x86_64::{interrupts::without_interrupts, mutex::Mutex, port::Port};
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+/// Set in the index to keep NMIs disabled while accessing the CMOS
+const NMI_DISABLE: u8 = 0x80;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const HOUR_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const PM: u8 = 1 << 7;
+
+/// Used if the FADT doesn't name a century register
+const DEFAULT_CENTURY: u16 = 20;
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos::new());
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    const fn new() -> Self {
+        Self {
+            index: Port::new(INDEX_PORT),
+            data: Port::new(DATA_PORT),
+        }
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        self.index.write(NMI_DISABLE | register);
+        self.data.read()
+    }
+
+    fn update_in_progress(&self) -> bool {
+        self.read(STATUS_A) & UPDATE_IN_PROGRESS != 0
+    }
+
+    /// Raw register values of seconds, minutes, hours, day, month, year and
+    /// century
+    fn read_raw(&self, century: Option<u8>) -> [u8; 7] {
+        while self.update_in_progress() {}
+        [
+            self.read(SECONDS),
+            self.read(MINUTES),
+            self.read(HOURS),
+            self.read(DAY),
+            self.read(MONTH),
+            self.read(YEAR),
+            century.map_or(0, |register| self.read(register)),
+        ]
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+/// Reads the current date and time
+pub fn read() -> DateTime {
+    let century_register = acpi::century_register();
+
+    let (raw, status) = without_interrupts(|| {
+        let cmos = CMOS.lock();
+        let mut raw = cmos.read_raw(century_register);
+        loop {
+            let again = cmos.read_raw(century_register);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos.read(STATUS_B))
+    });
+
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year, mut century] = raw;
+    let pm = hour & PM != 0;
+    hour &= !PM;
+
+    if status & BINARY == 0 {
+        for value in [
+            &mut second,
+            &mut minute,
+            &mut hour,
+            &mut day,
+            &mut month,
+            &mut year,
+            &mut century,
+        ] {
+            *value = from_bcd(*value);
+        }
+    }
+
+    // 12 AM is midnight, 12 PM noon
+    if status & HOUR_24 == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = match century {
+        0 => DEFAULT_CENTURY,
+        century => century as u16,
+    };
+
+    DateTime {
+        year: century * 100 + year as u16,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
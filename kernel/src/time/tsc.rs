@@ -0,0 +1,105 @@
+//! Time stamp counter (TSC).
+//!
+//! The TSC counts cycles of a constant reference clock on CPUs with an
+//! invariant TSC. On older CPUs it follows the core frequency and stops in
+//! deep sleep states, which makes it useless for time keeping.
+extern crate alloc;
+use super::ClockSource;
+use alloc::boxed::Box;
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{
+    cpuid::{self, Features},
+    instructions::rdtsc,
+};
+
+/// Time the TSC is measured against the reference clock
+const CALIBRATION_MILLIS: u64 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TscError {
+    /// The reference clock doesn't run
+    ReferenceStopped,
+}
+
+/// Calibrated frequency, 0 if the TSC isn't used
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+pub struct Tsc {
+    frequency: u64,
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn read(&self) -> u64 {
+        rdtsc()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+/// Whether the TSC runs at a constant rate
+pub fn is_invariant() -> bool {
+    cpuid::has(Features::INVARIANT_TSC)
+}
+
+/// Whether the local APIC timer supports TSC-deadline mode
+pub fn supports_deadline() -> bool {
+    cpuid::has(Features::TSC_DEADLINE)
+}
+
+/// Frequency of the TSC, None if it is not invariant or not calibrated yet
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::SeqCst) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Measures the frequency of the TSC against `reference`
+pub fn calibrate(reference: &dyn ClockSource) -> Result<u64, TscError> {
+    let frequency = reference.frequency();
+    if frequency == 0 {
+        return Err(TscError::ReferenceStopped);
+    }
+    let duration = (frequency * CALIBRATION_MILLIS / 1000).max(1);
+
+    // start right after the reference changed, so coarse references like the
+    // timer interrupt count are measured accurately
+    let initial = reference.read();
+    let mut start = initial;
+    let mut spins = 0u64;
+    while start == initial {
+        spins += 1;
+        if spins == u32::MAX as u64 {
+            return Err(TscError::ReferenceStopped);
+        }
+        spin_loop();
+        start = reference.read();
+    }
+    let tsc_start = rdtsc();
+
+    let mut end = start;
+    while end.wrapping_sub(start) < duration {
+        spin_loop();
+        end = reference.read();
+    }
+    let tsc_end = rdtsc();
+
+    let cycles = tsc_end.wrapping_sub(tsc_start) as u128;
+    Ok((cycles * frequency as u128 / end.wrapping_sub(start) as u128) as u64)
+}
+
+/// Calibrates the TSC against `reference`
+pub fn init(reference: &dyn ClockSource) -> Result<&'static Tsc, TscError> {
+    let frequency = calibrate(reference)?;
+    FREQUENCY.store(frequency, Ordering::SeqCst);
+    Ok(Box::leak(Box::new(Tsc { frequency })))
+}
@@ -0,0 +1,303 @@
+//! Static tracepoints.
+//!
+//! [`trace_event!`](crate::trace_event) places a [`TracePoint`] into the
+//! `tracepoints` section and, if it is enabled, records the uptime in
+//! nanoseconds and up to [`MAX_ARGS`] raw arguments into a ring of the last
+//! [`RING_SIZE`] events. Formatting is deferred until the events are read, so
+//! a disabled tracepoint costs one atomic load and an enabled one doesn't
+//! format anything. All tracepoints start disabled.
+//!
+//! ```ignore
+//! trace_event!(sched, "switch {} -> {}", previous, next);
+//! ```
+//!
+//! The format string only supports `{}` and `{:#x}` placeholders, arguments
+//! have to implement [`TraceArg`].
+//!
+//! Events are written with interrupts disabled. Readers detect events that
+//! were overwritten while they copied them and drop them.
+extern crate alloc;
+use crate::{process::Pid, scheduler::ThreadId, time};
+use alloc::{string::String, vec::Vec};
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Write},
+    slice,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use x86_64::{
+    interrupts::without_interrupts,
+    memory::{Address, PhysicalAddress, VirtualAddress},
+};
+
+pub const RING_SIZE: usize = 2048;
+pub const MAX_ARGS: usize = 4;
+
+/// Tracepoint created by [`trace_event!`](crate::trace_event)
+#[derive(Debug)]
+#[repr(C)]
+pub struct TracePoint {
+    pub subsystem: &'static str,
+    pub format: &'static str,
+    pub file: &'static str,
+    pub line: u32,
+    enabled: AtomicBool,
+}
+
+impl TracePoint {
+    #[doc(hidden)]
+    pub const fn new(
+        subsystem: &'static str,
+        format: &'static str,
+        file: &'static str,
+        line: u32,
+    ) -> Self {
+        Self {
+            subsystem,
+            format,
+            file,
+            line,
+            enabled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// Value that can be recorded as argument of a tracepoint
+pub trait TraceArg {
+    fn to_raw(&self) -> u64;
+}
+
+macro_rules! impl_trace_arg {
+    ($($ty:ty),*) => {
+        $(impl TraceArg for $ty {
+            fn to_raw(&self) -> u64 {
+                *self as u64
+            }
+        })*
+    };
+}
+
+impl_trace_arg!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, bool);
+
+impl TraceArg for VirtualAddress {
+    fn to_raw(&self) -> u64 {
+        self.as_u64()
+    }
+}
+
+impl TraceArg for PhysicalAddress {
+    fn to_raw(&self) -> u64 {
+        self.as_u64()
+    }
+}
+
+impl TraceArg for ThreadId {
+    fn to_raw(&self) -> u64 {
+        self.as_u64()
+    }
+}
+
+impl TraceArg for Pid {
+    fn to_raw(&self) -> u64 {
+        self.as_u64()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RawEvent {
+    tracepoint: *const TracePoint,
+    /// Uptime in nanoseconds
+    timestamp: u64,
+    args: [u64; MAX_ARGS],
+    arg_count: usize,
+}
+
+impl RawEvent {
+    const EMPTY: Self = Self {
+        tracepoint: core::ptr::null(),
+        timestamp: 0,
+        args: [0; MAX_ARGS],
+        arg_count: 0,
+    };
+}
+
+/// A recorded event
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub tracepoint: &'static TracePoint,
+    /// Uptime in nanoseconds
+    pub timestamp: u64,
+    args: [u64; MAX_ARGS],
+    arg_count: usize,
+}
+
+impl Event {
+    pub fn args(&self) -> &[u64] {
+        &self.args[..self.arg_count]
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:>5}.{:09}] {}: ",
+            self.timestamp / 1_000_000_000,
+            self.timestamp % 1_000_000_000,
+            self.tracepoint.subsystem
+        )?;
+
+        let mut args = self.args().iter();
+        let mut rest = self.tracepoint.format;
+        while let Some(start) = rest.find('{') {
+            f.write_str(&rest[..start])?;
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            let value = args.next().copied().unwrap_or(0);
+            match &rest[start..start + end + 1] {
+                "{:#x}" => write!(f, "{:#x}", value)?,
+                _ => write!(f, "{}", value)?,
+            }
+            rest = &rest[start + end + 1..];
+        }
+        f.write_str(rest)
+    }
+}
+
+struct Ring {
+    events: UnsafeCell<[RawEvent; RING_SIZE]>,
+    /// Events reserved by writers, the next one is stored at this modulo
+    /// [`RING_SIZE`]
+    reserved: AtomicUsize,
+    /// Events completely written
+    committed: AtomicUsize,
+    /// Events before this one were cleared
+    start: AtomicUsize,
+}
+
+unsafe impl Sync for Ring {}
+
+static RING: Ring = Ring {
+    events: UnsafeCell::new([RawEvent::EMPTY; RING_SIZE]),
+    reserved: AtomicUsize::new(0),
+    committed: AtomicUsize::new(0),
+    start: AtomicUsize::new(0),
+};
+
+extern "C" {
+    static __start_tracepoints: *const u8;
+    static __stop_tracepoints: *const u8;
+}
+
+/// All tracepoints, their index is used to refer to them
+pub fn tracepoints() -> &'static [TracePoint] {
+    unsafe {
+        let start = &__start_tracepoints as *const _ as *const TracePoint;
+        let end = &__stop_tracepoints as *const _ as *const TracePoint;
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Enables or disables all tracepoints of `subsystem`, returns how many there
+/// are
+pub fn set_subsystem_enabled(subsystem: &str, enabled: bool) -> usize {
+    tracepoints()
+        .iter()
+        .filter(|t| t.subsystem == subsystem)
+        .inspect(|t| t.set_enabled(enabled))
+        .count()
+}
+
+#[doc(hidden)]
+pub fn record(tracepoint: &'static TracePoint, args: &[u64]) {
+    let mut event = RawEvent {
+        tracepoint,
+        timestamp: time::uptime().as_nanos() as u64,
+        args: [0; MAX_ARGS],
+        arg_count: args.len().min(MAX_ARGS),
+    };
+    event.args[..event.arg_count].copy_from_slice(&args[..event.arg_count]);
+
+    without_interrupts(|| {
+        let position = RING.reserved.fetch_add(1, Ordering::AcqRel);
+        let events = RING.events.get() as *mut RawEvent;
+        unsafe { events.add(position % RING_SIZE).write(event) };
+        RING.committed.fetch_add(1, Ordering::Release);
+    });
+}
+
+/// The stored events, oldest first
+pub fn events() -> Vec<Event> {
+    let end = RING.committed.load(Ordering::Acquire);
+    let start = RING
+        .start
+        .load(Ordering::Relaxed)
+        .max(end.saturating_sub(RING_SIZE))
+        .min(end);
+
+    let events = RING.events.get() as *const RawEvent;
+    let raw: Vec<RawEvent> = (start..end)
+        .map(|position| unsafe { events.add(position % RING_SIZE).read() })
+        .collect();
+
+    // writers might have overwritten the oldest events while copying
+    let overwritten = RING
+        .reserved
+        .load(Ordering::Acquire)
+        .saturating_sub(RING_SIZE);
+    let skip = overwritten.saturating_sub(start).min(raw.len());
+    raw[skip..]
+        .iter()
+        .map(|event| Event {
+            tracepoint: unsafe { &*event.tracepoint },
+            timestamp: event.timestamp,
+            args: event.args,
+            arg_count: event.arg_count,
+        })
+        .collect()
+}
+
+/// Drops all stored events
+pub fn clear() {
+    RING.start
+        .store(RING.committed.load(Ordering::Acquire), Ordering::Relaxed);
+}
+
+/// The stored events formatted one per line
+pub fn dump() -> String {
+    let mut out = String::new();
+    for event in events() {
+        let _ = writeln!(out, "{}", event);
+    }
+    out
+}
+
+/// Records an event if the tracepoint is enabled, see [`tracepoint`](crate::tracepoint)
+#[macro_export]
+macro_rules! trace_event {
+    ($subsystem:ident, $format:literal $(, $arg:expr)* $(,)?) => {{
+        #[used]
+        #[link_section = "tracepoints"]
+        static TRACEPOINT: $crate::tracepoint::TracePoint = $crate::tracepoint::TracePoint::new(
+            stringify!($subsystem),
+            $format,
+            file!(),
+            line!(),
+        );
+        if TRACEPOINT.is_enabled() {
+            $crate::tracepoint::record(
+                &TRACEPOINT,
+                &[$($crate::tracepoint::TraceArg::to_raw(&$arg)),*],
+            );
+        }
+    }};
+}
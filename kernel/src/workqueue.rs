@@ -0,0 +1,122 @@
+//! Deferred work.
+//!
+//! Interrupt handlers shouldn't do heavy work. They [`queue`] a closure
+//! instead, which one of [`WORKERS`] kernel threads runs later with interrupts
+//! enabled. Closures are stored inline in a preallocated queue of [`CAPACITY`]
+//! entries, so queueing never allocates or blocks and is safe in interrupt
+//! context. A closure may capture at most [`WORK_SIZE`] bytes, which is
+//! checked at compile time.
+//!
+//! Work queued before [`init`] runs once the workers are started.
+extern crate alloc;
+use crate::{
+    memory::MemoryError,
+    scheduler::{self, ThreadPriority},
+    sync::WaitQueue,
+};
+use alloc::format;
+use core::{
+    mem::{self, ManuallyDrop, MaybeUninit},
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use lockfree::MpmcQueue;
+
+/// Work items that can be pending at once
+pub const CAPACITY: usize = 256;
+/// Bytes a queued closure may capture
+pub const WORK_SIZE: usize = 32;
+pub const WORKERS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkError {
+    /// [`CAPACITY`] work items are already pending
+    QueueFull,
+}
+
+/// A closure stored inline
+pub struct Work {
+    storage: MaybeUninit<[u64; WORK_SIZE / 8]>,
+    /// Moves the closure out of `storage`, then calls it if the flag is set
+    /// or drops it otherwise
+    consume: unsafe fn(*mut u8, bool),
+}
+
+// only closures that are Send are stored
+unsafe impl Send for Work {}
+
+impl Work {
+    pub fn new<F: FnOnce() + Send + 'static>(f: F) -> Self {
+        const {
+            assert!(
+                mem::size_of::<F>() <= WORK_SIZE && mem::align_of::<F>() <= mem::align_of::<u64>(),
+                "Closure captures too much to be queued"
+            )
+        };
+
+        unsafe fn consume<F: FnOnce()>(storage: *mut u8, call: bool) {
+            let f = unsafe { ptr::read(storage as *mut F) };
+            if call {
+                f();
+            }
+        }
+
+        let mut storage = MaybeUninit::uninit();
+        unsafe { ptr::write(storage.as_mut_ptr() as *mut F, f) };
+        Self {
+            storage,
+            consume: consume::<F>,
+        }
+    }
+
+    pub fn run(self) {
+        let mut work = ManuallyDrop::new(self);
+        unsafe { (work.consume)(work.storage.as_mut_ptr() as *mut u8, true) };
+    }
+}
+
+impl Drop for Work {
+    fn drop(&mut self) {
+        unsafe { (self.consume)(self.storage.as_mut_ptr() as *mut u8, false) };
+    }
+}
+
+static QUEUE: MpmcQueue<Work, CAPACITY> = MpmcQueue::new();
+static WORK_AVAILABLE: WaitQueue = WaitQueue::named("work");
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the worker threads
+pub fn init() -> Result<(), MemoryError> {
+    for i in 0..WORKERS {
+        let id = scheduler::spawn(worker_loop, ThreadPriority::Normal)?;
+        scheduler::set_name(id, &format!("worker-{}", i));
+    }
+    Ok(())
+}
+
+/// Runs `f` in a worker thread. Safe to call from interrupt handlers.
+pub fn queue<F: FnOnce() + Send + 'static>(f: F) -> Result<(), WorkError> {
+    QUEUE.push(Work::new(f)).map_err(|_| WorkError::QueueFull)?;
+    WORK_AVAILABLE.wake_one();
+    Ok(())
+}
+
+/// Amount of queued work items that didn't start running yet
+pub fn pending() -> usize {
+    QUEUE.len()
+}
+
+/// Amount of work items run since boot
+pub fn completed() -> u64 {
+    COMPLETED.load(Ordering::Relaxed)
+}
+
+fn worker_loop() {
+    loop {
+        WORK_AVAILABLE.wait_until(|| !QUEUE.is_empty());
+        while let Some(work) = QUEUE.pop() {
+            work.run();
+            COMPLETED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
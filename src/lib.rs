@@ -1,24 +1,451 @@
-use std::env;
+//! Support for running test kernels in QEMU.
+//!
+//! A test kernel reports its result by writing a [`QemuExitCode`] to the
+//! `isa-debug-exit` device. [`TestKernel`] boots an image, waits for the exit
+//! with a timeout and returns a [`TestOutcome`] to make assertions on. Test
+//! kernels using `kernel::test` print a result per test and a summary line,
+//! which is parsed into a [`TestSummary`].
+//!
+//! Stage2 of the bootloader prints through the BIOS, its output is mirrored to
+//! the debug port and captured with [`TestKernel::debugcon`]. Together with
+//! the serial output of the later stages this allows asserting on the
+//! [`BootStage`]s an image went through.
+use std::{
+    env, fmt, fs,
+    io::Read,
+    path::PathBuf,
+    process::{self, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// I/O port of the `isa-debug-exit` device
+pub const DEBUG_EXIT_IOBASE: u16 = 0xf4;
+
+/// Default time a test kernel may run before it is killed
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Exit codes written by the kernel, see `kernel::qemu::QemuExitCode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+impl QemuExitCode {
+    /// Exit status of QEMU after the kernel wrote `self`. The device exits
+    /// with `(value << 1) | 1` so that it can't be confused with QEMU's own
+    /// exit statuses.
+    pub fn exit_status(self) -> i32 {
+        ((self as i32) << 1) | 1
+    }
+
+    pub fn from_exit_status(status: i32) -> Option<Self> {
+        [Self::Success, Self::Failed]
+            .into_iter()
+            .find(|code| code.exit_status() == status)
+    }
+}
+
+/// Stages of the boot process, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootStage {
+    /// Has no space left to log, reaching stage2 implies it ran
+    Stage1,
+    Stage2,
+    Stage3,
+    Stage4,
+    Kernel,
+}
+
+impl BootStage {
+    pub const ALL: [BootStage; 5] = [
+        Self::Stage1,
+        Self::Stage2,
+        Self::Stage3,
+        Self::Stage4,
+        Self::Kernel,
+    ];
+
+    /// Line printed when the stage starts
+    pub fn marker(self) -> &'static str {
+        match self {
+            Self::Stage1 | Self::Stage2 => "Stage2",
+            Self::Stage3 => "Stage3",
+            Self::Stage4 => "Stage4",
+            Self::Kernel => "Initializing kernel",
+        }
+    }
+}
+
+/// Results reported by the test framework of the kernel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestSummary {
+    pub passed: usize,
+    pub failed: usize,
+    /// Names of the failed tests
+    pub failures: Vec<String>,
+}
+
+impl TestSummary {
+    /// Parses the `test result: ok. 2 passed; 0 failed` line, None if the
+    /// kernel didn't finish running its tests
+    pub fn parse(serial: &str) -> Option<Self> {
+        let mut summary = None;
+        let mut failures = Vec::new();
+        for line in serial.lines() {
+            if let Some(name) = line
+                .strip_prefix("test ")
+                .and_then(|l| l.strip_suffix(" ... FAILED"))
+            {
+                failures.push(name.to_string());
+            }
+            if let Some(result) = line.strip_prefix("test result: ") {
+                let (_, counts) = result.split_once(". ")?;
+                let (passed, failed) = counts.split_once("; ")?;
+                summary = Some((
+                    passed.strip_suffix(" passed")?.parse().ok()?,
+                    failed.strip_suffix(" failed")?.parse().ok()?,
+                ));
+            }
+        }
+        summary.map(|(passed, failed)| Self {
+            passed,
+            failed,
+            failures,
+        })
+    }
+}
+
+/// A disk image to boot in QEMU
+pub struct TestKernel {
+    image: PathBuf,
+    timeout: Duration,
+    serial_log: Option<PathBuf>,
+    debugcon: bool,
+    user_network: bool,
+    args: Vec<String>,
+}
+
+impl TestKernel {
+    pub fn new(image: impl Into<PathBuf>) -> Self {
+        Self {
+            image: image.into(),
+            timeout: DEFAULT_TIMEOUT,
+            serial_log: None,
+            debugcon: false,
+            user_network: false,
+            args: Vec::new(),
+        }
+    }
+
+    /// Kills QEMU if the kernel didn't exit after `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Also writes the serial output to `path`
+    pub fn serial_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.serial_log = Some(path.into());
+        self
+    }
+
+    /// Captures the output written to the debug port (0xe9)
+    pub fn debugcon(mut self) -> Self {
+        self.debugcon = true;
+        self
+    }
+
+    /// Attaches an e1000 card to QEMU's user networking, otherwise the
+    /// kernel runs without a network card
+    pub fn user_network(mut self) -> Self {
+        self.user_network = true;
+        self
+    }
+
+    /// Additional QEMU argument
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Boots the image and waits until the kernel exits or times out
+    pub fn run(self) -> TestOutcome {
+        let mut cmd = Command::new("qemu-system-x86_64");
+        cmd.arg("-drive")
+            .arg(format!("format=raw,file={}", self.image.display()));
+        cmd.arg("-no-reboot");
+        cmd.arg("-nographic");
+        cmd.arg("-monitor").arg("/dev/null");
+        cmd.arg("-device").arg(format!(
+            "isa-debug-exit,iobase={:#x},iosize=0x04",
+            DEBUG_EXIT_IOBASE
+        ));
+        if self.user_network {
+            cmd.arg("-netdev").arg("user,id=net0");
+            cmd.arg("-device").arg("e1000,netdev=net0");
+        } else {
+            cmd.arg("-nic").arg("none");
+        }
+        if env::consts::OS == "linux" {
+            cmd.arg("-enable-kvm");
+        }
+        let debugcon = self.debugcon.then(|| {
+            static RUNS: AtomicUsize = AtomicUsize::new(0);
+            let path = env::temp_dir().join(format!(
+                "miniatureos-debugcon-{}-{}.log",
+                process::id(),
+                RUNS.fetch_add(1, Ordering::SeqCst)
+            ));
+            cmd.arg("-debugcon").arg(format!("file:{}", path.display()));
+            path
+        });
+        cmd.args(&self.args);
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let start = Instant::now();
+        let mut child = cmd.spawn().expect("failed to execute qemu");
+        // read on other threads so QEMU never blocks on a full pipe
+        let stdout = read_to_end(child.stdout.take().unwrap());
+        let stderr = read_to_end(child.stderr.take().unwrap());
+
+        let mut timed_out = false;
+        let status = loop {
+            if let Some(status) = child.try_wait().expect("failed to wait for qemu") {
+                break status;
+            }
+            if start.elapsed() >= self.timeout {
+                timed_out = true;
+                let _ = child.kill();
+                break child.wait().expect("failed to wait for qemu");
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        let outcome = TestOutcome {
+            image: self.image,
+            status: status.code(),
+            timed_out,
+            duration: start.elapsed(),
+            serial: String::from_utf8_lossy(&stdout.join().unwrap()).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr.join().unwrap()).into_owned(),
+            debugcon: debugcon
+                .map(|path| {
+                    let data = fs::read(&path).unwrap_or_default();
+                    let _ = fs::remove_file(&path);
+                    String::from_utf8_lossy(&data).into_owned()
+                })
+                .unwrap_or_default(),
+        };
+        if let Some(path) = self.serial_log {
+            fs::write(&path, &outcome.serial)
+                .unwrap_or_else(|e| panic!("failed to write {}: {}", path.display(), e));
+        }
+        outcome
+    }
+}
+
+fn read_to_end<R: Read + Send + 'static>(mut reader: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        let _ = reader.read_to_end(&mut data);
+        data
+    })
+}
+
+/// Result of a test kernel run. The assertions panic with the serial output
+/// and can be chained.
+#[derive(Debug)]
+pub struct TestOutcome {
+    pub image: PathBuf,
+    /// Exit status of QEMU, None if it was killed
+    pub status: Option<i32>,
+    pub timed_out: bool,
+    pub duration: Duration,
+    pub serial: String,
+    pub stderr: String,
+    /// Output written to the debug port, empty unless captured
+    pub debugcon: String,
+}
+
+impl TestOutcome {
+    /// Exit code written by the kernel, None if it didn't write one
+    pub fn exit_code(&self) -> Option<QemuExitCode> {
+        self.status.and_then(QemuExitCode::from_exit_status)
+    }
+
+    pub fn assert_exit_code(&self, expected: QemuExitCode) -> &Self {
+        if self.exit_code() != Some(expected) {
+            self.fail(format_args!("expected exit code {:?}", expected));
+        }
+        self
+    }
+
+    pub fn summary(&self) -> Option<TestSummary> {
+        TestSummary::parse(&self.serial)
+    }
+
+    /// Asserts that the kernel ran all its tests and none failed
+    pub fn assert_all_passed(&self) -> &Self {
+        match self.summary() {
+            None => self.fail(format_args!("no test summary")),
+            Some(summary) if summary.failed > 0 => self.fail(format_args!(
+                "{} tests failed: {}",
+                summary.failed,
+                summary.failures.join(", ")
+            )),
+            Some(_) => self.assert_success(),
+        }
+    }
+
+    pub fn assert_success(&self) -> &Self {
+        self.assert_exit_code(QemuExitCode::Success)
+    }
+
+    pub fn assert_failed(&self) -> &Self {
+        self.assert_exit_code(QemuExitCode::Failed)
+    }
+
+    pub fn assert_serial_contains(&self, text: &str) -> &Self {
+        if !self.serial.contains(text) {
+            self.fail(format_args!("serial output doesn't contain {:?}", text));
+        }
+        self
+    }
+
+    pub fn assert_debugcon_contains(&self, text: &str) -> &Self {
+        if !self.debugcon.contains(text) {
+            self.fail(format_args!("debugcon output doesn't contain {:?}", text));
+        }
+        self
+    }
+
+    /// Whether the boot got to `stage`
+    pub fn reached(&self, stage: BootStage) -> bool {
+        self.stage_position(stage).is_some()
+    }
+
+    pub fn assert_reached(&self, stage: BootStage) -> &Self {
+        if !self.reached(stage) {
+            self.fail(format_args!("boot didn't reach {:?}", stage));
+        }
+        self
+    }
+
+    /// Asserts that all boot stages ran, in order. Needs
+    /// [`TestKernel::debugcon`].
+    pub fn assert_boot_sequence(&self) -> &Self {
+        let mut last = None;
+        for stage in BootStage::ALL {
+            let position = self.stage_position(stage);
+            if position.is_none() || position < last {
+                self.fail(format_args!("boot didn't reach {:?} in order", stage));
+            }
+            last = position;
+        }
+        self
+    }
+
+    /// Position of the stage marker in the output. Stage2 logs to the debug
+    /// port, which comes before everything on serial.
+    fn stage_position(&self, stage: BootStage) -> Option<usize> {
+        match stage {
+            BootStage::Stage1 | BootStage::Stage2 => self.debugcon.find(stage.marker()),
+            _ => self
+                .serial
+                .find(stage.marker())
+                .map(|i| self.debugcon.len() + i),
+        }
+    }
+
+    pub fn assert_serial_not_contains(&self, text: &str) -> &Self {
+        if self.serial.contains(text) {
+            self.fail(format_args!("serial output contains {:?}", text));
+        }
+        self
+    }
+
+    fn fail(&self, reason: fmt::Arguments) -> ! {
+        let result = match (self.timed_out, self.exit_code()) {
+            (true, _) => format!("timed out after {:?}", self.duration),
+            (false, Some(code)) => format!("exited with {:?}", code),
+            (false, None) => format!("exited with status {:?}", self.status),
+        };
+        panic!(
+            "test kernel {} failed: {}, {}\ndebugcon:\n{}\nserial:\n{}\nstderr:\n{}",
+            self.image.display(),
+            reason,
+            result,
+            self.debugcon,
+            self.serial,
+            self.stderr
+        );
+    }
+}
+
+/// Boots `img_path` and asserts that all tests of the kernel passed
 pub fn run_test_kernel(img_path: &str) {
-    let mut cmd = std::process::Command::new("qemu-system-x86_64");
-    cmd.arg("-drive").arg(format!("format=raw,file={img_path}"));
-    cmd.arg("-no-reboot");
-    cmd.arg("-nographic");
-    cmd.arg("-monitor").arg("/dev/null");
-    cmd.arg("-device")
-        .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
-    if env::consts::OS == "linux" {
-        cmd.arg("-enable-kvm");
-    }
-
-    let output = cmd.output().expect("failed to execute qemu");
-    assert_eq!(
-        output.status.code(),
-        Some(33),
-        "test failed:\nstdout:\n{}\nstderr:\n{}",
-        String::from_utf8_lossy(&output.stdout),
-        String::from_utf8_lossy(&output.stderr)
-    ); // 33 = success, 35 = failure. Idk why
-
-    println!("{}", String::from_utf8_lossy(&output.stdout));
+    let outcome = TestKernel::new(img_path).run();
+    outcome.assert_all_passed();
+    println!("{}", outcome.serial);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_status() {
+        assert_eq!(QemuExitCode::Success.exit_status(), 33);
+        assert_eq!(QemuExitCode::Failed.exit_status(), 35);
+        assert_eq!(
+            QemuExitCode::from_exit_status(35),
+            Some(QemuExitCode::Failed)
+        );
+        assert_eq!(QemuExitCode::from_exit_status(1), None);
+    }
+
+    #[test]
+    fn boot_sequence() {
+        let outcome = TestOutcome {
+            image: PathBuf::from("test.img"),
+            status: QemuExitCode::Success.exit_status().into(),
+            timed_out: false,
+            duration: Duration::ZERO,
+            serial: "Stage3\nStage4\n[INFO] Initializing kernel\n".to_string(),
+            stderr: String::new(),
+            debugcon: "Stage2 \r\n".to_string(),
+        };
+        outcome.assert_boot_sequence();
+
+        let outcome = TestOutcome {
+            serial: "Stage4\nStage3\n".to_string(),
+            ..outcome
+        };
+        assert!(outcome.reached(BootStage::Stage4));
+        assert!(!outcome.reached(BootStage::Kernel));
+    }
+
+    #[test]
+    fn parse_summary() {
+        let serial = "running 2 tests\n\
+                      test a::b ... ok\n\
+                      test a::c ... FAILED\n\
+                      panicked at src/main.rs:1:1\n\
+                      test result: FAILED. 1 passed; 1 failed\n";
+        assert_eq!(
+            TestSummary::parse(serial),
+            Some(TestSummary {
+                passed: 1,
+                failed: 1,
+                failures: vec!["a::c".to_string()],
+            })
+        );
+        assert_eq!(TestSummary::parse("test a::b ... ok\n"), None);
+    }
 }
@@ -1,18 +1,86 @@
-use std::env;
-fn main() {
+use clap::{Parser, ValueEnum};
+use std::{env, path::PathBuf, process::ExitCode};
+
+/// Boots MiniatureOS in QEMU
+#[derive(Debug, Parser)]
+struct Args {
+    /// Guest memory, e.g. 512M or 4G
+    #[arg(long, default_value = "128M")]
+    mem: String,
+    /// Number of CPUs
+    #[arg(long, default_value_t = 1)]
+    smp: u32,
+    #[arg(long, value_enum, default_value_t = Display::Window)]
+    display: Display,
+    /// Additional raw disk image, can be given multiple times
+    #[arg(long = "drive", value_name = "IMAGE")]
+    drives: Vec<PathBuf>,
+    /// Stop at boot until a debugger attached to the gdb server on port 1234
+    #[arg(long)]
+    gdb: bool,
+    /// Boot with the given OVMF firmware instead of the BIOS
+    #[arg(long, value_name = "OVMF")]
+    uefi: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Display {
+    /// QEMU's default window
+    Window,
+    /// Headless, only the serial console
+    None,
+    /// Text mode output in the terminal
+    Curses,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    if args.uefi.is_some() {
+        // the bootloader only builds BIOS images, OVMF can't boot them
+        eprintln!("UEFI images are not supported yet");
+        return ExitCode::FAILURE;
+    }
+
     // read env variables that were set in build script
     let bios_path = env!("BIOS_PATH");
 
     let mut cmd = std::process::Command::new("qemu-system-x86_64");
     cmd.arg("-drive")
         .arg(format!("format=raw,file={bios_path}"));
+    for drive in &args.drives {
+        cmd.arg("-drive")
+            .arg(format!("format=raw,file={}", drive.display()));
+    }
+    cmd.arg("-m").arg(&args.mem);
+    cmd.arg("-smp").arg(args.smp.to_string());
+    match args.display {
+        Display::Window => (),
+        Display::None => {
+            cmd.arg("-display").arg("none");
+        }
+        Display::Curses => {
+            cmd.arg("-display").arg("curses");
+        }
+    }
     cmd.arg("-no-reboot");
     cmd.arg("-monitor").arg("/dev/null");
+    // COM1 is the kernel console, COM2 is used by the gdb stub
+    cmd.arg("-serial").arg("stdio");
+    cmd.arg("-serial").arg("tcp::1235,server,nowait");
     if env::consts::OS == "linux" {
         cmd.arg("-enable-kvm");
     }
     cmd.arg("-s");
+    if args.gdb {
+        cmd.arg("-S");
+    }
 
     let mut child = cmd.spawn().unwrap();
-    child.wait().unwrap();
+    let status = child.wait().unwrap();
+    match status.code() {
+        Some(0) => ExitCode::SUCCESS,
+        Some(code) => ExitCode::from(code as u8),
+        // killed by a signal
+        None => ExitCode::FAILURE,
+    }
 }
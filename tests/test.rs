@@ -1,5 +1,150 @@
-use MiniatureOs::run_test_kernel;
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+use MiniatureOs::TestKernel;
 #[test]
 fn test_kernel_unittests() {
-    run_test_kernel(env!("TEST_KERNEL_UNITTESTS_BIOS_PATH"));
+    TestKernel::new(env!("TEST_KERNEL_UNITTESTS_BIOS_PATH"))
+        .run()
+        .assert_serial_contains("Hello from test kernel")
+        .assert_all_passed();
+}
+
+#[test]
+fn test_boot_stages() {
+    TestKernel::new(env!("TEST_KERNEL_UNITTESTS_BIOS_PATH"))
+        .debugcon()
+        .run()
+        .assert_boot_sequence()
+        .assert_debugcon_contains("Memory region, start: 0x0,")
+        .assert_debugcon_contains("VESA mode:")
+        .assert_serial_contains("Switching to kernel entry point");
+}
+
+/// Boot partition of a disk image, offsets are relative to its start
+struct Partition {
+    disk: fs::File,
+    start: u64,
+}
+
+impl Partition {
+    /// Opens the FAT partition of the image at `path`
+    fn open(path: &str) -> Self {
+        let mut disk = fs::File::open(path).unwrap();
+        let mut mbr = [0u8; 512];
+        disk.read_exact(&mut mbr).unwrap();
+        // the boot partition is the third entry of the partition table
+        let entry = &mbr[446 + 2 * 16..446 + 3 * 16];
+        let start_lba = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+        Self {
+            disk,
+            start: start_lba as u64 * 512,
+        }
+    }
+}
+
+impl Read for Partition {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.disk.read(buf)
+    }
+}
+
+impl Write for Partition {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::from(io::ErrorKind::PermissionDenied))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Partition {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => SeekFrom::Start(self.start + offset),
+            other => other,
+        };
+        Ok(self.disk.seek(pos)? - self.start)
+    }
+}
+
+#[test]
+fn test_fat32_image() {
+    let mut partition = Partition::open(env!("FAT32_BIOS_PATH"));
+    partition.seek(SeekFrom::Start(0)).unwrap();
+    let fs = fatfs::FileSystem::new(partition, fatfs::FsOptions::new()).unwrap();
+    assert_eq!(fs.fat_type(), fatfs::FatType::Fat32);
+    assert_eq!(fs.volume_label(), "MINIATURE");
+
+    let mut kernel = Vec::new();
+    fs.root_dir()
+        .open_file("boot/kernel.elf")
+        .unwrap()
+        .read_to_end(&mut kernel)
+        .unwrap();
+    assert_eq!(kernel, fs::read(env!("FAT32_KERNEL_PATH")).unwrap());
+
+    // stage2 has to follow the cluster chain of the FAT32 root directory
+    TestKernel::new(env!("FAT32_BIOS_PATH"))
+        .run()
+        .assert_all_passed();
+}
+
+#[test]
+fn test_kernel_frame_allocator() {
+    TestKernel::new(env!("TEST_KERNEL_FRAME_ALLOCATOR_BIOS_PATH"))
+        .run()
+        .assert_all_passed();
+}
+
+#[test]
+fn test_kernel_memory() {
+    TestKernel::new(env!("TEST_KERNEL_MEMORY_BIOS_PATH"))
+        .run()
+        .assert_all_passed();
+}
+
+#[test]
+fn test_kernel_scheduler() {
+    TestKernel::new(env!("TEST_KERNEL_SCHEDULER_BIOS_PATH"))
+        .run()
+        .assert_all_passed();
+}
+
+#[test]
+fn test_kernel_sync() {
+    TestKernel::new(env!("TEST_KERNEL_SYNC_BIOS_PATH"))
+        .run()
+        .assert_all_passed();
+}
+
+#[test]
+fn test_kernel_process() {
+    TestKernel::new(env!("TEST_KERNEL_PROCESS_BIOS_PATH"))
+        .run()
+        .assert_all_passed();
+}
+
+#[test]
+fn test_kernel_fs() {
+    TestKernel::new(env!("TEST_KERNEL_FS_BIOS_PATH"))
+        .run()
+        .assert_all_passed();
+}
+
+#[test]
+fn test_kernel_net() {
+    TestKernel::new(env!("TEST_KERNEL_NET_BIOS_PATH"))
+        .run()
+        .assert_all_passed();
+}
+
+#[test]
+fn test_kernel_nic() {
+    TestKernel::new(env!("TEST_KERNEL_NIC_BIOS_PATH"))
+        .user_network()
+        .run()
+        .assert_all_passed();
 }
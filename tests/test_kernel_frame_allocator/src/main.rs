@@ -0,0 +1,298 @@
+#![no_std]
+#![no_main]
+use api::BootInfo;
+use core::panic::PanicInfo;
+use kernel::{
+    acpi, kernel_init, kernel_test,
+    memory::{self, with_memory_manager, KernelFrameAllocator, VirtualMemoryObject},
+    paging, test,
+};
+use x86_64::{
+    memory::{
+        FrameAllocator, FrameDeallocator, MemoryRegion, Page, PageSize, PhysicalFrame,
+        PhysicalMemoryRegionType, Size2MiB, Size4KiB, VirtualAddress,
+    },
+    paging::{
+        recursive_page_table::RecursivePageTable, CacheAttribute, Mapper, PageTableEntryFlags,
+        Translator,
+    },
+};
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    test::panicked(info)
+}
+
+#[no_mangle]
+#[link_section = ".start"]
+pub extern "C" fn _start(info: &'static BootInfo) -> ! {
+    start(info);
+}
+
+fn start(info: &'static BootInfo) -> ! {
+    kernel_init(info).unwrap();
+    test::run()
+}
+
+/// xorshift64, deterministic so failures can be reproduced
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+/// Runs `f` with the frame allocator locked, so nothing else allocates in
+/// between, and checks that freeing everything restored the free blocks
+fn with_frame_allocator<F: FnOnce(&mut KernelFrameAllocator)>(f: F) {
+    with_memory_manager(|mm| {
+        let allocator = mm.frame_allocator();
+        let before = allocator.stats();
+        f(allocator);
+        assert_eq!(allocator.stats(), before);
+    })
+}
+
+fn test_free_single_frames() {
+    with_frame_allocator(|allocator| {
+        let mut frames = [None; 512];
+        for slot in frames.iter_mut() {
+            *slot = Some(allocator.try_allocate(1, Size4KiB::SIZE).unwrap());
+        }
+        // every other frame first, so nothing can be merged until the end
+        for start in [0, 1] {
+            for frame in frames.iter().skip(start).step_by(2) {
+                unsafe { allocator.deallocate_frame(frame.unwrap()) };
+            }
+        }
+    })
+}
+
+fn test_split_allocation() {
+    with_frame_allocator(|allocator| {
+        // freed in pieces other than the ones that were allocated
+        let frame = allocator.try_allocate(100, Size4KiB::SIZE).unwrap();
+        unsafe {
+            allocator.deallocate_contiguous(frame + 37, 63);
+            allocator.deallocate_contiguous(frame, 37);
+        }
+    })
+}
+
+fn test_allocate_below() {
+    with_frame_allocator(|allocator| {
+        let limit = 16 * 1024 * 1024;
+        let frame = allocator.try_allocate_below(64, 0x10000, limit).unwrap();
+        assert_eq!(frame.start() % 0x10000, 0);
+        assert!(frame.start() + 64 * Size4KiB::SIZE <= limit);
+        unsafe { allocator.deallocate_contiguous(frame, 64) };
+    })
+}
+
+fn test_run_across_blocks() {
+    const LIMIT: u64 = 2 * 1024 * 1024;
+
+    with_frame_allocator(|allocator| {
+        // take every frame below the limit
+        let mut frames = [None; (LIMIT / Size4KiB::SIZE) as usize];
+        for slot in frames.iter_mut() {
+            match allocator.try_allocate_below(1, Size4KiB::SIZE, LIMIT) {
+                Ok(frame) => *slot = Some(frame),
+                Err(_) => break,
+            }
+        }
+
+        // free 3 frames that are contiguous but no block of 4
+        let index = |frame: PhysicalFrame| frame.start() / Size4KiB::SIZE;
+        let held = |i: u64| frames.iter().flatten().any(|f| index(*f) == i);
+        let first = frames
+            .iter()
+            .flatten()
+            .map(|f| index(*f))
+            .find(|&i| i % 4 == 0 && (1..4).all(|n| held(i + n)))
+            .expect("No 4 frames below the limit");
+        for slot in frames.iter_mut() {
+            if slot.is_some_and(|f| (first + 1..first + 4).contains(&index(f))) {
+                unsafe { allocator.deallocate_frame(slot.take().unwrap()) };
+            }
+        }
+
+        let run = allocator
+            .try_allocate_below(3, Size4KiB::SIZE, LIMIT)
+            .unwrap();
+        assert_eq!(index(run), first + 1);
+        assert!(allocator
+            .try_allocate_below(1, Size4KiB::SIZE, LIMIT)
+            .is_err());
+
+        unsafe { allocator.deallocate_contiguous(run, 3) };
+        for frame in frames.iter().flatten() {
+            unsafe { allocator.deallocate_frame(*frame) };
+        }
+    })
+}
+
+fn test_random_stress() {
+    const SLOTS: usize = 64;
+
+    with_frame_allocator(|allocator| {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut live: [Option<(PhysicalFrame, usize)>; SLOTS] = [None; SLOTS];
+
+        for _ in 0..20_000 {
+            let slot = rng.next(SLOTS as u64) as usize;
+            if let Some((frame, count)) = live[slot].take() {
+                unsafe { allocator.deallocate_contiguous(frame, count) };
+                continue;
+            }
+
+            let count = 1 + rng.next(64) as usize;
+            let alignment = Size4KiB::SIZE << rng.next(5);
+            let frame = allocator.try_allocate(count, alignment).unwrap();
+            assert_eq!(frame.start() % alignment, 0);
+
+            let end = frame.start() + count as u64 * Size4KiB::SIZE;
+            for (other, other_count) in live.iter().flatten() {
+                let other_end = other.start() + *other_count as u64 * Size4KiB::SIZE;
+                assert!(end <= other.start() || other_end <= frame.start());
+            }
+            live[slot] = Some((frame, count));
+        }
+
+        for (frame, count) in live.iter().flatten() {
+            unsafe { allocator.deallocate_contiguous(*frame, *count) };
+        }
+    })
+}
+
+fn test_contiguous_frames() {
+    memory::with_memory_manager(|mm| {
+        let allocator = mm.frame_allocator();
+        let free = allocator.free_frames();
+
+        let first = allocator
+            .allocate_contiguous(16, Size2MiB::SIZE)
+            .expect("No contiguous frames left");
+        assert_eq!(first.start() % Size2MiB::SIZE, 0);
+        assert_eq!(allocator.free_frames(), free - 16);
+
+        // a freed range is handed out again
+        unsafe { allocator.deallocate_contiguous(first, 16) };
+        assert_eq!(allocator.free_frames(), free);
+        assert_eq!(
+            allocator.allocate_contiguous(16, Size2MiB::SIZE),
+            Some(first)
+        );
+
+        // mapped at once, crossing into the next L1 table
+        let frames = PhysicalFrame::<Size4KiB>::range_inclusive(first, first + 15);
+        let page = Page::<Size4KiB>::containing_address(VirtualAddress::new(0x7000_001f_8000));
+        let pages = Page::range_inclusive(page, page + 15);
+        let mut recursive = unsafe { RecursivePageTable::active(paging::recursive_index()) }
+            .expect("Recursive entry missing");
+        recursive
+            .map_range(
+                frames,
+                pages,
+                PageTableEntryFlags::PRESENT,
+                CacheAttribute::WriteBack,
+                mm.frame_allocator(),
+            )
+            .unwrap()
+            .flush();
+        for (page, frame) in pages.zip(frames) {
+            assert_eq!(mm.page_table().translate(page).unwrap().0, frame);
+            let (_, flusher) = Mapper::<Size4KiB>::unmap(&mut recursive, page).unwrap();
+            flusher.flush();
+        }
+
+        let allocator = mm.frame_allocator();
+        unsafe { allocator.deallocate_contiguous(first, 16) };
+    });
+
+    // frames of unmapped regions are freed
+    memory::with_memory_manager(|mm| {
+        let address = mm
+            .allocate(
+                4 * Size4KiB::SIZE,
+                PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
+                VirtualMemoryObject::Anonymous,
+            )
+            .unwrap();
+        let free = mm.frame_allocator().free_frames();
+        mm.munmap(address).unwrap();
+        assert_eq!(mm.frame_allocator().free_frames(), free + 4);
+    });
+}
+
+fn test_frame_stats() {
+    let stats = memory::frame_stats();
+    assert_eq!(stats.total, stats.free + stats.allocated + stats.reserved);
+    assert!(stats.largest_free <= stats.free);
+
+    memory::with_memory_manager(|mm| {
+        let allocator = mm.frame_allocator();
+        let free = allocator.region_usage().map(|u| u.free).sum::<usize>();
+        assert_eq!(free, stats.free);
+
+        let error = allocator
+            .try_allocate(stats.largest_free + 1, Size4KiB::SIZE)
+            .unwrap_err();
+        assert_eq!(error.requested, stats.largest_free + 1);
+        assert_eq!(error.stats, stats);
+    });
+}
+
+fn test_bootloader_memory() {
+    memory::with_memory_manager(|mm| {
+        let allocator = mm.frame_allocator();
+        // kernel_init already took it over
+        assert_eq!(unsafe { allocator.reclaim_bootloader_memory() }, 0);
+
+        let reclaimed = allocator
+            .region_usage()
+            .filter(|u| u.region.typ == PhysicalMemoryRegionType::BootloaderReclaimable)
+            .map(|u| u.total)
+            .sum::<usize>();
+        let total = allocator.region_usage().map(|u| u.total).sum::<usize>();
+        assert_eq!(total, allocator.stats().total);
+        assert!(reclaimed < total);
+    });
+}
+
+fn test_reserved_memory() {
+    memory::with_memory_manager(|mm| {
+        let allocator = mm.frame_allocator();
+        // kernel_init already copied the tables
+        assert_eq!(unsafe { allocator.reclaim_acpi_memory() }, 0);
+
+        // legacy VGA memory and the local APIC are never handed out
+        for usage in allocator.region_usage() {
+            let region = usage.region;
+            assert!(region.end() <= 0xa0000 || region.start() >= 0x100000);
+            assert!(!region.contains(0xfee0_0000));
+        }
+    });
+
+    // QEMU has an I/O APIC, its tables are still readable from the copies
+    assert!(acpi::io_apics().next().is_some());
+    assert!(acpi::find_table(b"FACP").is_some());
+}
+
+kernel_test!(
+    test_free_single_frames,
+    test_split_allocation,
+    test_allocate_below,
+    test_run_across_blocks,
+    test_random_stress
+);
+kernel_test!(
+    test_contiguous_frames,
+    test_frame_stats,
+    test_bootloader_memory,
+    test_reserved_memory
+);
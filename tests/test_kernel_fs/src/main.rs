@@ -0,0 +1,259 @@
+#![no_std]
+#![no_main]
+use api::{
+    initramfs::{self, EntryKind},
+    BootInfo,
+};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use kernel::{
+    fs::{self, devfs, vfs, File, FileDescriptor, FsError, NodeKind},
+    kernel_init, kernel_test,
+    memory::{self, VirtualMemoryObject},
+    process,
+    scheduler::ThreadPriority,
+    syscall::{self, Errno, Syscall},
+    test,
+};
+use x86_64::{
+    memory::{PageSize, Size4KiB},
+    paging::PageTableEntryFlags,
+};
+
+extern crate alloc;
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    test::panicked(info)
+}
+
+#[no_mangle]
+#[link_section = ".start"]
+pub extern "C" fn _start(info: &'static BootInfo) -> ! {
+    start(info);
+}
+
+fn start(info: &'static BootInfo) -> ! {
+    kernel_init(info).unwrap();
+    test::run()
+}
+
+fn open(path: &str) -> Result<FileDescriptor, Errno> {
+    unsafe { syscall::syscall3(Syscall::Open, path.as_ptr() as u64, path.len() as u64, 0) }
+        .map(FileDescriptor::from_u64)
+}
+
+struct Counter(AtomicU64);
+
+impl devfs::Device for Counter {
+    fn open(&self) -> Result<Arc<dyn File>, FsError> {
+        Ok(Arc::new(Counter(AtomicU64::new(
+            self.0.fetch_add(1, Ordering::SeqCst),
+        ))))
+    }
+}
+
+impl File for Counter {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        buf[0] = self.0.load(Ordering::SeqCst) as u8;
+        Ok(1)
+    }
+}
+
+fn test_devfs() {
+    assert_eq!(vfs::lookup("/dev").unwrap().kind(), NodeKind::Directory);
+    assert_eq!(
+        vfs::lookup("/dev/null").unwrap().kind(),
+        NodeKind::CharDevice
+    );
+    assert_eq!(open("/dev/missing"), Err(Errno::ENOENT));
+    assert_eq!(open("dev/null"), Err(Errno::EINVAL));
+
+    let mut buf = [0xffu8; 16];
+    let null = open("/dev/null").unwrap();
+    assert_eq!(fs::write(null, &buf), Ok(16));
+    assert_eq!(fs::read(null, &mut buf), Ok(0));
+    fs::close(null).unwrap();
+
+    let zero = open("//dev/./zero").unwrap();
+    assert_eq!(fs::read(zero, &mut buf), Ok(16));
+    assert!(buf.iter().all(|b| *b == 0));
+    fs::close(zero).unwrap();
+
+    let random = open("/dev/random").unwrap();
+    let mut other = [0u8; 16];
+    fs::read(random, &mut buf).unwrap();
+    fs::read(random, &mut other).unwrap();
+    assert_ne!(buf, other);
+    fs::close(random).unwrap();
+
+    devfs::register("counter", Arc::new(Counter(AtomicU64::new(7)))).unwrap();
+    assert!(devfs::register("counter", Arc::new(Counter(AtomicU64::new(0)))).is_err());
+    let entries = vfs::lookup("/dev").unwrap().entries().unwrap();
+    assert!(entries.iter().any(|e| e == "counter"));
+    assert!(entries.iter().any(|e| e == "keyboard"));
+
+    for expected in 7..9 {
+        let counter = open("/dev/counter").unwrap();
+        assert_eq!(fs::read(counter, &mut buf), Ok(1));
+        assert_eq!(buf[0], expected);
+        fs::close(counter).unwrap();
+    }
+    devfs::unregister("counter").unwrap();
+    assert_eq!(open("/dev/counter"), Err(Errno::ENOENT));
+}
+
+fn read_to_string(path: &str) -> String {
+    let file = vfs::open(path).unwrap();
+    let mut contents = Vec::new();
+    let mut buf = [0u8; 64];
+    loop {
+        match file.read(&mut buf).unwrap() {
+            0 => break,
+            len => contents.extend_from_slice(&buf[..len]),
+        }
+    }
+    String::from_utf8(contents).unwrap()
+}
+
+fn test_procfs() {
+    let entries = vfs::lookup("/proc").unwrap().entries().unwrap();
+    assert!(entries.iter().any(|e| e == "interrupts"));
+    assert!(entries.iter().any(|e| e == "0"));
+    assert_eq!(vfs::open("/proc/missing").err(), Some(FsError::NotFound));
+
+    let uptime = read_to_string("/proc/uptime");
+    let (up, idle) = uptime.trim_end().split_once(' ').unwrap();
+    let centiseconds = |s: &str| {
+        let (secs, centis) = s.split_once('.').unwrap();
+        assert_eq!(centis.len(), 2);
+        secs.parse::<u64>().unwrap() * 100 + centis.parse::<u64>().unwrap()
+    };
+    assert!(centiseconds(up) > 0);
+    assert!(centiseconds(idle) <= centiseconds(up));
+
+    // the scheduler tick has to show up in the counts
+    let interrupts = read_to_string("/proc/interrupts");
+    let ticks: u64 = interrupts
+        .lines()
+        .filter(|l| l.ends_with("timer_interrupt_handler") || l.ends_with("apic_timer_handler"))
+        .map(|l| l.split_whitespace().nth(1).unwrap().parse::<u64>().unwrap())
+        .sum();
+    assert!(ticks > 0);
+
+    let status = read_to_string("/proc/0/status");
+    assert!(status.starts_with("Name:\tkernel\nPid:\t0\n"));
+    assert!(status.contains("State:\trunning"));
+
+    let pid = process::spawn(|| 0, ThreadPriority::Normal).unwrap();
+    let path = format!("/proc/{}/status", pid);
+    assert!(read_to_string(&path).contains("PPid:\t0"));
+    process::waitpid(Some(pid)).unwrap();
+    assert_eq!(vfs::open(&path).err(), Some(FsError::NotFound));
+}
+
+fn test_tmpfs() {
+    vfs::create("/tmp", NodeKind::Directory).unwrap();
+    vfs::create("/tmp/dir", NodeKind::Directory).unwrap();
+    assert_eq!(
+        vfs::create("/tmp/dir", NodeKind::File).err(),
+        Some(FsError::AlreadyExists)
+    );
+
+    let file = fs::create("/tmp/dir/file").unwrap();
+    assert_eq!(fs::write(file, b"hello tmpfs"), Ok(11));
+    fs::get(file).unwrap().seek(6).unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(fs::read(file, &mut buf), Ok(5));
+    assert_eq!(&buf[..5], b"tmpfs");
+    fs::close(file).unwrap();
+
+    assert_eq!(vfs::unlink("/tmp/dir"), Err(FsError::NotEmpty));
+    vfs::rename("/tmp/dir", "/tmp/renamed").unwrap();
+    assert!(vfs::lookup("/tmp/dir/file").is_err());
+    assert_eq!(vfs::lookup("/tmp/renamed/file").unwrap().size(), 11);
+    assert_eq!(
+        vfs::rename("/tmp/renamed", "/tmp/renamed/inner"),
+        Err(FsError::InvalidPath)
+    );
+    assert_eq!(
+        vfs::rename("/tmp/renamed/file", "/dev/file"),
+        Err(FsError::CrossDevice)
+    );
+    vfs::rename("/tmp/renamed/file", "/tmp/file").unwrap();
+    assert_eq!(vfs::lookup("/tmp").unwrap().entries().unwrap().len(), 2);
+
+    // map the file and check that dirty pages are written back
+    let node = vfs::lookup("/tmp/file").unwrap();
+    assert_eq!(node.kind(), NodeKind::File);
+    let contents = node.contents().unwrap();
+    let address = memory::with_memory_manager(|mm| {
+        mm.allocate(
+            Size4KiB::SIZE,
+            PageTableEntryFlags::PRESENT
+                | PageTableEntryFlags::WRITABLE
+                | PageTableEntryFlags::NO_EXECUTE,
+            VirtualMemoryObject::FileBacked {
+                file: contents,
+                offset: 0,
+            },
+        )
+    })
+    .unwrap();
+    let ptr: *mut u8 = address.as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), b'h');
+        ptr.write_volatile(b'j');
+    }
+    memory::with_memory_manager(|mm| mm.munmap(address)).unwrap();
+
+    let file = fs::open("/tmp/file").unwrap();
+    assert_eq!(fs::read(file, &mut buf), Ok(11));
+    assert_eq!(&buf[..11], b"jello tmpfs");
+    fs::close(file).unwrap();
+
+    vfs::unlink("/tmp/file").unwrap();
+    vfs::unlink("/tmp/renamed").unwrap();
+    vfs::unlink("/tmp").unwrap();
+    assert_eq!(vfs::lookup("/tmp").err(), Some(FsError::NotFound));
+}
+
+fn push_initramfs_entry(archive: &mut Vec<u8>, kind: EntryKind, path: &str, data: &[u8]) {
+    archive.push(kind as u8);
+    archive.push(0);
+    archive.extend_from_slice(&(path.len() as u16).to_le_bytes());
+    archive.extend_from_slice(&[0; 4]);
+    archive.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    for part in [path.as_bytes(), data] {
+        archive.extend_from_slice(part);
+        archive.resize(initramfs::align_up(archive.len()), 0);
+    }
+}
+
+fn test_initramfs() {
+    let mut archive = Vec::new();
+    archive.extend_from_slice(&initramfs::MAGIC);
+    push_initramfs_entry(&mut archive, EntryKind::Directory, "initrd", &[]);
+    push_initramfs_entry(&mut archive, EntryKind::Directory, "initrd/etc", &[]);
+    push_initramfs_entry(&mut archive, EntryKind::File, "initrd/etc/motd", b"hello");
+    push_initramfs_entry(&mut archive, EntryKind::End, "", &[]);
+
+    assert_eq!(fs::initramfs::unpack_archive(&archive), Ok(3));
+    let file = fs::open("/initrd/etc/motd").unwrap();
+    let mut buf = [0u8; 8];
+    assert_eq!(fs::read(file, &mut buf), Ok(5));
+    assert_eq!(&buf[..5], b"hello");
+    fs::close(file).unwrap();
+
+    archive[0] = 0;
+    assert!(fs::initramfs::unpack_archive(&archive).is_err());
+
+    vfs::unlink("/initrd/etc/motd").unwrap();
+    vfs::unlink("/initrd/etc").unwrap();
+    vfs::unlink("/initrd").unwrap();
+}
+
+kernel_test!(test_devfs, test_procfs, test_tmpfs, test_initramfs);
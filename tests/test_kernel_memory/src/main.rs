@@ -0,0 +1,555 @@
+#![no_std]
+#![no_main]
+use api::BootInfo;
+use core::{alloc::Layout, mem::size_of, panic::PanicInfo};
+use kernel::{
+    allocator::{CacheBox, KmemCache, ObjectCache, ALLOCATOR, HEAP_SIZE, HEAP_START},
+    kernel_init, kernel_test,
+    memory::{
+        self, dma::DMA_32BIT_LIMIT, MemoryError, VirtualMemoryObject, VirtualRangeAllocator, Zone,
+        VIRTUAL_MEMORY_SIZE, VIRTUAL_MEMORY_START,
+    },
+    paging, test,
+};
+use x86_64::{
+    cpuid::{self, Features},
+    memory::{
+        Address, FrameAllocator, FrameDeallocator, Page, PageSize, PhysicalAddress, PhysicalFrame,
+        Size1GiB, Size2MiB, Size4KiB, VirtualAddress,
+    },
+    mutex::InterruptSafeMutex,
+    paging::{
+        dump, recursive_page_table::RecursivePageTable, CacheAttribute, Mapper,
+        PageTableEntryFlags, PagingLevels, Translator,
+    },
+    println,
+    register::{Cr4, Cr4Flags, MemoryType, Pat},
+};
+
+extern crate alloc;
+use alloc::{boxed::Box, vec, vec::Vec};
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    test::panicked(info)
+}
+
+#[no_mangle]
+#[link_section = ".start"]
+pub extern "C" fn _start(info: &'static BootInfo) -> ! {
+    start(info);
+}
+
+fn start(info: &'static BootInfo) -> ! {
+    kernel_init(info).unwrap();
+    test::run()
+}
+
+fn test_buddy_allocator() {
+    unsafe {
+        let mut allocator = ALLOCATOR.lock();
+        let layout_x100 = Layout::from_size_align(0x100, size_of::<usize>()).unwrap();
+        let layout_x200 = Layout::from_size_align(0x200, size_of::<usize>()).unwrap();
+        let layout_x400 = Layout::from_size_align(0x400, size_of::<usize>()).unwrap();
+
+        // Test easy merge
+        let c1 = allocator.alloc(layout_x100).unwrap();
+        let c2 = allocator.alloc(layout_x100).unwrap();
+
+        let addr = u64::min(c1.as_ref().start(), c2.as_ref().start());
+
+        // c1 and c2 should be merged into 1 0x200 sized chunk
+        allocator.dealloc(c1);
+        allocator.dealloc(c2);
+
+        let c3 = allocator.alloc(layout_x200).unwrap();
+        assert!(c3.as_ref().start() == addr);
+
+        let addr = c3.as_ref().start();
+        allocator.dealloc(c3);
+
+        // Test multistage merge
+
+        // c1 and c2 should be created from the c3 we just deallocated
+        let c1 = allocator.alloc(layout_x100).unwrap();
+        let c2 = allocator.alloc(layout_x100).unwrap();
+
+        assert!(u64::min(c1.as_ref().start(), c2.as_ref().start()) == addr);
+
+        let c3 = allocator.alloc(layout_x200).unwrap();
+        println!(
+            "C3 address: {:#x}, min address before: {:#x}",
+            c3.as_ref().start(),
+            addr
+        );
+        let addr = u64::min(
+            c3.as_ref().start(),
+            u64::min(c1.as_ref().start(), c2.as_ref().start()),
+        );
+        // merge 2* 0x100 into 0x200
+        allocator.dealloc(c1);
+        allocator.dealloc(c2);
+        // free c3 causing it to be merged with the 0x200 chunk created by
+        // deallocating c1 and c2. Should create 1 0x400 sized chunk
+        allocator.dealloc(c3);
+
+        let c4 = allocator.alloc(layout_x400).unwrap();
+
+        assert!(c4.as_ref().start() == addr);
+        assert!(c4.as_ref().start() == addr);
+
+        allocator.dealloc(c4);
+    }
+}
+
+fn test_slab_allocator() {
+    let mut cache: KmemCache<[u64; 4]> = KmemCache::new_poisoned();
+
+    let a = cache.alloc([1; 4]).unwrap();
+    let b = cache.alloc([2; 4]).unwrap();
+    assert!(a != b);
+    assert_eq!(cache.allocated(), 2);
+    assert_eq!(cache.slab_count(), 1);
+
+    unsafe {
+        assert_eq!(*a.as_ref(), [1; 4]);
+        assert_eq!(*b.as_ref(), [2; 4]);
+
+        // slots are reused in LIFO order
+        cache.free(b);
+        let c = cache.alloc([3; 4]).unwrap();
+        assert!(b == c);
+
+        cache.free(a);
+        cache.free(c);
+    }
+    assert_eq!(cache.allocated(), 0);
+
+    static CACHE: ObjectCache<[u64; 4]> = InterruptSafeMutex::new(KmemCache::new());
+    let boxed = CacheBox::new(&CACHE, [5; 4]);
+    assert_eq!(*boxed, [5; 4]);
+    assert_eq!(CACHE.lock().allocated(), 1);
+    drop(boxed);
+    assert_eq!(CACHE.lock().allocated(), 0);
+}
+
+fn test_heap_allocations() {
+    {
+        let heap_value_1 = Box::new(41);
+        let heap_value_2 = Box::new(13);
+        assert_eq!(*heap_value_1, 41);
+        assert_eq!(*heap_value_2, 13);
+
+        let n = 1000;
+        let mut vec = Vec::new();
+        for i in 0..n {
+            vec.push(i);
+        }
+        assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+    }
+
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+fn test_lazy_allocation() {
+    let address = memory::with_memory_manager(|mm| {
+        mm.allocate(
+            4 * Size4KiB::SIZE,
+            PageTableEntryFlags::PRESENT
+                | PageTableEntryFlags::WRITABLE
+                | PageTableEntryFlags::NO_EXECUTE,
+            VirtualMemoryObject::LazyAnonymous,
+        )
+        .expect("Failed to allocate lazy region")
+    });
+
+    // each access should trigger a demand fault which maps a zeroed frame
+    for i in 0..4 {
+        let ptr: *mut u64 = (address + i * Size4KiB::SIZE).as_mut_ptr();
+        unsafe {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(i);
+            assert_eq!(ptr.read_volatile(), i);
+        }
+    }
+
+    memory::with_memory_manager(|mm| mm.munmap(address)).expect("Failed to free lazy region");
+}
+
+fn test_stack_growth() {
+    let top = memory::with_memory_manager(|mm| {
+        mm.allocate_stack(
+            Size4KiB::SIZE,
+            8 * Size4KiB::SIZE,
+            PageTableEntryFlags::PRESENT
+                | PageTableEntryFlags::WRITABLE
+                | PageTableEntryFlags::NO_EXECUTE,
+        )
+        .expect("Failed to allocate stack")
+    });
+
+    // touch every page down to the maximum size, each access below the
+    // current region start grows the stack by one page
+    for i in 1..=8 {
+        let ptr: *mut u64 = (top - i * Size4KiB::SIZE).as_mut_ptr();
+        unsafe {
+            ptr.write_volatile(i);
+            assert_eq!(ptr.read_volatile(), i);
+        }
+    }
+}
+
+fn test_huge_pages() {
+    let physical = PhysicalAddress::new(0x12_3456);
+    let address = paging::physical_to_virtual(physical);
+    assert_eq!(paging::translate(address), Some(physical));
+    let (translated, flags) = memory::with_memory_manager(|mm| mm.translate(address)).unwrap();
+    assert_eq!(translated, physical);
+    assert!(flags.contains(PageTableEntryFlags::HUGE_PAGE | PageTableEntryFlags::PRESENT));
+
+    // physical memory is mapped with the largest supported page size
+    let huge_page = Page::<Size1GiB>::containing_address(address);
+    let result = memory::with_memory_manager(|mm| mm.page_table().translate(huge_page));
+    assert_eq!(result.is_ok(), cpuid::has(Features::PAGE_1GIB));
+    if let Ok((frame, flags)) = result {
+        assert_eq!(frame.address, PhysicalAddress::new(0));
+        assert!(flags.contains(PageTableEntryFlags::HUGE_PAGE));
+    }
+}
+
+fn test_cache_attributes() {
+    let pat = Pat::read();
+    assert_eq!(pat[0], Some(MemoryType::WriteBack));
+    assert_eq!(pat[3], Some(MemoryType::Uncacheable));
+    assert_eq!(pat[4], Some(MemoryType::WriteCombining));
+
+    // the PAT bit moves for huge pages
+    let combining = CacheAttribute::WriteCombining;
+    assert_eq!(combining.flags::<Size4KiB>().bits(), 1 << 7);
+    assert_eq!(combining.flags::<Size1GiB>().bits(), 1 << 12);
+    assert!(CacheAttribute::WriteBack.flags::<Size4KiB>().is_empty());
+
+    let frame = memory::with_memory_manager(|mm| mm.frame_allocator().allocate_frame()).unwrap();
+    for cache in [CacheAttribute::Uncached, CacheAttribute::WriteCombining] {
+        let address = memory::shared::map_physical(frame.address, Size4KiB::SIZE, cache).unwrap();
+        let page = Page::<Size4KiB>::containing_address(address);
+        let (mapped, flags) =
+            memory::with_memory_manager(|mm| mm.page_table().translate(page)).unwrap();
+        assert_eq!(mapped, frame);
+        assert!(flags.contains(cache.flags::<Size4KiB>()));
+        memory::with_memory_manager(|mm| mm.munmap(address)).unwrap();
+    }
+}
+
+fn test_address_space_dump() {
+    let physical_start = paging::physical_to_virtual(PhysicalAddress::new(0));
+    let mut ranges = Vec::new();
+    memory::with_memory_manager(|mm| dump::for_each_range(mm.page_table(), |r| ranges.push(*r)));
+
+    assert!(ranges
+        .windows(2)
+        .all(|w| w[0].mapping.start + w[0].mapping.size <= w[1].mapping.start));
+
+    // the huge pages of the physical memory mapping are merged into one range
+    let physical = ranges
+        .iter()
+        .find(|r| r.mapping.start == physical_start)
+        .expect("Physical memory mapping missing");
+    assert_eq!(physical.mapping.frame, PhysicalAddress::new(0));
+    assert!(physical.mapping.size >= Size2MiB::SIZE * physical.pages);
+    assert!(physical
+        .mapping
+        .flags
+        .contains(PageTableEntryFlags::NO_EXECUTE));
+}
+
+fn test_recursive_page_table() {
+    let heap = HEAP_START;
+    let page = Page::<Size4KiB>::containing_address(VirtualAddress::new(0x7000_0000_0000));
+
+    memory::with_memory_manager(|mm| {
+        let mut recursive = unsafe { RecursivePageTable::active(paging::recursive_index()) }
+            .expect("Recursive entry missing");
+        let (frame, _) = recursive
+            .translate(Page::<Size4KiB>::containing_address(heap))
+            .unwrap();
+        assert_eq!(Some(frame.address), paging::translate(heap));
+
+        // creates the missing tables through the recursive entry
+        let frame = mm.frame_allocator().allocate_frame().unwrap();
+        recursive
+            .map_to(
+                frame,
+                page,
+                PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
+                CacheAttribute::WriteBack,
+                mm.frame_allocator(),
+            )
+            .unwrap()
+            .flush();
+        assert_eq!(mm.page_table().translate(page).unwrap().0, frame);
+
+        // changed in place through the kernel page table
+        mm.page_table()
+            .update_flags(page, PageTableEntryFlags::PRESENT)
+            .unwrap()
+            .flush();
+        let (_, flags) = recursive.translate(page).unwrap();
+        assert!(!flags.contains(PageTableEntryFlags::WRITABLE));
+
+        let other = mm.frame_allocator().allocate_frame().unwrap();
+        let (old, flusher) = recursive.remap(page, other).unwrap();
+        flusher.flush();
+        assert_eq!(old, frame);
+        assert_eq!(mm.page_table().translate(page).unwrap(), (other, flags));
+        unsafe { mm.frame_allocator().deallocate_frame(frame) };
+        let frame = other;
+
+        let (unmapped, flusher) = Mapper::<Size4KiB>::unmap(&mut recursive, page).unwrap();
+        flusher.flush();
+        assert_eq!(unmapped, frame);
+        assert!(mm.page_table().translate(page).is_err());
+        assert!(Mapper::<Size4KiB>::update_flags(&mut recursive, page, flags).is_err());
+    });
+}
+
+fn test_page_table_verify() {
+    let page = Page::<Size4KiB>::containing_address(VirtualAddress::new(0xffff_d200_0000_0000));
+    let legacy = Page::<Size4KiB>::containing_address(VirtualAddress::new(0xffff_d200_0000_1000));
+    let vga = PhysicalFrame::containing_address(PhysicalAddress::new(0xa0000));
+    let issues_at = |issues: &[paging::Issue], page: Page| {
+        let mut kinds: Vec<_> = issues
+            .iter()
+            .filter(|issue| issue.mapping.start == page.address)
+            .map(|issue| issue.kind)
+            .collect();
+        kinds.sort_by_key(|kind| *kind as u8);
+        kinds
+    };
+
+    memory::with_memory_manager(|mm| {
+        let mut recursive = unsafe { RecursivePageTable::active(paging::recursive_index()) }
+            .expect("Recursive entry missing");
+        let frame = mm.frame_allocator().allocate_frame().unwrap();
+        recursive
+            .map_to(
+                frame,
+                page,
+                PageTableEntryFlags::PRESENT
+                    | PageTableEntryFlags::WRITABLE
+                    | PageTableEntryFlags::USER_ACCESSIBLE,
+                CacheAttribute::WriteBack,
+                mm.frame_allocator(),
+            )
+            .unwrap()
+            .flush();
+        recursive
+            .map_to(
+                vga,
+                legacy,
+                PageTableEntryFlags::PRESENT | PageTableEntryFlags::NO_EXECUTE,
+                CacheAttribute::WriteBack,
+                mm.frame_allocator(),
+            )
+            .unwrap()
+            .flush();
+
+        let issues = paging::verify(mm.page_table());
+        assert_eq!(
+            issues_at(&issues, page),
+            [
+                paging::IssueKind::WritableExecutable,
+                paging::IssueKind::UserAccessibleKernel
+            ]
+        );
+        assert_eq!(issues_at(&issues, legacy), [paging::IssueKind::Reserved]);
+
+        for page in [page, legacy] {
+            let (_, flusher) = Mapper::<Size4KiB>::unmap(&mut recursive, page).unwrap();
+            flusher.flush();
+        }
+        unsafe { mm.frame_allocator().deallocate_frame(frame) };
+        let issues = paging::verify(mm.page_table());
+        assert!(issues_at(&issues, page).is_empty());
+        assert!(issues_at(&issues, legacy).is_empty());
+    });
+}
+
+fn test_virtual_ranges() {
+    let zones = [
+        api::layout::PHYSICAL_MEMORY,
+        api::layout::HEAP,
+        api::layout::VMALLOC,
+        api::layout::MMIO,
+        api::layout::PER_CPU,
+        api::layout::MODULES,
+    ];
+    for (i, zone) in zones.iter().enumerate() {
+        assert!(zones[i + 1..].iter().all(|other| !zone.overlaps(other)));
+    }
+    assert!(api::layout::HEAP.contains(HEAP_START.as_u64()));
+    assert!(api::layout::PHYSICAL_MEMORY.contains(paging::physical_memory_offset()));
+
+    // MMIO remaps get their own zone and give their range back
+    let free = memory::with_memory_manager(|mm| mm.virtual_ranges().free_size(Zone::Mmio));
+    let frame = memory::with_memory_manager(|mm| mm.frame_allocator().allocate_frame()).unwrap();
+    let address =
+        memory::shared::map_physical(frame.address, Size4KiB::SIZE, CacheAttribute::Uncached)
+            .unwrap();
+    assert_eq!(Zone::containing(address), Some(Zone::Mmio));
+    memory::with_memory_manager(|mm| {
+        assert_eq!(
+            mm.virtual_ranges().free_size(Zone::Mmio),
+            free - Size4KiB::SIZE
+        );
+        mm.munmap(address).unwrap();
+        assert_eq!(mm.virtual_ranges().free_size(Zone::Mmio), free);
+        unsafe { mm.frame_allocator().deallocate_frame(frame) };
+    });
+
+    let mut ranges = VirtualRangeAllocator::new();
+    let a = ranges.allocate(Zone::Modules, 0x3000, 0x1000).unwrap();
+    let b = ranges.allocate(Zone::Modules, 0x1000, 0x10000).unwrap();
+    assert_eq!(a.as_u64(), api::layout::MODULES.start);
+    assert!(b.is_aligned(0x10000));
+    assert!(!ranges.reserve(a + 0x1000u64, 0x1000));
+    ranges.free(a, 0x3000);
+    ranges.free(b, 0x1000);
+    assert_eq!(ranges.free_size(Zone::Modules), api::layout::MODULES.size);
+
+    // freed gaps are reused lowest first
+    let pages: Vec<_> = (0..64)
+        .map(|_| ranges.allocate(Zone::Vmalloc, 0x1000, 0x1000).unwrap())
+        .collect();
+    ranges.free(pages[10], 0x1000);
+    ranges.free(pages[40], 0x1000);
+    ranges.free(pages[41], 0x1000);
+    assert_eq!(
+        ranges.allocate(Zone::Vmalloc, 0x2000, 0x1000),
+        Some(pages[40])
+    );
+    assert_eq!(
+        ranges.allocate(Zone::Vmalloc, 0x1000, 0x1000),
+        Some(pages[10])
+    );
+    assert!(!ranges.reserve(pages[20] + 0x800u64, 0x1000));
+    assert_eq!(
+        ranges.allocate(Zone::Vmalloc, 0x1000, 0x1000),
+        Some(pages[63] + 0x1000u64)
+    );
+}
+
+fn test_ioremap() {
+    let frame = memory::with_memory_manager(|mm| mm.frame_allocator().allocate_frame()).unwrap();
+    let physical = frame.address + 0x10u64;
+    let registers = memory::ioremap(physical, 8, CacheAttribute::Uncached).unwrap();
+    assert_eq!(Zone::containing(registers.address()), Some(Zone::Mmio));
+
+    registers.write::<u32>(4, 0x1234_5678);
+    assert_eq!(registers.read::<u32>(4), 0x1234_5678);
+
+    memory::iounmap(registers).unwrap();
+    assert!(paging::translate(registers.address()).is_none());
+    memory::with_memory_manager(|mm| unsafe { mm.frame_allocator().deallocate_frame(frame) });
+}
+
+fn test_dma() {
+    let mut buffer = memory::with_memory_manager(|mm| {
+        mm.allocate_dma(3 * Size4KiB::SIZE as usize + 1, DMA_32BIT_LIMIT)
+    })
+    .unwrap();
+    assert!(buffer.segment().is_below(DMA_32BIT_LIMIT));
+    assert!(buffer.as_slice().iter().all(|b| *b == 0));
+
+    // the buffer is a single segment
+    let segments = memory::with_memory_manager(|mm| {
+        mm.dma_segments(buffer.virtual_address(), buffer.len() as u64)
+    })
+    .unwrap();
+    assert_eq!(segments, vec![buffer.segment()]);
+
+    let data = [0xab; 16];
+    let mut out = [0; 16];
+    buffer.copy_from(&data);
+    buffer.copy_to(&mut out);
+    assert_eq!(out, data);
+
+    // a heap buffer covers every byte once
+    let heap = vec![0u8; 3 * Size4KiB::SIZE as usize];
+    let address = VirtualAddress::from_ptr(&heap[0]);
+    let segments =
+        memory::with_memory_manager(|mm| mm.dma_segments(address, heap.len() as u64)).unwrap();
+    assert_eq!(segments[0].address, paging::translate(address).unwrap());
+    assert_eq!(
+        segments.iter().map(|s| s.len).sum::<u64>(),
+        heap.len() as u64
+    );
+
+    // nothing is mapped at the end of the memory manager's range yet
+    let unmapped = VIRTUAL_MEMORY_START + VIRTUAL_MEMORY_SIZE - Size4KiB::SIZE;
+    assert!(matches!(
+        memory::with_memory_manager(|mm| mm.dma_segments(unmapped, 1)),
+        Err(MemoryError::NotMapped)
+    ));
+
+    let free = memory::frame_stats().free;
+    memory::with_memory_manager(|mm| mm.free_dma(buffer));
+    assert_eq!(memory::frame_stats().free, free + 4);
+}
+
+#[cfg(feature = "unmap-on-free")]
+fn test_unmap_on_free() {
+    let buffer = vec![0u8; 2 * Size4KiB::SIZE as usize];
+    let address = VirtualAddress::from_ptr(&buffer[0]);
+    assert!(address >= HEAP_START + HEAP_SIZE);
+    assert!(paging::translate(address).is_some());
+
+    drop(buffer);
+    assert!(paging::translate(address).is_none());
+    let freed = memory::freed::lookup(address).expect("Freed allocation not recorded");
+    assert_eq!(
+        freed.owner,
+        memory::freed::Owner::Allocation(2 * Size4KiB::SIZE as usize)
+    );
+}
+
+fn test_paging_levels() {
+    let address = VirtualAddress::new(0xff12_3456_789a_bcde);
+    assert_eq!(address.l5_index(), 0x112);
+    assert_eq!(address.l4_index(), 0x68);
+
+    // only the 4-level layout sign-extends bit 47
+    assert!(PagingLevels::Four.is_canonical(VirtualAddress::new(0xffff_8000_0000_0000)));
+    assert!(!PagingLevels::Five.is_canonical(VirtualAddress::new(0xff00_8000_0000_0000)));
+    assert!(PagingLevels::Five.is_canonical(VirtualAddress::new(0x00ff_8000_0000_0000)));
+    assert!(!PagingLevels::Four.is_canonical(VirtualAddress::new(0x00ff_8000_0000_0000)));
+
+    let levels = memory::with_memory_manager(|mm| mm.page_table().levels());
+    assert_eq!(levels, PagingLevels::current());
+    assert_eq!(
+        levels == PagingLevels::Five,
+        Cr4::read().contains(Cr4Flags::L5_PAGING)
+    );
+}
+
+kernel_test!(
+    test_buddy_allocator,
+    test_heap_allocations,
+    test_slab_allocator,
+    test_lazy_allocation,
+    test_stack_growth,
+    test_huge_pages,
+    test_paging_levels,
+    test_cache_attributes,
+    test_address_space_dump,
+    test_recursive_page_table,
+    test_page_table_verify,
+    test_virtual_ranges,
+    test_ioremap,
+    test_dma
+);
+#[cfg(feature = "unmap-on-free")]
+kernel_test!(test_unmap_on_free);
@@ -0,0 +1,1053 @@
+#![no_std]
+#![no_main]
+use api::BootInfo;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+use kernel::{
+    executor,
+    fs::vfs,
+    kernel_init, kernel_test,
+    net::{
+        self,
+        arp::{ArpOperation, ArpPacket},
+        dhcp::{self, Lease, MessageType},
+        dns::{self, Answer},
+        ethernet::{self, EtherType, EthernetFrame},
+        http::{self, DownloadError},
+        icmp::{self, IcmpMessage, IcmpType},
+        interface,
+        interface::Interface,
+        ipv4::{self, Ipv4Packet, Protocol},
+        tcp::{self, TcpFlags, TcpListener, TcpSegment, TcpState, TcpStream},
+        udp::{self, RawSocketAddress, SocketAddress, UdpDatagram, UdpSocket},
+        Ipv4Address, Ipv4Config, MacAddress, NetDevice, NetError,
+    },
+    scheduler::{self, thread, ThreadPriority},
+    syscall::{self, Errno, Syscall},
+    test, time,
+};
+use x86_64::mutex::Mutex;
+
+extern crate alloc;
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    test::panicked(info)
+}
+
+#[no_mangle]
+#[link_section = ".start"]
+pub extern "C" fn _start(info: &'static BootInfo) -> ! {
+    start(info);
+}
+
+fn start(info: &'static BootInfo) -> ! {
+    kernel_init(info).unwrap();
+    test::run()
+}
+
+/// Network device keeping the frames sent
+struct CaptureDevice {
+    sent: Mutex<Vec<Vec<u8>>>,
+}
+
+impl CaptureDevice {
+    const MAC: MacAddress = MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+
+    fn take(&self) -> Vec<Vec<u8>> {
+        core::mem::take(&mut *self.sent.lock())
+    }
+}
+
+impl NetDevice for CaptureDevice {
+    fn name(&self) -> &str {
+        "test0"
+    }
+
+    fn mac(&self) -> MacAddress {
+        Self::MAC
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.sent.lock().push(frame.to_vec());
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+fn test_network() {
+    let address: Ipv4Address = "10.0.2.15".parse().unwrap();
+    assert_eq!(address, Ipv4Address::new(10, 0, 2, 15));
+    assert!("10.0.2".parse::<Ipv4Address>().is_err());
+    assert!("10.0.2.256".parse::<Ipv4Address>().is_err());
+    assert_eq!(format!("{}", CaptureDevice::MAC), "52:54:00:12:34:56");
+
+    let header = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8, 0x00,
+        0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    assert_eq!(ipv4::checksum(&header), 0xb861);
+
+    let packet = Ipv4Packet {
+        source: address,
+        destination: Ipv4Address::new(10, 0, 2, 2),
+        protocol: Protocol::Udp,
+        ttl: 64,
+        identification: 7,
+        payload: b"payload",
+    };
+    let mut bytes = packet.serialize();
+    assert_eq!(Ipv4Packet::parse(&bytes), Ok(packet));
+    bytes[8] -= 1;
+    assert_eq!(Ipv4Packet::parse(&bytes), Err(NetError::InvalidChecksum));
+    assert_eq!(Ipv4Packet::parse(&bytes[..10]), Err(NetError::Truncated));
+
+    let frame = EthernetFrame {
+        destination: MacAddress::BROADCAST,
+        source: CaptureDevice::MAC,
+        ether_type: EtherType::Arp,
+        payload: b"short",
+    };
+    let bytes = frame.serialize();
+    assert_eq!(bytes.len(), ethernet::MIN_FRAME_SIZE);
+    let parsed = EthernetFrame::parse(&bytes).unwrap();
+    assert_eq!(parsed.source, CaptureDevice::MAC);
+    assert_eq!(parsed.ether_type, EtherType::Arp);
+    assert_eq!(&parsed.payload[..5], b"short");
+
+    // packets to other networks wait for the gateway to be resolved
+    let device = Arc::new(CaptureDevice {
+        sent: Mutex::new(Vec::new()),
+    });
+    let gateway = Ipv4Address::new(10, 0, 2, 2);
+    let gateway_mac = MacAddress([0x52, 0x55, 10, 0, 2, 2]);
+    let config = Ipv4Config {
+        address,
+        netmask: Ipv4Address::new(255, 255, 255, 0),
+        gateway: Some(gateway),
+    };
+    let interface = interface::add(device.clone(), config).unwrap();
+    assert_eq!(
+        interface::add(device.clone(), config).err(),
+        Some(NetError::AlreadyExists)
+    );
+    ipv4::send(
+        Ipv4Address::new(10, 1, 0, 1),
+        Protocol::Unknown(253),
+        b"hello",
+    )
+    .unwrap();
+    let sent = device.take();
+    assert_eq!(sent.len(), 1);
+    let frame = EthernetFrame::parse(&sent[0]).unwrap();
+    assert_eq!(frame.destination, MacAddress::BROADCAST);
+    let request = ArpPacket::parse(frame.payload).unwrap();
+    assert_eq!(request.operation, ArpOperation::Request);
+    assert_eq!(request.sender_ip, address);
+    assert_eq!(request.target_ip, gateway);
+
+    let reply = ArpPacket {
+        operation: ArpOperation::Reply,
+        sender_mac: gateway_mac,
+        sender_ip: gateway,
+        target_mac: CaptureDevice::MAC,
+        target_ip: address,
+    };
+    interface.receive(
+        &EthernetFrame {
+            destination: CaptureDevice::MAC,
+            source: gateway_mac,
+            ether_type: EtherType::Arp,
+            payload: &reply.serialize(),
+        }
+        .serialize(),
+    );
+    let sent = device.take();
+    assert_eq!(sent.len(), 1);
+    let frame = EthernetFrame::parse(&sent[0]).unwrap();
+    assert_eq!(frame.destination, gateway_mac);
+    let packet = Ipv4Packet::parse(frame.payload).unwrap();
+    assert_eq!(packet.destination, Ipv4Address::new(10, 1, 0, 1));
+    assert_eq!(packet.payload, b"hello");
+    assert!(interface
+        .arp_entries()
+        .iter()
+        .any(|(ip, entry)| *ip == gateway && entry.mac == gateway_mac));
+
+    // requests for the address of the interface are answered
+    let peer_mac = MacAddress([0x52, 0x55, 10, 0, 2, 3]);
+    let request = ArpPacket {
+        operation: ArpOperation::Request,
+        sender_mac: peer_mac,
+        sender_ip: Ipv4Address::new(10, 0, 2, 3),
+        target_mac: MacAddress::ZERO,
+        target_ip: address,
+    };
+    interface.receive(
+        &EthernetFrame {
+            destination: MacAddress::BROADCAST,
+            source: peer_mac,
+            ether_type: EtherType::Arp,
+            payload: &request.serialize(),
+        }
+        .serialize(),
+    );
+    let sent = device.take();
+    assert_eq!(sent.len(), 1);
+    let reply = ArpPacket::parse(EthernetFrame::parse(&sent[0]).unwrap().payload).unwrap();
+    assert_eq!(reply.operation, ArpOperation::Reply);
+    assert_eq!(reply.sender_mac, CaptureDevice::MAC);
+    assert_eq!(reply.target_mac, peer_mac);
+    assert_eq!(interface.stats().rx_packets, 2);
+    interface::remove("test0").unwrap();
+
+    // local addresses are reached over loopback
+    let loopback = interface::get("lo").unwrap();
+    assert_eq!(loopback.config().address, Ipv4Address::LOCALHOST);
+    let received = loopback.stats().rx_packets;
+    ipv4::send(Ipv4Address::LOCALHOST, Protocol::Unknown(253), b"loop").unwrap();
+    while loopback.stats().rx_packets == received {
+        net::poll();
+        thread::sleep_ms(10);
+    }
+    assert_eq!(
+        ipv4::send(address, Protocol::Udp, b""),
+        Err(NetError::NoRoute)
+    );
+}
+
+fn test_icmp() {
+    let request = IcmpMessage::echo(IcmpType::EchoRequest, 0x1234, 7, b"ping");
+    let bytes = request.serialize();
+    assert_eq!(ipv4::checksum(&bytes), 0);
+    let parsed = IcmpMessage::parse(&bytes).unwrap();
+    assert_eq!(parsed, request);
+    assert_eq!((parsed.identifier(), parsed.sequence()), (0x1234, 7));
+
+    // the stack answers its own echo requests
+    let rtt = icmp::ping(Ipv4Address::LOCALHOST, 0, icmp::DEFAULT_TIMEOUT).unwrap();
+    assert!(rtt < icmp::DEFAULT_TIMEOUT);
+    assert_eq!(
+        icmp::ping(Ipv4Address::new(10, 1, 0, 1), 0, icmp::DEFAULT_TIMEOUT),
+        Err(NetError::NoRoute)
+    );
+
+    // nobody resolves the peer
+    let device = Arc::new(CaptureDevice {
+        sent: Mutex::new(Vec::new()),
+    });
+    let config = Ipv4Config {
+        address: Ipv4Address::new(10, 0, 2, 15),
+        netmask: Ipv4Address::new(255, 255, 255, 0),
+        gateway: None,
+    };
+    interface::add(device.clone(), config).unwrap();
+    let start = time::uptime();
+    assert_eq!(
+        icmp::ping(Ipv4Address::new(10, 0, 2, 2), 0, Duration::from_millis(50)),
+        Err(NetError::Timeout)
+    );
+    assert!(time::uptime() - start >= Duration::from_millis(50));
+    assert_eq!(device.take().len(), 1);
+    interface::remove("test0").unwrap();
+}
+
+fn test_udp() {
+    let packet = Ipv4Packet {
+        source: Ipv4Address::new(10, 0, 2, 15),
+        destination: Ipv4Address::new(10, 0, 2, 2),
+        protocol: Protocol::Udp,
+        ttl: 64,
+        identification: 0,
+        payload: &[],
+    };
+    let datagram = UdpDatagram {
+        source_port: 1234,
+        destination_port: 53,
+        payload: b"query",
+    };
+    let bytes = datagram.serialize(packet.source, packet.destination);
+    assert_eq!(
+        UdpDatagram::parse(&Ipv4Packet {
+            payload: &bytes,
+            ..packet
+        }),
+        Ok(datagram)
+    );
+    // the checksum covers the addresses
+    assert_eq!(
+        UdpDatagram::parse(&Ipv4Packet {
+            payload: &bytes,
+            source: Ipv4Address::new(10, 0, 2, 16),
+            ..packet
+        }),
+        Err(NetError::InvalidChecksum)
+    );
+
+    // a server answering a client over loopback
+    let server = UdpSocket::new();
+    let address = SocketAddress::new(Ipv4Address::LOCALHOST, 7000);
+    assert_eq!(server.bind(address), Ok(address));
+    assert_eq!(server.bind(address), Err(NetError::AlreadyBound));
+    assert_eq!(
+        UdpSocket::new().bind(SocketAddress::new(Ipv4Address::UNSPECIFIED, 7000)),
+        Err(NetError::AddressInUse)
+    );
+
+    let client = UdpSocket::new();
+    assert_eq!(client.local_address(), None);
+    assert_eq!(client.send_to(b"hello", address), Ok(5));
+    let client_address = client.local_address().unwrap();
+    assert!(udp::EPHEMERAL_PORTS.contains(&client_address.port));
+
+    let mut buf = [0u8; 16];
+    let (len, source) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"hello");
+    assert_eq!(source.port, client_address.port);
+    server.send_to(b"world", source).unwrap();
+    let (len, source) = client.recv_from(&mut buf[..3]).unwrap();
+    assert_eq!(&buf[..len], b"wor");
+    assert_eq!(source, address);
+
+    // the port is free again once the socket is gone
+    drop(server);
+    let server = UdpSocket::new();
+    server.bind(address).unwrap();
+    drop(server);
+
+    // the same over system calls
+    let fd = unsafe { syscall::syscall3(Syscall::Socket, 0, 0, 0) }.unwrap();
+    let mut raw = RawSocketAddress::from(SocketAddress::new(Ipv4Address::LOCALHOST, 0));
+    unsafe { syscall::syscall3(Syscall::Bind, fd, &mut raw as *mut _ as u64, 0) }.unwrap();
+    assert_ne!(raw.port, 0);
+    let message = b"datagram";
+    assert_eq!(
+        unsafe {
+            syscall::syscall4(
+                Syscall::SendTo,
+                fd,
+                message.as_ptr() as u64,
+                message.len() as u64,
+                &raw as *const _ as u64,
+            )
+        },
+        Ok(8)
+    );
+    let mut from = RawSocketAddress::default();
+    let len = unsafe {
+        syscall::syscall4(
+            Syscall::RecvFrom,
+            fd,
+            buf.as_mut_ptr() as u64,
+            buf.len() as u64,
+            &mut from as *mut _ as u64,
+        )
+    }
+    .unwrap();
+    assert_eq!(&buf[..len as usize], message);
+    assert_eq!(from, raw);
+    unsafe { syscall::syscall3(Syscall::Close, fd, 0, 0) }.unwrap();
+
+    let path = "/proc/uptime";
+    let file =
+        unsafe { syscall::syscall3(Syscall::Open, path.as_ptr() as u64, path.len() as u64, 0) }
+            .unwrap();
+    assert_eq!(
+        unsafe { syscall::syscall3(Syscall::Bind, file, &mut raw as *mut _ as u64, 0) },
+        Err(Errno::ENOTSOCK)
+    );
+    unsafe { syscall::syscall3(Syscall::Close, file, 0, 0) }.unwrap();
+}
+
+const TCP_SERVER_PORT: u16 = 7007;
+
+static TCP_SERVER_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Echoes the first connection and answers an HTTP request on the second
+fn tcp_server() {
+    let listener =
+        TcpListener::bind(SocketAddress::new(Ipv4Address::LOCALHOST, TCP_SERVER_PORT)).unwrap();
+    TCP_SERVER_STATE.store(1, Ordering::SeqCst);
+
+    let stream = listener.accept().unwrap();
+    let mut buf = [0u8; 1024];
+    loop {
+        let len = stream.read(&mut buf).unwrap();
+        if len == 0 {
+            break;
+        }
+        stream.write_all(&buf[..len]).unwrap();
+    }
+    drop(stream);
+
+    let stream = listener.accept().unwrap();
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        let len = stream.read(&mut buf).unwrap();
+        assert_ne!(len, 0);
+        request.extend_from_slice(&buf[..len]);
+    }
+    assert!(request.starts_with(b"GET /index.html HTTP/1.0\r\n"));
+    stream
+        .write_all(b"HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nhello")
+        .unwrap();
+    drop(stream);
+    TCP_SERVER_STATE.store(2, Ordering::SeqCst);
+}
+
+/// Feeds a segment from `peer` into `interface`
+fn inject_tcp(
+    interface: &Interface,
+    peer: SocketAddress,
+    local: SocketAddress,
+    segment: TcpSegment,
+) {
+    let packet = Ipv4Packet {
+        source: peer.ip,
+        destination: local.ip,
+        protocol: Protocol::Tcp,
+        ttl: 64,
+        identification: 0,
+        payload: &segment.serialize(peer.ip, local.ip),
+    };
+    interface.receive(
+        &EthernetFrame {
+            destination: CaptureDevice::MAC,
+            source: MacAddress([0x52, 0x55, 10, 0, 2, 2]),
+            ether_type: EtherType::Ipv4,
+            payload: &packet.serialize(),
+        }
+        .serialize(),
+    );
+}
+
+/// Flags, sequence, acknowledgment, maximum segment size and payload
+type CapturedSegment = (TcpFlags, u32, u32, Option<u16>, Vec<u8>);
+
+/// Parses the segments sent to the capture device
+fn captured_tcp(device: &CaptureDevice) -> Vec<CapturedSegment> {
+    device
+        .take()
+        .iter()
+        .map(|frame| {
+            let frame = EthernetFrame::parse(frame).unwrap();
+            let packet = Ipv4Packet::parse(frame.payload).unwrap();
+            let segment = TcpSegment::parse(&packet).unwrap();
+            (
+                segment.flags,
+                segment.sequence,
+                segment.acknowledgment,
+                segment.mss,
+                segment.payload.to_vec(),
+            )
+        })
+        .collect()
+}
+
+fn test_tcp() {
+    let source = Ipv4Address::new(10, 0, 2, 15);
+    let destination = Ipv4Address::new(10, 0, 2, 2);
+    let segment = TcpSegment {
+        source_port: 80,
+        destination_port: 49152,
+        sequence: 0xffff_fff0,
+        acknowledgment: 7,
+        flags: TcpFlags::SYN | TcpFlags::ACK,
+        window: 1024,
+        mss: Some(1460),
+        payload: b"data",
+    };
+    let bytes = segment.serialize(source, destination);
+    assert_eq!(bytes.len(), tcp::HEADER_SIZE + 4 + 4);
+    let packet = Ipv4Packet {
+        source,
+        destination,
+        protocol: Protocol::Tcp,
+        ttl: 64,
+        identification: 0,
+        payload: &bytes,
+    };
+    assert_eq!(TcpSegment::parse(&packet), Ok(segment));
+    assert_eq!(segment.sequence_len(), 5);
+    assert_eq!(
+        TcpSegment::parse(&Ipv4Packet {
+            source: destination,
+            ..packet
+        }),
+        Err(NetError::InvalidChecksum)
+    );
+
+    // echo and HTTP over loopback
+    scheduler::spawn(tcp_server, ThreadPriority::Normal).expect("Failed to spawn thread");
+    while TCP_SERVER_STATE.load(Ordering::SeqCst) == 0 {
+        thread::sleep_ms(10);
+    }
+    let server = SocketAddress::new(Ipv4Address::LOCALHOST, TCP_SERVER_PORT);
+    assert_eq!(
+        TcpListener::bind(server).err(),
+        Some(NetError::AddressInUse)
+    );
+    let stream = TcpStream::connect(server).unwrap();
+    assert_eq!(stream.state(), TcpState::Established);
+    assert_eq!(stream.remote_address(), server);
+    assert!(udp::EPHEMERAL_PORTS.contains(&stream.local_address().port));
+
+    // more than fits into the buffers, sent in chunks to avoid both sides
+    // blocking on full windows
+    let data: Vec<u8> = (0..64 * 1024).map(|i| (i * 7 % 251) as u8).collect();
+    let mut echoed = Vec::new();
+    let mut buf = [0u8; 2048];
+    for chunk in data.chunks(4096) {
+        stream.write_all(chunk).unwrap();
+        let expected = echoed.len() + chunk.len();
+        while echoed.len() < expected {
+            let len = stream.read(&mut buf).unwrap();
+            assert_ne!(len, 0);
+            echoed.extend_from_slice(&buf[..len]);
+        }
+    }
+    assert!(echoed == data);
+    stream.close();
+    assert_eq!(stream.read(&mut buf), Ok(0));
+    assert_eq!(stream.write(b"late"), Err(NetError::NotConnected));
+    drop(stream);
+
+    let stream = TcpStream::connect(server).unwrap();
+    stream
+        .write_all(b"GET /index.html HTTP/1.0\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = Vec::new();
+    loop {
+        let len = stream.read(&mut buf).unwrap();
+        if len == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..len]);
+    }
+    assert!(response.starts_with(b"HTTP/1.0 200 OK\r\n"));
+    assert!(response.ends_with(b"\r\n\r\nhello"));
+    drop(stream);
+    while TCP_SERVER_STATE.load(Ordering::SeqCst) != 2 {
+        thread::sleep_ms(10);
+    }
+
+    assert_eq!(
+        TcpStream::connect(SocketAddress::new(Ipv4Address::LOCALHOST, 7999)).err(),
+        Some(NetError::ConnectionRefused)
+    );
+
+    // a peer on a capture device, reached after its address was resolved
+    let device = Arc::new(CaptureDevice {
+        sent: Mutex::new(Vec::new()),
+    });
+    let config = Ipv4Config {
+        address: source,
+        netmask: Ipv4Address::new(255, 255, 255, 0),
+        gateway: None,
+    };
+    let interface = interface::add(device.clone(), config).unwrap();
+    let peer_mac = MacAddress([0x52, 0x55, 10, 0, 2, 2]);
+    let request = ArpPacket {
+        operation: ArpOperation::Request,
+        sender_mac: peer_mac,
+        sender_ip: destination,
+        target_mac: MacAddress::ZERO,
+        target_ip: source,
+    };
+    interface.receive(
+        &EthernetFrame {
+            destination: MacAddress::BROADCAST,
+            source: peer_mac,
+            ether_type: EtherType::Arp,
+            payload: &request.serialize(),
+        }
+        .serialize(),
+    );
+    device.take();
+
+    let listener = TcpListener::bind(SocketAddress::new(Ipv4Address::UNSPECIFIED, 80)).unwrap();
+    let local = SocketAddress::new(source, 80);
+    let peer = SocketAddress::new(destination, 40000);
+    let segment = |flags, sequence, acknowledgment, payload| TcpSegment {
+        source_port: peer.port,
+        destination_port: local.port,
+        sequence,
+        acknowledgment,
+        flags,
+        window: 8192,
+        mss: None,
+        payload,
+    };
+    inject_tcp(
+        &interface,
+        peer,
+        local,
+        segment(TcpFlags::SYN, 1000, 0, b""),
+    );
+    let sent = captured_tcp(&device);
+    assert_eq!(sent.len(), 1);
+    let (flags, iss, acknowledgment, mss, _) = sent[0].clone();
+    assert_eq!(flags, TcpFlags::SYN | TcpFlags::ACK);
+    assert_eq!(acknowledgment, 1001);
+    assert_eq!(mss, Some(1460));
+
+    // the lost SYN-ACK is sent again once the timeout expires
+    thread::sleep_ms(tcp::INITIAL_RTO.as_millis() as u64 + 200);
+    let sent = captured_tcp(&device);
+    assert_eq!(sent.len(), 1);
+    assert_eq!((sent[0].0, sent[0].1), (TcpFlags::SYN | TcpFlags::ACK, iss));
+
+    inject_tcp(
+        &interface,
+        peer,
+        local,
+        segment(TcpFlags::ACK, 1001, iss.wrapping_add(1), b""),
+    );
+    let stream = listener.accept().unwrap();
+    assert_eq!(stream.remote_address(), peer);
+
+    // data arriving out of order is reassembled
+    inject_tcp(
+        &interface,
+        peer,
+        local,
+        segment(TcpFlags::ACK, 1006, iss.wrapping_add(1), b"world"),
+    );
+    inject_tcp(
+        &interface,
+        peer,
+        local,
+        segment(TcpFlags::ACK, 1001, iss.wrapping_add(1), b"hello"),
+    );
+    let acknowledgments: Vec<u32> = captured_tcp(&device).iter().map(|s| s.2).collect();
+    assert_eq!(acknowledgments, [1001, 1011]);
+    let mut buf = [0u8; 16];
+    assert_eq!(stream.read(&mut buf), Ok(10));
+    assert_eq!(&buf[..10], b"helloworld");
+
+    stream.write_all(b"reply").unwrap();
+    let sent = captured_tcp(&device);
+    assert_eq!(sent.len(), 1);
+    assert_eq!((sent[0].1, sent[0].2), (iss.wrapping_add(1), 1011));
+    assert_eq!(sent[0].4, b"reply");
+    inject_tcp(
+        &interface,
+        peer,
+        local,
+        segment(TcpFlags::ACK, 1011, iss.wrapping_add(6), b""),
+    );
+
+    // the peer closes first
+    inject_tcp(
+        &interface,
+        peer,
+        local,
+        segment(
+            TcpFlags::FIN | TcpFlags::ACK,
+            1011,
+            iss.wrapping_add(6),
+            b"",
+        ),
+    );
+    assert_eq!(captured_tcp(&device)[0].2, 1012);
+    assert_eq!(stream.read(&mut buf), Ok(0));
+    assert_eq!(stream.state(), TcpState::CloseWait);
+    stream.close();
+    let sent = captured_tcp(&device);
+    assert_eq!(
+        (sent[0].0, sent[0].1),
+        (TcpFlags::FIN | TcpFlags::ACK, iss.wrapping_add(6))
+    );
+    assert_eq!(stream.state(), TcpState::LastAck);
+    inject_tcp(
+        &interface,
+        peer,
+        local,
+        segment(TcpFlags::ACK, 1012, iss.wrapping_add(7), b""),
+    );
+    assert_eq!(stream.state(), TcpState::Closed);
+    drop(stream);
+
+    // segments to closed ports are reset
+    inject_tcp(
+        &interface,
+        peer,
+        SocketAddress::new(source, 81),
+        segment(TcpFlags::SYN, 5000, 0, b""),
+    );
+    let sent = captured_tcp(&device);
+    assert_eq!(
+        (sent[0].0, sent[0].2),
+        (TcpFlags::RST | TcpFlags::ACK, 5001)
+    );
+    drop(listener);
+    interface::remove("test0").unwrap();
+}
+
+static DNS_SERVER_STATE: AtomicU64 = AtomicU64::new(0);
+
+static DNS_QUERIES: AtomicU64 = AtomicU64::new(0);
+
+static DNS_RETRY_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Name server for the test: `missing.test` doesn't exist, the first query
+/// for `retry.test` is lost and all other names have two addresses.
+/// `short.test` may be cached for a second. Stops on a datagram that isn't a
+/// query.
+fn dns_server() {
+    let socket = UdpSocket::new();
+    socket
+        .bind(SocketAddress::new(Ipv4Address::LOCALHOST, dns::PORT))
+        .unwrap();
+    DNS_SERVER_STATE.store(1, Ordering::SeqCst);
+
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, source) = socket.recv_from(&mut buf).unwrap();
+        if len < 12 {
+            break;
+        }
+        DNS_QUERIES.fetch_add(1, Ordering::SeqCst);
+
+        let mut labels = Vec::new();
+        let mut offset = 12;
+        while buf[offset] != 0 {
+            let label_len = buf[offset] as usize;
+            labels.push(core::str::from_utf8(&buf[offset + 1..offset + 1 + label_len]).unwrap());
+            offset += 1 + label_len;
+        }
+        let name = labels.join(".");
+        if name == "retry.test" && DNS_RETRY_DROPPED.swap(1, Ordering::SeqCst) == 0 {
+            continue;
+        }
+
+        // the question followed by the answers, which point to its name
+        let mut response = buf[..offset + 5].to_vec();
+        response[2] = 0x81;
+        if name == "missing.test" {
+            response[3] = 0x83;
+        } else {
+            response[3] = 0x80;
+            response[7] = 2;
+            let ttl: u32 = if name == "short.test" { 1 } else { 300 };
+            for host in [1, 2] {
+                response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+                response.extend_from_slice(&ttl.to_be_bytes());
+                response.extend_from_slice(&[0, 4, 10, 0, 0, host]);
+            }
+        }
+        socket.send_to(&response, source).unwrap();
+    }
+    DNS_SERVER_STATE.store(2, Ordering::SeqCst);
+}
+
+fn test_dns() {
+    let query = dns::query(0x1234, "Example.COM.").unwrap();
+    assert_eq!(query[..4], [0x12, 0x34, 0x01, 0x00]);
+    assert_eq!(&query[12..], b"\x07example\x03com\x00\x00\x01\x00\x01");
+    assert_eq!(dns::query(1, "bad..name"), Err(NetError::InvalidName));
+    assert_eq!(dns::query(1, &"a".repeat(64)), Err(NetError::InvalidName));
+
+    // a CNAME the server followed precedes the A record
+    let mut response = query.clone();
+    response[2] = 0x81;
+    response[3] = 0x80;
+    response[7] = 2;
+    response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+    response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 93, 184, 216, 34]);
+    assert_eq!(
+        dns::parse_response(&response),
+        Ok(Answer {
+            addresses: vec![Ipv4Address::new(93, 184, 216, 34)],
+            ttl: Duration::from_secs(30),
+        })
+    );
+    assert_eq!(
+        dns::parse_response(&response[..response.len() - 2]),
+        Err(NetError::Truncated)
+    );
+    assert_eq!(dns::parse_response(&query), Err(NetError::Unsupported));
+    response[3] = 0x83;
+    assert_eq!(dns::parse_response(&response), Err(NetError::NameNotFound));
+
+    let resolve = |name: &'static str| executor::block_on(dns::resolve(name)).unwrap();
+    assert_eq!(resolve("10.0.2.2"), Ok(vec![Ipv4Address::new(10, 0, 2, 2)]));
+    assert_eq!(resolve("localhost"), Ok(vec![Ipv4Address::LOCALHOST]));
+    assert_eq!(resolve("host.test"), Err(NetError::NoRoute));
+
+    scheduler::spawn(dns_server, ThreadPriority::Normal).expect("Failed to spawn thread");
+    while DNS_SERVER_STATE.load(Ordering::SeqCst) == 0 {
+        thread::sleep_ms(10);
+    }
+    dns::set_servers(&[Ipv4Address::LOCALHOST]);
+    let addresses = vec![Ipv4Address::new(10, 0, 0, 1), Ipv4Address::new(10, 0, 0, 2)];
+    assert_eq!(resolve("host.test"), Ok(addresses.clone()));
+    assert_eq!(DNS_QUERIES.load(Ordering::SeqCst), 1);
+
+    // answered from the cache until the time to live passed
+    assert_eq!(resolve("HOST.test."), Ok(addresses.clone()));
+    assert_eq!(dns::cached("host.test"), Some(addresses.clone()));
+    assert_eq!(resolve("short.test"), Ok(addresses.clone()));
+    assert_eq!(DNS_QUERIES.load(Ordering::SeqCst), 2);
+    thread::sleep_ms(1100);
+    assert_eq!(dns::cached("short.test"), None);
+    assert_eq!(resolve("short.test"), Ok(addresses.clone()));
+    assert_eq!(DNS_QUERIES.load(Ordering::SeqCst), 3);
+
+    // the lost query is sent again after the timeout
+    let start = time::uptime();
+    assert_eq!(resolve("retry.test"), Ok(addresses.clone()));
+    assert!(time::uptime() - start >= dns::TIMEOUT);
+    assert_eq!(DNS_QUERIES.load(Ordering::SeqCst), 5);
+
+    assert_eq!(resolve("missing.test"), Err(NetError::NameNotFound));
+    assert_eq!(dns::cached("missing.test"), None);
+
+    // the same over the system call
+    let mut raw = [0u32; 1];
+    for (name, expected) in [("sys.test", Ok(1)), ("missing.test", Err(Errno::ENOENT))] {
+        assert_eq!(
+            unsafe {
+                syscall::syscall4(
+                    Syscall::Resolve,
+                    name.as_ptr() as u64,
+                    name.len() as u64,
+                    raw.as_mut_ptr() as u64,
+                    raw.len() as u64,
+                )
+            },
+            expected
+        );
+    }
+    assert_eq!(raw[0], addresses[0].as_u32());
+
+    UdpSocket::new()
+        .send_to(
+            b"stop",
+            SocketAddress::new(Ipv4Address::LOCALHOST, dns::PORT),
+        )
+        .unwrap();
+    while DNS_SERVER_STATE.load(Ordering::SeqCst) != 2 {
+        thread::sleep_ms(10);
+    }
+    dns::set_servers(&[]);
+    dns::flush_cache();
+}
+
+const HTTP_SERVER_PORT: u16 = 8080;
+
+/// Requests the test sends to the server
+const HTTP_REQUESTS: u64 = 5;
+
+static HTTP_SERVER_STATE: AtomicU64 = AtomicU64::new(0);
+
+fn http_payload() -> Vec<u8> {
+    (0..100 * 1024).map(|i| (i * 13 % 256) as u8).collect()
+}
+
+/// Serves `/payload`, `/nolength` without a content length, `/short` which
+/// ends before its content length and 404 for everything else
+fn http_server() {
+    let listener =
+        TcpListener::bind(SocketAddress::new(Ipv4Address::LOCALHOST, HTTP_SERVER_PORT)).unwrap();
+    HTTP_SERVER_STATE.store(1, Ordering::SeqCst);
+
+    for _ in 0..HTTP_REQUESTS {
+        let stream = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 512];
+        while !request.ends_with(b"\r\n\r\n") {
+            let len = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..len]);
+        }
+        let request = core::str::from_utf8(&request).unwrap();
+        assert!(request.contains("\r\nHost: "));
+        let path = request.split(' ').nth(1).unwrap();
+
+        match path {
+            "/payload" => {
+                let payload = http_payload();
+                let head = format!(
+                    "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n",
+                    payload.len()
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(&payload).unwrap();
+            }
+            "/nolength" => stream
+                .write_all(b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nno length")
+                .unwrap(),
+            "/short" => stream
+                .write_all(b"HTTP/1.0 200 OK\r\ncontent-length: 100\r\n\r\nshort")
+                .unwrap(),
+            _ => stream
+                .write_all(b"HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .unwrap(),
+        }
+    }
+    // refuses connections from now on
+    drop(listener);
+    HTTP_SERVER_STATE.store(2, Ordering::SeqCst);
+}
+
+fn test_http() {
+    assert_eq!(
+        http::Url::parse("http://example.com:8080/dir/file?x=1"),
+        Ok(http::Url {
+            host: String::from("example.com"),
+            port: 8080,
+            path: String::from("/dir/file?x=1"),
+        })
+    );
+    let url = http::Url::parse("http://example.com").unwrap();
+    assert_eq!((url.port, url.path.as_str()), (http::DEFAULT_PORT, "/"));
+    assert_eq!(
+        http::Url::parse("ftp://example.com/file"),
+        Err(NetError::InvalidUrl)
+    );
+    assert_eq!(
+        http::Url::parse("http://example.com:port/"),
+        Err(NetError::InvalidUrl)
+    );
+
+    scheduler::spawn(http_server, ThreadPriority::Normal).expect("Failed to spawn thread");
+    while HTTP_SERVER_STATE.load(Ordering::SeqCst) == 0 {
+        thread::sleep_ms(10);
+    }
+
+    // downloading twice replaces the file
+    let payload = http_payload();
+    for _ in 0..2 {
+        assert_eq!(
+            http::download("http://127.0.0.1:8080/payload", "/payload"),
+            Ok(payload.len())
+        );
+    }
+    assert_eq!(
+        vfs::lookup("/payload").unwrap().size(),
+        payload.len() as u64
+    );
+    assert!(vfs::lookup("/payload.part").is_err());
+    let file = vfs::open("/payload").unwrap();
+    let mut contents = vec![0u8; payload.len() + 1];
+    let mut len = 0;
+    loop {
+        let read = file.read(&mut contents[len..]).unwrap();
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+    assert!(contents[..len] == payload[..]);
+    drop(file);
+    vfs::unlink("/payload").unwrap();
+
+    assert_eq!(
+        http::get("http://localhost:8080/nolength"),
+        Ok(b"no length".to_vec())
+    );
+    assert_eq!(
+        http::get("http://127.0.0.1:8080/short"),
+        Err(DownloadError::Net(NetError::Truncated))
+    );
+
+    // failed downloads leave nothing behind
+    assert_eq!(
+        http::download("http://127.0.0.1:8080/missing", "/missing"),
+        Err(DownloadError::Net(NetError::HttpStatus(404)))
+    );
+    assert!(vfs::lookup("/missing").is_err());
+    assert!(vfs::lookup("/missing.part").is_err());
+
+    while HTTP_SERVER_STATE.load(Ordering::SeqCst) != 2 {
+        thread::sleep_ms(10);
+    }
+    assert_eq!(
+        http::get("http://127.0.0.1:8080/payload"),
+        Err(DownloadError::Net(NetError::ConnectionRefused))
+    );
+}
+
+fn test_dhcp() {
+    let discover = dhcp::message(MessageType::Discover, 0x1234_5678, CaptureDevice::MAC, None);
+    assert_eq!(
+        discover[..12],
+        [1, 1, 6, 0, 0x12, 0x34, 0x56, 0x78, 0, 0, 0x80, 0]
+    );
+    assert_eq!(discover[28..34], CaptureDevice::MAC.0);
+    assert_eq!(discover[236..243], [99, 130, 83, 99, 53, 1, 1]);
+    let server = Ipv4Address::new(10, 0, 2, 2);
+    let offered = Ipv4Address::new(10, 0, 2, 15);
+    let request = dhcp::message(
+        MessageType::Request,
+        0x1234_5678,
+        CaptureDevice::MAC,
+        Some((offered, server)),
+    );
+    assert_eq!(request[240..243], [53, 1, 3]);
+    assert_eq!(request[243..255], [50, 4, 10, 0, 2, 15, 54, 4, 10, 0, 2, 2]);
+
+    let mut ack = discover[..240].to_vec();
+    ack[0] = 2;
+    ack[16..20].copy_from_slice(&offered.0);
+    ack.extend_from_slice(&[53, 1, 5, 54, 4, 10, 0, 2, 2, 0, 1, 4, 255, 255, 255, 0]);
+    ack.extend_from_slice(&[3, 8, 10, 0, 2, 2, 10, 0, 2, 1, 51, 4, 0, 1, 0x51, 0x80]);
+    ack.extend_from_slice(&[6, 8, 10, 0, 2, 3, 8, 8, 8, 8, 255]);
+    assert_eq!(
+        dhcp::parse_reply(&ack),
+        Ok(Lease {
+            message_type: MessageType::Ack,
+            xid: 0x1234_5678,
+            address: offered,
+            server,
+            netmask: Ipv4Address::new(255, 255, 255, 0),
+            router: Some(server),
+            dns_servers: vec![Ipv4Address::new(10, 0, 2, 3), Ipv4Address::new(8, 8, 8, 8)],
+            lease_time: Some(Duration::from_secs(86400)),
+        })
+    );
+    assert_eq!(
+        dhcp::parse_reply(&ack[..ack.len() - 1]),
+        Err(NetError::Truncated)
+    );
+    assert_eq!(dhcp::parse_reply(&discover), Err(NetError::Unsupported));
+
+    // the lease configures the interface, unconfigured ones aren't routed to
+    let lease = dhcp::parse_reply(&ack).unwrap();
+    assert_eq!(lease.config().gateway, Some(server));
+    let device = Arc::new(CaptureDevice {
+        sent: Mutex::new(Vec::new()),
+    });
+    let interface = interface::add(device.clone(), Ipv4Config::UNCONFIGURED).unwrap();
+    assert!(interface::route(offered).is_none());
+    interface.set_config(lease.config());
+    assert_eq!(interface::route(offered).unwrap().0.name(), "test0");
+
+    // requests are broadcast before the interface has an address
+    interface.set_config(Ipv4Config::UNCONFIGURED);
+    ipv4::broadcast(&interface, Protocol::Udp, &[0; 8]).unwrap();
+    let sent = device.take();
+    let frame = EthernetFrame::parse(&sent[0]).unwrap();
+    assert!(frame.destination.is_broadcast());
+    let packet = Ipv4Packet::parse(frame.payload).unwrap();
+    assert_eq!(packet.source, Ipv4Address::UNSPECIFIED);
+    assert!(packet.destination.is_broadcast());
+    interface::remove("test0").unwrap();
+}
+
+kernel_test!(
+    test_network,
+    test_icmp,
+    test_udp,
+    test_tcp,
+    test_dns,
+    test_dhcp,
+    test_http
+);
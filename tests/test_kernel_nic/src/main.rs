@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+use api::BootInfo;
+use core::{panic::PanicInfo, time::Duration};
+use kernel::{
+    kernel_init, kernel_test,
+    net::{dhcp, dns, icmp, interface, Ipv4Address, Ipv4Config, MacAddress},
+    scheduler, test, time,
+};
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    test::panicked(info)
+}
+
+#[no_mangle]
+#[link_section = ".start"]
+pub extern "C" fn _start(info: &'static BootInfo) -> ! {
+    start(info);
+}
+
+fn start(info: &'static BootInfo) -> ! {
+    kernel_init(info).unwrap();
+    test::run()
+}
+
+fn test_e1000() {
+    let eth0 = interface::get("eth0").expect("no network card");
+    // default address of the first card in QEMU
+    assert_eq!(eth0.mac(), MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]));
+}
+
+fn test_dhcp() {
+    let eth0 = interface::get("eth0").unwrap();
+    // the lease is requested in the background while booting
+    let deadline = time::uptime() + dhcp::TIMEOUT * 2 * dhcp::ATTEMPTS as u32;
+    while eth0.config() == Ipv4Config::UNCONFIGURED && time::uptime() < deadline {
+        scheduler::sleep(Duration::from_millis(10));
+    }
+
+    // addresses handed out by QEMU's user networking
+    assert_eq!(
+        eth0.config(),
+        Ipv4Config {
+            address: Ipv4Address::new(10, 0, 2, 15),
+            netmask: Ipv4Address::new(255, 255, 255, 0),
+            gateway: Some(Ipv4Address::new(10, 0, 2, 2)),
+        }
+    );
+    assert_eq!(dns::servers(), [Ipv4Address::new(10, 0, 2, 3)]);
+}
+
+fn test_ping_gateway() {
+    let eth0 = interface::get("eth0").unwrap();
+    let gateway = Ipv4Address::new(10, 0, 2, 2);
+    // the first request waits for the gateway to be resolved
+    for sequence in 0..3 {
+        let rtt = icmp::ping(gateway, sequence, icmp::DEFAULT_TIMEOUT).unwrap();
+        assert!(rtt < icmp::DEFAULT_TIMEOUT);
+    }
+    assert!(eth0.arp_entries().iter().any(|(ip, _)| *ip == gateway));
+
+    let stats = eth0.stats();
+    // an ARP reply and the echo replies
+    assert!(stats.rx_packets >= 4, "{:?}", stats);
+    assert!(stats.tx_packets >= 4, "{:?}", stats);
+    assert_eq!(stats.tx_dropped, 0);
+}
+
+kernel_test!(test_e1000, test_dhcp, test_ping_gateway);
@@ -0,0 +1,414 @@
+#![no_std]
+#![no_main]
+use api::BootInfo;
+use core::{
+    arch::asm,
+    panic::PanicInfo,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+use kernel::{
+    fs::{self, FileDescriptor},
+    ipc::{self, EndpointId, RawMessage},
+    kernel_init, kernel_test,
+    memory::{self, ShmKey, VirtualMemoryObject, VIRTUAL_MEMORY_SIZE, VIRTUAL_MEMORY_START},
+    process::{
+        self,
+        signal::{self, Signal, SignalAction},
+        Pid, ProcessError,
+    },
+    scheduler::{self, thread, ThreadPriority},
+    syscall::{self, Errno, Syscall},
+    test,
+};
+use x86_64::{
+    memory::{Address, Page, PageSize, Size4KiB},
+    mutex::Mutex,
+    paging::{PageTableEntryFlags, Translator},
+};
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    test::panicked(info)
+}
+
+#[no_mangle]
+#[link_section = ".start"]
+pub extern "C" fn _start(info: &'static BootInfo) -> ! {
+    start(info);
+}
+
+fn start(info: &'static BootInfo) -> ! {
+    kernel_init(info).unwrap();
+    test::run()
+}
+
+fn child_process() -> i32 {
+    let address = process::allocate(
+        Size4KiB::SIZE,
+        PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE,
+        VirtualMemoryObject::LazyAnonymous,
+    )
+    .expect("Failed to allocate process memory");
+    unsafe { address.as_mut_ptr::<u64>().write(42) };
+
+    thread::sleep_ms(10);
+    unsafe { address.as_mut_ptr::<u64>().read() as i32 }
+}
+
+fn test_processes() {
+    assert_eq!(process::wait(), Err(ProcessError::NoChild));
+
+    let first = process::spawn(child_process, ThreadPriority::Normal).unwrap();
+    let second = process::spawn(child_process, ThreadPriority::Normal).unwrap();
+
+    assert_eq!(process::waitpid(Some(second)), Ok((second, 42)));
+    assert_eq!(process::wait(), Ok((first, 42)));
+    assert_eq!(process::wait(), Err(ProcessError::NoChild));
+}
+
+static SIGNAL_HANDLED: AtomicU32 = AtomicU32::new(0);
+
+fn sigusr1_handler(signal: Signal) {
+    assert_eq!(signal, Signal::SIGUSR1);
+    SIGNAL_HANDLED.fetch_add(1, Ordering::SeqCst);
+}
+
+fn signal_handling_process() -> i32 {
+    signal::set_action(Signal::SIGUSR1, SignalAction::Handler(sigusr1_handler)).unwrap();
+    while SIGNAL_HANDLED.load(Ordering::SeqCst) == 0 {
+        thread::sleep_ms(5);
+        signal::deliver_pending();
+    }
+    7
+}
+
+fn signal_ignoring_process() -> i32 {
+    signal::set_action(Signal::SIGTERM, SignalAction::Ignore).unwrap();
+    assert!(signal::set_action(Signal::SIGKILL, SignalAction::Ignore).is_err());
+    loop {
+        thread::sleep_ms(5);
+        signal::deliver_pending();
+    }
+}
+
+static STOPPABLE_ITERATIONS: AtomicU32 = AtomicU32::new(0);
+
+fn stoppable_process() -> i32 {
+    loop {
+        STOPPABLE_ITERATIONS.fetch_add(1, Ordering::SeqCst);
+        thread::sleep_ms(2);
+        signal::deliver_pending();
+    }
+}
+
+fn unmapped_access_process() -> i32 {
+    // nothing is mapped at the end of the memory manager's range
+    let address = VIRTUAL_MEMORY_START + VIRTUAL_MEMORY_SIZE - Size4KiB::SIZE;
+    unsafe { address.as_ptr::<u64>().read_volatile() as i32 }
+}
+
+fn invalid_opcode_process() -> i32 {
+    unsafe { asm!("ud2") };
+    0
+}
+
+static FAULTING_THREAD_RAN: AtomicU32 = AtomicU32::new(0);
+
+fn divide_by_zero_thread() {
+    FAULTING_THREAD_RAN.fetch_add(1, Ordering::SeqCst);
+    unsafe { asm!("xor ecx, ecx", "div ecx", out("eax") _, out("ecx") _, out("edx") _) };
+    unreachable!("Thread survived dividing by zero");
+}
+
+fn test_faulting_threads() {
+    let segfault = process::spawn(unmapped_access_process, ThreadPriority::Normal).unwrap();
+    let illegal = process::spawn(invalid_opcode_process, ThreadPriority::Normal).unwrap();
+    assert_eq!(
+        process::waitpid(Some(segfault)),
+        Ok((segfault, 128 + Signal::SIGSEGV.number() as i32))
+    );
+    assert_eq!(
+        process::waitpid(Some(illegal)),
+        Ok((illegal, 128 + Signal::SIGILL.number() as i32))
+    );
+
+    // kernel threads exit without taking anything else down
+    let thread = scheduler::spawn(divide_by_zero_thread, ThreadPriority::Normal).unwrap();
+    thread::sleep_ms(20);
+    assert_eq!(FAULTING_THREAD_RAN.load(Ordering::SeqCst), 1);
+    assert!(scheduler::with_scheduler(|s| s
+        .thread(thread)
+        .map_or(true, |t| t.state() == scheduler::ThreadState::Exited)));
+}
+
+fn kill(pid: Pid, signal: Signal) -> Result<u64, Errno> {
+    unsafe { syscall::syscall3(Syscall::Kill, pid.as_u64(), signal.number() as u64, 0) }
+}
+
+fn test_signals() {
+    let handling = process::spawn(signal_handling_process, ThreadPriority::Normal).unwrap();
+    let ignoring = process::spawn(signal_ignoring_process, ThreadPriority::Normal).unwrap();
+    thread::sleep_ms(20);
+
+    assert_eq!(kill(handling, Signal::SIGUSR1), Ok(0));
+    assert_eq!(process::waitpid(Some(handling)), Ok((handling, 7)));
+    assert_eq!(SIGNAL_HANDLED.load(Ordering::SeqCst), 1);
+
+    // SIGTERM is ignored, SIGKILL can't be
+    assert_eq!(kill(ignoring, Signal::SIGTERM), Ok(0));
+    thread::sleep_ms(20);
+    assert_eq!(kill(ignoring, Signal::SIGKILL), Ok(0));
+    assert_eq!(
+        process::waitpid(Some(ignoring)),
+        Ok((ignoring, 128 + Signal::SIGKILL.number() as i32))
+    );
+
+    // a stopped process only runs again once continued or killed
+    let stoppable = process::spawn(stoppable_process, ThreadPriority::Normal).unwrap();
+    let is_stopped = || {
+        process::with_process_table(|table| table.get(stoppable).unwrap().signals().is_stopped())
+    };
+    thread::sleep_ms(20);
+    assert_eq!(kill(stoppable, Signal::SIGSTOP), Ok(0));
+    thread::sleep_ms(20);
+    assert!(is_stopped());
+    let iterations = STOPPABLE_ITERATIONS.load(Ordering::SeqCst);
+    thread::sleep_ms(20);
+    assert_eq!(STOPPABLE_ITERATIONS.load(Ordering::SeqCst), iterations);
+
+    assert_eq!(kill(stoppable, Signal::SIGCONT), Ok(0));
+    thread::sleep_ms(20);
+    assert!(!is_stopped());
+    assert!(STOPPABLE_ITERATIONS.load(Ordering::SeqCst) > iterations);
+
+    assert_eq!(kill(stoppable, Signal::SIGSTOP), Ok(0));
+    thread::sleep_ms(20);
+    assert_eq!(kill(stoppable, Signal::SIGKILL), Ok(0));
+    assert_eq!(
+        process::waitpid(Some(stoppable)),
+        Ok((stoppable, 128 + Signal::SIGKILL.number() as i32))
+    );
+
+    assert_eq!(kill(ignoring, Signal::SIGTERM), Err(Errno::ESRCH));
+    assert_eq!(
+        unsafe { syscall::syscall3(Syscall::Kill, handling.as_u64(), 5, 0) },
+        Err(Errno::EINVAL)
+    );
+}
+
+static PIPE_FDS: Mutex<Option<(FileDescriptor, FileDescriptor)>> = Mutex::new(None);
+
+fn pipe_writing_process() -> i32 {
+    let (reader, writer) = PIPE_FDS.lock().unwrap();
+    fs::close(reader).unwrap();
+
+    // more than fits into the pipe at once, so the writer has to block
+    let data = [0xabu8; 1024];
+    for _ in 0..12 {
+        let mut written = 0;
+        while written < data.len() {
+            written += fs::write(writer, &data[written..]).unwrap();
+        }
+    }
+    0
+}
+
+fn test_pipes() {
+    let mut fds = [0u32; 2];
+    unsafe { syscall::syscall3(Syscall::Pipe, fds.as_mut_ptr() as u64, 0, 0) }.unwrap();
+    let reader = FileDescriptor::from_u64(fds[0] as u64);
+    let writer = FileDescriptor::from_u64(fds[1] as u64);
+    *PIPE_FDS.lock() = Some((reader, writer));
+
+    let child = process::spawn(pipe_writing_process, ThreadPriority::Normal).unwrap();
+    fs::close(writer).unwrap();
+
+    let mut buf = [0u8; 1000];
+    let mut total = 0;
+    loop {
+        let read = unsafe {
+            syscall::syscall3(
+                Syscall::Read,
+                reader.as_u64(),
+                buf.as_mut_ptr() as u64,
+                buf.len() as u64,
+            )
+        }
+        .unwrap() as usize;
+        if read == 0 {
+            break;
+        }
+        assert!(buf[..read].iter().all(|b| *b == 0xab));
+        total += read;
+    }
+    assert_eq!(total, 3 * 4096);
+    assert_eq!(process::waitpid(Some(child)), Ok((child, 0)));
+    fs::close(reader).unwrap();
+    assert_eq!(
+        unsafe { syscall::syscall3(Syscall::Close, reader.as_u64(), 0, 0) },
+        Err(Errno::EBADF)
+    );
+
+    // writing without readers
+    let (reader, writer) = fs::pipe::open();
+    fs::close(reader).unwrap();
+    assert_eq!(
+        unsafe { syscall::syscall3(Syscall::Write, writer.as_u64(), buf.as_ptr() as u64, 1) },
+        Err(Errno::EPIPE)
+    );
+    fs::close(writer).unwrap();
+}
+
+const SHM_KEY: ShmKey = ShmKey::new(0x5348);
+
+fn shm_writing_process() -> i32 {
+    let address = memory::shm_map(
+        SHM_KEY,
+        PageTableEntryFlags::PRESENT
+            | PageTableEntryFlags::WRITABLE
+            | PageTableEntryFlags::NO_EXECUTE,
+    )
+    .unwrap();
+    // last page, to check that all frames are mapped in order
+    let ptr: *mut u64 = (address + Size4KiB::SIZE).as_mut_ptr();
+    unsafe { ptr.write_volatile(0xdead_beef) };
+    // the mapping is removed on exit
+    0
+}
+
+fn test_shared_memory() {
+    let segment = memory::shm_create(SHM_KEY, 2 * Size4KiB::SIZE).unwrap();
+    assert!(memory::shm_create(SHM_KEY, Size4KiB::SIZE).is_err());
+
+    let read_only = memory::shm_map(
+        SHM_KEY,
+        PageTableEntryFlags::PRESENT | PageTableEntryFlags::NO_EXECUTE,
+    )
+    .unwrap();
+    let ptr: *const u64 = (read_only + Size4KiB::SIZE).as_ptr();
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+
+    let child = process::spawn(shm_writing_process, ThreadPriority::Normal).unwrap();
+    assert_eq!(process::waitpid(Some(child)), Ok((child, 0)));
+    assert_eq!(unsafe { ptr.read_volatile() }, 0xdead_beef);
+
+    let (frame, flags) = memory::with_memory_manager(|mm| {
+        mm.page_table()
+            .translate(Page::<Size4KiB>::containing_address(
+                read_only + Size4KiB::SIZE,
+            ))
+    })
+    .unwrap();
+    assert_eq!(frame, segment.frames()[1]);
+    assert!(!flags.contains(PageTableEntryFlags::WRITABLE));
+
+    memory::shm_unmap(read_only).unwrap();
+    memory::shared::shm_remove(SHM_KEY).unwrap();
+    assert!(memory::shm_map(SHM_KEY, PageTableEntryFlags::PRESENT).is_err());
+
+    // the finalizer frees the frames once the last reference is gone
+    let free = memory::frame_stats().free;
+    drop(segment);
+    thread::sleep_ms(20);
+    assert!(memory::frame_stats().free >= free + 2);
+}
+
+static ENDPOINT: AtomicU64 = AtomicU64::new(0);
+
+fn ipc_receiving_process() -> i32 {
+    let endpoint = ENDPOINT.load(Ordering::SeqCst);
+    let mut message = RawMessage::default();
+    unsafe {
+        syscall::syscall3(
+            Syscall::IpcReceive,
+            endpoint,
+            &mut message as *mut RawMessage as u64,
+            0,
+        )
+    }
+    .unwrap();
+
+    assert_eq!(message.label, 1);
+    assert_eq!(message.words, [1, 2, 3, 4]);
+    assert_eq!(message.sender, 0);
+    assert_eq!(message.grant_size, Size4KiB::SIZE);
+    let granted = message.grant_start as *mut u64;
+    assert_eq!(unsafe { granted.read_volatile() }, 0x1234);
+    unsafe { granted.write_volatile(0x4321) };
+
+    // blocks until the endpoint is destroyed
+    assert_eq!(
+        unsafe {
+            syscall::syscall3(
+                Syscall::IpcReceive,
+                endpoint,
+                &mut message as *mut RawMessage as u64,
+                0,
+            )
+        },
+        Err(Errno::EIDRM)
+    );
+    0
+}
+
+fn test_ipc() {
+    let endpoint = unsafe { syscall::syscall3(Syscall::IpcCreate, 0, 0, 0) }.unwrap();
+    ENDPOINT.store(endpoint, Ordering::SeqCst);
+    let child = process::spawn(ipc_receiving_process, ThreadPriority::Normal).unwrap();
+
+    let region = memory::with_memory_manager(|mm| {
+        mm.allocate(
+            Size4KiB::SIZE,
+            PageTableEntryFlags::PRESENT
+                | PageTableEntryFlags::WRITABLE
+                | PageTableEntryFlags::NO_EXECUTE,
+            VirtualMemoryObject::Anonymous,
+        )
+    })
+    .unwrap();
+    let ptr: *mut u64 = region.as_mut_ptr();
+    unsafe { ptr.write_volatile(0x1234) };
+
+    let message = RawMessage {
+        label: 1,
+        words: [1, 2, 3, 4],
+        grant_start: region.as_u64(),
+        grant_size: Size4KiB::SIZE,
+        sender: 0,
+    };
+    // returns once the child received the message
+    unsafe {
+        syscall::syscall3(
+            Syscall::IpcSend,
+            endpoint,
+            &message as *const RawMessage as u64,
+            0,
+        )
+    }
+    .unwrap();
+
+    thread::sleep_ms(20);
+    assert_eq!(unsafe { ptr.read_volatile() }, 0x4321);
+    // the frames outlive the region of the sender as long as the receiver
+    // maps them
+    assert!(memory::with_memory_manager(|mm| mm
+        .regions()
+        .any(
+            |r| r.start() == region && matches!(r.object(), VirtualMemoryObject::Shared(_))
+        )));
+
+    ipc::destroy(EndpointId::from_u64(endpoint)).unwrap();
+    assert_eq!(process::waitpid(Some(child)), Ok((child, 0)));
+    assert!(ipc::lookup(EndpointId::from_u64(endpoint)).is_err());
+
+    memory::with_memory_manager(|mm| mm.munmap(region)).unwrap();
+}
+
+kernel_test!(
+    test_processes,
+    test_signals,
+    test_faulting_threads,
+    test_pipes,
+    test_shared_memory,
+    test_ipc
+);
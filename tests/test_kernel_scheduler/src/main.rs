@@ -0,0 +1,530 @@
+#![no_std]
+#![no_main]
+use api::BootInfo;
+use core::{
+    arch::asm,
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use kernel::{
+    backtrace,
+    executor::{self, channel, sleep, Event},
+    fs::vfs,
+    interrupts::softirq::{self, SoftIrq, SoftIrqError},
+    kernel_init, kernel_test,
+    process::{self, Pid},
+    scheduler::{
+        self,
+        cpuidle::{self, IdleMethod},
+        stats::{self, Histogram},
+        thread::{self, KernelStack},
+        MultilevelPolicy, ThreadId, ThreadPriority,
+    },
+    sync::WaitQueue,
+    test, time,
+    workqueue::{self, Work, WorkError},
+};
+use x86_64::mutex::InterruptSafeMutex;
+
+extern crate alloc;
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    test::panicked(info)
+}
+
+#[no_mangle]
+#[link_section = ".start"]
+pub extern "C" fn _start(info: &'static BootInfo) -> ! {
+    start(info);
+}
+
+fn start(info: &'static BootInfo) -> ! {
+    kernel_init(info).unwrap();
+    test::run()
+}
+
+fn test_scheduling_policy() {
+    let low = ThreadId::from_u64(100);
+    let normal = ThreadId::from_u64(101);
+    let high = ThreadId::from_u64(102);
+
+    let mut policy = MultilevelPolicy::new();
+    policy.add(low, ThreadPriority::Low);
+    policy.add(normal, ThreadPriority::Normal);
+
+    // highest priority first
+    assert_eq!(policy.pick_next(), Some(normal));
+
+    // preempted once the time slice is used up
+    for _ in 1..ThreadPriority::Normal.time_slice() {
+        assert!(!policy.tick(Some(normal)));
+    }
+    assert!(policy.tick(Some(normal)));
+    policy.requeue(normal);
+    assert_eq!(policy.pick_next(), Some(normal));
+
+    // higher priority thread becoming ready preempts immediately
+    policy.add(high, ThreadPriority::High);
+    assert!(policy.tick(Some(normal)));
+    policy.requeue(normal);
+    assert_eq!(policy.pick_next(), Some(high));
+
+    // woken up threads are boosted by one level until they used up a slice
+    policy.block(high);
+    policy.block(normal);
+    policy.wake(normal, true);
+    assert_eq!(
+        policy.effective_priority(normal),
+        Some(ThreadPriority::High)
+    );
+    assert_eq!(policy.pick_next(), Some(normal));
+    for _ in 0..ThreadPriority::High.time_slice() {
+        policy.tick(Some(normal));
+    }
+    policy.requeue(normal);
+    assert_eq!(
+        policy.effective_priority(normal),
+        Some(ThreadPriority::Normal)
+    );
+
+    // threads waiting too long in a run queue get promoted
+    let mut policy = MultilevelPolicy::new();
+    policy.set_starvation_threshold(ThreadPriority::Normal.time_slice());
+    policy.add(low, ThreadPriority::Low);
+    policy.add(normal, ThreadPriority::Normal);
+    assert_eq!(policy.pick_next(), Some(normal));
+    for _ in 0..ThreadPriority::Normal.time_slice() {
+        policy.tick(Some(normal));
+    }
+    assert_eq!(policy.effective_priority(low), Some(ThreadPriority::Normal));
+    policy.requeue(normal);
+    assert_eq!(policy.pick_next(), Some(low));
+}
+
+static THREAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn counting_thread() {
+    for _ in 0..100 {
+        THREAD_COUNTER.fetch_add(1, Ordering::Relaxed);
+        scheduler::yield_now();
+    }
+}
+
+fn test_threads() {
+    for priority in [
+        ThreadPriority::Low,
+        ThreadPriority::Normal,
+        ThreadPriority::High,
+    ] {
+        scheduler::spawn(counting_thread, priority).expect("Failed to spawn thread");
+    }
+
+    while THREAD_COUNTER.load(Ordering::Relaxed) < 300 {
+        thread::sleep_ms(10);
+    }
+}
+
+static FPU_THREADS_DONE: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps `value` in xmm0 across context switches
+fn check_sse_register(value: u64) {
+    unsafe { asm!("movq xmm0, {}", in(reg) value) };
+    for _ in 0..50 {
+        scheduler::yield_now();
+        let current: u64;
+        unsafe { asm!("movq {}, xmm0", out(reg) current) };
+        assert_eq!(current, value);
+    }
+    FPU_THREADS_DONE.fetch_add(1, Ordering::SeqCst);
+}
+
+fn fpu_thread_a() {
+    check_sse_register(0x1111_2222_3333_4444);
+}
+
+fn fpu_thread_b() {
+    check_sse_register(0x5555_6666_7777_8888);
+}
+
+static STACK_USAGE: AtomicU64 = AtomicU64::new(0);
+
+fn stack_using_thread() {
+    let buf = core::hint::black_box([1u8; 4096]);
+    core::hint::black_box(&buf);
+    let usage = scheduler::stack_usage(scheduler::current()).unwrap();
+    STACK_USAGE.store(usage, Ordering::SeqCst);
+}
+
+fn test_kernel_stacks() {
+    scheduler::spawn(stack_using_thread, ThreadPriority::Normal).expect("Failed to spawn thread");
+    while STACK_USAGE.load(Ordering::SeqCst) == 0 {
+        thread::sleep_ms(10);
+    }
+    let usage = STACK_USAGE.load(Ordering::SeqCst);
+    assert!(
+        usage >= 4096 && usage < thread::KERNEL_STACK_SIZE,
+        "{}",
+        usage
+    );
+    assert_eq!(scheduler::stack_usage(ThreadId::BOOT), None);
+
+    let stack = KernelStack::allocate(thread::KERNEL_STACK_SIZE).unwrap();
+    assert!(stack.canary_intact());
+    assert_eq!(stack.usage(), 0);
+    // overflow into the canary
+    unsafe {
+        stack
+            .top()
+            .as_mut_ptr::<u64>()
+            .sub(stack.size() as usize / 8)
+            .write(0)
+    };
+    assert!(!stack.canary_intact());
+    assert_eq!(stack.usage(), stack.size());
+    stack.free();
+}
+
+fn test_fpu_state() {
+    scheduler::spawn(fpu_thread_a, ThreadPriority::Normal).expect("Failed to spawn thread");
+    scheduler::spawn(fpu_thread_b, ThreadPriority::Normal).expect("Failed to spawn thread");
+
+    while FPU_THREADS_DONE.load(Ordering::SeqCst) < 2 {
+        thread::sleep_ms(10);
+    }
+}
+
+fn test_sleep() {
+    let start = time::uptime();
+    thread::sleep_ms(100);
+    let slept = time::uptime() - start;
+    assert!(
+        slept >= Duration::from_millis(100),
+        "woke up after {:?}",
+        slept
+    );
+
+    let start = time::uptime();
+    thread::sleep_us(500);
+    let slept = time::uptime() - start;
+    assert!(
+        slept >= Duration::from_micros(500),
+        "woke up after {:?}",
+        slept
+    );
+}
+
+fn read_to_string(path: &str) -> String {
+    let file = vfs::open(path).unwrap();
+    let mut contents = Vec::new();
+    let mut buf = [0u8; 64];
+    loop {
+        match file.read(&mut buf).unwrap() {
+            0 => break,
+            len => contents.extend_from_slice(&buf[..len]),
+        }
+    }
+    String::from_utf8(contents).unwrap()
+}
+
+fn test_scheduler_stats() {
+    assert_eq!(Histogram::bucket(Duration::from_nanos(999)), 0);
+    assert_eq!(Histogram::bucket(Duration::from_micros(1)), 1);
+    assert_eq!(Histogram::bucket(Duration::from_micros(3)), 2);
+    assert_eq!(Histogram::bucket(Duration::from_micros(4)), 3);
+    assert_eq!(
+        Histogram::bucket(Duration::from_secs(1)),
+        stats::BUCKETS - 1
+    );
+
+    let mut histogram = Histogram::new();
+    histogram.record(Duration::from_micros(2));
+    histogram.record(Duration::from_micros(6));
+    assert_eq!(histogram.count(), 2);
+    assert_eq!(histogram.buckets()[2], 1);
+    assert_eq!(histogram.buckets()[3], 1);
+    assert_eq!(histogram.mean(), Duration::from_micros(4));
+    assert_eq!(histogram.max(), Duration::from_micros(6));
+
+    // sleeping blocks, waking up makes the thread wait for the CPU again
+    let boot_stats = || scheduler::with_scheduler(|s| *s.thread(ThreadId::BOOT).unwrap().stats());
+    let (before, switches) = (
+        boot_stats(),
+        scheduler::with_scheduler(|s| s.stats().context_switches),
+    );
+    thread::sleep_ms(10);
+    let after = boot_stats();
+    assert_eq!(after.voluntary_switches, before.voluntary_switches + 1);
+    assert_eq!(after.latency.count(), before.latency.count() + 1);
+    assert!(scheduler::with_scheduler(|s| s.stats().context_switches) >= switches + 2);
+
+    let schedstat = read_to_string("/proc/schedstat");
+    assert!(schedstat.starts_with("Switches:\t"));
+    assert!(schedstat.contains("RunQueue:\tmean "));
+    assert!(schedstat.contains("Latency:\tcount "));
+
+    let sched = read_to_string("/proc/0/sched");
+    assert!(sched.starts_with("Thread:\t0\n"));
+    assert!(sched.contains(" voluntary "));
+}
+
+fn test_cpuidle() {
+    let states = cpuidle::states();
+    assert!(!states.is_empty());
+    if cpuidle::method() == IdleMethod::Halt {
+        assert_eq!(states.len(), 1);
+    }
+    assert!(states
+        .windows(2)
+        .all(|w| w[0].target_residency <= w[1].target_residency));
+
+    // nothing else is ready, so the CPU idles until the sleep is over
+    let idled = |states: &[cpuidle::IdleState]| {
+        states
+            .iter()
+            .map(|s| (s.entries, s.residency))
+            .fold((0, Duration::ZERO), |(entries, residency), (e, r)| {
+                (entries + e, residency + r)
+            })
+    };
+    let (entries, residency) = idled(&states);
+    thread::sleep_ms(20);
+    let (entries_after, residency_after) = idled(&cpuidle::states());
+    assert!(entries_after > entries);
+    assert!(residency_after > residency);
+
+    let contents = read_to_string("/proc/cpuidle");
+    assert!(contents.starts_with("Method:\t"));
+    assert!(contents.contains(states[0].name));
+}
+
+static NAMED_QUEUE: WaitQueue = WaitQueue::named("test queue");
+
+static WAITER_DONE: AtomicU64 = AtomicU64::new(0);
+
+fn waiting_thread() {
+    NAMED_QUEUE.wait();
+    WAITER_DONE.store(1, Ordering::SeqCst);
+}
+
+fn named_process() -> i32 {
+    0
+}
+
+fn test_thread_names() {
+    let name = |id| scheduler::with_scheduler(|s| String::from(s.thread(id).unwrap().name()));
+    assert_eq!(name(ThreadId::BOOT), "boot");
+    assert_eq!(name(scheduler::idle().unwrap()), "idle");
+
+    let id = scheduler::spawn(waiting_thread, ThreadPriority::Normal).unwrap();
+    if backtrace::symbols().is_some() {
+        assert_eq!(name(id), "waiting_thread");
+    }
+    scheduler::set_name(id, "waiter");
+    assert_eq!(name(id), "waiter");
+
+    while NAMED_QUEUE.is_empty() {
+        thread::sleep_ms(10);
+    }
+    let state = scheduler::with_scheduler(|s| s.thread(id).unwrap().describe_state());
+    assert_eq!(state, "blocked (test queue)");
+    let threads = read_to_string("/proc/threads");
+    assert!(threads.contains(&format!("{}\t0\twaiter\tblocked (test queue)\n", id)));
+    assert!(threads.contains("\tboot\trunning\n"));
+
+    NAMED_QUEUE.wake_one();
+    while WAITER_DONE.load(Ordering::SeqCst) == 0 {
+        thread::sleep_ms(10);
+    }
+
+    let pid = process::spawn(named_process, ThreadPriority::Normal).unwrap();
+    let process_name =
+        process::with_process_table(|table| String::from(table.get(pid).unwrap().name()));
+    if backtrace::symbols().is_some() {
+        assert_eq!(process_name, "named_process");
+    }
+    let status = read_to_string(&format!("/proc/{}/status", pid));
+    assert!(status.starts_with(&format!("Name:\t{}\n", process_name)));
+    process::waitpid(Some(pid)).unwrap();
+    assert!(process::with_process_table(|table| table
+        .get(Pid::KERNEL)
+        .unwrap()
+        .name()
+        == "kernel"));
+}
+
+static WORK_DONE: AtomicU64 = AtomicU64::new(0);
+
+fn test_workqueue() {
+    let completed = workqueue::completed();
+    for i in 1..=10 {
+        workqueue::queue(move || {
+            WORK_DONE.fetch_add(i, Ordering::SeqCst);
+        })
+        .unwrap();
+    }
+    while WORK_DONE.load(Ordering::SeqCst) < 55 {
+        thread::sleep_ms(10);
+    }
+    assert_eq!(WORK_DONE.load(Ordering::SeqCst), 55);
+
+    // the workers can't run while interrupts are disabled
+    let full = x86_64::interrupts::without_interrupts(|| {
+        (0..=workqueue::CAPACITY)
+            .map(|_| workqueue::queue(|| ()))
+            .filter(|r| *r == Err(WorkError::QueueFull))
+            .count()
+    });
+    assert_eq!(full, 1);
+    while workqueue::pending() > 0 {
+        thread::sleep_ms(10);
+    }
+    assert!(workqueue::completed() >= completed + 10);
+
+    // unrun work drops its captures
+    let captured = Arc::new(0u64);
+    let work = Work::new({
+        let captured = captured.clone();
+        move || drop(captured)
+    });
+    assert_eq!(Arc::strong_count(&captured), 2);
+    drop(work);
+    assert_eq!(Arc::strong_count(&captured), 1);
+}
+
+static SOFTIRQ_ORDER: InterruptSafeMutex<Vec<SoftIrq>> = InterruptSafeMutex::new(Vec::new());
+
+fn net_rx_softirq() {
+    SOFTIRQ_ORDER.lock().push(SoftIrq::NetRx);
+}
+
+fn timer_softirq() {
+    SOFTIRQ_ORDER.lock().push(SoftIrq::Timer);
+}
+
+fn test_softirqs() {
+    softirq::register(SoftIrq::NetRx, net_rx_softirq).unwrap();
+    softirq::register(SoftIrq::Timer, timer_softirq).unwrap();
+    assert_eq!(
+        softirq::register(SoftIrq::NetRx, net_rx_softirq),
+        Err(SoftIrqError::AlreadyRegistered)
+    );
+    // taken by the executor
+    assert_eq!(
+        softirq::register(SoftIrq::BlockComplete, net_rx_softirq),
+        Err(SoftIrqError::AlreadyRegistered)
+    );
+
+    // both run at the same exit, in priority order
+    let count = softirq::count(SoftIrq::NetRx);
+    x86_64::interrupts::without_interrupts(|| {
+        // timer interrupts ran the timer handler already
+        SOFTIRQ_ORDER.lock().clear();
+        softirq::raise(SoftIrq::NetRx);
+        softirq::raise(SoftIrq::Timer);
+        assert!(softirq::is_pending(SoftIrq::NetRx));
+        softirq::irq_exit();
+        softirq::unregister(SoftIrq::Timer);
+    });
+    assert!(!softirq::is_pending(SoftIrq::NetRx));
+    assert_eq!(*SOFTIRQ_ORDER.lock(), [SoftIrq::Timer, SoftIrq::NetRx]);
+    assert_eq!(softirq::count(SoftIrq::NetRx), count + 1);
+
+    // raised outside of an interrupt, run on the exit of the next one
+    SOFTIRQ_ORDER.lock().clear();
+    softirq::raise(SoftIrq::NetRx);
+    thread::sleep_ms(20);
+    assert_eq!(*SOFTIRQ_ORDER.lock(), [SoftIrq::NetRx]);
+
+    softirq::unregister(SoftIrq::NetRx);
+    assert!(read_to_string("/proc/softirqs").contains("block-complete"));
+}
+
+static EXECUTOR_RESULT: AtomicU64 = AtomicU64::new(0);
+
+fn wait_for_executor_result(expected: u64) {
+    while EXECUTOR_RESULT.load(Ordering::SeqCst) != expected {
+        thread::sleep_ms(10);
+    }
+}
+
+fn test_executor() {
+    let completed = executor::completed();
+
+    // sleeping tasks finish in deadline order
+    let (sender, mut receiver) = channel::channel();
+    for (delay, value) in [(60, 3u64), (20, 1), (40, 2)] {
+        let sender = sender.clone();
+        executor::spawn(async move {
+            let start = time::uptime();
+            sleep::sleep(Duration::from_millis(delay)).await;
+            assert!(time::uptime() - start >= Duration::from_millis(delay));
+            sender.send(value).unwrap();
+        })
+        .unwrap();
+    }
+    drop(sender);
+    executor::spawn(async move {
+        let mut expected = 1;
+        while let Some(value) = receiver.recv().await {
+            assert_eq!(value, expected);
+            expected += 1;
+        }
+        EXECUTOR_RESULT.store(expected, Ordering::SeqCst);
+    })
+    .unwrap();
+    wait_for_executor_result(4);
+
+    // a thread sending to a task
+    let (sender, mut receiver) = channel::channel();
+    executor::spawn(async move {
+        let mut sum = 0;
+        while let Some(value) = receiver.recv().await {
+            sum += value;
+        }
+        EXECUTOR_RESULT.store(sum, Ordering::SeqCst);
+    })
+    .unwrap();
+    for i in 1..=10 {
+        sender.send(i).unwrap();
+    }
+    drop(sender);
+    wait_for_executor_result(55);
+
+    let (sender, receiver) = channel::channel();
+    drop(receiver);
+    assert_eq!(sender.send(1), Err(1));
+
+    // block completions wake the waiting tasks
+    EXECUTOR_RESULT.store(0, Ordering::SeqCst);
+    executor::spawn(async {
+        Event::BlockComplete.next().await;
+        EXECUTOR_RESULT.store(1, Ordering::SeqCst);
+    })
+    .unwrap();
+    thread::sleep_ms(20);
+    assert_eq!(EXECUTOR_RESULT.load(Ordering::SeqCst), 0);
+    softirq::raise(SoftIrq::BlockComplete);
+    wait_for_executor_result(1);
+
+    while executor::tasks() > 0 {
+        thread::sleep_ms(10);
+    }
+    assert_eq!(executor::completed(), completed + 6);
+}
+
+kernel_test!(
+    test_scheduling_policy,
+    test_threads,
+    test_fpu_state,
+    test_kernel_stacks,
+    test_sleep,
+    test_scheduler_stats,
+    test_cpuidle,
+    test_thread_names,
+    test_workqueue,
+    test_softirqs,
+    test_executor
+);
@@ -0,0 +1,201 @@
+#![no_std]
+#![no_main]
+use api::BootInfo;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+use kernel::{
+    kernel_init, kernel_test,
+    scheduler::{self, thread, ThreadPriority},
+    sync::{
+        futex::{FUTEX_WAIT, FUTEX_WAKE},
+        rcu, rcu_read_lock, synchronize_rcu, BlockingMutex, Condvar, RcuCell, RwLock,
+    },
+    syscall::{self, Errno, Syscall},
+    test,
+};
+use x86_64::mutex::{InterruptSafeMutex, Mutex};
+
+#[panic_handler]
+pub fn panic(info: &PanicInfo) -> ! {
+    test::panicked(info)
+}
+
+#[no_mangle]
+#[link_section = ".start"]
+pub extern "C" fn _start(info: &'static BootInfo) -> ! {
+    start(info);
+}
+
+fn start(info: &'static BootInfo) -> ! {
+    kernel_init(info).unwrap();
+    test::run()
+}
+
+static READY: Mutex<bool> = Mutex::new(false);
+
+static READY_CONDVAR: Condvar = Condvar::new();
+
+fn notifying_thread() {
+    thread::sleep_ms(20);
+    *READY.lock() = true;
+    READY_CONDVAR.notify_all();
+}
+
+fn test_condvar() {
+    scheduler::spawn(notifying_thread, ThreadPriority::Normal).expect("Failed to spawn thread");
+
+    let ready = READY_CONDVAR.wait_while(READY.lock(), |ready| !*ready);
+    assert!(*ready);
+}
+
+fn test_interrupt_safe_mutex() {
+    let mutex = InterruptSafeMutex::new(0);
+    assert!(x86_64::interrupts::are_enabled());
+    {
+        let mut outer = mutex.lock();
+        *outer += 1;
+        assert!(!x86_64::interrupts::are_enabled());
+    }
+    assert!(x86_64::interrupts::are_enabled());
+
+    // nested in a section without interrupts, which has to stay that way
+    x86_64::interrupts::without_interrupts(|| {
+        *mutex.lock() += 1;
+        assert!(!x86_64::interrupts::are_enabled());
+    });
+    assert!(x86_64::interrupts::are_enabled());
+    assert_eq!(*mutex.lock(), 2);
+}
+
+static BLOCKING_COUNTER: BlockingMutex<u64> = BlockingMutex::new(0);
+
+static BLOCKING_THREADS_DONE: AtomicU64 = AtomicU64::new(0);
+
+fn blocking_counter_thread() {
+    for _ in 0..20 {
+        let mut counter = BLOCKING_COUNTER.lock();
+        let value = *counter;
+        // other threads run and block on the mutex in the meantime
+        thread::sleep_ms(1);
+        *counter = value + 1;
+    }
+    BLOCKING_THREADS_DONE.fetch_add(1, Ordering::SeqCst);
+}
+
+fn test_blocking_mutex() {
+    for _ in 0..3 {
+        scheduler::spawn(blocking_counter_thread, ThreadPriority::Normal)
+            .expect("Failed to spawn thread");
+    }
+    while BLOCKING_THREADS_DONE.load(Ordering::SeqCst) < 3 {
+        thread::sleep_ms(10);
+    }
+    assert_eq!(*BLOCKING_COUNTER.lock(), 60);
+    assert!(!BLOCKING_COUNTER.is_locked());
+}
+
+static RW_LOCK: RwLock<u64> = RwLock::new(0);
+
+static RW_READERS_DONE: AtomicU64 = AtomicU64::new(0);
+
+fn rw_reading_thread() {
+    let value = RW_LOCK.read();
+    assert_eq!(*value, 1);
+    RW_READERS_DONE.fetch_add(1, Ordering::SeqCst);
+}
+
+fn test_rwlock() {
+    let mut value = RW_LOCK.write();
+    assert!(RW_LOCK.try_read().is_none());
+    for _ in 0..2 {
+        scheduler::spawn(rw_reading_thread, ThreadPriority::Normal)
+            .expect("Failed to spawn thread");
+    }
+    // the readers block until the writer is done
+    thread::sleep_ms(20);
+    assert_eq!(RW_READERS_DONE.load(Ordering::SeqCst), 0);
+    *value = 1;
+    drop(value);
+
+    while RW_READERS_DONE.load(Ordering::SeqCst) < 2 {
+        thread::sleep_ms(10);
+    }
+    let first = RW_LOCK.read();
+    let second = RW_LOCK.read();
+    assert_eq!(RW_LOCK.readers(), Some(2));
+    assert!(RW_LOCK.try_write().is_none());
+    drop((first, second));
+    assert!(RW_LOCK.try_write().is_some());
+}
+
+static RCU_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+struct RcuValue(u64);
+
+impl Drop for RcuValue {
+    fn drop(&mut self) {
+        RCU_DROPPED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn test_rcu() {
+    let cell = RcuCell::new(RcuValue(1));
+    let guard = rcu_read_lock();
+    let old = cell.read(&guard);
+    cell.replace(RcuValue(2));
+    assert_eq!(cell.read(&guard).0, 2);
+
+    // ticks pass while the reader is still active
+    let start = scheduler::ticks();
+    while scheduler::ticks() < start + 5 {
+        core::hint::spin_loop();
+    }
+    assert_eq!(old.0, 1);
+    assert_eq!(RCU_DROPPED.load(Ordering::SeqCst), 0);
+    assert!(rcu::pending_callbacks() > 0);
+    drop(guard);
+
+    synchronize_rcu();
+    // the finalizer drops the old value
+    while RCU_DROPPED.load(Ordering::SeqCst) == 0 {
+        thread::sleep_ms(10);
+    }
+    drop(cell);
+    assert_eq!(RCU_DROPPED.load(Ordering::SeqCst), 2);
+}
+
+static FUTEX_WORD: AtomicU32 = AtomicU32::new(0);
+
+fn futex_waking_thread() {
+    thread::sleep_ms(20);
+    FUTEX_WORD.store(1, Ordering::SeqCst);
+    let address = &FUTEX_WORD as *const AtomicU32 as u64;
+    unsafe { syscall::syscall3(Syscall::Futex, address, FUTEX_WAKE, 1) }
+        .expect("futex wake failed");
+}
+
+fn test_futex() {
+    let address = &FUTEX_WORD as *const AtomicU32 as u64;
+
+    // value doesn't match, returns immediately
+    assert_eq!(
+        unsafe { syscall::syscall3(Syscall::Futex, address, FUTEX_WAIT, 1) },
+        Err(Errno::EAGAIN)
+    );
+
+    scheduler::spawn(futex_waking_thread, ThreadPriority::Normal).expect("Failed to spawn thread");
+    while FUTEX_WORD.load(Ordering::SeqCst) == 0 {
+        let _ = unsafe { syscall::syscall3(Syscall::Futex, address, FUTEX_WAIT, 0) };
+    }
+}
+
+kernel_test!(
+    test_condvar,
+    test_interrupt_safe_mutex,
+    test_blocking_mutex,
+    test_rwlock,
+    test_rcu,
+    test_futex
+);
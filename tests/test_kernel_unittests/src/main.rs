@@ -1,13 +1,48 @@
 #![no_std]
 #![no_main]
-use api::BootInfo;
-use core::panic::PanicInfo;
-use kernel::{kernel_init, qemu};
-use x86_64::println;
+use api::{
+    symbols::{self, SymbolTable},
+    BootInfo,
+};
+use core::{arch::asm, hint::black_box, mem::size_of, panic::PanicInfo, ptr, time::Duration};
+use kernel::{
+    acpi,
+    allocator::KmemCache,
+    ata::{self, SECTOR_SIZE},
+    backtrace,
+    cmdline::{self, OptionError},
+    fs::vfs,
+    interrupts, kernel_init, kernel_test,
+    log::{self, Level},
+    memory::{self, VIRTUAL_MEMORY_SIZE, VIRTUAL_MEMORY_START},
+    profiler,
+    random::{self, chacha::ChaCha20},
+    test,
+    time::{self, tsc, DateTime},
+    tracepoint,
+};
+use x86_64::{
+    cpuid::{self, CpuInfo, Features},
+    gdt::{
+        self, DescriptorError, SegmentDescriptor, SegmentDescriptorFlags, SegmentKind,
+        SegmentSelector, SystemSegmentType,
+    },
+    idt::{self, InterruptDescriptor},
+    instructions::hlt,
+    interrupts::{DescriptorTable, SelectorErrorCode},
+    memory::{Address, PageSize, Size4KiB},
+    paging::PageTableEntryFlags,
+    println,
+    register::CS,
+    tss::{TaskStateSegment, DOUBLE_FAULT_IST_IDX},
+};
+
+extern crate alloc;
+use alloc::{boxed::Box, format, vec, vec::Vec};
 
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
-    loop {}
+    test::panicked(info)
 }
 
 #[no_mangle]
@@ -20,5 +55,548 @@ fn start(info: &'static BootInfo) -> ! {
     kernel_init(info).unwrap();
     println!("Hello from test kernel");
 
-    qemu::exit(qemu::QemuExitCode::Success);
+    test::run()
+}
+
+fn test_box() {
+    let value = Box::new(42);
+    assert_eq!(*value, 42);
+}
+
+fn test_vec() {
+    let vec: Vec<u64> = (0..1000).collect();
+    assert_eq!(vec.iter().sum::<u64>(), 999 * 1000 / 2);
+}
+
+fn test_assert_fails() {
+    assert_eq!(1 + 1, 3);
+}
+
+fn test_slab_cache_leak() {
+    let mut cache: KmemCache<u64> = KmemCache::new();
+    cache.alloc(1).unwrap();
+    // still holds an object
+    drop(cache);
+}
+
+fn test_unmapped_access() {
+    // nothing is mapped at the end of the memory manager's range
+    let address = VIRTUAL_MEMORY_START + VIRTUAL_MEMORY_SIZE - Size4KiB::SIZE;
+    unsafe { ptr::read_volatile(address.as_ptr::<u64>()) };
+}
+
+fn test_null_deref() {
+    unsafe { ptr::read_volatile(black_box(ptr::null::<u64>())) };
+}
+
+fn test_guard_page_hit() {
+    let top = memory::with_memory_manager(|mm| {
+        mm.allocate_stack(
+            Size4KiB::SIZE,
+            4 * Size4KiB::SIZE,
+            PageTableEntryFlags::PRESENT
+                | PageTableEntryFlags::WRITABLE
+                | PageTableEntryFlags::NO_EXECUTE,
+        )
+        .expect("Failed to allocate stack")
+    });
+
+    // the guard page sits right below the maximum size
+    let guard = top - 5 * Size4KiB::SIZE;
+    unsafe { ptr::read_volatile(guard.as_ptr::<u64>()) };
+}
+
+fn test_double_fault() {
+    // the page fault caused by the push can't store its frame on the
+    // unmapped stack either
+    let stack = VIRTUAL_MEMORY_START + VIRTUAL_MEMORY_SIZE;
+    unsafe {
+        asm!(
+            "mov rsp, {stack}",
+            "push rax",
+            stack = in(reg) stack.as_u64(),
+            options(noreturn)
+        )
+    };
+}
+
+fn test_non_canonical_access() {
+    unsafe { ptr::read_volatile(0x8000_0000_0000_0000 as *const u64) };
+}
+
+fn test_simd_divide_by_zero() {
+    // unmasks the divide by zero exception, the handler masks it again
+    let mxcsr: u32 = 0x1f80 & !(1 << 9);
+    unsafe {
+        asm!(
+            "ldmxcsr [{mxcsr}]",
+            "xorps {zero}, {zero}",
+            "divss {value}, {zero}",
+            mxcsr = in(reg) &mxcsr,
+            zero = out(xmm_reg) _,
+            value = inout(xmm_reg) 1.0f32 => _,
+        )
+    };
+}
+
+fn test_overflow() {
+    unsafe { asm!("int 4") };
+}
+
+fn push_symbol(records: &mut Vec<u8>, names: &mut Vec<u8>, address: u64, size: u64, name: &str) {
+    records.extend_from_slice(&address.to_le_bytes());
+    records.extend_from_slice(&size.to_le_bytes());
+    records.extend_from_slice(&(names.len() as u32).to_le_bytes());
+    records.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    names.extend_from_slice(name.as_bytes());
+}
+
+#[inline(never)]
+fn backtrace_caller() -> (u64, usize) {
+    let mut frames = backtrace::frames();
+    let first = frames.next().unwrap();
+    (first, frames.count() + 1)
+}
+
+fn test_backtrace() {
+    let (mut records, mut names) = (Vec::new(), Vec::new());
+    push_symbol(&mut records, &mut names, 0x1000, 0x10, "first");
+    push_symbol(&mut records, &mut names, 0x2000, 0, "second");
+    let mut table = Vec::new();
+    table.extend_from_slice(&symbols::MAGIC);
+    table.extend_from_slice(&2u64.to_le_bytes());
+    table.extend_from_slice(&records);
+    table.extend_from_slice(&names);
+
+    let symbols = SymbolTable::parse(&table).unwrap();
+    assert_eq!(symbols.len(), 2);
+    assert_eq!(symbols.lookup(0xfff), None);
+    assert_eq!(symbols.lookup(0x100f).unwrap().name, "first");
+    assert_eq!(symbols.lookup(0x1010), None);
+    // unknown size extends up to the end of the address space
+    assert_eq!(symbols.lookup(0x9000).unwrap().name, "second");
+    assert!(SymbolTable::parse(&table[..table.len() - names.len() - 1]).is_err());
+
+    // at least the caller and start are on the stack
+    let (address, depth) = backtrace_caller();
+    assert!(depth >= 2);
+    if backtrace::symbols().is_some() {
+        let (symbol, _) = backtrace::symbolize(address - 1).unwrap();
+        assert!(symbol.name.contains("backtrace_caller"));
+    }
+}
+
+fn test_logging() {
+    assert_eq!("warn".parse(), Ok(Level::Warn));
+    assert!("verbose".parse::<Level>().is_err());
+
+    log::set_target_level("kernel::shell", Some(Level::Trace)).unwrap();
+    assert!(log::enabled(Level::Trace, "kernel::shell"));
+    assert!(log::enabled(Level::Trace, "kernel::shell::commands"));
+    // only whole path components match
+    assert!(!log::enabled(Level::Trace, "kernel::shellcode"));
+    assert!(!log::enabled(Level::Trace, "kernel::fs"));
+    log::set_target_level("kernel::shell", None).unwrap();
+    assert!(!log::enabled(Level::Trace, "kernel::shell"));
+
+    let ring = &log::ring::RING;
+    let mut buf = vec![0; log::ring::RING_SIZE];
+    log::log(Level::Error, "kernel::test", format_args!("logging {}", 42));
+    let len = ring.read(&mut buf);
+    let output = core::str::from_utf8(&buf[..len]).unwrap();
+    assert!(output.ends_with("ERROR kernel::test: logging 42\n"));
+
+    // long records are cut off but still end the line
+    let (_, end) = ring.bounds();
+    let long = format_args!("{:1$}", "", log::ring::MAX_RECORD_SIZE);
+    log::log(Level::Error, "kernel::test", long);
+    let (start, len) = ring.read_at(end, &mut buf);
+    assert_eq!((start, len), (end, log::ring::MAX_RECORD_SIZE));
+    assert_eq!(buf[len - 1], b'\n');
+
+    let written = ring.flush("/dmesg").unwrap();
+    assert_eq!(vfs::lookup("/dmesg").unwrap().size(), written as u64);
+    vfs::unlink("/dmesg").unwrap();
+}
+
+fn test_cpuid() {
+    let cpu = CpuInfo::read();
+    assert!(!cpu.vendor().is_empty());
+    assert!(cpu.family != 0);
+
+    // required to run this kernel at all
+    let required = Features::LONG_MODE | Features::NX | Features::TSC | Features::SSE2;
+    assert!(cpu.features.contains(required), "{:?}", cpu.features);
+    assert!(cpuid::has(Features::NX));
+}
+
+fn test_pstore() {
+    let mut data = [0u8; 64];
+    let text = "Kernel PANIC: test";
+    api::pstore::write_header(&mut data, text.len());
+    data[api::pstore::HEADER_SIZE..][..text.len()].copy_from_slice(text.as_bytes());
+    assert_eq!(api::pstore::parse(&data), Ok(text));
+    assert_eq!(
+        api::pstore::parse(&[0; 64]),
+        Err(api::pstore::PstoreError::Empty)
+    );
+
+    // the boot disk has an MBR with the pstore partition
+    let mut mbr = [0; SECTOR_SIZE];
+    let mut drive = ata::PRIMARY_MASTER.lock();
+    assert!(drive.identify().unwrap() > 0);
+    drive.read(0, &mut mbr).unwrap();
+    assert_eq!(&mbr[510..], &[0x55, 0xaa]);
+    assert!(mbr[446..510]
+        .chunks_exact(16)
+        .any(|entry| entry[4] == api::pstore::PARTITION_TYPE));
+}
+
+fn test_edid() {
+    let mut data = [0u8; api::edid::SIZE];
+    data[..8].copy_from_slice(&api::edid::HEADER);
+    // preferred timing of 1920x1080
+    data[54..62].copy_from_slice(&[0x02, 0x3a, 0x80, 0x18, 0x71, 0x38, 0x2d, 0x40]);
+    let checksum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    data[127] = 0u8.wrapping_sub(checksum);
+
+    let edid = api::edid::Edid::new(data).unwrap();
+    assert_eq!(edid.native_resolution(), Some((1920, 1080)));
+
+    data[127] ^= 1;
+    assert_eq!(
+        api::edid::Edid::new(data),
+        Err(api::edid::EdidError::InvalidChecksum)
+    );
 }
+
+fn test_manifest() {
+    use api::manifest;
+
+    assert_eq!(manifest::crc32(b""), 0);
+    assert_eq!(manifest::crc32(b"123456789"), 0xcbf4_3926);
+
+    let text = "stage3 0000abcd\nkernel cbf43926\nbroken xyz\n";
+    assert_eq!(manifest::lookup(text, "kernel"), Some(0xcbf4_3926));
+    assert_eq!(manifest::lookup(text, "stage3"), Some(0xabcd));
+    assert_eq!(manifest::lookup(text, "broken"), None);
+    assert_eq!(manifest::lookup(text, "stage4"), None);
+}
+
+fn test_ed25519() {
+    use api::{ed25519, sha512};
+
+    let digest = sha512::sha512(b"abc");
+    assert_eq!(
+        digest[..8],
+        [0xdd, 0xaf, 0x35, 0xa1, 0x93, 0x61, 0x7a, 0xba]
+    );
+    assert_eq!(digest[60..], [0xa5, 0x4c, 0xa4, 0x9f]);
+
+    // RFC 8032 7.1, test 2
+    let public_key = [
+        0x3d, 0x40, 0x17, 0xc3, 0xe8, 0x43, 0x89, 0x5a, 0x92, 0xb7, 0x0a, 0xa7, 0x4d, 0x1b, 0x7e,
+        0xbc, 0x9c, 0x98, 0x2c, 0xcf, 0x2e, 0xc4, 0x96, 0x8c, 0xc0, 0xcd, 0x55, 0xf1, 0x2a, 0xf4,
+        0x66, 0x0c,
+    ];
+    let mut signature = [
+        0x92, 0xa0, 0x09, 0xa9, 0xf0, 0xd4, 0xca, 0xb8, 0x72, 0x0e, 0x82, 0x0b, 0x5f, 0x64, 0x25,
+        0x40, 0xa2, 0xb2, 0x7b, 0x54, 0x16, 0x50, 0x3f, 0x8f, 0xb3, 0x76, 0x22, 0x23, 0xeb, 0xdb,
+        0x69, 0xda, 0x08, 0x5a, 0xc1, 0xe4, 0x3e, 0x15, 0x99, 0x6e, 0x45, 0x8f, 0x36, 0x13, 0xd0,
+        0xf1, 0x1d, 0x8c, 0x38, 0x7b, 0x2e, 0xae, 0xb4, 0x30, 0x2a, 0xee, 0xb0, 0x0d, 0x29, 0x16,
+        0x12, 0xbb, 0x0c, 0x00,
+    ];
+    assert_eq!(ed25519::verify(&public_key, &[0x72], &signature), Ok(()));
+    assert_eq!(
+        ed25519::verify(&public_key, &[0x73], &signature),
+        Err(ed25519::SignatureError::InvalidSignature)
+    );
+    signature[0] ^= 1;
+    assert_eq!(
+        ed25519::verify(&public_key, &[0x72], &signature),
+        Err(ed25519::SignatureError::InvalidSignature)
+    );
+}
+
+fn test_compression() {
+    use api::compression::{self, DecompressError};
+
+    // a literal followed by an overlapping match of it
+    let mut data = Vec::from(compression::MAGIC);
+    data.extend_from_slice(&8u64.to_le_bytes());
+    data.extend_from_slice(&[0b10, b'a', 1, 0, 3]);
+    assert!(compression::is_compressed(&data));
+    assert_eq!(compression::decompressed_size(&data), Ok(8));
+
+    let mut out = [0u8; 8];
+    assert_eq!(compression::decompress(&data, &mut out), Ok(()));
+    assert_eq!(&out, b"aaaaaaaa");
+
+    assert_eq!(
+        compression::decompress(&data[..data.len() - 1], &mut out),
+        Err(DecompressError::Truncated)
+    );
+    data[compression::HEADER_SIZE + 2] = 2;
+    assert_eq!(
+        compression::decompress(&data, &mut out),
+        Err(DecompressError::InvalidDistance)
+    );
+    assert!(!compression::is_compressed(b"\x7fELF"));
+}
+
+#[inline(never)]
+fn profiled_loop(duration: Duration) {
+    let start = time::uptime();
+    while time::uptime() - start < duration {
+        core::hint::spin_loop();
+    }
+}
+
+fn test_profiler() {
+    profiler::reset();
+    profiler::start();
+    profiled_loop(Duration::from_millis(200));
+    profiler::stop();
+
+    assert!(profiler::sample_count() > 0);
+    let folded = profiler::folded();
+    assert_eq!(
+        folded.iter().map(|(_, count)| count).sum::<usize>(),
+        profiler::sample_count()
+    );
+    if backtrace::symbols().is_some() {
+        assert!(folded
+            .iter()
+            .any(|(stack, _)| stack.contains("profiled_loop")));
+    }
+    profiler::reset();
+    assert_eq!(profiler::sample_count(), 0);
+}
+
+fn trace_test_event(value: u64) {
+    kernel::trace_event!(test, "value {:#x}, count {}", value, 7usize);
+}
+
+fn test_tracepoints() {
+    let tracepoints = tracepoint::tracepoints();
+    assert!(tracepoints.iter().any(|t| t.subsystem == "sched"));
+    assert!(tracepoints.iter().all(|t| !t.is_enabled()));
+
+    tracepoint::clear();
+    trace_test_event(1);
+    assert!(tracepoint::events().is_empty());
+
+    assert_eq!(tracepoint::set_subsystem_enabled("test", true), 1);
+    trace_test_event(0xabc);
+    trace_test_event(0xdef);
+    tracepoint::set_subsystem_enabled("test", false);
+    trace_test_event(2);
+
+    let events = tracepoint::events();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].args(), [0xabc, 7]);
+    assert!(events[0].timestamp <= events[1].timestamp);
+    assert!(format!("{}", events[1]).ends_with("test: value 0xdef, count 7"));
+
+    tracepoint::clear();
+    assert!(tracepoint::events().is_empty());
+}
+
+fn test_cmdline() {
+    let (options, errors) = cmdline::parse(
+        "log=debug  nosmp root=/dev/sda log=loud doublefault=reboot watchdog=panic idle=halt",
+    );
+    assert_eq!(options.log_level, Some(Level::Debug));
+    assert!(options.nosmp);
+    assert!(!options.test);
+    assert!(options.double_fault_reboot);
+    assert!(options.watchdog_panic);
+    assert!(options.idle_halt);
+    assert_eq!(
+        errors,
+        [
+            OptionError::Unknown("root"),
+            OptionError::InvalidValue {
+                key: "log",
+                value: "loud"
+            }
+        ]
+    );
+
+    assert_eq!(cmdline::parse("").0, cmdline::Options::default());
+    assert_eq!(
+        cmdline::parse("doublefault=retry").1,
+        [OptionError::InvalidValue {
+            key: "doublefault",
+            value: "retry"
+        }]
+    );
+    if cmdline::options().test {
+        assert_eq!(cmdline::get("test"), Some(""));
+    }
+}
+
+fn test_descriptor_tables() {
+    let gdt = unsafe { gdt::current_entries() };
+    let segments: Vec<_> = gdt::decode(gdt).collect();
+    assert_eq!(segments[0].1.kind, SegmentKind::Null);
+    // loading the task register marked the TSS busy
+    let (_, tss) = segments[1];
+    assert_eq!(tss.kind, SegmentKind::System(SystemSegmentType::TssBusy));
+    assert_eq!(tss.limit as usize, size_of::<TaskStateSegment>() - 1);
+    let code = SegmentSelector::from(CS::read());
+    assert!(matches!(
+        segments.iter().find(|(index, _)| *index == code.index() as usize),
+        Some((_, segment)) if segment.kind == SegmentKind::Code { long: true, conforming: false }
+    ));
+
+    let idt = unsafe { idt::current_entries() };
+    assert_eq!(idt.len(), 256);
+    for entry in idt.iter().filter(|entry| entry.is_present()) {
+        assert_eq!(entry.validate(gdt), Ok(()));
+    }
+    let double_fault = &idt[idt::vectors::DOUBLE_FAULT as usize];
+    assert_eq!(
+        double_fault.interrupt_stack_index(),
+        Some(DOUBLE_FAULT_IST_IDX as u16)
+    );
+    assert_eq!(
+        idt[idt::vectors::PAGE_FAULT as usize].interrupt_stack_index(),
+        None
+    );
+
+    let long_and_protected = SegmentDescriptor::new_user(
+        SegmentDescriptorFlags::PRESENT
+            | SegmentDescriptorFlags::USER_SEGMENT
+            | SegmentDescriptorFlags::EXECUTABLE
+            | SegmentDescriptorFlags::LONG_MODE
+            | SegmentDescriptorFlags::PROTECTED_MODE,
+        0,
+        0,
+    );
+    assert_eq!(
+        long_and_protected.validate(),
+        Err(DescriptorError::LongAndProtectedMode)
+    );
+    let missing = SegmentDescriptor::new_user(SegmentDescriptorFlags::USER_SEGMENT, 0, 0);
+    assert_eq!(missing.validate(), Err(DescriptorError::NotPresent));
+    assert_eq!(SegmentDescriptor::kernel_code_segment().validate(), Ok(()));
+    assert_eq!(
+        InterruptDescriptor::missing().validate(gdt),
+        Err(DescriptorError::NotPresent)
+    );
+
+    // selector 0x18 of the GDT, and vector 13 raised externally
+    let error = SelectorErrorCode::new(0x18);
+    assert_eq!(error.table(), DescriptorTable::Gdt);
+    assert_eq!(error.index(), 3);
+    assert!(!error.is_external());
+    let error = SelectorErrorCode::new(13 << 3 | 0b011);
+    assert_eq!(error.table(), DescriptorTable::Idt);
+    assert_eq!(error.index(), 13);
+    assert!(error.is_external());
+}
+
+fn test_random() {
+    // RFC 8439 section 2.3.2
+    let key: [u8; 32] = core::array::from_fn(|i| i as u8);
+    let nonce = [0, 0, 0, 0x09, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+    let block = ChaCha20::new(&key, &nonce).block(1);
+    assert_eq!(
+        block[..16],
+        [
+            0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20,
+            0x71, 0xc4
+        ]
+    );
+    assert_eq!(
+        block[48..],
+        [
+            0xb5, 0x12, 0x9c, 0xd1, 0xde, 0x16, 0x4e, 0xb9, 0xcb, 0xd0, 0x83, 0xe8, 0xa2, 0x50,
+            0x3c, 0x4e
+        ]
+    );
+
+    let mut first = [0u8; 100];
+    let mut second = [0u8; 100];
+    random::random_bytes(&mut first);
+    random::random_bytes(&mut second);
+    assert_ne!(first, second);
+    assert!(first.iter().any(|b| *b != 0));
+    assert_ne!(random::random_u64(), random::random_u64());
+}
+
+fn relocated_function() -> u64 {
+    42
+}
+
+// the kernel is a PIE, pointers in statics are only valid once stage4 applied
+// the relocations
+static RELOCATED_FUNCTIONS: [fn() -> u64; 1] = [relocated_function];
+static RELOCATED_STRINGS: [&str; 2] = ["relocated", "strings"];
+
+fn test_relocations() {
+    assert_eq!(black_box(&RELOCATED_FUNCTIONS)[0](), 42);
+    let strings = black_box(&RELOCATED_STRINGS);
+    assert_eq!(strings[0].as_bytes(), b"relocated");
+    assert_eq!(strings[1].as_bytes(), b"strings");
+}
+
+fn test_time() {
+    // QEMU always provides a FADT
+    assert!(acpi::find_table(b"FACP").is_some());
+    assert!(acpi::find_table(b"NONE").is_none());
+
+    let source = time::clock_source();
+    assert!(source.frequency() > 0);
+    assert!(tsc::calibrate(source).unwrap() > 0);
+
+    let start = time::uptime();
+    let ticks = time::ticks();
+    while time::ticks() < ticks + 2 {
+        hlt();
+    }
+    // at least one complete timer period passed
+    let period = Duration::from_secs(1) / interrupts::TIMER_FREQUENCY;
+    assert!(time::uptime() - start >= period);
+
+    let date = DateTime::from_unix_timestamp(951_868_800);
+    assert_eq!((date.year, date.month, date.day), (2000, 3, 1));
+    let date = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 23,
+        minute: 59,
+        second: 59,
+    };
+    assert_eq!(date.to_unix_timestamp(), 1_709_251_199);
+    assert_eq!(DateTime::from_unix_timestamp(1_709_251_199), date);
+    assert!(time::wall_clock().year >= 2024);
+}
+
+kernel_test!(test_box, test_vec);
+kernel_test!(should_panic: test_assert_fails, test_slab_cache_leak);
+kernel_test!(should_fault(PageFault): test_unmapped_access, test_null_deref);
+kernel_test!(should_fault(StackOverflow): test_guard_page_hit);
+kernel_test!(should_fault(DoubleFault): test_double_fault);
+kernel_test!(should_fault(GeneralProtection): test_non_canonical_access);
+kernel_test!(should_fault(SimdFloatingPoint): test_simd_divide_by_zero);
+kernel_test!(should_fault(Overflow): test_overflow);
+kernel_test!(
+    test_backtrace,
+    test_logging,
+    test_cpuid,
+    test_descriptor_tables,
+    test_pstore,
+    test_time,
+    test_random,
+    test_cmdline,
+    test_profiler,
+    test_tracepoints,
+    test_edid,
+    test_manifest,
+    test_ed25519,
+    test_compression,
+    test_relocations
+);
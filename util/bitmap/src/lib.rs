@@ -0,0 +1,281 @@
+//! Bitmap for allocators.
+//!
+//! A set bit marks an object in use, a clear bit a free one. Queries scan a
+//! whole word at a time, so finding a run of free bits skips 64 used or free
+//! objects per step.
+//!
+//! The bits are stored in anything that derefs to a slice of words, e.g. an
+//! array in a `static` or a `Vec`.
+#![no_std]
+extern crate alloc;
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+
+const BITS: usize = u64::BITS as usize;
+
+/// Amount of words needed for `len` bits
+pub const fn words_for(len: usize) -> usize {
+    len.div_ceil(BITS)
+}
+
+pub struct Bitmap<S> {
+    words: S,
+    len: usize,
+}
+
+impl Bitmap<Vec<u64>> {
+    /// Creates a bitmap of `len` clear bits on the heap
+    pub fn with_len(len: usize) -> Self {
+        Self {
+            words: vec![0; words_for(len)],
+            len,
+        }
+    }
+}
+
+impl<S: AsRef<[u64]>> Bitmap<S> {
+    /// Uses the first `len` bits of `words`
+    pub fn new(words: S, len: usize) -> Self {
+        assert!(words.as_ref().len() >= words_for(len));
+        Self { words, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        assert!(index < self.len);
+        self.words.as_ref()[index / BITS] & (1 << (index % BITS)) != 0
+    }
+
+    pub fn count_ones(&self) -> usize {
+        (0..self.len)
+            .step_by(BITS)
+            .map(|start| (self.word(start) & mask(0, (self.len - start).min(BITS))).count_ones())
+            .sum::<u32>() as usize
+    }
+
+    /// First set bit in `range`
+    pub fn next_set(&self, range: Range<usize>) -> Option<usize> {
+        self.next(range, false)
+    }
+
+    /// First clear bit in `range`
+    pub fn next_clear(&self, range: Range<usize>) -> Option<usize> {
+        self.next(range, true)
+    }
+
+    /// Start of the lowest run of `len` clear bits
+    pub fn find_free_run(&self, len: usize) -> Option<usize> {
+        self.find_free_run_aligned(len, 1, self.len)
+    }
+
+    /// Start of the lowest run of `len` clear bits that starts at a multiple
+    /// of `alignment` and ends at or below `limit`
+    pub fn find_free_run_aligned(
+        &self,
+        len: usize,
+        alignment: usize,
+        limit: usize,
+    ) -> Option<usize> {
+        assert!(len > 0 && alignment.is_power_of_two());
+        let limit = limit.min(self.len);
+        let mut from = 0;
+        loop {
+            let start = self.next_clear(from..limit)?.next_multiple_of(alignment);
+            let end = start.checked_add(len).filter(|&end| end <= limit)?;
+            match self.next_set(start..end) {
+                None => return Some(start),
+                Some(used) => from = used + 1,
+            }
+        }
+    }
+
+    fn word(&self, index: usize) -> u64 {
+        self.words.as_ref()[index / BITS]
+    }
+
+    /// First bit in `range` that is clear if `clear` and set otherwise
+    fn next(&self, range: Range<usize>, clear: bool) -> Option<usize> {
+        let end = range.end.min(self.len);
+        let mut index = range.start;
+        while index < end {
+            let offset = index % BITS;
+            let mut word = self.word(index);
+            if clear {
+                word = !word;
+            }
+            let word = word & mask(offset, (end - index + offset).min(BITS));
+            if word != 0 {
+                return Some(index - offset + word.trailing_zeros() as usize);
+            }
+            index += BITS - offset;
+        }
+        None
+    }
+}
+
+impl<S: AsRef<[u64]> + AsMut<[u64]>> Bitmap<S> {
+    pub fn set(&mut self, index: usize) {
+        assert!(index < self.len);
+        self.words.as_mut()[index / BITS] |= 1 << (index % BITS);
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        assert!(index < self.len);
+        self.words.as_mut()[index / BITS] &= !(1 << (index % BITS));
+    }
+
+    pub fn set_range(&mut self, range: Range<usize>) {
+        self.update_range(range, true);
+    }
+
+    pub fn clear_range(&mut self, range: Range<usize>) {
+        self.update_range(range, false);
+    }
+
+    /// Sets or clears all bits
+    pub fn fill(&mut self, value: bool) {
+        self.update_range(0..self.len, value);
+    }
+
+    fn update_range(&mut self, range: Range<usize>, value: bool) {
+        assert!(range.end <= self.len);
+        let mut index = range.start;
+        while index < range.end {
+            let offset = index % BITS;
+            let bits = mask(offset, (range.end - index + offset).min(BITS));
+            let word = &mut self.words.as_mut()[index / BITS];
+            if value {
+                *word |= bits;
+            } else {
+                *word &= !bits;
+            }
+            index += BITS - offset;
+        }
+    }
+}
+
+/// Bits `from..to` of a word
+fn mask(from: usize, to: usize) -> u64 {
+    let upper = if to == BITS { u64::MAX } else { (1 << to) - 1 };
+    upper & !((1 << from) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+    }
+
+    /// Lowest run of `len` false entries starting at a multiple of `alignment`
+    fn naive_run(model: &[bool], len: usize, alignment: usize, limit: usize) -> Option<usize> {
+        (0..limit)
+            .step_by(alignment)
+            .find(|&start| start + len <= limit && model[start..start + len].iter().all(|b| !b))
+    }
+
+    #[test]
+    fn test_set_clear() {
+        let mut bitmap = Bitmap::new([0u64; 3], 150);
+        bitmap.set(0);
+        bitmap.set(63);
+        bitmap.set(64);
+        bitmap.set(149);
+        assert!(bitmap.get(63) && bitmap.get(64) && !bitmap.get(65));
+        assert_eq!(bitmap.count_ones(), 4);
+
+        bitmap.clear(64);
+        assert!(!bitmap.get(64));
+        bitmap.set_range(10..140);
+        assert_eq!(bitmap.count_ones(), 132);
+        bitmap.clear_range(60..70);
+        assert_eq!(bitmap.next_clear(0..150), Some(1));
+        assert_eq!(bitmap.next_clear(10..150), Some(60));
+        assert_eq!(bitmap.next_set(60..150), Some(70));
+        assert_eq!(bitmap.next_set(141..149), None);
+
+        bitmap.fill(true);
+        assert_eq!(bitmap.count_ones(), 150);
+        // bits beyond the length are never touched
+        assert_eq!(bitmap.words[2] >> 22, 0);
+        assert!(bitmap.find_free_run(1).is_none());
+    }
+
+    #[test]
+    fn test_find_free_run() {
+        let mut bitmap = Bitmap::with_len(256);
+        bitmap.set_range(0..100);
+        bitmap.set(130);
+        assert_eq!(bitmap.find_free_run(30), Some(100));
+        assert_eq!(bitmap.find_free_run(31), Some(131));
+        assert_eq!(bitmap.find_free_run_aligned(8, 64, 256), Some(192));
+        assert_eq!(bitmap.find_free_run_aligned(8, 64, 199), None);
+        assert_eq!(bitmap.find_free_run(126), None);
+        assert_eq!(bitmap.find_free_run(125), Some(131));
+    }
+
+    #[test]
+    fn test_random_against_model() {
+        let mut random = Random(0x1234_5678_9abc_def1);
+        for round in 0..200 {
+            let len = 1 + random.next(700);
+            let mut bitmap = Bitmap::with_len(len);
+            let mut model = vec![false; len];
+
+            for _ in 0..50 {
+                let start = random.next(len);
+                let end = start + random.next(len - start + 1);
+                let value = random.next(3) != 0;
+                if value {
+                    bitmap.set_range(start..end);
+                } else {
+                    bitmap.clear_range(start..end);
+                }
+                model[start..end].fill(value);
+
+                let index = random.next(len);
+                model[index] = random.next(2) == 0;
+                if model[index] {
+                    bitmap.set(index);
+                } else {
+                    bitmap.clear(index);
+                }
+            }
+
+            assert_eq!(bitmap.count_ones(), model.iter().filter(|b| **b).count());
+            for (index, value) in model.iter().enumerate() {
+                assert_eq!(bitmap.get(index), *value);
+            }
+
+            let from = random.next(len);
+            let to = from + random.next(len - from + 1);
+            assert_eq!(bitmap.next_set(from..to), (from..to).find(|&i| model[i]));
+            assert_eq!(bitmap.next_clear(from..to), (from..to).find(|&i| !model[i]));
+
+            let run = 1 + random.next(40);
+            let alignment = 1 << random.next(5);
+            let limit = random.next(len + 1);
+            assert_eq!(
+                bitmap.find_free_run_aligned(run, alignment, limit),
+                naive_run(&model, run, alignment, limit),
+                "round {}",
+                round
+            );
+        }
+    }
+}
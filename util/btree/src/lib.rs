@@ -0,0 +1,709 @@
+//! Ordered map implemented as a B-tree.
+//!
+//! Every node except the root holds between `B - 1` and `2 * B - 1` keys,
+//! inner nodes have one child more than keys. Insertion splits full nodes and
+//! removal refills nodes with only `B - 1` keys on the way down, so neither
+//! ever has to walk back up.
+//!
+//! Besides exact lookups the map supports finding the nearest keys and
+//! iterating over ranges in `O(log n)` plus the amount of visited entries.
+#![no_std]
+extern crate alloc;
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    borrow::Borrow,
+    fmt, mem,
+    ops::{Bound, RangeBounds},
+    ptr,
+};
+
+/// Minimum degree
+const B: usize = 6;
+const MAX_KEYS: usize = 2 * B - 1;
+
+struct Node<K, V> {
+    keys: Vec<K>,
+    values: Vec<V>,
+    // empty for leaves
+    children: Vec<Node<K, V>>,
+}
+
+impl<K: Ord, V> Node<K, V> {
+    fn new() -> Self {
+        Self {
+            keys: Vec::with_capacity(MAX_KEYS),
+            values: Vec::with_capacity(MAX_KEYS),
+            children: Vec::new(),
+        }
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    fn search<Q>(&self, key: &Q) -> Result<usize, usize>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.keys.binary_search_by(|k| k.borrow().cmp(key))
+    }
+
+    /// Moves the upper half of the full child `idx` into a new sibling
+    fn split_child(&mut self, idx: usize) {
+        let child = &mut self.children[idx];
+        let mut sibling = Node::new();
+        sibling.keys = child.keys.split_off(B);
+        sibling.values = child.values.split_off(B);
+        if !child.is_leaf() {
+            sibling.children = child.children.split_off(B);
+        }
+        let key = child.keys.pop().unwrap();
+        let value = child.values.pop().unwrap();
+
+        self.keys.insert(idx, key);
+        self.values.insert(idx, value);
+        self.children.insert(idx + 1, sibling);
+    }
+
+    /// Inserts into a node that is not full
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let mut node = self;
+        loop {
+            let mut idx = match node.search(&key) {
+                Ok(idx) => return Some(mem::replace(&mut node.values[idx], value)),
+                Err(idx) => idx,
+            };
+
+            if node.is_leaf() {
+                node.keys.insert(idx, key);
+                node.values.insert(idx, value);
+                return None;
+            }
+
+            if node.children[idx].keys.len() == MAX_KEYS {
+                node.split_child(idx);
+                match key.cmp(&node.keys[idx]) {
+                    core::cmp::Ordering::Equal => {
+                        return Some(mem::replace(&mut node.values[idx], value))
+                    }
+                    core::cmp::Ordering::Greater => idx += 1,
+                    core::cmp::Ordering::Less => (),
+                }
+            }
+            node = &mut node.children[idx];
+        }
+    }
+
+    /// Removes `key` from the subtree. The node has at least `B` keys unless
+    /// it is the root.
+    fn remove<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        match self.search(key) {
+            Ok(idx) if self.is_leaf() => Some((self.keys.remove(idx), self.values.remove(idx))),
+            Ok(idx) => {
+                // replace the key with its predecessor or successor
+                if self.children[idx].keys.len() >= B {
+                    let (k, v) = self.children[idx].remove_last();
+                    Some(self.replace(idx, k, v))
+                } else if self.children[idx + 1].keys.len() >= B {
+                    let (k, v) = self.children[idx + 1].remove_first();
+                    Some(self.replace(idx, k, v))
+                } else {
+                    self.merge(idx);
+                    self.children[idx].remove(key)
+                }
+            }
+            Err(_) if self.is_leaf() => None,
+            Err(idx) => {
+                let idx = self.refill(idx);
+                self.children[idx].remove(key)
+            }
+        }
+    }
+
+    fn remove_first(&mut self) -> (K, V) {
+        if self.is_leaf() {
+            return (self.keys.remove(0), self.values.remove(0));
+        }
+        let idx = self.refill(0);
+        self.children[idx].remove_first()
+    }
+
+    fn remove_last(&mut self) -> (K, V) {
+        if self.is_leaf() {
+            return (self.keys.pop().unwrap(), self.values.pop().unwrap());
+        }
+        let idx = self.refill(self.children.len() - 1);
+        self.children[idx].remove_last()
+    }
+
+    fn replace(&mut self, idx: usize, key: K, value: V) -> (K, V) {
+        (
+            mem::replace(&mut self.keys[idx], key),
+            mem::replace(&mut self.values[idx], value),
+        )
+    }
+
+    /// Makes sure child `idx` has at least `B` keys by borrowing one from a
+    /// sibling or merging with it. Returns the new index of the child.
+    fn refill(&mut self, idx: usize) -> usize {
+        if self.children[idx].keys.len() >= B {
+            return idx;
+        }
+
+        if idx > 0 && self.children[idx - 1].keys.len() >= B {
+            let (left, right) = self.children.split_at_mut(idx);
+            let (left, child) = (&mut left[idx - 1], &mut right[0]);
+            let key = mem::replace(&mut self.keys[idx - 1], left.keys.pop().unwrap());
+            let value = mem::replace(&mut self.values[idx - 1], left.values.pop().unwrap());
+            child.keys.insert(0, key);
+            child.values.insert(0, value);
+            if let Some(grandchild) = left.children.pop() {
+                child.children.insert(0, grandchild);
+            }
+            idx
+        } else if idx + 1 < self.children.len() && self.children[idx + 1].keys.len() >= B {
+            let (left, right) = self.children.split_at_mut(idx + 1);
+            let (child, right) = (&mut left[idx], &mut right[0]);
+            let key = mem::replace(&mut self.keys[idx], right.keys.remove(0));
+            let value = mem::replace(&mut self.values[idx], right.values.remove(0));
+            child.keys.push(key);
+            child.values.push(value);
+            if !right.is_leaf() {
+                child.children.push(right.children.remove(0));
+            }
+            idx
+        } else if idx + 1 < self.children.len() {
+            self.merge(idx);
+            idx
+        } else {
+            self.merge(idx - 1);
+            idx - 1
+        }
+    }
+
+    /// Merges child `idx + 1` and the key separating them into child `idx`
+    fn merge(&mut self, idx: usize) {
+        let right = self.children.remove(idx + 1);
+        let key = self.keys.remove(idx);
+        let value = self.values.remove(idx);
+
+        let child = &mut self.children[idx];
+        child.keys.push(key);
+        child.values.push(value);
+        child.keys.extend(right.keys);
+        child.values.extend(right.values);
+        child.children.extend(right.children);
+    }
+}
+
+pub struct BTreeMap<K, V> {
+    root: Option<Box<Node<K, V>>>,
+    len: usize,
+}
+
+impl<K: Ord, V> BTreeMap<K, V> {
+    pub const fn new() -> Self {
+        Self { root: None, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.root = None;
+        self.len = 0;
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut node = self.root.as_deref()?;
+        loop {
+            match node.search(key) {
+                Ok(idx) => return Some(&node.values[idx]),
+                Err(_) if node.is_leaf() => return None,
+                Err(idx) => node = &node.children[idx],
+            }
+        }
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut node = self.root.as_deref_mut()?;
+        loop {
+            match node.search(key) {
+                Ok(idx) => return Some(&mut node.values[idx]),
+                Err(_) if node.is_leaf() => return None,
+                Err(idx) => node = &mut node.children[idx],
+            }
+        }
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    /// Inserts `value` under `key` and returns the value it replaced
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let root = self.root.get_or_insert_with(|| Box::new(Node::new()));
+        if root.keys.len() == MAX_KEYS {
+            let old = mem::replace(root, Box::new(Node::new()));
+            root.children.push(*old);
+            root.split_child(0);
+        }
+
+        let old = root.insert(key, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.remove_entry(key).map(|(_, v)| v)
+    }
+
+    pub fn remove_entry<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let root = self.root.as_mut()?;
+        let entry = root.remove(key);
+        if root.keys.is_empty() {
+            // the tree shrinks at the root
+            self.root = root.children.pop().map(Box::new);
+        }
+        if entry.is_some() {
+            self.len -= 1;
+        }
+        entry
+    }
+
+    pub fn first(&self) -> Option<(&K, &V)> {
+        let mut node = self.root.as_deref()?;
+        while !node.is_leaf() {
+            node = &node.children[0];
+        }
+        Some((node.keys.first()?, node.values.first()?))
+    }
+
+    pub fn last(&self) -> Option<(&K, &V)> {
+        let mut node = self.root.as_deref()?;
+        while !node.is_leaf() {
+            node = node.children.last().unwrap();
+        }
+        Some((node.keys.last()?, node.values.last()?))
+    }
+
+    /// Entry with the greatest key less than or equal to `key`
+    pub fn floor<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut node = self.root.as_deref()?;
+        let mut best = None;
+        loop {
+            let idx = match node.search(key) {
+                Ok(idx) => return Some((&node.keys[idx], &node.values[idx])),
+                Err(idx) => idx,
+            };
+            // keys in the child are greater than the one left of it
+            if idx > 0 {
+                best = Some((&node.keys[idx - 1], &node.values[idx - 1]));
+            }
+            if node.is_leaf() {
+                return best;
+            }
+            node = &node.children[idx];
+        }
+    }
+
+    /// Entry with the smallest key greater than or equal to `key`
+    pub fn ceiling<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut node = self.root.as_deref()?;
+        let mut best = None;
+        loop {
+            let idx = match node.search(key) {
+                Ok(idx) => return Some((&node.keys[idx], &node.values[idx])),
+                Err(idx) => idx,
+            };
+            if idx < node.keys.len() {
+                best = Some((&node.keys[idx], &node.values[idx]));
+            }
+            if node.is_leaf() {
+                return best;
+            }
+            node = &node.children[idx];
+        }
+    }
+
+    /// Iterates over all entries in ascending key order
+    pub fn iter(&self) -> Range<'_, K, V> {
+        self.range::<K, _>(..)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+
+    /// Iterates over the entries with keys in `range` in ascending order
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let mut iter = Range {
+            stack: Vec::new(),
+            last: None,
+        };
+        let Some(root) = self.root.as_deref() else {
+            return iter;
+        };
+
+        seek(&mut iter.stack, root, range.start_bound());
+        // the iteration ends after the last key within the range
+        let last = last_below(root, range.end_bound());
+        let first = Range {
+            stack: iter.stack.clone(),
+            last: None,
+        }
+        .next_entry();
+        if let (Some((first, _)), Some(last)) = (first, last) {
+            if first <= last {
+                iter.last = Some(last);
+            }
+        }
+        iter
+    }
+}
+
+/// Descends to the first entry not below `bound`. Every stack entry is a
+/// node and the index of its next key, the children left of it are done.
+fn seek<'a, K, V, Q>(
+    stack: &mut Vec<(&'a Node<K, V>, usize)>,
+    root: &'a Node<K, V>,
+    bound: Bound<&Q>,
+) where
+    K: Ord + Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    let mut node = root;
+    loop {
+        let idx = match bound {
+            Bound::Unbounded => 0,
+            Bound::Included(key) => match node.search(key) {
+                Ok(idx) => {
+                    stack.push((node, idx));
+                    return;
+                }
+                Err(idx) => idx,
+            },
+            Bound::Excluded(key) => match node.search(key) {
+                Ok(idx) => {
+                    stack.push((node, idx + 1));
+                    if let Some(child) = node.children.get(idx + 1) {
+                        push_leftmost(stack, child);
+                    }
+                    return;
+                }
+                Err(idx) => idx,
+            },
+        };
+        stack.push((node, idx));
+        if node.is_leaf() {
+            return;
+        }
+        node = &node.children[idx];
+    }
+}
+
+/// Greatest key not above `bound`
+fn last_below<'a, K, V, Q>(root: &'a Node<K, V>, bound: Bound<&Q>) -> Option<&'a K>
+where
+    K: Ord + Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    let mut node = root;
+    let mut best = None;
+    loop {
+        let idx = match bound {
+            Bound::Unbounded => node.keys.len(),
+            Bound::Included(key) => match node.search(key) {
+                Ok(idx) => return Some(&node.keys[idx]),
+                Err(idx) => idx,
+            },
+            Bound::Excluded(key) => match node.search(key) {
+                // the predecessor is the last key of the left child
+                Ok(idx) if node.is_leaf() => return node.keys[..idx].last().or(best),
+                Ok(idx) => {
+                    let mut child = &node.children[idx];
+                    while let Some(next) = child.children.last() {
+                        child = next;
+                    }
+                    return child.keys.last();
+                }
+                Err(idx) => idx,
+            },
+        };
+        if idx > 0 {
+            best = Some(&node.keys[idx - 1]);
+        }
+        if node.is_leaf() {
+            return best;
+        }
+        node = &node.children[idx];
+    }
+}
+
+fn push_leftmost<'a, K, V>(stack: &mut Vec<(&'a Node<K, V>, usize)>, mut node: &'a Node<K, V>) {
+    loop {
+        stack.push((node, 0));
+        match node.children.first() {
+            Some(child) => node = child,
+            None => return,
+        }
+    }
+}
+
+/// Iterator over a range of entries in ascending key order
+pub struct Range<'a, K, V> {
+    stack: Vec<(&'a Node<K, V>, usize)>,
+    // None once the last key in the range was returned
+    last: Option<&'a K>,
+}
+
+impl<'a, K, V> Range<'a, K, V> {
+    fn next_entry(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            let (node, idx) = self.stack.last_mut()?;
+            let node: &'a Node<K, V> = node;
+            if *idx < node.keys.len() {
+                let i = *idx;
+                *idx += 1;
+                if let Some(child) = node.children.get(i + 1) {
+                    push_leftmost(&mut self.stack, child);
+                }
+                return Some((&node.keys[i], &node.values[i]));
+            }
+            self.stack.pop();
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Range<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let last = self.last?;
+        let entry = self.next_entry()?;
+        if ptr::eq(entry.0, last) {
+            self.last = None;
+        }
+        Some(entry)
+    }
+}
+
+impl<K: Ord, V> Default for BTreeMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + fmt::Debug, V: fmt::Debug> fmt::Debug for BTreeMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap as Reference;
+
+    /// Checks the key order, node sizes and that all leaves are at the same
+    /// depth. Returns the depth and amount of keys.
+    fn check<K: Ord, V>(node: &Node<K, V>, is_root: bool) -> (usize, usize) {
+        assert!(node.keys.len() <= MAX_KEYS);
+        assert!(is_root || node.keys.len() >= B - 1);
+        assert!(node.keys.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(node.keys.len(), node.values.len());
+        if node.is_leaf() {
+            return (0, node.keys.len());
+        }
+
+        assert_eq!(node.children.len(), node.keys.len() + 1);
+        let mut depth = None;
+        let mut count = node.keys.len();
+        for (i, child) in node.children.iter().enumerate() {
+            if i > 0 {
+                assert!(child.keys.first().unwrap() > &node.keys[i - 1]);
+            }
+            if i < node.keys.len() {
+                assert!(child.keys.last().unwrap() < &node.keys[i]);
+            }
+            let (d, c) = check(child, false);
+            assert!(depth.is_none() || depth == Some(d));
+            depth = Some(d);
+            count += c;
+        }
+        (depth.unwrap() + 1, count)
+    }
+
+    fn check_map(map: &BTreeMap<u64, u64>) {
+        match map.root.as_deref() {
+            Some(root) => assert_eq!(check(root, true).1, map.len()),
+            None => assert_eq!(map.len(), 0),
+        }
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut map = BTreeMap::new();
+        assert!(map.get(&1).is_none());
+        assert!(map.remove(&1).is_none());
+
+        for i in 0..1000u64 {
+            assert!(map.insert(i * 7 % 1000, i).is_none());
+        }
+        check_map(&map);
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.insert(7, 0), Some(1));
+        *map.get_mut(&7).unwrap() = 42;
+        assert_eq!(map.get(&7), Some(&42));
+        assert!(map.keys().copied().eq(0..1000));
+
+        for i in (0..1000).step_by(2) {
+            assert!(map.remove(&i).is_some());
+        }
+        check_map(&map);
+        assert_eq!(map.len(), 500);
+        assert!(map.keys().copied().eq((1..1000).step_by(2)));
+        assert_eq!(map.first(), Some((&1, &143)));
+        assert_eq!(map.last().map(|(k, _)| *k), Some(999));
+
+        for i in (1..1000).step_by(2) {
+            assert!(map.remove(&i).is_some());
+        }
+        assert!(map.is_empty());
+        assert!(map.root.is_none());
+    }
+
+    #[test]
+    fn test_floor_ceiling() {
+        let mut map = BTreeMap::new();
+        assert!(map.floor(&5).is_none());
+        for i in 1..200u64 {
+            map.insert(i * 10, i);
+        }
+
+        assert!(map.floor(&9).is_none());
+        assert_eq!(map.floor(&10), Some((&10, &1)));
+        assert_eq!(map.floor(&1005), Some((&1000, &100)));
+        assert_eq!(map.floor(&100_000), Some((&1990, &199)));
+        assert_eq!(map.ceiling(&0), Some((&10, &1)));
+        assert_eq!(map.ceiling(&1001), Some((&1010, &101)));
+        assert!(map.ceiling(&1991).is_none());
+    }
+
+    #[test]
+    fn test_range() {
+        let mut map = BTreeMap::new();
+        for i in 0..500u64 {
+            map.insert(i * 2, ());
+        }
+
+        let keys =
+            |range: (Bound<u64>, Bound<u64>)| map.range(range).map(|(k, _)| *k).collect::<Vec<_>>();
+        let expected = |from: u64, to: u64| (from..=to).step_by(2).collect::<Vec<_>>();
+
+        assert_eq!(
+            keys((Bound::Included(10), Bound::Excluded(20))),
+            expected(10, 18)
+        );
+        assert_eq!(
+            keys((Bound::Excluded(10), Bound::Included(20))),
+            expected(12, 20)
+        );
+        assert_eq!(
+            keys((Bound::Included(11), Bound::Included(19))),
+            expected(12, 18)
+        );
+        assert_eq!(keys((Bound::Unbounded, Bound::Excluded(7))), expected(0, 6));
+        assert_eq!(
+            keys((Bound::Excluded(990), Bound::Unbounded)),
+            expected(992, 998)
+        );
+        assert!(keys((Bound::Included(11), Bound::Excluded(12))).is_empty());
+        assert!(keys((Bound::Excluded(10), Bound::Excluded(12))).is_empty());
+        assert!(keys((Bound::Included(2000), Bound::Unbounded)).is_empty());
+        assert_eq!(map.range(100..).next(), Some((&100, &())));
+        assert_eq!(map.range(..).count(), 500);
+    }
+
+    #[test]
+    fn test_random_against_reference() {
+        let mut map = BTreeMap::new();
+        let mut reference = Reference::new();
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+
+        for round in 0..20_000 {
+            let key = random() % 512;
+            if random() % 3 == 0 {
+                assert_eq!(map.remove(&key), reference.remove(&key));
+            } else {
+                assert_eq!(map.insert(key, round), reference.insert(key, round));
+            }
+            assert_eq!(map.len(), reference.len());
+
+            if round % 500 == 0 {
+                check_map(&map);
+                assert!(map.iter().eq(reference.iter()));
+                let (from, to) = (random() % 512, random() % 512);
+                let (from, to) = (from.min(to), from.max(to));
+                assert!(map.range(from..to).eq(reference.range(from..to)));
+                assert_eq!(map.floor(&from), reference.range(..=from).next_back());
+                assert_eq!(map.ceiling(&to), reference.range(to..).next());
+            }
+        }
+    }
+}
@@ -0,0 +1,424 @@
+//! Implementation of a hash map using separate chaining.
+//!
+//! Every bucket is a small vector of key value pairs. Colliding keys are simply
+//! appended to the bucket. Once the load factor exceeds 3/4 the amount of
+//! buckets is doubled and all entries are rehashed.
+//!
+//! Keys are hashed using FNV-1a which is fast for the small keys (ids,
+//! addresses) the kernel uses. It is not resistant against hash flooding, which
+//! does not matter as long as keys are not chosen by an attacker.
+#![no_std]
+extern crate alloc;
+use alloc::vec::Vec;
+use core::{
+    hash::{BuildHasher, Hash, Hasher},
+    mem,
+};
+
+const INITIAL_BUCKETS: usize = 8;
+
+/// 64 bit FNV-1a hasher
+pub struct FnvHasher(u64);
+
+impl FnvHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+}
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BuildFnvHasher;
+
+impl BuildHasher for BuildFnvHasher {
+    type Hasher = FnvHasher;
+
+    fn build_hasher(&self) -> FnvHasher {
+        FnvHasher::default()
+    }
+}
+
+pub struct HashMap<K, V, S = BuildFnvHasher> {
+    buckets: Vec<Vec<(K, V)>>,
+    len: usize,
+    hasher: S,
+}
+
+impl<K, V> HashMap<K, V, BuildFnvHasher> {
+    /// Creates an empty map. No memory is allocated until the first insert,
+    /// which makes it possible to use the map in statics.
+    pub const fn new() -> Self {
+        Self {
+            buckets: Vec::new(),
+            len: 0,
+            hasher: BuildFnvHasher,
+        }
+    }
+}
+
+impl<K, V, S> HashMap<K, V, S> {
+    pub const fn with_hasher(hasher: S) -> Self {
+        Self {
+            buckets: Vec::new(),
+            len: 0,
+            hasher,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets.iter_mut().for_each(|b| b.clear());
+        self.len = 0;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.buckets.iter().flatten().map(|(k, v)| (k, v))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.buckets.iter_mut().flatten().map(|(k, v)| (&*k, v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.iter_mut().map(|(_, v)| v)
+    }
+
+    /// Removes all entries and returns them. Entries that are not consumed
+    /// are dropped together with the iterator.
+    pub fn drain(&mut self) -> Drain<'_, K, V> {
+        self.len = 0;
+        Drain {
+            buckets: &mut self.buckets,
+            index: 0,
+        }
+    }
+
+    /// Keeps only the entries for which `f` returns true
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, mut f: F) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.retain_mut(|(k, v)| f(k, v));
+        }
+        self.len = self.buckets.iter().map(|b| b.len()).sum();
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> HashMap<K, V, S> {
+    fn bucket_index(&self, key: &K) -> usize {
+        (self.hasher.hash_one(key) % self.buckets.len() as u64) as usize
+    }
+
+    fn grow_if_needed(&mut self) {
+        if self.buckets.is_empty() {
+            self.buckets.resize_with(INITIAL_BUCKETS, Vec::new);
+            return;
+        }
+
+        if self.len * 4 < self.buckets.len() * 3 {
+            return;
+        }
+
+        let mut buckets = Vec::new();
+        buckets.resize_with(self.buckets.len() * 2, Vec::new);
+        let old = mem::replace(&mut self.buckets, buckets);
+
+        for (key, value) in old.into_iter().flatten() {
+            let idx = self.bucket_index(&key);
+            self.buckets[idx].push((key, value));
+        }
+    }
+
+    /// Inserts a key value pair. Returns the old value if the key was present.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(old) = self.get_mut(&key) {
+            return Some(mem::replace(old, value));
+        }
+
+        self.grow_if_needed();
+        let idx = self.bucket_index(&key);
+        self.buckets[idx].push((key, value));
+        self.len += 1;
+
+        None
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        if self.buckets.is_empty() {
+            return None;
+        }
+
+        self.buckets[self.bucket_index(key)]
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        if self.buckets.is_empty() {
+            return None;
+        }
+
+        let idx = self.bucket_index(key);
+        self.buckets[idx]
+            .iter_mut()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        if self.buckets.is_empty() {
+            return None;
+        }
+
+        let idx = self.bucket_index(key);
+        let bucket = &mut self.buckets[idx];
+        let pos = bucket.iter().position(|(k, _)| k == key)?;
+        self.len -= 1;
+
+        Some(bucket.swap_remove(pos).1)
+    }
+
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, S> {
+        if self.contains_key(&key) {
+            Entry::Occupied(OccupiedEntry { map: self, key })
+        } else {
+            Entry::Vacant(VacantEntry { map: self, key })
+        }
+    }
+}
+
+impl<K, V> Default for HashMap<K, V, BuildFnvHasher> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Extend<(K, V)> for HashMap<K, V, S> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Default> FromIterator<(K, V)> for HashMap<K, V, S> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::with_hasher(S::default());
+        map.extend(iter);
+        map
+    }
+}
+
+/// Iterator returned by [`HashMap::drain`]
+pub struct Drain<'a, K, V> {
+    buckets: &'a mut Vec<Vec<(K, V)>>,
+    index: usize,
+}
+
+impl<K, V> Iterator for Drain<'_, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        while let Some(bucket) = self.buckets.get_mut(self.index) {
+            if let Some(entry) = bucket.pop() {
+                return Some(entry);
+            }
+            self.index += 1;
+        }
+        None
+    }
+}
+
+impl<K, V> Drop for Drain<'_, K, V> {
+    fn drop(&mut self) {
+        for bucket in self.buckets[self.index..].iter_mut() {
+            bucket.clear();
+        }
+    }
+}
+
+pub enum Entry<'a, K, V, S> {
+    Occupied(OccupiedEntry<'a, K, V, S>),
+    Vacant(VacantEntry<'a, K, V, S>),
+}
+
+pub struct OccupiedEntry<'a, K, V, S> {
+    map: &'a mut HashMap<K, V, S>,
+    key: K,
+}
+
+pub struct VacantEntry<'a, K, V, S> {
+    map: &'a mut HashMap<K, V, S>,
+    key: K,
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> Entry<'a, K, V, S> {
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> OccupiedEntry<'a, K, V, S> {
+    pub fn into_mut(self) -> &'a mut V {
+        self.map.get_mut(&self.key).unwrap()
+    }
+
+    pub fn remove(self) -> V {
+        self.map.remove(&self.key).unwrap()
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> VacantEntry<'a, K, V, S> {
+    pub fn insert(self, value: V) -> &'a mut V {
+        self.map.grow_if_needed();
+        let idx = self.map.bucket_index(&self.key);
+        let bucket = &mut self.map.buckets[idx];
+        bucket.push((self.key, value));
+        self.map.len += 1;
+        &mut bucket.last_mut().unwrap().1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut map = HashMap::new();
+        assert!(map.get(&1).is_none());
+
+        for i in 0..100u64 {
+            assert!(map.insert(i, i * 2).is_none());
+        }
+        assert_eq!(map.len(), 100);
+
+        for i in 0..100u64 {
+            assert_eq!(map.get(&i), Some(&(i * 2)));
+        }
+
+        assert_eq!(map.insert(5, 42), Some(10));
+        assert_eq!(map.remove(&5), Some(42));
+        assert!(map.remove(&5).is_none());
+        assert_eq!(map.len(), 99);
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut map: HashMap<u64, u64> = HashMap::new();
+        *map.entry(1).or_insert(0) += 1;
+        *map.entry(1).or_insert(0) += 1;
+        *map.entry(2).or_default() += 5;
+
+        assert_eq!(map.get(&1), Some(&2));
+        assert_eq!(map.get(&2), Some(&5));
+        assert_eq!(map.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_or_insert_with() {
+        let mut map: HashMap<u64, u64> = HashMap::new();
+        let mut calls = 0;
+        for _ in 0..3 {
+            *map.entry(7).or_insert_with(|| {
+                calls += 1;
+                10
+            }) += 1;
+        }
+        assert_eq!(calls, 1);
+        assert_eq!(map.get(&7), Some(&13));
+    }
+
+    #[test]
+    fn test_keys_values() {
+        let mut map: HashMap<u64, u64> = (0..20).map(|i| (i, i * 3)).collect();
+        map.values_mut().for_each(|v| *v += 1);
+
+        let mut keys: Vec<_> = map.keys().copied().collect();
+        keys.sort_unstable();
+        assert!(keys.into_iter().eq(0..20));
+        assert_eq!(map.values().sum::<u64>(), (0..20).map(|i| i * 3 + 1).sum());
+
+        map.extend([(0, 0), (100, 100)]);
+        assert_eq!(map.len(), 21);
+        assert_eq!(map.get(&0), Some(&0));
+    }
+
+    #[test]
+    fn test_retain_drain() {
+        let mut map: HashMap<u64, u64> = (0..100).map(|i| (i, i)).collect();
+        map.retain(|k, v| {
+            *v *= 2;
+            k % 3 == 0
+        });
+        assert_eq!(map.len(), 34);
+        assert_eq!(map.get(&3), Some(&6));
+        assert!(map.get(&4).is_none());
+
+        let mut drained: Vec<_> = map.drain().collect();
+        drained.sort_unstable();
+        assert!(drained
+            .into_iter()
+            .eq((0..100).step_by(3).map(|i| (i, i * 2))));
+        assert!(map.is_empty() && map.iter().next().is_none());
+
+        // entries that were not iterated are dropped as well
+        map.extend((0..50).map(|i| (i, i)));
+        assert_eq!(map.drain().take(5).count(), 5);
+        assert!(map.is_empty() && map.iter().next().is_none());
+        map.insert(1, 1);
+        assert_eq!(map.len(), 1);
+    }
+}
@@ -0,0 +1,106 @@
+//! Attribute macros of the kernel.
+//!
+//! `#[interrupt_handler(vector = X)]` turns an `extern "C"` function taking
+//! the exception stack frame into the handler of interrupt vector `X`. It
+//! wraps the function with `handler_without_error_code!` (or
+//! `handler_with_error_code!` if `error_code` is given) of the x86_64 crate
+//! and registers the wrapper in the `interrupt_handlers` section, from which
+//! the kernel fills its IDT. `stack = N` makes the CPU switch to interrupt
+//! stack `N` of the TSS. The function counts every interrupt it handles with
+//! `crate::interrupts::count_interrupt`.
+//!
+//! ```ignore
+//! #[interrupt_handler(vector = vectors::PAGE_FAULT, error_code)]
+//! extern "C" fn page_fault_handler(frame: &ExceptionStackFrame, error_code: u64) {
+//!     ...
+//! }
+//! ```
+//!
+//! The expansion refers to `crate::interrupts`, so the macro can only be used
+//! inside the kernel crate.
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{meta, parse_macro_input, parse_quote, Error, Expr, ItemFn, Result};
+
+#[proc_macro_attribute]
+pub fn interrupt_handler(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut vector: Option<Expr> = None;
+    let mut stack: Option<Expr> = None;
+    let mut error_code = false;
+    let parser = meta::parser(|meta| {
+        if meta.path.is_ident("vector") {
+            vector = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("stack") {
+            stack = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("error_code") {
+            error_code = true;
+        } else {
+            return Err(meta.error("expected `vector`, `stack` or `error_code`"));
+        }
+        Ok(())
+    });
+    parse_macro_input!(args with parser);
+    let function = parse_macro_input!(item as ItemFn);
+
+    expand(vector, stack, error_code, function)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(
+    vector: Option<Expr>,
+    stack: Option<Expr>,
+    error_code: bool,
+    mut function: ItemFn,
+) -> Result<TokenStream2> {
+    let name = &function.sig.ident;
+    let vector = vector.ok_or_else(|| Error::new_spanned(name, "missing `vector = ..`"))?;
+
+    if function.sig.abi.is_none() {
+        return Err(Error::new_spanned(
+            &function.sig,
+            "interrupt handlers must be `extern \"C\"`",
+        ));
+    }
+    let expected = if error_code { 2 } else { 1 };
+    if function.sig.inputs.len() != expected {
+        let message = if error_code {
+            "expected the stack frame and the error code as arguments"
+        } else {
+            "expected the stack frame as only argument, add `error_code` if the CPU pushes one"
+        };
+        return Err(Error::new_spanned(&function.sig.inputs, message));
+    }
+
+    function.block.stmts.insert(
+        0,
+        parse_quote!(crate::interrupts::count_interrupt(#vector);),
+    );
+
+    let wrapper = if error_code {
+        quote!(::x86_64::handler_with_error_code!(#name))
+    } else {
+        quote!(::x86_64::handler_without_error_code!(#name))
+    };
+    let stack = match stack {
+        Some(stack) => quote!(::core::option::Option::Some(#stack)),
+        None => quote!(::core::option::Option::None),
+    };
+
+    Ok(quote! {
+        #function
+
+        const _: () = {
+            #[used]
+            #[link_section = "interrupt_handlers"]
+            static HANDLER: crate::interrupts::InterruptHandler =
+                crate::interrupts::InterruptHandler {
+                    name: stringify!(#name),
+                    vector: #vector,
+                    entry: #wrapper,
+                    stack: #stack,
+                };
+        };
+    })
+}
@@ -0,0 +1,11 @@
+//! Bounded lock-free queues.
+//!
+//! Neither queue ever blocks or allocates, which makes them usable to hand
+//! data from interrupt handlers to threads. Both have a fixed capacity and can
+//! be constructed in a `static`.
+#![no_std]
+pub mod mpmc;
+pub mod spsc;
+
+pub use mpmc::MpmcQueue;
+pub use spsc::{Receiver, Sender, SpscChannel};
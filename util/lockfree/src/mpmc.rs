@@ -0,0 +1,239 @@
+//! Bounded queue with any amount of producers and consumers.
+//!
+//! Every slot has a sequence number telling which lap of the ring it belongs
+//! to. A producer may fill a slot once its sequence equals the position it
+//! claimed, a consumer may empty it once the sequence is one ahead. Positions
+//! are claimed with a compare exchange, the slot contents are then accessed
+//! exclusively.
+//!
+//! This is the algorithm of Dmitry Vyukov's bounded MPMC queue.
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+struct Slot<T> {
+    // stored relative to the slot index so all slots start out as 0
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+pub struct MpmcQueue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    // next position to pop
+    head: AtomicUsize,
+    // next position to push
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send, const N: usize> Send for MpmcQueue<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for MpmcQueue<T, N> {}
+
+impl<T, const N: usize> MpmcQueue<T, N> {
+    pub const fn new() -> Self {
+        assert!(N > 0);
+        Self {
+            slots: [const {
+                Slot {
+                    sequence: AtomicUsize::new(0),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Appends `value`, returns it back if the queue is full
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut position = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[position % N];
+            let sequence = self.sequence(position);
+            let diff = sequence.wrapping_sub(position) as isize;
+            if diff == 0 {
+                match self.tail.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        self.set_sequence(position, position.wrapping_add(1));
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                }
+            } else if diff < 0 {
+                // the slot still holds the value of the previous lap
+                return Err(value);
+            } else {
+                position = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Removes the oldest value, None if the queue is empty
+    pub fn pop(&self) -> Option<T> {
+        let mut position = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[position % N];
+            let sequence = self.sequence(position);
+            let diff = sequence.wrapping_sub(position.wrapping_add(1)) as isize;
+            if diff == 0 {
+                match self.head.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        self.set_sequence(position, position.wrapping_add(N));
+                        return Some(value);
+                    }
+                    Err(current) => position = current,
+                }
+            } else if diff < 0 {
+                // nothing was pushed to this slot yet
+                return None;
+            } else {
+                position = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Amount of values in the queue. Only a snapshot if other threads
+    /// access the queue concurrently.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Relaxed);
+        tail.wrapping_sub(head).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn sequence(&self, position: usize) -> usize {
+        let index = position % N;
+        self.slots[index]
+            .sequence
+            .load(Ordering::Acquire)
+            .wrapping_add(index)
+    }
+
+    fn set_sequence(&self, position: usize, sequence: usize) {
+        let index = position % N;
+        self.slots[index]
+            .sequence
+            .store(sequence.wrapping_sub(index), Ordering::Release);
+    }
+}
+
+impl<T, const N: usize> Default for MpmcQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for MpmcQueue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::{sync::Arc, thread, vec::Vec};
+
+    #[test]
+    fn test_push_pop() {
+        let queue: MpmcQueue<u32, 3> = MpmcQueue::new();
+        assert!(queue.pop().is_none());
+
+        for lap in 0..5 {
+            for i in 0..3 {
+                assert!(queue.push(lap * 3 + i).is_ok());
+            }
+            assert_eq!(queue.push(100), Err(100));
+            assert_eq!(queue.len(), 3);
+            for i in 0..3 {
+                assert_eq!(queue.pop(), Some(lap * 3 + i));
+            }
+            assert!(queue.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_drops_remaining() {
+        let value = Arc::new(());
+        {
+            let queue: MpmcQueue<Arc<()>, 4> = MpmcQueue::new();
+            for _ in 0..3 {
+                queue.push(value.clone()).unwrap();
+            }
+            assert_eq!(Arc::strong_count(&value), 4);
+        }
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn test_threads() {
+        const PRODUCERS: usize = 4;
+        const CONSUMERS: usize = 4;
+        const COUNT: usize = 20_000;
+        let queue: Arc<MpmcQueue<usize, 32>> = Arc::new(MpmcQueue::new());
+
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|p| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for i in 0..COUNT {
+                        let mut value = p * COUNT + i;
+                        while let Err(v) = queue.push(value) {
+                            value = v;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let consumers: Vec<_> = (0..CONSUMERS)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    let mut received = Vec::new();
+                    while received.len() < PRODUCERS * COUNT / CONSUMERS {
+                        match queue.pop() {
+                            Some(value) => received.push(value),
+                            None => thread::yield_now(),
+                        }
+                    }
+                    received
+                })
+            })
+            .collect();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let mut received: Vec<usize> = consumers
+            .into_iter()
+            .flat_map(|c| c.join().unwrap())
+            .collect();
+        assert!(queue.is_empty());
+
+        received.sort_unstable();
+        assert!(received.iter().copied().eq(0..PRODUCERS * COUNT));
+    }
+}
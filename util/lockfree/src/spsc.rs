@@ -0,0 +1,201 @@
+//! Ring buffer channel with a single sender and a single receiver.
+//!
+//! `head` and `tail` count all received and sent values and only ever
+//! increase, the slot of a value is its count modulo the capacity. Each index
+//! is written by one side only, so a load of the other one is enough to know
+//! which slots are filled.
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+pub struct SpscChannel<T, const N: usize> {
+    buffer: [UnsafeCell<MaybeUninit<T>>; N],
+    // next value to receive
+    head: AtomicUsize,
+    // next value to send
+    tail: AtomicUsize,
+    split: AtomicBool,
+}
+
+unsafe impl<T: Send, const N: usize> Send for SpscChannel<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for SpscChannel<T, N> {}
+
+impl<T, const N: usize> SpscChannel<T, N> {
+    pub const fn new() -> Self {
+        assert!(N > 0);
+        Self {
+            buffer: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            split: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the two ends of the channel. Only succeeds once, so there is
+    /// never more than one sender and one receiver.
+    pub fn split(&self) -> Option<(Sender<'_, T, N>, Receiver<'_, T, N>)> {
+        if self.split.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        Some((Sender { channel: self }, Receiver { channel: self }))
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Amount of values that were sent but not received yet
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        self.buffer[index % N].get()
+    }
+}
+
+impl<T, const N: usize> Default for SpscChannel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for SpscChannel<T, N> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        let mut head = *self.head.get_mut();
+        while head != tail {
+            unsafe { (*self.slot(head)).assume_init_drop() };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+pub struct Sender<'a, T, const N: usize> {
+    channel: &'a SpscChannel<T, N>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for Sender<'_, T, N> {}
+
+impl<T, const N: usize> Sender<'_, T, N> {
+    /// Sends `value`, returns it back if the channel is full
+    pub fn try_send(&mut self, value: T) -> Result<(), T> {
+        let channel = self.channel;
+        let tail = channel.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(channel.head.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+
+        unsafe { (*channel.slot(tail)).write(value) };
+        channel.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.channel.len() == N
+    }
+}
+
+pub struct Receiver<'a, T, const N: usize> {
+    channel: &'a SpscChannel<T, N>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for Receiver<'_, T, N> {}
+
+impl<T, const N: usize> Receiver<'_, T, N> {
+    /// Receives the oldest value, None if the channel is empty
+    pub fn try_recv(&mut self) -> Option<T> {
+        let channel = self.channel;
+        let head = channel.head.load(Ordering::Relaxed);
+        if head == channel.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let value = unsafe { (*channel.slot(head)).assume_init_read() };
+        channel.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channel.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::{sync::Arc, thread, vec::Vec};
+
+    #[test]
+    fn test_send_receive() {
+        let channel: SpscChannel<u32, 4> = SpscChannel::new();
+        let (mut sender, mut receiver) = channel.split().unwrap();
+        assert!(channel.split().is_none());
+        assert!(receiver.try_recv().is_none());
+
+        for i in 0..4 {
+            assert!(sender.try_send(i).is_ok());
+        }
+        assert!(sender.is_full());
+        assert_eq!(sender.try_send(4), Err(4));
+        assert_eq!(channel.len(), 4);
+
+        assert_eq!(receiver.try_recv(), Some(0));
+        assert!(sender.try_send(4).is_ok());
+        for i in 1..5 {
+            assert_eq!(receiver.try_recv(), Some(i));
+        }
+        assert!(receiver.is_empty());
+    }
+
+    #[test]
+    fn test_drops_remaining() {
+        let value = Arc::new(());
+        {
+            let channel: SpscChannel<Arc<()>, 8> = SpscChannel::new();
+            let (mut sender, mut receiver) = channel.split().unwrap();
+            for _ in 0..5 {
+                sender.try_send(value.clone()).unwrap();
+            }
+            receiver.try_recv().unwrap();
+            assert_eq!(Arc::strong_count(&value), 5);
+        }
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn test_threads() {
+        const COUNT: usize = 100_000;
+        static CHANNEL: SpscChannel<usize, 16> = SpscChannel::new();
+        let (mut sender, mut receiver) = CHANNEL.split().unwrap();
+
+        let producer = thread::spawn(move || {
+            for i in 0..COUNT {
+                let mut value = i;
+                while let Err(v) = sender.try_send(value) {
+                    value = v;
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut received = Vec::with_capacity(COUNT);
+        while received.len() < COUNT {
+            match receiver.try_recv() {
+                Some(value) => received.push(value),
+                None => thread::yield_now(),
+            }
+        }
+        producer.join().unwrap();
+        assert!(received.iter().copied().eq(0..COUNT));
+    }
+}
@@ -0,0 +1,282 @@
+//! Normalization of physical memory maps.
+//!
+//! Firmware reports memory regions in any order and they may overlap. The
+//! bootloader and the kernel work on normalized maps instead: sorted by start
+//! address, without overlaps, empty regions or adjacent regions of the same
+//! kind. Where regions overlap the kind with the highest precedence wins, so
+//! memory is never considered more usable than any region claims.
+#![no_std]
+
+/// Type of the memory of a region
+pub trait Kind: Copy + Eq {
+    /// Overlapping regions are clipped in favour of the higher precedence,
+    /// which should be the more restrictive kind
+    fn precedence(&self) -> u8;
+}
+
+/// A region of physical memory, from `start` up to, not including, `end`
+pub trait Region: Copy {
+    type Kind: Kind;
+
+    fn with_range(start: u64, end: u64, kind: Self::Kind) -> Self;
+    fn region_start(&self) -> u64;
+    fn region_end(&self) -> u64;
+    fn kind(&self) -> Self::Kind;
+}
+
+/// The output buffer is too small for the map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full;
+
+/// Writes a normalized map to a buffer, region by region
+pub struct Builder<'a, R: Region> {
+    regions: &'a mut [R],
+    len: usize,
+}
+
+impl<'a, R: Region> Builder<'a, R> {
+    pub fn new(regions: &'a mut [R]) -> Self {
+        Self { regions, len: 0 }
+    }
+
+    /// Appends `region`, which must not start before the end of the last one.
+    /// It is merged into the last region if it is adjacent and of the same
+    /// kind, empty regions are skipped.
+    pub fn push(&mut self, region: R) -> Result<(), Full> {
+        if region.region_start() >= region.region_end() {
+            return Ok(());
+        }
+
+        if let Some(last) = self.regions[..self.len].last_mut() {
+            assert!(
+                region.region_start() >= last.region_end(),
+                "Regions must be pushed in order"
+            );
+            if last.region_end() == region.region_start() && last.kind() == region.kind() {
+                *last = R::with_range(last.region_start(), region.region_end(), last.kind());
+                return Ok(());
+            }
+        }
+
+        let slot = self.regions.get_mut(self.len).ok_or(Full)?;
+        *slot = region;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[R] {
+        &self.regions[..self.len]
+    }
+}
+
+/// Writes the normalized map of `regions` to `out` and returns its amount of
+/// regions. If `out` is too small it is filled with the lowest regions of the
+/// map.
+pub fn normalize<R: Region>(regions: &[R], out: &mut [R]) -> Result<usize, Full> {
+    let regions = || {
+        regions
+            .iter()
+            .filter(|region| region.region_start() < region.region_end())
+    };
+    let mut builder = Builder::new(out);
+    let Some(mut address) = regions().map(Region::region_start).min() else {
+        return Ok(0);
+    };
+
+    // the kind can only change where a region starts or ends
+    while let Some(next) = regions()
+        .flat_map(|region| [region.region_start(), region.region_end()])
+        .filter(|&boundary| boundary > address)
+        .min()
+    {
+        let kind = regions()
+            .filter(|region| region.region_start() <= address && address < region.region_end())
+            .map(Region::kind)
+            .max_by_key(Kind::precedence);
+
+        if let Some(kind) = kind {
+            builder.push(R::with_range(address, next, kind))?;
+        }
+        address = next;
+    }
+
+    Ok(builder.len())
+}
+
+/// Splits `region` into the part below `address` and the part from it on,
+/// either of them may be empty
+pub fn split<R: Region>(region: R, address: u64) -> (R, R) {
+    let address = address.clamp(region.region_start(), region.region_end());
+    (
+        R::with_range(region.region_start(), address, region.kind()),
+        R::with_range(address, region.region_end(), region.kind()),
+    )
+}
+
+/// Whether `regions` is a normalized map
+pub fn is_normalized<R: Region>(regions: &[R]) -> bool {
+    regions
+        .iter()
+        .all(|region| region.region_start() < region.region_end())
+        && regions.windows(2).all(|pair| {
+            pair[0].region_end() < pair[1].region_start()
+                || (pair[0].region_end() == pair[1].region_start()
+                    && pair[0].kind() != pair[1].kind())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum TestKind {
+        Usable,
+        Acpi,
+        Reserved,
+    }
+
+    impl Kind for TestKind {
+        fn precedence(&self) -> u8 {
+            *self as u8
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct TestRegion(u64, u64, TestKind);
+
+    impl Region for TestRegion {
+        type Kind = TestKind;
+
+        fn with_range(start: u64, end: u64, kind: TestKind) -> Self {
+            Self(start, end, kind)
+        }
+        fn region_start(&self) -> u64 {
+            self.0
+        }
+        fn region_end(&self) -> u64 {
+            self.1
+        }
+        fn kind(&self) -> TestKind {
+            self.2
+        }
+    }
+
+    use TestKind::*;
+
+    fn normalized(regions: &[TestRegion]) -> ([TestRegion; 16], usize) {
+        let mut out = [TestRegion(0, 0, Usable); 16];
+        let len = normalize(regions, &mut out).unwrap();
+        assert!(is_normalized(&out[..len]));
+        (out, len)
+    }
+
+    #[test]
+    fn test_sort() {
+        let (out, len) = normalized(&[
+            TestRegion(0x3000, 0x4000, Usable),
+            TestRegion(0x1000, 0x2000, Reserved),
+            TestRegion(0x5000, 0x5000, Reserved),
+        ]);
+        assert_eq!(
+            out[..len],
+            [
+                TestRegion(0x1000, 0x2000, Reserved),
+                TestRegion(0x3000, 0x4000, Usable),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let (out, len) = normalized(&[
+            TestRegion(0x2000, 0x3000, Usable),
+            TestRegion(0x1000, 0x2000, Usable),
+            TestRegion(0x3000, 0x4000, Acpi),
+            TestRegion(0x4000, 0x5000, Usable),
+        ]);
+        assert_eq!(
+            out[..len],
+            [
+                TestRegion(0x1000, 0x3000, Usable),
+                TestRegion(0x3000, 0x4000, Acpi),
+                TestRegion(0x4000, 0x5000, Usable),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clip() {
+        // a reserved hole within usable memory and a partial overlap
+        let (out, len) = normalized(&[
+            TestRegion(0x1000, 0x9000, Usable),
+            TestRegion(0x3000, 0x4000, Reserved),
+            TestRegion(0x8000, 0xa000, Acpi),
+            TestRegion(0x9000, 0xb000, Usable),
+        ]);
+        assert_eq!(
+            out[..len],
+            [
+                TestRegion(0x1000, 0x3000, Usable),
+                TestRegion(0x3000, 0x4000, Reserved),
+                TestRegion(0x4000, 0x8000, Usable),
+                TestRegion(0x8000, 0xa000, Acpi),
+                TestRegion(0xa000, 0xb000, Usable),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_full() {
+        let mut out = [TestRegion(0, 0, Usable); 1];
+        let regions = [
+            TestRegion(0x1000, 0x2000, Usable),
+            TestRegion(0x3000, 0x4000, Usable),
+        ];
+        assert_eq!(normalize(&regions, &mut out), Err(Full));
+        assert_eq!(out[0], TestRegion(0x1000, 0x2000, Usable));
+        assert_eq!(normalize(&[], &mut out), Ok(0));
+    }
+
+    #[test]
+    fn test_split() {
+        let region = TestRegion(0x1000, 0x3000, Usable);
+        assert_eq!(
+            split(region, 0x2000),
+            (
+                TestRegion(0x1000, 0x2000, Usable),
+                TestRegion(0x2000, 0x3000, Usable)
+            )
+        );
+        assert_eq!(
+            split(region, 0x5000),
+            (region, TestRegion(0x3000, 0x3000, Usable))
+        );
+        assert_eq!(
+            split(region, 0),
+            (TestRegion(0x1000, 0x1000, Usable), region)
+        );
+    }
+
+    #[test]
+    fn test_is_normalized() {
+        assert!(is_normalized::<TestRegion>(&[]));
+        assert!(!is_normalized(&[
+            TestRegion(0x2000, 0x3000, Usable),
+            TestRegion(0x1000, 0x2000, Reserved),
+        ]));
+        assert!(!is_normalized(&[
+            TestRegion(0x1000, 0x2000, Usable),
+            TestRegion(0x2000, 0x3000, Usable),
+        ]));
+        assert!(!is_normalized(&[TestRegion(0x1000, 0x1000, Usable)]));
+    }
+}
@@ -0,0 +1,198 @@
+//! Derive macros converting between fieldless enums and their primitive
+//! representation.
+//!
+//! Both derives need a `#[repr(..)]` with an integer type. Discriminants may
+//! be implicit or any constant expression, the generated code compares
+//! against `Enum::Variant as T` so it always agrees with the compiler.
+//!
+//! `TryFromPrimitive` implements `TryFrom<repr>`. By default an unknown value
+//! is returned back as the error. This can be changed with
+//! `#[num_enum(error_type = Errno, error = Errno::EINVAL)]`, and
+//! `#[num_enum(from = u64)]` converts from a different primitive than the
+//! repr.
+//!
+//! `IntoPrimitive` implements `From<Enum> for repr`.
+//!
+//! ```
+//! use num_enum::{IntoPrimitive, TryFromPrimitive};
+//!
+//! #[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+//! #[repr(u8)]
+//! enum Kind {
+//!     End,
+//!     File = 4,
+//!     Directory,
+//! }
+//!
+//! assert_eq!(Kind::try_from(5), Ok(Kind::Directory));
+//! assert_eq!(Kind::try_from(1), Err(1));
+//! assert_eq!(u8::from(Kind::File), 4);
+//! ```
+//!
+//! Variants with fields are rejected:
+//!
+//! ```compile_fail
+//! #[derive(num_enum::TryFromPrimitive)]
+//! #[repr(u8)]
+//! enum Message {
+//!     Quit,
+//!     Write(u8),
+//! }
+//! ```
+//!
+//! So are enums without an integer repr:
+//!
+//! ```compile_fail
+//! #[derive(num_enum::IntoPrimitive)]
+//! enum Kind {
+//!     End,
+//!     File,
+//! }
+//! ```
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Error, Expr, Fields, Ident, Result, Type};
+
+const PRIMITIVES: [&str; 12] = [
+    "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize",
+];
+
+#[proc_macro_derive(TryFromPrimitive, attributes(num_enum))]
+pub fn derive_try_from_primitive(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    try_from_primitive(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(IntoPrimitive, attributes(num_enum))]
+pub fn derive_into_primitive(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    into_primitive(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Options given with `#[num_enum(..)]`
+#[derive(Default)]
+struct Options {
+    from: Option<Type>,
+    error_type: Option<Type>,
+    error: Option<Expr>,
+}
+
+impl Options {
+    fn parse(input: &DeriveInput) -> Result<Self> {
+        let mut options = Self::default();
+        for attr in input.attrs.iter().filter(|a| a.path().is_ident("num_enum")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("from") {
+                    options.from = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("error_type") {
+                    options.error_type = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("error") {
+                    options.error = Some(meta.value()?.parse()?);
+                } else {
+                    return Err(meta.error("expected `from`, `error_type` or `error`"));
+                }
+                Ok(())
+            })?;
+        }
+
+        if options.error_type.is_some() != options.error.is_some() {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "`error_type` and `error` must be given together",
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// The integer type of `#[repr(..)]`
+fn repr(input: &DeriveInput) -> Result<Ident> {
+    let mut repr = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            if let Some(ident) = meta.path.get_ident() {
+                if PRIMITIVES.contains(&ident.to_string().as_str()) {
+                    repr = Some(ident.clone());
+                }
+            }
+            Ok(())
+        })?;
+    }
+    repr.ok_or_else(|| {
+        Error::new_spanned(&input.ident, "missing `#[repr(..)]` with an integer type")
+    })
+}
+
+/// Names of all variants, fails for anything but a fieldless enum
+fn variants(input: &DeriveInput) -> Result<Vec<&Ident>> {
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => return Err(Error::new_spanned(&input.ident, "only enums are supported")),
+    };
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "generic enums are not supported",
+        ));
+    }
+
+    data.variants
+        .iter()
+        .map(|variant| match variant.fields {
+            Fields::Unit => Ok(&variant.ident),
+            _ => Err(Error::new_spanned(variant, "variants must not have fields")),
+        })
+        .collect()
+}
+
+fn try_from_primitive(input: &DeriveInput) -> Result<TokenStream2> {
+    let repr = repr(input)?;
+    let variants = variants(input)?;
+    let options = Options::parse(input)?;
+    let name = &input.ident;
+
+    let from = options
+        .from
+        .unwrap_or_else(|| Type::Verbatim(quote!(#repr)));
+    let (error_type, error) = match (options.error_type, options.error) {
+        (Some(error_type), Some(error)) => (quote!(#error_type), quote!(#error)),
+        _ => (quote!(#from), quote!(value)),
+    };
+    let constants: Vec<_> = variants
+        .iter()
+        .map(|variant| format_ident!("{}_DISCRIMINANT", variant.to_string().to_uppercase()))
+        .collect();
+
+    Ok(quote! {
+        impl ::core::convert::TryFrom<#from> for #name {
+            type Error = #error_type;
+
+            fn try_from(value: #from) -> ::core::result::Result<Self, #error_type> {
+                #(const #constants: #from = #name::#variants as #from;)*
+                match value {
+                    #(#constants => ::core::result::Result::Ok(#name::#variants),)*
+                    _ => ::core::result::Result::Err(#error),
+                }
+            }
+        }
+    })
+}
+
+fn into_primitive(input: &DeriveInput) -> Result<TokenStream2> {
+    let repr = repr(input)?;
+    variants(input)?;
+    let name = &input.ident;
+
+    Ok(quote! {
+        impl ::core::convert::From<#name> for #repr {
+            fn from(value: #name) -> #repr {
+                value as #repr
+            }
+        }
+    })
+}
@@ -0,0 +1,65 @@
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+enum Implicit {
+    A,
+    B,
+    C,
+}
+
+const BASE: i16 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[repr(i16)]
+enum Explicit {
+    Negative = -3,
+    Next,
+    Far = BASE * 2,
+    #[allow(non_camel_case_types)]
+    lower_case = 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Error {
+    Invalid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+#[num_enum(from = u64, error_type = Error, error = Error::Invalid)]
+enum Custom {
+    One = 1,
+    Two = 2,
+}
+
+#[test]
+fn test_implicit_discriminants() {
+    assert_eq!(Implicit::try_from(0), Ok(Implicit::A));
+    assert_eq!(Implicit::try_from(2), Ok(Implicit::C));
+    assert_eq!(Implicit::try_from(3), Err(3));
+    assert_eq!(u8::from(Implicit::B), 1);
+}
+
+#[test]
+fn test_explicit_discriminants() {
+    assert_eq!(Explicit::try_from(-3), Ok(Explicit::Negative));
+    assert_eq!(Explicit::try_from(-2), Ok(Explicit::Next));
+    assert_eq!(Explicit::try_from(200), Ok(Explicit::Far));
+    assert_eq!(Explicit::try_from(7), Ok(Explicit::lower_case));
+    assert_eq!(Explicit::try_from(100), Err(100));
+    assert_eq!(i16::from(Explicit::Far), 200);
+
+    for value in [Explicit::Negative, Explicit::Next, Explicit::Far] {
+        assert_eq!(Explicit::try_from(i16::from(value)), Ok(value));
+    }
+}
+
+#[test]
+fn test_custom_error() {
+    assert_eq!(Custom::try_from(2u64), Ok(Custom::Two));
+    assert_eq!(Custom::try_from(0u64), Err(Error::Invalid));
+    // no truncation to the repr
+    assert_eq!(Custom::try_from(257u64), Err(Error::Invalid));
+    assert_eq!(u8::from(Custom::One), 1);
+}
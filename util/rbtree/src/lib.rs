@@ -0,0 +1,679 @@
+//! Intrusive augmented red-black tree.
+//!
+//! The tree never allocates. Values embed an [`RbLink`] and the tree only
+//! links those together, [`container_of!`] gets back from a link to the value
+//! containing it. Ordering is up to the user: lookups walk down from
+//! [`RbTree::root`] and insertion either takes the position found that way or
+//! a comparison with [`RbTree::insert_by`].
+//!
+//! Every node can cache data about its subtree, e.g. the largest gap between
+//! two ranges. [`Augment::update`] recomputes it from the children and is
+//! called for every node whose subtree changed. Rotations keep the set of
+//! values in the subtree above them, so only the rotated nodes and the path
+//! from a changed node to the root have to be updated.
+#![no_std]
+use core::{marker::PhantomData, ptr::NonNull};
+
+/// Returns a pointer to the `$type` whose field `$member` `$ptr` points to
+#[macro_export]
+macro_rules! container_of {
+    ($ptr:expr, $type:path, $member:ident) => {
+        $ptr.cast::<u8>()
+            .sub(::core::mem::offset_of!($type, $member))
+            .cast::<$type>()
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Red,
+    Black,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// Node of the tree, embedded in the values
+#[derive(Debug)]
+pub struct RbLink {
+    parent: Option<NonNull<RbLink>>,
+    left: Option<NonNull<RbLink>>,
+    right: Option<NonNull<RbLink>>,
+    color: Color,
+}
+
+impl RbLink {
+    pub const fn new() -> Self {
+        Self {
+            parent: None,
+            left: None,
+            right: None,
+            color: Color::Red,
+        }
+    }
+
+    pub fn parent(&self) -> Option<NonNull<RbLink>> {
+        self.parent
+    }
+
+    pub fn left(&self) -> Option<NonNull<RbLink>> {
+        self.left
+    }
+
+    pub fn right(&self) -> Option<NonNull<RbLink>> {
+        self.right
+    }
+
+    pub fn child(&self, side: Side) -> Option<NonNull<RbLink>> {
+        match side {
+            Side::Left => self.left,
+            Side::Right => self.right,
+        }
+    }
+}
+
+impl Default for RbLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Maintains data cached in the nodes about their subtrees
+pub trait Augment {
+    /// Recomputes the data of `link` from its own value and its children,
+    /// whose data is up to date
+    ///
+    /// # Safety
+    /// `link` is part of a tree of values this is implemented for.
+    unsafe fn update(link: NonNull<RbLink>);
+}
+
+/// No cached data
+impl Augment for () {
+    unsafe fn update(_link: NonNull<RbLink>) {}
+}
+
+// Field accesses go through raw pointers, references to several links that
+// point to each other would alias.
+unsafe fn parent(link: NonNull<RbLink>) -> Option<NonNull<RbLink>> {
+    (*link.as_ptr()).parent
+}
+
+unsafe fn left(link: NonNull<RbLink>) -> Option<NonNull<RbLink>> {
+    (*link.as_ptr()).left
+}
+
+unsafe fn right(link: NonNull<RbLink>) -> Option<NonNull<RbLink>> {
+    (*link.as_ptr()).right
+}
+
+unsafe fn set_parent(link: NonNull<RbLink>, parent: Option<NonNull<RbLink>>) {
+    (*link.as_ptr()).parent = parent;
+}
+
+unsafe fn set_left(link: NonNull<RbLink>, left: Option<NonNull<RbLink>>) {
+    (*link.as_ptr()).left = left;
+}
+
+unsafe fn set_right(link: NonNull<RbLink>, right: Option<NonNull<RbLink>>) {
+    (*link.as_ptr()).right = right;
+}
+
+unsafe fn color(link: Option<NonNull<RbLink>>) -> Color {
+    // missing children are black
+    link.map_or(Color::Black, |l| (*l.as_ptr()).color)
+}
+
+unsafe fn set_color(link: NonNull<RbLink>, color: Color) {
+    (*link.as_ptr()).color = color;
+}
+
+/// Leftmost node of the subtree of `link`
+///
+/// # Safety
+/// `link` is part of a tree.
+pub unsafe fn leftmost(mut link: NonNull<RbLink>) -> NonNull<RbLink> {
+    while let Some(l) = left(link) {
+        link = l;
+    }
+    link
+}
+
+/// Rightmost node of the subtree of `link`
+///
+/// # Safety
+/// `link` is part of a tree.
+pub unsafe fn rightmost(mut link: NonNull<RbLink>) -> NonNull<RbLink> {
+    while let Some(r) = right(link) {
+        link = r;
+    }
+    link
+}
+
+/// In order successor of `link`
+///
+/// # Safety
+/// `link` is part of a tree.
+pub unsafe fn next(link: NonNull<RbLink>) -> Option<NonNull<RbLink>> {
+    if let Some(r) = right(link) {
+        return Some(leftmost(r));
+    }
+    let mut child = link;
+    while let Some(p) = parent(child) {
+        if left(p) == Some(child) {
+            return Some(p);
+        }
+        child = p;
+    }
+    None
+}
+
+/// In order predecessor of `link`
+///
+/// # Safety
+/// `link` is part of a tree.
+pub unsafe fn prev(link: NonNull<RbLink>) -> Option<NonNull<RbLink>> {
+    if let Some(l) = left(link) {
+        return Some(rightmost(l));
+    }
+    let mut child = link;
+    while let Some(p) = parent(child) {
+        if right(p) == Some(child) {
+            return Some(p);
+        }
+        child = p;
+    }
+    None
+}
+
+pub struct RbTree<A: Augment = ()> {
+    root: Option<NonNull<RbLink>>,
+    len: usize,
+    _augment: PhantomData<A>,
+}
+
+unsafe impl<A: Augment> Send for RbTree<A> {}
+
+impl<A: Augment> RbTree<A> {
+    pub const fn new() -> Self {
+        Self {
+            root: None,
+            len: 0,
+            _augment: PhantomData,
+        }
+    }
+
+    pub fn root(&self) -> Option<NonNull<RbLink>> {
+        self.root
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn first(&self) -> Option<NonNull<RbLink>> {
+        self.root.map(|root| unsafe { leftmost(root) })
+    }
+
+    pub fn last(&self) -> Option<NonNull<RbLink>> {
+        self.root.map(|root| unsafe { rightmost(root) })
+    }
+
+    /// Iterates over the links in order
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            next: self.first(),
+            _tree: PhantomData,
+        }
+    }
+
+    /// Forgets all nodes without touching them
+    pub fn clear(&mut self) {
+        self.root = None;
+        self.len = 0;
+    }
+
+    /// Inserts `link` at the position where `goes_left` returns true for
+    /// all nodes after it and false for all before it. Equal nodes are
+    /// inserted after the existing ones if `goes_left` returns false for them.
+    ///
+    /// # Safety
+    /// `link` must not be part of a tree and must stay at the same address
+    /// until it is removed again.
+    pub unsafe fn insert_by<F>(&mut self, link: NonNull<RbLink>, mut goes_left: F)
+    where
+        F: FnMut(NonNull<RbLink>) -> bool,
+    {
+        let mut parent = None;
+        let mut side = Side::Left;
+        let mut current = self.root;
+        while let Some(node) = current {
+            parent = Some(node);
+            side = if goes_left(node) {
+                Side::Left
+            } else {
+                Side::Right
+            };
+            current = (*node.as_ptr()).child(side);
+        }
+        self.insert(link, parent, side);
+    }
+
+    /// Inserts `link` as the `side` child of `parent`, which must not have
+    /// one yet, or as the root if `parent` is None, and rebalances the tree.
+    ///
+    /// # Safety
+    /// Same as [`insert_by`](Self::insert_by). `parent` is part of this tree
+    /// and the position keeps the order of the tree.
+    pub unsafe fn insert(
+        &mut self,
+        link: NonNull<RbLink>,
+        parent: Option<NonNull<RbLink>>,
+        side: Side,
+    ) {
+        link.as_ptr().write(RbLink {
+            parent,
+            left: None,
+            right: None,
+            color: Color::Red,
+        });
+        match parent {
+            Some(p) => {
+                assert!((*p.as_ptr()).child(side).is_none());
+                match side {
+                    Side::Left => set_left(p, Some(link)),
+                    Side::Right => set_right(p, Some(link)),
+                }
+            }
+            None => {
+                assert!(self.root.is_none());
+                self.root = Some(link);
+            }
+        }
+        self.len += 1;
+
+        self.propagate(Some(link));
+        self.insert_fixup(link);
+    }
+
+    /// Unlinks `link` from the tree and rebalances it
+    ///
+    /// # Safety
+    /// `link` is part of this tree.
+    pub unsafe fn remove(&mut self, link: NonNull<RbLink>) {
+        let removed_color;
+        let x;
+        let x_parent;
+        match (left(link), right(link)) {
+            (None, child) | (child, None) => {
+                removed_color = color(Some(link));
+                x = child;
+                x_parent = parent(link);
+                self.transplant(link, child);
+            }
+            (Some(l), Some(r)) => {
+                // the successor takes the place of the removed node
+                let successor = leftmost(r);
+                removed_color = color(Some(successor));
+                x = right(successor);
+                if successor == r {
+                    x_parent = Some(successor);
+                } else {
+                    x_parent = parent(successor);
+                    self.transplant(successor, x);
+                    set_right(successor, Some(r));
+                    set_parent(r, Some(successor));
+                }
+                self.transplant(link, Some(successor));
+                set_left(successor, Some(l));
+                set_parent(l, Some(successor));
+                set_color(successor, color(Some(link)));
+            }
+        }
+        self.len -= 1;
+
+        self.propagate(x_parent);
+        if removed_color == Color::Black {
+            self.remove_fixup(x, x_parent);
+        }
+        link.as_ptr().write(RbLink::new());
+    }
+
+    /// Updates the cached data of `link` and all its ancestors
+    ///
+    /// # Safety
+    /// `link` is part of this tree.
+    pub unsafe fn propagate(&mut self, mut link: Option<NonNull<RbLink>>) {
+        while let Some(l) = link {
+            A::update(l);
+            link = parent(l);
+        }
+    }
+
+    /// Replaces the subtree of `old` with the one of `new`
+    unsafe fn transplant(&mut self, old: NonNull<RbLink>, new: Option<NonNull<RbLink>>) {
+        let p = parent(old);
+        match p {
+            None => self.root = new,
+            Some(p) if left(p) == Some(old) => set_left(p, new),
+            Some(p) => set_right(p, new),
+        }
+        if let Some(new) = new {
+            set_parent(new, p);
+        }
+    }
+
+    unsafe fn rotate_left(&mut self, x: NonNull<RbLink>) {
+        let y = right(x).unwrap();
+        set_right(x, left(y));
+        if let Some(l) = left(y) {
+            set_parent(l, Some(x));
+        }
+        self.transplant(x, Some(y));
+        set_left(y, Some(x));
+        set_parent(x, Some(y));
+        A::update(x);
+        A::update(y);
+    }
+
+    unsafe fn rotate_right(&mut self, x: NonNull<RbLink>) {
+        let y = left(x).unwrap();
+        set_left(x, right(y));
+        if let Some(r) = right(y) {
+            set_parent(r, Some(x));
+        }
+        self.transplant(x, Some(y));
+        set_right(y, Some(x));
+        set_parent(x, Some(y));
+        A::update(x);
+        A::update(y);
+    }
+
+    unsafe fn insert_fixup(&mut self, mut z: NonNull<RbLink>) {
+        while let Some(mut p) = parent(z).filter(|p| color(Some(*p)) == Color::Red) {
+            // the root is black, so a red node has a parent
+            let g = parent(p).unwrap();
+            if left(g) == Some(p) {
+                let uncle = right(g);
+                if color(uncle) == Color::Red {
+                    set_color(p, Color::Black);
+                    set_color(uncle.unwrap(), Color::Black);
+                    set_color(g, Color::Red);
+                    z = g;
+                    continue;
+                }
+                if right(p) == Some(z) {
+                    z = p;
+                    self.rotate_left(z);
+                    p = parent(z).unwrap();
+                }
+                set_color(p, Color::Black);
+                set_color(g, Color::Red);
+                self.rotate_right(g);
+            } else {
+                let uncle = left(g);
+                if color(uncle) == Color::Red {
+                    set_color(p, Color::Black);
+                    set_color(uncle.unwrap(), Color::Black);
+                    set_color(g, Color::Red);
+                    z = g;
+                    continue;
+                }
+                if left(p) == Some(z) {
+                    z = p;
+                    self.rotate_right(z);
+                    p = parent(z).unwrap();
+                }
+                set_color(p, Color::Black);
+                set_color(g, Color::Red);
+                self.rotate_left(g);
+            }
+        }
+        set_color(self.root.unwrap(), Color::Black);
+    }
+
+    /// `x` carries an extra black, `parent` is its parent since `x` might
+    /// be missing
+    unsafe fn remove_fixup(
+        &mut self,
+        mut x: Option<NonNull<RbLink>>,
+        mut x_parent: Option<NonNull<RbLink>>,
+    ) {
+        while x != self.root && color(x) == Color::Black {
+            let p = x_parent.unwrap();
+            if left(p) == x {
+                // the sibling exists since the path through x lacks a black
+                let mut w = right(p).unwrap();
+                if color(Some(w)) == Color::Red {
+                    set_color(w, Color::Black);
+                    set_color(p, Color::Red);
+                    self.rotate_left(p);
+                    w = right(p).unwrap();
+                }
+                if color(left(w)) == Color::Black && color(right(w)) == Color::Black {
+                    set_color(w, Color::Red);
+                    x = Some(p);
+                    x_parent = parent(p);
+                    continue;
+                }
+                if color(right(w)) == Color::Black {
+                    set_color(left(w).unwrap(), Color::Black);
+                    set_color(w, Color::Red);
+                    self.rotate_right(w);
+                    w = right(p).unwrap();
+                }
+                set_color(w, color(Some(p)));
+                set_color(p, Color::Black);
+                set_color(right(w).unwrap(), Color::Black);
+                self.rotate_left(p);
+            } else {
+                let mut w = left(p).unwrap();
+                if color(Some(w)) == Color::Red {
+                    set_color(w, Color::Black);
+                    set_color(p, Color::Red);
+                    self.rotate_right(p);
+                    w = left(p).unwrap();
+                }
+                if color(left(w)) == Color::Black && color(right(w)) == Color::Black {
+                    set_color(w, Color::Red);
+                    x = Some(p);
+                    x_parent = parent(p);
+                    continue;
+                }
+                if color(left(w)) == Color::Black {
+                    set_color(right(w).unwrap(), Color::Black);
+                    set_color(w, Color::Red);
+                    self.rotate_left(w);
+                    w = left(p).unwrap();
+                }
+                set_color(w, color(Some(p)));
+                set_color(p, Color::Black);
+                set_color(left(w).unwrap(), Color::Black);
+                self.rotate_right(p);
+            }
+            x = self.root;
+            break;
+        }
+        if let Some(x) = x {
+            set_color(x, Color::Black);
+        }
+    }
+}
+
+impl<A: Augment> Default for RbTree<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// In order iterator over the links of a tree
+pub struct Iter<'a> {
+    next: Option<NonNull<RbLink>>,
+    _tree: PhantomData<&'a ()>,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = NonNull<RbLink>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let link = self.next?;
+        self.next = unsafe { next(link) };
+        Some(link)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use super::*;
+    use alloc::{boxed::Box, vec::Vec};
+
+    struct Item {
+        key: u64,
+        link: RbLink,
+        // amount of items in the subtree
+        count: usize,
+    }
+
+    impl Item {
+        fn new(key: u64) -> Box<Self> {
+            Box::new(Self {
+                key,
+                link: RbLink::new(),
+                count: 1,
+            })
+        }
+    }
+
+    unsafe fn item<'a>(link: NonNull<RbLink>) -> &'a mut Item {
+        &mut *container_of!(link.as_ptr(), Item, link)
+    }
+
+    struct Count;
+
+    impl Augment for Count {
+        unsafe fn update(link: NonNull<RbLink>) {
+            let count = |child: Option<NonNull<RbLink>>| child.map_or(0, |c| item(c).count);
+            item(link).count = 1 + count(left(link)) + count(right(link));
+        }
+    }
+
+    /// Checks the red-black properties, parent links, order and cached
+    /// counts. Returns the black height.
+    unsafe fn check(link: Option<NonNull<RbLink>>, parent_link: Option<NonNull<RbLink>>) -> usize {
+        let Some(link) = link else {
+            return 1;
+        };
+        assert_eq!(parent(link), parent_link);
+        if color(Some(link)) == Color::Red {
+            assert_eq!(color(left(link)), Color::Black);
+            assert_eq!(color(right(link)), Color::Black);
+        }
+        if let Some(l) = left(link) {
+            assert!(item(l).key <= item(link).key);
+        }
+        if let Some(r) = right(link) {
+            assert!(item(r).key >= item(link).key);
+        }
+        let count = |child: Option<NonNull<RbLink>>| child.map_or(0, |c| item(c).count);
+        assert_eq!(item(link).count, 1 + count(left(link)) + count(right(link)));
+
+        let height = check(left(link), Some(link));
+        assert_eq!(height, check(right(link), Some(link)));
+        height + (color(Some(link)) == Color::Black) as usize
+    }
+
+    fn insert(tree: &mut RbTree<Count>, item: &mut Item) {
+        let key = item.key;
+        unsafe {
+            tree.insert_by(NonNull::from(&mut item.link), |l| key < self::item(l).key);
+        }
+    }
+
+    fn keys(tree: &RbTree<Count>) -> Vec<u64> {
+        tree.iter().map(|l| unsafe { item(l).key }).collect()
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut tree = RbTree::<Count>::new();
+        let mut items: Vec<Box<Item>> = (0..100).map(|i| Item::new(i * 37 % 100)).collect();
+        for item in items.iter_mut() {
+            insert(&mut tree, item);
+            unsafe { check(tree.root(), None) };
+        }
+        assert_eq!(tree.len(), 100);
+        assert!(keys(&tree).into_iter().eq(0..100));
+        assert_eq!(unsafe { item(tree.root().unwrap()).count }, 100);
+        assert_eq!(unsafe { item(tree.first().unwrap()).key }, 0);
+        assert_eq!(unsafe { item(tree.last().unwrap()).key }, 99);
+
+        for item in items.iter_mut().filter(|i| i.key % 2 == 0) {
+            unsafe { tree.remove(NonNull::from(&mut item.link)) };
+            unsafe { check(tree.root(), None) };
+        }
+        assert!(keys(&tree).into_iter().eq((1..100).step_by(2)));
+
+        let mut link = tree.last();
+        let mut previous = Vec::new();
+        while let Some(l) = link {
+            previous.push(unsafe { item(l).key });
+            link = unsafe { prev(l) };
+        }
+        previous.reverse();
+        assert!(previous.into_iter().eq((1..100).step_by(2)));
+    }
+
+    #[test]
+    fn test_random_against_reference() {
+        let mut tree = RbTree::<Count>::new();
+        let mut items: Vec<Box<Item>> = (0..300).map(Item::new).collect();
+        let mut linked = [false; 300];
+        let mut seed = 0x9e37_79b9_7f4a_7c15u64;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+
+        for round in 0..20_000 {
+            let idx = (random() % 300) as usize;
+            if linked[idx] {
+                unsafe { tree.remove(NonNull::from(&mut items[idx].link)) };
+            } else {
+                insert(&mut tree, &mut items[idx]);
+            }
+            linked[idx] = !linked[idx];
+
+            if round % 100 == 0 {
+                unsafe { check(tree.root(), None) };
+                let expected = (0..300u64).filter(|i| linked[*i as usize]);
+                assert!(keys(&tree).into_iter().eq(expected));
+            }
+        }
+        assert_eq!(tree.len(), linked.iter().filter(|l| **l).count());
+    }
+
+    #[test]
+    fn test_duplicates_keep_insertion_order() {
+        let mut tree = RbTree::<Count>::new();
+        let mut items: Vec<Box<Item>> = (0..20).map(|_| Item::new(5)).collect();
+        for item in items.iter_mut() {
+            insert(&mut tree, item);
+        }
+        let order: Vec<*const Item> = tree
+            .iter()
+            .map(|l| unsafe { item(l) as *const Item })
+            .collect();
+        let expected: Vec<*const Item> = items.iter().map(|i| &**i as *const Item).collect();
+        assert_eq!(order, expected);
+    }
+}
@@ -0,0 +1,221 @@
+//! Grace period bookkeeping for read-copy-update.
+//!
+//! Readers access shared data without taking a lock. A writer publishes a new
+//! version and may only free the old one once every reader that could still
+//! see it is done. Readers are never tracked individually, instead every CPU
+//! reports a quiescent state whenever it is known to be outside of a read
+//! side critical section. Once all CPUs did so after a grace period started,
+//! the grace period is complete and everything retired before it started can
+//! be reclaimed.
+//!
+//! This crate only implements the state machine. Tracking read side critical
+//! sections, locking and running the callbacks is left to the user.
+#![no_std]
+extern crate alloc;
+use alloc::collections::VecDeque;
+
+/// Maximum amount of CPUs, every CPU is a bit in the pending mask
+pub const MAX_CPUS: usize = 64;
+
+/// Grace period state of all CPUs and the callbacks waiting for grace periods
+/// to complete.
+///
+/// Grace periods are numbered. A grace period is in progress while `started`
+/// is ahead of `completed`.
+pub struct GracePeriods<C> {
+    cpus: usize,
+    started: u64,
+    completed: u64,
+    // highest grace period anyone waits for
+    requested: u64,
+    // CPUs that still have to pass through a quiescent state
+    pending: u64,
+    // ordered by the grace period they wait for
+    callbacks: VecDeque<(u64, C)>,
+}
+
+impl<C> GracePeriods<C> {
+    pub const fn new(cpus: usize) -> Self {
+        assert!(cpus > 0 && cpus <= MAX_CPUS);
+        Self {
+            cpus,
+            started: 0,
+            completed: 0,
+            requested: 0,
+            pending: 0,
+            callbacks: VecDeque::new(),
+        }
+    }
+
+    pub fn cpus(&self) -> usize {
+        self.cpus
+    }
+
+    /// Number of the last completed grace period
+    pub fn completed(&self) -> u64 {
+        self.completed
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.started != self.completed
+    }
+
+    /// Returns whether grace period `target` is complete
+    pub fn is_complete(&self, target: u64) -> bool {
+        self.completed >= target
+    }
+
+    /// Requests a grace period that starts after this call and returns its
+    /// number.
+    ///
+    /// A grace period that is already in progress might have started before
+    /// a reader that still sees the retired data, so the next one is needed
+    /// then.
+    pub fn request(&mut self) -> u64 {
+        let target = if self.in_progress() {
+            self.started + 1
+        } else {
+            self.start();
+            self.started
+        };
+        self.requested = self.requested.max(target);
+        target
+    }
+
+    /// Queues `callback` until a grace period starting after this call
+    /// completed
+    pub fn defer(&mut self, callback: C) -> u64 {
+        let target = self.request();
+        self.callbacks.push_back((target, callback));
+        target
+    }
+
+    /// Records that `cpu` is outside of any read side critical section.
+    /// Returns whether this completed a grace period.
+    pub fn quiescent_state(&mut self, cpu: usize) -> bool {
+        assert!(cpu < self.cpus);
+        if !self.in_progress() {
+            return false;
+        }
+
+        self.pending &= !(1 << cpu);
+        if self.pending != 0 {
+            return false;
+        }
+
+        self.completed = self.started;
+        // a grace period was requested while this one was in progress
+        if self.requested > self.completed {
+            self.start();
+        }
+        true
+    }
+
+    /// Amount of callbacks that wait for a grace period
+    pub fn pending_callbacks(&self) -> usize {
+        self.callbacks.len()
+    }
+
+    /// Returns whether callbacks are ready to run
+    pub fn has_ready(&self) -> bool {
+        self.callbacks
+            .front()
+            .is_some_and(|(t, _)| self.is_complete(*t))
+    }
+
+    /// Removes the next callback whose grace period completed
+    pub fn pop_ready(&mut self) -> Option<C> {
+        if self.has_ready() {
+            self.callbacks.pop_front().map(|(_, c)| c)
+        } else {
+            None
+        }
+    }
+
+    fn start(&mut self) {
+        self.started += 1;
+        self.pending = if self.cpus == MAX_CPUS {
+            u64::MAX
+        } else {
+            (1 << self.cpus) - 1
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn ready(periods: &mut GracePeriods<u32>) -> Vec<u32> {
+        let mut ready = Vec::new();
+        while let Some(callback) = periods.pop_ready() {
+            ready.push(callback);
+        }
+        ready
+    }
+
+    #[test]
+    fn test_waits_for_all_cpus() {
+        let mut periods = GracePeriods::new(3);
+        assert!(!periods.quiescent_state(0));
+
+        let target = periods.defer(1);
+        assert!(periods.in_progress());
+        assert!(!periods.quiescent_state(0));
+        assert!(!periods.quiescent_state(0));
+        assert!(!periods.quiescent_state(2));
+        assert!(!periods.has_ready());
+
+        assert!(periods.quiescent_state(1));
+        assert!(periods.is_complete(target));
+        assert!(!periods.in_progress());
+        assert_eq!(ready(&mut periods), [1]);
+        assert_eq!(periods.pending_callbacks(), 0);
+    }
+
+    #[test]
+    fn test_defer_during_grace_period() {
+        let mut periods = GracePeriods::new(2);
+        let first = periods.defer(1);
+        periods.quiescent_state(0);
+        // cpu 0 might already read the data retired now
+        let second = periods.defer(2);
+        assert_eq!(second, first + 1);
+
+        assert!(periods.quiescent_state(1));
+        assert_eq!(ready(&mut periods), [1]);
+        // the next grace period started right away
+        assert!(periods.in_progress());
+        periods.quiescent_state(1);
+        assert!(periods.quiescent_state(0));
+        assert!(periods.is_complete(second));
+        assert_eq!(ready(&mut periods), [2]);
+        assert!(!periods.in_progress());
+    }
+
+    #[test]
+    fn test_request_without_callback() {
+        let mut periods: GracePeriods<u32> = GracePeriods::new(1);
+        let target = periods.request();
+        assert!(!periods.is_complete(target));
+        assert_eq!(periods.request(), target + 1);
+        assert!(periods.quiescent_state(0));
+        assert!(periods.is_complete(target));
+        assert!(!periods.is_complete(target + 1));
+        assert!(periods.quiescent_state(0));
+        assert!(periods.is_complete(target + 1));
+        assert!(!periods.has_ready());
+    }
+
+    #[test]
+    fn test_max_cpus() {
+        let mut periods = GracePeriods::new(MAX_CPUS);
+        periods.defer(1);
+        for cpu in 0..MAX_CPUS - 1 {
+            assert!(!periods.quiescent_state(cpu));
+        }
+        assert!(periods.quiescent_state(MAX_CPUS - 1));
+        assert_eq!(ready(&mut periods), [1]);
+    }
+}
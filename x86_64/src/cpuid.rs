@@ -0,0 +1,327 @@
+//! This module implements structured access to the cpuid instruction
+//!
+//! Feature bits are spread over several leaves and registers, [`Features`]
+//! collects the ones this project cares about into a single set of flags.
+//!
+//! https://www.felixcloutier.com/x86/cpuid
+use crate::instructions::cpuid;
+use bitflags::bitflags;
+use core::{fmt, str};
+
+const VENDOR: u32 = 0;
+const FEATURES: u32 = 1;
+const MONITOR_MWAIT: u32 = 5;
+const EXTENDED_FEATURES: u32 = 7;
+const PERFORMANCE_MONITORING: u32 = 0xa;
+const EXTENDED_FUNCTIONS: u32 = 0x8000_0000;
+const EXTENDED_INFO: u32 = 0x8000_0001;
+const BRAND_STRING: u32 = 0x8000_0002;
+const POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+bitflags! {
+    /// Features reported by the cpuid instruction.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Features: u64 {
+        /// x87 floating point unit on chip.
+        const FPU = 1;
+        /// The time stamp counter and `rdtsc`.
+        const TSC = 1 << 1;
+        /// `rdmsr` and `wrmsr`.
+        const MSR = 1 << 2;
+        /// Local APIC on chip.
+        const APIC = 1 << 3;
+        /// Page attribute table.
+        const PAT = 1 << 4;
+        /// `fxsave` and `fxrstor`.
+        const FXSR = 1 << 5;
+        const SSE = 1 << 6;
+        const SSE2 = 1 << 7;
+        const SSE3 = 1 << 8;
+        const SSSE3 = 1 << 9;
+        const SSE4_1 = 1 << 10;
+        const SSE4_2 = 1 << 11;
+        /// Process context identifiers.
+        const PCID = 1 << 12;
+        /// The local APIC supports x2APIC mode.
+        const X2APIC = 1 << 13;
+        /// The local APIC timer supports TSC-deadline mode.
+        const TSC_DEADLINE = 1 << 14;
+        /// `xsave`, `xrstor`, `xsetbv` and `xgetbv`.
+        const XSAVE = 1 << 15;
+        /// The OS enabled `xsave` in CR4.
+        const OSXSAVE = 1 << 16;
+        const AVX = 1 << 17;
+        /// `rdrand`.
+        const RDRAND = 1 << 18;
+        const AVX2 = 1 << 19;
+        /// Supervisor mode execution prevention.
+        const SMEP = 1 << 20;
+        /// `rdseed`.
+        const RDSEED = 1 << 21;
+        /// Supervisor mode access prevention.
+        const SMAP = 1 << 22;
+        /// 5-level paging.
+        const LA57 = 1 << 23;
+        /// `syscall` and `sysret`.
+        const SYSCALL = 1 << 24;
+        /// No-execute page protection.
+        const NX = 1 << 25;
+        /// 1GiB pages.
+        const PAGE_1GIB = 1 << 26;
+        /// `rdtscp`.
+        const RDTSCP = 1 << 27;
+        /// 64-bit long mode.
+        const LONG_MODE = 1 << 28;
+        /// The TSC runs at a constant rate in all power states.
+        const INVARIANT_TSC = 1 << 29;
+        /// `monitor` and `mwait`.
+        const MONITOR = 1 << 30;
+    }
+}
+
+/// Location of a feature bit in the output of cpuid
+#[derive(Clone, Copy)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx,
+}
+use Register::*;
+
+/// (leaf, register, bit, feature)
+const FEATURE_BITS: [(u32, Register, u32, Features); 31] = [
+    (FEATURES, Edx, 0, Features::FPU),
+    (FEATURES, Edx, 4, Features::TSC),
+    (FEATURES, Edx, 5, Features::MSR),
+    (FEATURES, Edx, 9, Features::APIC),
+    (FEATURES, Edx, 16, Features::PAT),
+    (FEATURES, Edx, 24, Features::FXSR),
+    (FEATURES, Edx, 25, Features::SSE),
+    (FEATURES, Edx, 26, Features::SSE2),
+    (FEATURES, Ecx, 0, Features::SSE3),
+    (FEATURES, Ecx, 3, Features::MONITOR),
+    (FEATURES, Ecx, 9, Features::SSSE3),
+    (FEATURES, Ecx, 19, Features::SSE4_1),
+    (FEATURES, Ecx, 20, Features::SSE4_2),
+    (FEATURES, Ecx, 17, Features::PCID),
+    (FEATURES, Ecx, 21, Features::X2APIC),
+    (FEATURES, Ecx, 24, Features::TSC_DEADLINE),
+    (FEATURES, Ecx, 26, Features::XSAVE),
+    (FEATURES, Ecx, 27, Features::OSXSAVE),
+    (FEATURES, Ecx, 28, Features::AVX),
+    (FEATURES, Ecx, 30, Features::RDRAND),
+    (EXTENDED_FEATURES, Ebx, 5, Features::AVX2),
+    (EXTENDED_FEATURES, Ebx, 7, Features::SMEP),
+    (EXTENDED_FEATURES, Ebx, 18, Features::RDSEED),
+    (EXTENDED_FEATURES, Ebx, 20, Features::SMAP),
+    (EXTENDED_FEATURES, Ecx, 16, Features::LA57),
+    (EXTENDED_INFO, Edx, 11, Features::SYSCALL),
+    (EXTENDED_INFO, Edx, 20, Features::NX),
+    (EXTENDED_INFO, Edx, 26, Features::PAGE_1GIB),
+    (EXTENDED_INFO, Edx, 27, Features::RDTSCP),
+    (EXTENDED_INFO, Edx, 29, Features::LONG_MODE),
+    (POWER_MANAGEMENT, Edx, 8, Features::INVARIANT_TSC),
+];
+
+impl Features {
+    /// Queries the features of the current CPU
+    pub fn detect() -> Self {
+        let max_leaf = cpuid(VENDOR, 0).eax;
+        let max_extended_leaf = cpuid(EXTENDED_FUNCTIONS, 0).eax;
+
+        let mut features = Features::empty();
+        for (leaf, register, bit, feature) in FEATURE_BITS {
+            let supported = if leaf >= EXTENDED_FUNCTIONS {
+                leaf <= max_extended_leaf
+            } else {
+                leaf <= max_leaf
+            };
+            if !supported {
+                continue;
+            }
+
+            let result = cpuid(leaf, 0);
+            let value = match register {
+                Ebx => result.ebx,
+                Ecx => result.ecx,
+                Edx => result.edx,
+            };
+            if value & (1 << bit) != 0 {
+                features |= feature;
+            }
+        }
+        features
+    }
+}
+
+/// Whether the current CPU supports `feature`
+pub fn has(feature: Features) -> bool {
+    Features::detect().contains(feature)
+}
+
+/// Architectural performance monitoring of the current CPU. Only Intel CPUs
+/// report it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerformanceMonitoring {
+    pub version: u8,
+    /// General purpose counters per logical processor
+    pub counters: u8,
+    /// Bits of a general purpose counter
+    pub counter_width: u8,
+    /// Number of architectural events reported in `unavailable_events`
+    events: u8,
+    /// Set bits mark architectural events that can't be counted
+    unavailable_events: u32,
+}
+
+impl PerformanceMonitoring {
+    /// None if the CPU has no architectural performance monitoring
+    pub fn read() -> Option<Self> {
+        if cpuid(VENDOR, 0).eax < PERFORMANCE_MONITORING {
+            return None;
+        }
+
+        let result = cpuid(PERFORMANCE_MONITORING, 0);
+        let version = result.eax as u8;
+        if version == 0 {
+            return None;
+        }
+        Some(Self {
+            version,
+            counters: (result.eax >> 8) as u8,
+            counter_width: (result.eax >> 16) as u8,
+            events: (result.eax >> 24) as u8,
+            unavailable_events: result.ebx,
+        })
+    }
+
+    /// Whether the general purpose counters can count unhalted core cycles
+    pub fn has_unhalted_core_cycles(&self) -> bool {
+        self.counters > 0 && self.events > 0 && self.unavailable_events & 1 == 0
+    }
+}
+
+/// `monitor` / `mwait` parameters of the current CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MonitorMwait {
+    /// Smallest and largest monitored range in bytes
+    pub smallest_line: u16,
+    pub largest_line: u16,
+    /// Interrupts end `mwait` even if they are disabled, see
+    /// `instructions::mwait`
+    pub interrupt_break: bool,
+    /// Sub C-states of C0 to C7 `mwait` can enter, 0 if the C-state isn't
+    /// supported
+    pub substates: [u8; 8],
+}
+
+impl MonitorMwait {
+    /// None if the CPU doesn't support `monitor` and `mwait`
+    pub fn read() -> Option<Self> {
+        if cpuid(VENDOR, 0).eax < MONITOR_MWAIT || !has(Features::MONITOR) {
+            return None;
+        }
+
+        let result = cpuid(MONITOR_MWAIT, 0);
+        // the extensions are only valid if bit 0 says so
+        let extensions = result.ecx & 1 != 0;
+        let mut substates = [0; 8];
+        for (i, count) in substates.iter_mut().enumerate() {
+            *count = ((result.edx >> (i * 4)) & 0xf) as u8;
+        }
+        Some(Self {
+            smallest_line: result.eax as u16,
+            largest_line: result.ebx as u16,
+            interrupt_break: extensions && result.ecx & 2 != 0,
+            substates,
+        })
+    }
+}
+
+/// Identification of the current CPU
+#[derive(Debug, Clone, Copy)]
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: Features,
+}
+
+impl CpuInfo {
+    pub fn read() -> Self {
+        let result = cpuid(VENDOR, 0);
+        let mut vendor = [0; 12];
+        vendor[..4].copy_from_slice(&result.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&result.edx.to_le_bytes());
+        vendor[8..].copy_from_slice(&result.ecx.to_le_bytes());
+
+        let mut brand = [0; 48];
+        if cpuid(EXTENDED_FUNCTIONS, 0).eax >= BRAND_STRING + 2 {
+            for (i, chunk) in brand.chunks_exact_mut(16).enumerate() {
+                let result = cpuid(BRAND_STRING + i as u32, 0);
+                for (j, register) in [result.eax, result.ebx, result.ecx, result.edx]
+                    .iter()
+                    .enumerate()
+                {
+                    chunk[j * 4..j * 4 + 4].copy_from_slice(&register.to_le_bytes());
+                }
+            }
+        }
+
+        // the extended family and model only apply to some base families
+        let version = cpuid(FEATURES, 0).eax;
+        let base_family = (version >> 8) & 0xf;
+        let base_model = (version >> 4) & 0xf;
+        let family = match base_family {
+            0xf => base_family + ((version >> 20) & 0xff),
+            _ => base_family,
+        };
+        let model = match base_family {
+            0x6 | 0xf => base_model + (((version >> 16) & 0xf) << 4),
+            _ => base_model,
+        };
+
+        Self {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping: version & 0xf,
+            features: Features::detect(),
+        }
+    }
+
+    /// Vendor identification, e.g. "GenuineIntel" or "AuthenticAMD"
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    /// Processor name, empty if the CPU doesn't report one
+    pub fn brand(&self) -> &str {
+        let len = self
+            .brand
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.brand.len());
+        str::from_utf8(&self.brand[..len]).unwrap_or("").trim()
+    }
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} family {:#x} model {:#x} stepping {}",
+            self.vendor(),
+            self.family,
+            self.model,
+            self.stepping
+        )?;
+        if !self.brand().is_empty() {
+            write!(f, " ({})", self.brand())?;
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,8 @@
 //! Global Descriptor Table definitions
-use crate::{memory::VirtualAddress, tss::TaskStateSegment, PrivilegeLevel};
+use crate::{memory::VirtualAddress, println, tss::TaskStateSegment, PrivilegeLevel};
 use bit_field::BitField;
 use bitflags::bitflags;
-use core::{arch::asm, convert::From, mem::size_of, ptr};
+use core::{arch::asm, convert::From, fmt, mem::size_of, ptr, slice};
 
 #[derive(Debug, Clone, Copy)]
 pub struct SegmentSelector(u16);
@@ -15,6 +15,16 @@ impl SegmentSelector {
     pub fn raw(&self) -> u16 {
         self.0
     }
+
+    /// Index of the descriptor in the GDT
+    pub fn index(&self) -> u16 {
+        self.0 >> 3
+    }
+
+    /// Requested privilege level
+    pub fn rpl(&self) -> PrivilegeLevel {
+        PrivilegeLevel::from((self.0 & 0b11) as u8)
+    }
 }
 
 impl From<u16> for SegmentSelector {
@@ -34,6 +44,17 @@ pub enum SystemSegmentType {
     TssBusy = 0xB,
 }
 
+impl SystemSegmentType {
+    fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0x2 => Some(Self::LDT),
+            0x9 => Some(Self::TssAvailable),
+            0xB => Some(Self::TssBusy),
+            _ => None,
+        }
+    }
+}
+
 bitflags! {
     /// Combines the access byte and flags of a segment descriptor
     pub struct SegmentDescriptorFlags: u64 {
@@ -189,6 +210,221 @@ impl SegmentDescriptor {
 
         PrivilegeLevel::from(dpl as u8)
     }
+
+    pub fn decode(&self) -> DecodedSegment {
+        match *self {
+            SegmentDescriptor::UserSegment(low) => DecodedSegment::new(low, None),
+            SegmentDescriptor::SystemSegment(low, high) => DecodedSegment::new(low, Some(high)),
+        }
+    }
+
+    /// Checks the descriptor for combinations the CPU rejects when the
+    /// segment is loaded, which would only show up as a general protection
+    /// fault much later
+    pub fn validate(&self) -> Result<(), DescriptorError> {
+        let segment = self.decode();
+        if !segment.present {
+            return Err(DescriptorError::NotPresent);
+        }
+        let flags = SegmentDescriptorFlags::from_bits_truncate(segment.low);
+        match (self, segment.kind) {
+            (SegmentDescriptor::UserSegment(_), SegmentKind::System(_) | SegmentKind::Invalid) => {
+                Err(DescriptorError::WrongDescriptorType)
+            }
+            (SegmentDescriptor::SystemSegment(..), SegmentKind::Invalid) => Err(
+                DescriptorError::InvalidSystemType(segment.low.get_bits(40..=43)),
+            ),
+            (
+                SegmentDescriptor::SystemSegment(..),
+                SegmentKind::Code { .. } | SegmentKind::Data,
+            ) => Err(DescriptorError::WrongDescriptorType),
+            (SegmentDescriptor::SystemSegment(..), _) if !is_canonical(segment.base) => {
+                Err(DescriptorError::NonCanonicalBase(segment.base))
+            }
+            _ if flags.contains(
+                SegmentDescriptorFlags::LONG_MODE | SegmentDescriptorFlags::PROTECTED_MODE,
+            ) =>
+            {
+                Err(DescriptorError::LongAndProtectedMode)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Why a descriptor is not well-formed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorError {
+    NotPresent,
+    /// A code or data segment in a system segment slot or the other way round
+    WrongDescriptorType,
+    InvalidSystemType(u64),
+    NonCanonicalBase(u64),
+    /// The L and D bits are both set, which is reserved
+    LongAndProtectedMode,
+    /// Gate type other than a 64-bit interrupt or trap gate
+    InvalidGateType(u16),
+    /// The selector of a gate doesn't refer to a 64-bit code segment
+    InvalidSelector(u16),
+}
+
+/// What a GDT entry describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    Null,
+    Code {
+        long: bool,
+        conforming: bool,
+    },
+    Data,
+    System(SystemSegmentType),
+    /// A system segment type that doesn't exist in long mode
+    Invalid,
+}
+
+/// Fields of a GDT entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedSegment {
+    pub kind: SegmentKind,
+    pub dpl: PrivilegeLevel,
+    pub present: bool,
+    pub base: u64,
+    /// Last valid offset, already scaled by the granularity
+    pub limit: u64,
+    low: u64,
+}
+
+impl DecodedSegment {
+    /// `high` is the upper half of a system segment descriptor
+    fn new(low: u64, high: Option<u64>) -> Self {
+        let flags = SegmentDescriptorFlags::from_bits_truncate(low);
+        let kind = if low == 0 {
+            SegmentKind::Null
+        } else if !flags.contains(SegmentDescriptorFlags::USER_SEGMENT) {
+            SystemSegmentType::from_bits(low.get_bits(40..=43))
+                .map_or(SegmentKind::Invalid, SegmentKind::System)
+        } else if flags.contains(SegmentDescriptorFlags::EXECUTABLE) {
+            SegmentKind::Code {
+                long: flags.contains(SegmentDescriptorFlags::LONG_MODE),
+                conforming: flags.contains(SegmentDescriptorFlags::CONFORMING),
+            }
+        } else {
+            SegmentKind::Data
+        };
+
+        let mut base = low.get_bits(16..=39) | low.get_bits(56..=63) << 24;
+        if let Some(high) = high {
+            base |= high.get_bits(0..=31) << 32;
+        }
+        let mut limit = low.get_bits(0..=15) | low.get_bits(48..=51) << 16;
+        if flags.contains(SegmentDescriptorFlags::GRANULARITY) {
+            limit = limit << 12 | 0xfff;
+        }
+
+        Self {
+            kind,
+            dpl: PrivilegeLevel::from(low.get_bits(45..=46) as u8),
+            present: flags.contains(SegmentDescriptorFlags::PRESENT),
+            base,
+            limit,
+            low,
+        }
+    }
+}
+
+impl fmt::Display for DecodedSegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            SegmentKind::Null => return write!(f, "null"),
+            SegmentKind::Code { long: true, .. } => "code64",
+            SegmentKind::Code { long: false, .. } => "code32",
+            SegmentKind::Data => "data",
+            SegmentKind::System(SystemSegmentType::LDT) => "ldt",
+            SegmentKind::System(SystemSegmentType::TssAvailable) => "tss",
+            SegmentKind::System(SystemSegmentType::TssBusy) => "tss (busy)",
+            SegmentKind::Invalid => "invalid",
+        };
+        write!(
+            f,
+            "{:<10} dpl {} {} base {:#x} limit {:#x}",
+            kind,
+            self.dpl as u8,
+            if self.present { "present" } else { "missing" },
+            self.base,
+            self.limit
+        )
+    }
+}
+
+/// Decodes the raw entries of a GDT, system segments span two entries.
+/// Yields the selector index of each descriptor.
+pub fn decode(entries: &[u64]) -> impl Iterator<Item = (usize, DecodedSegment)> + '_ {
+    let mut index = 0;
+    core::iter::from_fn(move || {
+        let low = *entries.get(index)?;
+        let system = low != 0 && !low.get_bit(44);
+        let segment = DecodedSegment::new(
+            low,
+            system.then(|| entries.get(index + 1)).flatten().copied(),
+        );
+        let current = index;
+        index += if system { 2 } else { 1 };
+        Some((current, segment))
+    })
+}
+
+/// Value of the GDTR or IDTR
+#[derive(Debug, Clone, Copy)]
+pub struct DescriptorTablePointer {
+    /// Size of the table in bytes - 1
+    pub limit: u16,
+    pub base: u64,
+}
+
+impl DescriptorTablePointer {
+    /// Amount of `T` sized entries in the table
+    pub fn entries<T>(&self) -> usize {
+        (self.limit as usize + 1) / size_of::<T>()
+    }
+}
+
+/// Reads the GDT register
+pub fn sgdt() -> DescriptorTablePointer {
+    let mut pointer = GlobalDescriptorTableDescriptor {
+        size: 0,
+        base: ptr::null(),
+    };
+    unsafe {
+        asm!("sgdt [{}]", in(reg) &mut pointer, options(nostack, preserves_flags));
+    }
+    DescriptorTablePointer {
+        limit: pointer.size,
+        base: pointer.base as u64,
+    }
+}
+
+/// Raw entries of the loaded GDT
+///
+/// # Safety
+///
+/// The loaded GDT must be mapped and stay alive
+pub unsafe fn current_entries() -> &'static [u64] {
+    let pointer = sgdt();
+    unsafe { slice::from_raw_parts(pointer.base as *const u64, pointer.entries::<u64>()) }
+}
+
+/// Prints all entries of the loaded GDT
+pub fn dump() {
+    let pointer = sgdt();
+    println!("GDT at {:#x}, limit {:#x}", pointer.base, pointer.limit);
+    for (index, segment) in decode(unsafe { current_entries() }) {
+        println!("{:#06x} {}", index << 3, segment);
+    }
+}
+
+/// Canonical with 4-level paging, which makes it canonical with 5 levels too
+fn is_canonical(address: u64) -> bool {
+    ((address << 16) as i64 >> 16) as u64 == address
 }
 
 const GLOBAL_DESCRIPTOR_TABLE_ENTRY_COUNT: usize = 0x8;
@@ -219,6 +455,9 @@ impl GlobalDescriptorTable {
     }
 
     pub fn add_entry(&mut self, entry: SegmentDescriptor) -> SegmentSelector {
+        // not checked in release builds, the early boot stages are size
+        // constrained
+        debug_assert_eq!(entry.validate(), Ok(()), "Invalid GDT entry");
         let idx = match entry {
             SegmentDescriptor::UserSegment(val) => self.push(val),
             SegmentDescriptor::SystemSegment(low, high) => {
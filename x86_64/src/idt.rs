@@ -10,9 +10,19 @@
 //! Interrupt vs trap gate: when you call an interrupt-gate, interrupts get disabled,
 //! and when you call a trap-gate, they don't
 //!
-use crate::{const_assert, gdt::SegmentSelector, println, register::CS, PrivilegeLevel};
+use crate::{
+    const_assert,
+    gdt::{self, DescriptorError, DescriptorTablePointer, SegmentKind, SegmentSelector},
+    println,
+    register::CS,
+    PrivilegeLevel,
+};
 use bit_field::BitField;
-use core::{arch::asm, default::Default, mem::size_of};
+use core::{arch::asm, default::Default, fmt, mem::size_of, slice};
+
+/// Gate types in long mode
+const INTERRUPT_GATE: u16 = 0xe;
+const TRAP_GATE: u16 = 0xf;
 
 #[derive(Debug, Clone, Copy)]
 pub struct InterruptDescriptorOptions(u16);
@@ -47,6 +57,7 @@ impl InterruptDescriptorOptions {
     pub fn set_interrupt_stack_index(&mut self, index: u16) -> &mut Self {
         // The hardware IST index starts at 1, but our software IST index
         // starts at 0. Therefore we need to add 1 here.
+        assert!(index < 7, "Invalid interrupt stack index {}", index);
         self.0.set_bits(0..=2, index + 1);
         self
     }
@@ -54,6 +65,35 @@ impl InterruptDescriptorOptions {
 
 pub type HandlerFunc = extern "C" fn() -> !;
 
+/// Vectors of the exceptions
+pub mod vectors {
+    pub const DIVIDE_ERROR: u8 = 0;
+    pub const DEBUG: u8 = 1;
+    pub const NON_MASKABLE_INTERRUPT: u8 = 2;
+    pub const BREAKPOINT: u8 = 3;
+    pub const OVERFLOW: u8 = 4;
+    pub const BOUND_RANGE_EXCEEDED: u8 = 5;
+    pub const INVALID_OPCODE: u8 = 6;
+    pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+    pub const DOUBLE_FAULT: u8 = 8;
+    pub const INVALID_TSS: u8 = 10;
+    pub const SEGMENT_NOT_PRESENT: u8 = 11;
+    pub const STACK_SEGMENT_FAULT: u8 = 12;
+    pub const GENERAL_PROTECTION_FAULT: u8 = 13;
+    pub const PAGE_FAULT: u8 = 14;
+    pub const X87_FLOATING_POINT: u8 = 16;
+    pub const ALIGNMENT_CHECK: u8 = 17;
+    pub const MACHINE_CHECK: u8 = 18;
+    pub const SIMD_FLOATING_POINT: u8 = 19;
+    pub const VIRTUALIZATION: u8 = 20;
+    pub const CONTROL_PROTECTION_EXCEPTION: u8 = 21;
+    pub const HYPERVISOR_INJECTION_EXCEPTION: u8 = 28;
+    pub const VMM_COMMUNICATION_EXCEPTION: u8 = 29;
+    pub const SECURITY_EXCEPTION: u8 = 30;
+    /// First vector not reserved for exceptions
+    pub const FIRST_INTERRUPT: u8 = 32;
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct InterruptDescriptor {
@@ -97,6 +137,80 @@ impl InterruptDescriptor {
 
         &mut self.options
     }
+
+    pub fn is_present(&self) -> bool {
+        self.options.0.get_bit(15)
+    }
+
+    pub fn handler_address(&self) -> u64 {
+        self.pointer_low as u64
+            | (self.pointer_middle as u64) << 16
+            | (self.pointer_high as u64) << 32
+    }
+
+    pub fn selector(&self) -> SegmentSelector {
+        self.segment_selector
+    }
+
+    /// Whether interrupts stay enabled while the handler runs
+    pub fn is_trap_gate(&self) -> bool {
+        self.options.0.get_bits(8..=11) == TRAP_GATE
+    }
+
+    pub fn privilege_level(&self) -> PrivilegeLevel {
+        PrivilegeLevel::from(self.options.0.get_bits(13..=14) as u8)
+    }
+
+    /// Interrupt stack table index the CPU switches to, counting from 0 like
+    /// [`InterruptDescriptorOptions::set_interrupt_stack_index`]
+    pub fn interrupt_stack_index(&self) -> Option<u16> {
+        self.options.0.get_bits(0..=2).checked_sub(1)
+    }
+
+    /// Checks the gate type and that the selector refers to a 64-bit code
+    /// segment in `gdt`
+    pub fn validate(&self, gdt: &[u64]) -> Result<(), DescriptorError> {
+        if !self.is_present() {
+            return Err(DescriptorError::NotPresent);
+        }
+        let gate = self.options.0.get_bits(8..=11);
+        if gate != INTERRUPT_GATE && gate != TRAP_GATE {
+            return Err(DescriptorError::InvalidGateType(gate));
+        }
+
+        let selector = self.segment_selector;
+        let segment = gdt::decode(gdt).find(|(index, _)| *index == selector.index() as usize);
+        match segment {
+            Some((_, segment))
+                if segment.present
+                    && matches!(segment.kind, SegmentKind::Code { long: true, .. }) =>
+            {
+                Ok(())
+            }
+            _ => Err(DescriptorError::InvalidSelector(selector.raw())),
+        }
+    }
+}
+
+impl fmt::Display for InterruptDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x} {} selector {:#x} dpl {}",
+            self.handler_address(),
+            if self.is_trap_gate() {
+                "trap"
+            } else {
+                "interrupt"
+            },
+            self.segment_selector.raw(),
+            self.privilege_level() as u8
+        )?;
+        if let Some(index) = self.interrupt_stack_index() {
+            write!(f, " ist {}", index)?;
+        }
+        Ok(())
+    }
 }
 
 /// IDT descriptor which will be loaded into the IDT register
@@ -147,6 +261,17 @@ const_assert!(
 );
 
 impl InterruptDescriptorTable {
+    /// Descriptor of interrupt `vector`
+    pub fn entry_mut(&mut self, vector: u8) -> &mut InterruptDescriptor {
+        // the table is laid out as an array of 256 descriptors
+        unsafe { &mut *(self as *mut Self as *mut InterruptDescriptor).add(vector as usize) }
+    }
+
+    /// All 256 descriptors, indexed by vector
+    pub fn entries(&self) -> &[InterruptDescriptor] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const InterruptDescriptor, 256) }
+    }
+
     // Static lifetime to make sure idt will live long enough and not e.g.
     // be initialized on the stack stack inside a function which causes
     // undefined behavior when the function returns
@@ -159,6 +284,13 @@ impl InterruptDescriptorTable {
         let val = desc.base;
         println!("Idt addr: {:x}", val);
 
+        let gdt = unsafe { gdt::current_entries() };
+        for (vector, entry) in self.entries().iter().enumerate() {
+            if entry.is_present() {
+                debug_assert_eq!(entry.validate(gdt), Ok(()), "Invalid IDT entry {}", vector);
+            }
+        }
+
         unsafe {
             lidt(&desc);
         };
@@ -208,3 +340,57 @@ impl Default for InterruptDescriptorTable {
 unsafe fn lidt(descriptor: &InterruptTableDescriptor) {
     asm!("lidt [{}]", in(reg) descriptor, options(readonly, nostack, preserves_flags));
 }
+
+/// Resets the machine by loading an empty IDT and raising an interrupt,
+/// which ends in a triple fault
+pub fn triple_fault() -> ! {
+    let empty = InterruptTableDescriptor { size: 0, base: 0 };
+    unsafe {
+        lidt(&empty);
+        asm!("int3", options(nomem, nostack));
+    }
+    unreachable!("Triple fault did not reset the machine");
+}
+
+/// Reads the IDT register
+pub fn sidt() -> DescriptorTablePointer {
+    let mut descriptor = InterruptTableDescriptor { size: 0, base: 0 };
+    unsafe {
+        asm!("sidt [{}]", in(reg) &mut descriptor, options(nostack, preserves_flags));
+    }
+    DescriptorTablePointer {
+        limit: descriptor.size,
+        base: descriptor.base,
+    }
+}
+
+/// Descriptors of the loaded IDT
+///
+/// # Safety
+///
+/// The loaded IDT must be mapped and stay alive
+pub unsafe fn current_entries() -> &'static [InterruptDescriptor] {
+    let pointer = sidt();
+    unsafe {
+        slice::from_raw_parts(
+            pointer.base as *const InterruptDescriptor,
+            pointer.entries::<InterruptDescriptor>(),
+        )
+    }
+}
+
+/// Prints the present entries of the loaded IDT and whether they are valid
+pub fn dump() {
+    let pointer = sidt();
+    println!("IDT at {:#x}, limit {:#x}", pointer.base, pointer.limit);
+    let gdt = unsafe { gdt::current_entries() };
+    for (vector, entry) in unsafe { current_entries() }.iter().enumerate() {
+        if !entry.is_present() {
+            continue;
+        }
+        match entry.validate(gdt) {
+            Ok(()) => println!("{:>3} {}", vector, entry),
+            Err(error) => println!("{:>3} {} ({:?})", vector, entry, error),
+        }
+    }
+}
@@ -16,6 +16,176 @@ pub fn flush_tlb(address: VirtualAddress) {
     }
 }
 
+/// Invalidates all TLB entries except the ones of global pages by reloading
+/// CR3
+pub fn flush_tlb_all() {
+    unsafe {
+        asm!(
+            "mov {0}, cr3",
+            "mov cr3, {0}",
+            out(reg) _,
+            options(nostack, preserves_flags)
+        )
+    }
+}
+
+/// Writes back and invalidates all caches
+pub fn wbinvd() {
+    unsafe { asm!("wbinvd", options(nostack, preserves_flags)) }
+}
+
 pub fn hlt() {
     unsafe { asm!("hlt", options(nostack, nomem, preserves_flags)) }
 }
+
+/// Arms address monitoring for the cache line containing `address`, see
+/// [`mwait`]
+pub fn monitor(address: VirtualAddress) {
+    unsafe {
+        asm!(
+            "monitor",
+            in("rax") address.as_u64(),
+            in("ecx") 0,
+            in("edx") 0,
+            options(nostack, preserves_flags)
+        )
+    }
+}
+
+/// Waits until the monitored cache line is written or an interrupt arrives.
+/// `hints` selects the C-state, bits 7:4 are the C-state minus 1 and bits
+/// 3:0 the sub C-state. With bit 0 of `extensions` set interrupts end the
+/// wait even if they are disabled.
+pub fn mwait(hints: u32, extensions: u32) {
+    unsafe { asm!("mwait", in("eax") hints, in("ecx") extensions, options(nostack)) }
+}
+
+/// Reads the time stamp counter
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags))
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+pub use core::arch::x86_64::CpuidResult;
+
+/// Executes cpuid for `leaf` and `subleaf`
+#[allow(unused_unsafe)]
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    unsafe { core::arch::x86_64::__cpuid_count(leaf, subleaf) }
+}
+
+/// Resets the x87 FPU to its initial state
+pub fn fninit() {
+    unsafe { asm!("fninit", options(nomem, nostack)) }
+}
+
+/// Clears the pending exceptions of the x87 FPU
+pub fn fnclex() {
+    unsafe { asm!("fnclex", options(nomem, nostack)) }
+}
+
+/// Reads the x87 FPU status word
+pub fn fnstsw() -> u16 {
+    let status: u16;
+    unsafe { asm!("fnstsw ax", out("ax") status, options(nomem, nostack, preserves_flags)) }
+    status
+}
+
+/// Reads the SSE control and status register
+///
+/// # Safety
+///
+/// SSE has to be enabled in CR0 and CR4
+pub unsafe fn stmxcsr() -> u32 {
+    let mut value = 0u32;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut value, options(nostack, preserves_flags)) }
+    value
+}
+
+/// Loads the SSE control and status register
+///
+/// # Safety
+///
+/// SSE has to be enabled in CR0 and CR4
+pub unsafe fn ldmxcsr(value: u32) {
+    unsafe { asm!("ldmxcsr [{}]", in(reg) &value, options(nostack, readonly)) }
+}
+
+/// Saves the x87, MMX and SSE state to the 512 byte area at `area`
+///
+/// # Safety
+///
+/// `area` has to be writable and 16 byte aligned
+pub unsafe fn fxsave(area: *mut u8) {
+    unsafe { asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags)) }
+}
+
+/// Restores the x87, MMX and SSE state from the 512 byte area at `area`
+///
+/// # Safety
+///
+/// `area` has to be 16 byte aligned and contain a valid state
+pub unsafe fn fxrstor(area: *const u8) {
+    unsafe { asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags, readonly)) }
+}
+
+/// Saves the state components in `mask` to the xsave area at `area`
+///
+/// # Safety
+///
+/// `area` has to be writable, 64 byte aligned and large enough for all
+/// components enabled in XCR0
+pub unsafe fn xsave(area: *mut u8, mask: u64) {
+    unsafe {
+        asm!(
+            "xsave64 [{}]",
+            in(reg) area,
+            in("eax") mask as u32,
+            in("edx") (mask >> 32) as u32,
+            options(nostack, preserves_flags)
+        )
+    }
+}
+
+/// Restores the state components in `mask` from the xsave area at `area`
+///
+/// # Safety
+///
+/// `area` has to be 64 byte aligned and contain a valid state
+pub unsafe fn xrstor(area: *const u8, mask: u64) {
+    unsafe {
+        asm!(
+            "xrstor64 [{}]",
+            in(reg) area,
+            in("eax") mask as u32,
+            in("edx") (mask >> 32) as u32,
+            options(nostack, preserves_flags, readonly)
+        )
+    }
+}
+
+/// Reads a random number from the hardware generator, None if it is
+/// temporarily exhausted. The CPU has to support `rdrand`.
+pub fn rdrand() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    unsafe {
+        asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack))
+    };
+    (ok != 0).then_some(value)
+}
+
+/// Reads a random seed from the hardware entropy source, None if it is
+/// temporarily exhausted. The CPU has to support `rdseed`.
+pub fn rdseed() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    unsafe {
+        asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack))
+    };
+    (ok != 0).then_some(value)
+}
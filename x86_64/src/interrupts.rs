@@ -1,3 +1,4 @@
+use crate::{memory::VirtualAddress, PrivilegeLevel};
 use bitflags::bitflags;
 use core::{arch::asm, fmt};
 
@@ -22,6 +23,17 @@ pub unsafe fn enable() {
     unsafe { asm!("sti", options(nostack, preserves_flags)) }
 }
 
+/// Enables CPU interrupts and halts until the next one. `sti` only takes
+/// effect after the following instruction, so an interrupt arriving in between
+/// still wakes up the `hlt`.
+///
+/// # Safety
+///
+/// Same as [`enable`].
+pub unsafe fn enable_and_hlt() {
+    unsafe { asm!("sti; hlt", options(nomem, nostack)) }
+}
+
 // todo: https://os.phil-opp.com/catching-exceptions/
 // cur: https://os.phil-opp.com/double-fault-exceptions/
 // exception numbers: https://wiki.osdev.org/Exceptions
@@ -66,18 +78,19 @@ macro_rules! pop_scratch_registers {
 #[macro_export]
 macro_rules! handler_with_error_code {
     ($name: ident) => {{
+        use core::arch::asm;
         #[naked]
         extern "C" fn wrapper() -> ! {
             unsafe {
                 asm!(
-                    push_scratch_registers!(),
+                    $crate::push_scratch_registers!(),
                     "mov rsi, [rsp + 9*8]", // pop error code (cant use pop before saving scratch registers since this would corrupt rsi)
                     "mov rdi, rsp",
                     "add rdi, 10*8", // jump over saved scratch registers and error code
                     "sub rsp, 8",
                     "call {}",
                     "add rsp, 8",
-                    pop_scratch_registers!(),
+                    $crate::pop_scratch_registers!(),
                     "add rsp, 8", // pop error code
                     "iretq",
                     sym $name,
@@ -93,15 +106,16 @@ macro_rules! handler_with_error_code {
 #[macro_export]
 macro_rules! handler_without_error_code {
     ($name: ident) => {{
+        use core::arch::asm;
         #[naked]
         extern "C" fn wrapper() -> ! {
             unsafe {
                 asm!(
-                    push_scratch_registers!(),
+                    $crate::push_scratch_registers!(),
                     "mov rdi, rsp",
                     "add rdi, 9*8",
                     "call {}",
-                    pop_scratch_registers!(),
+                    $crate::pop_scratch_registers!(),
                     "iretq",
                     sym $name,
                     options(noreturn)
@@ -123,6 +137,62 @@ bitflags! {
     }
 }
 
+/// Descriptor table a selector error code refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// Error code of the exceptions caused by loading a selector or by a gate,
+/// e.g. general protection faults and invalid TSS exceptions
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(u64);
+
+impl SelectorErrorCode {
+    pub fn new(error_code: u64) -> Self {
+        Self(error_code)
+    }
+
+    /// Whether the exception was caused by an event external to the program,
+    /// e.g. a hardware interrupt
+    pub fn is_external(&self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(&self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// Index into [`table`](Self::table), the vector for the IDT
+    pub fn index(&self) -> u16 {
+        ((self.0 >> 3) & 0x1fff) as u16
+    }
+
+    /// Faults not related to a selector push 0
+    pub fn is_null(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl fmt::Debug for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_null() {
+            return write!(f, "SelectorErrorCode(0)");
+        }
+        f.debug_struct("SelectorErrorCode")
+            .field("table", &self.table())
+            .field("index", &self.index())
+            .field("external", &self.is_external())
+            .finish()
+    }
+}
+
 // naked functions have no function prologue
 /// Information the CPU pushes onto the stack before jumping to the exception
 /// handler function
@@ -137,6 +207,28 @@ pub struct ExceptionStackFrame {
     stack_segment: u64,
 }
 
+impl ExceptionStackFrame {
+    /// Address of the faulting instruction, or of the next one for traps
+    pub fn instruction_pointer(&self) -> VirtualAddress {
+        VirtualAddress::new(self.instruction_pointer)
+    }
+
+    pub fn stack_pointer(&self) -> VirtualAddress {
+        VirtualAddress::new(self.stack_pointer)
+    }
+
+    /// Privilege level of the interrupted code
+    pub fn privilege_level(&self) -> PrivilegeLevel {
+        PrivilegeLevel::from((self.code_segment & 0b11) as u8)
+    }
+
+    /// Whether the interrupted code ran with interrupts enabled
+    pub fn interrupts_enabled(&self) -> bool {
+        const INTERRUPT_FLAG: u64 = 1 << 9;
+        self.cpu_flags & INTERRUPT_FLAG != 0
+    }
+}
+
 impl fmt::Debug for ExceptionStackFrame {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "ExceptionFrame {{")?;
@@ -149,13 +241,31 @@ impl fmt::Debug for ExceptionStackFrame {
     }
 }
 
+/// Returns whether interrupts are enabled by checking the IF flag in RFLAGS
+pub fn are_enabled() -> bool {
+    const INTERRUPT_FLAG: u64 = 1 << 9;
+    let rflags: u64;
+    unsafe { asm!("pushfq; pop {}", out(reg) rflags, options(nomem, preserves_flags)) };
+    rflags & INTERRUPT_FLAG != 0
+}
+
+/// Runs `c` with interrupts disabled. Interrupts are only re-enabled
+/// afterwards if they were enabled before, so calls can be nested and are
+/// safe to use from interrupt handlers.
 pub fn without_interrupts<F, R>(c: F) -> R
 where
     F: FnOnce() -> R,
 {
-    unsafe { disable() };
+    let enabled = are_enabled();
+    if enabled {
+        unsafe { disable() };
+    }
+
     let ret = c();
-    unsafe { enable() };
+
+    if enabled {
+        unsafe { enable() };
+    }
 
     ret
 }
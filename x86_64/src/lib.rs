@@ -1,6 +1,7 @@
 #![no_std]
 #![feature(hint_must_use)]
 #![feature(naked_functions)]
+pub mod cpuid;
 pub mod gdt;
 pub mod idt;
 pub mod instructions;
@@ -17,6 +18,7 @@ pub mod uart;
 use core::convert::From;
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 /// CPU privilege levels, or also "rings"
 pub enum PrivilegeLevel {
     /// Supervisor mode. Least protection, most access to resources
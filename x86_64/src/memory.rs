@@ -20,6 +20,36 @@ pub const TIB: u64 = GIB * 1024;
 pub unsafe trait FrameAllocator<S: PageSize> {
     /// Allocate a frame of the appropriate size and return it if possible.
     fn allocate_frame(&mut self) -> Option<PhysicalFrame<S>>;
+
+    /// Allocates `count` physically contiguous frames, the first one starting
+    /// at a multiple of `alignment` bytes. Returns the first frame.
+    fn allocate_contiguous(&mut self, count: usize, alignment: u64) -> Option<PhysicalFrame<S>> {
+        let _ = (count, alignment);
+        None
+    }
+}
+
+/// A trait for frame allocators that can take back frames.
+pub trait FrameDeallocator<S: PageSize> {
+    /// Returns `frame` to the allocator.
+    ///
+    /// # Safety
+    ///
+    /// The frame must have been allocated by this allocator and must not be
+    /// in use anymore
+    unsafe fn deallocate_frame(&mut self, frame: PhysicalFrame<S>);
+
+    /// Returns `count` contiguous frames starting at `frame`, e.g. from
+    /// [`FrameAllocator::allocate_contiguous`].
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`deallocate_frame`](Self::deallocate_frame)
+    unsafe fn deallocate_contiguous(&mut self, frame: PhysicalFrame<S>, count: usize) {
+        for i in 0..count {
+            self.deallocate_frame(frame + i as u64);
+        }
+    }
 }
 
 pub trait MemoryRegion: Copy + core::fmt::Debug {
@@ -86,6 +116,27 @@ pub enum PhysicalMemoryRegionType {
 
     /// Used by Bootloader / Kernel
     Used,
+    /// Allocated by the bootloader and still in use by the kernel, e.g. page
+    /// tables or the boot info
+    Bootloader,
+    /// Used by the bootloader only to boot, e.g. the earlier stages. Free once
+    /// the kernel has taken over.
+    BootloaderReclaimable,
+    /// ACPI tables, free once the kernel has read them
+    AcpiReclaimable,
+}
+
+impl memory_map::Kind for PhysicalMemoryRegionType {
+    fn precedence(&self) -> u8 {
+        match self {
+            PhysicalMemoryRegionType::Free => 0,
+            PhysicalMemoryRegionType::BootloaderReclaimable => 1,
+            PhysicalMemoryRegionType::AcpiReclaimable => 2,
+            PhysicalMemoryRegionType::Bootloader => 3,
+            PhysicalMemoryRegionType::Used => 4,
+            PhysicalMemoryRegionType::Reserved => 5,
+        }
+    }
 }
 
 // ensure 8 byte alignment so it works between the different cpu modes where we have
@@ -109,6 +160,26 @@ impl PhysicalMemoryRegion {
     }
 }
 
+impl memory_map::Region for PhysicalMemoryRegion {
+    type Kind = PhysicalMemoryRegionType;
+
+    fn with_range(start: u64, end: u64, typ: PhysicalMemoryRegionType) -> Self {
+        Self::new(start, end - start, typ)
+    }
+
+    fn region_start(&self) -> u64 {
+        self.start
+    }
+
+    fn region_end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    fn kind(&self) -> PhysicalMemoryRegionType {
+        self.typ
+    }
+}
+
 impl MemoryRegion for PhysicalMemoryRegion {
     fn start(&self) -> u64 {
         self.start
@@ -157,11 +228,19 @@ impl PageSize for Size2MiB {
     const SIZE: u64 = 0x200000;
 }
 
+/// Requires CPU support, see [`Features::PAGE_1GIB`](crate::cpuid::Features::PAGE_1GIB)
+#[derive(Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Debug)]
+pub enum Size1GiB {}
+
+impl PageSize for Size1GiB {
+    const SIZE: u64 = 0x4000_0000;
+}
+
 pub trait Address {
     fn as_u64(&self) -> u64;
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Ord, PartialOrd)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Ord, PartialOrd, Hash)]
 pub struct PhysicalAddress(u64);
 
 impl PhysicalAddress {
@@ -256,7 +335,7 @@ impl LowerHex for PhysicalAddress {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
 pub struct VirtualAddress(u64);
 
 impl VirtualAddress {
@@ -301,6 +380,11 @@ impl VirtualAddress {
         self.as_u64() as *const T
     }
 
+    /// Only used with 5-level paging
+    pub fn l5_index(&self) -> usize {
+        self.0.get_bits(48..=56) as usize
+    }
+
     pub fn l4_index(&self) -> usize {
         self.0.get_bits(39..=47) as usize
     }
@@ -482,7 +566,7 @@ impl<S: PageSize> AddAssign<u64> for PhysicalFrame<S> {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct Page<S: PageSize = Size4KiB> {
     pub address: VirtualAddress,
     pub size: PhantomData<S>,
@@ -1,6 +1,7 @@
 // todo: this is not x86_64 specific code. should be moved to somewhere else
 
 // implementation based on: https://whenderson.dev/blog/rust-mutexes/
+use crate::interrupts;
 use core::{
     cell::UnsafeCell,
     ops::{Deref, DerefMut},
@@ -10,6 +11,8 @@ use core::{
 pub struct Mutex<T> {
     pub inner: UnsafeCell<T>,
     pub lock_status: AtomicBool,
+    #[cfg(feature = "mutex-debug")]
+    owner: debug::Owner,
 }
 
 impl<T> Mutex<T> {
@@ -17,10 +20,16 @@ impl<T> Mutex<T> {
         Self {
             inner: UnsafeCell::new(val),
             lock_status: AtomicBool::new(false),
+            #[cfg(feature = "mutex-debug")]
+            owner: debug::Owner::new(),
         }
     }
 
+    #[cfg_attr(feature = "mutex-debug", track_caller)]
     pub fn lock(&self) -> MutexGuard<T> {
+        #[cfg(feature = "mutex-debug")]
+        self.owner.check_reentrant();
+
         loop {
             core::hint::spin_loop();
             match self.lock_status.compare_exchange(
@@ -34,8 +43,25 @@ impl<T> Mutex<T> {
             }
         }
 
+        #[cfg(feature = "mutex-debug")]
+        self.owner.acquired();
         MutexGuard::new(self)
     }
+
+    /// Tries to acquire the lock once without spinning.
+    ///
+    /// Useful in contexts such as exception handlers where spinning on a lock
+    /// held by the interrupted code would deadlock
+    #[cfg_attr(feature = "mutex-debug", track_caller)]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.lock_status
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+
+        #[cfg(feature = "mutex-debug")]
+        self.owner.acquired();
+        Some(MutexGuard::new(self))
+    }
 }
 
 unsafe impl<T: Send> Send for Mutex<T> {}
@@ -49,6 +75,12 @@ impl<'a, T> MutexGuard<'a, T> {
     pub fn new(mutex: &'a Mutex<T>) -> Self {
         Self { mutex }
     }
+
+    /// Returns the mutex the guard belongs to. Needed to reacquire the lock
+    /// after the guard was dropped, e.g. when waiting on a condition variable.
+    pub fn mutex(guard: &Self) -> &'a Mutex<T> {
+        guard.mutex
+    }
 }
 
 impl<T> Deref for MutexGuard<'_, T> {
@@ -67,6 +99,144 @@ impl<T> DerefMut for MutexGuard<'_, T> {
 
 impl<T> Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
+        #[cfg(feature = "mutex-debug")]
+        self.mutex.owner.released();
         self.mutex.lock_status.store(false, Ordering::Release);
     }
 }
+
+/// A mutex that keeps interrupts disabled while it is held, so it can be
+/// shared with interrupt handlers without deadlocking when a handler
+/// interrupts the holder.
+pub struct InterruptSafeMutex<T> {
+    mutex: Mutex<T>,
+}
+
+impl<T> InterruptSafeMutex<T> {
+    pub const fn new(val: T) -> Self {
+        Self {
+            mutex: Mutex::new(val),
+        }
+    }
+
+    /// Disables interrupts and acquires the lock. Interrupts are restored to
+    /// their previous state when the guard is dropped.
+    #[cfg_attr(feature = "mutex-debug", track_caller)]
+    pub fn lock(&self) -> InterruptSafeMutexGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        if enabled {
+            unsafe { interrupts::disable() };
+        }
+
+        InterruptSafeMutexGuard {
+            guard: Some(self.mutex.lock()),
+            enabled,
+        }
+    }
+}
+
+pub struct InterruptSafeMutexGuard<'a, T> {
+    guard: Option<MutexGuard<'a, T>>,
+    /// Whether interrupts were enabled before locking
+    enabled: bool,
+}
+
+impl<T> Deref for InterruptSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for InterruptSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for InterruptSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // unlock first, an interrupt right after enabling may take the lock
+        drop(self.guard.take());
+        if self.enabled {
+            unsafe { interrupts::enable() };
+        }
+    }
+}
+
+/// Records the owner of every mutex and panics when the owner locks it
+/// again, which would spin forever.
+///
+/// The owner is identified by the function passed to [`set_owner_id`], e.g.
+/// the id of the current thread. Interrupt handlers count as the thread they
+/// interrupted, so a handler taking a lock its thread holds is caught as well.
+#[cfg(feature = "mutex-debug")]
+pub mod debug {
+    use core::{
+        panic::Location,
+        ptr,
+        sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+    };
+
+    /// Owner id of a free mutex
+    const NONE: u64 = u64::MAX;
+
+    static OWNER_ID: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+    /// Sets the function that identifies the caller of a lock. Until then all
+    /// callers have the id 0.
+    pub fn set_owner_id(id: fn() -> u64) {
+        OWNER_ID.store(id as *mut (), Ordering::SeqCst);
+    }
+
+    fn owner_id() -> u64 {
+        match OWNER_ID.load(Ordering::SeqCst) {
+            id if id.is_null() => 0,
+            id => unsafe { core::mem::transmute::<*mut (), fn() -> u64>(id)() },
+        }
+    }
+
+    pub(super) struct Owner {
+        id: AtomicU64,
+        location: AtomicPtr<Location<'static>>,
+    }
+
+    impl Owner {
+        pub(super) const fn new() -> Self {
+            Self {
+                id: AtomicU64::new(NONE),
+                location: AtomicPtr::new(ptr::null_mut()),
+            }
+        }
+
+        #[track_caller]
+        pub(super) fn check_reentrant(&self) {
+            if self.id.load(Ordering::SeqCst) != owner_id() {
+                return;
+            }
+
+            let location = self.location.load(Ordering::SeqCst);
+            if location.is_null() {
+                panic!("Deadlock: mutex locked again by its owner");
+            }
+            panic!(
+                "Deadlock: mutex locked again by its owner, which locked it at {}",
+                unsafe { &*location }
+            );
+        }
+
+        #[track_caller]
+        pub(super) fn acquired(&self) {
+            let location: &'static Location<'static> = Location::caller();
+            self.location
+                .store(location as *const _ as *mut _, Ordering::SeqCst);
+            self.id.store(owner_id(), Ordering::SeqCst);
+        }
+
+        pub(super) fn released(&self) {
+            self.id.store(NONE, Ordering::SeqCst);
+            self.location.store(ptr::null_mut(), Ordering::SeqCst);
+        }
+    }
+}
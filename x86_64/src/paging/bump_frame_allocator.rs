@@ -61,6 +61,17 @@ where
         PhysicalAddress::new(self.memory_map.clone().map(|r| r.end()).max().unwrap())
     }
 
+    /// Number of frames handed out so far
+    pub fn allocated_frames(&self) -> usize {
+        self.next
+    }
+
+    /// Usable frames that haven't been handed out yet, e.g. to pass them on
+    /// to an allocator that can free frames
+    pub fn remaining_frames(&self) -> impl Iterator<Item = PhysicalFrame> {
+        self.usable_frames().skip(self.next)
+    }
+
     fn usable_frames(&self) -> impl Iterator<Item = PhysicalFrame> {
         let usable_regions = self.memory_map.clone().filter(|r| r.is_usable());
         let addr_ranges = usable_regions.map(|r| r.start()..r.end());
@@ -79,4 +90,33 @@ where
         self.next += 1;
         frame
     }
+
+    /// Skipped frames are lost, a bump allocator can't hand them out later
+    fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        alignment: u64,
+    ) -> Option<PhysicalFrame<Size4KiB>> {
+        assert!(count > 0 && alignment.is_power_of_two());
+
+        // first frame, last frame and length of the current run
+        let mut run: Option<(PhysicalFrame, PhysicalFrame, usize)> = None;
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            run = match run {
+                Some((first, last, length)) if last + 1 == frame => {
+                    Some((first, frame, length + 1))
+                }
+                _ if frame.start() % alignment == 0 => Some((frame, frame, 1)),
+                _ => None,
+            };
+
+            if let Some((first, _, length)) = run {
+                if length == count {
+                    self.next = index + 1;
+                    return Some(first);
+                }
+            }
+        }
+        None
+    }
 }
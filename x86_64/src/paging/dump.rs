@@ -0,0 +1,112 @@
+//! Prints the layout of an address space.
+//!
+//! Neighbouring mappings are merged if they are contiguous in both the virtual
+//! and the physical address space and have the same flags and memory type, so
+//! e.g. the physical memory mapping shows up as a single line.
+use crate::{
+    memory::{Address, KIB},
+    paging::{mapped_page_table::Mapping, Mappings, PageTableEntryFlags},
+    println,
+    register::MemoryType,
+};
+use core::fmt;
+
+/// Flags set by the CPU, ignored when merging mappings
+const STATUS_FLAGS: PageTableEntryFlags =
+    PageTableEntryFlags::ACCESSED.union(PageTableEntryFlags::DIRTY);
+
+/// Contiguous mappings with the same attributes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    /// First mapping of the range, its size is the size of the whole range
+    pub mapping: Mapping,
+    /// Amount of page table entries in the range
+    pub pages: u64,
+}
+
+impl MappedRange {
+    fn extend(&mut self, next: &Mapping) -> bool {
+        let current = &self.mapping;
+        // wrapping, the last page of the address space ends at 2^64
+        let contiguous = next.start.as_u64().wrapping_sub(current.start.as_u64()) == current.size
+            && next.frame.as_u64().wrapping_sub(current.frame.as_u64()) == current.size;
+        let same_attributes = current.flags.difference(STATUS_FLAGS)
+            == next.flags.difference(STATUS_FLAGS)
+            && current.memory_type == next.memory_type;
+        if !contiguous || !same_attributes {
+            return false;
+        }
+
+        self.mapping.size += next.size;
+        self.mapping.flags |= next.flags & STATUS_FLAGS;
+        self.pages += 1;
+        true
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mapping = &self.mapping;
+        let flags = mapping.flags;
+        let flag = |flag, c| if flags.contains(flag) { c } else { '-' };
+        let memory_type = match mapping.memory_type {
+            MemoryType::Uncacheable => "UC",
+            MemoryType::WriteCombining => "WC",
+            MemoryType::WriteThrough => "WT",
+            MemoryType::WriteProtected => "WP",
+            MemoryType::WriteBack => "WB",
+            MemoryType::UncachedMinus => "UC-",
+        };
+
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x}-{:#014x} {:>10} KiB r{}{}{}{} {:<3} ({} pages)",
+            mapping.start.as_u64(),
+            mapping.start.as_u64().wrapping_add(mapping.size),
+            mapping.frame.as_u64(),
+            mapping.frame.as_u64() + mapping.size,
+            mapping.size / KIB,
+            flag(PageTableEntryFlags::WRITABLE, 'w'),
+            if flags.contains(PageTableEntryFlags::NO_EXECUTE) {
+                '-'
+            } else {
+                'x'
+            },
+            flag(PageTableEntryFlags::USER_ACCESSIBLE, 'u'),
+            flag(PageTableEntryFlags::GLOBAL, 'g'),
+            memory_type,
+            self.pages
+        )
+    }
+}
+
+/// Calls `f` for every range of merged mappings in ascending address order
+pub fn for_each_range<T, F>(page_table: &T, mut f: F)
+where
+    T: Mappings,
+    F: FnMut(&MappedRange),
+{
+    let mut current: Option<MappedRange> = None;
+    page_table.for_each_mapping(|mapping| {
+        if let Some(range) = current.as_mut() {
+            if range.extend(&mapping) {
+                return;
+            }
+            f(range);
+        }
+        current = Some(MappedRange { mapping, pages: 1 });
+    });
+
+    if let Some(range) = current {
+        f(&range);
+    }
+}
+
+/// Prints all present mappings of `page_table`
+pub fn dump_address_space<T: Mappings>(page_table: &T) {
+    println!(
+        "{:<37} {:<33} {:>14} {:<5} {}",
+        "virtual", "physical", "size", "flags", "type"
+    );
+    for_each_range(page_table, |range| println!("{}", range));
+}
@@ -1,12 +1,15 @@
 use crate::{
     memory::{
-        Address, FrameAllocator, Page, PageSize, PhysicalFrame, Size2MiB, Size4KiB, VirtualAddress,
+        Address, FrameAllocator, Page, PageRangeInclusive, PageSize, PhysicalAddress,
+        PhysicalFrame, PhysicalFrameRangeInclusive, Size1GiB, Size2MiB, Size4KiB, VirtualAddress,
     },
     paging::{
-        Mapper, MappingError, PageTable, PageTableEntry, PageTableEntryFlags, TlbFlusher,
-        TranslationError, Translator, UnmappingError,
+        CacheAttribute, FlagUpdateError, Mapper, MappingError, PageTable, PageTableEntry,
+        PageTableEntryFlags, PagingLevels, RangeFlusher, TlbFlusher, TranslationError, Translator,
+        UnmappingError,
     },
     println,
+    register::MemoryType,
 };
 use core::ops::Add;
 /// Provides a virtual address mapping for physical page table frames.
@@ -23,14 +26,139 @@ pub unsafe trait PageTableFrameMapping {
     fn frame_to_virtual(&self, frame: PhysicalFrame) -> VirtualAddress;
 }
 
+/// A present leaf entry of a page table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtualAddress,
+    pub frame: PhysicalAddress,
+    /// Size of the mapped page
+    pub size: u64,
+    /// Flags of the entry without the bits selecting the page size and the
+    /// memory type
+    pub flags: PageTableEntryFlags,
+    pub memory_type: MemoryType,
+}
+
 pub struct MappedPageTable<'a, P: PageTableFrameMapping> {
     walker: PageTableWalker<P>,
-    pml4t: &'a mut PageTable,
+    /// The PML4 table, or the PML5 table with 5-level paging
+    root: &'a mut PageTable,
+    levels: PagingLevels,
 }
 
 impl<'a, P: PageTableFrameMapping> MappedPageTable<'a, P> {
-    pub fn new(walker: PageTableWalker<P>, pml4t: &'a mut PageTable) -> Self {
-        Self { walker, pml4t }
+    /// Page table using the active paging mode
+    pub fn new(walker: PageTableWalker<P>, root: &'a mut PageTable) -> Self {
+        Self::with_levels(walker, root, PagingLevels::current())
+    }
+
+    pub fn with_levels(
+        walker: PageTableWalker<P>,
+        root: &'a mut PageTable,
+        levels: PagingLevels,
+    ) -> Self {
+        Self {
+            walker,
+            root,
+            levels,
+        }
+    }
+
+    pub fn levels(&self) -> PagingLevels {
+        self.levels
+    }
+
+    /// Calls `f` for every present mapping in ascending address order
+    pub fn for_each_mapping<F>(&self, mut f: F)
+    where
+        F: FnMut(Mapping),
+    {
+        let level = match self.levels {
+            PagingLevels::Four => 4,
+            PagingLevels::Five => 5,
+        };
+        self.walk(self.root, level, 0, &mut f);
+    }
+
+    /// Level 1 table responsible for `address`, missing tables on the way
+    /// are allocated
+    fn l1_table_mut<A>(
+        &mut self,
+        address: VirtualAddress,
+        frame_allocator: &mut A,
+    ) -> Result<&mut PageTable, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let parent_flags = PageTableEntryFlags::PRESENT
+            | PageTableEntryFlags::WRITABLE
+            | PageTableEntryFlags::USER_ACCESSIBLE;
+        let l4 = self.walker.l4_table_mut(
+            self.root,
+            self.levels,
+            address,
+            parent_flags,
+            frame_allocator,
+        )?;
+        let l3 = self.walker.get_or_allocate_pagetable(
+            &mut l4[address.l4_index()],
+            parent_flags,
+            frame_allocator,
+        )?;
+        let l2 = self.walker.get_or_allocate_pagetable(
+            &mut l3[address.l3_index()],
+            parent_flags,
+            frame_allocator,
+        )?;
+        self.walker.get_or_allocate_pagetable(
+            &mut l2[address.l2_index()],
+            parent_flags,
+            frame_allocator,
+        )
+    }
+
+    /// Entry for `address` in the table of `level`, 1 being the lowest. None
+    /// if a table on the way is missing or maps a huge frame.
+    fn leaf_entry(&mut self, address: VirtualAddress, level: usize) -> Option<&mut PageTableEntry> {
+        let l4 = self.walker.l4_table(self.root, self.levels, address)?;
+        let mut table = self.walker.get_pagetable(&l4[address.l4_index()])?;
+        let indices = [address.l3_index(), address.l2_index(), address.l1_index()];
+        for index in &indices[..3 - level] {
+            let parent = table;
+            table = self.walker.get_pagetable(&parent[*index])?;
+        }
+        Some(&mut table[indices[3 - level]])
+    }
+
+    fn walk<F>(&self, table: &PageTable, level: u32, base: u64, f: &mut F)
+    where
+        F: FnMut(Mapping),
+    {
+        let entry_size = 1u64 << (12 + 9 * (level - 1));
+        for (index, entry) in table.entries.iter().enumerate() {
+            if !entry.is_present() {
+                continue;
+            }
+            let address = base + index as u64 * entry_size;
+
+            let huge = level > 1 && entry.is_huge();
+            if level == 1 || huge {
+                let flags = entry.flags().difference(
+                    PageTableEntryFlags::HUGE_PAGE
+                        | PageTableEntryFlags::NO_CACHE
+                        | PageTableEntryFlags::WRITE_THROUGH,
+                );
+                f(Mapping {
+                    start: self.levels.canonicalize(address),
+                    frame: entry.address().align_down(entry_size),
+                    size: entry_size,
+                    flags,
+                    memory_type: entry.memory_type(huge),
+                });
+            } else if let Some(next) = self.walker.get_pagetable(entry) {
+                self.walk(next, level - 1, address, f);
+            }
+        }
     }
 }
 
@@ -51,12 +179,18 @@ impl<P: PageTableFrameMapping> PageTableWalker<P> {
         pagetable_entry: &'a mut PageTableEntry,
         flags: PageTableEntryFlags,
         allocator: &mut A,
-    ) -> Option<&'a mut PageTable>
+    ) -> Result<&'a mut PageTable, MappingError>
     where
         A: FrameAllocator<Size4KiB>,
     {
+        if pagetable_entry.is_huge() {
+            return Err(MappingError::ParentEntryHugePage);
+        }
+
         let table = if pagetable_entry.is_unused() {
-            let frame = allocator.allocate_frame()?;
+            let frame = allocator
+                .allocate_frame()
+                .ok_or(MappingError::FrameAllocationFailed)?;
             pagetable_entry.set_address(frame.address(), flags);
 
             let virtual_address = self.page_table_frame_mapping.frame_to_virtual(frame);
@@ -75,14 +209,51 @@ impl<P: PageTableFrameMapping> PageTableWalker<P> {
             unsafe { PageTable::at_address(virtual_address) }
         };
 
-        Some(table)
+        Ok(table)
+    }
+
+    /// PML4 table responsible for `address`, allocated if missing
+    fn l4_table_mut<'b, A>(
+        &self,
+        root: &'b mut PageTable,
+        levels: PagingLevels,
+        address: VirtualAddress,
+        flags: PageTableEntryFlags,
+        allocator: &mut A,
+    ) -> Result<&'b mut PageTable, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        match levels {
+            PagingLevels::Four => Ok(root),
+            PagingLevels::Five => {
+                self.get_or_allocate_pagetable(&mut root[address.l5_index()], flags, allocator)
+            }
+        }
+    }
+
+    /// PML4 table responsible for `address`
+    fn l4_table<'b>(
+        &self,
+        root: &'b PageTable,
+        levels: PagingLevels,
+        address: VirtualAddress,
+    ) -> Option<&'b PageTable> {
+        match levels {
+            PagingLevels::Four => Some(root),
+            PagingLevels::Five => self
+                .get_pagetable(&root[address.l5_index()])
+                .map(|table| &*table),
+        }
     }
 
+    /// Page table the entry points to, None if it is unused or maps a huge
+    /// frame
     pub fn get_pagetable<'a>(
         &self,
         pagetable_entry: &'a PageTableEntry,
     ) -> Option<&'a mut PageTable> {
-        match pagetable_entry.is_unused() {
+        match pagetable_entry.is_unused() || pagetable_entry.is_huge() {
             true => None,
             false => {
                 let virtual_address = self
@@ -101,67 +272,81 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size4KiB> for MappedPageTable<'a, P> {
         frame: PhysicalFrame<Size4KiB>,
         page: Page<Size4KiB>,
         flags: PageTableEntryFlags,
+        cache: CacheAttribute,
         frame_allocator: &mut A,
     ) -> Result<TlbFlusher<Size4KiB>, MappingError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        let parent_flags = PageTableEntryFlags::PRESENT
-            | PageTableEntryFlags::WRITABLE
-            | PageTableEntryFlags::USER_ACCESSIBLE;
-        let l4 = &mut self.pml4t;
-        let l3 = self
-            .walker
-            .get_or_allocate_pagetable(
-                &mut l4[page.address.l4_index()],
-                parent_flags,
-                frame_allocator,
-            )
-            .ok_or(MappingError::FrameAllocationFailed)?;
-        let l2 = self
-            .walker
-            .get_or_allocate_pagetable(
-                &mut l3[page.address.l3_index()],
-                parent_flags,
-                frame_allocator,
-            )
-            .ok_or(MappingError::FrameAllocationFailed)?;
-        let l1 = self
-            .walker
-            .get_or_allocate_pagetable(
-                &mut l2[page.address.l2_index()],
-                parent_flags,
-                frame_allocator,
-            )
-            .ok_or(MappingError::FrameAllocationFailed)?;
-
+        let l1 = self.l1_table_mut(page.address, frame_allocator)?;
         let pte = &mut l1[page.address.l1_index()];
 
         if pte.is_present() {
             Err(MappingError::PageAlreadyMapped)
         } else {
-            pte.set_address(frame.address(), flags);
+            pte.set_address(frame.address(), flags | cache.flags::<Size4KiB>());
             Ok(TlbFlusher::new(page))
         }
     }
 
+    fn map_range<A>(
+        &mut self,
+        frames: PhysicalFrameRangeInclusive<Size4KiB>,
+        pages: PageRangeInclusive<Size4KiB>,
+        flags: PageTableEntryFlags,
+        cache: CacheAttribute,
+        frame_allocator: &mut A,
+    ) -> Result<RangeFlusher<Size4KiB>, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        assert!(frames.end - frames.start == pages.end - pages.start);
+        let flags = flags | cache.flags::<Size4KiB>();
+
+        // consecutive pages share their level 1 table, it is only walked to
+        // once per 2 MiB
+        let mut streak: Option<(VirtualAddress, *mut PageTable)> = None;
+        for (frame, page) in frames.zip(pages) {
+            let base = page.address.align_down(Size2MiB::SIZE);
+            let l1 = match streak {
+                Some((start, l1)) if start == base => l1,
+                _ => {
+                    let l1: *mut PageTable = self.l1_table_mut(page.address, frame_allocator)?;
+                    streak = Some((base, l1));
+                    l1
+                }
+            };
+
+            // the table stays valid, tables are never freed while mapping
+            let pte = unsafe { &mut (&mut *l1)[page.address.l1_index()] };
+            if pte.is_present() {
+                return Err(MappingError::PageAlreadyMapped);
+            }
+            pte.set_address(frame.address(), flags);
+        }
+        Ok(RangeFlusher::new(pages))
+    }
+
     fn unmap(
         &mut self,
         page: Page<Size4KiB>,
     ) -> Result<(PhysicalFrame<Size4KiB>, TlbFlusher<Size4KiB>), UnmappingError> {
-        let l4 = &mut self.pml4t;
+        let l4 = self
+            .walker
+            .l4_table(self.root, self.levels, page.address)
+            .ok_or(UnmappingError::PageNotMapped)?;
         let l3 = self
             .walker
-            .get_pagetable(&mut l4[page.address.l4_index()])
+            .get_pagetable(&l4[page.address.l4_index()])
             .unwrap();
         let l2 = self
             .walker
-            .get_pagetable(&mut l3[page.address.l3_index()])
+            .get_pagetable(&l3[page.address.l3_index()])
             .unwrap();
 
         let l1 = self
             .walker
-            .get_pagetable(&mut l2[page.address.l2_index()])
+            .get_pagetable(&l2[page.address.l2_index()])
             .unwrap();
 
         let pte = &mut l1[page.address().l1_index()];
@@ -177,6 +362,26 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size4KiB> for MappedPageTable<'a, P> {
             TlbFlusher::new(page),
         ))
     }
+
+    fn update_flags(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableEntryFlags,
+    ) -> Result<TlbFlusher<Size4KiB>, FlagUpdateError> {
+        self.leaf_entry(page.address, 1)
+            .ok_or(FlagUpdateError::PageNotMapped)?
+            .update_leaf_flags(page, flags)
+    }
+
+    fn remap(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysicalFrame<Size4KiB>,
+    ) -> Result<(PhysicalFrame<Size4KiB>, TlbFlusher<Size4KiB>), FlagUpdateError> {
+        self.leaf_entry(page.address, 1)
+            .ok_or(FlagUpdateError::PageNotMapped)?
+            .remap_leaf(page, frame)
+    }
 }
 
 impl<'a, P: PageTableFrameMapping> Mapper<Size2MiB> for MappedPageTable<'a, P> {
@@ -185,6 +390,7 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size2MiB> for MappedPageTable<'a, P> {
         frame: PhysicalFrame<Size2MiB>,
         page: Page<Size2MiB>,
         flags: PageTableEntryFlags,
+        cache: CacheAttribute,
         frame_allocator: &mut A,
     ) -> Result<TlbFlusher<Size2MiB>, MappingError>
     where
@@ -193,30 +399,33 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size2MiB> for MappedPageTable<'a, P> {
         let parent_flags = PageTableEntryFlags::PRESENT
             | PageTableEntryFlags::WRITABLE
             | PageTableEntryFlags::USER_ACCESSIBLE;
-        let l4 = &mut self.pml4t;
-        let l3 = self
-            .walker
-            .get_or_allocate_pagetable(
-                &mut l4[page.address.l4_index()],
-                parent_flags,
-                frame_allocator,
-            )
-            .ok_or(MappingError::FrameAllocationFailed)?;
-        let l2 = self
-            .walker
-            .get_or_allocate_pagetable(
-                &mut l3[page.address.l3_index()],
-                parent_flags,
-                frame_allocator,
-            )
-            .ok_or(MappingError::FrameAllocationFailed)?;
+        let l4 = self.walker.l4_table_mut(
+            self.root,
+            self.levels,
+            page.address,
+            parent_flags,
+            frame_allocator,
+        )?;
+        let l3 = self.walker.get_or_allocate_pagetable(
+            &mut l4[page.address.l4_index()],
+            parent_flags,
+            frame_allocator,
+        )?;
+        let l2 = self.walker.get_or_allocate_pagetable(
+            &mut l3[page.address.l3_index()],
+            parent_flags,
+            frame_allocator,
+        )?;
 
         let pte = &mut l2[page.address.l2_index()];
 
         if pte.is_present() {
             Err(MappingError::PageAlreadyMapped)
         } else {
-            pte.set_address(frame.address(), flags | PageTableEntryFlags::HUGE_PAGE);
+            pte.set_address(
+                frame.address(),
+                flags | cache.flags::<Size2MiB>() | PageTableEntryFlags::HUGE_PAGE,
+            );
             Ok(TlbFlusher::new(page))
         }
     }
@@ -225,14 +434,17 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size2MiB> for MappedPageTable<'a, P> {
         &mut self,
         page: Page<Size2MiB>,
     ) -> Result<(PhysicalFrame<Size2MiB>, TlbFlusher<Size2MiB>), UnmappingError> {
-        let l4 = &mut self.pml4t;
+        let l4 = self
+            .walker
+            .l4_table(self.root, self.levels, page.address)
+            .ok_or(UnmappingError::PageNotMapped)?;
         let l3 = self
             .walker
-            .get_pagetable(&mut l4[page.address.l4_index()])
+            .get_pagetable(&l4[page.address.l4_index()])
             .unwrap();
         let l2 = self
             .walker
-            .get_pagetable(&mut l3[page.address.l3_index()])
+            .get_pagetable(&l3[page.address.l3_index()])
             .unwrap();
 
         let pte = &mut l2[page.address.l2_index()];
@@ -248,6 +460,26 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size2MiB> for MappedPageTable<'a, P> {
             TlbFlusher::new(page),
         ))
     }
+
+    fn update_flags(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableEntryFlags,
+    ) -> Result<TlbFlusher<Size2MiB>, FlagUpdateError> {
+        self.leaf_entry(page.address, 2)
+            .ok_or(FlagUpdateError::PageNotMapped)?
+            .update_leaf_flags(page, flags)
+    }
+
+    fn remap(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysicalFrame<Size2MiB>,
+    ) -> Result<(PhysicalFrame<Size2MiB>, TlbFlusher<Size2MiB>), FlagUpdateError> {
+        self.leaf_entry(page.address, 2)
+            .ok_or(FlagUpdateError::PageNotMapped)?
+            .remap_leaf(page, frame)
+    }
 }
 
 impl<'a, P: PageTableFrameMapping> Translator<Size4KiB> for MappedPageTable<'a, P> {
@@ -255,7 +487,10 @@ impl<'a, P: PageTableFrameMapping> Translator<Size4KiB> for MappedPageTable<'a,
         &self,
         page: Page<Size4KiB>,
     ) -> Result<(PhysicalFrame<Size4KiB>, PageTableEntryFlags), TranslationError> {
-        let l4 = &self.pml4t;
+        let l4 = self
+            .walker
+            .l4_table(self.root, self.levels, page.address)
+            .ok_or(TranslationError::NotMapped)?;
         let l3 = self
             .walker
             .get_pagetable(&l4[page.address.l4_index()])
@@ -287,7 +522,10 @@ impl<'a, P: PageTableFrameMapping> Translator<Size2MiB> for MappedPageTable<'a,
         &self,
         page: Page<Size2MiB>,
     ) -> Result<(PhysicalFrame<Size2MiB>, PageTableEntryFlags), TranslationError> {
-        let l4 = &self.pml4t;
+        let l4 = self
+            .walker
+            .l4_table(self.root, self.levels, page.address)
+            .ok_or(TranslationError::NotMapped)?;
         let l3 = self
             .walker
             .get_pagetable(&l4[page.address.l4_index()])
@@ -309,3 +547,117 @@ impl<'a, P: PageTableFrameMapping> Translator<Size2MiB> for MappedPageTable<'a,
         }
     }
 }
+
+impl<'a, P: PageTableFrameMapping> Mapper<Size1GiB> for MappedPageTable<'a, P> {
+    fn map_to<A>(
+        &mut self,
+        frame: PhysicalFrame<Size1GiB>,
+        page: Page<Size1GiB>,
+        flags: PageTableEntryFlags,
+        cache: CacheAttribute,
+        frame_allocator: &mut A,
+    ) -> Result<TlbFlusher<Size1GiB>, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let parent_flags = PageTableEntryFlags::PRESENT
+            | PageTableEntryFlags::WRITABLE
+            | PageTableEntryFlags::USER_ACCESSIBLE;
+        let l4 = self.walker.l4_table_mut(
+            self.root,
+            self.levels,
+            page.address,
+            parent_flags,
+            frame_allocator,
+        )?;
+        let l3 = self.walker.get_or_allocate_pagetable(
+            &mut l4[page.address.l4_index()],
+            parent_flags,
+            frame_allocator,
+        )?;
+
+        let pte = &mut l3[page.address.l3_index()];
+
+        if pte.is_present() {
+            Err(MappingError::PageAlreadyMapped)
+        } else {
+            pte.set_address(
+                frame.address(),
+                flags | cache.flags::<Size1GiB>() | PageTableEntryFlags::HUGE_PAGE,
+            );
+            Ok(TlbFlusher::new(page))
+        }
+    }
+
+    fn unmap(
+        &mut self,
+        page: Page<Size1GiB>,
+    ) -> Result<(PhysicalFrame<Size1GiB>, TlbFlusher<Size1GiB>), UnmappingError> {
+        let l4 = self
+            .walker
+            .l4_table(self.root, self.levels, page.address)
+            .ok_or(UnmappingError::PageNotMapped)?;
+        let l3 = self
+            .walker
+            .get_pagetable(&l4[page.address.l4_index()])
+            .ok_or(UnmappingError::PageNotMapped)?;
+
+        let pte = &mut l3[page.address.l3_index()];
+
+        if !pte.is_present() || !pte.is_huge() {
+            return Err(UnmappingError::PageNotMapped);
+        }
+
+        let frame = PhysicalFrame::containing_address(pte.address());
+        pte.set_unused();
+
+        Ok((frame, TlbFlusher::new(page)))
+    }
+
+    fn update_flags(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableEntryFlags,
+    ) -> Result<TlbFlusher<Size1GiB>, FlagUpdateError> {
+        self.leaf_entry(page.address, 3)
+            .ok_or(FlagUpdateError::PageNotMapped)?
+            .update_leaf_flags(page, flags)
+    }
+
+    fn remap(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysicalFrame<Size1GiB>,
+    ) -> Result<(PhysicalFrame<Size1GiB>, TlbFlusher<Size1GiB>), FlagUpdateError> {
+        self.leaf_entry(page.address, 3)
+            .ok_or(FlagUpdateError::PageNotMapped)?
+            .remap_leaf(page, frame)
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> Translator<Size1GiB> for MappedPageTable<'a, P> {
+    fn translate(
+        &self,
+        page: Page<Size1GiB>,
+    ) -> Result<(PhysicalFrame<Size1GiB>, PageTableEntryFlags), TranslationError> {
+        let l4 = self
+            .walker
+            .l4_table(self.root, self.levels, page.address)
+            .ok_or(TranslationError::NotMapped)?;
+        let l3 = self
+            .walker
+            .get_pagetable(&l4[page.address.l4_index()])
+            .ok_or(TranslationError::NotMapped)?;
+
+        let pte = &l3[page.address.l3_index()];
+
+        if pte.is_present() && pte.is_huge() {
+            Ok((
+                PhysicalFrame::containing_address(pte.address()),
+                pte.flags(),
+            ))
+        } else {
+            Err(TranslationError::NotMapped)
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use crate::{
+    cpuid::{self, Features},
     instructions,
     memory::{
-        Address, FrameAllocator, Page, PageSize, PhysicalAddress, PhysicalFrame, Size2MiB,
-        Size4KiB, VirtualAddress,
+        Address, FrameAllocator, Page, PageRangeInclusive, PageSize, PhysicalAddress,
+        PhysicalFrame, PhysicalFrameRangeInclusive, Size1GiB, Size2MiB, Size4KiB, VirtualAddress,
     },
+    register::{Cr3, Cr4, Cr4Flags, MemoryType, Pat},
 };
 use bit_field::BitField;
 use bitflags::bitflags;
@@ -15,12 +17,14 @@ use core::{
 };
 
 pub mod bump_frame_allocator;
+pub mod dump;
 pub mod mapped_page_table;
 pub mod offset_page_table;
+pub mod recursive_page_table;
 
 bitflags! {
     /// Possible flags for a page table entry.
-    #[derive(Clone, Copy)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub struct PageTableEntryFlags: u64 {
         const NONE = 0;
         /// Specifies whether the mapped frame or page table is loaded in memory.
@@ -86,6 +90,110 @@ bitflags! {
 
 const TABLE_ENTRY_COUNT: usize = 512;
 
+/// Caching policy of a mapping, selected through the page attribute table.
+///
+/// The effective memory type also depends on the MTRRs of the physical range,
+/// e.g. write combining takes precedence over an uncacheable MTRR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheAttribute {
+    #[default]
+    WriteBack,
+    /// Writes are buffered and may be reordered, suited for framebuffers
+    WriteCombining,
+    /// Every access goes to memory, suited for memory mapped registers
+    Uncached,
+}
+
+impl CacheAttribute {
+    /// Entries 0 to 3 keep their power-on values, so PWT and PCD alone mean
+    /// the same as without the PAT. Entry 4 is write combining.
+    const PAT_ENTRIES: [MemoryType; 8] = [
+        MemoryType::WriteBack,
+        MemoryType::WriteThrough,
+        MemoryType::UncachedMinus,
+        MemoryType::Uncacheable,
+        MemoryType::WriteCombining,
+        MemoryType::WriteThrough,
+        MemoryType::UncachedMinus,
+        MemoryType::Uncacheable,
+    ];
+    /// PAT bit of a 4KiB page, the same bit marks huge pages in higher levels
+    const PAT_4KIB: u64 = 1 << 7;
+    /// PAT bit of a 2MiB or 1GiB page
+    const PAT_HUGE: u64 = 1 << 12;
+
+    /// Entry flags selecting the attribute for a page of size `S`
+    pub fn flags<S: PageSize>(self) -> PageTableEntryFlags {
+        match self {
+            CacheAttribute::WriteBack => PageTableEntryFlags::empty(),
+            CacheAttribute::WriteCombining if S::SIZE == Size4KiB::SIZE => {
+                PageTableEntryFlags::from_bits_retain(Self::PAT_4KIB)
+            }
+            CacheAttribute::WriteCombining => PageTableEntryFlags::from_bits_retain(Self::PAT_HUGE),
+            CacheAttribute::Uncached => {
+                PageTableEntryFlags::NO_CACHE | PageTableEntryFlags::WRITE_THROUGH
+            }
+        }
+    }
+}
+
+/// Programs the page attribute table with the layout [`CacheAttribute`]
+/// expects. Has to run once per CPU before write combining is used.
+///
+/// # Safety
+///
+/// No mapping may use the PAT bit yet
+pub unsafe fn init_pat() {
+    assert!(cpuid::has(Features::PAT), "CPU doesn't support the PAT");
+    Pat::write(CacheAttribute::PAT_ENTRIES);
+    instructions::wbinvd();
+    // reloading CR3 flushes all non global TLB entries
+    let (root, flags) = Cr3::read();
+    Cr3::write(root, flags);
+}
+
+/// Amount of page table levels used for address translation.
+///
+/// 5-level paging (LA57) can only be enabled while paging is disabled, so the
+/// mode is chosen by whoever set up paging first and can only be detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingLevels {
+    Four,
+    Five,
+}
+
+impl PagingLevels {
+    /// Levels of the active paging mode
+    pub fn current() -> Self {
+        if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+            PagingLevels::Five
+        } else {
+            PagingLevels::Four
+        }
+    }
+
+    /// Amount of significant bits of a virtual address
+    pub fn address_bits(self) -> u32 {
+        match self {
+            PagingLevels::Four => 48,
+            PagingLevels::Five => 57,
+        }
+    }
+
+    /// Whether all bits above the significant ones equal the highest
+    /// significant bit
+    pub fn is_canonical(self, address: VirtualAddress) -> bool {
+        let shift = 64 - self.address_bits();
+        ((address.as_u64() << shift) as i64 >> shift) as u64 == address.as_u64()
+    }
+
+    /// Sign extends the significant bits of `raw` into a canonical address
+    pub fn canonicalize(self, raw: u64) -> VirtualAddress {
+        let shift = 64 - self.address_bits();
+        VirtualAddress::new(((raw << shift) as i64 >> shift) as u64)
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct PageTableEntry(u64);
@@ -103,6 +211,11 @@ impl PageTableEntry {
         self.0 == 0
     }
 
+    /// Whether the entry maps a huge frame instead of pointing to a page table
+    pub fn is_huge(&self) -> bool {
+        (self.0 & PageTableEntryFlags::HUGE_PAGE.bits()) != 0
+    }
+
     pub fn address(&self) -> PhysicalAddress {
         PhysicalAddress::new(self.0.get_bits(12..48) << 12)
     }
@@ -111,6 +224,18 @@ impl PageTableEntry {
         PhysicalFrame::containing_address(self.address())
     }
 
+    /// Memory type of a leaf entry, assuming the PAT set up by [`init_pat`]
+    pub fn memory_type(&self, huge: bool) -> MemoryType {
+        let pat = match huge {
+            true => CacheAttribute::PAT_HUGE,
+            false => CacheAttribute::PAT_4KIB,
+        };
+        let index = (self.0 & pat != 0) as usize * 4
+            + self.flags().contains(PageTableEntryFlags::NO_CACHE) as usize * 2
+            + self.flags().contains(PageTableEntryFlags::WRITE_THROUGH) as usize;
+        CacheAttribute::PAT_ENTRIES[index]
+    }
+
     /// Sets the physical address of either the next page table this entry points
     /// to or the physical address of the physical frame if last level
     pub fn set_address(&mut self, addr: PhysicalAddress, flags: PageTableEntryFlags) {
@@ -132,6 +257,46 @@ impl PageTableEntry {
     pub fn set_unused(&mut self) {
         self.0 = 0;
     }
+
+    /// Replaces the flags of a leaf entry mapping a page of size `S`. The
+    /// frame and the huge page bit are kept.
+    fn update_leaf_flags<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableEntryFlags,
+    ) -> Result<TlbFlusher<S>, FlagUpdateError> {
+        self.check_leaf::<S>()?;
+        let huge = self.0 & PageTableEntryFlags::HUGE_PAGE.bits();
+        self.0 = (self.0 & Self::frame_mask::<S>()) | flags.bits() | huge;
+        Ok(TlbFlusher::new(page))
+    }
+
+    /// Points a leaf entry mapping a page of size `S` to `frame`, keeping all
+    /// flags. Returns the previous frame.
+    fn remap_leaf<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysicalFrame<S>,
+    ) -> Result<(PhysicalFrame<S>, TlbFlusher<S>), FlagUpdateError> {
+        self.check_leaf::<S>()?;
+        let mask = Self::frame_mask::<S>();
+        let old = PhysicalFrame::containing_address(PhysicalAddress::new(self.0 & mask));
+        self.0 = (self.0 & !mask) | frame.address().as_u64();
+        Ok((old, TlbFlusher::new(page)))
+    }
+
+    fn check_leaf<S: PageSize>(&self) -> Result<(), FlagUpdateError> {
+        let huge = S::SIZE != Size4KiB::SIZE;
+        match self.is_present() && self.is_huge() == huge {
+            true => Ok(()),
+            false => Err(FlagUpdateError::PageNotMapped),
+        }
+    }
+
+    /// Address bits of an entry mapping a frame of size `S`
+    fn frame_mask<S: PageSize>() -> u64 {
+        0x000f_ffff_ffff_f000 & !(S::SIZE - 1)
+    }
 }
 
 #[repr(align(4096))]
@@ -192,6 +357,8 @@ impl IndexMut<usize> for PageTable {
 pub enum MappingError {
     FrameAllocationFailed,
     PageAlreadyMapped,
+    /// A huge page already covers the page
+    ParentEntryHugePage,
 }
 
 #[derive(Debug)]
@@ -200,6 +367,12 @@ pub enum UnmappingError {
     PageNotMapped,
 }
 
+#[derive(Debug)]
+pub enum FlagUpdateError {
+    // Given page not mapped to physical frame
+    PageNotMapped,
+}
+
 // TODO: make unsafe to mark that these functions are inherently unsafe
 // S = trait wide scope
 pub trait Mapper<S: PageSize> {
@@ -209,6 +382,7 @@ pub trait Mapper<S: PageSize> {
         from: PhysicalFrame<S>,
         to: Page<S>,
         flags: PageTableEntryFlags,
+        cache: CacheAttribute,
         frame_allocator: &mut A,
     ) -> Result<TlbFlusher<S>, MappingError>
     where
@@ -218,31 +392,84 @@ pub trait Mapper<S: PageSize> {
         &mut self,
         frame: PhysicalFrame<S>,
         flags: PageTableEntryFlags,
+        cache: CacheAttribute,
         frame_allocator: &mut A,
     ) -> Result<TlbFlusher<S>, MappingError>
     where
         A: FrameAllocator<Size4KiB>,
     {
         let page = Page::containing_address(VirtualAddress::new(frame.address.as_u64()));
-        self.map_to(frame, page, flags, frame_allocator)
+        self.map_to(frame, page, flags, cache, frame_allocator)
+    }
+
+    /// Maps every page of `pages` to the frame at the same position in
+    /// `frames`. The TLB is flushed once for the whole range. Pages mapped
+    /// before an error stay mapped.
+    fn map_range<A>(
+        &mut self,
+        frames: PhysicalFrameRangeInclusive<S>,
+        pages: PageRangeInclusive<S>,
+        flags: PageTableEntryFlags,
+        cache: CacheAttribute,
+        frame_allocator: &mut A,
+    ) -> Result<RangeFlusher<S>, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        assert!(frames.end - frames.start == pages.end - pages.start);
+        for (frame, page) in frames.zip(pages) {
+            self.map_to(frame, page, flags, cache, frame_allocator)?
+                .ignore();
+        }
+        Ok(RangeFlusher::new(pages))
     }
 
     fn unmap(&mut self, page: Page<S>)
         -> Result<(PhysicalFrame<S>, TlbFlusher<S>), UnmappingError>;
+
+    /// Replaces the flags of the mapping of `page` in place, e.g. to make it
+    /// read-only. Cache attribute bits have to be part of `flags`.
+    fn update_flags(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableEntryFlags,
+    ) -> Result<TlbFlusher<S>, FlagUpdateError>;
+
+    /// Points the mapping of `page` to `frame` in place without changing its
+    /// flags. Returns the frame that was mapped before.
+    fn remap(
+        &mut self,
+        page: Page<S>,
+        frame: PhysicalFrame<S>,
+    ) -> Result<(PhysicalFrame<S>, TlbFlusher<S>), FlagUpdateError>;
 }
 
-pub trait MapperAllSizes: Mapper<Size4KiB> + Mapper<Size2MiB> {}
+pub trait MapperAllSizes: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB> {}
 
-impl<T> MapperAllSizes for T where T: Mapper<Size4KiB> + Mapper<Size2MiB> {}
+impl<T> MapperAllSizes for T where T: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB> {}
 
 #[derive(Debug)]
 pub enum TranslationError {
     NotMapped,
 }
 
-pub trait TranslatorAllSizes: Translator<Size4KiB> + Translator<Size2MiB> {}
+pub trait TranslatorAllSizes:
+    Translator<Size4KiB> + Translator<Size2MiB> + Translator<Size1GiB>
+{
+}
+
+impl<T> TranslatorAllSizes for T where
+    T: Translator<Size4KiB> + Translator<Size2MiB> + Translator<Size1GiB>
+{
+}
 
-impl<T> TranslatorAllSizes for T where T: Translator<Size4KiB> + Translator<Size2MiB> {}
+/// Page tables whose present mappings can be enumerated
+pub trait Mappings {
+    /// Calls `f` for every present mapping in ascending address order
+    fn for_each_mapping<F>(&self, f: F)
+    where
+        F: FnMut(mapped_page_table::Mapping);
+}
 
 /// Translates page to physical frame using page table
 pub trait Translator<S: PageSize> {
@@ -266,3 +493,29 @@ impl<S: PageSize> TlbFlusher<S> {
 
     pub fn ignore(self) {}
 }
+
+/// Flushing more pages than this one by one is slower than flushing the
+/// whole TLB
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+#[must_use = "Page table changes must be flushed or ignored"]
+pub struct RangeFlusher<S: PageSize>(PageRangeInclusive<S>);
+
+impl<S: PageSize> RangeFlusher<S> {
+    pub fn new(pages: PageRangeInclusive<S>) -> Self {
+        RangeFlusher(pages)
+    }
+
+    /// Flushes every page of the range, or the whole TLB if the range is
+    /// large. Global pages are not used, so reloading CR3 is enough for that.
+    pub fn flush(self) {
+        let pages = self.0;
+        if pages.start <= pages.end && pages.end - pages.start >= FLUSH_ALL_THRESHOLD {
+            instructions::flush_tlb_all();
+        } else {
+            pages.for_each(|page| instructions::flush_tlb(page.address()));
+        }
+    }
+
+    pub fn ignore(self) {}
+}
@@ -1,10 +1,13 @@
-use super::TlbFlusher;
+use super::{RangeFlusher, TlbFlusher};
 use crate::{
-    memory::{Address, PhysicalFrame, Size2MiB, Size4KiB, VirtualAddress},
+    memory::{
+        Address, PageRangeInclusive, PhysicalFrame, PhysicalFrameRangeInclusive, Size1GiB,
+        Size2MiB, Size4KiB, VirtualAddress,
+    },
     paging::{
-        mapped_page_table::{MappedPageTable, PageTableFrameMapping, PageTableWalker},
-        FrameAllocator, Mapper, MappingError, Page, PageTable, PageTableEntryFlags,
-        TranslationError, Translator, UnmappingError,
+        mapped_page_table::{MappedPageTable, Mapping, PageTableFrameMapping, PageTableWalker},
+        CacheAttribute, FlagUpdateError, FrameAllocator, Mapper, MappingError, Mappings, Page,
+        PageTable, PageTableEntryFlags, PagingLevels, TranslationError, Translator, UnmappingError,
     },
 };
 #[derive(Debug)]
@@ -32,10 +35,20 @@ pub struct OffsetPageTable<'a, P: PageTableFrameMapping> {
 }
 
 impl<'a, P: PageTableFrameMapping> OffsetPageTable<'a, P> {
-    pub fn new(pml4t: &'a mut PageTable, mapping: P) -> Self {
-        let inner = MappedPageTable::new(PageTableWalker::new(mapping), pml4t);
+    /// Page table using the active paging mode. `root` is the PML4 table, or
+    /// the PML5 table with 5-level paging.
+    pub fn new(root: &'a mut PageTable, mapping: P) -> Self {
+        Self::with_levels(root, mapping, PagingLevels::current())
+    }
+
+    pub fn with_levels(root: &'a mut PageTable, mapping: P, levels: PagingLevels) -> Self {
+        let inner = MappedPageTable::with_levels(PageTableWalker::new(mapping), root, levels);
         Self { inner }
     }
+
+    pub fn levels(&self) -> PagingLevels {
+        self.inner.levels()
+    }
 }
 
 impl<'a, P: PageTableFrameMapping> Mapper<Size4KiB> for OffsetPageTable<'a, P> {
@@ -44,12 +57,14 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size4KiB> for OffsetPageTable<'a, P> {
         frame: PhysicalFrame<Size4KiB>,
         page: Page<Size4KiB>,
         flags: PageTableEntryFlags,
+        cache: CacheAttribute,
         frame_allocator: &mut A,
     ) -> Result<TlbFlusher<Size4KiB>, MappingError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        self.inner.map_to(frame, page, flags, frame_allocator)
+        self.inner
+            .map_to(frame, page, flags, cache, frame_allocator)
     }
 
     fn unmap(
@@ -58,6 +73,37 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size4KiB> for OffsetPageTable<'a, P> {
     ) -> Result<(PhysicalFrame<Size4KiB>, TlbFlusher<Size4KiB>), UnmappingError> {
         self.inner.unmap(page)
     }
+
+    fn map_range<A>(
+        &mut self,
+        frames: PhysicalFrameRangeInclusive<Size4KiB>,
+        pages: PageRangeInclusive<Size4KiB>,
+        flags: PageTableEntryFlags,
+        cache: CacheAttribute,
+        frame_allocator: &mut A,
+    ) -> Result<RangeFlusher<Size4KiB>, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        self.inner
+            .map_range(frames, pages, flags, cache, frame_allocator)
+    }
+
+    fn update_flags(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableEntryFlags,
+    ) -> Result<TlbFlusher<Size4KiB>, FlagUpdateError> {
+        self.inner.update_flags(page, flags)
+    }
+
+    fn remap(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysicalFrame<Size4KiB>,
+    ) -> Result<(PhysicalFrame<Size4KiB>, TlbFlusher<Size4KiB>), FlagUpdateError> {
+        self.inner.remap(page, frame)
+    }
 }
 
 impl<'a, P: PageTableFrameMapping> Mapper<Size2MiB> for OffsetPageTable<'a, P> {
@@ -66,12 +112,14 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size2MiB> for OffsetPageTable<'a, P> {
         frame: PhysicalFrame<Size2MiB>,
         page: Page<Size2MiB>,
         flags: PageTableEntryFlags,
+        cache: CacheAttribute,
         frame_allocator: &mut A,
     ) -> Result<TlbFlusher<Size2MiB>, MappingError>
     where
         A: FrameAllocator<Size4KiB>,
     {
-        self.inner.map_to(frame, page, flags, frame_allocator)
+        self.inner
+            .map_to(frame, page, flags, cache, frame_allocator)
     }
 
     fn unmap(
@@ -80,6 +128,22 @@ impl<'a, P: PageTableFrameMapping> Mapper<Size2MiB> for OffsetPageTable<'a, P> {
     ) -> Result<(PhysicalFrame<Size2MiB>, TlbFlusher<Size2MiB>), UnmappingError> {
         self.inner.unmap(page)
     }
+
+    fn update_flags(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableEntryFlags,
+    ) -> Result<TlbFlusher<Size2MiB>, FlagUpdateError> {
+        self.inner.update_flags(page, flags)
+    }
+
+    fn remap(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysicalFrame<Size2MiB>,
+    ) -> Result<(PhysicalFrame<Size2MiB>, TlbFlusher<Size2MiB>), FlagUpdateError> {
+        self.inner.remap(page, frame)
+    }
 }
 
 impl<'a, P: PageTableFrameMapping> Translator<Size4KiB> for OffsetPageTable<'a, P> {
@@ -99,3 +163,61 @@ impl<'a, P: PageTableFrameMapping> Translator<Size2MiB> for OffsetPageTable<'a,
         self.inner.translate(page)
     }
 }
+
+impl<'a, P: PageTableFrameMapping> Mapper<Size1GiB> for OffsetPageTable<'a, P> {
+    fn map_to<A>(
+        &mut self,
+        frame: PhysicalFrame<Size1GiB>,
+        page: Page<Size1GiB>,
+        flags: PageTableEntryFlags,
+        cache: CacheAttribute,
+        frame_allocator: &mut A,
+    ) -> Result<TlbFlusher<Size1GiB>, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        self.inner
+            .map_to(frame, page, flags, cache, frame_allocator)
+    }
+
+    fn unmap(
+        &mut self,
+        page: Page<Size1GiB>,
+    ) -> Result<(PhysicalFrame<Size1GiB>, TlbFlusher<Size1GiB>), UnmappingError> {
+        self.inner.unmap(page)
+    }
+
+    fn update_flags(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableEntryFlags,
+    ) -> Result<TlbFlusher<Size1GiB>, FlagUpdateError> {
+        self.inner.update_flags(page, flags)
+    }
+
+    fn remap(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysicalFrame<Size1GiB>,
+    ) -> Result<(PhysicalFrame<Size1GiB>, TlbFlusher<Size1GiB>), FlagUpdateError> {
+        self.inner.remap(page, frame)
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> Translator<Size1GiB> for OffsetPageTable<'a, P> {
+    fn translate(
+        &self,
+        page: Page<Size1GiB>,
+    ) -> Result<(PhysicalFrame<Size1GiB>, PageTableEntryFlags), TranslationError> {
+        self.inner.translate(page)
+    }
+}
+
+impl<'a, P: PageTableFrameMapping> Mappings for OffsetPageTable<'a, P> {
+    fn for_each_mapping<F>(&self, f: F)
+    where
+        F: FnMut(Mapping),
+    {
+        self.inner.for_each_mapping(f)
+    }
+}
@@ -0,0 +1,492 @@
+use super::TlbFlusher;
+use crate::{
+    instructions,
+    memory::{
+        FrameAllocator, Page, PageSize, PhysicalFrame, Size1GiB, Size2MiB, Size4KiB, VirtualAddress,
+    },
+    paging::{
+        mapped_page_table::Mapping, CacheAttribute, FlagUpdateError, Mapper, MappingError,
+        Mappings, PageTable, PageTableEntry, PageTableEntryFlags, PagingLevels, TranslationError,
+        Translator, UnmappingError,
+    },
+    register::Cr3,
+};
+
+/// Pagetable that accesses its tables through a PML4 entry pointing to the
+/// PML4 table itself.
+///
+/// Walking through the recursive entry once or several times ends at the
+/// level 1, 2 or 3 tables, so no mapping of physical memory is required. The
+/// entry occupies 512 GiB of the virtual address space. Only supports 4-level
+/// paging.
+///
+/// https://os.phil-opp.com/paging-implementation/#recursive-page-tables
+pub struct RecursivePageTable<'a> {
+    pml4t: &'a mut PageTable,
+    index: usize,
+}
+
+#[derive(Debug)]
+pub enum RecursivePageTableError {
+    /// 5-level paging is active
+    UnsupportedLevels,
+    /// The entry at the index doesn't point to the active PML4 table
+    NotRecursive,
+}
+
+impl<'a> RecursivePageTable<'a> {
+    /// Page table using the active PML4 table, whose entry `index` has to
+    /// point to the table itself
+    ///
+    /// # Safety
+    ///
+    /// The active page table must not be accessed through other references
+    /// while the returned one is used
+    pub unsafe fn active(index: u16) -> Result<Self, RecursivePageTableError> {
+        if PagingLevels::current() != PagingLevels::Four {
+            return Err(RecursivePageTableError::UnsupportedLevels);
+        }
+
+        let index = index as usize;
+        let (frame, _) = Cr3::read();
+        let pml4t = PageTable::at_address(table_address(index, index, index, index));
+        let entry = &pml4t[index];
+        if !entry.is_present() || entry.physical_frame() != frame {
+            return Err(RecursivePageTableError::NotRecursive);
+        }
+
+        Ok(Self { pml4t, index })
+    }
+
+    /// Index of the recursive entry
+    pub fn index(&self) -> u16 {
+        self.index as u16
+    }
+
+    pub fn levels(&self) -> PagingLevels {
+        PagingLevels::Four
+    }
+
+    /// Virtual address of the level 3 table responsible for `address`
+    fn l3_address(&self, address: VirtualAddress) -> VirtualAddress {
+        let r = self.index;
+        table_address(r, r, r, address.l4_index())
+    }
+
+    /// Virtual address of the level 2 table responsible for `address`
+    fn l2_address(&self, address: VirtualAddress) -> VirtualAddress {
+        let r = self.index;
+        table_address(r, r, address.l4_index(), address.l3_index())
+    }
+
+    /// Virtual address of the level 1 table responsible for `address`
+    fn l1_address(&self, address: VirtualAddress) -> VirtualAddress {
+        let r = self.index;
+        table_address(
+            r,
+            address.l4_index(),
+            address.l3_index(),
+            address.l2_index(),
+        )
+    }
+
+    fn l3_table_mut<A>(
+        &mut self,
+        address: VirtualAddress,
+        allocator: &mut A,
+    ) -> Result<&'a mut PageTable, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let l3 = self.l3_address(address);
+        unsafe { get_or_allocate_table(&mut self.pml4t[address.l4_index()], l3, allocator) }
+    }
+
+    fn l2_table_mut<A>(
+        &mut self,
+        address: VirtualAddress,
+        allocator: &mut A,
+    ) -> Result<&'a mut PageTable, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let l2 = self.l2_address(address);
+        let l3 = self.l3_table_mut(address, allocator)?;
+        unsafe { get_or_allocate_table(&mut l3[address.l3_index()], l2, allocator) }
+    }
+
+    fn l1_table_mut<A>(
+        &mut self,
+        address: VirtualAddress,
+        allocator: &mut A,
+    ) -> Result<&'a mut PageTable, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let l1 = self.l1_address(address);
+        let l2 = self.l2_table_mut(address, allocator)?;
+        unsafe { get_or_allocate_table(&mut l2[address.l2_index()], l1, allocator) }
+    }
+
+    fn l3_table(&self, address: VirtualAddress) -> Option<&'a mut PageTable> {
+        unsafe { get_table(&self.pml4t[address.l4_index()], self.l3_address(address)) }
+    }
+
+    fn l2_table(&self, address: VirtualAddress) -> Option<&'a mut PageTable> {
+        let l3 = self.l3_table(address)?;
+        unsafe { get_table(&l3[address.l3_index()], self.l2_address(address)) }
+    }
+
+    fn l1_table(&self, address: VirtualAddress) -> Option<&'a mut PageTable> {
+        let l2 = self.l2_table(address)?;
+        unsafe { get_table(&l2[address.l2_index()], self.l1_address(address)) }
+    }
+
+    fn walk<F>(&self, table: &PageTable, level: u32, indices: [usize; 4], f: &mut F)
+    where
+        F: FnMut(Mapping),
+    {
+        let depth = 4 - level as usize;
+        let entry_size = 1u64 << (12 + 9 * (level - 1));
+        for (index, entry) in table.entries.iter().enumerate() {
+            // walking the recursive entry would visit the tables themselves
+            if !entry.is_present() || (depth == 0 && index == self.index) {
+                continue;
+            }
+            let mut indices = indices;
+            indices[depth] = index;
+
+            let huge = level > 1 && entry.is_huge();
+            if level == 1 || huge {
+                let flags = entry.flags().difference(
+                    PageTableEntryFlags::HUGE_PAGE
+                        | PageTableEntryFlags::NO_CACHE
+                        | PageTableEntryFlags::WRITE_THROUGH,
+                );
+                f(Mapping {
+                    start: table_address_of(indices),
+                    frame: entry.address().align_down(entry_size),
+                    size: entry_size,
+                    flags,
+                    memory_type: entry.memory_type(huge),
+                });
+                continue;
+            }
+
+            // the table of the next level is reached by shifting the indices
+            // up and walking through the recursive entry
+            let mut next = [self.index; 4];
+            next[3 - depth..].copy_from_slice(&indices[..=depth]);
+            let next = unsafe { PageTable::at_address(table_address_of(next)) };
+            self.walk(next, level - 1, indices, f);
+        }
+    }
+}
+
+/// Virtual address that is translated by walking the PML4 entry `l4`, then
+/// `l3`, `l2` and `l1`. Also the address mapped by these entries.
+fn table_address(l4: usize, l3: usize, l2: usize, l1: usize) -> VirtualAddress {
+    table_address_of([l4, l3, l2, l1])
+}
+
+fn table_address_of(indices: [usize; 4]) -> VirtualAddress {
+    let raw = indices
+        .iter()
+        .fold(0, |address, &index| (address << 9) | index as u64)
+        << 12;
+    PagingLevels::Four.canonicalize(raw)
+}
+
+/// Table the entry points to, which is accessible at `next`. Allocated if
+/// missing.
+///
+/// # Safety
+///
+/// `next` has to be the recursive address of the table behind `entry`
+unsafe fn get_or_allocate_table<'b, A>(
+    entry: &mut PageTableEntry,
+    next: VirtualAddress,
+    allocator: &mut A,
+) -> Result<&'b mut PageTable, MappingError>
+where
+    A: FrameAllocator<Size4KiB>,
+{
+    let flags = PageTableEntryFlags::PRESENT
+        | PageTableEntryFlags::WRITABLE
+        | PageTableEntryFlags::USER_ACCESSIBLE;
+
+    if entry.is_huge() {
+        return Err(MappingError::ParentEntryHugePage);
+    }
+
+    if entry.is_unused() {
+        let frame = allocator
+            .allocate_frame()
+            .ok_or(MappingError::FrameAllocationFailed)?;
+        entry.set_address(frame.address(), flags);
+        instructions::flush_tlb(next);
+        Ok(PageTable::initialize_empty_at_address(next))
+    } else {
+        if !entry.flags().contains(flags) {
+            entry.add_flags(flags);
+        }
+        Ok(PageTable::at_address(next))
+    }
+}
+
+/// Table the entry points to, None if it is unused or maps a huge frame
+///
+/// # Safety
+///
+/// `next` has to be the recursive address of the table behind `entry`
+unsafe fn get_table<'b>(entry: &PageTableEntry, next: VirtualAddress) -> Option<&'b mut PageTable> {
+    match entry.is_present() && !entry.is_huge() {
+        true => Some(PageTable::at_address(next)),
+        false => None,
+    }
+}
+
+/// Sets `entry` to `frame` unless it is already in use
+fn map_entry<S: PageSize>(
+    entry: &mut PageTableEntry,
+    frame: PhysicalFrame<S>,
+    page: Page<S>,
+    flags: PageTableEntryFlags,
+) -> Result<TlbFlusher<S>, MappingError> {
+    if entry.is_present() {
+        return Err(MappingError::PageAlreadyMapped);
+    }
+    entry.set_address(frame.address(), flags);
+    Ok(TlbFlusher::new(page))
+}
+
+fn unmap_entry<S: PageSize>(
+    entry: &mut PageTableEntry,
+    page: Page<S>,
+) -> Result<(PhysicalFrame<S>, TlbFlusher<S>), UnmappingError> {
+    if !entry.is_present() {
+        return Err(UnmappingError::PageNotMapped);
+    }
+    let frame = PhysicalFrame::containing_address(entry.address());
+    entry.set_unused();
+    Ok((frame, TlbFlusher::new(page)))
+}
+
+fn translate_entry<S: PageSize>(
+    entry: &PageTableEntry,
+) -> Result<(PhysicalFrame<S>, PageTableEntryFlags), TranslationError> {
+    match entry.is_present() {
+        true => Ok((
+            PhysicalFrame::containing_address(entry.address()),
+            entry.flags(),
+        )),
+        false => Err(TranslationError::NotMapped),
+    }
+}
+
+impl<'a> Mapper<Size4KiB> for RecursivePageTable<'a> {
+    fn map_to<A>(
+        &mut self,
+        frame: PhysicalFrame<Size4KiB>,
+        page: Page<Size4KiB>,
+        flags: PageTableEntryFlags,
+        cache: CacheAttribute,
+        frame_allocator: &mut A,
+    ) -> Result<TlbFlusher<Size4KiB>, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let l1 = self.l1_table_mut(page.address, frame_allocator)?;
+        map_entry(
+            &mut l1[page.address.l1_index()],
+            frame,
+            page,
+            flags | cache.flags::<Size4KiB>(),
+        )
+    }
+
+    fn unmap(
+        &mut self,
+        page: Page<Size4KiB>,
+    ) -> Result<(PhysicalFrame<Size4KiB>, TlbFlusher<Size4KiB>), UnmappingError> {
+        let l1 = self
+            .l1_table(page.address)
+            .ok_or(UnmappingError::PageNotMapped)?;
+        unmap_entry(&mut l1[page.address.l1_index()], page)
+    }
+
+    fn update_flags(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableEntryFlags,
+    ) -> Result<TlbFlusher<Size4KiB>, FlagUpdateError> {
+        let l1 = self
+            .l1_table(page.address)
+            .ok_or(FlagUpdateError::PageNotMapped)?;
+        l1[page.address.l1_index()].update_leaf_flags(page, flags)
+    }
+
+    fn remap(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysicalFrame<Size4KiB>,
+    ) -> Result<(PhysicalFrame<Size4KiB>, TlbFlusher<Size4KiB>), FlagUpdateError> {
+        let l1 = self
+            .l1_table(page.address)
+            .ok_or(FlagUpdateError::PageNotMapped)?;
+        l1[page.address.l1_index()].remap_leaf(page, frame)
+    }
+}
+
+impl<'a> Mapper<Size2MiB> for RecursivePageTable<'a> {
+    fn map_to<A>(
+        &mut self,
+        frame: PhysicalFrame<Size2MiB>,
+        page: Page<Size2MiB>,
+        flags: PageTableEntryFlags,
+        cache: CacheAttribute,
+        frame_allocator: &mut A,
+    ) -> Result<TlbFlusher<Size2MiB>, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let l2 = self.l2_table_mut(page.address, frame_allocator)?;
+        map_entry(
+            &mut l2[page.address.l2_index()],
+            frame,
+            page,
+            flags | cache.flags::<Size2MiB>() | PageTableEntryFlags::HUGE_PAGE,
+        )
+    }
+
+    fn unmap(
+        &mut self,
+        page: Page<Size2MiB>,
+    ) -> Result<(PhysicalFrame<Size2MiB>, TlbFlusher<Size2MiB>), UnmappingError> {
+        let l2 = self
+            .l2_table(page.address)
+            .ok_or(UnmappingError::PageNotMapped)?;
+        unmap_entry(&mut l2[page.address.l2_index()], page)
+    }
+
+    fn update_flags(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableEntryFlags,
+    ) -> Result<TlbFlusher<Size2MiB>, FlagUpdateError> {
+        let l2 = self
+            .l2_table(page.address)
+            .ok_or(FlagUpdateError::PageNotMapped)?;
+        l2[page.address.l2_index()].update_leaf_flags(page, flags)
+    }
+
+    fn remap(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysicalFrame<Size2MiB>,
+    ) -> Result<(PhysicalFrame<Size2MiB>, TlbFlusher<Size2MiB>), FlagUpdateError> {
+        let l2 = self
+            .l2_table(page.address)
+            .ok_or(FlagUpdateError::PageNotMapped)?;
+        l2[page.address.l2_index()].remap_leaf(page, frame)
+    }
+}
+
+impl<'a> Mapper<Size1GiB> for RecursivePageTable<'a> {
+    fn map_to<A>(
+        &mut self,
+        frame: PhysicalFrame<Size1GiB>,
+        page: Page<Size1GiB>,
+        flags: PageTableEntryFlags,
+        cache: CacheAttribute,
+        frame_allocator: &mut A,
+    ) -> Result<TlbFlusher<Size1GiB>, MappingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let l3 = self.l3_table_mut(page.address, frame_allocator)?;
+        map_entry(
+            &mut l3[page.address.l3_index()],
+            frame,
+            page,
+            flags | cache.flags::<Size1GiB>() | PageTableEntryFlags::HUGE_PAGE,
+        )
+    }
+
+    fn unmap(
+        &mut self,
+        page: Page<Size1GiB>,
+    ) -> Result<(PhysicalFrame<Size1GiB>, TlbFlusher<Size1GiB>), UnmappingError> {
+        let l3 = self
+            .l3_table(page.address)
+            .ok_or(UnmappingError::PageNotMapped)?;
+        unmap_entry(&mut l3[page.address.l3_index()], page)
+    }
+
+    fn update_flags(
+        &mut self,
+        page: Page<Size1GiB>,
+        flags: PageTableEntryFlags,
+    ) -> Result<TlbFlusher<Size1GiB>, FlagUpdateError> {
+        let l3 = self
+            .l3_table(page.address)
+            .ok_or(FlagUpdateError::PageNotMapped)?;
+        l3[page.address.l3_index()].update_leaf_flags(page, flags)
+    }
+
+    fn remap(
+        &mut self,
+        page: Page<Size1GiB>,
+        frame: PhysicalFrame<Size1GiB>,
+    ) -> Result<(PhysicalFrame<Size1GiB>, TlbFlusher<Size1GiB>), FlagUpdateError> {
+        let l3 = self
+            .l3_table(page.address)
+            .ok_or(FlagUpdateError::PageNotMapped)?;
+        l3[page.address.l3_index()].remap_leaf(page, frame)
+    }
+}
+
+impl<'a> Translator<Size4KiB> for RecursivePageTable<'a> {
+    fn translate(
+        &self,
+        page: Page<Size4KiB>,
+    ) -> Result<(PhysicalFrame<Size4KiB>, PageTableEntryFlags), TranslationError> {
+        let l1 = self
+            .l1_table(page.address)
+            .ok_or(TranslationError::NotMapped)?;
+        translate_entry(&l1[page.address.l1_index()])
+    }
+}
+
+impl<'a> Translator<Size2MiB> for RecursivePageTable<'a> {
+    fn translate(
+        &self,
+        page: Page<Size2MiB>,
+    ) -> Result<(PhysicalFrame<Size2MiB>, PageTableEntryFlags), TranslationError> {
+        let l2 = self
+            .l2_table(page.address)
+            .ok_or(TranslationError::NotMapped)?;
+        translate_entry(&l2[page.address.l2_index()])
+    }
+}
+
+impl<'a> Translator<Size1GiB> for RecursivePageTable<'a> {
+    fn translate(
+        &self,
+        page: Page<Size1GiB>,
+    ) -> Result<(PhysicalFrame<Size1GiB>, PageTableEntryFlags), TranslationError> {
+        let l3 = self
+            .l3_table(page.address)
+            .ok_or(TranslationError::NotMapped)?;
+        translate_entry(&l3[page.address.l3_index()])
+    }
+}
+
+impl<'a> Mappings for RecursivePageTable<'a> {
+    fn for_each_mapping<F>(&self, mut f: F)
+    where
+        F: FnMut(Mapping),
+    {
+        self.walk(self.pml4t, 4, [0; 4], &mut f);
+    }
+}
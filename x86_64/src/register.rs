@@ -1,7 +1,7 @@
 //! This module implements helper functions for x86 registers
 use crate::{
     gdt::SegmentSelector,
-    memory::{Address, PhysicalAddress, PhysicalFrame},
+    memory::{Address, PhysicalAddress, PhysicalFrame, VirtualAddress},
 };
 use bitflags::bitflags;
 use core::arch::asm;
@@ -116,6 +116,241 @@ impl Efer {
     }
 }
 
+/// Physical base address and enable bit of the local APIC
+pub struct ApicBase;
+
+impl ApicBase {
+    const MSR_NUM: u32 = 0x1B;
+    /// Set if the local APIC is enabled
+    pub const ENABLE: u64 = 1 << 11;
+    /// Bits of the 4 KiB aligned base address
+    pub const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    pub fn read() -> u64 {
+        Msr::read(Self::MSR_NUM)
+    }
+
+    /// Writes the raw register
+    ///
+    /// # Safety
+    ///
+    /// Unsafe because moving or disabling the local APIC affects all
+    /// interrupt delivery
+    pub unsafe fn write(val: u64) {
+        Msr::write(Self::MSR_NUM, val)
+    }
+}
+
+/// Deadline of the local APIC timer in TSC-deadline mode
+pub struct TscDeadline;
+
+impl TscDeadline {
+    const MSR_NUM: u32 = 0x6E0;
+
+    /// Arms the timer to fire once the time stamp counter reaches `deadline`.
+    /// A deadline of 0 disarms it.
+    pub fn write(deadline: u64) {
+        Msr::write(Self::MSR_NUM, deadline)
+    }
+}
+
+/// General purpose counters of the architectural performance monitoring, see
+/// `cpuid::PerformanceMonitoring`
+pub struct PerformanceCounter;
+
+impl PerformanceCounter {
+    const EVENT_SELECT_MSR: u32 = 0x186;
+    const COUNTER_MSR: u32 = 0xC1;
+    const GLOBAL_CONTROL_MSR: u32 = 0x38F;
+
+    /// Event select of the unhalted core cycles event
+    pub const UNHALTED_CORE_CYCLES: u64 = 0x3c;
+    /// Count in user mode
+    pub const USER: u64 = 1 << 16;
+    /// Count in kernel mode
+    pub const KERNEL: u64 = 1 << 17;
+    /// Raise the performance counter interrupt of the local APIC on overflow
+    pub const INTERRUPT: u64 = 1 << 20;
+    pub const ENABLE: u64 = 1 << 22;
+
+    /// Configures what `counter` counts
+    pub fn select(counter: u8, event: u64) {
+        Msr::write(Self::EVENT_SELECT_MSR + counter as u32, event)
+    }
+
+    pub fn read(counter: u8) -> u64 {
+        Msr::read(Self::COUNTER_MSR + counter as u32)
+    }
+
+    /// Sets `counter` to `value` sign extended to the counter width, only the
+    /// low 32 bits are writable
+    pub fn write(counter: u8, value: i32) {
+        Msr::write(Self::COUNTER_MSR + counter as u32, value as i64 as u64)
+    }
+
+    /// Enables `counter` in the global control register, which exists since
+    /// version 2
+    pub fn enable(counter: u8) {
+        let control = Msr::read(Self::GLOBAL_CONTROL_MSR);
+        Msr::write(Self::GLOBAL_CONTROL_MSR, control | 1 << counter)
+    }
+}
+
+/// Global and per bank registers of the machine check architecture
+pub struct MachineCheck;
+
+impl MachineCheck {
+    const CAPABILITIES_MSR: u32 = 0x179;
+    const STATUS_MSR: u32 = 0x17A;
+    /// Each bank has a control, status, address and misc register
+    const FIRST_BANK_MSR: u32 = 0x400;
+
+    /// Whether the interrupted instruction can be restarted (bit 0) and
+    /// whether a machine check is in progress (bit 2)
+    pub fn status() -> u64 {
+        Msr::read(Self::STATUS_MSR)
+    }
+
+    pub fn bank_count() -> u8 {
+        Msr::read(Self::CAPABILITIES_MSR) as u8
+    }
+
+    /// Status of `bank`, bit 63 is set if it holds a valid error
+    pub fn bank_status(bank: u8) -> u64 {
+        Msr::read(Self::FIRST_BANK_MSR + 4 * bank as u32 + 1)
+    }
+
+    /// Address related to the error in `bank`, only valid if bit 58 of its
+    /// status is set
+    pub fn bank_address(bank: u8) -> u64 {
+        Msr::read(Self::FIRST_BANK_MSR + 4 * bank as u32 + 2)
+    }
+}
+
+/// Memory types used by the page attribute table and the MTRRs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+    /// Uncacheable, but can be overridden by a write combining MTRR. Only
+    /// valid in the PAT.
+    UncachedMinus = 7,
+}
+
+impl MemoryType {
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        match raw {
+            0 => Some(MemoryType::Uncacheable),
+            1 => Some(MemoryType::WriteCombining),
+            4 => Some(MemoryType::WriteThrough),
+            5 => Some(MemoryType::WriteProtected),
+            6 => Some(MemoryType::WriteBack),
+            7 => Some(MemoryType::UncachedMinus),
+            _ => None,
+        }
+    }
+}
+
+/// Page attribute table. Entry `n` is selected by the PAT, PCD and PWT bits
+/// of a page table entry, read as the 3 bit number `PAT:PCD:PWT`.
+pub struct Pat;
+
+impl Pat {
+    const MSR_NUM: u32 = 0x277;
+
+    pub fn read() -> [Option<MemoryType>; 8] {
+        let raw = Msr::read(Self::MSR_NUM).to_le_bytes();
+        raw.map(|entry| MemoryType::from_raw(entry & 0x7))
+    }
+
+    /// Writes the memory types of all entries
+    ///
+    /// # Safety
+    ///
+    /// Unsafe because changing the type of existing mappings can break cache
+    /// coherency. Caches and TLBs have to be flushed afterwards.
+    pub unsafe fn write(entries: [MemoryType; 8]) {
+        Msr::write(Self::MSR_NUM, u64::from_le_bytes(entries.map(|t| t as u8)))
+    }
+}
+
+/// Base address of the FS segment, used as thread pointer for thread local
+/// storage
+pub struct FsBase;
+
+impl FsBase {
+    const MSR_NUM: u32 = 0xc000_0100;
+
+    pub fn read() -> VirtualAddress {
+        VirtualAddress::new(Msr::read(Self::MSR_NUM))
+    }
+
+    pub fn write(address: VirtualAddress) {
+        Msr::write(Self::MSR_NUM, address.as_u64())
+    }
+}
+
+/// A variable range MTRR, covering all physical addresses `a` with
+/// `a & mask == base & mask`
+#[derive(Debug, Clone, Copy)]
+pub struct MtrrRange {
+    pub base: PhysicalAddress,
+    pub mask: u64,
+    pub memory_type: Option<MemoryType>,
+}
+
+/// Memory type range registers, read only
+pub struct Mtrr;
+
+impl Mtrr {
+    const CAPABILITIES: u32 = 0xFE;
+    const DEFAULT_TYPE: u32 = 0x2FF;
+    const PHYS_BASE: u32 = 0x200;
+    const PHYS_MASK: u32 = 0x201;
+    /// Set in the default type and mask registers if the MTRRs are enabled
+    const ENABLE: u64 = 1 << 11;
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    /// Amount of variable range registers
+    pub fn variable_count() -> usize {
+        (Msr::read(Self::CAPABILITIES) & 0xff) as usize
+    }
+
+    /// Whether the write combining type is supported
+    pub fn write_combining() -> bool {
+        Msr::read(Self::CAPABILITIES) & (1 << 10) != 0
+    }
+
+    /// Type of memory not covered by any range, None if the MTRRs are disabled
+    pub fn default_type() -> Option<MemoryType> {
+        let raw = Msr::read(Self::DEFAULT_TYPE);
+        if raw & Self::ENABLE == 0 {
+            return None;
+        }
+        MemoryType::from_raw(raw as u8)
+    }
+
+    /// Enabled variable ranges
+    pub fn variable_ranges() -> impl Iterator<Item = MtrrRange> {
+        (0..Self::variable_count() as u32).filter_map(|i| {
+            let mask = Msr::read(Self::PHYS_MASK + 2 * i);
+            if mask & Self::ENABLE == 0 {
+                return None;
+            }
+            let base = Msr::read(Self::PHYS_BASE + 2 * i);
+            Some(MtrrRange {
+                base: PhysicalAddress::new(base & Self::ADDRESS_MASK),
+                mask: mask & Self::ADDRESS_MASK,
+                memory_type: MemoryType::from_raw(base as u8),
+            })
+        })
+    }
+}
+
 bitflags! {
     /// Configuration flags of the [`Cr0`] register.
     pub struct Cr0Flags: u64 {
@@ -214,6 +449,160 @@ impl Cr0 {
     }
 }
 
+bitflags! {
+    /// Configuration flags of the [`Cr4`] register.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Cr4Flags: u64 {
+        /// Enables virtual-8086 mode extensions.
+        const VIRTUAL_8086_MODE_EXTENSIONS = 1;
+        /// Enables protected mode virtual interrupts.
+        const PROTECTED_MODE_VIRTUAL_INTERRUPTS = 1 << 1;
+        /// Restricts `rdtsc` to ring 0.
+        const TIMESTAMP_DISABLE = 1 << 2;
+        /// Enables I/O breakpoints in the debug registers.
+        const DEBUGGING_EXTENSIONS = 1 << 3;
+        /// Enables 4MiB pages in 32-bit paging.
+        const PAGE_SIZE_EXTENSION = 1 << 4;
+        /// Enables physical address extension, required for long mode.
+        const PHYSICAL_ADDRESS_EXTENSION = 1 << 5;
+        /// Enables the machine check exception.
+        const MACHINE_CHECK_EXCEPTION = 1 << 6;
+        /// Enables global pages which are kept in the TLB on CR3 writes.
+        const PAGE_GLOBAL = 1 << 7;
+        /// Allows `rdpmc` outside of ring 0.
+        const PERFORMANCE_MONITOR_COUNTER = 1 << 8;
+        /// Enables `fxsave`, `fxrstor` and SSE instructions.
+        const OSFXSR = 1 << 9;
+        /// Enables the `#XM` exception for unmasked SIMD floating point errors.
+        const OSXMMEXCPT_ENABLE = 1 << 10;
+        /// Prevents `sgdt`, `sidt` and similar instructions outside of ring 0.
+        const USER_MODE_INSTRUCTION_PREVENTION = 1 << 11;
+        /// Enables 5-level paging.
+        const L5_PAGING = 1 << 12;
+        /// Enables `rdfsbase`, `wrfsbase`, `rdgsbase` and `wrgsbase`.
+        const FSGSBASE = 1 << 16;
+        /// Enables process context identifiers.
+        const PCID = 1 << 17;
+        /// Enables `xsave`, `xrstor` and the extended control registers.
+        const OSXSAVE = 1 << 18;
+        /// Enables supervisor mode execution prevention.
+        const SUPERVISOR_MODE_EXECUTION_PROTECTION = 1 << 20;
+        /// Enables supervisor mode access prevention.
+        const SUPERVISOR_MODE_ACCESS_PREVENTION = 1 << 21;
+        /// Enables protection keys for user pages.
+        const PROTECTION_KEY_USER = 1 << 22;
+    }
+}
+
+/// Control register 4. Enables architectural extensions
+#[derive(Debug)]
+pub struct Cr4;
+
+impl Cr4 {
+    /// Updates CR4 register flags.
+    ///
+    /// # Safety
+    ///
+    /// Unsafe because it’s possible to break memory safety with wrong flags,
+    /// e.g. by changing the paging mode
+    pub unsafe fn update<F>(f: F)
+    where
+        F: FnOnce(&mut Cr4Flags),
+    {
+        let mut flags = Self::read();
+        f(&mut flags);
+        Self::write(flags);
+    }
+
+    /// Reads the raw CR4 register.
+    pub fn read_raw() -> u64 {
+        let mut cr4: usize;
+        unsafe {
+            asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        }
+        cr4 as u64
+    }
+
+    /// Reads the CR4 flags.
+    pub fn read() -> Cr4Flags {
+        Cr4Flags::from_bits_truncate(Self::read_raw())
+    }
+
+    /// Writes CR4 flags
+    ///
+    /// # Safety
+    ///
+    /// Unsafe because it’s possible to break memory safety with wrong flags,
+    /// e.g. by changing the paging mode
+    pub unsafe fn write(val: Cr4Flags) {
+        // keep bits unknown to Cr4Flags
+        let reserved = Self::read_raw() & !Cr4Flags::all().bits();
+        unsafe {
+            asm!("mov cr4, {}", in(reg) (reserved | val.bits()) as usize, options(nostack, preserves_flags))
+        };
+    }
+}
+
+bitflags! {
+    /// State components enabled in the [`Xcr0`] register.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Xcr0Flags: u64 {
+        /// x87 FPU and MMX state, always set.
+        const X87 = 1;
+        /// SSE state, XMM registers and MXCSR.
+        const SSE = 1 << 1;
+        /// AVX state, upper halves of the YMM registers.
+        const AVX = 1 << 2;
+    }
+}
+
+/// Extended control register 0. Selects the state components managed by
+/// `xsave` and `xrstor`. Only accessible once CR4.OSXSAVE is set.
+#[derive(Debug)]
+pub struct Xcr0;
+
+impl Xcr0 {
+    pub fn read() -> Xcr0Flags {
+        let low: u32;
+        let high: u32;
+        unsafe {
+            asm!("xgetbv", in("ecx") 0, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+        }
+        Xcr0Flags::from_bits_truncate(((high as u64) << 32) | low as u64)
+    }
+
+    /// # Safety
+    ///
+    /// Enabling components not supported by the CPU raises `#GP`
+    pub unsafe fn write(val: Xcr0Flags) {
+        let bits = val.bits();
+        unsafe {
+            asm!("xsetbv", in("ecx") 0, in("eax") bits as u32, in("edx") (bits >> 32) as u32, options(nostack, preserves_flags));
+        }
+    }
+}
+
+/// Control register 2. Contains the virtual address that caused the last
+/// page fault
+#[derive(Debug)]
+pub struct Cr2;
+
+impl Cr2 {
+    /// Reads the raw CR2 register.
+    pub fn read_raw() -> u64 {
+        let mut cr2: usize;
+        unsafe {
+            asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+        }
+        cr2 as u64
+    }
+
+    /// Reads the page fault linear address
+    pub fn read() -> VirtualAddress {
+        VirtualAddress::new(Self::read_raw())
+    }
+}
+
 bitflags! {
     /// Controls cache settings for the highest-level page table.
     ///
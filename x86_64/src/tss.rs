@@ -16,6 +16,9 @@ use crate::{gdt::SegmentSelector, memory::VirtualAddress};
 use core::{arch::asm, mem::size_of};
 
 pub const DOUBLE_FAULT_IST_IDX: usize = 0x0;
+/// Machine checks can interrupt the kernel at any point, even while it
+/// switches stacks
+pub const MACHINE_CHECK_IST_IDX: usize = 0x1;
 
 /// TaskStateSegment struct
 #[repr(C, packed(4))]
@@ -97,6 +97,18 @@ impl SerialPort {
 
         unsafe { self.data.read() }
     }
+
+    /// Returns a received byte if there is one, without blocking
+    pub fn try_recv(&self) -> Option<u8> {
+        if self
+            .line_status_flags()
+            .contains(LineStatusFlags::DATA_READY)
+        {
+            Some(unsafe { self.data.read() })
+        } else {
+            None
+        }
+    }
 }
 
 impl fmt::Write for SerialPort {